@@ -1,5 +1,7 @@
 use std::env;
-use std::process::Command;
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
 
 fn rustflags_request_native() -> bool {
     let flags = rustflags();
@@ -84,6 +86,90 @@ fn exe_exists(name: &str) -> bool {
     }
 }
 
+/// x86 features `-march=native` can enable, as the C++ compiler's
+/// predefined macro and the name `is_x86_feature_detected!` knows it by.
+const X86_FEATURE_MACROS: &[(&str, &str)] = &[
+    ("__SSE3__", "sse3"),
+    ("__SSSE3__", "ssse3"),
+    ("__SSE4_1__", "sse4.1"),
+    ("__SSE4_2__", "sse4.2"),
+    ("__SSE4A__", "sse4a"),
+    ("__POPCNT__", "popcnt"),
+    ("__LZCNT__", "lzcnt"),
+    ("__AVX__", "avx"),
+    ("__AVX2__", "avx2"),
+    ("__FMA__", "fma"),
+    ("__F16C__", "f16c"),
+    ("__BMI__", "bmi1"),
+    ("__BMI2__", "bmi2"),
+    ("__AES__", "aes"),
+    ("__PCLMUL__", "pclmulqdq"),
+    ("__SHA__", "sha"),
+    ("__ADX__", "adx"),
+    ("__RDRND__", "rdrand"),
+    ("__RDSEED__", "rdseed"),
+    ("__MOVBE__", "movbe"),
+    ("__XSAVE__", "xsave"),
+    ("__AVX512F__", "avx512f"),
+    ("__AVX512BW__", "avx512bw"),
+    ("__AVX512CD__", "avx512cd"),
+    ("__AVX512DQ__", "avx512dq"),
+    ("__AVX512VL__", "avx512vl"),
+    ("__AVX512VBMI__", "avx512vbmi"),
+    ("__AVX512VNNI__", "avx512vnni"),
+    ("__GFNI__", "gfni"),
+    ("__VAES__", "vaes"),
+    ("__VPCLMULQDQ__", "vpclmulqdq"),
+];
+
+/// The x86 features the C++ compiler enables for `-march=native`, read from
+/// its predefined macros.  Empty if it cannot be asked, in which case
+/// `flag_if_supported` has not passed it `-march=native` either.
+fn native_x86_features(build: &cc::Build) -> Vec<&'static str> {
+    let output = build
+        .get_compiler()
+        .to_command()
+        .args(["-march=native", "-dM", "-E", "-x", "c++", "-"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output();
+    let Ok(output) = output else {
+        return Vec::new();
+    };
+    if !output.status.success() {
+        return Vec::new();
+    }
+    let defined: Vec<&str> = std::str::from_utf8(&output.stdout)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.strip_prefix("#define ")?.split_whitespace().next())
+        .collect();
+    X86_FEATURE_MACROS
+        .iter()
+        .filter(|(name, _)| defined.contains(name))
+        .map(|&(_, feature)| feature)
+        .collect()
+}
+
+/// Records the CPU features the C++ was compiled to require, for the
+/// runtime check in `src/cpu.rs`.
+fn write_cpu_features(native: bool, features: &[&str]) {
+    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR"));
+    let list: Vec<String> = features.iter().map(|f| format!("{f:?}")).collect();
+    fs::write(
+        out.join("cpu_features.rs"),
+        format!(
+            "const COMPILED_CPU_FEATURES: &[&str] = &[{}];\n",
+            list.join(", ")
+        ),
+    )
+    .expect("write cpu_features.rs");
+    println!(
+        "cargo:rustc-env=ZPAQ_RS_NATIVE_CPU={}",
+        if native { "1" } else { "0" }
+    );
+}
+
 fn building_python_extension() -> bool {
     env::var_os("PYO3_BUILD_EXTENSION_MODULE").is_some()
 }
@@ -91,7 +177,9 @@ fn building_python_extension() -> bool {
 fn main() {
     println!("cargo:rerun-if-changed=zpaq/libzpaq.cpp");
     println!("cargo:rerun-if-changed=zpaq/libzpaq.h");
+    println!("cargo:rerun-if-changed=zpaq/zpaq.cpp");
     println!("cargo:rerun-if-changed=zpaq_rs_ffi.cpp");
+    println!("cargo:rerun-if-changed=zpaq_rs_cli.cpp");
     println!("cargo:rerun-if-env-changed=PYO3_BUILD_EXTENSION_MODULE");
 
     let mut build = cc::Build::new();
@@ -112,7 +200,8 @@ fn main() {
         .cpp(true)
         .include("zpaq")
         .file("zpaq/libzpaq.cpp")
-        .file("zpaq/zpaq.cpp")
+        // zpaq.cpp, with file scanning hooked by the shim.
+        .file("zpaq_rs_cli.cpp")
         .file("zpaq_rs_ffi.cpp")
         // zpaq.cpp contains a `main()` (or `wmain()` on Windows). Rename it so it can be linked into this library.
         .define("main", "zpaq_cli_main")
@@ -143,10 +232,13 @@ fn main() {
     build.flag_if_supported("-O3");
 
     // Keep C++ codegen aligned with Rust when native tuning is explicitly requested.
-    if rustflags_request_native() {
+    let native = rustflags_request_native();
+    let mut cpu_features = Vec::new();
+    if native {
         match target_arch.as_str() {
             "x86" | "x86_64" => {
                 build.flag_if_supported("-march=native");
+                cpu_features = native_x86_features(&build);
             }
             "arm" | "aarch64" => {
                 build.flag_if_supported("-mcpu=native");
@@ -176,6 +268,8 @@ fn main() {
         build.flag_if_supported("-flto");
     }
 
+    write_cpu_features(native, &cpu_features);
+
     build.compile("zpaq_rs_ffi");
 
     // On Windows, zpaq needs advapi32 for CryptoAPI (CryptAcquireContext, etc.)
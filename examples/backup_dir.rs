@@ -0,0 +1,98 @@
+//! Backs a directory up into a journaling archive at a limited rate, with
+//! progress, then checks every file reads back.
+//!
+//! ```text
+//! cargo run --example backup_dir -- <dir> <archive> <bytes per second>
+//! ```
+//!
+//! Each run adds a version, so files that did not change cost only their
+//! index entry.  Prints a `progress` line per report and `key=value`
+//! totals.
+
+#![forbid(unsafe_code)]
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use zpaq_rs::{
+    ArchiveReader, ArchiveWriter, EntryMeta, ProgressCallback, ProgressInterval, RateLimit,
+    Throttle,
+};
+
+/// The files under `dir`, recursively, sorted.
+fn files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut out = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                pending.push(entry.path());
+            } else if entry.file_type()?.is_file() {
+                out.push(entry.path());
+            }
+        }
+    }
+    out.sort();
+    Ok(out)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, dir, archive, rate] = &args[..] else {
+        return Err("usage: backup_dir <dir> <archive> <bytes per second>".into());
+    };
+    let dir = Path::new(dir);
+    let files = files(dir)?;
+    let total = files
+        .iter()
+        .map(|path| Ok(std::fs::metadata(path)?.len()))
+        .sum::<std::io::Result<u64>>()?;
+
+    // One throttle shared by every file, so the rate holds across them.
+    let throttle = Throttle::new(RateLimit::new(rate.parse()?))?;
+    // Each reader counts from zero; report the bytes of the files before.
+    let progress = |done: u64| {
+        ProgressCallback::new(move |p| {
+            println!(
+                "progress bytes={} total={total} rate={:.0}",
+                done + p.bytes,
+                p.bytes_per_sec
+            );
+        })
+        .interval(ProgressInterval::Bytes(total / 10 + 1))
+    };
+
+    let started = Instant::now();
+    let mut writer = ArchiveWriter::append(archive, "2")?;
+    let mut added = Vec::new();
+    let mut done = 0;
+    for path in &files {
+        let name = path.strip_prefix(dir)?.to_string_lossy().replace('\\', "/");
+        let meta = EntryMeta {
+            mtime: Some(std::fs::metadata(path)?.modified()?),
+            ..EntryMeta::default()
+        };
+        let data = throttle.reader(File::open(path)?);
+        writer.add_entry(&name, progress(done).reader(data), &meta)?;
+        done += std::fs::metadata(path)?.len();
+        added.push((name, path));
+    }
+    writer.finish()?;
+    let elapsed = started.elapsed();
+
+    let mut reader = ArchiveReader::open_path(archive)?;
+    for (name, path) in &added {
+        if reader.read(name)? != std::fs::read(path)? {
+            return Err(format!("{name} does not read back").into());
+        }
+    }
+
+    println!("files={}", added.len());
+    println!("bytes={total}");
+    println!("archive_bytes={}", std::fs::metadata(archive)?.len());
+    println!("versions={}", reader.versions());
+    println!("elapsed_ms={}", elapsed.as_millis());
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! Groups files by content with the normalized compression distance.
+//!
+//! ```text
+//! cargo run --example ncd_cluster -- <dir> <threshold>
+//! ```
+//!
+//! Two files are close when compressing them together costs little more
+//! than compressing the larger alone.  Files join a cluster when their
+//! distance to any member is below the threshold (single linkage).  Prints
+//! an `ncd` line per pair and a `cluster` line per group, members sorted.
+
+#![forbid(unsafe_code)]
+
+use std::collections::BTreeMap;
+
+use zpaq_rs::ncd;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, dir, threshold] = &args[..] else {
+        return Err("usage: ncd_cluster <dir> <threshold>".into());
+    };
+    let threshold: f64 = threshold.parse()?;
+    let mut files = BTreeMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            files.insert(name, std::fs::read(entry.path())?);
+        }
+    }
+    let names: Vec<&String> = files.keys().collect();
+
+    // Union-find over the files, joined by each close pair.
+    let mut parent: Vec<usize> = (0..names.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    for i in 0..names.len() {
+        for j in i + 1..names.len() {
+            let distance = ncd(&files[names[i]], &files[names[j]], "2")?;
+            println!("ncd {} {} {distance:.4}", names[i], names[j]);
+            if distance < threshold {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut clusters: BTreeMap<usize, Vec<&str>> = BTreeMap::new();
+    for (i, name) in names.iter().enumerate() {
+        clusters
+            .entry(root(&mut parent, i))
+            .or_default()
+            .push(name.as_str());
+    }
+    for members in clusters.values() {
+        println!("cluster {}", members.join(" "));
+    }
+    println!("clusters={}", clusters.len());
+    Ok(())
+}
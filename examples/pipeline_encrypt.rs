@@ -0,0 +1,103 @@
+//! Compress-then-encrypt against encrypt-then-compress, and hashing the
+//! input while it is compressed.
+//!
+//! ```text
+//! cargo run --example pipeline_encrypt -- <input> <work dir> <password>
+//! ```
+//!
+//! Encrypted data looks random, so compressing it afterwards gains
+//! nothing: compress first.  `zpaq` encrypts whole archives with `-key`,
+//! so the encrypt-first pipeline stores the input in an encrypted archive
+//! (method 0) and compresses that.  Prints `key=value` lines.
+
+#![forbid(unsafe_code)]
+
+use std::fs::File;
+use std::io::{BufReader, Cursor};
+use std::path::Path;
+
+use zpaq_rs::{
+    ArchiveReader, CompressOptions, Digest, HashKind, ZpaqError, compress_stream_with,
+    decompress_to_vec, list_blocks, sha256, zpaq_command,
+};
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// The one file of the encrypted archive `archive`.
+fn read_encrypted(
+    archive: impl std::io::Read + std::io::Seek + Send,
+    key: &str,
+) -> Result<Vec<u8>, ZpaqError> {
+    let mut reader = ArchiveReader::with_key(archive, key)?;
+    let path = reader.entries().next().expect("one file").path.clone();
+    reader.read(&path)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, input, work, key] = &args[..] else {
+        return Err("usage: pipeline_encrypt <input> <work dir> <password>".into());
+    };
+    let data = std::fs::read(input)?;
+    let work = Path::new(work);
+    let path = |name: &str| work.join(name).to_string_lossy().into_owned();
+
+    // Compress, then encrypt: `zpaq add -key` does both in that order.
+    let compressed_first = path("compressed-first.zpaq");
+    zpaq_command(&["add", &compressed_first, input, "-method", "2", "-key", key])?;
+    let file = BufReader::new(File::open(&compressed_first)?);
+    assert_eq!(read_encrypted(file, key)?, data);
+
+    // Encrypt, then compress: store encrypted, and compress the result.
+    let stored = path("encrypted.zpaq");
+    zpaq_command(&["add", &stored, input, "-method", "0", "-key", key])?;
+    let encrypted = std::fs::read(&stored)?;
+    let encrypted_first = path("encrypted-first.zpaq");
+    let mut compressed = Vec::new();
+    compress_stream_with(
+        &encrypted[..],
+        &mut compressed,
+        "2",
+        &CompressOptions::default(),
+    )?;
+    std::fs::write(&encrypted_first, &compressed)?;
+    let restored = decompress_to_vec(&compressed)?;
+    assert_eq!(read_encrypted(Cursor::new(restored), key)?, data);
+
+    // Hash while compressing: the SHA-256 of each segment lands in its
+    // comment.  Inputs up to a block (16 MiB for method 2) are one segment.
+    let options = CompressOptions {
+        filename: Some("input".into()),
+        extra_checksum: Some(HashKind::Sha256),
+        ..CompressOptions::default()
+    };
+    let mut hashed = Vec::new();
+    let stats = compress_stream_with(File::open(input)?, &mut hashed, "2", &options)?;
+    let recorded: Vec<Digest> = list_blocks(&hashed)?
+        .iter()
+        .flat_map(|block| &block.segments)
+        .filter_map(|segment| segment.extra_checksum)
+        .collect();
+    let [Digest::Sha256(digest)] = recorded[..] else {
+        return Err(format!("expected one segment hash, found {recorded:?}").into());
+    };
+    assert_eq!(digest, sha256(&data)?);
+    let hashed_path = path("hashed.zpaq");
+    std::fs::write(&hashed_path, &hashed)?;
+
+    println!("input_bytes={}", data.len());
+    println!(
+        "compressed_first_bytes={}",
+        std::fs::metadata(&compressed_first)?.len()
+    );
+    println!("encrypted_bytes={}", encrypted.len());
+    println!("encrypted_first_bytes={}", compressed.len());
+    println!("hashed_bytes={}", stats.bytes_out);
+    println!("sha256={}", hex(&digest));
+    println!("compressed_first={compressed_first}");
+    println!("encrypted_first={encrypted_first}");
+    println!("hashed={hashed_path}");
+    Ok(())
+}
@@ -0,0 +1,108 @@
+//! Compresses a stream in parallel and "uploads" it as fixed-size objects,
+//! then reads byte ranges back through the objects without decoding the
+//! whole stream.
+//!
+//! ```text
+//! cargo run --example seekable_store -- <input> <store dir> <part bytes> <threads>
+//! ```
+//!
+//! The uploader runs on its own thread and receives the compressed stream
+//! over a channel, as a client for an object store would.  Objects are
+//! named like the parts of a multi-part archive, so [`ArchiveSpec::open`]
+//! reads them back as one seekable stream.  Prints `key=value` lines.
+
+#![forbid(unsafe_code)]
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use zpaq_rs::{ArchiveSpec, build_stream_index, compress_stream_parallel, random_read};
+
+/// Hands what is written to the uploader.
+struct UploadWriter(Sender<Vec<u8>>);
+
+impl Write for UploadWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::other("uploader stopped"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Cuts what arrives into objects of `part_bytes` and stores each as the
+/// next part of `spec`.  Returns how many it stored.
+fn upload(spec: &ArchiveSpec, part_bytes: usize, chunks: Receiver<Vec<u8>>) -> io::Result<u64> {
+    let mut parts = 0;
+    let mut pending = Vec::new();
+    let mut store = |object: &[u8]| {
+        parts += 1;
+        let name = spec.part(parts).map_err(io::Error::other)?;
+        std::fs::write(name, object)
+    };
+    for chunk in chunks {
+        pending.extend_from_slice(&chunk);
+        while pending.len() >= part_bytes {
+            store(&pending[..part_bytes])?;
+            pending.drain(..part_bytes);
+        }
+    }
+    if !pending.is_empty() {
+        store(&pending)?;
+    }
+    Ok(parts)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, input, store, part_bytes, threads] = &args[..] else {
+        return Err("usage: seekable_store <input> <store dir> <part bytes> <threads>".into());
+    };
+    let part_bytes: usize = part_bytes.parse()?;
+    let threads: usize = threads.parse()?;
+    let data = std::fs::read(input)?;
+    let pattern = Path::new(store).join("obj????.zpaq");
+    let spec = ArchiveSpec::parse(&pattern.to_string_lossy())?;
+
+    // Blocks of 1 MiB (method "10"), so a range decodes only its blocks.
+    let (sender, receiver) = channel();
+    let uploader = {
+        let spec = spec.clone();
+        std::thread::spawn(move || upload(&spec, part_bytes, receiver))
+    };
+    compress_stream_parallel(&data[..], UploadWriter(sender), "10", None, None, threads)?;
+    let parts = uploader.join().expect("uploader panicked")?;
+
+    let index = build_stream_index(spec.open()?)?;
+    if index.len() != data.len() as u64 {
+        return Err(format!("index covers {} of {} bytes", index.len(), data.len()).into());
+    }
+    // A range at each end, one inside a block and one across blocks.
+    let len = index.len();
+    let ranges = [
+        0..len.min(100),
+        len.saturating_sub(100)..len,
+        len / 3..len / 3 + len.min(1000) / 2,
+        len / 2 - len.min(1 << 20) / 2..len / 2 + len.min(1 << 20) / 2,
+    ];
+    let mut ranges_ok = 0;
+    for range in ranges {
+        let bytes = random_read(spec.open()?, &index, range.clone())?;
+        if bytes[..] != data[range.start as usize..range.end as usize] {
+            return Err(format!("range {range:?} reads back wrong").into());
+        }
+        ranges_ok += 1;
+    }
+
+    println!("input_bytes={}", data.len());
+    println!("parts={parts}");
+    println!("blocks={}", index.blocks.len());
+    println!("ranges_ok={ranges_ok}");
+    println!("pattern={}", spec.pattern());
+    Ok(())
+}
@@ -0,0 +1,23 @@
+//! Decompresses arbitrary input.  Seed it with valid archives and with
+//! `fuzz/regressions`:
+//!
+//! ```text
+//! cargo +nightly fuzz run decompress corpus/decompress regressions -- -rss_limit_mb=4096
+//! ```
+//!
+//! Inputs found to crash belong in `regressions`, which
+//! `tests/malformed_headers.rs` replays.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use zpaq_rs::{DecompressLimits, decompress_to_vec_limited};
+
+fuzz_target!(|data: &[u8]| {
+    // Bound output so that valid, highly compressible inputs do not stall
+    // the fuzzer.
+    let limits = DecompressLimits {
+        max_output_bytes: Some(64 << 20),
+        ..Default::default()
+    };
+    let _ = decompress_to_vec_limited(data, &limits);
+});
@@ -0,0 +1,336 @@
+//! Appending to in-memory archives with tail-block coalescing.
+//!
+//! Every block starts its model from scratch, so an archive built from many
+//! small appends compresses each one as if it were alone.  When the last
+//! block is small and uses the same model, [`archive_append_entries_with`]
+//! can instead rewrite it together with the new entries as one block.
+
+use std::io::Cursor;
+use std::ptr;
+
+use crate::verify::{ArchiveSource, parse_journal_name, read_segments_at};
+use crate::{
+    ArchiveEntry, FfiReader, FfiWriter, Result, SharedVecWriter, archive_from_entries,
+    clear_last_error, err_from_last, format, handle, peek_block, sys,
+};
+
+/// Options for [`archive_append_entries_with`].
+///
+/// The default appends a new block, as
+/// [`archive_append_entries`](crate::archive_append_entries) does.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AppendOptions {
+    /// Rewrite the archive's last block together with the new entries when
+    /// it holds at most this many uncompressed bytes.
+    ///
+    /// The last block is decompressed and compressed again with the new
+    /// entries as its later segments, replacing the original bytes of the
+    /// archive from that block on.  Finding it means scanning the headers of
+    /// every block before it, so each append takes time linear in the number
+    /// of blocks, but earlier blocks are not decompressed.  The rewrite
+    /// is kept only if the last block's model fingerprint (see
+    /// [`BlockInfo::model_fingerprint`](crate::BlockInfo::model_fingerprint))
+    /// matches that of the new entries compressed alone, and the result is
+    /// smaller than appending separately.
+    ///
+    /// Only blocks this crate's entry functions write qualify: blocks with
+    /// segment checksums, journaling blocks, and segments whose filename or
+    /// comment is not UTF-8 are never rewritten.
+    pub coalesce_below: Option<u64>,
+}
+
+/// Appends raw byte entries to an in-memory archive, as
+/// [`archive_append_entries`](crate::archive_append_entries) does, with the
+/// tail-block coalescing described by [`AppendOptions`].
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{AppendOptions, ArchiveEntry, archive_append_entries_with};
+///
+/// let options = AppendOptions {
+///     coalesce_below: Some(1 << 20),
+/// };
+/// let mut archive = Vec::new();
+/// for i in 0..3 {
+///     let path = format!("log/{i}.txt");
+///     let data = format!("entry {i}: the same kind of line again\n").repeat(20);
+///     let entry = ArchiveEntry {
+///         path: &path,
+///         data: data.as_bytes(),
+///         comment: None,
+///     };
+///     archive_append_entries_with(&mut archive, &[entry], "1", &options)?;
+/// }
+/// let blocks = zpaq_rs::list_blocks(&archive)?;
+/// assert_eq!(blocks.len(), 1);
+/// assert_eq!(blocks[0].segments.len(), 3);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::WrongFormat`](crate::ZpaqError::WrongFormat) if
+/// `archive` is encrypted or not an archive at all.
+pub fn archive_append_entries_with(
+    archive: &mut Vec<u8>,
+    entries: &[ArchiveEntry<'_>],
+    method: &str,
+    options: &AppendOptions,
+) -> Result<()> {
+    format::require_plain(&mut Cursor::new(&archive[..]))?;
+    let payload = archive_from_entries(entries, method)?;
+    if let Some(limit) = options.coalesce_below
+        && !payload.is_empty()
+        && let Some((offset, block)) = coalesce(archive, entries, method, limit, &payload)?
+    {
+        archive.truncate(offset as usize);
+        archive.extend_from_slice(&block);
+        return Ok(());
+    }
+    archive.extend_from_slice(&payload);
+    Ok(())
+}
+
+/// The offset of the archive's last block and the block replacing it and
+/// `payload`, if coalescing applies and pays.
+fn coalesce(
+    archive: &[u8],
+    entries: &[ArchiveEntry<'_>],
+    method: &str,
+    limit: u64,
+    payload: &[u8],
+) -> Result<Option<(u64, Vec<u8>)>> {
+    let Some((offset, headers)) = last_block(archive)? else {
+        return Ok(None);
+    };
+    if headers.iter().any(|h| h.has_sha1) {
+        return Ok(None);
+    }
+    let Ok(names) = headers
+        .iter()
+        .map(|h| std::str::from_utf8(&h.filename))
+        .collect::<std::result::Result<Vec<_>, _>>()
+    else {
+        return Ok(None);
+    };
+    let Ok(comments) = headers
+        .iter()
+        .map(|h| std::str::from_utf8(&h.comment))
+        .collect::<std::result::Result<Vec<_>, _>>()
+    else {
+        return Ok(None);
+    };
+    if names.iter().any(|name| parse_journal_name(name).is_some()) {
+        return Ok(None);
+    }
+
+    let tail = &archive[offset as usize..];
+    let (Some(old), Some(new)) = (peek_block(tail)?, peek_block(payload)?) else {
+        return Ok(None);
+    };
+    if old.model_fingerprint != new.model_fingerprint {
+        return Ok(None);
+    }
+    let mut source = ArchiveSource::new(Cursor::new(archive), None)?;
+    let Ok(segments) = read_segments_at(&mut source, offset, headers.len())? else {
+        return Ok(None);
+    };
+    let size: u64 = segments.iter().map(|(_, data, _)| data.len() as u64).sum();
+    if size > limit {
+        return Ok(None);
+    }
+
+    let mut combined: Vec<ArchiveEntry<'_>> = segments
+        .iter()
+        .zip(names.iter().zip(&comments))
+        .map(|((_, data, _), (&path, &comment))| ArchiveEntry {
+            path,
+            data,
+            comment: (!comment.is_empty()).then_some(comment),
+        })
+        .collect();
+    combined.extend_from_slice(entries);
+    let block = archive_from_entries(&combined, method)?;
+    Ok((block.len() < tail.len() + payload.len()).then_some((offset, block)))
+}
+
+/// A segment header of the last block, read without decoding its data.
+struct SegmentHeader {
+    filename: Vec<u8>,
+    comment: Vec<u8>,
+    has_sha1: bool,
+}
+
+/// The offset and segment headers of the last block of `archive`.  Segment
+/// data is skipped, not decoded.
+fn last_block(archive: &[u8]) -> Result<Option<(u64, Vec<SegmentHeader>)>> {
+    let mut source = ArchiveSource::new(Cursor::new(archive), None)?;
+    let state = source.shared_state();
+    clear_last_error()?;
+    let reader = FfiReader::new(&mut source)?;
+    let decompresser = handle::Decompresser::new()?;
+    let offset = || {
+        let buffered = unsafe { sys::zpaq_decompresser_buffered(decompresser.raw) }.max(0);
+        state.pos().saturating_sub(buffered as u64)
+    };
+    let result = (|| {
+        if unsafe { sys::zpaq_decompresser_set_input(decompresser.raw, reader.raw) } != 0 {
+            return Err(err_from_last());
+        }
+        let mut last = None;
+        loop {
+            let start = offset();
+            let rc_block =
+                unsafe { sys::zpaq_decompresser_find_block(decompresser.raw, ptr::null_mut()) };
+            if rc_block < 0 {
+                return Err(err_from_last());
+            }
+            if rc_block == 0 {
+                return Ok(last);
+            }
+            let mut headers = Vec::new();
+            loop {
+                let filename = SharedVecWriter::new();
+                let filename_writer = FfiWriter::new(filename.clone())?;
+                let rc_filename = unsafe {
+                    sys::zpaq_decompresser_find_filename(decompresser.raw, filename_writer.raw)
+                };
+                if rc_filename < 0 {
+                    return Err(err_from_last());
+                }
+                if rc_filename == 0 {
+                    break;
+                }
+                drop(filename_writer);
+                let comment = SharedVecWriter::new();
+                let comment_writer = FfiWriter::new(comment.clone())?;
+                if unsafe {
+                    sys::zpaq_decompresser_read_comment(decompresser.raw, comment_writer.raw)
+                } != 0
+                {
+                    return Err(err_from_last());
+                }
+                drop(comment_writer);
+                let mut segment_end = [0u8; 21];
+                if unsafe {
+                    sys::zpaq_decompresser_read_segment_end(
+                        decompresser.raw,
+                        segment_end.as_mut_ptr(),
+                    )
+                } != 0
+                {
+                    return Err(err_from_last());
+                }
+                headers.push(SegmentHeader {
+                    filename: filename.bytes(),
+                    comment: comment.bytes(),
+                    has_sha1: segment_end[0] == 1,
+                });
+            }
+            last = Some((start, headers));
+        }
+    })();
+    drop(decompresser);
+    drop(reader);
+    if let Some(err) = source.take_io_error() {
+        return Err(err);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{archive_append_entries, archive_read_file_bytes, decompress_to_vec, list_blocks};
+
+    /// A 4 KB log chunk: similar lines with varying numbers.
+    fn chunk(i: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut n = 0;
+        while out.len() < 4096 {
+            let line = format!(
+                "2024-05-{:02} request id={} path=/api/v1/items/{} status={}\n",
+                i % 28 + 1,
+                i * 1000 + n,
+                (i * 7 + n * 13) % 997,
+                [200, 404, 500][n % 3]
+            );
+            out.extend_from_slice(line.as_bytes());
+            n += 1;
+        }
+        out.truncate(4096);
+        out
+    }
+
+    #[test]
+    fn coalescing_small_appends_improves_ratio() {
+        let chunks: Vec<Vec<u8>> = (0..60).map(chunk).collect();
+        let paths: Vec<String> = (0..chunks.len()).map(|i| format!("log/{i:03}")).collect();
+        let options = AppendOptions {
+            coalesce_below: Some(1 << 20),
+        };
+        let mut naive = Vec::new();
+        let mut coalesced = Vec::new();
+        for (path, data) in paths.iter().zip(&chunks) {
+            let entry = [ArchiveEntry {
+                path,
+                data,
+                comment: Some("from test"),
+            }];
+            archive_append_entries(&mut naive, &entry, "1").expect("append");
+            archive_append_entries_with(&mut coalesced, &entry, "1", &options).expect("coalesce");
+        }
+
+        assert_eq!(list_blocks(&naive).expect("list").len(), chunks.len());
+        let blocks = list_blocks(&coalesced).expect("list");
+        assert_eq!(blocks.len(), 1);
+        assert!(blocks[0].segments.iter().all(|s| s.comment == "from test"));
+        assert!(
+            coalesced.len() * 2 < naive.len(),
+            "coalesced {} vs naive {}",
+            coalesced.len(),
+            naive.len()
+        );
+        assert_eq!(
+            decompress_to_vec(&coalesced).expect("decompress"),
+            chunks.concat()
+        );
+        assert_eq!(
+            archive_read_file_bytes(&coalesced, &paths[17]).expect("read"),
+            chunks[17]
+        );
+    }
+
+    #[test]
+    fn large_or_different_tail_blocks_are_kept() {
+        fn entry<'a>(path: &'a str, data: &'a [u8]) -> ArchiveEntry<'a> {
+            ArchiveEntry {
+                path,
+                data,
+                comment: None,
+            }
+        }
+        let small = AppendOptions {
+            coalesce_below: Some(5000),
+        };
+        let mut archive = Vec::new();
+        for i in 0..3 {
+            let data = chunk(i);
+            archive_append_entries_with(&mut archive, &[entry("a", &data)], "1", &small)
+                .expect("append");
+        }
+        // The second append fits under 5000 bytes; the third would not.
+        assert_eq!(list_blocks(&archive).expect("list").len(), 2);
+
+        // A different model is appended as a block of its own.
+        let data = chunk(3);
+        let any = AppendOptions {
+            coalesce_below: Some(u64::MAX),
+        };
+        archive_append_entries_with(&mut archive, &[entry("b", &data)], "3", &any).expect("append");
+        assert_eq!(list_blocks(&archive).expect("list").len(), 3);
+        let expected: Vec<u8> = (0..4).flat_map(chunk).collect();
+        assert_eq!(decompress_to_vec(&archive).expect("decompress"), expected);
+    }
+}
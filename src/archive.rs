@@ -0,0 +1,602 @@
+//! An archive held in memory, read and changed as a whole.
+
+use std::collections::BTreeMap;
+use std::io::{Cursor, Seek, Write};
+use std::sync::Arc;
+
+use crate::fragments::version_list;
+use crate::journal::{append_in_memory, check_entry};
+use crate::{
+    ArchiveEntryInfo, ArchiveReader, ArchiveWriter, EntryMeta, Result, VersionInfo, ZpaqError,
+    io_err,
+};
+
+/// A versioned store of files in one in-memory archive.
+///
+/// Changes made with [`insert`](Self::insert), [`remove`](Self::remove)
+/// and [`rename`](Self::rename) are seen at once by [`entries`](Self::entries) and [`read`](Self::read),
+/// and written together as one new version by [`flush`](Self::flush), which
+/// [`to_bytes`](Self::to_bytes) and [`write_to`](Self::write_to) call.  The
+/// archive is journaling, as `zpaq add` writes it, so earlier versions stay
+/// readable and `zpaq extract` restores each file's date and permissions.
+///
+/// [`parse`](Self::parse) takes journaling archives and streaming ones (as
+/// written by [`archive_from_entries`](crate::archive_from_entries)).  A
+/// streaming archive is kept as it is until the first flush, which rewrites
+/// it as a journaling archive whose one version holds its files and the
+/// changes.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{EntryMeta, ZpaqArchive};
+///
+/// let mut archive = ZpaqArchive::new("1");
+/// archive.insert("config.toml", "answer = 42", EntryMeta::default())?;
+/// archive.insert("notes.txt", "first draft", EntryMeta::default())?;
+/// let bytes = archive.to_bytes()?;
+///
+/// let mut archive = ZpaqArchive::parse(bytes)?;
+/// archive.insert("notes.txt", "second draft", EntryMeta::default())?;
+/// archive.remove("config.toml");
+/// assert_eq!(archive.read("notes.txt")?, b"second draft");
+/// archive.flush()?;
+/// assert_eq!(archive.versions().len(), 2);
+/// assert_eq!(archive.entries().len(), 1);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub struct ZpaqArchive {
+    method: String,
+    bytes: Arc<Vec<u8>>,
+    /// A reader of `bytes`; `None` while they are empty.
+    reader: Option<ArchiveReader<Cursor<Shared>>>,
+    /// Changes not flushed yet, by path.
+    changes: BTreeMap<String, Change>,
+}
+
+/// The archive bytes, shared with the reader.
+#[derive(Clone)]
+struct Shared(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for Shared {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+enum Change {
+    Insert(Vec<u8>, EntryMeta),
+    Remove,
+    /// The file the last flushed version stores at this other path.
+    Moved(String),
+}
+
+impl ZpaqArchive {
+    /// An empty archive, whose data blocks will be compressed with `method`
+    /// (as for [`zpaq_add`](crate::zpaq_add)).
+    pub fn new(method: &str) -> Self {
+        ZpaqArchive {
+            method: method.to_string(),
+            bytes: Arc::default(),
+            reader: None,
+            changes: BTreeMap::new(),
+        }
+    }
+
+    /// Opens the unencrypted archive `bytes`; new versions are compressed
+    /// with method `"1"` unless [`set_method`](Self::set_method) says
+    /// otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Fails as [`ArchiveReader::open`] does.
+    pub fn parse(bytes: Vec<u8>) -> Result<Self> {
+        let mut archive = ZpaqArchive::new("1");
+        archive.bytes = Arc::new(bytes);
+        archive.reopen()?;
+        Ok(archive)
+    }
+
+    /// Sets the method new versions are compressed with.
+    pub fn set_method(&mut self, method: &str) {
+        self.method = method.to_string();
+    }
+
+    /// The files, sorted by path, with the changes not flushed yet.
+    /// Inserted files have no [`date`](ArchiveEntryInfo::date) until
+    /// flushed.
+    pub fn entries(&self) -> Vec<ArchiveEntryInfo> {
+        let stored = self.reader.iter().flat_map(|reader| reader.entries());
+        let mut entries: Vec<ArchiveEntryInfo> = stored
+            .filter(|entry| !self.changes.contains_key(&entry.path))
+            .cloned()
+            .collect();
+        for (path, change) in &self.changes {
+            match change {
+                Change::Insert(data, meta) => entries.push(ArchiveEntryInfo {
+                    path: path.clone(),
+                    size: data.len() as u64,
+                    date: None,
+                    comment: None,
+                    meta: meta.clone(),
+                }),
+                Change::Moved(from) => {
+                    let mut stored = self.reader.iter().flat_map(|reader| reader.entries());
+                    if let Some(entry) = stored.find(|entry| entry.path == *from) {
+                        entries.push(ArchiveEntryInfo {
+                            path: path.clone(),
+                            ..entry.clone()
+                        });
+                    }
+                }
+                Change::Remove => {}
+            }
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        entries
+    }
+
+    /// Whether there is a file stored as `path`.
+    pub fn contains(&self, path: &str) -> bool {
+        match self.changes.get(path) {
+            Some(Change::Insert(..) | Change::Moved(_)) => true,
+            Some(Change::Remove) => false,
+            None => self.stored(path),
+        }
+    }
+
+    /// Reads the file stored as `path`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::NotFound`] if there is none, with `was_deleted`
+    /// set if it was removed since the last flush.
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>> {
+        let not_found = |was_deleted| ZpaqError::NotFound {
+            path: path.to_string(),
+            was_deleted,
+        };
+        let stored = match self.changes.get(path) {
+            Some(Change::Insert(data, _)) => return Ok(data.clone()),
+            Some(Change::Remove) => return Err(not_found(true)),
+            Some(Change::Moved(from)) => from.as_str(),
+            None => path,
+        };
+        match &mut self.reader {
+            Some(reader) => reader.read(stored),
+            None => Err(not_found(false)),
+        }
+    }
+
+    /// Stores `data` as `path`, replacing any file there.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `path` is empty or holds a NUL, or `meta` cannot be stored
+    /// in a journaling archive (see [`ArchiveWriter::add_entry`]).
+    pub fn insert(&mut self, path: &str, data: impl Into<Vec<u8>>, meta: EntryMeta) -> Result<()> {
+        check_entry(path, &meta)?;
+        let change = Change::Insert(data.into(), meta);
+        self.changes.insert(path.to_string(), change);
+        Ok(())
+    }
+
+    /// Removes the file stored as `path`, and returns whether there was
+    /// one.  Earlier versions keep it.
+    pub fn remove(&mut self, path: &str) -> bool {
+        let present = self.contains(path);
+        if self.stored(path) {
+            self.changes.insert(path.to_string(), Change::Remove);
+        } else {
+            self.changes.remove(path);
+        }
+        present
+    }
+
+    /// Moves the file stored as `from` to `to`.  When flushed, the new path
+    /// keeps the file's date and attributes and refers to the data already
+    /// in the archive, without compressing it again; earlier versions keep
+    /// the old path.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::NotFound`] if there is no file at `from`, and
+    /// [`ZpaqError::PathExists`] if there is one at `to` and `overwrite`
+    /// is `false`.  Fails as [`insert`](Self::insert) does for a `to` that
+    /// cannot be stored.  Nothing changes on error.
+    pub fn rename(&mut self, from: &str, to: &str, overwrite: bool) -> Result<()> {
+        check_entry(to, &EntryMeta::default())?;
+        if !self.contains(from) {
+            return Err(ZpaqError::NotFound {
+                path: from.to_string(),
+                was_deleted: matches!(self.changes.get(from), Some(Change::Remove)),
+            });
+        }
+        if from == to {
+            return Ok(());
+        }
+        if !overwrite && self.contains(to) {
+            return Err(ZpaqError::PathExists(to.to_string()));
+        }
+        let change = self
+            .changes
+            .remove(from)
+            .unwrap_or_else(|| Change::Moved(from.to_string()));
+        if self.stored(from) {
+            self.changes.insert(from.to_string(), Change::Remove);
+        }
+        match change {
+            // Back where it is stored: unchanged.
+            Change::Moved(stored) if stored == to => {
+                self.changes.remove(to);
+            }
+            change => {
+                self.changes.insert(to.to_string(), change);
+            }
+        }
+        Ok(())
+    }
+
+    /// The versions flushed so far, oldest first; none for an archive
+    /// parsed from a streaming one and not flushed since.
+    pub fn versions(&self) -> Vec<VersionInfo> {
+        let scan = self.reader.as_ref().and_then(|r| r.journal_scan());
+        scan.map(version_list).unwrap_or_default()
+    }
+
+    /// Whether there are changes not flushed yet.
+    pub fn has_changes(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    /// Writes the changes made since the last flush as a new version.
+    /// Nothing is written if there are none.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the method is empty, or if a streaming archive holds a
+    /// file with [`extra`](EntryMeta::extra) metadata, which a journaling
+    /// archive cannot store.  The archive and the changes are left as
+    /// they were.
+    pub fn flush(&mut self) -> Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        let streaming = self.reader.as_ref().is_some_and(|r| r.versions() == 0);
+        let written = if streaming {
+            self.rewrite()
+        } else {
+            self.append()
+        };
+        self.reopen()?;
+        written?;
+        self.changes.clear();
+        Ok(())
+    }
+
+    /// Flushes the changes and returns a copy of the archive.
+    pub fn to_bytes(&mut self) -> Result<Vec<u8>> {
+        self.flush()?;
+        Ok(self.bytes.to_vec())
+    }
+
+    /// Flushes the changes and returns the archive.
+    pub fn into_bytes(mut self) -> Result<Vec<u8>> {
+        self.flush()?;
+        self.reader = None;
+        Ok(Arc::unwrap_or_clone(self.bytes))
+    }
+
+    /// Flushes the changes and writes the archive to `writer`.
+    pub fn write_to(&mut self, mut writer: impl Write) -> Result<()> {
+        self.flush()?;
+        writer.write_all(&self.bytes).map_err(io_err)
+    }
+
+    /// Whether the last flushed version has a file stored as `path`.
+    fn stored(&self, path: &str) -> bool {
+        self.reader.as_ref().is_some_and(|r| r.contains(path))
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        self.reader = None;
+        if !self.bytes.is_empty() {
+            let bytes = Cursor::new(Shared(Arc::clone(&self.bytes)));
+            self.reader = Some(ArchiveReader::open(bytes)?);
+        }
+        Ok(())
+    }
+
+    /// Appends the changes to a journaling (or empty) archive.
+    fn append(&mut self) -> Result<()> {
+        // The reader's copy of the bytes must go before they are changed.
+        self.reader = None;
+        let archive = Arc::make_mut(&mut self.bytes);
+        let (writer, start) = if archive.is_empty() {
+            (
+                ArchiveWriter::new(Cursor::new(&mut *archive), &self.method)?,
+                0,
+            )
+        } else {
+            append_in_memory(archive, &self.method)?
+        };
+        let result = write_changes(writer, &self.changes).map(drop);
+        if result.is_err() {
+            archive.truncate(start);
+        }
+        result
+    }
+
+    /// Rewrites a streaming archive as a journaling one, with the changes.
+    fn rewrite(&mut self) -> Result<()> {
+        let reader = self
+            .reader
+            .as_mut()
+            .expect("a streaming archive has a reader");
+        // Files kept and moved ones are stored anew, as `(path, stored
+        // path, meta)`: there are no fragments to refer to.
+        let mut copied: Vec<(String, String, EntryMeta)> = Vec::new();
+        for entry in reader.entries() {
+            let kept = (!self.changes.contains_key(&entry.path)).then_some(&entry.path);
+            let moved = self
+                .changes
+                .iter()
+                .filter_map(|(path, change)| match change {
+                    Change::Moved(from) if *from == entry.path => Some(path),
+                    _ => None,
+                });
+            for path in kept.into_iter().chain(moved) {
+                copied.push((path.clone(), entry.path.clone(), entry.meta.clone()));
+            }
+        }
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), &self.method)?;
+        for (path, stored, meta) in &copied {
+            let data = reader.read(stored)?;
+            writer.add_entry(path, &data[..], meta)?;
+        }
+        let rest = self
+            .changes
+            .iter()
+            .filter(|(_, change)| !matches!(change, Change::Moved(_)));
+        let rewritten = write_changes(writer, rest)?;
+        self.bytes = Arc::new(rewritten.into_inner());
+        Ok(())
+    }
+}
+
+/// Adds `changes` to the version `writer` is writing and finishes it.
+fn write_changes<'a, W: Write + Seek>(
+    mut writer: ArchiveWriter<W>,
+    changes: impl IntoIterator<Item = (&'a String, &'a Change)>,
+) -> Result<W> {
+    for (path, change) in changes {
+        match change {
+            Change::Insert(data, meta) => writer.add_entry(path, &data[..], meta)?,
+            Change::Remove => writer.delete_entry(path)?,
+            Change::Moved(from) => writer.copy_entry(from, path)?,
+        }
+    }
+    writer.finish()
+}
+
+impl std::fmt::Debug for ZpaqArchive {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ZpaqArchive")
+            .field("method", &self.method)
+            .field("len", &self.bytes.len())
+            .field("changes", &self.changes.len())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{ArchiveEntry, archive_from_entries, zpaq_add, zpaq_command};
+
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn paths(archive: &ZpaqArchive) -> Vec<String> {
+        archive.entries().into_iter().map(|e| e.path).collect()
+    }
+
+    #[test]
+    fn changes_are_flushed_as_versions_the_cli_extracts() {
+        let dir = TempDir::new("zpaq-rs-archive");
+        let mut archive = ZpaqArchive::new("1");
+        let meta = EntryMeta {
+            mtime: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            ..Default::default()
+        };
+        archive
+            .insert("kv/a", "alpha", meta.clone())
+            .expect("insert");
+        archive
+            .insert("kv/b", "bravo", EntryMeta::default())
+            .expect("insert");
+        assert!(archive.insert("", "x", EntryMeta::default()).is_err());
+        assert!(archive.has_changes());
+        assert_eq!(archive.entries()[0].date, None);
+        let first = archive.to_bytes().expect("flush");
+        assert!(!archive.has_changes());
+        assert_eq!(archive.versions().len(), 1);
+
+        // Reparse, change, and see the changes before and after flushing.
+        let mut archive = ZpaqArchive::parse(first.clone()).expect("parse");
+        assert_eq!(archive.entries()[0].meta.mtime, meta.mtime);
+        assert_eq!(archive.entries()[0].date, Some(20231114221320));
+        archive
+            .insert("kv/b", "bravo 2", EntryMeta::default())
+            .expect("insert");
+        archive
+            .insert("kv/c", "charlie", EntryMeta::default())
+            .expect("insert");
+        assert!(archive.remove("kv/a"));
+        assert!(!archive.remove("kv/a"));
+        assert!(archive.remove("kv/c"));
+        assert!(!archive.remove("kv/missing"));
+        assert!(matches!(
+            archive.read("kv/a"),
+            Err(ZpaqError::NotFound {
+                was_deleted: true,
+                ..
+            })
+        ));
+        assert_eq!(archive.read("kv/b").expect("read"), b"bravo 2");
+        assert_eq!(paths(&archive), ["kv/b"]);
+        archive.flush().expect("flush");
+        archive.flush().expect("flush without changes");
+        let second = archive.into_bytes().expect("bytes");
+        assert!(second.starts_with(&first));
+
+        let mut archive = ZpaqArchive::parse(second.clone()).expect("parse");
+        assert_eq!(paths(&archive), ["kv/b"]);
+        assert_eq!(archive.read("kv/b").expect("read"), b"bravo 2");
+        let versions = archive.versions();
+        assert_eq!(versions.len(), 2);
+        assert_eq!((versions[1].added, versions[1].deleted), (1, 1));
+
+        let path = dir.join("kv.zpaq");
+        let mut file = std::fs::File::create(&path).expect("create");
+        archive.write_to(&mut file).expect("write");
+        drop(file);
+        let path = path.to_string_lossy().into_owned();
+        for (until, expected) in [("1", &["a", "b"][..]), ("2", &["b"])] {
+            let out = dir.join(format!("v{until}"));
+            let out_s = out.to_string_lossy().into_owned();
+            zpaq_command(&["extract", &path, "kv", "-to", &out_s, "-until", until])
+                .expect("zpaq extract");
+            let mut found: Vec<String> = std::fs::read_dir(&out)
+                .expect("read dir")
+                .map(|e| e.expect("entry").file_name().to_string_lossy().into_owned())
+                .collect();
+            found.sort();
+            assert_eq!(found, expected);
+        }
+        assert_eq!(std::fs::read(dir.join("v2/b")).expect("read b"), b"bravo 2");
+        assert_eq!(std::fs::read(dir.join("v1/a")).expect("read a"), b"alpha");
+    }
+
+    #[test]
+    fn cli_and_streaming_archives_are_parsed_and_extended() {
+        let dir = TempDir::new("zpaq-rs-archive-parse");
+        let input = dir.join("tree");
+        std::fs::create_dir_all(&input).expect("tree");
+        std::fs::write(input.join("x.txt"), "from the cli").expect("write");
+        let journal = dir.join("cli.zpaq").to_string_lossy().into_owned();
+        zpaq_add(&journal, &[&input.to_string_lossy()], "1", 1).expect("zpaq add");
+
+        let mut archive =
+            ZpaqArchive::parse(std::fs::read(&journal).expect("read")).expect("parse");
+        let stored = paths(&archive)
+            .into_iter()
+            .find(|p| p.ends_with("tree/x.txt"))
+            .expect("stored path");
+        assert_eq!(archive.read(&stored).expect("read"), b"from the cli");
+        archive
+            .insert("added.txt", "by the library", EntryMeta::default())
+            .expect("insert");
+        let bytes = archive.to_bytes().expect("flush");
+        let mut reparsed = ZpaqArchive::parse(bytes).expect("parse");
+        assert_eq!(reparsed.versions().len(), 2);
+        assert_eq!(reparsed.read(&stored).expect("read"), b"from the cli");
+        assert_eq!(reparsed.read("added.txt").expect("read"), b"by the library");
+
+        let entries = [
+            ArchiveEntry {
+                path: "s/one",
+                data: b"one",
+                comment: None,
+            },
+            ArchiveEntry {
+                path: "s/two",
+                data: b"two",
+                comment: None,
+            },
+        ];
+        let streaming = archive_from_entries(&entries, "1").expect("streaming");
+        let mut archive = ZpaqArchive::parse(streaming.clone()).expect("parse");
+        assert!(archive.versions().is_empty());
+        assert_eq!(paths(&archive), ["s/one", "s/two"]);
+        // Unchanged, the bytes are kept as they are.
+        assert_eq!(archive.to_bytes().expect("bytes"), streaming);
+
+        assert!(archive.remove("s/one"));
+        archive
+            .insert("s/three", "three", EntryMeta::default())
+            .expect("insert");
+        let mut rewritten = ZpaqArchive::parse(archive.to_bytes().expect("flush")).expect("parse");
+        assert_eq!(rewritten.versions().len(), 1);
+        assert_eq!(paths(&rewritten), ["s/three", "s/two"]);
+        assert_eq!(rewritten.read("s/two").expect("read"), b"two");
+    }
+
+    #[test]
+    fn renames_are_seen_at_once_and_reuse_stored_data() {
+        let mut archive = ZpaqArchive::new("1");
+        let big = crate::test_support::random(3, 300_000);
+        archive
+            .insert("old/big.bin", big.clone(), EntryMeta::default())
+            .expect("insert");
+        archive
+            .insert("old/note", "note", EntryMeta::default())
+            .expect("insert");
+        let first = archive.to_bytes().expect("flush");
+
+        assert!(matches!(
+            archive.rename("old/none", "new/none", false),
+            Err(ZpaqError::NotFound { .. })
+        ));
+        assert!(matches!(
+            archive.rename("old/note", "old/big.bin", false),
+            Err(ZpaqError::PathExists(_))
+        ));
+        assert!(!archive.has_changes());
+
+        // Renamed there and back: nothing to write.
+        archive.rename("old/note", "tmp", false).expect("rename");
+        archive.rename("tmp", "old/note", false).expect("rename");
+        assert!(!archive.has_changes());
+
+        archive
+            .rename("old/big.bin", "new/big.bin", false)
+            .expect("rename");
+        archive
+            .insert("added", "fresh", EntryMeta::default())
+            .expect("insert");
+        archive.rename("added", "new/added", false).expect("rename");
+        assert_eq!(paths(&archive), ["new/added", "new/big.bin", "old/note"]);
+        assert_eq!(archive.read("new/big.bin").expect("read"), big);
+        assert!(matches!(
+            archive.read("old/big.bin"),
+            Err(ZpaqError::NotFound {
+                was_deleted: true,
+                ..
+            })
+        ));
+        let second = archive.to_bytes().expect("flush");
+        // The moved file's 300 KB are not stored again.
+        assert!(second.len() - first.len() < 10_000);
+
+        let mut reparsed = ZpaqArchive::parse(second).expect("parse");
+        assert_eq!(paths(&reparsed), ["new/added", "new/big.bin", "old/note"]);
+        assert_eq!(reparsed.read("new/big.bin").expect("read"), big);
+        assert_eq!(reparsed.read("new/added").expect("read"), b"fresh");
+        let versions = reparsed.versions();
+        assert_eq!((versions[1].added, versions[1].deleted), (2, 1));
+
+        // A streaming archive is rewritten with the file at its new path.
+        let entries = [ArchiveEntry {
+            path: "s/one",
+            data: b"one",
+            comment: None,
+        }];
+        let streaming = archive_from_entries(&entries, "1").expect("streaming");
+        let mut archive = ZpaqArchive::parse(streaming).expect("parse");
+        archive.rename("s/one", "s/uno", false).expect("rename");
+        let mut rewritten = ZpaqArchive::parse(archive.to_bytes().expect("flush")).expect("parse");
+        assert_eq!(paths(&rewritten), ["s/uno"]);
+        assert_eq!(rewritten.read("s/uno").expect("read"), b"one");
+    }
+}
@@ -0,0 +1,178 @@
+//! Decompressing many small streams one after another with one
+//! decompresser.
+
+use crate::{
+    FfiWriter, Result, SharedVecWriter, clear_last_error, err_from_last, handle, sys, trace,
+};
+
+/// Decompresses each complete ZPAQ stream of `inputs` in turn, calling
+/// `on_output` with its index and its decompressed bytes, and returns the
+/// decompressed size of each.
+///
+/// The output of each input is what [`decompress_to_vec`](crate::decompress_to_vec)
+/// returns for it.  One libzpaq decompresser and one output writer serve
+/// all inputs, which the shim reads from memory, so an input costs no
+/// reader, no size-hint pass and a single call to the writer, where
+/// `decompress_to_vec` sets all of these up anew.  That dominates for
+/// records of a few hundred bytes.  libzpaq still allocates and
+/// initializes the model of every block it decodes, whatever the block
+/// before it held.
+///
+/// # Errors
+///
+/// Stops at the first input that fails to decompress and at the first
+/// error `on_output` returns, and returns that error.  `on_output` has
+/// been called for each input before the failing one, so the number of
+/// calls is the index of the input that failed.
+///
+/// # Example
+///
+/// ```rust
+/// let records: Vec<Vec<u8>> = ["first", "second", "third"]
+///     .iter()
+///     .map(|text| zpaq_rs::compress_to_vec(text.as_bytes(), "1"))
+///     .collect::<zpaq_rs::Result<_>>()?;
+/// let mut texts = Vec::new();
+/// let sizes = zpaq_rs::decompress_many(records.iter().map(Vec::as_slice), |i, data| {
+///     texts.push((i, String::from_utf8_lossy(data).into_owned()));
+///     Ok(())
+/// })?;
+/// assert_eq!(sizes, [5, 6, 5]);
+/// assert_eq!(texts[1], (1, "second".to_string()));
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn decompress_many<'a>(
+    inputs: impl IntoIterator<Item = &'a [u8]>,
+    on_output: impl FnMut(usize, &[u8]) -> Result<()>,
+) -> Result<Vec<u64>> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress("decompress_many");
+    let output = SharedVecWriter::new();
+    let writer = FfiWriter::new(output.clone())?;
+    let decompresser = handle::Decompresser::new()?;
+    decompress_each(decompresser.raw, &writer, &output, inputs, on_output)
+}
+
+fn decompress_each<'a>(
+    decompresser: *mut sys::Decompresser,
+    writer: &FfiWriter<'_>,
+    output: &SharedVecWriter,
+    inputs: impl IntoIterator<Item = &'a [u8]>,
+    mut on_output: impl FnMut(usize, &[u8]) -> Result<()>,
+) -> Result<Vec<u64>> {
+    let mut sizes = Vec::new();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let rc = unsafe {
+            sys::zpaq_decompresser_decompress_bytes(
+                decompresser,
+                input.as_ptr(),
+                input.len(),
+                writer.raw,
+            )
+        };
+        if rc != 0 {
+            return Err(err_from_last());
+        }
+        let data = output.take();
+        on_output(index, &data)?;
+        sizes.push(data.len() as u64);
+    }
+    Ok(sizes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ZpaqError, compress_to_vec, compress_to_vec_parallel, decompress_to_vec};
+
+    fn records() -> Vec<Vec<u8>> {
+        let mut data = vec![
+            Vec::new(),
+            b"x".to_vec(),
+            crate::test_support::text(100_000),
+            crate::test_support::random(7, 3 << 20),
+        ];
+        data.extend((0..20).map(|i| format!("record {i} ").repeat(i).into_bytes()));
+        data
+    }
+
+    fn decompress_all(inputs: &[Vec<u8>]) -> Result<(Vec<Vec<u8>>, Vec<u64>)> {
+        let mut outputs = Vec::new();
+        let sizes = decompress_many(inputs.iter().map(Vec::as_slice), |i, data| {
+            assert_eq!(i, outputs.len());
+            outputs.push(data.to_vec());
+            Ok(())
+        })?;
+        Ok((outputs, sizes))
+    }
+
+    #[test]
+    fn outputs_match_one_call_per_input() {
+        let data = records();
+        let methods = ["0", "1", "2", "3", "x4.3ci1", "10"];
+        let compressed: Vec<Vec<u8>> = data
+            .iter()
+            .enumerate()
+            .map(|(i, d)| compress_to_vec_parallel(d, methods[i % methods.len()], 2).unwrap())
+            .collect();
+        let (outputs, sizes) = decompress_all(&compressed).expect("decompress");
+        assert_eq!(outputs.len(), data.len());
+        for (i, c) in compressed.iter().enumerate() {
+            assert_eq!(
+                outputs[i],
+                decompress_to_vec(c).expect("decompress"),
+                "record {i}"
+            );
+            assert_eq!(outputs[i], data[i]);
+            assert_eq!(sizes[i], data[i].len() as u64);
+        }
+        assert_eq!(decompress_all(&[]).expect("nothing"), (vec![], vec![]));
+    }
+
+    #[test]
+    fn errors_stop_at_the_failing_input() {
+        let good = compress_to_vec(b"intact record", "1").unwrap();
+        let mut bad = compress_to_vec(&crate::test_support::text(10_000), "2").unwrap();
+        bad.truncate(bad.len() / 2);
+        let mut seen = Vec::new();
+        let err = decompress_many([&good[..], &bad, &good], |i, _| {
+            seen.push(i);
+            Ok(())
+        })
+        .expect_err("truncated record");
+        assert!(matches!(err, ZpaqError::Ffi(_)), "{err:?}");
+        assert_eq!(seen, [0]);
+
+        let err = decompress_many([&good[..], &good], |i, _| match i {
+            0 => Ok(()),
+            _ => Err(ZpaqError::Ffi("enough".into())),
+        })
+        .expect_err("callback error");
+        assert_eq!(err.to_string(), ZpaqError::Ffi("enough".into()).to_string());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn tiny_records_cost_one_callback_each() {
+        use crate::StatsCollector;
+
+        let compressed: Vec<Vec<u8>> = (0..10_000)
+            .map(|i| compress_to_vec(format!("tiny record {i}").as_bytes(), "1").unwrap())
+            .collect();
+        let collector = StatsCollector::new();
+        let (naive, naive_stats) = collector.collect(|| {
+            compressed
+                .iter()
+                .map(|c| decompress_to_vec(c))
+                .collect::<Result<Vec<_>>>()
+        });
+        let (batched, stats) = collector.collect(|| decompress_all(&compressed));
+        let (outputs, sizes) = batched.expect("decompress");
+        assert_eq!(outputs, naive.expect("decompress"));
+        assert_eq!(sizes.iter().sum::<u64>(), stats.bytes_written);
+        assert_eq!(stats.read_calls, 0);
+        assert_eq!(stats.write_calls, compressed.len() as u64);
+        let calls = |s: &crate::OperationStats| s.read_calls + s.write_calls;
+        assert!(calls(&naive_stats) >= 4 * calls(&stats), "{naive_stats:?}");
+    }
+}
@@ -0,0 +1,266 @@
+//! `zpaqrs`: a small command-line front end to the `zpaq_rs` library (the
+//! `cli` feature).
+//!
+//! Each subcommand is a thin layer over one public library call; `-` stands
+//! for standard input or output.  Run `zpaqrs` without arguments for usage.
+//!
+//! Everything but `add` works over pipes, with no temporary files:
+//!
+//! ```text
+//! tar c docs | zpaqrs compress -m 3 > docs.tar.zpaq
+//! zpaqrs decompress < docs.tar.zpaq | tar x
+//! ssh host cat backup.zpaq | zpaqrs list -
+//! ssh host cat backup.zpaq | zpaqrs extract - restore
+//! ```
+//!
+//! `list` reads its input once, front to back; `extract -` and `verify -`
+//! read all of it into memory first.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Cursor, Read, Write};
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use zpaq_rs::{
+    ArchiveSpec, MemberFilter, Result, VerifyOptions, ZpaqAddOptions, ZpaqError,
+    archive_extract_reader_to_dir, compress_size_stream, compress_size_stream_parallel,
+    compress_stream, compress_stream_parallel, decompress_stream, list_stream, verify_archive,
+    verify_archive_file, zpaq_add_opts,
+};
+
+const USAGE: &str = "\
+usage: zpaqrs <command> [options] [args]
+
+  compress   [-m METHOD] [-t THREADS] [INPUT] [OUTPUT]
+  decompress [INPUT] [OUTPUT]
+  size       [-m METHOD] [-t THREADS] [INPUT]
+  list       ARCHIVE
+  add        [-m METHOD] [-t THREADS] ARCHIVE INPUT...
+  extract    ARCHIVE DIR [PREFIX]
+  verify     [--deep [--state FILE]] ARCHIVE
+
+INPUT, OUTPUT and ARCHIVE default to or may be `-`, for standard input or
+output; `add` needs an archive file.  METHOD defaults to 1.  `verify --deep`
+records its progress in the --state FILE, and a rerun with it continues
+where an interrupted one stopped.";
+
+/// Why a command failed.
+enum Failure {
+    /// A command-line mistake, reported with the usage text.
+    Usage(String),
+    Zpaq(ZpaqError),
+    /// Reported already; exit with status 1.
+    Reported,
+}
+
+impl From<ZpaqError> for Failure {
+    fn from(e: ZpaqError) -> Self {
+        Failure::Zpaq(e)
+    }
+}
+
+/// Options and operands of a subcommand.
+#[derive(Default)]
+struct Args {
+    method: Option<String>,
+    threads: Option<usize>,
+    deep: bool,
+    state: Option<PathBuf>,
+    operands: Vec<String>,
+}
+
+impl Args {
+    fn parse(mut args: impl Iterator<Item = String>) -> std::result::Result<Self, Failure> {
+        let mut parsed = Args::default();
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| Failure::Usage(format!("{arg} needs a value")))
+            };
+            match arg.as_str() {
+                "-m" | "--method" => parsed.method = Some(value()?),
+                "-t" | "--threads" => {
+                    let threads = value()?;
+                    let threads = threads
+                        .parse()
+                        .map_err(|_| Failure::Usage(format!("bad thread count: {threads}")))?;
+                    parsed.threads = Some(threads);
+                }
+                "--deep" => parsed.deep = true,
+                "--state" => parsed.state = Some(value()?.into()),
+                "--" => parsed.operands.extend(args.by_ref()),
+                flag if flag.len() > 1 && flag.starts_with('-') => {
+                    return Err(Failure::Usage(format!("unknown option: {flag}")));
+                }
+                _ => parsed.operands.push(arg),
+            }
+        }
+        Ok(parsed)
+    }
+
+    fn method(&self) -> &str {
+        self.method.as_deref().unwrap_or("1")
+    }
+
+    /// The operands, checking there are between `min` and `max` of them.
+    fn operands(&self, min: usize, max: usize) -> std::result::Result<&[String], Failure> {
+        let n = self.operands.len();
+        if n < min || n > max {
+            return Err(Failure::Usage("wrong number of arguments".into()));
+        }
+        Ok(&self.operands)
+    }
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{USAGE}");
+        return ExitCode::from(2);
+    };
+    let result = Args::parse(args).and_then(|args| run(&command, &args));
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(Failure::Usage(message)) => {
+            eprintln!("zpaqrs: {message}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+        Err(Failure::Zpaq(e)) => {
+            eprintln!("zpaqrs: {e}");
+            ExitCode::FAILURE
+        }
+        Err(Failure::Reported) => ExitCode::FAILURE,
+    }
+}
+
+fn run(command: &str, args: &Args) -> std::result::Result<(), Failure> {
+    match command {
+        "compress" | "c" => {
+            let operands = args.operands(0, 2)?;
+            let input = input(operands.first())?;
+            let mut output = output(operands.get(1))?;
+            match args.threads {
+                Some(threads) => {
+                    compress_stream_parallel(input, &mut output, args.method(), None, None, threads)
+                }
+                None => compress_stream(input, &mut output, args.method(), None, None),
+            }?;
+            flush(output)
+        }
+        "decompress" | "d" => {
+            let operands = args.operands(0, 2)?;
+            let mut output = output(operands.get(1))?;
+            decompress_stream(input(operands.first())?, &mut output)?;
+            flush(output)
+        }
+        "size" => {
+            let operands = args.operands(0, 1)?;
+            let input = input(operands.first())?;
+            let size = match args.threads {
+                Some(threads) => {
+                    compress_size_stream_parallel(input, args.method(), None, None, threads)
+                }
+                None => compress_size_stream(input, args.method(), None, None),
+            }?;
+            println!("{size}");
+            Ok(())
+        }
+        "list" | "l" => {
+            let operands = args.operands(1, 1)?;
+            let listing = list_stream(input(operands.first())?)?;
+            let mut out = BufWriter::new(std::io::stdout());
+            for entry in &listing.entries {
+                writeln!(out, "{:>12} {}", entry.size, entry.path).map_err(io)?;
+            }
+            flush(out)
+        }
+        "add" | "a" => {
+            let operands = args.operands(2, usize::MAX)?;
+            let options = ZpaqAddOptions {
+                method: args.method.clone().unwrap_or_default(),
+                threads: args.threads.unwrap_or(0),
+                ..Default::default()
+            };
+            let inputs: Vec<&str> = operands[1..].iter().map(String::as_str).collect();
+            let output = zpaq_add_opts(&operands[0], &inputs, &options)?;
+            eprint!("{}{}", output.stdout, output.stderr);
+            Ok(())
+        }
+        "extract" | "x" => {
+            let operands = args.operands(2, 3)?;
+            let filter = operands.get(2).map(|prefix| MemberFilter::Prefix(prefix));
+            let report = match operands[0].as_str() {
+                "-" => {
+                    let archive = Cursor::new(read_stdin()?);
+                    archive_extract_reader_to_dir(archive, &operands[1], filter.as_ref())
+                }
+                path => {
+                    let archive = BufReader::new(ArchiveSpec::parse(path)?.open()?);
+                    archive_extract_reader_to_dir(archive, &operands[1], filter.as_ref())
+                }
+            }?;
+            for (path, size) in &report.written {
+                println!("{size:>12} {}", path.display());
+            }
+            for rejected in &report.rejected {
+                eprintln!("zpaqrs: skipped {rejected}");
+            }
+            Ok(())
+        }
+        "verify" | "v" => {
+            let operands = args.operands(1, 1)?;
+            let options = VerifyOptions {
+                deep: args.deep,
+                persist_state: args.state.clone(),
+                ..Default::default()
+            };
+            let report = match operands[0].as_str() {
+                "-" => verify_archive(&read_stdin()?, &options),
+                path => verify_archive_file(path, &options),
+            }?;
+            print!("{report}");
+            if report.is_ok() {
+                Ok(())
+            } else {
+                Err(Failure::Reported)
+            }
+        }
+        _ => Err(Failure::Usage(format!("unknown command: {command}"))),
+    }
+}
+
+fn input(operand: Option<&String>) -> Result<Box<dyn Read + Send>> {
+    match operand.map(String::as_str) {
+        None | Some("-") => Ok(Box::new(std::io::stdin())),
+        Some(path) => Ok(Box::new(BufReader::new(
+            File::open(path).map_err(|e| open(path, e))?,
+        ))),
+    }
+}
+
+fn output(operand: Option<&String>) -> Result<BufWriter<Box<dyn Write + Send>>> {
+    let out: Box<dyn Write + Send> = match operand.map(String::as_str) {
+        None | Some("-") => Box::new(std::io::stdout()),
+        Some(path) => Box::new(File::create(path).map_err(|e| open(path, e))?),
+    };
+    Ok(BufWriter::new(out))
+}
+
+fn read_stdin() -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    std::io::stdin().read_to_end(&mut data).map_err(io)?;
+    Ok(data)
+}
+
+fn flush(mut out: impl Write) -> std::result::Result<(), Failure> {
+    out.flush().map_err(io)?;
+    Ok(())
+}
+
+fn open(path: &str, e: std::io::Error) -> ZpaqError {
+    ZpaqError::Ffi(format!("{path}: {e}"))
+}
+
+fn io(e: std::io::Error) -> ZpaqError {
+    ZpaqError::Ffi(e.to_string())
+}
@@ -0,0 +1,565 @@
+//! Block and segment inspection for ZPAQ streams.
+
+use std::io::Read;
+use std::ptr;
+
+use crate::{
+    Digest, FfiReader, FfiWriter, Result, STREAM_INDEX_FILENAME, SharedVecWriter, clear_last_error,
+    display, err_from_last, handle, sys,
+};
+
+/// Metadata for one ZPAQ block, as returned by [`list_blocks`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockInfo {
+    /// Memory libzpaq needs to decompress this block, in bytes.
+    pub memory_bytes: f64,
+    /// Number of context-model components declared in the block header.
+    pub components: usize,
+    /// Length of the block's HCOMP: the header, component list and context
+    /// computation code, as libzpaq stores it.
+    pub hcomp_len: usize,
+    /// SHA-256 of the HCOMP bytes.  Blocks compressed with the same model
+    /// share a fingerprint.  Numeric methods pick the model after looking at
+    /// the data (text, executable code, ...), so blocks of one method can
+    /// still differ.
+    pub model_fingerprint: [u8; 32],
+    /// Whether the block carries a PCOMP postprocessor.
+    pub has_pcomp: bool,
+    /// `true` for store blocks: the header declares no model components and
+    /// no postprocessor, so segment data is kept verbatim.
+    pub stored: bool,
+    /// Segments contained in the block, in stream order.
+    pub segments: Vec<SegmentInfo>,
+}
+
+/// A summary line, then one indented line per segment, sizes aligned.  A
+/// precision (`{:.40}`) shortens long filenames.
+impl std::fmt::Display for BlockInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} block, {}, {:.0} bytes of memory, {}",
+            if self.stored { "stored" } else { "modeled" },
+            display::count(self.components as u64, "component"),
+            self.memory_bytes,
+            display::count(self.segments.len() as u64, "segment")
+        )?;
+        let width = display::size_width(self.segments.iter().map(|segment| segment.size));
+        for segment in &self.segments {
+            f.write_str("  ")?;
+            segment.write_row(f, width, f.precision())?;
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Metadata for one segment inside a [`BlockInfo`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SegmentInfo {
+    /// Stored segment filename (empty if none), lossily decoded as UTF-8.
+    pub filename: String,
+    /// Stored segment comment (empty if none), lossily decoded as UTF-8.
+    pub comment: String,
+    /// Stored segment filename exactly as written.
+    pub filename_bytes: Vec<u8>,
+    /// Stored segment comment exactly as written.
+    pub comment_bytes: Vec<u8>,
+    /// Decompressed size of the segment in bytes.
+    pub size: u64,
+    /// SHA-1 from the segment trailer, if one was written.
+    pub sha1: Option<[u8; 20]>,
+    /// Checksum the comment ends with, if the segment was written with
+    /// [`CompressOptions::extra_checksum`](crate::CompressOptions::extra_checksum).
+    pub extra_checksum: Option<Digest>,
+    /// Copied from the enclosing block's [`BlockInfo::stored`].
+    pub stored: bool,
+}
+
+impl SegmentInfo {
+    fn write_row(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+        width: usize,
+        max_path: Option<usize>,
+    ) -> std::fmt::Result {
+        let sha1 = self
+            .sha1
+            .map_or_else(|| "-".to_string(), |sha1| display::hex(&sha1));
+        let filename = match self.filename.as_str() {
+            "" => "-",
+            filename => filename,
+        };
+        write!(
+            f,
+            "{:>width$}  {sha1:<40}  {}",
+            self.size,
+            display::shorten(filename, max_path)
+        )?;
+        if !self.comment.is_empty() {
+            write!(f, "  ({})", self.comment)?;
+        }
+        Ok(())
+    }
+}
+
+/// Size, SHA-1, filename and comment on one line; a width (`{:8}`) aligns
+/// the size and a precision (`{:.40}`) shortens long filenames.
+impl std::fmt::Display for SegmentInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_row(f, f.width().unwrap_or(0), f.precision())
+    }
+}
+
+/// Lists every block and segment in an in-memory ZPAQ stream.
+///
+/// Wrapper around [`list_blocks_stream`] with a [`std::io::Cursor`].
+///
+/// # Example
+///
+/// ```rust
+/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "0").unwrap();
+/// let blocks = zpaq_rs::list_blocks(&c).unwrap();
+/// assert!(blocks[0].stored);
+/// assert_eq!(blocks[0].segments[0].size, 10);
+/// ```
+pub fn list_blocks(archive: &[u8]) -> Result<Vec<BlockInfo>> {
+    list_blocks_stream(std::io::Cursor::new(archive))
+}
+
+/// Lists every block and segment in the ZPAQ stream from `reader`.
+///
+/// Each segment is decoded to obtain its size, so the cost is that of a full
+/// decompression, without the cost of passing any output back to Rust.
+pub fn list_blocks_stream<R: Read + Send>(reader: R) -> Result<Vec<BlockInfo>> {
+    clear_last_error()?;
+    let reader = FfiReader::new(reader)?;
+    let decompresser = handle::Decompresser::new()?;
+    read_blocks(decompresser.raw, reader.raw)
+}
+
+fn read_blocks(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+) -> Result<Vec<BlockInfo>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+
+    let mut blocks = Vec::new();
+    loop {
+        let mut memory_bytes = 0.0f64;
+        let rc_block =
+            unsafe { sys::zpaq_decompresser_find_block(decompresser, &mut memory_bytes) };
+        if rc_block < 0 {
+            return Err(err_from_last());
+        }
+        if rc_block == 0 {
+            break;
+        }
+
+        let mut block = block_header(decompresser, memory_bytes)?;
+
+        loop {
+            let filename = SharedVecWriter::new();
+            let filename_writer = FfiWriter::new(filename.clone())?;
+            let rc_filename =
+                unsafe { sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw) };
+            if rc_filename < 0 {
+                return Err(err_from_last());
+            }
+            if rc_filename == 0 {
+                break;
+            }
+            drop(filename_writer);
+
+            let comment = SharedVecWriter::new();
+            let comment_writer = FfiWriter::new(comment.clone())?;
+            if unsafe { sys::zpaq_decompresser_read_comment(decompresser, comment_writer.raw) } != 0
+            {
+                return Err(err_from_last());
+            }
+            drop(comment_writer);
+
+            if block.segments.is_empty() {
+                // Loads the PCOMP (if any) without producing output.
+                if unsafe { sys::zpaq_decompresser_set_output(decompresser, ptr::null_mut()) } != 0
+                    || unsafe { sys::zpaq_decompresser_decompress(decompresser, 0) } < 0
+                {
+                    return Err(err_from_last());
+                }
+                let has_pcomp = unsafe { sys::zpaq_decompresser_has_pcomp(decompresser) };
+                if has_pcomp < 0 {
+                    return Err(err_from_last());
+                }
+                block.has_pcomp = has_pcomp != 0;
+                block.stored = block.components == 0 && !block.has_pcomp;
+            }
+
+            let size = skip_segment(decompresser)?;
+            let mut segment_end = [0u8; 21];
+            let rc_end = unsafe {
+                sys::zpaq_decompresser_read_segment_end(decompresser, segment_end.as_mut_ptr())
+            };
+            if rc_end != 0 {
+                return Err(err_from_last());
+            }
+
+            let sha1 = (segment_end[0] == 1).then(|| {
+                let mut digest = [0u8; 20];
+                digest.copy_from_slice(&segment_end[1..]);
+                digest
+            });
+            let filename_bytes = trimmed(filename.bytes());
+            let comment_bytes = trimmed(comment.bytes());
+            block.segments.push(SegmentInfo {
+                filename: String::from_utf8_lossy(&filename_bytes).into_owned(),
+                comment: String::from_utf8_lossy(&comment_bytes).into_owned(),
+                extra_checksum: comment_checksum(&comment_bytes),
+                filename_bytes,
+                comment_bytes,
+                size,
+                sha1,
+                stored: block.stored,
+            });
+        }
+
+        blocks.push(block);
+    }
+
+    Ok(blocks)
+}
+
+/// The [`BlockInfo`] of the block just found, without its segments and
+/// before its postprocessor is known.
+fn block_header(decompresser: *mut sys::Decompresser, memory_bytes: f64) -> Result<BlockInfo> {
+    let components = unsafe { sys::zpaq_decompresser_components(decompresser) };
+    if components < 0 {
+        return Err(err_from_last());
+    }
+    let mut hcomp_len = 0u64;
+    let mut model_fingerprint = [0u8; 32];
+    let rc = unsafe {
+        sys::zpaq_decompresser_hcomp_fingerprint(
+            decompresser,
+            &mut hcomp_len,
+            model_fingerprint.as_mut_ptr(),
+        )
+    };
+    if rc != 0 {
+        return Err(err_from_last());
+    }
+    Ok(BlockInfo {
+        memory_bytes,
+        components: components as usize,
+        hcomp_len: hcomp_len as usize,
+        model_fingerprint,
+        has_pcomp: false,
+        stored: components == 0,
+        segments: Vec::new(),
+    })
+}
+
+/// Reads the header of the first block of the ZPAQ stream in `archive`,
+/// or `None` if it has no block.
+///
+/// Only the block header and the first segment's postprocessor are decoded,
+/// so this is cheap whatever the size of the block.  `segments` is left
+/// empty; [`list_blocks`] fills it.
+///
+/// # Example
+///
+/// ```rust
+/// let a = zpaq_rs::compress_to_vec(b"some text", "3")?;
+/// let b = zpaq_rs::compress_to_vec(b"other text", "3")?;
+/// let a = zpaq_rs::peek_block(&a)?.expect("block");
+/// let b = zpaq_rs::peek_block(&b)?.expect("block");
+/// assert_eq!(a.model_fingerprint, b.model_fingerprint);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn peek_block(archive: &[u8]) -> Result<Option<BlockInfo>> {
+    clear_last_error()?;
+    let reader = FfiReader::new(archive)?;
+    let decompresser = handle::Decompresser::new()?;
+    read_first_block(decompresser.raw, reader.raw)
+}
+
+fn read_first_block(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+) -> Result<Option<BlockInfo>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let mut memory_bytes = 0.0f64;
+    let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, &mut memory_bytes) };
+    if rc_block < 0 {
+        return Err(err_from_last());
+    }
+    if rc_block == 0 {
+        return Ok(None);
+    }
+    let mut block = block_header(decompresser, memory_bytes)?;
+    let rc_filename =
+        unsafe { sys::zpaq_decompresser_find_filename(decompresser, ptr::null_mut()) };
+    if rc_filename < 0 {
+        return Err(err_from_last());
+    }
+    if rc_filename > 0 {
+        // Loads the PCOMP (if any) without producing output.
+        let has_pcomp = unsafe {
+            if sys::zpaq_decompresser_read_comment(decompresser, ptr::null_mut()) != 0
+                || sys::zpaq_decompresser_set_output(decompresser, ptr::null_mut()) != 0
+                || sys::zpaq_decompresser_decompress(decompresser, 0) < 0
+            {
+                return Err(err_from_last());
+            }
+            sys::zpaq_decompresser_has_pcomp(decompresser)
+        };
+        if has_pcomp < 0 {
+            return Err(err_from_last());
+        }
+        block.has_pcomp = has_pcomp != 0;
+        block.stored = block.components == 0 && !block.has_pcomp;
+    }
+    Ok(Some(block))
+}
+
+/// The decompressed size of the ZPAQ stream in `archive` as its segment
+/// comments declare it, without decoding any data.
+///
+/// `zpaq` and this crate start every segment comment with the segment's
+/// size; `None` if some segment does not.  An embedded stream index (see
+/// [`STREAM_INDEX_FILENAME`]) is left out, as decompression leaves it out.  The comments are not checked
+/// against the data, so treat the result as a hint.
+///
+/// # Example
+///
+/// ```rust
+/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "1").unwrap();
+/// assert_eq!(zpaq_rs::original_size_hint(&c).unwrap(), Some(10));
+/// ```
+pub fn original_size_hint(archive: &[u8]) -> Result<Option<u64>> {
+    clear_last_error()?;
+    let reader = FfiReader::new(std::io::Cursor::new(archive))?;
+    let decompresser = handle::Decompresser::new()?;
+    sum_comment_sizes(decompresser.raw, reader.raw)
+}
+
+fn sum_comment_sizes(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+) -> Result<Option<u64>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let mut total = 0u64;
+    loop {
+        let rc_block =
+            unsafe { sys::zpaq_decompresser_find_block(decompresser, std::ptr::null_mut()) };
+        if rc_block < 0 {
+            return Err(err_from_last());
+        }
+        if rc_block == 0 {
+            return Ok(Some(total));
+        }
+        loop {
+            let filename = SharedVecWriter::new();
+            let filename_writer = FfiWriter::new(filename.clone())?;
+            let rc_filename =
+                unsafe { sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw) };
+            drop(filename_writer);
+            if rc_filename < 0 {
+                return Err(err_from_last());
+            }
+            if rc_filename == 0 {
+                break;
+            }
+            let comment = SharedVecWriter::new();
+            let comment_writer = FfiWriter::new(comment.clone())?;
+            if unsafe { sys::zpaq_decompresser_read_comment(decompresser, comment_writer.raw) } != 0
+            {
+                return Err(err_from_last());
+            }
+            drop(comment_writer);
+            // An embedded stream index does not decompress to output.
+            if filename.bytes() != STREAM_INDEX_FILENAME.as_bytes() {
+                let Some(size) = comment_size(&comment.bytes()) else {
+                    return Ok(None);
+                };
+                total = total.saturating_add(size);
+            }
+            // Skips the segment's data without decoding it.
+            let rc_end = unsafe {
+                sys::zpaq_decompresser_read_segment_end(decompresser, std::ptr::null_mut())
+            };
+            if rc_end != 0 {
+                return Err(err_from_last());
+            }
+        }
+    }
+}
+
+/// The size a segment comment starts with: digits up to the end or a space.
+pub(crate) fn comment_size(comment: &[u8]) -> Option<u64> {
+    let digits = comment.split(|&b| b == b' ').next()?;
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+/// The checksum [`CompressOptions::extra_checksum`](crate::CompressOptions::extra_checksum)
+/// ends a comment with: `sha1:` or `sha256:` and the digest in hex.
+pub(crate) fn comment_checksum(comment: &[u8]) -> Option<Digest> {
+    let end = comment.iter().rposition(|&b| b != 0)? + 1;
+    let word = comment[..end].rsplit(|&b| b == b' ').next()?;
+    let word = std::str::from_utf8(word).ok()?;
+    let (kind, digits) = word.split_once(':')?;
+    let mut digest = match kind {
+        "sha1" => Digest::Sha1([0; 20]),
+        "sha256" => Digest::Sha256([0; 32]),
+        _ => return None,
+    };
+    let bytes = match &mut digest {
+        Digest::Sha1(bytes) => &mut bytes[..],
+        Digest::Sha256(bytes) => &mut bytes[..],
+    };
+    if digits.len() != 2 * bytes.len() || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    for (byte, pair) in bytes.iter_mut().zip(digits.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+/// Decodes the rest of the current segment, discarding the data inside the
+/// shim, and returns its size.
+pub(crate) fn skip_segment(decompresser: *mut sys::Decompresser) -> Result<u64> {
+    let mut size = 0u64;
+    if unsafe { sys::zpaq_decompresser_skip_segment(decompresser, &mut size) } != 0 {
+        return Err(err_from_last());
+    }
+    Ok(size)
+}
+
+fn trimmed(mut bytes: Vec<u8>) -> Vec<u8> {
+    while bytes.last().copied() == Some(0) {
+        bytes.pop();
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, sha1, test_support};
+
+    #[test]
+    fn store_blocks_are_flagged_stored() {
+        let data = test_support::text(50_000);
+        let c = compress_to_vec(&data, "0").expect("compress");
+        let blocks = list_blocks(&c).expect("list_blocks");
+        assert_eq!(blocks.len(), 1);
+        assert!(blocks[0].stored);
+        assert_eq!(blocks[0].components, 0);
+        assert!(!blocks[0].has_pcomp);
+        let seg = &blocks[0].segments[0];
+        assert!(seg.stored);
+        assert_eq!(seg.size, data.len() as u64);
+        assert_eq!(seg.sha1, Some(sha1(&data).expect("sha1")));
+    }
+
+    #[test]
+    fn compressed_blocks_are_not_stored() {
+        let data = test_support::text(50_000);
+        for method in ["1", "3"] {
+            let c = compress_to_vec(&data, method).expect("compress");
+            let blocks = list_blocks(&c).expect("list_blocks");
+            assert_eq!(blocks.len(), 1, "method={method}");
+            assert!(!blocks[0].stored, "method={method}");
+            assert!(!blocks[0].segments[0].stored, "method={method}");
+            assert_eq!(blocks[0].segments[0].size, data.len() as u64);
+        }
+    }
+
+    #[test]
+    fn store_fast_path_roundtrips_across_chunks() {
+        // Straddles the 64 KiB chunk boundary of the unmodeled encoder.
+        for len in [0usize, 1, 65_535, 65_536, 200_000] {
+            let data: Vec<u8> = (0..len).map(|i| (i * 7 % 256) as u8).collect();
+            let c = compress_to_vec(&data, "0").expect("compress");
+            assert_eq!(crate::decompress_to_vec(&c).expect("decompress"), data);
+        }
+    }
+
+    #[test]
+    fn size_hint_sums_segment_comments() {
+        // Method "00" uses 1 MiB blocks: three segments here.
+        let data: Vec<u8> = (0..2_500_000u32).map(|i| (i % 253) as u8).collect();
+        let c = compress_to_vec(&data, "00").expect("compress");
+        assert_eq!(list_blocks(&c).expect("list_blocks").len(), 3);
+        assert_eq!(
+            original_size_hint(&c).expect("hint"),
+            Some(data.len() as u64)
+        );
+        assert_eq!(original_size_hint(&[]).expect("hint"), Some(0));
+
+        assert_eq!(comment_size(b"12 jDC\x01"), Some(12));
+        assert_eq!(comment_size(b"12"), Some(12));
+        assert_eq!(comment_size(b"12x"), None);
+        assert_eq!(comment_size(b""), None);
+    }
+
+    #[test]
+    fn byte_metadata_roundtrips_through_list_blocks() {
+        let name = b"dir/\xff\xfe-na\xefve.bin";
+        let comment = b"\x80 raw";
+        for threads in [1, 2] {
+            let mut c = Vec::new();
+            crate::compress_stream_parallel_bytes(
+                std::io::Cursor::new(b"payload"),
+                &mut c,
+                "1",
+                Some(name),
+                Some(comment),
+                threads,
+            )
+            .expect("compress");
+            let seg = &list_blocks(&c).expect("list_blocks")[0].segments[0];
+            assert_eq!(seg.filename_bytes, name);
+            assert_eq!(seg.filename, String::from_utf8_lossy(name));
+            // libzpaq prefixes the comment with the segment size.
+            assert_eq!(seg.comment_bytes, b"7 \x80 raw");
+        }
+
+        let err = crate::compress_stream_bytes(
+            std::io::Cursor::new(b"payload"),
+            Vec::new(),
+            "1",
+            Some(b"a\0b"),
+            None,
+        )
+        .expect_err("NUL cannot be stored in a segment header");
+        assert!(matches!(err, crate::ZpaqError::NulInString));
+    }
+
+    #[test]
+    fn fingerprints_group_blocks_by_model() {
+        let data = test_support::text(50_000);
+        let fingerprint = |input: &[u8], method: &str| {
+            let c = compress_to_vec(input, method).expect("compress");
+            let peeked = peek_block(&c).expect("peek").expect("block");
+            let listed = &list_blocks(&c).expect("list_blocks")[0];
+            assert_eq!(peeked.model_fingerprint, listed.model_fingerprint);
+            assert_eq!(peeked.hcomp_len, listed.hcomp_len);
+            assert_eq!(peeked.has_pcomp, listed.has_pcomp);
+            assert!(peeked.segments.is_empty());
+            peeked.model_fingerprint
+        };
+        let level3 = fingerprint(&data, "3");
+        assert_eq!(fingerprint(&data[..20_000], "3"), level3);
+        assert_eq!(fingerprint(b"unrelated", "3"), level3);
+        assert_ne!(fingerprint(&data, "1"), level3);
+        assert_ne!(fingerprint(&data, "0"), level3);
+        assert_eq!(peek_block(&[]).expect("peek"), None);
+    }
+}
@@ -0,0 +1,188 @@
+//! Which bytes of a compressed size are framing, and how the sizes of the
+//! streaming and journaling formats relate.
+
+use std::fs::File;
+use std::io::Read;
+
+use crate::{
+    Result, ZPAQ_TAG, ZpaqError, compress_block_sizes, compress_size_stream_as,
+    zpaq_add_archive_size_file,
+};
+
+/// Bytes of the SHA-1 a segment trailer carries: the trailer is marker 253
+/// and the digest, or marker 254 alone without one.
+const SHA1_TRAILER: u64 = 20;
+
+/// The sizes behind [`compress_size`](crate::compress_size), as computed by
+/// [`explain_size`] and [`explain_size_file`].
+///
+/// `raw_stream` is the whole streaming archive; `tag_overhead`,
+/// `checksum_overhead` and `segment_header` are parts of it, and
+/// [`payload`](Self::payload) is the rest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeBreakdown {
+    /// Size of the streaming archive [`compress_to_vec`](crate::compress_to_vec)
+    /// and [`compress_stream`](crate::compress_stream) write, with no
+    /// segment filename or comment: what [`compress_size`](crate::compress_size)
+    /// returns.
+    pub raw_stream: u64,
+    /// Blocks in the streaming archive, one segment each.
+    pub blocks: u64,
+    /// The 13-byte locator tag before each block, which lets readers find
+    /// blocks in other data.
+    pub tag_overhead: u64,
+    /// The SHA-1 of each segment's data in its trailer; `raw_stream` less
+    /// this is the size without checksums.
+    pub checksum_overhead: u64,
+    /// Each segment's header: a marker byte, the empty filename and the
+    /// comment holding the segment's size in decimal, each ended by a NUL,
+    /// and a reserved byte.
+    pub segment_header: u64,
+    /// Size of the journaling archive `zpaq add` would create for the file
+    /// (see [`zpaq_add_archive_size_file`]), only for
+    /// [`explain_size_file`].  It splits the file into deduplicated
+    /// fragments with its own blocks and adds an index, so it is not the
+    /// sum of the parts above.
+    pub journaling_archive: Option<u64>,
+}
+
+impl SizeBreakdown {
+    /// The rest of `raw_stream`: block headers (the model), coded data and
+    /// the end-of-segment and end-of-block markers.
+    pub fn payload(&self) -> u64 {
+        self.raw_stream - self.tag_overhead - self.checksum_overhead - self.segment_header
+    }
+}
+
+/// Breaks the compressed size of `input` with `method` into its framing.
+///
+/// Compresses `input` three times: as [`compress_size`](crate::compress_size)
+/// does, without checksums, and block by block to count the blocks and
+/// their segment headers.  `journaling_archive` is `None`.
+///
+/// # Example
+///
+/// ```rust
+/// let data = b"explain where the bytes go. ".repeat(1000);
+/// let sizes = zpaq_rs::explain_size(&data, "1")?;
+/// assert_eq!(sizes.raw_stream, zpaq_rs::compress_size(&data, "1")?);
+/// assert_eq!(sizes.tag_overhead, 13 * sizes.blocks);
+/// assert_eq!(sizes.checksum_overhead, 20 * sizes.blocks);
+/// // A marker byte, "28000", two NULs and a reserved byte.
+/// assert_eq!(sizes.segment_header, 9);
+/// assert_eq!(
+///     sizes.payload() + sizes.tag_overhead + sizes.checksum_overhead + sizes.segment_header,
+///     sizes.raw_stream
+/// );
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn explain_size(input: &[u8], method: &str) -> Result<SizeBreakdown> {
+    breakdown(|| Ok(input), method)
+}
+
+/// [`explain_size`] for the file at `path`, read once per pass, with
+/// `journaling_archive` set to what [`zpaq_add_archive_size_file`] returns
+/// for it.
+///
+/// # Errors
+///
+/// Fails if the file cannot be read, or as [`explain_size`] and
+/// [`zpaq_add_archive_size_file`] do.
+pub fn explain_size_file(path: &str, method: &str) -> Result<SizeBreakdown> {
+    let open = || File::open(path).map_err(|e| ZpaqError::Ffi(format!("open {path} failed: {e}")));
+    let mut sizes = breakdown(open, method)?;
+    sizes.journaling_archive = Some(zpaq_add_archive_size_file(path, method, 0)?);
+    Ok(sizes)
+}
+
+fn breakdown<R: Read + Send>(open: impl Fn() -> Result<R>, method: &str) -> Result<SizeBreakdown> {
+    let raw_stream = compress_size_stream_as(open()?, method, None, None, true)?;
+    let unchecked = compress_size_stream_as(open()?, method, None, None, false)?;
+    let segments = compress_block_sizes(open()?, method, None, 1)?;
+    let blocks = segments.len() as u64;
+    let segment_header = segments
+        .iter()
+        .map(|segment| 4 + segment.uncompressed.to_string().len() as u64)
+        .sum();
+    let checksum_overhead = raw_stream - unchecked;
+    debug_assert_eq!(checksum_overhead, SHA1_TRAILER * blocks);
+    Ok(SizeBreakdown {
+        raw_stream,
+        blocks,
+        tag_overhead: ZPAQ_TAG.len() as u64 * blocks,
+        checksum_overhead,
+        segment_header,
+        journaling_archive: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, list_blocks, test_support};
+
+    /// Counts the framing of an actual streaming archive.
+    fn observed(compressed: &[u8]) -> (u64, u64, u64) {
+        let blocks = list_blocks(compressed).expect("blocks");
+        let tags = compressed
+            .windows(ZPAQ_TAG.len())
+            .filter(|window| *window == ZPAQ_TAG)
+            .count() as u64;
+        let headers = blocks
+            .iter()
+            .flat_map(|block| &block.segments)
+            .map(|segment| 4 + (segment.filename_bytes.len() + segment.comment_bytes.len()) as u64)
+            .sum();
+        (blocks.len() as u64, tags * ZPAQ_TAG.len() as u64, headers)
+    }
+
+    #[test]
+    fn parts_match_the_compressed_stream() {
+        let mut multi_block = test_support::text(1 << 20);
+        multi_block.extend(test_support::random(1, 1 << 20));
+        for (data, method) in [
+            (Vec::new(), "1"),
+            (b"hello zpaq".to_vec(), "0"),
+            (test_support::text(100_000), "2"),
+            (multi_block, "10"),
+        ] {
+            let sizes = explain_size(&data, method).expect("explain");
+            let compressed = compress_to_vec(&data, method).expect("compress");
+            assert_eq!(sizes.raw_stream, compressed.len() as u64);
+            let (blocks, tags, headers) = observed(&compressed);
+            assert_eq!(sizes.blocks, blocks, "{sizes:?}");
+            assert_eq!(sizes.tag_overhead, tags);
+            assert_eq!(sizes.segment_header, headers);
+            assert_eq!(sizes.checksum_overhead, 20 * blocks);
+            assert_eq!(
+                sizes.payload()
+                    + sizes.tag_overhead
+                    + sizes.checksum_overhead
+                    + sizes.segment_header,
+                sizes.raw_stream
+            );
+            assert_eq!(sizes.journaling_archive, None);
+        }
+    }
+
+    #[test]
+    fn files_add_the_journaling_archive() {
+        let dir = test_support::TempDir::new("zpaq-rs-breakdown");
+        let path = dir.join("input.txt");
+        let data = test_support::text(300_000);
+        std::fs::write(&path, &data).expect("write");
+        let path_str = path.to_string_lossy().into_owned();
+        let sizes = explain_size_file(&path_str, "1").expect("explain");
+        assert_eq!(
+            sizes,
+            SizeBreakdown {
+                journaling_archive: Some(
+                    zpaq_add_archive_size_file(&path_str, "1", 0).expect("archive size")
+                ),
+                ..explain_size(&data, "1").expect("explain")
+            }
+        );
+        std::fs::remove_file(&path).expect("remove");
+        assert!(explain_size_file(&path_str, "1").is_err());
+    }
+}
@@ -0,0 +1,67 @@
+//! Stopping a long operation from another thread.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{Result, ZpaqError};
+
+/// A flag that stops an operation given it, such as
+/// [`verify_archive`](crate::verify_archive) with
+/// [`VerifyOptions::cancel`](crate::VerifyOptions::cancel), at its next
+/// block.
+///
+/// Clones share the flag, so one clone can be handed to the operation and
+/// another kept to cancel it, from any thread.  Tokens compare equal when
+/// they share a flag.  An operation that stops returns
+/// [`ZpaqError::Cancelled`].
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{CancellationToken, VerifyOptions, ZpaqError};
+///
+/// let archive = zpaq_rs::compress_to_vec(b"data", "1")?;
+/// let token = CancellationToken::new();
+/// let options = VerifyOptions { cancel: Some(token.clone()), ..Default::default() };
+/// token.cancel();
+/// assert!(matches!(
+///     zpaq_rs::verify_archive(&archive, &options),
+///     Err(ZpaqError::Cancelled)
+/// ));
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    /// A token not yet cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the operations holding a clone of this token to stop.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether [`cancel`](Self::cancel) was called on this token or a clone.
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// `Err(Cancelled)` once the token is cancelled.
+    pub(crate) fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(ZpaqError::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for CancellationToken {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for CancellationToken {}
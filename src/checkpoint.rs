@@ -0,0 +1,217 @@
+//! Compression that records a checkpoint after every block.
+//!
+//! ZPAQ blocks are independent, and a stream may end after any block, so a
+//! compression cut short keeps every block it completed.  Continuing it only
+//! needs the input position and the output length of the last complete
+//! block: [`CheckpointingCompressor::resume`] starts a fresh block there and
+//! the result decompresses like an uninterrupted run.
+
+use std::io::{Read, Write};
+
+use crate::method::log_block_size;
+use crate::{Result, compress_stream, io_err};
+
+/// Progress of a [`CheckpointingCompressor`] after a complete block.
+///
+/// With the `serde` feature the checkpoint serializes as an object with the
+/// three fields below, so it can be saved between runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CompressCheckpoint {
+    /// Index of the block just completed, counting from 0 at the start of
+    /// the stream.
+    pub block_index: u64,
+    /// Input bytes compressed so far, up to the end of that block.
+    pub input_bytes_consumed: u64,
+    /// Compressed bytes written so far, up to the end of that block.
+    pub output_bytes_emitted: u64,
+}
+
+/// Compresses a stream block by block, reporting a [`CompressCheckpoint`]
+/// after each block so that an interrupted run can be continued.
+///
+/// The input is cut at the method's block size, as
+/// [`compress_stream`](crate::compress_stream) cuts it, and an uninterrupted
+/// run writes the same bytes.  Each block is compressed in memory and
+/// written to the output whole, then the output is flushed before the
+/// checkpoint is reported.
+///
+/// To continue after a crash, truncate the output to the last checkpoint's
+/// `output_bytes_emitted` (dropping any partly written block), position the
+/// input at its `input_bytes_consumed`, and compress with
+/// [`resume`](Self::resume).
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::CheckpointingCompressor;
+///
+/// let data = vec![5u8; 3 << 20];
+/// let mut out = Vec::new();
+/// let mut checkpoints = Vec::new();
+/// // Method "10" uses 1 MiB blocks.
+/// CheckpointingCompressor::new("10").compress(&data[..], &mut out, |c| {
+///     checkpoints.push(c);
+///     Ok(())
+/// })?;
+/// assert_eq!(checkpoints.len(), 4);
+/// assert_eq!(checkpoints[3].output_bytes_emitted, out.len() as u64);
+/// assert_eq!(zpaq_rs::decompress_to_vec(&out)?, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct CheckpointingCompressor {
+    method: String,
+    last: Option<CompressCheckpoint>,
+}
+
+impl CheckpointingCompressor {
+    /// Compresses a new stream with `method`.
+    pub fn new(method: &str) -> Self {
+        CheckpointingCompressor {
+            method: method.to_string(),
+            last: None,
+        }
+    }
+
+    /// Continues the stream `checkpoint` was taken from.
+    ///
+    /// The input passed to [`compress`](Self::compress) must start at
+    /// `checkpoint.input_bytes_consumed` and the output must hold exactly
+    /// `checkpoint.output_bytes_emitted` bytes; later checkpoints count on
+    /// from `checkpoint`.  `method` need not be the one used before.
+    pub fn resume(method: &str, checkpoint: CompressCheckpoint) -> Self {
+        CheckpointingCompressor {
+            method: method.to_string(),
+            last: Some(checkpoint),
+        }
+    }
+
+    /// The checkpoint after the last complete block, or `None` before the
+    /// first block of a new stream.
+    pub fn checkpoint(&self) -> Option<CompressCheckpoint> {
+        self.last
+    }
+
+    /// Compresses `reader` to `writer`, calling `on_block` after each
+    /// complete block.  Returns the final checkpoint.
+    ///
+    /// An error from `on_block` stops compression and is returned; the block
+    /// it was called for is complete and [`checkpoint`](Self::checkpoint)
+    /// includes it.
+    pub fn compress<R, W, F>(
+        &mut self,
+        mut reader: R,
+        mut writer: W,
+        mut on_block: F,
+    ) -> Result<Option<CompressCheckpoint>>
+    where
+        R: Read,
+        W: Write,
+        F: FnMut(CompressCheckpoint) -> Result<()>,
+    {
+        let block_size = (1usize << log_block_size(&self.method)) - 4096;
+        let mut input = Vec::new();
+        let mut block = Vec::new();
+        loop {
+            input.clear();
+            (&mut reader)
+                .take(block_size as u64)
+                .read_to_end(&mut input)
+                .map_err(io_err)?;
+            if input.is_empty() {
+                return Ok(self.last);
+            }
+            block.clear();
+            compress_stream(&input[..], &mut block, &self.method, None, None)?;
+            writer.write_all(&block).map_err(io_err)?;
+            writer.flush().map_err(io_err)?;
+
+            let checkpoint = match self.last {
+                None => CompressCheckpoint {
+                    block_index: 0,
+                    input_bytes_consumed: input.len() as u64,
+                    output_bytes_emitted: block.len() as u64,
+                },
+                Some(last) => CompressCheckpoint {
+                    block_index: last.block_index + 1,
+                    input_bytes_consumed: last.input_bytes_consumed + input.len() as u64,
+                    output_bytes_emitted: last.output_bytes_emitted + block.len() as u64,
+                },
+            };
+            self.last = Some(checkpoint);
+            on_block(checkpoint)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ZpaqError, decompress_to_vec};
+
+    fn input() -> Vec<u8> {
+        let mut x = 99u32;
+        (0..4_300_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'a' + (x >> 16) as u8 % 20
+            })
+            .collect()
+    }
+
+    #[test]
+    fn uninterrupted_run_matches_compress_stream() {
+        let data = input();
+        let mut out = Vec::new();
+        let mut seen = Vec::new();
+        let last = CheckpointingCompressor::new("10")
+            .compress(&data[..], &mut out, |c| {
+                seen.push(c);
+                Ok(())
+            })
+            .expect("compress")
+            .expect("blocks");
+
+        let mut expected = Vec::new();
+        compress_stream(&data[..], &mut expected, "10", None, None).expect("compress_stream");
+        assert_eq!(out, expected);
+        assert_eq!(seen.len(), 5);
+        assert_eq!(last, seen[4]);
+        assert_eq!(last.block_index, 4);
+        assert_eq!(last.input_bytes_consumed, data.len() as u64);
+    }
+
+    #[test]
+    fn crashed_run_resumes_from_last_checkpoint() {
+        let data = input();
+        let mut out = Vec::new();
+        let mut saved = None;
+        let err = CheckpointingCompressor::new("10")
+            .compress(&data[..], &mut out, |c| {
+                saved = Some(c);
+                if c.block_index == 1 {
+                    return Err(ZpaqError::Ffi("killed".into()));
+                }
+                Ok(())
+            })
+            .expect_err("interrupted");
+        assert!(matches!(err, ZpaqError::Ffi(ref m) if m == "killed"));
+        let saved = saved.expect("checkpoint");
+        assert_eq!(saved.output_bytes_emitted, out.len() as u64);
+
+        // A crash mid-block leaves part of the next block behind.
+        out.extend_from_slice(b"zPQ\x01partial block");
+        out.truncate(saved.output_bytes_emitted as usize);
+        let rest = &data[saved.input_bytes_consumed as usize..];
+        let mut resumed = CheckpointingCompressor::resume("10", saved);
+        let last = resumed
+            .compress(rest, &mut out, |_| Ok(()))
+            .expect("resume")
+            .expect("blocks");
+
+        assert_eq!(last.block_index, 4);
+        assert_eq!(last.input_bytes_consumed, data.len() as u64);
+        assert_eq!(last.output_bytes_emitted, out.len() as u64);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), data);
+    }
+}
@@ -0,0 +1,180 @@
+//! Content-defined chunking, as `zpaq add` splits files into fragments.
+
+/// One chunk found by a [`Chunker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkBoundary {
+    /// Offset of the chunk's first byte in the chunked stream.
+    pub start: u64,
+    /// Length of the chunk in bytes.
+    pub len: u32,
+}
+
+impl ChunkBoundary {
+    /// Offset just past the chunk's last byte.
+    pub fn end(&self) -> u64 {
+        self.start + self.len as u64
+    }
+}
+
+/// Splits a byte stream at the same boundaries `zpaq add` chooses for
+/// fragments, so the chunks of a file are exactly the fragments it would be
+/// stored as.
+///
+/// A boundary falls after a byte where a rolling hash of the chunk so far
+/// drops below a threshold, giving an average chunk size of
+/// `2^fragment_exponent` KiB, bounded by a minimum of `64 << exponent` and a
+/// maximum of `8128 << exponent` bytes (and by the block size).  The hash
+/// mixes in whether each byte was predicted by an order-1 model, and all
+/// state is reset at every boundary.
+///
+/// # Example
+///
+/// ```rust
+/// let data = vec![7u8; 1 << 20];
+/// let mut chunker = zpaq_rs::Chunker::new(6);
+/// let mut chunks: Vec<_> = chunker.push(&data).collect();
+/// chunks.extend(chunker.finish());
+/// assert_eq!(chunks.iter().map(|c| c.len as usize).sum::<usize>(), data.len());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    min: u32,
+    max: u32,
+    /// Boundary when `hash < limit`; 0 disables hash boundaries.
+    limit: u32,
+    start: u64,
+    len: u32,
+    hash: u32,
+    prev: u8,
+    predicted: [u8; 256],
+}
+
+impl Chunker {
+    /// Creates a chunker matching `zpaq add -fragment <fragment_exponent>`
+    /// (the default exponent is 6, for 64 KiB chunks) with the 16 MiB blocks
+    /// of the default method.
+    pub fn new(fragment_exponent: u32) -> Self {
+        Self::with_block_size(fragment_exponent, 24)
+    }
+
+    /// Creates a chunker for methods with `2^log_block_size` byte blocks
+    /// (20 to 31; the second digit of a method plus 20).  This only matters
+    /// when large exponents make the maximum chunk exceed a block.
+    pub fn with_block_size(fragment_exponent: u32, log_block_size: u32) -> Self {
+        let log_block_size = log_block_size.clamp(20, 31);
+        let block_size = (1u32 << log_block_size) - 4096;
+        let max = if fragment_exponent > 19 || (8128u32 << fragment_exponent) > block_size - 12 {
+            block_size - 12
+        } else {
+            8128 << fragment_exponent
+        };
+        let min = if fragment_exponent > 25 || (64u32 << fragment_exponent) > max {
+            max
+        } else {
+            64 << fragment_exponent
+        };
+        let limit = if fragment_exponent <= 22 {
+            1 << (22 - fragment_exponent)
+        } else {
+            0
+        };
+        Chunker {
+            min,
+            max,
+            limit,
+            start: 0,
+            len: 0,
+            hash: 0,
+            prev: 0,
+            predicted: [0; 256],
+        }
+    }
+
+    /// Feeds `data` and returns the chunks it completes.
+    pub fn push(&mut self, data: &[u8]) -> impl Iterator<Item = ChunkBoundary> + use<> {
+        let mut out = Vec::new();
+        for &c in data {
+            if c == self.predicted[self.prev as usize] {
+                self.hash = self.hash.wrapping_add(c as u32 + 1).wrapping_mul(314159265);
+            } else {
+                self.hash = self.hash.wrapping_add(c as u32 + 1).wrapping_mul(271828182);
+            }
+            self.predicted[self.prev as usize] = c;
+            self.prev = c;
+            self.len += 1;
+            if self.len >= self.max || (self.hash < self.limit && self.len >= self.min) {
+                out.extend(self.cut());
+            }
+        }
+        out.into_iter()
+    }
+
+    /// Ends the stream, returning the final partial chunk if there is one.
+    /// The chunker can then be reused for a new stream.
+    pub fn finish(&mut self) -> Option<ChunkBoundary> {
+        let last = self.cut();
+        self.start = 0;
+        last
+    }
+
+    fn cut(&mut self) -> Option<ChunkBoundary> {
+        let chunk = (self.len > 0).then_some(ChunkBoundary {
+            start: self.start,
+            len: self.len,
+        });
+        self.start += self.len as u64;
+        self.len = 0;
+        self.hash = 0;
+        self.prev = 0;
+        self.predicted = [0; 256];
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{archive_fragments, zpaq_add};
+
+    fn sample() -> Vec<u8> {
+        let mut x = 99u64;
+        let noise = (0..600_000).map(|_| {
+            x = x
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (x >> 56) as u8
+        });
+        let text =
+            (0..40_000).flat_map(|i| format!("record {} of many\n", i * 31 % 977).into_bytes());
+        // A long run hits the maximum chunk size.
+        noise
+            .chain(text)
+            .chain(std::iter::repeat_n(0u8, 1_200_000))
+            .collect()
+    }
+
+    #[test]
+    fn chunks_match_archive_fragments() {
+        let data = sample();
+        let mut chunker = Chunker::new(6);
+        // Uneven pushes must not move boundaries.
+        let mut chunks = Vec::new();
+        for piece in data.chunks(12_345) {
+            chunks.extend(chunker.push(piece));
+        }
+        chunks.extend(chunker.finish());
+        let sizes: Vec<u32> = chunks.iter().map(|c| c.len).collect();
+        assert!(chunks.windows(2).all(|w| w[0].end() == w[1].start));
+        assert!(sizes.contains(&(8128 << 6)));
+
+        let dir = TempDir::new("zpaq-rs-chunker");
+        let path = dir.join("sample.bin").to_string_lossy().into_owned();
+        std::fs::write(&path, &data).expect("write sample");
+        let archive = dir.join("test.zpaq").to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&path], "1", 1).expect("zpaq add");
+        let fragments = archive_fragments(&archive, Some(&path)).expect("fragments");
+        let stored: Vec<u32> = fragments.iter().map(|f| f.usize).collect();
+        assert_eq!(sizes, stored);
+    }
+}
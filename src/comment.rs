@@ -0,0 +1,138 @@
+//! Application metadata in segment comments.
+//!
+//! Streaming segments carry a free-form comment, which `zpaq` itself fills
+//! with the file's date and attributes (`20240101120000 u33188`) and
+//! otherwise ignores.  [`encode_extra_comment`] stores string key-value
+//! pairs there instead, as a first line `zpaq-rs-extra 1` followed by one
+//! `key=value` line per pair in key order.  In keys and values, `\`, `=`,
+//! line feed, carriage return and NUL are written as `\\`, `\=`, `\n`,
+//! `\r` and `\0`, so any strings round-trip.  Comments not in this form,
+//! including those written by `zpaq`, are left to the caller as they are.
+
+use std::collections::BTreeMap;
+
+/// First line of a comment holding extra metadata.
+const HEADER: &str = "zpaq-rs-extra 1";
+
+/// Encodes `extra` as a segment comment, for
+/// [`ArchiveEntry::comment`](crate::ArchiveEntry::comment).  Readers decode
+/// it into [`EntryMeta::extra`](crate::EntryMeta::extra).
+///
+/// # Example
+///
+/// ```rust
+/// use std::collections::BTreeMap;
+/// use zpaq_rs::{ArchiveEntry, archive_from_entries, archive_list_entries, encode_extra_comment};
+///
+/// let extra = BTreeMap::from([("origin".to_string(), "https://example.com/a".to_string())]);
+/// let comment = encode_extra_comment(&extra);
+/// let entry = ArchiveEntry { path: "a.txt", data: b"alpha", comment: Some(&comment) };
+/// let archive = archive_from_entries(&[entry], "1")?;
+/// assert_eq!(archive_list_entries(&archive)?[0].meta.extra, extra);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn encode_extra_comment(extra: &BTreeMap<String, String>) -> String {
+    let mut comment = HEADER.to_string();
+    for (key, value) in extra {
+        comment.push('\n');
+        escape_into(&mut comment, key);
+        comment.push('=');
+        escape_into(&mut comment, value);
+    }
+    comment
+}
+
+/// Decodes a comment written by [`encode_extra_comment`], or returns `None`
+/// if `comment` is not one.
+pub fn decode_extra_comment(comment: &str) -> Option<BTreeMap<String, String>> {
+    let mut lines = comment.split('\n');
+    if lines.next()? != HEADER {
+        return None;
+    }
+    let mut extra = BTreeMap::new();
+    for line in lines {
+        let (key, value) = split_unescaped(line)?;
+        extra.insert(unescape(key)?, unescape(value)?);
+    }
+    Some(extra)
+}
+
+fn escape_into(out: &mut String, text: &str) {
+    for c in text.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '=' => out.push_str("\\="),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\0' => out.push_str("\\0"),
+            c => out.push(c),
+        }
+    }
+}
+
+/// Splits `line` at its first `=` that is not escaped.
+fn split_unescaped(line: &str) -> Option<(&str, &str)> {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '=' => return Some((&line[..i], &line[i + 1..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.push(match chars.next()? {
+                '\\' => '\\',
+                '=' => '=',
+                'n' => '\n',
+                'r' => '\r',
+                '0' => '\0',
+                _ => return None,
+            }),
+            '=' => return None,
+            c => out.push(c),
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_round_trips_through_escapes() {
+        let extra = BTreeMap::from([
+            ("a=b".to_string(), "line\nbreak\r\n".to_string()),
+            ("back\\slash".to_string(), "x=y=\\=".to_string()),
+            ("empty".to_string(), String::new()),
+            ("nul\0".to_string(), "ünïcode".to_string()),
+        ]);
+        let comment = encode_extra_comment(&extra);
+        assert!(!comment.contains('\0'));
+        assert_eq!(comment.lines().count(), 1 + extra.len());
+        assert_eq!(decode_extra_comment(&comment), Some(extra));
+        assert_eq!(
+            decode_extra_comment(&encode_extra_comment(&BTreeMap::new())),
+            Some(BTreeMap::new())
+        );
+
+        for plain in [
+            "",
+            "20240101120000 u33188",
+            "zpaq-rs-extra 1\nno pair",
+            "x=1",
+        ] {
+            assert_eq!(decode_extra_comment(plain), None, "{plain:?}");
+        }
+        assert_eq!(decode_extra_comment("zpaq-rs-extra 1\nbad\\q=1"), None);
+    }
+}
@@ -0,0 +1,179 @@
+//! Moving data between streaming and journaling archives.
+
+use std::io::{Read, Write};
+
+use crate::parallel::spawn_scoped;
+use crate::{
+    ArchiveReader, ArchiveWriter, EntryMeta, Result, ZpaqError, compress_stream, decompress_stream,
+    io_err,
+};
+
+/// Decompresses the streaming archive read from `streaming_input` (as
+/// written by [`compress_stream`]) and adds its data to the journaling
+/// archive `dest_archive` as the file `member_name`, in a new version.
+///
+/// `dest_archive` is created if missing.  Its data is deduplicated against
+/// what the archive already holds and compressed with `method`, as by
+/// [`ArchiveWriter::append`].  Decompression feeds the writer through a
+/// pipe, so neither the data nor a scratch file is ever whole.  All segments
+/// of the input are joined into the one file, as [`decompress_stream`]
+/// joins them.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// for name in ["2024-01.log", "2024-02.log"] {
+///     let stream = std::fs::File::open(format!("{name}.zpaq"))?;
+///     zpaq_rs::convert_to_journal(stream, "logs.zpaq", name, "2")?;
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails if the input cannot be decompressed, leaving the new version
+/// incomplete: readers and `zpaq` ignore it, and the next add replaces it.
+pub fn convert_to_journal<R: Read + Send>(
+    streaming_input: R,
+    dest_archive: &str,
+    member_name: &str,
+    method: &str,
+) -> Result<()> {
+    let mut writer = ArchiveWriter::append(dest_archive, method)?;
+    let (pipe_in, pipe_out) = std::io::pipe().map_err(io_err)?;
+    let (added, decompressed) = std::thread::scope(|scope| {
+        let decompressing =
+            spawn_scoped(scope, move || decompress_stream(streaming_input, pipe_out));
+        let added = writer.add_entry(member_name, pipe_in, &EntryMeta::default());
+        let decompressed = decompressing.join().expect("decompression panicked");
+        (added, decompressed)
+    });
+    // A failed add closes the pipe under the decompression, so its error
+    // comes first.
+    added?;
+    decompressed?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// Writes the file `member` of `archive` (the latest version of a
+/// journaling archive, or the newest copy in a streaming one) to `writer`
+/// as a bare streaming archive compressed with `method`, and returns the
+/// file's size.
+///
+/// The file is decompressed into the compressor through a pipe, one block
+/// at a time.  The stream carries no file name, so [`decompress_stream`]
+/// gives back exactly the file.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::NotFound`], without writing anything, if `archive`
+/// has no file `member`.  If the file cannot be read, what was written so
+/// far is a valid stream of part of it.
+pub fn export_member_as_stream<W: Write + Send>(
+    archive: &str,
+    member: &str,
+    writer: W,
+    method: &str,
+) -> Result<u64> {
+    let mut reader = ArchiveReader::open_path(archive)?;
+    if !reader.contains(member) {
+        return Err(ZpaqError::NotFound {
+            path: member.to_string(),
+            was_deleted: false,
+        });
+    }
+    let (pipe_in, pipe_out) = std::io::pipe().map_err(io_err)?;
+    let (read, compressed) = std::thread::scope(|scope| {
+        let compressing = spawn_scoped(scope, move || {
+            compress_stream(pipe_in, writer, method, None, None)
+        });
+        let read = reader.read_to(member, pipe_out);
+        let compressed = compressing.join().expect("compression panicked");
+        (read, compressed)
+    });
+    // A failed compression closes the pipe under the read, so its error
+    // comes first.
+    compressed?;
+    read
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::random;
+    use crate::{
+        ArchiveFormat, archive_format, archive_list_entries, compress_to_vec, decompress_to_vec,
+        zpaq_list,
+    };
+
+    #[test]
+    fn streams_round_trip_through_a_journaling_archive() {
+        let dir = TempDir::new("zpaq-rs-convert");
+        let archive = dir.join("folded.zpaq").to_string_lossy().into_owned();
+        let first = [random(1, 300_000), b"shared tail ".repeat(5000)].concat();
+        let second = [random(2, 100_000), b"shared tail ".repeat(5000)].concat();
+        for (name, data) in [("first.bin", &first), ("second.bin", &second)] {
+            let stream = compress_to_vec(data, "1").expect("compress");
+            assert_eq!(
+                archive_format(&stream).expect("format"),
+                ArchiveFormat::Streaming
+            );
+            convert_to_journal(&stream[..], &archive, name, "1").expect("convert");
+        }
+
+        let bytes = std::fs::read(&archive).expect("read archive");
+        assert_eq!(
+            archive_format(&bytes).expect("format"),
+            ArchiveFormat::Journaling {
+                versions_hint: Some(2)
+            }
+        );
+        let listed: Vec<(String, u64)> = archive_list_entries(&bytes)
+            .expect("list")
+            .into_iter()
+            .map(|e| (e.path, e.size))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("first.bin".to_string(), first.len() as u64),
+                ("second.bin".to_string(), second.len() as u64),
+            ]
+        );
+        let cli = zpaq_list(&archive, &[]).expect("zpaq list").stdout;
+        assert!(
+            cli.contains("first.bin") && cli.contains("second.bin"),
+            "{cli}"
+        );
+        // The shared tail is stored once.
+        assert!(bytes.len() < first.len() + second.len() - 50_000);
+
+        for (name, data) in [("first.bin", &first), ("second.bin", &second)] {
+            let mut stream = Vec::new();
+            let size = export_member_as_stream(&archive, name, &mut stream, "1").expect("export");
+            assert_eq!(size, data.len() as u64);
+            assert_eq!(
+                archive_format(&stream).expect("format"),
+                ArchiveFormat::Streaming
+            );
+            assert_eq!(&decompress_to_vec(&stream).expect("decompress"), data);
+        }
+
+        let mut untouched = Vec::new();
+        assert!(matches!(
+            export_member_as_stream(&archive, "missing.bin", &mut untouched, "1"),
+            Err(ZpaqError::NotFound { .. })
+        ));
+        assert!(untouched.is_empty());
+
+        // A damaged stream leaves no new version behind.
+        let mut damaged = compress_to_vec(&random(3, 50_000), "1").expect("compress");
+        damaged.truncate(damaged.len() / 2);
+        assert!(convert_to_journal(&damaged[..], &archive, "third.bin", "1").is_err());
+        let reader = ArchiveReader::open_path(&archive).expect("reopen");
+        assert_eq!(reader.versions(), 2);
+        assert!(!reader.contains("third.bin"));
+    }
+}
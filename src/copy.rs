@@ -0,0 +1,433 @@
+//! Copying files from one journaling archive into another, moving their
+//! compressed data blocks as they are where the destination's models allow.
+
+use std::collections::{BTreeSet, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::filter::selects;
+use crate::journal::{FragmentKey, open_appendable};
+use crate::parts::{MultiPartReader, open_archive};
+use crate::reader::check_scan;
+use crate::verify::{ArchiveSource, IndexEntry, Scan};
+use crate::{ArchiveReader, ArchiveWriter, Result, ZPAQ_TAG, ZpaqError, io_err};
+
+/// How [`zpaq_copy_members`] copied a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CopyMode {
+    /// The data blocks holding its fragments were copied as they are.
+    Verbatim,
+    /// Its data was decompressed and compressed again with the method
+    /// given, because a block holding it uses a model the destination
+    /// does not.
+    Recompressed,
+    /// The destination held all its fragments already, so only its index
+    /// record was written.
+    AlreadyStored,
+}
+
+/// What [`zpaq_copy_members`] did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CopyReport {
+    /// Each file copied, by stored path, with how.
+    pub members: Vec<(String, CopyMode)>,
+    /// `d` blocks copied as they are.
+    pub blocks_copied: u64,
+    /// Compressed bytes of those blocks.
+    pub bytes_copied: u64,
+}
+
+/// Copies the current files of the journaling archive `src_archive`
+/// selected by `members` into a new version of the journaling archive
+/// `dst_archive` (created if missing), with their dates and attributes.
+///
+/// Patterns select as for [`zpaq_delete`](crate::zpaq_delete), and a file
+/// already at the same path in the destination is replaced.  Fragments the
+/// destination already holds are not stored again.  The others are copied
+/// in the source's `d` blocks, without decoding them, when each block's
+/// model (its components and context computation) is one the destination
+/// already uses in a block of its own, or the destination holds no data
+/// yet; only the blocks' names are rewritten.  A copied block is copied
+/// whole, so it may bring fragments of other files along, as with
+/// `zpaq extract -repack`.  Otherwise the file is decompressed and
+/// compressed again with `method` (as for [`zpaq_add`](crate::zpaq_add)).
+/// [`CopyReport::members`] says which way each file went.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::NotFound`] for a pattern that selects no file,
+/// [`ZpaqError::StreamingArchive`] if either archive is a streaming one,
+/// and fails on a damaged or encrypted source.  The destination is left
+/// unchanged on error.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::CopyMode;
+///
+/// let report = zpaq_rs::zpaq_copy_members("media.zpaq", "selected.zpaq", &["video/talk.mkv"], "1")?;
+/// assert_eq!(report.members, [("video/talk.mkv".to_string(), CopyMode::Verbatim)]);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn zpaq_copy_members(
+    src_archive: &str,
+    dst_archive: &str,
+    members: &[&str],
+    method: &str,
+) -> Result<CopyReport> {
+    let mut reader = open_archive(src_archive)?;
+    crate::format::require_plain(&mut reader)?;
+    let mut source = ArchiveSource::new(BufReader::new(reader), None)?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    check_scan(&scan)?;
+    if scan.versions() == 0 {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    let files = scan.files_at(scan.versions());
+    for pattern in members {
+        if !files.keys().any(|path| selects(pattern, path)) {
+            return Err(ZpaqError::NotFound {
+                path: pattern.to_string(),
+                was_deleted: false,
+            });
+        }
+    }
+    let selected: Vec<&IndexEntry> = files
+        .into_iter()
+        .filter(|(path, _)| members.iter().any(|pattern| selects(pattern, path)))
+        .map(|(_, entry)| entry)
+        .collect();
+
+    let (mut file, history) = open_appendable(dst_archive)?;
+    let start = file.stream_position().map_err(io_err)?;
+    let models = stored_models(&mut file, &history.blocks)?;
+    file.seek(SeekFrom::Start(start)).map_err(io_err)?;
+    let mut writer = ArchiveWriter::with_history(file, method, history)?;
+    let mut copy = Copy {
+        src_archive,
+        source,
+        scan: &scan,
+        models,
+        slow: None,
+        report: CopyReport::default(),
+    };
+    let result = selected
+        .into_iter()
+        .try_for_each(|entry| copy.member(&mut writer, entry))
+        .and_then(|()| writer.finish().map(drop));
+    if let Err(e) = result {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(dst_archive)
+            .map_err(|e| ZpaqError::Ffi(format!("{dst_archive}: {e}")))?;
+        file.set_len(start).map_err(io_err)?;
+        return Err(e);
+    }
+    Ok(copy.report)
+}
+
+/// A copy in progress.
+struct Copy<'a> {
+    src_archive: &'a str,
+    source: ArchiveSource<BufReader<MultiPartReader>>,
+    scan: &'a Scan<'static>,
+    /// Models of the destination's `d` blocks.
+    models: HashSet<Vec<u8>>,
+    /// A reader of the source for files copied the slow way, once needed.
+    slow: Option<ArchiveReader<MultiPartReader>>,
+    report: CopyReport,
+}
+
+impl Copy<'_> {
+    fn member(&mut self, writer: &mut ArchiveWriter<File>, entry: &IndexEntry) -> Result<()> {
+        let path = entry.file.as_str();
+        let fragments = self.scan.fragments();
+        let keys = entry
+            .fragments
+            .iter()
+            .map(|id| match fragments.get(id) {
+                Some(&(size, sha1)) => Ok((sha1, size)),
+                None => Err(ZpaqError::Ffi(format!(
+                    "{path}: fragment {id} is not described by any h block"
+                ))),
+            })
+            .collect::<Result<Vec<FragmentKey>>>()?;
+
+        // The source blocks holding fragments the destination lacks.
+        let tables: BTreeSet<usize> = entry
+            .fragments
+            .iter()
+            .zip(&keys)
+            .filter(|(_, key)| !writer.has_fragment(key))
+            .filter_map(|(&id, _)| {
+                self.scan.tables.iter().position(|table| {
+                    (table.first..table.first + table.fragments.len() as u32).contains(&id)
+                })
+            })
+            .collect();
+        let mut blocks = Vec::with_capacity(tables.len());
+        for &index in &tables {
+            let table = &self.scan.tables[index];
+            self.source.seek_to(table.data_offset)?;
+            let mut block = vec![0u8; table.data_size as usize];
+            self.source.read_exact(&mut block).map_err(io_err)?;
+            match block_layout(&block) {
+                Some((model, name)) if self.fits(&block[model.clone()]) => {
+                    blocks.push((block, name, index))
+                }
+                _ => return self.recompress(writer, entry),
+            }
+        }
+
+        let mode = if blocks.is_empty() {
+            CopyMode::AlreadyStored
+        } else {
+            CopyMode::Verbatim
+        };
+        for (block, name, index) in blocks {
+            let table = &self.scan.tables[index];
+            let fragments = table.fragments.iter().map(|&(size, sha1)| (sha1, size));
+            self.report.blocks_copied += 1;
+            self.report.bytes_copied += block.len() as u64;
+            writer.copy_block(block, name, fragments.collect())?;
+        }
+        writer.add_fragments(path, entry.date, &entry.attr, &keys)?;
+        self.report.members.push((path.to_string(), mode));
+        Ok(())
+    }
+
+    /// Whether a block with `model` can go into the destination as it is.
+    fn fits(&self, model: &[u8]) -> bool {
+        self.models.is_empty() || self.models.contains(model)
+    }
+
+    fn recompress(&mut self, writer: &mut ArchiveWriter<File>, entry: &IndexEntry) -> Result<()> {
+        let reader = match &mut self.slow {
+            Some(reader) => reader,
+            slow => slow.insert(ArchiveReader::open(open_archive(self.src_archive)?)?),
+        };
+        let data = reader.read(&entry.file)?;
+        writer.add_data(&entry.file, entry.date, &entry.attr, &data[..])?;
+        let member = (entry.file.clone(), CopyMode::Recompressed);
+        self.report.members.push(member);
+        Ok(())
+    }
+}
+
+/// Where the model (level, ZPAQL type, header size and header) and the
+/// first segment's name lie in a compressed block.
+fn block_layout(block: &[u8]) -> Option<(Range<usize>, Range<usize>)> {
+    let model = model_range(block)?;
+    if block.get(model.end) != Some(&1) {
+        return None;
+    }
+    let start = model.end + 1;
+    let len = block.get(start..)?.iter().position(|&b| b == 0)?;
+    Some((model, start..start + len))
+}
+
+/// The model part of [`block_layout`], from as much of the block as
+/// reaches past it.
+fn model_range(block: &[u8]) -> Option<Range<usize>> {
+    let start = if block.starts_with(&ZPAQ_TAG) {
+        ZPAQ_TAG.len()
+    } else {
+        0
+    };
+    let head = block.get(start..start + 7)?;
+    if !head.starts_with(b"zPQ") || !matches!(head[3], 1 | 2) || head[4] != 1 {
+        return None;
+    }
+    let hsize = u16::from_le_bytes([head[5], head[6]]) as usize;
+    let model = start + 3..start + 7 + hsize;
+    (model.end <= block.len()).then_some(model)
+}
+
+/// The models of the `d` blocks at `blocks` (offset and size) in `file`.
+fn stored_models(file: &mut File, blocks: &[(u64, u64)]) -> Result<HashSet<Vec<u8>>> {
+    let mut models = HashSet::new();
+    for &(offset, size) in blocks {
+        // The tag, "zPQ", level and type, then the header size.
+        let head = read_at(file, offset, size.min(ZPAQ_TAG.len() as u64 + 7))?;
+        let Some(start) = head.len().checked_sub(2) else {
+            continue;
+        };
+        let hsize = u16::from_le_bytes([head[start], head[start + 1]]) as u64;
+        let block = read_at(file, offset, size.min(head.len() as u64 + hsize))?;
+        if let Some(model) = model_range(&block) {
+            models.insert(block[model].to_vec());
+        }
+    }
+    Ok(models)
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset)).map_err(io_err)?;
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf).map_err(io_err)?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::{random, text};
+    use crate::{ArchiveEntryOwned, archive_from_owned_entries, zpaq_command};
+    use std::path::PathBuf;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn write_archive(path: &PathBuf, entries: Vec<ArchiveEntryOwned>, method: &str) -> String {
+        let bytes = archive_from_owned_entries(entries, method).expect("write");
+        std::fs::write(path, bytes).expect("write archive");
+        path.to_string_lossy().into_owned()
+    }
+
+    fn read_all(archive: &str) -> Vec<(String, Vec<u8>)> {
+        let mut reader = ArchiveReader::open(open_archive(archive).expect("open")).expect("open");
+        let paths: Vec<String> = reader.entries().map(|e| e.path.clone()).collect();
+        paths
+            .into_iter()
+            .map(|path| {
+                let data = reader.read(&path).expect("read");
+                (path, data)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn same_models_copy_blocks_verbatim() {
+        let dir = TempDir::new("zpaq-rs-copy");
+        let big = random(11, 300_000);
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_400_000_000);
+        let src = write_archive(
+            &dir.join("src.zpaq"),
+            vec![
+                ArchiveEntryOwned::from_bytes("media/big.bin", big.clone()).with_mtime(mtime),
+                ArchiveEntryOwned::from_bytes("media/notes.txt", text(20_000)),
+                ArchiveEntryOwned::from_bytes("other.txt", b"left behind".to_vec()),
+            ],
+            "1",
+        );
+        let dst = write_archive(
+            &dir.join("dst.zpaq"),
+            vec![
+                ArchiveEntryOwned::from_bytes("own.txt", text(5000)),
+                ArchiveEntryOwned::from_bytes("media/notes.txt", b"replaced".to_vec()),
+            ],
+            "1",
+        );
+        let before = std::fs::read(&dst).expect("read");
+
+        let missing = zpaq_copy_members(&src, &dst, &["media", "nope"], "1");
+        assert!(matches!(missing, Err(ZpaqError::NotFound { path, .. }) if path == "nope"));
+        assert_eq!(std::fs::read(&dst).expect("read"), before);
+
+        let report = zpaq_copy_members(&src, &dst, &["media"], "1").expect("copy");
+        assert_eq!(
+            report.members,
+            [
+                ("media/big.bin".to_string(), CopyMode::Verbatim),
+                ("media/notes.txt".to_string(), CopyMode::AlreadyStored),
+            ]
+        );
+        assert!(report.blocks_copied >= 1);
+        let after = std::fs::read(&dst).expect("read");
+        assert!(after.starts_with(&before));
+        assert!(after.len() - before.len() < report.bytes_copied as usize + 1000);
+
+        // Copying again finds every fragment in place.
+        let again = zpaq_copy_members(&src, &dst, &["media/big.bin"], "1").expect("copy");
+        assert_eq!(
+            again.members,
+            [("media/big.bin".to_string(), CopyMode::AlreadyStored)]
+        );
+        assert_eq!(again.blocks_copied, 0);
+
+        let files = read_all(&dst);
+        let paths: Vec<&str> = files.iter().map(|(p, _)| p.as_str()).collect();
+        assert_eq!(paths, ["media/big.bin", "media/notes.txt", "own.txt"]);
+        assert_eq!(files[0].1, big);
+        assert_eq!(files[1].1, text(20_000));
+
+        let out = dir.join("out").to_string_lossy().into_owned();
+        zpaq_command(&["extract", &dst, "-to", &out]).expect("extract");
+        assert_eq!(
+            std::fs::read(dir.join("out/media/big.bin")).expect("read"),
+            big
+        );
+        let listed =
+            crate::archive_list_entries(&std::fs::read(&dst).expect("read")).expect("list");
+        assert_eq!(listed[0].meta.mtime, Some(mtime));
+    }
+
+    #[test]
+    fn other_models_recompress_and_new_archives_take_the_source_models() {
+        let dir = TempDir::new("zpaq-rs-copy-models");
+        let data = text(100_000);
+        let src = write_archive(
+            &dir.join("src.zpaq"),
+            vec![ArchiveEntryOwned::from_bytes("a.txt", data.clone())],
+            "1",
+        );
+        let dst = write_archive(
+            &dir.join("dst.zpaq"),
+            vec![ArchiveEntryOwned::from_bytes("b.txt", random(3, 10_000))],
+            "3",
+        );
+        let report = zpaq_copy_members(&src, &dst, &["a.txt"], "3").expect("copy");
+        assert_eq!(
+            report.members,
+            [("a.txt".to_string(), CopyMode::Recompressed)]
+        );
+        assert_eq!((report.blocks_copied, report.bytes_copied), (0, 0));
+        assert_eq!(read_all(&dst)[0], ("a.txt".to_string(), data.clone()));
+
+        // A destination created by the copy holds no model to keep to.
+        let fresh = dir.join("fresh.zpaq").to_string_lossy().into_owned();
+        let report = zpaq_copy_members(&src, &fresh, &["*"], "3").expect("copy");
+        assert_eq!(report.members, [("a.txt".to_string(), CopyMode::Verbatim)]);
+        assert_eq!(read_all(&fresh), [("a.txt".to_string(), data)]);
+
+        let entry = crate::ArchiveEntry {
+            path: "s",
+            data: b"s",
+            comment: None,
+        };
+        let streaming = dir.join("streaming.zpaq");
+        std::fs::write(
+            &streaming,
+            crate::archive_from_entries(&[entry], "1").unwrap(),
+        )
+        .unwrap();
+        let refused = zpaq_copy_members(&streaming.to_string_lossy(), &fresh, &["s"], "1");
+        assert!(matches!(refused, Err(ZpaqError::StreamingArchive)));
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn verbatim_copies_decode_no_data() {
+        use crate::StatsCollector;
+
+        let dir = TempDir::new("zpaq-rs-copy-stats");
+        let big = random(5, 32 << 20);
+        let src = write_archive(
+            &dir.join("src.zpaq"),
+            vec![ArchiveEntryOwned::from_bytes("big.bin", big)],
+            "1",
+        );
+        let dst = dir.join("dst.zpaq").to_string_lossy().into_owned();
+        let (report, stats) =
+            StatsCollector::new().collect(|| zpaq_copy_members(&src, &dst, &["big.bin"], "1"));
+        let report = report.expect("copy");
+        assert_eq!(
+            report.members,
+            [("big.bin".to_string(), CopyMode::Verbatim)]
+        );
+        assert!(report.bytes_copied > 32 << 20);
+        // The scan reads past the blocks, but only the index is decoded.
+        assert!(stats.bytes_written < 1 << 20, "{stats:?}");
+    }
+}
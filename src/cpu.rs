@@ -0,0 +1,131 @@
+//! Checks that this CPU has the instruction set extensions the C++ was
+//! compiled to use.
+//!
+//! A build with `-C target-cpu=native` in `RUSTFLAGS` also compiles libzpaq
+//! with `-march=native`, and running it on an older CPU would crash with an
+//! illegal instruction somewhere inside C++.  `build.rs` records which
+//! features that enabled, and the first call into the shim compares them
+//! against the running CPU instead.
+
+use std::sync::OnceLock;
+
+use crate::{Result, ZpaqError};
+
+include!(concat!(env!("OUT_DIR"), "/cpu_features.rs"));
+
+/// The CPU features the C++ part of this build requires beyond the target's
+/// baseline, by the names `is_x86_feature_detected!` uses.
+///
+/// Empty unless the crate was built with `-C target-cpu=native` for x86 or
+/// x86_64.  Every call into libzpaq fails with
+/// [`ZpaqError::UnsupportedCpu`] on a CPU that lacks one of them; call this
+/// (or [`check_cpu`]) to find out before, for example, rolling a binary out
+/// to a mixed fleet.
+///
+/// # Example
+///
+/// ```rust
+/// for feature in zpaq_rs::required_cpu_features() {
+///     println!("needs {feature}");
+/// }
+/// ```
+pub fn required_cpu_features() -> &'static [&'static str] {
+    COMPILED_CPU_FEATURES
+}
+
+/// Fails with [`ZpaqError::UnsupportedCpu`] if this CPU lacks any of the
+/// [`required_cpu_features`].  The result is worked out once and cached.
+pub fn check_cpu() -> Result<()> {
+    static MISSING: OnceLock<Vec<String>> = OnceLock::new();
+    let missing = MISSING.get_or_init(|| {
+        COMPILED_CPU_FEATURES
+            .iter()
+            .filter(|feature| detected(feature) == Some(false))
+            .map(|feature| feature.to_string())
+            .collect()
+    });
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(ZpaqError::UnsupportedCpu {
+            missing: missing.clone(),
+        })
+    }
+}
+
+/// Whether this CPU has `feature`, or `None` for a name `build.rs` should
+/// not have recorded.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn detected(feature: &str) -> Option<bool> {
+    Some(match feature {
+        "sse3" => is_x86_feature_detected!("sse3"),
+        "ssse3" => is_x86_feature_detected!("ssse3"),
+        "sse4.1" => is_x86_feature_detected!("sse4.1"),
+        "sse4.2" => is_x86_feature_detected!("sse4.2"),
+        "sse4a" => is_x86_feature_detected!("sse4a"),
+        "popcnt" => is_x86_feature_detected!("popcnt"),
+        "lzcnt" => is_x86_feature_detected!("lzcnt"),
+        "avx" => is_x86_feature_detected!("avx"),
+        "avx2" => is_x86_feature_detected!("avx2"),
+        "fma" => is_x86_feature_detected!("fma"),
+        "f16c" => is_x86_feature_detected!("f16c"),
+        "bmi1" => is_x86_feature_detected!("bmi1"),
+        "bmi2" => is_x86_feature_detected!("bmi2"),
+        "aes" => is_x86_feature_detected!("aes"),
+        "pclmulqdq" => is_x86_feature_detected!("pclmulqdq"),
+        "sha" => is_x86_feature_detected!("sha"),
+        "adx" => is_x86_feature_detected!("adx"),
+        "rdrand" => is_x86_feature_detected!("rdrand"),
+        "rdseed" => is_x86_feature_detected!("rdseed"),
+        "movbe" => is_x86_feature_detected!("movbe"),
+        "xsave" => is_x86_feature_detected!("xsave"),
+        "avx512f" => is_x86_feature_detected!("avx512f"),
+        "avx512bw" => is_x86_feature_detected!("avx512bw"),
+        "avx512cd" => is_x86_feature_detected!("avx512cd"),
+        "avx512dq" => is_x86_feature_detected!("avx512dq"),
+        "avx512vl" => is_x86_feature_detected!("avx512vl"),
+        "avx512vbmi" => is_x86_feature_detected!("avx512vbmi"),
+        "avx512vnni" => is_x86_feature_detected!("avx512vnni"),
+        "gfni" => is_x86_feature_detected!("gfni"),
+        "vaes" => is_x86_feature_detected!("vaes"),
+        "vpclmulqdq" => is_x86_feature_detected!("vpclmulqdq"),
+        _ => return None,
+    })
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn detected(_feature: &str) -> Option<bool> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorded_features_follow_the_build_flags() {
+        let features = required_cpu_features();
+        if env!("ZPAQ_RS_NATIVE_CPU") == "0" {
+            assert!(features.is_empty(), "{features:?}");
+        }
+        for (i, feature) in features.iter().enumerate() {
+            assert!(detected(feature).is_some(), "unknown feature {feature}");
+            assert!(!features[..i].contains(feature), "{feature} listed twice");
+        }
+    }
+
+    #[test]
+    fn the_build_machine_passes_the_check() {
+        check_cpu().expect("cpu check");
+        assert!(
+            required_cpu_features()
+                .iter()
+                .all(|feature| detected(feature) == Some(true))
+        );
+        assert!(
+            !crate::compress_to_vec(b"runs here", "1")
+                .expect("compress")
+                .is_empty()
+        );
+    }
+}
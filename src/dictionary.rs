@@ -0,0 +1,273 @@
+//! Preset-dictionary compression.
+//!
+//! The dictionary is run through the context model before the payload, so the
+//! payload is coded as if it followed the dictionary, but none of the
+//! dictionary is written to the stream.  The decoder primes its model with the
+//! same bytes; with a different dictionary its predictions diverge from the
+//! encoder's and the segment no longer decodes to data matching its SHA-1.
+
+use std::ptr;
+
+use crate::{
+    FfiReader, FfiWriter, Result, SharedVecWriter, ZpaqError, clear_last_error, err_from_last,
+    handle, sha1, start_block_for_method, sys,
+};
+
+/// Compresses `input` with a model primed on `dict`.
+///
+/// Produces one block with one unnamed segment, using the same model as
+/// [`StreamingCompressor`](crate::StreamingCompressor) for `method` (so
+/// methods with block preprocessing are rejected).  The segment carries the
+/// SHA-1 of `input`.  The output is a valid ZPAQ stream, but it can only be
+/// decoded by [`decompress_with_dictionary`] with the same `dict`.
+///
+/// # Example
+///
+/// ```rust
+/// let dict = br#"{"id": 0, "name": "", "tags": []}"#;
+/// let doc = br#"{"id": 7, "name": "widget", "tags": ["a"]}"#;
+/// let c = zpaq_rs::compress_with_dictionary(doc, dict, "2").unwrap();
+/// assert_eq!(zpaq_rs::decompress_with_dictionary(&c, dict).unwrap(), doc);
+/// ```
+pub fn compress_with_dictionary(input: &[u8], dict: &[u8], method: &str) -> Result<Vec<u8>> {
+    let digest = sha1(input)?;
+    clear_last_error()?;
+    let compressor = handle::Compressor::new()?;
+    let out = SharedVecWriter::new();
+    let result = (|| {
+        let output = FfiWriter::new(out.clone())?;
+        let reader = FfiReader::new(input)?;
+        unsafe {
+            if sys::zpaq_compressor_set_output(compressor.raw, output.raw) != 0
+                || sys::zpaq_compressor_set_input(compressor.raw, reader.raw) != 0
+                || sys::zpaq_compressor_write_tag(compressor.raw) != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        start_block_for_method(compressor.raw, method)?;
+        unsafe {
+            if sys::zpaq_compressor_start_segment(compressor.raw, ptr::null(), ptr::null()) != 0
+                || sys::zpaq_compressor_prime(compressor.raw, dict.as_ptr(), dict.len()) != 0
+            {
+                return Err(err_from_last());
+            }
+            loop {
+                let rc = sys::zpaq_compressor_compress(compressor.raw, 1 << 20);
+                if rc < 0 {
+                    return Err(err_from_last());
+                }
+                if rc == 0 {
+                    break;
+                }
+            }
+            if sys::zpaq_compressor_end_segment(compressor.raw, digest.as_ptr()) != 0
+                || sys::zpaq_compressor_end_block(compressor.raw) != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        Ok(())
+    })();
+    drop(compressor);
+    result.map(|()| out.bytes())
+}
+
+/// Decompresses a stream produced by [`compress_with_dictionary`].
+///
+/// The model of each block is primed with `dict` before its first segment.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::ChecksumMismatch`] when a segment cannot be decoded to
+/// data matching its SHA-1, which is what a wrong dictionary (or corrupted
+/// segment data) produces.  This covers both a digest mismatch and the
+/// arithmetic decoder running off the rails part-way, which is the usual
+/// outcome of a mismatched model.  Other failures, such as a stream cut
+/// short, are returned as libzpaq reports them.
+pub fn decompress_with_dictionary(compressed: &[u8], dict: &[u8]) -> Result<Vec<u8>> {
+    clear_last_error()?;
+    let reader = FfiReader::new(compressed)?;
+    let decompresser = handle::Decompresser::new()?;
+    decode_primed(decompresser.raw, reader.raw, dict)
+}
+
+fn decode_primed(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+    dict: &[u8],
+) -> Result<Vec<u8>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+
+    let mut out = Vec::new();
+    loop {
+        let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+        if rc_block < 0 {
+            return Err(err_from_last());
+        }
+        if rc_block == 0 {
+            break;
+        }
+
+        let mut first_segment = true;
+        loop {
+            let rc_filename =
+                unsafe { sys::zpaq_decompresser_find_filename(decompresser, ptr::null_mut()) };
+            if rc_filename < 0 {
+                return Err(err_from_last());
+            }
+            if rc_filename == 0 {
+                break;
+            }
+            if unsafe { sys::zpaq_decompresser_read_comment(decompresser, ptr::null_mut()) } != 0 {
+                return Err(err_from_last());
+            }
+
+            let segment = SharedVecWriter::new();
+            let segment_writer = FfiWriter::new(segment.clone())?;
+            if unsafe { sys::zpaq_decompresser_set_output(decompresser, segment_writer.raw) } != 0 {
+                return Err(err_from_last());
+            }
+            if first_segment {
+                let rc = unsafe {
+                    sys::zpaq_decompresser_prime(decompresser, dict.as_ptr(), dict.len())
+                };
+                if rc != 0 {
+                    return Err(err_from_last());
+                }
+                first_segment = false;
+            }
+            loop {
+                let rc = unsafe { sys::zpaq_decompresser_decompress(decompresser, 1 << 20) };
+                if rc < 0 {
+                    return Err(divergence(err_from_last()));
+                }
+                if rc == 0 {
+                    break;
+                }
+            }
+
+            let mut segment_end = [0u8; 21];
+            let rc_end = unsafe {
+                sys::zpaq_decompresser_read_segment_end(decompresser, segment_end.as_mut_ptr())
+            };
+            if rc_end != 0 {
+                return Err(err_from_last());
+            }
+            drop(segment_writer);
+
+            let bytes = segment.bytes();
+            if segment_end[0] == 1 && sha1(&bytes)? != segment_end[1..] {
+                return Err(ZpaqError::ChecksumMismatch);
+            }
+            out.extend_from_slice(&bytes);
+        }
+    }
+
+    Ok(out)
+}
+
+/// A decoder whose model does not match the encoder's leaves the range the
+/// arithmetic code allows, which libzpaq reports as a corrupted archive;
+/// that is reported as [`ZpaqError::ChecksumMismatch`], any other failure
+/// as it is.
+fn divergence(err: ZpaqError) -> ZpaqError {
+    match err {
+        ZpaqError::Ffi(message) if message.ends_with("archive corrupted") => {
+            ZpaqError::ChecksumMismatch
+        }
+        err => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compress_to_vec;
+
+    fn documents() -> Vec<Vec<u8>> {
+        let names = ["alpha", "bravo", "charlie", "delta", "echo", "foxtrot"];
+        (0..40)
+            .map(|i| {
+                format!(
+                    r#"{{"id": {i}, "name": "{}", "status": "active", "owner": {{"team": "storage", "region": "eu-west-1"}}, "tags": ["zpaq", "archive"], "retries": {}}}"#,
+                    names[i % names.len()],
+                    i % 3
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    fn dictionary() -> Vec<u8> {
+        br#"{"id": 0, "name": "", "status": "active", "owner": {"team": "storage", "region": "eu-west-1"}, "tags": ["zpaq", "archive"], "retries": 0}"#
+            .repeat(4)
+    }
+
+    #[test]
+    fn dictionary_roundtrips() {
+        let dict = dictionary();
+        for method in ["1", "2", "3"] {
+            for doc in documents().iter().take(5) {
+                let c = compress_with_dictionary(doc, &dict, method).expect("compress");
+                let d = decompress_with_dictionary(&c, &dict).expect("decompress");
+                assert_eq!(&d, doc, "method={method}");
+            }
+        }
+        let c = compress_with_dictionary(b"", &dict, "2").expect("compress empty");
+        assert!(
+            decompress_with_dictionary(&c, &dict)
+                .expect("decompress")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn dictionary_beats_plain_compression() {
+        let dict = dictionary();
+        let mut plain = 0usize;
+        let mut primed = 0usize;
+        for doc in documents() {
+            plain += compress_to_vec(&doc, "2").expect("plain").len();
+            primed += compress_with_dictionary(&doc, &dict, "2")
+                .expect("primed")
+                .len();
+        }
+        assert!(
+            primed * 10 <= plain * 8,
+            "primed={primed} plain={plain} (want at least 20% smaller)"
+        );
+    }
+
+    #[test]
+    fn wrong_dictionary_is_rejected() {
+        let dict = dictionary();
+        let doc = &documents()[3];
+        let c = compress_with_dictionary(doc, &dict, "2").expect("compress");
+        let mut flipped = dict.clone();
+        flipped[0] = b'[';
+        let other = b"a completely different dictionary about something else".repeat(3);
+        for wrong in [
+            &flipped[..],
+            &dict[1..],
+            &dict[..dict.len() - 1],
+            &other[..],
+            b"",
+        ] {
+            let err = decompress_with_dictionary(&c, wrong).unwrap_err();
+            assert!(matches!(err, ZpaqError::ChecksumMismatch), "{err}");
+        }
+    }
+
+    #[test]
+    fn truncated_stream_is_not_a_wrong_dictionary() {
+        let dict = dictionary();
+        let c = compress_with_dictionary(&documents()[3], &dict, "2").expect("compress");
+        for len in [c.len() / 2, c.len() - 30] {
+            let err = decompress_with_dictionary(&c[..len], &dict).unwrap_err();
+            assert!(err.to_string().contains("unexpected end of file"), "{err}");
+        }
+    }
+}
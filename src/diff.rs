@@ -0,0 +1,584 @@
+//! Differences between archive versions, or between an archive and the
+//! files on disk.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use crate::parts::open_archive;
+use crate::verify::{ArchiveSource, Scan};
+use crate::{ArchiveSpec, Result, SecretString, ZpaqError, io_err, sha1};
+
+/// What [`archive_diff`] compares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffTarget<'a> {
+    /// Version `a` against version `b` of the archive, as numbered by
+    /// `zpaq list -all` (version 0 is the empty archive).
+    Version(u32, u32),
+    /// The latest version of the archive against the files on disk under
+    /// this path.  Only archive entries stored under the same path are
+    /// compared, as `zpaq extract` without `-to` would restore them.
+    Directory(&'a str),
+}
+
+/// Options for [`archive_diff`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DiffOptions {
+    /// Password of an encrypted archive (the `-key` argument of `zpaq`).
+    pub key: Option<SecretString>,
+    /// For [`DiffTarget::Directory`], compare the contents of files whose
+    /// size matches instead of trusting their modification times.
+    pub hash: bool,
+}
+
+/// How a file differs, as reported in a [`DiffEntry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffChange {
+    /// Only on the new side.
+    Added,
+    /// Only on the old side.
+    Removed,
+    /// On both sides with different contents (or, for a directory compared
+    /// without [`DiffOptions::hash`], a different modification time).
+    Modified,
+    /// On both sides with the same contents.
+    Unchanged,
+}
+
+/// One file in the result of [`archive_diff`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiffEntry {
+    /// Path as stored in the archive.
+    pub path: String,
+    /// How the file differs.
+    pub change: DiffChange,
+    /// Size on the old side (the first version, or the archive).
+    pub old_size: Option<u64>,
+    /// Size on the new side (the second version, or the directory).
+    pub new_size: Option<u64>,
+}
+
+/// Compares two versions of the journaling archive at `archive`, or its
+/// latest version with a directory.
+///
+/// Returns one entry per file on either side, sorted by path.  Directory
+/// entries are not compared.  Versions are compared by content: a file
+/// re-added with a new date but the same data is [`DiffChange::Unchanged`].
+///
+/// # Errors
+///
+/// Fails if the archive cannot be read, a version does not exist, or the
+/// archive is too damaged to tell the size of a compared file.
+pub fn archive_diff(
+    archive: &str,
+    target: &DiffTarget<'_>,
+    options: &DiffOptions,
+) -> Result<Vec<DiffEntry>> {
+    let file = open_archive(archive)?;
+    let mut source = ArchiveSource::new(
+        BufReader::new(file),
+        options.key.as_ref().map(SecretString::expose),
+    )?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    let history = History::new(&scan);
+
+    match *target {
+        DiffTarget::Version(a, b) => {
+            let old = history.state(a)?;
+            let new = history.state(b)?;
+            let paths: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            paths
+                .into_iter()
+                .map(|path| {
+                    let old = old.get(path);
+                    let new = new.get(path);
+                    let change = match (old, new) {
+                        (Some(o), Some(n)) if o.fragments == n.fragments => DiffChange::Unchanged,
+                        (Some(_), Some(_)) => DiffChange::Modified,
+                        (Some(_), None) => DiffChange::Removed,
+                        _ => DiffChange::Added,
+                    };
+                    Ok(DiffEntry {
+                        path: path.clone(),
+                        change,
+                        old_size: old.map(|f| history.size(path, f)).transpose()?,
+                        new_size: new.map(|f| history.size(path, f)).transpose()?,
+                    })
+                })
+                .collect()
+        }
+        DiffTarget::Directory(dir) => compare(&history, &[dir], options.hash),
+    }
+}
+
+/// Options for [`zpaq_compare`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompareOptions {
+    /// Compare the contents of files whose size matches, fragment by
+    /// fragment against the SHA-1s in the index, instead of trusting their
+    /// modification times.
+    pub hash: bool,
+}
+
+/// What the next `zpaq add` would do with a file, as reported in a
+/// [`CompareEntry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareState {
+    /// On disk but not in the archive: it would be added.
+    New,
+    /// In both, but changed on disk: it would be added again.
+    Modified,
+    /// In both and unchanged: it would be skipped.
+    Unchanged,
+    /// In the archive but no longer on disk: it would be marked deleted.
+    MissingOnDisk,
+}
+
+/// One file in the result of [`zpaq_compare`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareEntry {
+    /// Path as stored in the archive.
+    pub path: String,
+    /// What the next add would do with it.
+    pub state: CompareState,
+}
+
+/// Tells what `zpaq add <archive> <inputs...>` would change, without
+/// writing anything.
+///
+/// Every regular file at or below `inputs`, and every file of the latest
+/// version of the journaling archive stored under one of them, is listed
+/// once, sorted by path.  Like `zpaq add`, files are taken as unchanged
+/// when their size and modification time match the archive's, unless
+/// [`CompareOptions::hash`] is set.  If `archive` does not exist, every file
+/// is [`CompareState::New`].
+///
+/// # Errors
+///
+/// Fails if the archive cannot be read, or a file cannot be read in hash
+/// mode.
+pub fn zpaq_compare(
+    archive: &str,
+    inputs: &[&Path],
+    options: &CompareOptions,
+) -> Result<Vec<CompareEntry>> {
+    let inputs: Vec<String> = inputs
+        .iter()
+        .map(|input| input.to_string_lossy().into_owned())
+        .collect();
+    let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+    let mut scan = Scan::default();
+    if !ArchiveSpec::parse(archive)?.parts().is_empty() {
+        let file = open_archive(archive)?;
+        let mut source = ArchiveSource::new(BufReader::new(file), None)?;
+        scan.scan(&mut source)?;
+    }
+    let history = History::new(&scan);
+    let entries = compare(&history, &inputs, options.hash)?;
+    Ok(entries
+        .into_iter()
+        .map(|entry| CompareEntry {
+            path: entry.path,
+            state: match entry.change {
+                DiffChange::Added => CompareState::New,
+                DiffChange::Removed => CompareState::MissingOnDisk,
+                DiffChange::Modified => CompareState::Modified,
+                DiffChange::Unchanged => CompareState::Unchanged,
+            },
+        })
+        .collect())
+}
+
+/// Compares the latest version of an archive with the files at or below
+/// `dirs`, as [`DiffTarget::Directory`] describes.
+fn compare(history: &History<'_>, dirs: &[&str], hash: bool) -> Result<Vec<DiffEntry>> {
+    let roots: Vec<String> = dirs.iter().map(|dir| archive_path(dir)).collect();
+    let under = |path: &str| {
+        roots.iter().any(|root| {
+            path == root
+                || root.is_empty()
+                || path
+                    .strip_prefix(root.as_str())
+                    .is_some_and(|rest| rest.starts_with('/') || root.ends_with('/'))
+        })
+    };
+    let latest = history.state(history.versions as u32)?;
+    let mut disk = BTreeMap::new();
+    for (dir, root) in dirs.iter().zip(&roots) {
+        walk(dir, root, &mut disk)?;
+    }
+    let old: BTreeMap<&String, &FileVersion> =
+        latest.iter().filter(|(path, _)| under(path)).collect();
+    let paths: BTreeSet<&String> = old.keys().copied().chain(disk.keys()).collect();
+    paths
+        .into_iter()
+        .map(|path| {
+            let old = old.get(path);
+            let new = disk.get(path);
+            let old_size = old.map(|f| history.size(path, f)).transpose()?;
+            let change = match (old, new) {
+                (Some(o), Some(n)) => {
+                    let same = if Some(n.size) != old_size {
+                        false
+                    } else if hash {
+                        history.same_contents(o, &n.disk_path)?
+                    } else {
+                        o.date == n.date
+                    };
+                    if same {
+                        DiffChange::Unchanged
+                    } else {
+                        DiffChange::Modified
+                    }
+                }
+                (Some(_), None) => DiffChange::Removed,
+                _ => DiffChange::Added,
+            };
+            Ok(DiffEntry {
+                path: path.clone(),
+                change,
+                old_size,
+                new_size: new.map(|n| n.size),
+            })
+        })
+        .collect()
+}
+
+/// A file as recorded in one version of the index.
+struct FileVersion {
+    date: u64,
+    fragments: Vec<u32>,
+}
+
+/// The index and fragment tables of a scanned archive.
+struct History<'a> {
+    scan: &'a Scan<'a>,
+    versions: usize,
+    /// `(size, sha1)` by fragment ID.
+    fragments: HashMap<u32, (u32, [u8; 20])>,
+}
+
+impl<'a> History<'a> {
+    fn new(scan: &'a Scan<'a>) -> Self {
+        History {
+            scan,
+            versions: scan.versions(),
+            fragments: scan.fragments(),
+        }
+    }
+
+    /// Files present after `version`.
+    fn state(&self, version: u32) -> Result<BTreeMap<String, FileVersion>> {
+        let version = version as usize;
+        if version > self.versions {
+            return Err(ZpaqError::Ffi(format!(
+                "version {version} does not exist (archive has {})",
+                self.versions
+            )));
+        }
+        let files = self
+            .scan
+            .files_at(version)
+            .into_iter()
+            .filter(|(path, _)| !path.ends_with('/'))
+            .map(|(path, entry)| {
+                let file = FileVersion {
+                    date: entry.date,
+                    fragments: entry.fragments.clone(),
+                };
+                (path.to_string(), file)
+            })
+            .collect();
+        Ok(files)
+    }
+
+    fn size(&self, path: &str, file: &FileVersion) -> Result<u64> {
+        file.fragments.iter().try_fold(0u64, |total, id| {
+            let (size, _) = self.fragment(path, *id)?;
+            Ok(total + size as u64)
+        })
+    }
+
+    fn fragment(&self, path: &str, id: u32) -> Result<(u32, [u8; 20])> {
+        self.fragments.get(&id).copied().ok_or_else(|| {
+            ZpaqError::Ffi(format!(
+                "{path}: fragment {id} is not described by any h block"
+            ))
+        })
+    }
+
+    /// Compares a file on disk with an archived one, fragment by fragment.
+    /// The caller has checked that the sizes match.
+    fn same_contents(&self, file: &FileVersion, disk_path: &str) -> Result<bool> {
+        let mut reader = BufReader::new(File::open(disk_path).map_err(io_err)?);
+        let mut buf = Vec::new();
+        for &id in &file.fragments {
+            let (size, digest) = self.fragment(disk_path, id)?;
+            buf.resize(size as usize, 0);
+            reader.read_exact(&mut buf).map_err(io_err)?;
+            if sha1(&buf)? != digest {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// A regular file found on disk.
+struct DiskFile {
+    disk_path: String,
+    size: u64,
+    date: u64,
+}
+
+/// Stored form of a path given on the command line: `/` separators and no
+/// trailing separator.
+fn archive_path(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+/// Collects the regular files at or below `disk_path` (which has the stored
+/// form `stored`), without following symbolic links.
+fn walk(disk_path: &str, stored: &str, out: &mut BTreeMap<String, DiskFile>) -> Result<()> {
+    let meta = match fs::symlink_metadata(disk_path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(io_err(e)),
+    };
+    if meta.is_dir() {
+        for entry in fs::read_dir(disk_path).map_err(io_err)? {
+            let entry = entry.map_err(io_err)?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let child = entry.path().to_string_lossy().into_owned();
+            let stored = if stored.ends_with('/') {
+                format!("{stored}{name}")
+            } else {
+                format!("{stored}/{name}")
+            };
+            walk(&child, &stored, out)?;
+        }
+    } else if meta.is_file() {
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        out.insert(
+            stored.to_string(),
+            DiskFile {
+                disk_path: disk_path.to_string(),
+                size: meta.len(),
+                date: decimal_time(mtime),
+            },
+        );
+    }
+    Ok(())
+}
+
+/// Seconds since 1970 to the `YYYYMMDDHHMMSS` dates `zpaq` stores (UTC),
+/// as `decimal_time()` in `zpaq.cpp` computes them.
+pub(crate) fn decimal_time(t: u64) -> u64 {
+    let second = t % 60;
+    let minute = t / 60 % 60;
+    let hour = t / 3600 % 24;
+    let days = t / 86400;
+    let term = days / 1461;
+    let mut t = days % 1461;
+    // Pad every year to 366 days and every month to 31.
+    t += u64::from(t >= 59);
+    t += u64::from(t >= 425);
+    t += u64::from(t >= 1157);
+    let year = term * 4 + t / 366 + 1970;
+    t %= 366;
+    t += u64::from(t >= 60) * 2;
+    t += u64::from(t >= 123);
+    t += u64::from(t >= 185);
+    t += u64::from(t >= 278);
+    t += u64::from(t >= 340);
+    let month = t / 31 + 1;
+    let day = t % 31 + 1;
+    year * 10_000_000_000
+        + month * 100_000_000
+        + day * 1_000_000
+        + hour * 10_000
+        + minute * 100
+        + second
+}
+
+/// The inverse of [`decimal_time`] (`unix_time()` in `zpaq.cpp`), for dates
+/// from 1970 on.
+pub(crate) fn unix_time(date: u64) -> u64 {
+    const DAYS: [u64; 12] = [0, 31, 59, 90, 120, 151, 181, 212, 243, 273, 304, 334];
+    let year = date / 10_000_000_000 % 10_000;
+    let month = (date / 100_000_000 % 100).saturating_sub(1) % 12;
+    let day = date / 1_000_000 % 100;
+    let hour = date / 10_000 % 100;
+    let minute = date / 100 % 100;
+    let second = date % 100;
+    let leap = u64::from(year.is_multiple_of(4) && month > 1);
+    let days = (day + DAYS[month as usize] + leap + (year.saturating_sub(1970) * 1461 + 1) / 4)
+        .saturating_sub(1);
+    days * 86400 + hour * 3600 + minute * 60 + second
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::zpaq_add;
+
+    fn changes(entries: &[DiffEntry]) -> Vec<(String, DiffChange)> {
+        entries
+            .iter()
+            .map(|e| {
+                let name = e.path.rsplit('/').next().expect("name").to_string();
+                (name, e.change)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn versions_and_directories_diff() {
+        let dir = TempDir::new("zpaq-rs-diff");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).expect("data dir");
+        let archive = dir.join("test.zpaq").to_string_lossy().into_owned();
+        let data_s = archive_path(&data.to_string_lossy());
+        let write = |name: &str, text: &str| fs::write(data.join(name), text).expect("write");
+
+        write("a.txt", "alpha stays the same\n");
+        write("b.txt", "bravo before\n");
+        write("c.txt", "charlie goes away\n");
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v1");
+
+        write("b.txt", "bravo after the edit\n");
+        fs::remove_file(data.join("c.txt")).expect("remove c");
+        write("d.txt", "delta is new\n");
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v2");
+
+        let options = DiffOptions::default();
+        let v12 = archive_diff(&archive, &DiffTarget::Version(1, 2), &options).expect("diff");
+        use DiffChange::*;
+        assert_eq!(
+            changes(&v12),
+            [
+                ("a.txt".into(), Unchanged),
+                ("b.txt".into(), Modified),
+                ("c.txt".into(), Removed),
+                ("d.txt".into(), Added),
+            ]
+        );
+        let b = &v12[1];
+        assert_eq!(b.path, format!("{data_s}/b.txt"));
+        assert_eq!((b.old_size, b.new_size), (Some(13), Some(21)));
+        assert_eq!((v12[2].old_size, v12[2].new_size), (Some(18), None));
+
+        let v01 = archive_diff(&archive, &DiffTarget::Version(0, 1), &options).expect("diff");
+        assert!(v01.iter().all(|e| e.change == Added));
+        assert_eq!(v01.len(), 3);
+        assert!(archive_diff(&archive, &DiffTarget::Version(1, 3), &options).is_err());
+
+        let on_disk = DiffTarget::Directory(&data_s);
+        let clean = archive_diff(&archive, &on_disk, &options).expect("diff");
+        assert!(clean.iter().all(|e| e.change == Unchanged), "{clean:?}");
+
+        // Same size and modification time, different contents: only hashing
+        // notices.
+        let a = data.join("a.txt");
+        let mtime = fs::metadata(&a).and_then(|m| m.modified()).expect("mtime");
+        write("a.txt", "ALPHA stays the same\n");
+        File::options()
+            .write(true)
+            .open(&a)
+            .and_then(|f| f.set_modified(mtime))
+            .expect("set mtime");
+        write("e.txt", "echo");
+        let trusting = archive_diff(&archive, &on_disk, &options).expect("diff");
+        let hashing = DiffOptions {
+            hash: true,
+            ..Default::default()
+        };
+        let hashed = archive_diff(&archive, &on_disk, &hashing).expect("diff");
+        assert_eq!(trusting[0].change, Unchanged);
+        assert_eq!(hashed[0].change, Modified);
+        assert_eq!(
+            changes(&hashed)[1..],
+            [
+                ("b.txt".into(), Unchanged),
+                ("d.txt".into(), Unchanged),
+                ("e.txt".into(), Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn compare_classifies_files_for_next_add() {
+        let dir = TempDir::new("zpaq-rs-compare");
+        let data = dir.join("data");
+        let more = dir.join("more.txt");
+        fs::create_dir_all(&data).expect("data dir");
+        let archive = dir.join("test.zpaq").to_string_lossy().into_owned();
+        let write = |name: &str, text: &str| fs::write(data.join(name), text).expect("write");
+        write("touched.txt", "same contents, new date\n");
+        write("edited.txt", "before\n");
+        write("deleted.txt", "soon gone\n");
+        write("kept.txt", "left alone\n");
+        fs::write(&more, "outside the directory\n").expect("write more");
+
+        let inputs = [data.as_path(), more.as_path()];
+        let options = CompareOptions::default();
+        let fresh = zpaq_compare(&archive, &inputs, &options).expect("compare");
+        assert_eq!(fresh.len(), 5);
+        assert!(fresh.iter().all(|e| e.state == CompareState::New));
+
+        let data_s = data.to_string_lossy().into_owned();
+        let more_s = more.to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&data_s, &more_s], "1", 1).expect("add");
+        let before = fs::read(&archive).expect("read archive");
+
+        let touched = data.join("touched.txt");
+        let mtime = fs::metadata(&touched)
+            .and_then(|m| m.modified())
+            .expect("mtime");
+        File::options()
+            .write(true)
+            .open(&touched)
+            .and_then(|f| f.set_modified(mtime + std::time::Duration::from_secs(60)))
+            .expect("touch");
+        write("edited.txt", "after the edit\n");
+        fs::remove_file(data.join("deleted.txt")).expect("remove");
+        write("new.txt", "brand new\n");
+
+        use CompareState::*;
+        let states = |options: &CompareOptions| -> Vec<(String, CompareState)> {
+            zpaq_compare(&archive, &inputs, options)
+                .expect("compare")
+                .into_iter()
+                .map(|e| {
+                    let name = e.path.rsplit('/').next().expect("name").to_string();
+                    (name, e.state)
+                })
+                .collect()
+        };
+        let expected = |touched| {
+            vec![
+                ("deleted.txt".to_string(), MissingOnDisk),
+                ("edited.txt".to_string(), Modified),
+                ("kept.txt".to_string(), Unchanged),
+                ("new.txt".to_string(), New),
+                ("touched.txt".to_string(), touched),
+                ("more.txt".to_string(), Unchanged),
+            ]
+        };
+        assert_eq!(states(&options), expected(Modified));
+        assert_eq!(states(&CompareOptions { hash: true }), expected(Unchanged));
+        assert_eq!(fs::read(&archive).expect("read archive"), before);
+    }
+}
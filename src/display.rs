@@ -0,0 +1,258 @@
+//! Helpers for the human-readable tables of the report types' `Display`.
+//!
+//! Sizes are right-aligned to the widest in the table and paths come last,
+//! so a long path never pushes the other columns out of line.  A precision
+//! (`{:.40}`) caps the width of paths, shortening longer ones from the
+//! front so the file name stays readable.
+
+use std::borrow::Cow;
+use std::fmt::{Formatter, Result};
+
+/// `path` cut to at most `max` characters (when given), keeping its end
+/// behind a leading `...`.
+pub(crate) fn shorten(path: &str, max: Option<usize>) -> Cow<'_, str> {
+    let Some(max) = max else {
+        return Cow::Borrowed(path);
+    };
+    let len = path.chars().count();
+    if len <= max {
+        return Cow::Borrowed(path);
+    }
+    let keep = max.saturating_sub(3);
+    let tail: String = path.chars().skip(len - keep).collect();
+    Cow::Owned(format!("{}{tail}", &"..."[..max.min(3)]))
+}
+
+/// Width of the widest of `sizes` in decimal.
+pub(crate) fn size_width(sizes: impl IntoIterator<Item = u64>) -> usize {
+    sizes
+        .into_iter()
+        .map(|size| size.to_string().len())
+        .max()
+        .unwrap_or(1)
+}
+
+/// A `YYYYMMDDHHMMSS` date as `YYYY-MM-DD HH:MM:SS`.
+pub(crate) fn date(date: u64) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        date / 10_000_000_000,
+        date / 100_000_000 % 100,
+        date / 1_000_000 % 100,
+        date / 10_000 % 100,
+        date / 100 % 100,
+        date % 100
+    )
+}
+
+/// `n` followed by `noun`, with an `s` unless `n` is 1.
+pub(crate) fn count(n: impl Into<u64>, noun: &str) -> String {
+    let n = n.into();
+    match n {
+        1 => format!("1 {noun}"),
+        _ => format!("{n} {noun}s"),
+    }
+}
+
+/// `n` bytes in the largest of GiB, MiB and KiB that divides it exactly.
+pub(crate) fn bytes(n: u64) -> String {
+    for (unit, name) in [(1 << 30, "GiB"), (1 << 20, "MiB"), (1 << 10, "KiB")] {
+        if n >= unit && n.is_multiple_of(unit) {
+            return format!("{} {name}", n / unit);
+        }
+    }
+    count(n, "byte")
+}
+
+/// Writes `text` line by line, each line indented by `indent`, ending with
+/// a newline unless empty.
+pub(crate) fn indented(f: &mut Formatter<'_>, indent: &str, text: &str) -> Result {
+    for line in text.lines() {
+        writeln!(f, "{indent}{line}")?;
+    }
+    Ok(())
+}
+
+/// Lowercase hex of `bytes`.
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        AddReport, ArchiveEntryInfo, ArchiveListing, BlockInfo, EntryMeta, ExtractReport,
+        SegmentInfo, VerifyError, VerifyErrorKind, VerifyLocation, VerifyReport, ZpaqCommandOutput,
+        ZpaqError,
+    };
+    use std::path::PathBuf;
+
+    fn entry(path: &str, size: u64, date: Option<u64>) -> ArchiveEntryInfo {
+        ArchiveEntryInfo {
+            path: path.into(),
+            size,
+            date,
+            comment: None,
+            meta: EntryMeta::default(),
+        }
+    }
+
+    #[test]
+    fn long_paths_are_shortened_from_the_front() {
+        assert_eq!(shorten("short.txt", Some(20)), "short.txt");
+        assert_eq!(
+            shorten("a/very/long/path/to/file.txt", Some(12)),
+            ".../file.txt"
+        );
+        assert_eq!(shorten("αβγδεζηθ", Some(5)), "...ηθ");
+        assert_eq!(shorten("abcdef", Some(2)), "..");
+        assert_eq!(shorten("abcdef", None), "abcdef");
+    }
+
+    #[test]
+    fn listings_are_aligned_tables() {
+        let listing = ArchiveListing {
+            versions: 2,
+            entries: vec![
+                entry("docs/readme.md", 1200, Some(20240131235959)),
+                entry(
+                    "docs/a/deeply/nested/directory/of/reports/2024/q1.csv",
+                    5,
+                    Some(20240101000000),
+                ),
+                entry("stream.bin", 73_000_000, None),
+            ],
+        };
+        assert_eq!(
+            listing.to_string(),
+            "    1200  2024-01-31 23:59:59  docs/readme.md
+       5  2024-01-01 00:00:00  docs/a/deeply/nested/directory/of/reports/2024/q1.csv
+73000000  -                    stream.bin
+3 files, 73001205 bytes, 2 versions
+"
+        );
+        assert_eq!(
+            format!("{listing:.20}"),
+            "    1200  2024-01-31 23:59:59  docs/readme.md
+       5  2024-01-01 00:00:00  ...ports/2024/q1.csv
+73000000  -                    stream.bin
+3 files, 73001205 bytes, 2 versions
+"
+        );
+        assert_eq!(
+            ArchiveListing::default().to_string(),
+            "0 files, 0 bytes, 0 versions\n"
+        );
+    }
+
+    #[test]
+    fn reports_render_as_summaries() {
+        let output = ZpaqCommandOutput {
+            stdout: "Adding 1 file\n".into(),
+            stderr: "warning: skipped\n".into(),
+        };
+        assert_eq!(output.to_string(), "Adding 1 file\nwarning: skipped\n");
+
+        let mismatch = VerifyError {
+            location: VerifyLocation {
+                offset: 120,
+                segment: "jDC20240101120000d0000000001".into(),
+                fragment: Some(1),
+                file: None,
+            },
+            kind: VerifyErrorKind::FragmentMismatch,
+        };
+        let report = AddReport {
+            output: output.clone(),
+            verified: false,
+            mismatches: vec![mismatch.clone()],
+        };
+        assert_eq!(
+            report.to_string(),
+            "\
+Adding 1 file
+warning: skipped
+verification failed:
+  block at offset 120 (jDC20240101120000d0000000001), fragment 1: FragmentMismatch
+"
+        );
+        let unchecked = AddReport {
+            output,
+            ..Default::default()
+        };
+        assert!(unchecked.to_string().ends_with("skipped\n"));
+
+        let report = VerifyReport {
+            blocks_checked: 4,
+            segments_checked: 5,
+            extra_checksums_checked: 0,
+            errors: vec![mismatch],
+        };
+        assert_eq!(
+            report.to_string(),
+            "\
+block at offset 120 (jDC20240101120000d0000000001), fragment 1: FragmentMismatch
+4 blocks, 5 segments, 1 error
+"
+        );
+        assert_eq!(
+            VerifyReport::default().to_string(),
+            "0 blocks, 0 segments, 0 errors\n"
+        );
+
+        let extracted = ExtractReport {
+            written: vec![
+                (PathBuf::from("out/a.txt"), 12),
+                (PathBuf::from("out/b/c.txt"), 3456),
+            ],
+            rejected: vec![ZpaqError::PathTraversal("../etc/passwd".into())],
+        };
+        assert_eq!(
+            extracted.to_string(),
+            "  12  out/a.txt
+3456  out/b/c.txt
+skipped: unsafe path in archive: ../etc/passwd
+2 files, 3468 bytes
+"
+        );
+    }
+
+    #[test]
+    fn blocks_list_their_segments() {
+        let segment = |filename: &str, size, sha1| SegmentInfo {
+            filename: filename.into(),
+            comment: format!("{size}"),
+            filename_bytes: filename.as_bytes().to_vec(),
+            comment_bytes: format!("{size}").into_bytes(),
+            size,
+            sha1,
+            extra_checksum: None,
+            stored: false,
+        };
+        let block = BlockInfo {
+            memory_bytes: 4_194_304.0,
+            components: 2,
+            hcomp_len: 30,
+            model_fingerprint: [0; 32],
+            has_pcomp: false,
+            stored: false,
+            segments: vec![
+                segment("a.txt", 10, Some([0xab; 20])),
+                segment("", 123_456, None),
+            ],
+        };
+        assert_eq!(
+            block.segments[0].to_string(),
+            "10  abababababababababababababababababababab  a.txt  (10)"
+        );
+        assert_eq!(
+            block.to_string(),
+            "\
+modeled block, 2 components, 4194304 bytes of memory, 2 segments
+      10  abababababababababababababababababababab  a.txt  (10)
+  123456  -                                         -  (123456)
+"
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! Extracting in-memory archives to a directory.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Cursor, Read, Seek};
+use std::path::{Component, Path, PathBuf};
+
+use crate::display;
+use crate::{ArchiveReader, MemberFilter, Result, ZpaqError, io_err};
+
+/// Result of [`archive_extract_to_dir`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtractReport {
+    /// Files written, with their path on disk and size in bytes, in stored
+    /// path order.
+    pub written: Vec<(PathBuf, u64)>,
+    /// Files skipped because their stored path would leave the destination,
+    /// each as a [`ZpaqError::PathTraversal`].
+    pub rejected: Vec<ZpaqError>,
+}
+
+/// A table of the files written, sizes aligned, then the skipped files and
+/// the totals.  A precision (`{:.40}`) shortens long paths.
+impl std::fmt::Display for ExtractReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = display::size_width(self.written.iter().map(|(_, size)| *size));
+        for (path, size) in &self.written {
+            let path = path.to_string_lossy();
+            writeln!(
+                f,
+                "{size:>width$}  {}",
+                display::shorten(&path, f.precision())
+            )?;
+        }
+        for error in &self.rejected {
+            writeln!(f, "skipped: {error}")?;
+        }
+        let total: u64 = self.written.iter().map(|(_, size)| size).sum();
+        writeln!(
+            f,
+            "{}, {}",
+            display::count(self.written.len() as u64, "file"),
+            display::count(total, "byte")
+        )
+    }
+}
+
+/// Writes the files of an in-memory archive below the directory `dest`, or
+/// only those selected by `filter`.
+///
+/// Files are read as by [`ArchiveReader::read`] (the latest version, or the
+/// newest copy of a streaming segment) and stored paths are recreated below
+/// `dest`, creating directories as needed and overwriting existing files.
+/// Modification times from journaling archives are restored, as are Unix
+/// permissions on Unix, less the setuid, setgid and sticky bits.  Empty
+/// directories are not created.
+///
+/// A stored path that is absolute, has a `..` component or a component
+/// with a `:` in it (a drive letter, say) is not written but reported in [`ExtractReport::rejected`],
+/// so a hostile archive cannot write outside `dest`.  Archives made by
+/// `zpaq add` of absolute paths store them as such; extract those with
+/// [`zpaq_extract`](crate::zpaq_extract) and `-to` instead.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let archive = std::fs::read("site.zpaq")?;
+/// let filter = zpaq_rs::MemberFilter::Prefix("assets/");
+/// let report = zpaq_rs::archive_extract_to_dir(&archive, "out", Some(&filter))?;
+/// for (path, size) in &report.written {
+///     println!("{} ({size} bytes)", path.display());
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn archive_extract_to_dir(
+    archive: &[u8],
+    dest: impl AsRef<Path>,
+    filter: Option<&MemberFilter<'_>>,
+) -> Result<ExtractReport> {
+    archive_extract_reader_to_dir(Cursor::new(archive), dest, filter)
+}
+
+/// Like [`archive_extract_to_dir`], reading the archive from `reader`, for
+/// example an archive file, instead of from memory.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let archive = std::io::BufReader::new(std::fs::File::open("site.zpaq")?);
+/// zpaq_rs::archive_extract_reader_to_dir(archive, "out", None)?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn archive_extract_reader_to_dir<R: Read + Seek + Send>(
+    reader: R,
+    dest: impl AsRef<Path>,
+    filter: Option<&MemberFilter<'_>>,
+) -> Result<ExtractReport> {
+    let mut reader = ArchiveReader::open(reader)?;
+    let entries: Vec<_> = reader
+        .entries()
+        .filter(|entry| filter.is_none_or(|filter| filter.matches(&entry.path)))
+        .cloned()
+        .collect();
+    let dest = dest.as_ref();
+    fs::create_dir_all(dest).map_err(io_err)?;
+
+    let mut report = ExtractReport::default();
+    for entry in entries {
+        let Some(target) = safe_path(dest, &entry.path) else {
+            report.rejected.push(ZpaqError::PathTraversal(entry.path));
+            continue;
+        };
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).map_err(io_err)?;
+        }
+        let file = File::create(&target)
+            .map_err(|e| ZpaqError::Ffi(format!("{}: {e}", target.display())))?;
+        let mut out = BufWriter::new(file);
+        let size = reader.read_to(&entry.path, &mut out)?;
+        let file = out.into_inner().map_err(|e| io_err(e.into_error()))?;
+        if let Some(mtime) = entry.meta.mtime {
+            file.set_modified(mtime).map_err(io_err)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = entry.meta.unix_mode {
+            use std::os::unix::fs::PermissionsExt;
+            let permissions = fs::Permissions::from_mode(mode & 0o777);
+            file.set_permissions(permissions).map_err(io_err)?;
+        }
+        report.written.push((target, size));
+    }
+    Ok(report)
+}
+
+/// Maps a stored path to a path below `dest`, or `None` if it is absolute,
+/// has a `..` component or one containing `:`, or is empty.
+///
+/// Each component is checked as a path of its own, as on Windows pushing
+/// one with a prefix such as `C:` would replace everything before it.
+fn safe_path(dest: &Path, stored: &str) -> Option<PathBuf> {
+    if stored.starts_with(['/', '\\']) {
+        return None;
+    }
+    let mut out = dest.to_path_buf();
+    for part in stored.split(['/', '\\']) {
+        if part.contains(':') {
+            return None;
+        }
+        for component in Path::new(part).components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => out.push(name),
+                _ => return None,
+            }
+        }
+    }
+    (out != dest).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{
+        ArchiveEntry, ArchiveEntryOwned, archive_from_entries, archive_from_owned_entries,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn traversal_is_rejected_and_the_rest_extracted() {
+        let dir = TempDir::new("zpaq-rs-extract");
+        let dest = dir.join("out");
+        let entry = |path, data| ArchiveEntry {
+            path,
+            data,
+            comment: None,
+        };
+        let archive = archive_from_entries(
+            &[
+                entry("a.txt", b"alpha"),
+                entry("sub/dir/b.txt", b"bravo"),
+                entry("../escape.txt", b"gotcha"),
+                entry("sub/../../escape.txt", b"gotcha"),
+                entry("/etc/escape.txt", b"gotcha"),
+                entry("c:/escape.txt", b"gotcha"),
+                entry("a/C:/escape.txt", b"gotcha"),
+                entry("a/C:escape.txt", b"gotcha"),
+            ],
+            "1",
+        )
+        .expect("archive");
+
+        let report = archive_extract_to_dir(&archive, &dest, None).expect("extract");
+        assert_eq!(
+            report.written,
+            [(dest.join("a.txt"), 5), (dest.join("sub/dir/b.txt"), 5)]
+        );
+        let mut rejected: Vec<_> = report
+            .rejected
+            .iter()
+            .map(|e| match e {
+                ZpaqError::PathTraversal(path) => path.as_str(),
+                other => panic!("unexpected error {other}"),
+            })
+            .collect();
+        rejected.sort_unstable();
+        assert_eq!(
+            rejected,
+            [
+                "../escape.txt",
+                "/etc/escape.txt",
+                "a/C:/escape.txt",
+                "a/C:escape.txt",
+                "c:/escape.txt",
+                "sub/../../escape.txt"
+            ]
+        );
+        assert_eq!(fs::read(dest.join("sub/dir/b.txt")).expect("b"), b"bravo");
+        assert!(!dir.join("escape.txt").exists());
+
+        // Journaling archives restore dates and permissions.
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let owned = ArchiveEntryOwned::from_bytes("docs/c.txt", b"charlie".to_vec())
+            .with_mtime(mtime)
+            .with_unix_mode(0o104600);
+        let skipped = ArchiveEntryOwned::from_bytes("other/d.txt", b"delta".to_vec());
+        let journal = archive_from_owned_entries([owned, skipped], "1").expect("journal");
+        let filter = MemberFilter::Prefix("docs/");
+        let report = archive_extract_to_dir(&journal, &dest, Some(&filter)).expect("extract");
+        let c = dest.join("docs/c.txt");
+        assert_eq!(report.written, [(c.clone(), 7)]);
+        let metadata = fs::metadata(&c).expect("stat");
+        assert_eq!(metadata.modified().expect("mtime"), mtime);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            // The setuid bit is not restored.
+            assert_eq!(metadata.permissions().mode() & 0o7777, 0o600);
+        }
+        assert!(!dest.join("other").exists());
+    }
+}
@@ -0,0 +1,120 @@
+//! Selecting archive members by path.
+
+/// Which stored paths to select, for [`archive_read_matching`] and
+/// [`archive_entries`].
+///
+/// Paths are matched exactly as stored, with `/` separators.
+///
+/// [`archive_read_matching`]: crate::archive_read_matching
+/// [`archive_entries`]: crate::archive_entries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberFilter<'a> {
+    /// Exactly this path.
+    Exact(&'a str),
+    /// Paths starting with this string.  Add a trailing `/` to select the
+    /// contents of a directory: `"assets/"` does not match `"assets2/x"`.
+    Prefix(&'a str),
+    /// Paths matching a glob pattern.  `?` matches any character but `/`,
+    /// `*` any run of characters without a `/`, and `**` any run including
+    /// `/`; `**/` also matches no directory at all, so `"a/**/*.png"`
+    /// selects `"a/x.png"` and `"a/b/c/x.png"`.  Other characters match
+    /// themselves.
+    Glob(&'a str),
+}
+
+impl MemberFilter<'_> {
+    /// Whether the stored path `path` is selected.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use zpaq_rs::MemberFilter;
+    ///
+    /// let textures = MemberFilter::Glob("assets/textures/*.png");
+    /// assert!(textures.matches("assets/textures/stone.png"));
+    /// assert!(!textures.matches("assets/textures/old/stone.png"));
+    /// assert!(MemberFilter::Prefix("assets/").matches("assets/textures/old/stone.png"));
+    /// ```
+    pub fn matches(&self, path: &str) -> bool {
+        match *self {
+            MemberFilter::Exact(exact) => path == exact,
+            MemberFilter::Prefix(prefix) => path.starts_with(prefix),
+            MemberFilter::Glob(pattern) => {
+                let pattern: Vec<char> = pattern.chars().collect();
+                let path: Vec<char> = path.chars().collect();
+                glob(&pattern, &path)
+            }
+        }
+    }
+}
+
+/// Whether `pattern` selects the stored path `path` the way `zpaq` file
+/// arguments do: as a [`MemberFilter::Glob`] matching the path itself or one
+/// of its parent directories, so a directory selects its contents.
+pub(crate) fn selects(pattern: &str, path: &str) -> bool {
+    let filter = MemberFilter::Glob(pattern.strip_suffix('/').unwrap_or(pattern));
+    filter.matches(path.strip_suffix('/').unwrap_or(path))
+        || path
+            .match_indices('/')
+            .any(|(i, _)| i > 0 && filter.matches(&path[..i]))
+}
+
+fn glob(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => {
+            rest.strip_prefix(&['/'])
+                .is_some_and(|after| glob(after, path))
+                || (0..=path.len()).any(|i| glob(rest, &path[i..]))
+        }
+        ['*', rest @ ..] => {
+            let run = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=run).any(|i| glob(rest, &path[i..]))
+        }
+        ['?', rest @ ..] => path.first().is_some_and(|&c| c != '/') && glob(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && glob(rest, &path[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs_match_like_shell_patterns() {
+        let cases = [
+            ("*.txt", "a.txt", true),
+            ("*.txt", "dir/a.txt", false),
+            ("dir/*", "dir/a.txt", true),
+            ("dir/*", "dir/sub/a.txt", false),
+            ("dir/**", "dir/sub/a.txt", true),
+            ("dir/**/a.txt", "dir/a.txt", true),
+            ("dir/**/a.txt", "dir/x/y/a.txt", true),
+            ("dir/**/a.txt", "dir/x/y/b.txt", false),
+            ("**/*.png", "/abs/path/x.png", true),
+            ("?.bin", "é.bin", true),
+            ("?.bin", "/.bin", false),
+            ("a*b*c", "aXXbYYc", true),
+            ("a*b*c", "aXXbYY", false),
+            ("", "", true),
+            ("exact", "exactly", false),
+        ];
+        for (pattern, path, expected) in cases {
+            assert_eq!(
+                MemberFilter::Glob(pattern).matches(path),
+                expected,
+                "{pattern} vs {path}"
+            );
+        }
+        assert!(MemberFilter::Prefix("").matches("anything"));
+        assert!(!MemberFilter::Prefix("assets/").matches("assets2/x"));
+        assert!(MemberFilter::Exact("a/b").matches("a/b"));
+        assert!(!MemberFilter::Exact("a/b").matches("a/b/"));
+
+        assert!(selects("docs", "docs/a/b.txt"));
+        assert!(selects("docs/", "docs/"));
+        assert!(selects("*/a", "docs/a/b.txt"));
+        assert!(!selects("docs", "docs2/a.txt"));
+        assert!(!selects("", "/abs/a.txt"));
+    }
+}
@@ -0,0 +1,258 @@
+//! Telling streaming, journaling and encrypted archives apart.
+
+use std::io::{Read, Seek};
+
+use crate::verify::parse_journal_name;
+use crate::{Result, ZPAQ_TAG, ZpaqError, io_err};
+
+/// The kind of data an archive holds, as told by [`archive_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Plain ZPAQ blocks, as written by [`compress_stream`](crate::compress_stream)
+    /// and [`archive_from_entries`](crate::archive_from_entries).
+    Streaming,
+    /// Versions of `c`, `d`, `h` and `i` blocks, as written by `zpaq add`
+    /// and [`ArchiveWriter`](crate::ArchiveWriter).
+    Journaling {
+        /// Number of versions (`c` blocks, an incomplete last one included)
+        /// that start in the bytes examined: exact if they were the whole
+        /// archive, a lower bound otherwise.  `None` if the first block is
+        /// not a `c` block.
+        versions_hint: Option<u32>,
+    },
+    /// No ZPAQ block, but random-looking bytes, as an archive encrypted with
+    /// `zpaq -key` begins.
+    Encrypted,
+    /// Anything else.
+    Unknown,
+}
+
+impl std::fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArchiveFormat::Streaming => write!(f, "streaming archive"),
+            ArchiveFormat::Journaling { .. } => write!(f, "journaling archive"),
+            ArchiveFormat::Encrypted => write!(f, "encrypted archive"),
+            ArchiveFormat::Unknown => write!(f, "not a ZPAQ archive"),
+        }
+    }
+}
+
+/// Tells what kind of archive `prefix`, the start of an archive, belongs to.
+///
+/// The first block is found as `zpaq` finds it: at the start, or after
+/// other data at the first locator tag.  Its first segment name tells the
+/// formats apart: journaling archives name theirs `jDC`, a 14-digit date,
+/// the block type and a 10-digit number.  A few kilobytes of prefix are
+/// plenty; encryption can only be guessed from the bytes looking random,
+/// so an empty or short prefix is [`Unknown`](ArchiveFormat::Unknown), as
+/// is compressed data of other formats that happens not to look random.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{ArchiveFormat, archive_format, compress_to_vec};
+///
+/// let stream = compress_to_vec(b"hello", "1")?;
+/// assert_eq!(archive_format(&stream)?, ArchiveFormat::Streaming);
+/// assert_eq!(archive_format(b"hello")?, ArchiveFormat::Unknown);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails if `prefix` ends inside the first block's header, before its first
+/// segment name.
+pub fn archive_format(prefix: &[u8]) -> Result<ArchiveFormat> {
+    let start = if crate::is_zpaq(prefix) {
+        Some(0)
+    } else {
+        prefix.windows(ZPAQ_TAG.len()).position(|w| w == ZPAQ_TAG)
+    };
+    let Some(start) = start else {
+        return Ok(if looks_random(prefix) {
+            ArchiveFormat::Encrypted
+        } else {
+            ArchiveFormat::Unknown
+        });
+    };
+    let mut block = &prefix[start..];
+    if block.starts_with(&ZPAQ_TAG) {
+        block = &block[ZPAQ_TAG.len()..];
+    }
+    let truncated = || ZpaqError::Ffi("archive prefix ends in the first block header".into());
+    if block.len() < 7 {
+        return Err(truncated());
+    }
+    if !matches!(block, [b'z', b'P', b'Q', 1 | 2, 1, ..]) {
+        return Ok(ArchiveFormat::Unknown);
+    }
+    let segment = 7 + u16::from_le_bytes([block[5], block[6]]) as usize;
+    let name = match block.get(segment..) {
+        None | Some([]) => return Err(truncated()),
+        Some([1, rest @ ..]) => {
+            let len = rest.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+            &rest[..len]
+        }
+        // A block without segments, or a damaged one.
+        Some(_) => return Ok(ArchiveFormat::Streaming),
+    };
+    let Some(first) = journal_kind(name) else {
+        return Ok(ArchiveFormat::Streaming);
+    };
+    let versions_hint = (first == b'c').then(|| {
+        // Every block's first segment name follows its header uncompressed.
+        prefix
+            .windows(30)
+            .filter(|w| w[0] == 1 && w[29] == 0 && journal_kind(&w[1..29]) == Some(b'c'))
+            .count() as u32
+    });
+    Ok(ArchiveFormat::Journaling { versions_hint })
+}
+
+/// The block type of a journaling segment name.
+fn journal_kind(name: &[u8]) -> Option<u8> {
+    Some(parse_journal_name(std::str::from_utf8(name).ok()?)?.kind)
+}
+
+/// Whether `bytes` look like the salt and ciphertext of an encrypted
+/// archive: at least 32 bytes (the salt), with nearly as many distinct byte
+/// values as random bytes would have.
+fn looks_random(bytes: &[u8]) -> bool {
+    let sample = &bytes[..bytes.len().min(1024)];
+    if sample.len() < 32 {
+        return false;
+    }
+    let mut seen = [false; 256];
+    for &b in sample {
+        seen[b as usize] = true;
+    }
+    let distinct = seen.iter().filter(|&&s| s).count() as f64;
+    let expected = 256.0 * (1.0 - (255.0f64 / 256.0).powi(sample.len() as i32));
+    distinct >= 0.9 * expected
+}
+
+/// Bytes of an archive [`sniff`] examines.
+const SNIFF_LEN: u64 = 4096;
+
+/// Tells the format of the archive at `reader`'s position, which is kept.
+/// `None` if it cannot be told: the archive is empty, ends inside the first
+/// block header, or has no ZPAQ block in the bytes examined but is longer.
+pub(crate) fn sniff<R: Read + Seek>(reader: &mut R) -> Result<Option<ArchiveFormat>> {
+    let mut prefix = Vec::new();
+    reader
+        .by_ref()
+        .take(SNIFF_LEN)
+        .read_to_end(&mut prefix)
+        .map_err(io_err)?;
+    reader
+        .seek_relative(-(prefix.len() as i64))
+        .map_err(io_err)?;
+    Ok(match archive_format(&prefix) {
+        _ if prefix.is_empty() => None,
+        Ok(ArchiveFormat::Unknown) if prefix.len() as u64 == SNIFF_LEN => None,
+        Ok(format) => Some(format),
+        Err(_) => None,
+    })
+}
+
+/// Fails with [`ZpaqError::WrongFormat`] if the archive at `reader`'s
+/// position is encrypted or not an archive.
+pub(crate) fn require_plain<R: Read + Seek>(reader: &mut R) -> Result<()> {
+    match sniff(reader)? {
+        Some(found @ (ArchiveFormat::Encrypted | ArchiveFormat::Unknown)) => {
+            Err(ZpaqError::WrongFormat { found })
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{
+        ArchiveEntry, ArchiveReader, archive_append_entries, archive_from_entries,
+        archive_list_entries, archive_read_file_bytes, zpaq_add, zpaq_command,
+    };
+
+    #[test]
+    fn formats_are_told_apart_and_rejected_where_wrong() {
+        let dir = TempDir::new("zpaq-rs-format");
+        let input = dir.join("a.txt");
+        let input_s = input.to_string_lossy().into_owned();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        let entry = ArchiveEntry {
+            path: "a.txt",
+            data: b"alpha",
+            comment: None,
+        };
+        let streaming = archive_from_entries(&[entry], "1").expect("streaming");
+        assert_eq!(
+            archive_format(&streaming).expect("format"),
+            ArchiveFormat::Streaming
+        );
+
+        let journaling = path("journal.zpaq");
+        for round in 0..2 {
+            std::fs::write(&input, "version\n".repeat(round + 1)).expect("write input");
+            zpaq_add(&journaling, &[&input_s], "1", 1).expect("add");
+        }
+        let journaling = std::fs::read(&journaling).expect("read journaling");
+        assert_eq!(
+            archive_format(&journaling).expect("format"),
+            ArchiveFormat::Journaling {
+                versions_hint: Some(2)
+            }
+        );
+        // A prefix holding only the first version's blocks.
+        assert_eq!(
+            archive_format(&journaling[..100]).expect("format"),
+            ArchiveFormat::Journaling {
+                versions_hint: Some(1)
+            }
+        );
+
+        let encrypted = path("secret.zpaq");
+        zpaq_command(&["add", &encrypted, &input_s, "-key", "hunter2"]).expect("add -key");
+        let encrypted = std::fs::read(&encrypted).expect("read encrypted");
+        assert_eq!(
+            archive_format(&encrypted).expect("format"),
+            ArchiveFormat::Encrypted
+        );
+
+        let text = "just some text, certainly not an archive\n".repeat(20);
+        for unknown in [&b""[..], b"zPQ", text.as_bytes()] {
+            assert_eq!(
+                archive_format(unknown).expect("format"),
+                ArchiveFormat::Unknown
+            );
+        }
+        assert!(archive_format(&streaming[..ZPAQ_TAG.len() + 8]).is_err());
+
+        let wrong = |result: Result<()>| match result {
+            Err(ZpaqError::WrongFormat { found }) => found,
+            Err(other) => panic!("expected WrongFormat, got {other}"),
+            Ok(_) => panic!("expected WrongFormat"),
+        };
+        assert_eq!(
+            wrong(archive_read_file_bytes(&encrypted, "a.txt").map(drop)),
+            ArchiveFormat::Encrypted
+        );
+        assert_eq!(
+            wrong(archive_list_entries(&encrypted).map(drop)),
+            ArchiveFormat::Encrypted
+        );
+        assert_eq!(
+            wrong(ArchiveReader::open(std::io::Cursor::new(text.as_bytes())).map(drop)),
+            ArchiveFormat::Unknown
+        );
+        let mut appended = encrypted.clone();
+        assert_eq!(
+            wrong(archive_append_entries(&mut appended, &[entry], "1")),
+            ArchiveFormat::Encrypted
+        );
+        assert_eq!(appended, encrypted);
+    }
+}
@@ -0,0 +1,761 @@
+//! The fragment-level view of journaling archives.
+//!
+//! `zpaq add` splits files into content-defined fragments, stores each
+//! distinct fragment once, and records every file as a list of fragment IDs.
+//! The SHA-1 and size of each fragment are kept in the `h` blocks, so
+//! fragments can be compared across archives without decompressing data.
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::io::BufReader;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::diff::unix_time;
+use crate::parts::open_archive;
+use crate::verify::{ArchiveSource, Scan, SegmentSink};
+use crate::{Result, ZpaqError};
+
+/// One fragment of one file, as returned by [`archive_fragments`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FragmentRef {
+    /// Path of the file as stored in the archive.
+    pub file: String,
+    /// Position of the fragment in the file (0 for the first).
+    pub ordinal: usize,
+    /// Fragment ID, unique within the archive.
+    pub id: u32,
+    /// SHA-1 of the fragment's data.
+    pub sha1: [u8; 20],
+    /// Uncompressed size of the fragment in bytes.
+    pub usize: u32,
+    /// ID of the block holding the fragment (the ID of its first fragment).
+    pub block: u32,
+}
+
+/// Lists the fragments of the current version of every file in the
+/// journaling archive at `archive`, or only of `file` (a stored path).
+///
+/// Entries are ordered by path, then by position in the file.  A fragment
+/// shared by several files (or repeated within one) is listed once per use.
+pub fn archive_fragments(archive: &str, file: Option<&str>) -> Result<Vec<FragmentRef>> {
+    let scan = scan_archive(archive)?;
+    let mut fragments = HashMap::new();
+    for table in &scan.tables {
+        for (i, &(size, sha1)) in table.fragments.iter().enumerate() {
+            fragments.insert(table.first + i as u32, (size, sha1, table.first));
+        }
+    }
+
+    let mut out = Vec::new();
+    for (path, entry) in scan.files_at(scan.versions()) {
+        if file.is_some_and(|f| f != path) {
+            continue;
+        }
+        for (ordinal, &id) in entry.fragments.iter().enumerate() {
+            let &(usize, sha1, block) = fragments.get(&id).ok_or_else(|| {
+                ZpaqError::Ffi(format!(
+                    "{path}: fragment {id} is not described by any h block"
+                ))
+            })?;
+            out.push(FragmentRef {
+                file: path.to_string(),
+                ordinal,
+                id,
+                sha1,
+                usize,
+                block,
+            });
+        }
+    }
+    Ok(out)
+}
+
+/// Totals for a journaling archive, as shown by `zpaq list -summary`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveSummary {
+    /// Number of versions (complete transactions).
+    pub versions: u32,
+    /// Number of files in the latest version, directories excluded.
+    pub files: u64,
+    /// Number of fragments stored in the archive, including those only
+    /// referenced by older versions.
+    pub fragments: u64,
+    /// Total size of the files in the latest version.
+    pub uncompressed_bytes: u64,
+    /// Size of the archive up to the end of its last complete transaction.
+    pub compressed_bytes: u64,
+    /// `uncompressed_bytes` divided by the size of the distinct fragments
+    /// the latest version uses; 1.0 when nothing is shared, or for an empty
+    /// version.
+    pub dedup_ratio: f64,
+}
+
+/// Summarizes the journaling archive at `archive`: the version, file and
+/// fragment counts and sizes `zpaq list -summary` reports.
+///
+/// The numbers come from the archive's own index blocks, not from the text
+/// `zpaq` prints.  Unlike the "files" count `zpaq` prints, directories are
+/// not counted, so `files` is the length of the listings of
+/// [`archive_entries`](crate::archive_entries).
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+pub fn zpaq_summary(archive: &str) -> Result<ArchiveSummary> {
+    let scan = scan_archive(archive)?;
+    let versions = scan.versions();
+    if versions == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    let fragments = scan.fragments();
+    let mut files = 0;
+    let mut uncompressed = 0u64;
+    let mut distinct = HashSet::new();
+    let mut deduplicated = 0u64;
+    for (path, entry) in scan.files_at(versions) {
+        if path.ends_with('/') {
+            continue;
+        }
+        files += 1;
+        for &id in &entry.fragments {
+            let &(size, _) = fragments.get(&id).ok_or_else(|| {
+                ZpaqError::Ffi(format!(
+                    "{path}: fragment {id} is not described by any h block"
+                ))
+            })?;
+            uncompressed += size as u64;
+            if distinct.insert(id) {
+                deduplicated += size as u64;
+            }
+        }
+    }
+    let compressed = match scan.incomplete {
+        Some(end) => end,
+        None => open_archive(archive)?.len(),
+    };
+    Ok(ArchiveSummary {
+        versions: versions as u32,
+        files,
+        fragments: fragments.len() as u64,
+        uncompressed_bytes: uncompressed,
+        compressed_bytes: compressed,
+        dedup_ratio: if deduplicated == 0 {
+            1.0
+        } else {
+            uncompressed as f64 / deduplicated as f64
+        },
+    })
+}
+
+/// One version of a journaling archive, as `zpaq list -all` shows it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// Version number, from 1.
+    pub number: u32,
+    /// Date of the version as `YYYYMMDDHHMMSS` (UTC).
+    pub date: u64,
+    /// The same date as a time.
+    pub time: SystemTime,
+    /// Files and directories added or updated.
+    pub added: u64,
+    /// Files and directories deleted.
+    pub deleted: u64,
+}
+
+/// Lists the versions of the journaling archive at `archive`, oldest first,
+/// from the archive's `c` and index blocks.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+pub fn zpaq_versions(archive: &str) -> Result<Vec<VersionInfo>> {
+    let scan = scan_archive(archive)?;
+    if scan.versions() == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    Ok(version_list(&scan))
+}
+
+/// The complete versions `scan` found, oldest first.
+pub(crate) fn version_list<S: SegmentSink + ?Sized>(scan: &Scan<'_, S>) -> Vec<VersionInfo> {
+    let mut versions: Vec<VersionInfo> = scan
+        .version_dates()
+        .into_iter()
+        .zip(1..)
+        .map(|(date, number)| VersionInfo {
+            number,
+            date,
+            time: UNIX_EPOCH + Duration::from_secs(unix_time(date)),
+            added: 0,
+            deleted: 0,
+        })
+        .collect();
+    let end = scan.incomplete.unwrap_or(u64::MAX);
+    for entry in scan.index.iter().filter(|e| e.location.offset < end) {
+        let Some(version) = entry
+            .version
+            .checked_sub(1)
+            .and_then(|v| versions.get_mut(v))
+        else {
+            continue;
+        };
+        if entry.date == 0 {
+            version.deleted += 1;
+        } else {
+            version.added += 1;
+        }
+    }
+    versions
+}
+
+/// Where one version of a journaling archive lies, as listed by
+/// [`archive_layout`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionLayout {
+    /// Version number, from 1.
+    pub version: u32,
+    /// Bytes of the archive the version added: from its `c` block to the
+    /// next version's (the first version's from the start of the archive).
+    pub byte_range: Range<u64>,
+    /// `d` blocks, holding the version's compressed fragments.
+    pub data_blocks: u32,
+    /// `h` and `i` blocks, holding its fragment tables and file index.
+    pub index_blocks: u32,
+    /// Bytes of its `d` blocks.
+    pub compressed_bytes: u64,
+}
+
+/// Lists where each version of the journaling archive at `archive` lies,
+/// oldest first, from the names and sizes of its blocks; no `d` block is
+/// decompressed.
+///
+/// The ranges follow one another from the start of the archive, and end
+/// where the archive does, or where its first incomplete transaction (a
+/// version `zpaq` does not list) starts.  So after adding to an archive
+/// that was `len` bytes long, the new versions' ranges start at `len`, and
+/// syncing those ranges brings a copy of the archive up to date.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let layout = zpaq_rs::archive_layout("backup.zpaq")?;
+/// let synced = 1_000_000; // bytes uploaded so far
+/// for version in layout.iter().filter(|v| v.byte_range.end > synced) {
+///     println!("upload version {}: {:?}", version.version, version.byte_range);
+/// }
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn archive_layout(archive: &str) -> Result<Vec<VersionLayout>> {
+    let scan = scan_archive(archive)?;
+    let versions = scan.versions();
+    if versions == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    let end = match scan.incomplete {
+        Some(end) => end,
+        None => open_archive(archive)?.len(),
+    };
+    let starts: Vec<u64> = scan
+        .transaction_extents()
+        .into_iter()
+        .map(|(offset, _)| offset)
+        .collect();
+    let mut layout: Vec<VersionLayout> = starts
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| VersionLayout {
+            version: i as u32 + 1,
+            byte_range: if i == 0 { 0 } else { start }..starts.get(i + 1).copied().unwrap_or(end),
+            data_blocks: 0,
+            index_blocks: 0,
+            compressed_bytes: 0,
+        })
+        .collect();
+    // The version whose range holds a block at `offset`.
+    let version_at = |offset: u64| {
+        let next = starts.partition_point(|&start| start <= offset);
+        next.checked_sub(1).filter(|_| offset < end)
+    };
+    for table in &scan.tables {
+        if let Some(i) = version_at(table.data_offset) {
+            layout[i].data_blocks += 1;
+            layout[i].compressed_bytes += table.data_size;
+        }
+        if let Some(i) = version_at(table.location.offset) {
+            layout[i].index_blocks += 1;
+        }
+    }
+    let mut index: Vec<u64> = scan.index.iter().map(|e| e.location.offset).collect();
+    index.dedup();
+    for i in index.into_iter().filter_map(version_at) {
+        layout[i].index_blocks += 1;
+    }
+    Ok(layout)
+}
+
+/// Lists the current files in the journaling archive at `archive` that
+/// contain a fragment with the given SHA-1, in path order.
+pub fn find_fragment(archive: &str, sha1: &[u8; 20]) -> Result<Vec<String>> {
+    let mut files: Vec<String> = archive_fragments(archive, None)?
+        .into_iter()
+        .filter(|f| &f.sha1 == sha1)
+        .map(|f| f.file)
+        .collect();
+    files.dedup();
+    Ok(files)
+}
+
+/// A file holding data stored earlier in the archive, as listed by
+/// [`zpaq_duplicates`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateEntry {
+    /// Path of the file as stored in the archive.
+    pub path: String,
+    /// Bytes of the file whose fragments an earlier file (in path order),
+    /// or an earlier part of the same file, already holds.
+    pub duplicate_bytes: u64,
+    /// The earlier file most of those bytes are shared with; `None` if they
+    /// are all repeats within this file.
+    pub duplicate_of: Option<String>,
+}
+
+/// Ranks the current files of the journaling archive at `archive` by
+/// duplicated content and returns the first `top_n` (fewer if fewer files
+/// share data), most duplicated first.
+///
+/// This is the question `zpaq list -summary N` answers with its `^` marks,
+/// answered from the fragment index instead of the text: files are taken
+/// in path order, and a fragment counts as duplicated in every file that
+/// uses it after the first.  Unlike `zpaq`, which only marks files whose
+/// fragments all match the previous file's, partial overlap counts too.
+/// Files that share nothing are left out.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let duplicates = zpaq_rs::zpaq_duplicates("backup.zpaq", 10)?;
+/// let redundant: u64 = duplicates.iter().map(|d| d.duplicate_bytes).sum();
+/// for d in &duplicates {
+///     let share = 100.0 * d.duplicate_bytes as f64 / redundant as f64;
+///     println!("{share:5.1}% {} (of {:?})", d.path, d.duplicate_of);
+/// }
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+pub fn zpaq_duplicates(archive: &str, top_n: usize) -> Result<Vec<DuplicateEntry>> {
+    let scan = scan_archive(archive)?;
+    let versions = scan.versions();
+    if versions == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    let fragments = scan.fragments();
+    // File that first uses each fragment.
+    let mut first_use: HashMap<u32, &str> = HashMap::new();
+    let mut out = Vec::new();
+    for (path, entry) in scan.files_at(versions) {
+        if path.ends_with('/') {
+            continue;
+        }
+        let mut duplicate_bytes = 0;
+        let mut shared_with: HashMap<&str, u64> = HashMap::new();
+        for &id in &entry.fragments {
+            let &(size, _) = fragments.get(&id).ok_or_else(|| {
+                ZpaqError::Ffi(format!(
+                    "{path}: fragment {id} is not described by any h block"
+                ))
+            })?;
+            let first = match first_use.entry(id) {
+                Entry::Occupied(first) => *first.get(),
+                Entry::Vacant(unused) => {
+                    unused.insert(path);
+                    continue;
+                }
+            };
+            duplicate_bytes += size as u64;
+            *shared_with.entry(first).or_default() += size as u64;
+        }
+        if duplicate_bytes == 0 {
+            continue;
+        }
+        let duplicate_of = shared_with
+            .into_iter()
+            .filter(|&(other, _)| other != path)
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(a.0)))
+            .map(|(other, _)| other.to_string());
+        out.push(DuplicateEntry {
+            path: path.to_string(),
+            duplicate_bytes,
+            duplicate_of,
+        });
+    }
+    out.sort_by(|a, b| {
+        b.duplicate_bytes
+            .cmp(&a.duplicate_bytes)
+            .then_with(|| a.path.cmp(&b.path))
+    });
+    out.truncate(top_n);
+    Ok(out)
+}
+
+/// The fragments stored in a journaling archive, reduced to what is needed
+/// to compare them with other archives: a 64-bit SHA-1 prefix and the size
+/// of each, as returned by [`export_fragment_hashes`].
+///
+/// [`to_bytes`](Self::to_bytes) encodes a set in about 10 bytes per
+/// fragment (the prefixes are sorted and delta-coded), so the set of a
+/// terabyte archive with 64 KiB fragments takes some 150 MiB and can be
+/// moved instead of the archive.  Two different fragments share a prefix
+/// with probability 2^-64, which is negligible for an estimate.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FragmentSet {
+    /// `(prefix, size)`, sorted and distinct by prefix.
+    fragments: Vec<(u64, u32)>,
+}
+
+const FRAGMENT_SET_MAGIC: &[u8; 5] = b"zpFS1";
+
+impl FragmentSet {
+    fn from_fragments(mut fragments: Vec<(u64, u32)>) -> Self {
+        fragments.sort_unstable();
+        fragments.dedup_by_key(|f| f.0);
+        FragmentSet { fragments }
+    }
+
+    /// Number of distinct fragments.
+    pub fn len(&self) -> usize {
+        self.fragments.len()
+    }
+
+    /// `true` if the set has no fragments.
+    pub fn is_empty(&self) -> bool {
+        self.fragments.is_empty()
+    }
+
+    /// Uncompressed size of all the fragments.
+    pub fn total_bytes(&self) -> u64 {
+        self.fragments.iter().map(|&(_, size)| size as u64).sum()
+    }
+
+    /// Encodes the set: a 5-byte magic, the fragment count, then for each
+    /// fragment the difference from the previous prefix and the size, all
+    /// as LEB128 varints.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = FRAGMENT_SET_MAGIC.to_vec();
+        put_varint(&mut out, self.fragments.len() as u64);
+        let mut previous = 0;
+        for &(prefix, size) in &self.fragments {
+            put_varint(&mut out, prefix - previous);
+            put_varint(&mut out, size as u64);
+            previous = prefix;
+        }
+        out
+    }
+
+    /// Decodes a set written by [`to_bytes`](Self::to_bytes).
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let malformed = || ZpaqError::Ffi("malformed fragment set".into());
+        let mut rest = bytes
+            .strip_prefix(FRAGMENT_SET_MAGIC.as_slice())
+            .ok_or_else(malformed)?;
+        let count = take_varint(&mut rest).ok_or_else(malformed)?;
+        // Each fragment takes at least 2 bytes.
+        if count > rest.len() as u64 / 2 {
+            return Err(malformed());
+        }
+        let mut fragments = Vec::with_capacity(count as usize);
+        let mut prefix = 0u64;
+        for i in 0..count {
+            let delta = take_varint(&mut rest).ok_or_else(malformed)?;
+            let size = take_varint(&mut rest).ok_or_else(malformed)?;
+            prefix = prefix.checked_add(delta).ok_or_else(malformed)?;
+            if i > 0 && delta == 0 {
+                return Err(malformed());
+            }
+            fragments.push((prefix, u32::try_from(size).map_err(|_| malformed())?));
+        }
+        if !rest.is_empty() {
+            return Err(malformed());
+        }
+        Ok(FragmentSet { fragments })
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn take_varint(bytes: &mut &[u8]) -> Option<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&b, rest) = bytes.split_first()?;
+        *bytes = rest;
+        v |= ((b & 0x7f) as u64).checked_shl(shift)?;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/// Collects the hash and size of every fragment stored in the journaling
+/// archive at `archive`, from its `h` blocks and without decompressing any
+/// data.
+///
+/// Fragments only older versions use are included: they are part of what
+/// consolidating the archive would move.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+pub fn export_fragment_hashes(archive: &str) -> Result<FragmentSet> {
+    let scan = scan_archive(archive)?;
+    if scan.versions() == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    Ok(FragmentSet::from_fragments(
+        scan.fragments()
+            .into_values()
+            .map(|(size, sha1)| {
+                let prefix = u64::from_be_bytes(sha1[..8].try_into().expect("8 bytes"));
+                (prefix, size)
+            })
+            .collect(),
+    ))
+}
+
+/// What deduplicating several archives into one would save, as returned by
+/// [`estimate_dedup`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupEstimate {
+    /// Bytes of the distinct fragments across all the sets: what a
+    /// consolidated archive would store.
+    pub unique_bytes: u64,
+    /// Bytes of fragments that appear in more than one set, counted once
+    /// for each set after the first: what consolidation would save.
+    pub duplicate_bytes: u64,
+}
+
+/// Estimates the overlap between the archives `sets` were exported from.
+///
+/// Sizes are uncompressed.  Fragments are only found equal when `zpaq`
+/// split both archives at the same places, which holds for shared files and
+/// for shared regions away from their edges.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let a = zpaq_rs::export_fragment_hashes("host-a.zpaq")?;
+/// let b = zpaq_rs::FragmentSet::from_bytes(&std::fs::read("host-b.fragments")?)?;
+/// let estimate = zpaq_rs::estimate_dedup(&[a, b]);
+/// println!("{} bytes would be saved", estimate.duplicate_bytes);
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn estimate_dedup(sets: &[FragmentSet]) -> DedupEstimate {
+    let mut seen = HashSet::new();
+    let mut estimate = DedupEstimate::default();
+    for set in sets {
+        for &(prefix, size) in &set.fragments {
+            if seen.insert(prefix) {
+                estimate.unique_bytes += size as u64;
+            } else {
+                estimate.duplicate_bytes += size as u64;
+            }
+        }
+    }
+    estimate
+}
+
+fn scan_archive(archive: &str) -> Result<Scan<'static>> {
+    let mut source = ArchiveSource::new(BufReader::new(open_archive(archive)?), None)?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::random;
+    use crate::{sha1, zpaq_add, zpaq_command};
+
+    #[test]
+    fn files_with_a_common_region_share_fragments() {
+        let dir = TempDir::new("zpaq-rs-fragments");
+        let common = random(1, 1 << 20);
+        let a = [&common[..], &random(2, 50_000)].concat();
+        let b = [&random(3, 30_000)[..], &common, &random(4, 70_000)].concat();
+        let a_path = dir.join("a.bin").to_string_lossy().into_owned();
+        let b_path = dir.join("b.bin").to_string_lossy().into_owned();
+        std::fs::write(&a_path, &a).expect("write a");
+        std::fs::write(&b_path, &b).expect("write b");
+        let archive = dir.join("test.zpaq").to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&a_path, &b_path], "1", 1).expect("zpaq add");
+
+        let all = archive_fragments(&archive, None).expect("fragments");
+        let of_a = archive_fragments(&archive, Some(&a_path)).expect("fragments of a");
+        let of_b: Vec<_> = all.iter().filter(|f| f.file == b_path).collect();
+        assert_eq!(all.len(), of_a.len() + of_b.len());
+
+        // Sizes add up and the first fragment hashes to its data.
+        let total: u64 = of_a.iter().map(|f| f.usize as u64).sum();
+        assert_eq!(total, a.len() as u64);
+        let first = &of_a[0];
+        assert_eq!(first.ordinal, 0);
+        assert_eq!(first.sha1, sha1(&a[..first.usize as usize]).expect("sha1"));
+
+        let in_a: HashSet<_> = of_a.iter().map(|f| f.sha1).collect();
+        let shared: Vec<_> = of_b.iter().filter(|f| in_a.contains(&f.sha1)).collect();
+        let shared_bytes: u64 = shared.iter().map(|f| f.usize as u64).sum();
+        assert!(
+            shared_bytes * 10 >= common.len() as u64 * 8,
+            "only {shared_bytes} bytes shared"
+        );
+        // Shared data is stored once.
+        let a_ids: HashMap<_, _> = of_a.iter().map(|f| (f.sha1, f.id)).collect();
+        assert!(shared.iter().all(|f| a_ids[&f.sha1] == f.id));
+
+        let files = find_fragment(&archive, &shared[0].sha1).expect("find");
+        assert_eq!(files.len(), 2);
+        assert!(files.contains(&a_path) && files.contains(&b_path));
+        assert!(find_fragment(&archive, &[0; 20]).expect("find").is_empty());
+    }
+
+    #[test]
+    fn identical_files_dominate_the_duplicate_report() {
+        let dir = TempDir::new("zpaq-rs-duplicates");
+        let data = dir.join("data");
+        std::fs::create_dir_all(&data).expect("data dir");
+        let path = |name: &str| data.join(name).to_string_lossy().into_owned();
+        let original = random(1, 400_000);
+        std::fs::write(path("a.bin"), &original).expect("write a");
+        for copy in ["copy1.bin", "copy2.bin", "copy3.bin"] {
+            std::fs::write(path(copy), &original).expect("write copy");
+        }
+        let partial = [&original[..100_000], &random(2, 200_000)].concat();
+        std::fs::write(path("partial.bin"), partial).expect("write partial");
+        std::fs::write(path("unique.bin"), random(3, 500_000)).expect("write unique");
+        let archive = dir.join("dups.zpaq").to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&data.to_string_lossy()], "1", 1).expect("add");
+
+        let duplicates = zpaq_duplicates(&archive, 10).expect("duplicates");
+        let names: Vec<&str> = duplicates.iter().map(|d| d.path.as_str()).collect();
+        assert_eq!(
+            names[..3],
+            [path("copy1.bin"), path("copy2.bin"), path("copy3.bin")]
+        );
+        for copy in &duplicates[..3] {
+            assert_eq!(copy.duplicate_bytes, original.len() as u64);
+            assert_eq!(copy.duplicate_of, Some(path("a.bin")));
+        }
+        let rest = &duplicates[3..];
+        assert!(
+            rest.iter().all(|d| d.path == path("partial.bin")),
+            "{names:?}"
+        );
+        let total: u64 = duplicates.iter().map(|d| d.duplicate_bytes).sum();
+        assert!(3 * original.len() as u64 * 10 >= total * 8);
+        assert_eq!(zpaq_duplicates(&archive, 2).expect("top 2").len(), 2);
+
+        // zpaq marks the copies after the first of the identical run.
+        let cli = zpaq_command(&["list", &archive, "-summary", "10"])
+            .expect("list -summary")
+            .stdout;
+        assert!(
+            cli.contains("3 of largest 10 files are ^duplicates"),
+            "{cli}"
+        );
+    }
+
+    #[test]
+    fn versions_lie_one_after_another() {
+        let dir = TempDir::new("zpaq-rs-layout");
+        let archive = dir.join("layout.zpaq").to_string_lossy().into_owned();
+        let mut sizes = Vec::new();
+        for round in 0..3u64 {
+            let file = dir.join(format!("file{round}.bin"));
+            std::fs::write(&file, random(round, 100_000 * (round as usize + 1))).expect("write");
+            zpaq_add(&archive, &[&file.to_string_lossy()], "1", 1).expect("add");
+            sizes.push(std::fs::metadata(&archive).expect("stat").len());
+        }
+
+        let layout = archive_layout(&archive).expect("layout");
+        assert_eq!(layout.len(), 3);
+        let mut start = 0;
+        for (version, size) in layout.iter().zip(&sizes) {
+            // Each add appended exactly one range.
+            assert_eq!(version.byte_range, start..*size, "{version:?}");
+            assert!(version.data_blocks >= 1, "{version:?}");
+            assert!(version.index_blocks >= 2, "{version:?}");
+            assert!(version.compressed_bytes > 100_000 * version.version as u64);
+            assert!(version.compressed_bytes < version.byte_range.end - version.byte_range.start);
+            start = *size;
+        }
+        assert_eq!(
+            layout.iter().map(|v| v.version).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+
+        let streaming = dir.join("streaming.zpaq");
+        let entry = crate::ArchiveEntry {
+            path: "s",
+            data: b"s",
+            comment: None,
+        };
+        std::fs::write(
+            &streaming,
+            crate::archive_from_entries(&[entry], "1").unwrap(),
+        )
+        .unwrap();
+        let refused = archive_layout(&streaming.to_string_lossy());
+        assert!(matches!(refused, Err(ZpaqError::StreamingArchive)));
+    }
+
+    #[test]
+    fn fragment_sets_estimate_shared_content() {
+        let dir = TempDir::new("zpaq-rs-fragment-sets");
+        let shared = random(5, 2 << 20);
+        let mut sets = Vec::new();
+        for (name, seed) in [("a", 6), ("b", 7)] {
+            let file = dir
+                .join(format!("{name}.bin"))
+                .to_string_lossy()
+                .into_owned();
+            std::fs::write(&file, [&shared[..], &random(seed, 2 << 20)].concat()).expect("write");
+            let archive = dir
+                .join(format!("{name}.zpaq"))
+                .to_string_lossy()
+                .into_owned();
+            zpaq_add(&archive, &[&file], "1", 1).expect("zpaq add");
+            let set = export_fragment_hashes(&archive).expect("export");
+            assert_eq!(set.total_bytes(), 4 << 20);
+            let bytes = set.to_bytes();
+            assert!(bytes.len() <= 12 * set.len() + 16, "{} bytes", bytes.len());
+            assert_eq!(FragmentSet::from_bytes(&bytes).expect("decode"), set);
+            sets.push(set);
+        }
+
+        let estimate = estimate_dedup(&sets);
+        assert_eq!(estimate.unique_bytes + estimate.duplicate_bytes, 8 << 20);
+        // Half of the second archive is already in the first.
+        let share = estimate.duplicate_bytes as f64 / sets[1].total_bytes() as f64;
+        assert!((0.45..=0.5).contains(&share), "share={share}");
+        assert_eq!(estimate_dedup(&sets[..1]).duplicate_bytes, 0);
+
+        let bytes = sets[0].to_bytes();
+        assert!(FragmentSet::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(FragmentSet::from_bytes(b"zpFS1").is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Owned guards for the `libzpaq` objects the shim hands out, so that every
+//! early return frees what was made before it.  The reader and writer
+//! guards, which also own their callback state, are
+//! [`FfiReader`](crate::FfiReader) and [`FfiWriter`](crate::FfiWriter).
+//!
+//! The shim counts the objects its `zpaq_*_new` functions make and have not
+//! freed, per thread; the tests below fail each allocation of an operation
+//! in turn and check the count comes back.
+
+use std::os::raw::c_char;
+
+use crate::{Result, err_from_last, sys};
+
+macro_rules! handle {
+    ($(#[$doc:meta])* $name:ident($raw:ty), $free:ident) => {
+        $(#[$doc])*
+        pub(crate) struct $name {
+            pub(crate) raw: *mut $raw,
+        }
+
+        // SAFETY: the object is plain `libzpaq` state owned by the guard,
+        // with no thread affinity.  Not `Sync`: none of it is made for
+        // concurrent use.
+        unsafe impl Send for $name {}
+
+        impl $name {
+            /// Takes ownership of what a `zpaq_*_new` function returned,
+            /// failing with its error if that was null.
+            fn from_raw(raw: *mut $raw) -> Result<Self> {
+                if raw.is_null() {
+                    return Err(err_from_last());
+                }
+                Ok(Self { raw })
+            }
+        }
+
+        impl Drop for $name {
+            fn drop(&mut self) {
+                unsafe { sys::$free(self.raw) };
+            }
+        }
+    };
+}
+
+handle! {
+    /// A `libzpaq::Compressor`.
+    Compressor(sys::Compressor), zpaq_compressor_free
+}
+
+handle! {
+    /// A `libzpaq::Decompresser`.
+    Decompresser(sys::Decompresser), zpaq_decompresser_free
+}
+
+handle! {
+    /// A `libzpaq::SHA1`.
+    Sha1(sys::SHA1), zpaq_sha1_free
+}
+
+handle! {
+    /// A `libzpaq::SHA256`.
+    Sha256(sys::SHA256), zpaq_sha256_free
+}
+
+handle! {
+    /// A `libzpaq::AES_CTR` keyed for one archive.
+    AesCtr(sys::AES_CTR), zpaq_aes_ctr_free
+}
+
+impl Compressor {
+    pub(crate) fn new() -> Result<Self> {
+        Self::from_raw(unsafe { sys::zpaq_compressor_new() })
+    }
+}
+
+impl Decompresser {
+    pub(crate) fn new() -> Result<Self> {
+        Self::from_raw(unsafe { sys::zpaq_decompresser_new() })
+    }
+}
+
+impl Sha1 {
+    pub(crate) fn new() -> Result<Self> {
+        Self::from_raw(unsafe { sys::zpaq_sha1_new() })
+    }
+}
+
+impl Sha256 {
+    pub(crate) fn new() -> Result<Self> {
+        Self::from_raw(unsafe { sys::zpaq_sha256_new() })
+    }
+}
+
+impl AesCtr {
+    pub(crate) fn new(key: &[u8; 32], iv: &[u8; 32]) -> Result<Self> {
+        Self::from_raw(unsafe {
+            sys::zpaq_aes_ctr_new(
+                key.as_ptr() as *const c_char,
+                32,
+                iv.as_ptr() as *const c_char,
+            )
+        })
+    }
+}
+
+/// Objects made by the shim's `zpaq_*_new` functions on this thread and not
+/// yet freed on it.
+#[cfg(any(test, feature = "test-support"))]
+pub(crate) fn live_objects() -> i64 {
+    unsafe { sys::zpaq_debug_live_objects() }
+}
+
+/// Makes the `n`th `zpaq_*_new` call from now on this thread fail as if
+/// out of memory; 0 turns the failure off.
+#[cfg(any(test, feature = "test-support"))]
+pub(crate) fn fail_nth_new(n: u32) {
+    unsafe { sys::zpaq_debug_fail_new(n.try_into().unwrap_or(i32::MAX)) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ArchiveEntry, DecompressLimits, PushDecompressor, StreamingCompressor, VerifyOptions,
+        ZpaqError, archive_from_entries, archive_read_file_bytes, bits_per_byte, compress_to_vec,
+        compress_with_dictionary, decompress_many, decompress_stream, decompress_to_vec_limited,
+        decompress_with_dictionary, describe_block, list_blocks, sha1, sha256, verify_archive,
+    };
+
+    /// Runs `op` with each of its allocations failing in turn, then with
+    /// none, checking that every run leaves no object behind.  Returns the
+    /// number of allocations the successful run made.
+    fn fail_each_allocation<T>(mut op: impl FnMut() -> Result<T>) -> u32 {
+        let before = live_objects();
+        for n in 1.. {
+            fail_nth_new(n);
+            let result = op().map(drop);
+            fail_nth_new(0);
+            assert_eq!(live_objects(), before, "leak with allocation {n} failing");
+            match result {
+                Ok(()) => return n - 1,
+                Err(ZpaqError::Ffi(message)) => {
+                    assert!(message.contains("injected"), "allocation {n}: {message}");
+                }
+                Err(e) => panic!("allocation {n}: unexpected error {e:?}"),
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn failed_allocations_leave_nothing_behind() {
+        let data = b"every early return frees what it made\n".repeat(50);
+        let compressed = compress_to_vec(&data, "1").expect("compress");
+        let entries = [ArchiveEntry {
+            path: "a.txt",
+            data: &data,
+            comment: Some("note"),
+        }];
+        let archive = archive_from_entries(&entries, "1").expect("archive");
+        let dict = b"every early return".repeat(4);
+        let primed = compress_with_dictionary(&data, &dict, "1").expect("primed");
+        assert_eq!(live_objects(), 0);
+
+        let counts = [
+            (
+                "compress_to_vec",
+                fail_each_allocation(|| compress_to_vec(&data, "1")),
+            ),
+            (
+                "decompress_stream",
+                fail_each_allocation(|| decompress_stream(&compressed[..], Vec::new())),
+            ),
+            ("sha1", fail_each_allocation(|| sha1(&data))),
+            ("sha256", fail_each_allocation(|| sha256(&data))),
+            (
+                "streaming",
+                fail_each_allocation(|| StreamingCompressor::new("2")),
+            ),
+            (
+                "push",
+                fail_each_allocation(|| {
+                    let mut push = PushDecompressor::new()?;
+                    push.feed(&compressed)?;
+                    push.finish()
+                }),
+            ),
+            (
+                "from_entries",
+                fail_each_allocation(|| archive_from_entries(&entries, "1")),
+            ),
+            (
+                "read_file",
+                fail_each_allocation(|| archive_read_file_bytes(&archive, "a.txt")),
+            ),
+            (
+                "decompress_many",
+                fail_each_allocation(|| decompress_many([&compressed[..]], |_, _| Ok(()))),
+            ),
+            (
+                "list_blocks",
+                fail_each_allocation(|| list_blocks(&archive)),
+            ),
+            (
+                "describe_block",
+                fail_each_allocation(|| describe_block(&compressed)),
+            ),
+            (
+                "limited",
+                fail_each_allocation(|| {
+                    decompress_to_vec_limited(&compressed, &DecompressLimits::default())
+                }),
+            ),
+            (
+                "compress_with_dictionary",
+                fail_each_allocation(|| compress_with_dictionary(&data, &dict, "1")),
+            ),
+            (
+                "decompress_with_dictionary",
+                fail_each_allocation(|| decompress_with_dictionary(&primed, &dict)),
+            ),
+            (
+                "bits_per_byte",
+                fail_each_allocation(|| bits_per_byte(&data, "1")),
+            ),
+            (
+                "verify",
+                fail_each_allocation(|| verify_archive(&archive, &VerifyOptions::default())),
+            ),
+        ];
+        for (name, allocations) in counts {
+            assert!(allocations > 0, "{name} made no objects");
+        }
+        assert_eq!(live_objects(), 0);
+    }
+
+    #[test]
+    fn guards_free_their_objects() {
+        let handles = (
+            Compressor::new().expect("compressor"),
+            Decompresser::new().expect("decompresser"),
+            Sha1::new().expect("sha1"),
+            Sha256::new().expect("sha256"),
+            AesCtr::new(&[1; 32], &[2; 32]).expect("aes"),
+        );
+        assert_eq!(live_objects(), 5);
+        drop(handles);
+        assert_eq!(live_objects(), 0);
+    }
+}
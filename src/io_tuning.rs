@@ -0,0 +1,194 @@
+//! How much data crosses the FFI per callback.
+//!
+//! The shim reads its input from Rust and hands its output back through
+//! callbacks.  Larger chunks mean fewer calls, which matters for readers
+//! with a high cost per call such as files on network mounts; smaller ones
+//! keep the buffers small where memory is tight.  The bytes are the same
+//! for every size.
+
+use std::cell::Cell;
+use std::sync::{Mutex, PoisonError};
+
+use crate::{Result, ZpaqError};
+
+/// Chunk sizes for the read and write callbacks.
+///
+/// The tuning in force for a call is the one of the innermost
+/// [`run`](Self::run) on the calling thread, or else the one of
+/// [`set_default_io_tuning`].  `read_chunk` applies to the input of the
+/// stream functions ([`compress_stream`](crate::compress_stream),
+/// [`decompress_stream`](crate::decompress_stream), the size functions and
+/// their variants), which the shim then reads that many bytes at a time
+/// whatever `libzpaq` asks for.  `write_chunk` applies to every writer: the
+/// shim gathers output written a byte at a time into callbacks of that
+/// size.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::IoTuning;
+///
+/// let data = b"fewer, larger reads. ".repeat(1000);
+/// let tuning = IoTuning { read_chunk: 4 << 20, ..IoTuning::default() };
+/// let compressed = tuning.run(|| zpaq_rs::compress_to_vec(&data, "1"))??;
+/// assert_eq!(zpaq_rs::decompress_to_vec(&compressed)?, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IoTuning {
+    /// Bytes requested from the reader per callback.
+    pub read_chunk: usize,
+    /// Bytes of output gathered before each write callback.
+    pub write_chunk: usize,
+}
+
+impl IoTuning {
+    /// The smallest chunk accepted.
+    pub const MIN_CHUNK: usize = 4 << 10;
+    /// The largest chunk accepted.
+    pub const MAX_CHUNK: usize = 64 << 20;
+
+    /// Fails unless both chunks are between [`MIN_CHUNK`](Self::MIN_CHUNK)
+    /// and [`MAX_CHUNK`](Self::MAX_CHUNK).
+    pub fn validate(&self) -> Result<()> {
+        for (name, chunk) in [
+            ("read_chunk", self.read_chunk),
+            ("write_chunk", self.write_chunk),
+        ] {
+            if !(Self::MIN_CHUNK..=Self::MAX_CHUNK).contains(&chunk) {
+                return Err(ZpaqError::Ffi(format!(
+                    "{name} of {chunk} bytes is outside {}..={}",
+                    Self::MIN_CHUNK,
+                    Self::MAX_CHUNK
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs `f` with this tuning applied to the calls it makes on this
+    /// thread.  Runs nest, the innermost applying.
+    ///
+    /// # Errors
+    ///
+    /// Fails without running `f` if the tuning is not
+    /// [valid](Self::validate).
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> Result<T> {
+        self.validate()?;
+        let _scope = Scope {
+            outer: CURRENT.replace(Some(*self)),
+        };
+        Ok(f())
+    }
+
+    /// The tuning in force on this thread.
+    pub(crate) fn current() -> IoTuning {
+        CURRENT
+            .get()
+            .unwrap_or_else(|| *DEFAULT.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+impl Default for IoTuning {
+    /// 64 KiB reads, which is what `libzpaq` asks for when decoding, and
+    /// 32 KiB writes.
+    fn default() -> Self {
+        INITIAL
+    }
+}
+
+const INITIAL: IoTuning = IoTuning {
+    read_chunk: 64 << 10,
+    write_chunk: 32 << 10,
+};
+
+/// The tuning of [`set_default_io_tuning`].
+static DEFAULT: Mutex<IoTuning> = Mutex::new(INITIAL);
+
+thread_local! {
+    /// The tuning of the innermost [`IoTuning::run`] on this thread.
+    static CURRENT: Cell<Option<IoTuning>> = const { Cell::new(None) };
+}
+
+/// Sets the tuning for calls made from now on, in any thread, outside an
+/// [`IoTuning::run`].
+///
+/// # Errors
+///
+/// Fails, leaving the default as it was, if the tuning is not
+/// [valid](IoTuning::validate).
+pub fn set_default_io_tuning(tuning: IoTuning) -> Result<()> {
+    tuning.validate()?;
+    *DEFAULT.lock().unwrap_or_else(PoisonError::into_inner) = tuning;
+    Ok(())
+}
+
+/// An [`IoTuning::run`] in progress; restores the enclosing tuning when
+/// dropped.
+struct Scope {
+    outer: Option<IoTuning>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.set(self.outer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_stream, decompress_stream};
+
+    #[test]
+    fn output_is_the_same_for_every_chunk_size() {
+        let mut data = crate::test_support::text(3 << 20);
+        data.extend(crate::test_support::random(3, 1 << 20));
+        let reference = {
+            let mut out = Vec::new();
+            compress_stream(&data[..], &mut out, "1", None, None).expect("compress");
+            out
+        };
+        for chunk in [4 << 10, 1 << 20, 16 << 20] {
+            let tuning = IoTuning {
+                read_chunk: chunk,
+                write_chunk: chunk,
+            };
+            let (compressed, restored) = tuning
+                .run(|| {
+                    let mut compressed = Vec::new();
+                    compress_stream(&data[..], &mut compressed, "1", None, None)?;
+                    let mut restored = Vec::new();
+                    decompress_stream(&compressed[..], &mut restored)?;
+                    Ok::<_, ZpaqError>((compressed, restored))
+                })
+                .expect("valid tuning")
+                .expect("round trip");
+            assert!(compressed == reference, "chunk {chunk}");
+            assert!(restored == data, "chunk {chunk}");
+        }
+    }
+
+    #[test]
+    fn chunks_outside_the_bounds_are_rejected() {
+        for (read_chunk, write_chunk) in [
+            (IoTuning::MIN_CHUNK - 1, 32 << 10),
+            (64 << 10, IoTuning::MAX_CHUNK + 1),
+            (0, 0),
+        ] {
+            let tuning = IoTuning {
+                read_chunk,
+                write_chunk,
+            };
+            assert!(tuning.validate().is_err());
+            assert!(tuning.run(|| ()).is_err());
+            assert!(set_default_io_tuning(tuning).is_err());
+        }
+        assert_eq!(IoTuning::current(), IoTuning::default());
+        let bounds = IoTuning {
+            read_chunk: IoTuning::MIN_CHUNK,
+            write_chunk: IoTuning::MAX_CHUNK,
+        };
+        assert_eq!(bounds.run(IoTuning::current).expect("valid"), bounds);
+    }
+}
@@ -0,0 +1,1461 @@
+//! Writing journaling archives entry by entry.
+//!
+//! [`archive_from_entries`](crate::archive_from_entries) writes streaming
+//! segments, which carry no dates or attributes.  The writers here produce
+//! the `jDC` transactions of `zpaq add` instead (a `c` header, `d` data
+//! blocks of deduplicated fragments, `h` fragment tables and `i` index
+//! blocks), so `zpaq extract` restores each entry's date and permissions.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::chunker::{ChunkBoundary, Chunker};
+use crate::diff::{decimal_time, unix_time};
+use crate::filter::selects;
+use crate::method::{log_block_size, normalize_method};
+use crate::verify::{ArchiveSource, Scan};
+use crate::{
+    DeterministicOptions, Result, ZpaqError, compress_stream, decimal_time_of, io_err, sha1,
+};
+
+/// Average fragment size exponent of `zpaq add` (64 KiB fragments).
+const FRAGMENT: u32 = 6;
+
+/// Index blocks are cut once they hold this many bytes, as in `zpaq add`.
+const INDEX_BLOCK: usize = 16000;
+
+/// A fragment's SHA-1 and size, which identify it for deduplication.
+pub(crate) type FragmentKey = ([u8; 20], u32);
+
+/// Date, attributes and application metadata stored with an entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Modification time to restore (the time of writing if `None`).
+    /// Stored to the second.
+    pub mtime: Option<SystemTime>,
+    /// Unix permission bits (`st_mode`, low 16 bits) for `zpaq` to
+    /// `chmod` on extraction on Unix.
+    pub unix_mode: Option<u32>,
+    /// Windows file attributes for `zpaq` to set on extraction on Windows.
+    /// At most one of `unix_mode` and `windows_attrs` may be set.
+    pub windows_attrs: Option<u32>,
+    /// Application key-value pairs, kept in the segment comments of
+    /// streaming archives (see
+    /// [`encode_extra_comment`](crate::encode_extra_comment)).  Journaling
+    /// archives have no room for them, so they must be empty there.
+    pub extra: BTreeMap<String, String>,
+}
+
+impl EntryMeta {
+    /// Reads the date and attribute bytes of an `i` block entry.
+    pub(crate) fn from_index(date: u64, attr: &[u8]) -> Self {
+        let value = |n: usize| {
+            let mut bytes = [0u8; 4];
+            let n = n.min(attr.len() - 1);
+            bytes[..n].copy_from_slice(&attr[1..=n]);
+            u32::from_le_bytes(bytes)
+        };
+        EntryMeta {
+            mtime: (date > 0).then(|| UNIX_EPOCH + Duration::from_secs(unix_time(date))),
+            unix_mode: (attr.first() == Some(&b'u')).then(|| value(2)),
+            windows_attrs: (attr.first() == Some(&b'w')).then(|| value(4)),
+            extra: BTreeMap::new(),
+        }
+    }
+}
+
+/// One file for [`archive_from_owned_entries`], with its metadata.
+///
+/// The contents are read from `data` as the archive is written, so entries
+/// need not fit in memory.  Journaling archives have no per-file comments;
+/// use [`ArchiveEntry`](crate::ArchiveEntry) and streaming segments for
+/// those.
+pub struct ArchiveEntryOwned {
+    /// File path to store in the archive.
+    pub path: String,
+    /// File contents.
+    pub data: Box<dyn Read + Send>,
+    /// Date and attributes.
+    pub meta: EntryMeta,
+}
+
+impl ArchiveEntryOwned {
+    /// Creates an entry without metadata, reading its contents from `data`.
+    pub fn new(path: impl Into<String>, data: impl Read + Send + 'static) -> Self {
+        ArchiveEntryOwned {
+            path: path.into(),
+            data: Box::new(data),
+            meta: EntryMeta::default(),
+        }
+    }
+
+    /// Creates an entry without metadata holding `data`.
+    pub fn from_bytes(path: impl Into<String>, data: impl Into<Vec<u8>>) -> Self {
+        Self::new(path, Cursor::new(data.into()))
+    }
+
+    /// Sets the modification time.
+    pub fn with_mtime(mut self, mtime: SystemTime) -> Self {
+        self.meta.mtime = Some(mtime);
+        self
+    }
+
+    /// Sets the Unix permission bits.
+    pub fn with_unix_mode(mut self, mode: u32) -> Self {
+        self.meta.unix_mode = Some(mode);
+        self
+    }
+
+    /// Sets the Windows file attributes.
+    pub fn with_windows_attrs(mut self, attrs: u32) -> Self {
+        self.meta.windows_attrs = Some(attrs);
+        self
+    }
+}
+
+impl std::fmt::Debug for ArchiveEntryOwned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ArchiveEntryOwned")
+            .field("path", &self.path)
+            .field("meta", &self.meta)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Creates a journaling archive in memory holding one version with
+/// `entries`, compressing data blocks with `method` (as for
+/// [`zpaq_add`](crate::zpaq_add)).
+///
+/// Files are split into fragments and deduplicated as `zpaq add` does.  An
+/// empty entry list gives an empty archive.
+pub fn archive_from_owned_entries(
+    entries: impl IntoIterator<Item = ArchiveEntryOwned>,
+    method: &str,
+) -> Result<Vec<u8>> {
+    let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), method)?;
+    for mut entry in entries {
+        writer.add_entry(&entry.path, &mut entry.data, &entry.meta)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Like [`archive_from_owned_entries`], normalized by `deterministic` so the
+/// same entries always give the same bytes.
+///
+/// With [`DeterministicOptions::zero_mtimes`] entries are stored dated at
+/// the Unix epoch and without attributes, whatever their
+/// [`EntryMeta`]; with [`DeterministicOptions::sort_inputs`] they are
+/// written in path order.  ([`archive_from_entries`](crate::archive_from_entries)
+/// writes no dates, so its output already depends only on the entries and
+/// their order.)
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use zpaq_rs::{ArchiveEntryOwned, DeterministicOptions, archive_from_owned_entries_with};
+///
+/// let options = DeterministicOptions::pinned(UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+/// let build = || {
+///     let entries = [
+///         ArchiveEntryOwned::from_bytes("b.txt", "bravo"),
+///         ArchiveEntryOwned::from_bytes("a.txt", "alpha"),
+///     ];
+///     archive_from_owned_entries_with(entries, "1", &options)
+/// };
+/// assert_eq!(build()?, build()?);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn archive_from_owned_entries_with(
+    entries: impl IntoIterator<Item = ArchiveEntryOwned>,
+    method: &str,
+    deterministic: &DeterministicOptions,
+) -> Result<Vec<u8>> {
+    let mut entries: Vec<_> = entries.into_iter().collect();
+    if deterministic.sort_inputs {
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), method)?;
+    if let Some(version_date) = deterministic.version_date {
+        writer.pin_date(decimal_time_of(version_date)?);
+    }
+    for mut entry in entries {
+        if deterministic.zero_mtimes {
+            entry.meta = EntryMeta {
+                mtime: Some(UNIX_EPOCH),
+                ..EntryMeta::default()
+            };
+        }
+        writer.add_entry(&entry.path, &mut entry.data, &entry.meta)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Adds `entries` as a new version of the journaling archive at
+/// `archive_path`, creating it if missing.
+///
+/// Fragments already in the archive are referenced rather than stored
+/// again.  The archive is left unchanged on error.
+///
+/// # Errors
+///
+/// Fails if the archive is a streaming archive.
+pub fn archive_append_owned_entries_file(
+    archive_path: &str,
+    entries: impl IntoIterator<Item = ArchiveEntryOwned>,
+    method: &str,
+) -> Result<()> {
+    let mut writer = ArchiveWriter::append(archive_path, method)?;
+    let start = writer.start;
+    let result = entries
+        .into_iter()
+        .try_for_each(|mut entry| writer.add_entry(&entry.path, &mut entry.data, &entry.meta))
+        .and_then(|()| writer.finish().map(drop));
+    if result.is_err() {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(archive_path)
+            .map_err(|e| ZpaqError::Ffi(format!("{archive_path}: {e}")))?;
+        file.set_len(start).map_err(io_err)?;
+    }
+    result
+}
+
+/// Deletes the files of the journaling archive at `archive_path` selected by
+/// `patterns`, and returns how many were deleted.
+///
+/// Like `zpaq`, this appends a version in which the files are absent: they
+/// no longer appear in the latest version, but extracting an earlier one
+/// (`-until`) still restores them, and their data stays in the archive.  A
+/// pattern selects a stored path if it matches it or one of its parent
+/// directories as a [`MemberFilter::Glob`](crate::MemberFilter::Glob), so
+/// `"docs"` deletes a directory with its contents.  Nothing is written if
+/// no file is selected.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::StreamingArchive`] for streaming archives, whose
+/// segments cannot be deleted.  The archive is left unchanged on error.
+pub fn zpaq_delete(archive_path: &str, patterns: &[&str]) -> Result<usize> {
+    let edit = edit_file(archive_path, |history| Ok(history.deletion(patterns)))?;
+    Ok(edit.deleted.len())
+}
+
+/// Like [`zpaq_delete`], for a journaling archive held in memory.
+pub fn archive_delete_entries(archive: &mut Vec<u8>, patterns: &[&str]) -> Result<usize> {
+    let edit = edit_in_memory(archive, |history| Ok(history.deletion(patterns)))?;
+    Ok(edit.deleted.len())
+}
+
+/// Renames files of the journaling archive at `archive_path`, each
+/// `(from, to)` of `mappings` in turn, and returns how many files end up
+/// under a new path.
+///
+/// This appends a version in which each renamed file is stored under its
+/// new path with its date and attributes, pointing at the fragments already
+/// in the archive, so no data is compressed again, and its old path is
+/// deleted.  Extracting an earlier version (`-until`) still restores the
+/// old layout.  Paths are exact stored paths, not patterns: a directory is
+/// moved by renaming each file below it.  Since the mappings apply in
+/// turn, two files swap through a third path.  Nothing is written if the
+/// mappings change nothing.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::NotFound`] if some `from` holds no file at that
+/// point, [`ZpaqError::PathExists`] if some `to` already holds one and
+/// `overwrite` is `false`, and [`ZpaqError::StreamingArchive`] for
+/// streaming archives.  The archive is left unchanged on error.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let renamed = zpaq_rs::zpaq_rename(
+///     "backup.zpaq",
+///     &[("draft.txt", "docs/final.txt"), ("old/logo.png", "img/logo.png")],
+///     false,
+/// )?;
+/// assert_eq!(renamed, 2);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn zpaq_rename(
+    archive_path: &str,
+    mappings: &[(&str, &str)],
+    overwrite: bool,
+) -> Result<usize> {
+    let edit = edit_file(archive_path, |history| {
+        history.renaming(mappings, overwrite)
+    })?;
+    Ok(edit.moved.len())
+}
+
+/// Like [`zpaq_rename`], for a journaling archive held in memory.
+pub fn archive_rename_entries(
+    archive: &mut Vec<u8>,
+    mappings: &[(&str, &str)],
+    overwrite: bool,
+) -> Result<usize> {
+    let edit = edit_in_memory(archive, |history| history.renaming(mappings, overwrite))?;
+    Ok(edit.moved.len())
+}
+
+/// Appends to the archive at `archive_path` the version `plan` makes from
+/// its history, if it changes anything, and returns that edit.
+fn edit_file(archive_path: &str, plan: impl FnOnce(&History) -> Result<Edit>) -> Result<Edit> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(archive_path)
+        .map_err(|e| ZpaqError::Ffi(format!("{archive_path}: {e}")))?;
+    let history = History::read(&file)?;
+    let edit = plan(&history)?;
+    if edit.is_empty() {
+        return Ok(edit);
+    }
+    resume(&mut file, &history)?;
+    let start = file.stream_position().map_err(io_err)?;
+    let result = edit.write(ArchiveWriter::with_history(&mut file, "0", history)?);
+    if result.is_err() {
+        file.set_len(start).map_err(io_err)?;
+    }
+    result.map(|()| edit)
+}
+
+/// [`edit_file`] for an archive held in memory.
+fn edit_in_memory(
+    archive: &mut Vec<u8>,
+    plan: impl FnOnce(&History) -> Result<Edit>,
+) -> Result<Edit> {
+    let history = History::read(Cursor::new(&archive[..]))?;
+    let edit = plan(&history)?;
+    if edit.is_empty() {
+        return Ok(edit);
+    }
+    let start = history.end.map_or(archive.len(), |end| end as usize);
+    archive.truncate(start);
+    let mut out = Cursor::new(&mut *archive);
+    out.set_position(start as u64);
+    let result = edit.write(ArchiveWriter::with_history(out, "0", history)?);
+    if result.is_err() {
+        archive.truncate(start);
+    }
+    result.map(|()| edit)
+}
+
+/// A writer for a new version of the journaling archive held in `archive`,
+/// which loses an incomplete last transaction, and the offset it starts at.
+pub(crate) fn append_in_memory<'a>(
+    archive: &'a mut Vec<u8>,
+    method: &str,
+) -> Result<(ArchiveWriter<Cursor<&'a mut Vec<u8>>>, usize)> {
+    let history = History::read(Cursor::new(&archive[..]))?;
+    let start = history.end.map_or(archive.len(), |end| end as usize);
+    archive.truncate(start);
+    let mut out = Cursor::new(archive);
+    out.set_position(start as u64);
+    Ok((ArchiveWriter::with_history(out, method, history)?, start))
+}
+
+/// A version that only deletes and renames files.
+#[derive(Default)]
+struct Edit {
+    /// Paths deleted.
+    deleted: Vec<String>,
+    /// New paths, with the stored paths of the files they take.
+    moved: Vec<(String, String)>,
+}
+
+impl Edit {
+    fn is_empty(&self) -> bool {
+        self.deleted.is_empty() && self.moved.is_empty()
+    }
+
+    fn write<W: Write + Seek>(&self, mut writer: ArchiveWriter<W>) -> Result<()> {
+        for path in &self.deleted {
+            writer.delete_entry(path)?;
+        }
+        for (to, from) in &self.moved {
+            writer.copy_entry(from, to)?;
+        }
+        writer.finish().map(drop)
+    }
+}
+
+/// Writes a version of a journaling archive one entry at a time.
+///
+/// Each entry is split into fragments as it is read, and fragments already
+/// seen (in this version or, when appending, in earlier ones) are stored
+/// once.  At most one data block (16 MiB for the default methods) is held
+/// in memory, plus a few bytes of index per entry, so any number of entries
+/// of any size can be written.
+///
+/// [`finish`](Self::finish) writes the fragment tables and index that make
+/// the version visible.  A writer dropped before that leaves an incomplete
+/// transaction, which `zpaq` ignores and the next append overwrites.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::{ArchiveWriter, EntryMeta};
+///
+/// let mut writer = ArchiveWriter::create("logs.zpaq", "2")?;
+/// for day in 1..=31 {
+///     let log = std::fs::File::open(format!("logs/{day}.log"))?;
+///     writer.add_entry(&format!("logs/{day}.log"), log, &EntryMeta::default())?;
+/// }
+/// writer.finish()?;
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct ArchiveWriter<W: Write + Seek> {
+    blocks: DataBlocks<W>,
+    chunker: Chunker,
+    date: u64,
+    first_id: u32,
+    /// Offset of the `c` block.
+    start: u64,
+    /// Offset just past the `c` block, once written.
+    data_start: Option<u64>,
+    index: Vec<IndexRecord>,
+    /// Files of the version appended to, by path.
+    files: BTreeMap<String, IndexRecord>,
+}
+
+impl ArchiveWriter<File> {
+    /// Creates a new archive at `path`, replacing any file there.
+    pub fn create(path: &str, method: &str) -> Result<Self> {
+        let file = File::create(path).map_err(|e| ZpaqError::Ffi(format!("{path}: {e}")))?;
+        Self::new(file, method)
+    }
+
+    /// Opens the journaling archive at `path` (creating it if missing) to
+    /// add a new version.  The file stays locked against other writers, as
+    /// with [`ArchiveLock::Wait`](crate::ArchiveLock::Wait), until the writer
+    /// is dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::StreamingArchive`] for a streaming archive and
+    /// [`ZpaqError::WrongFormat`] for an encrypted one or other data.
+    pub fn append(path: &str, method: &str) -> Result<Self> {
+        let (file, history) = open_appendable(path)?;
+        Self::with_history(file, method, history)
+    }
+}
+
+/// Opens the journaling archive at `path` (creating it if missing) and
+/// locks it as [`ArchiveWriter::append`] does, positioned where the next
+/// version goes, with its history.
+pub(crate) fn open_appendable(path: &str) -> Result<(File, History)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| ZpaqError::Ffi(format!("{path}: {e}")))?;
+    file.lock()
+        .map_err(|e| ZpaqError::Ffi(format!("{path}: lock failed: {e}")))?;
+    let end = file.seek(SeekFrom::End(0)).map_err(io_err)?;
+    if end == 0 {
+        return Ok((file, History::default()));
+    }
+    let history = History::read(&file)?;
+    resume(&mut file, &history)?;
+    Ok((file, history))
+}
+
+/// Positions `file` where the next version goes: its end or, like
+/// `zpaq add`, the start of an incomplete last transaction, which is cut off.
+fn resume(file: &mut File, history: &History) -> Result<()> {
+    let end = file.seek(SeekFrom::End(0)).map_err(io_err)?;
+    let start = history.end.unwrap_or(end);
+    file.set_len(start).map_err(io_err)?;
+    file.seek(SeekFrom::Start(start)).map_err(io_err)?;
+    Ok(())
+}
+
+impl<W: Write + Seek> ArchiveWriter<W> {
+    /// Starts a new archive at the current position of `writer`, compressing
+    /// data blocks with `method` (as for [`zpaq_add`](crate::zpaq_add)).
+    pub fn new(writer: W, method: &str) -> Result<Self> {
+        Self::with_history(writer, method, History::default())
+    }
+
+    pub(crate) fn with_history(mut out: W, method: &str, history: History) -> Result<Self> {
+        let method = normalize_method(method);
+        if method.is_empty() {
+            return Err(ZpaqError::Ffi("method string is empty".into()));
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| ZpaqError::Ffi(format!("system clock: {e}")))?
+            .as_secs();
+        let mut date = decimal_time(now);
+        if let Some(last) = history.last_date.filter(|&last| last >= date) {
+            // Versions must be in date order.
+            date = decimal_time(unix_time(last) + 1);
+        }
+        let first_id = history.next_id.max(1);
+        let start = out.stream_position().map_err(io_err)?;
+        let log_block_size = log_block_size(&method);
+        Ok(ArchiveWriter {
+            blocks: DataBlocks {
+                out,
+                method,
+                date,
+                capacity: (1usize << log_block_size) - 4096,
+                next_id: first_id,
+                known: history.fragments,
+                data: Vec::new(),
+                pending: Vec::new(),
+                tables: Vec::new(),
+            },
+            chunker: Chunker::with_block_size(FRAGMENT, log_block_size),
+            date,
+            first_id,
+            start,
+            data_start: None,
+            index: Vec::new(),
+            files: history.files,
+        })
+    }
+
+    /// Dates the version `date` instead of the time of writing.  Only for a
+    /// new archive, before anything is added.
+    fn pin_date(&mut self, date: u64) {
+        self.date = date;
+        self.blocks.date = date;
+    }
+
+    /// Adds a file stored as `path`, reading its contents from `data` to the
+    /// end.
+    pub fn add_entry(&mut self, path: &str, data: impl Read, meta: &EntryMeta) -> Result<()> {
+        let record = entry_record(path, meta, self.date)?;
+        self.add_record(record, data)
+    }
+
+    /// Adds a file stored as `path` with the date and attribute bytes of an
+    /// index record, reading its contents from `data`.
+    pub(crate) fn add_data(
+        &mut self,
+        path: &str,
+        date: u64,
+        attr: &[u8],
+        data: impl Read,
+    ) -> Result<()> {
+        let record = entry_record(path, &EntryMeta::default(), date)?;
+        let record = IndexRecord {
+            attr: attr.to_vec(),
+            ..record
+        };
+        self.add_record(record, data)
+    }
+
+    /// Whether the archive holds `fragment`, from an earlier version or
+    /// this one.
+    pub(crate) fn has_fragment(&self, fragment: &FragmentKey) -> bool {
+        self.blocks.known.contains_key(fragment)
+    }
+
+    /// Writes `block`, a compressed `d` block of another archive holding
+    /// `fragments`, as it is but for the segment name at `name`, which
+    /// takes this version's date and the next fragment ID.
+    pub(crate) fn copy_block(
+        &mut self,
+        mut block: Vec<u8>,
+        name: Range<usize>,
+        fragments: Vec<FragmentKey>,
+    ) -> Result<()> {
+        self.begin()?;
+        self.blocks.copy(&mut block, name, fragments)
+    }
+
+    /// Adds a file stored as `path` with the date and attribute bytes of an
+    /// index record, made of `fragments`, which the archive must hold.
+    pub(crate) fn add_fragments(
+        &mut self,
+        path: &str,
+        date: u64,
+        attr: &[u8],
+        fragments: &[FragmentKey],
+    ) -> Result<()> {
+        let record = entry_record(path, &EntryMeta::default(), date)?;
+        let fragments =
+            fragments
+                .iter()
+                .map(|key| {
+                    self.blocks.known.get(key).copied().ok_or_else(|| {
+                        ZpaqError::Ffi(format!("{path}: fragment not in the archive"))
+                    })
+                })
+                .collect::<Result<_>>()?;
+        self.begin()?;
+        self.index.push(IndexRecord {
+            attr: attr.to_vec(),
+            fragments,
+            ..record
+        });
+        Ok(())
+    }
+
+    /// Starts a file stored as `path`, whose contents are then written to
+    /// the returned [`MemberWriter`] and which is added when it is
+    /// [finished](MemberWriter::finish).  Its size need not be known.
+    ///
+    /// The contents are split into fragments and deduplicated as they are
+    /// written, against the archive and the files added before, exactly as
+    /// [`add_entry`](Self::add_entry) would; only the current fragment and
+    /// data block are held in memory.  One file is written at a time: the
+    /// member writer borrows this writer until it is finished or dropped.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::process::{Command, Stdio};
+    /// use zpaq_rs::{ArchiveWriter, EntryMeta};
+    ///
+    /// let mut writer = ArchiveWriter::append("db.zpaq", "2")?;
+    /// let mut dump = Command::new("pg_dump").arg("shop").stdout(Stdio::piped()).spawn()?;
+    /// let mut member = writer.open_member("shop.sql", &EntryMeta::default())?;
+    /// std::io::copy(dump.stdout.as_mut().unwrap(), &mut member)?;
+    /// member.finish()?;
+    /// writer.finish()?;
+    /// # Ok::<(), Box<dyn std::error::Error>>(())
+    /// ```
+    pub fn open_member(&mut self, path: &str, meta: &EntryMeta) -> Result<MemberWriter<'_, W>> {
+        let record = entry_record(path, meta, self.date)?;
+        self.member(record)
+    }
+
+    fn member(&mut self, record: IndexRecord) -> Result<MemberWriter<'_, W>> {
+        self.begin()?;
+        let stored = self.blocks.known.len();
+        Ok(MemberWriter {
+            writer: self,
+            record: Some(record),
+            unread: Vec::new(),
+            size: 0,
+            stored,
+        })
+    }
+
+    fn add_record(&mut self, record: IndexRecord, mut data: impl Read) -> Result<()> {
+        let path = record.path.clone();
+        let mut member = self.member(record)?;
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let n = match data.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(ZpaqError::Ffi(format!("{path}: {e}"))),
+            };
+            member.push(&buf[..n])?;
+        }
+        member.finish()
+    }
+
+    /// Marks the file stored as `path` deleted in this version.  It is then
+    /// absent from the version, as if `zpaq add` had not found it, but
+    /// earlier versions still hold it.
+    ///
+    /// Paths are not checked against the archive; deleting a path it does
+    /// not hold only adds an index record.
+    pub fn delete_entry(&mut self, path: &str) -> Result<()> {
+        let record = entry_record(path, &EntryMeta::default(), 0)?;
+        self.begin()?;
+        self.index.push(IndexRecord { date: 0, ..record });
+        Ok(())
+    }
+
+    /// Adds a file stored as `to` that is the file the version appended to
+    /// stores as `from`: same date, attributes and fragments, so nothing is
+    /// compressed.
+    pub(crate) fn copy_entry(&mut self, from: &str, to: &str) -> Result<()> {
+        let record = entry_record(to, &EntryMeta::default(), 0)?;
+        let Some(source) = self.files.get(from) else {
+            return Err(ZpaqError::NotFound {
+                path: from.to_string(),
+                was_deleted: false,
+            });
+        };
+        let record = IndexRecord {
+            path: record.path,
+            ..source.clone()
+        };
+        self.begin()?;
+        self.index.push(record);
+        Ok(())
+    }
+
+    /// Writes the transaction header, before the first entry.
+    fn begin(&mut self) -> Result<()> {
+        if self.data_start.is_none() {
+            write_header(&mut self.blocks.out, self.date, -1, self.first_id)?;
+            self.data_start = Some(self.blocks.out.stream_position().map_err(io_err)?);
+        }
+        Ok(())
+    }
+
+    /// Writes the fragment tables and index of the version and returns the
+    /// writer, positioned after it.  Nothing is written if no entries were
+    /// added.
+    pub fn finish(mut self) -> Result<W> {
+        let Some(data_start) = self.data_start else {
+            return Ok(self.blocks.out);
+        };
+        self.blocks.flush()?;
+        let date = self.date;
+        let out = &mut self.blocks.out;
+        let data_size = out.stream_position().map_err(io_err)? - data_start;
+
+        for (first, size, fragments) in &self.blocks.tables {
+            let mut table = (*size as u32).to_le_bytes().to_vec();
+            for (sha1, usize) in fragments {
+                table.extend_from_slice(sha1);
+                table.extend_from_slice(&usize.to_le_bytes());
+            }
+            write_block(out, &table, "0", &format!("jDC{date}h{first:010}"))?;
+        }
+
+        let mut block = Vec::new();
+        let mut count = 0;
+        for (i, record) in self.index.iter().enumerate() {
+            block.extend_from_slice(&record.date.to_le_bytes());
+            block.extend_from_slice(record.path.as_bytes());
+            block.push(0);
+            // Deletions are only a date of 0 and a name.
+            if record.date != 0 {
+                block.extend_from_slice(&(record.attr.len() as u32).to_le_bytes());
+                block.extend_from_slice(&record.attr);
+                block.extend_from_slice(&(record.fragments.len() as u32).to_le_bytes());
+                for id in &record.fragments {
+                    block.extend_from_slice(&id.to_le_bytes());
+                }
+            }
+            if block.len() > INDEX_BLOCK || i + 1 == self.index.len() {
+                count += 1;
+                write_block(out, &block, "1", &format!("jDC{date}i{count:010}"))?;
+                block.clear();
+            }
+        }
+
+        let end = out.stream_position().map_err(io_err)?;
+        out.seek(SeekFrom::Start(self.start)).map_err(io_err)?;
+        write_header(out, date, data_size as i64, self.first_id)?;
+        out.seek(SeekFrom::Start(end)).map_err(io_err)?;
+        out.flush().map_err(io_err)?;
+        Ok(self.blocks.out)
+    }
+}
+
+/// A file being added to an [`ArchiveWriter`]; see
+/// [`ArchiveWriter::open_member`].
+///
+/// Errors from the archive come out of [`Write::write`] as
+/// [`std::io::Error`]s wrapping the [`ZpaqError`].  Dropping the member
+/// without [`finish`](Self::finish)ing it leaves it out of the version;
+/// the fragments already stored stay in the archive unreferenced.
+pub struct MemberWriter<'a, W: Write + Seek> {
+    writer: &'a mut ArchiveWriter<W>,
+    /// Taken when the member is finished.
+    record: Option<IndexRecord>,
+    /// Data of the fragment not yet cut.
+    unread: Vec<u8>,
+    size: u64,
+    /// Fragments the archive held before this member.
+    stored: usize,
+}
+
+impl<W: Write + Seek> MemberWriter<'_, W> {
+    /// Adds the next `data` of the file, storing the fragments it
+    /// completes.
+    fn push(&mut self, data: &[u8]) -> Result<()> {
+        let cuts: Vec<_> = self.writer.chunker.push(data).collect();
+        self.unread.extend_from_slice(data);
+        self.size += data.len() as u64;
+        self.store(cuts)
+    }
+
+    fn store(&mut self, cuts: impl IntoIterator<Item = ChunkBoundary>) -> Result<()> {
+        let record = self.record.as_mut().expect("member not finished");
+        let mut used = 0;
+        for cut in cuts {
+            let len = cut.len as usize;
+            let id = self.writer.blocks.add(&self.unread[used..used + len])?;
+            record.fragments.push(id);
+            used += len;
+        }
+        self.unread.drain(..used);
+        Ok(())
+    }
+
+    /// Stores the last fragment and adds the file to the version.
+    pub fn finish(mut self) -> Result<()> {
+        let last = self.writer.chunker.finish();
+        self.store(last)?;
+        let mut record = self.record.take().expect("member not finished");
+        if record.fragments.is_empty() {
+            // `zpaq add` gives empty files one empty fragment.
+            record.fragments.push(self.writer.blocks.add(&[])?);
+        }
+        let new_fragments = self.writer.blocks.known.len() - self.stored;
+        crate::trace::file_added(
+            &record.path,
+            self.size,
+            record.fragments.len(),
+            new_fragments,
+        );
+        self.writer.index.push(record);
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for MemberWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.push(buf).map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    /// Does nothing: fragments are stored as they are cut, and the rest
+    /// when the member is finished.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Drop for MemberWriter<'_, W> {
+    fn drop(&mut self) {
+        if self.record.is_some() {
+            // Abandoned: start the next file on a fresh chunk.
+            self.writer.chunker.finish();
+        }
+    }
+}
+
+/// What a new version must follow: the last version date, the fragments
+/// already stored and the current files.
+#[derive(Default)]
+pub(crate) struct History {
+    last_date: Option<u64>,
+    next_id: u32,
+    fragments: HashMap<FragmentKey, u32>,
+    /// Offset and size of each `d` block an `h` table describes.
+    pub(crate) blocks: Vec<(u64, u64)>,
+    /// Offset of an incomplete last transaction.
+    end: Option<u64>,
+    /// Files of the latest version by stored path, directories included.
+    files: BTreeMap<String, IndexRecord>,
+}
+
+impl History {
+    fn read(mut reader: impl Read + Seek + Send) -> Result<Self> {
+        crate::format::require_plain(&mut reader)?;
+        let mut source = ArchiveSource::new(BufReader::new(reader), None)?;
+        let mut scan = Scan::default();
+        scan.scan(&mut source)?;
+        if scan.versions() == 0 && scan.incomplete.is_none() {
+            return Err(ZpaqError::StreamingArchive);
+        }
+        let fragments: HashMap<_, _> = scan
+            .fragments()
+            .into_iter()
+            .map(|(id, (size, sha1))| ((sha1, size), id))
+            .collect();
+        Ok(History {
+            last_date: scan.last_date(),
+            next_id: fragments.values().max().map_or(1, |id| id + 1),
+            fragments,
+            blocks: scan
+                .tables
+                .iter()
+                .map(|table| (table.data_offset, table.data_size))
+                .collect(),
+            end: scan.incomplete,
+            files: scan
+                .files_at(scan.versions())
+                .into_iter()
+                .map(|(path, entry)| {
+                    let record = IndexRecord {
+                        path: path.to_string(),
+                        date: entry.date,
+                        attr: entry.attr.clone(),
+                        fragments: entry.fragments.clone(),
+                    };
+                    (record.path.clone(), record)
+                })
+                .collect(),
+        })
+    }
+
+    /// Deletes the current files selected by any of `patterns`.
+    fn deletion(&self, patterns: &[&str]) -> Edit {
+        let deleted = self
+            .files
+            .keys()
+            .filter(|path| patterns.iter().any(|pattern| selects(pattern, path)))
+            .cloned()
+            .collect();
+        Edit {
+            deleted,
+            moved: Vec::new(),
+        }
+    }
+
+    /// Applies `mappings` in turn to the current files, and returns what
+    /// changed.
+    fn renaming(&self, mappings: &[(&str, &str)], overwrite: bool) -> Result<Edit> {
+        // The stored path of the file each path holds so far.
+        let mut sources: BTreeMap<String, &str> = self
+            .files
+            .keys()
+            .map(|path| (path.clone(), path.as_str()))
+            .collect();
+        for &(from, to) in mappings {
+            check_entry(to, &EntryMeta::default())?;
+            let Some(source) = sources.remove(from) else {
+                return Err(ZpaqError::NotFound {
+                    path: from.to_string(),
+                    was_deleted: false,
+                });
+            };
+            if !overwrite && sources.contains_key(to) {
+                return Err(ZpaqError::PathExists(to.to_string()));
+            }
+            sources.insert(to.to_string(), source);
+        }
+        let deleted = self
+            .files
+            .keys()
+            .filter(|path| !sources.contains_key(*path))
+            .cloned()
+            .collect();
+        let moved = sources
+            .into_iter()
+            .filter(|(path, source)| path != source)
+            .map(|(path, source)| (path, source.to_string()))
+            .collect();
+        Ok(Edit { deleted, moved })
+    }
+}
+
+/// One `i` block record.
+#[derive(Clone)]
+struct IndexRecord {
+    path: String,
+    date: u64,
+    attr: Vec<u8>,
+    fragments: Vec<u32>,
+}
+
+/// Data block being filled with new fragments.
+struct DataBlocks<W> {
+    out: W,
+    method: String,
+    date: u64,
+    capacity: usize,
+    next_id: u32,
+    known: HashMap<FragmentKey, u32>,
+    data: Vec<u8>,
+    /// `(sha1, size)` of the fragments in `data`.
+    pending: Vec<FragmentKey>,
+    /// `(first ID, compressed size, fragments)` of each block written.
+    tables: Vec<(u32, u64, Vec<FragmentKey>)>,
+}
+
+impl<W: Write> DataBlocks<W> {
+    /// Returns the ID of `fragment`, storing it if it is new.
+    fn add(&mut self, fragment: &[u8]) -> Result<u32> {
+        let key = (sha1(fragment)?, fragment.len() as u32);
+        if let Some(&id) = self.known.get(&key) {
+            return Ok(id);
+        }
+        let trailer = (self.pending.len() + 1) * 4 + 8;
+        if !self.pending.is_empty() && self.data.len() + fragment.len() + trailer > self.capacity {
+            self.flush()?;
+        }
+        let id = self.next_id + self.pending.len() as u32;
+        self.data.extend_from_slice(fragment);
+        self.pending.push(key);
+        self.known.insert(key, id);
+        Ok(id)
+    }
+
+    /// Writes `block` holding `fragments` as [`ArchiveWriter::copy_block`]
+    /// describes, after the pending fragments.
+    fn copy(
+        &mut self,
+        block: &mut [u8],
+        name: Range<usize>,
+        fragments: Vec<FragmentKey>,
+    ) -> Result<()> {
+        self.flush()?;
+        let first = self.next_id;
+        let new_name = format!("jDC{}d{first:010}", self.date);
+        let Some(slot) = block
+            .get_mut(name)
+            .filter(|slot| slot.len() == new_name.len())
+        else {
+            return Err(ZpaqError::Ffi("copied d block has an unusual name".into()));
+        };
+        slot.copy_from_slice(new_name.as_bytes());
+        self.out.write_all(block).map_err(io_err)?;
+        for (i, &key) in fragments.iter().enumerate() {
+            self.known.entry(key).or_insert(first + i as u32);
+        }
+        self.next_id += fragments.len() as u32;
+        self.tables.push((first, block.len() as u64, fragments));
+        Ok(())
+    }
+
+    /// Compresses the pending fragments into a `d` block.
+    fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        for &(_, size) in &self.pending {
+            self.data.extend_from_slice(&size.to_le_bytes());
+        }
+        // The first ID is left 0 so blocks can be moved between archives.
+        self.data.extend_from_slice(&0u32.to_le_bytes());
+        self.data
+            .extend_from_slice(&(self.pending.len() as u32).to_le_bytes());
+        let name = format!("jDC{}d{:010}", self.date, self.next_id);
+        let size = write_block(&mut self.out, &self.data, &self.method, &name)?;
+        let fragments = std::mem::take(&mut self.pending);
+        let first = self.next_id;
+        self.next_id += fragments.len() as u32;
+        self.tables.push((first, size, fragments));
+        self.data.clear();
+        Ok(())
+    }
+}
+
+/// Fails as [`ArchiveWriter::add_entry`] would for `path` and `meta`.
+pub(crate) fn check_entry(path: &str, meta: &EntryMeta) -> Result<()> {
+    entry_record(path, meta, 0).map(drop)
+}
+
+/// The index record of an entry, without fragments.
+fn entry_record(path: &str, meta: &EntryMeta, version_date: u64) -> Result<IndexRecord> {
+    if path.is_empty() {
+        return Err(ZpaqError::Ffi("entry path is empty".into()));
+    }
+    if path.contains('\0') {
+        return Err(ZpaqError::NulInString);
+    }
+    if !meta.extra.is_empty() {
+        return Err(ZpaqError::Ffi(format!(
+            "{path}: journaling archives cannot store extra metadata"
+        )));
+    }
+    let date = match meta.mtime {
+        None => version_date,
+        Some(mtime) => {
+            let secs = mtime
+                .duration_since(UNIX_EPOCH)
+                .map_err(|_| ZpaqError::Ffi(format!("{path}: mtime before 1970")))?
+                .as_secs();
+            decimal_time(secs)
+        }
+    };
+    let attr = match (meta.unix_mode, meta.windows_attrs) {
+        (Some(_), Some(_)) => {
+            return Err(ZpaqError::Ffi(format!(
+                "{path}: both unix_mode and windows_attrs set"
+            )));
+        }
+        (Some(mode), None) => {
+            let attr = u32::from(b'u') | (mode & 0xffff) << 8;
+            attr.to_le_bytes()[..3].to_vec()
+        }
+        (None, Some(attrs)) => {
+            let attr = u64::from(b'w') | u64::from(attrs) << 8;
+            attr.to_le_bytes()[..5].to_vec()
+        }
+        (None, None) => Vec::new(),
+    };
+    Ok(IndexRecord {
+        path: path.to_string(),
+        date,
+        attr,
+        fragments: Vec::new(),
+    })
+}
+
+/// Writes the `c` block of a transaction: the size of its data blocks, or
+/// -1 while they are being written.  Its size does not depend on `data_size`.
+fn write_header<W: Write>(out: &mut W, date: u64, data_size: i64, first_id: u32) -> Result<()> {
+    let name = format!("jDC{date}c{first_id:010}");
+    write_block(out, &data_size.to_le_bytes(), "0", &name)?;
+    Ok(())
+}
+
+/// Compresses `data` into one block named `name` and returns its size.
+fn write_block<W: Write>(out: &mut W, data: &[u8], method: &str, name: &str) -> Result<u64> {
+    let mut block = Vec::new();
+    compress_stream(data, &mut block, method, Some(name), Some("jDC\x01"))?;
+    out.write_all(&block).map_err(io_err)?;
+    Ok(block.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::random;
+    use crate::{ArchiveReader, archive_list_entries, zpaq_command};
+    use std::path::Path;
+
+    #[test]
+    fn owned_entries_extract_with_zpaq() {
+        let dir = TempDir::new("zpaq-rs-journal");
+        let archive = dir.join("owned.zpaq").to_string_lossy().into_owned();
+        let big = random(5, 700_000);
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_234_567_890);
+        let entries = vec![
+            ArchiveEntryOwned::new("data/big.bin", Cursor::new(big.clone())).with_mtime(mtime),
+            ArchiveEntryOwned::from_bytes("data/empty", Vec::new()),
+            // Same contents as the first file: stored once.
+            ArchiveEntryOwned::from_bytes("data/copy.bin", big.clone()),
+        ];
+        let bytes = archive_from_owned_entries(entries, "1").expect("write");
+        assert!(bytes.len() < big.len() * 11 / 10);
+        std::fs::write(&archive, &bytes).expect("write archive");
+
+        // Two versions in the same second still get increasing dates.
+        for round in 0..2 {
+            let extra = ArchiveEntryOwned::from_bytes("data/small.txt", format!("round {round}"));
+            archive_append_owned_entries_file(&archive, [extra], "1").expect("append");
+        }
+        let grown = std::fs::metadata(&archive).expect("stat").len();
+        let listed = archive_list_entries(&std::fs::read(&archive).expect("read")).expect("list");
+        let sizes: Vec<_> = listed.iter().map(|e| (e.path.as_str(), e.size)).collect();
+        assert_eq!(
+            sizes,
+            [
+                ("data/big.bin", big.len() as u64),
+                ("data/copy.bin", big.len() as u64),
+                ("data/empty", 0),
+                ("data/small.txt", 7),
+            ]
+        );
+        assert_eq!(listed[0].date, Some(20090213233130));
+
+        let out = dir.join("out").to_string_lossy().into_owned();
+        zpaq_command(&["extract", &archive, "-to", &out]).expect("extract");
+        let read = |name: &str| std::fs::read(format!("{out}/data/{name}")).expect("read");
+        assert_eq!(read("big.bin"), big);
+        assert_eq!(read("copy.bin"), big);
+        assert!(read("empty").is_empty());
+        assert_eq!(read("small.txt"), b"round 1");
+
+        // Streaming archives cannot take a journaling version.
+        let streaming = dir.join("streaming.zpaq").to_string_lossy().into_owned();
+        let entry = crate::ArchiveEntry {
+            path: "s",
+            data: b"s",
+            comment: None,
+        };
+        std::fs::write(
+            &streaming,
+            crate::archive_from_entries(&[entry], "1").unwrap(),
+        )
+        .unwrap();
+        let extra = ArchiveEntryOwned::from_bytes("x", b"x".to_vec());
+        assert!(archive_append_owned_entries_file(&streaming, [extra], "1").is_err());
+        let extra = ArchiveEntryOwned::from_bytes("x", b"x".to_vec())
+            .with_unix_mode(0o644)
+            .with_windows_attrs(0x20);
+        assert!(archive_append_owned_entries_file(&archive, [extra], "1").is_err());
+        assert_eq!(std::fs::metadata(&archive).expect("stat").len(), grown);
+    }
+
+    /// `len` bytes of lines that never repeat, as a database dump might.
+    struct Dump {
+        line: u64,
+        pending: Vec<u8>,
+        left: u64,
+    }
+
+    impl Dump {
+        fn new(len: u64) -> Self {
+            Dump {
+                line: 0,
+                pending: Vec::new(),
+                left: len,
+            }
+        }
+    }
+
+    impl Read for Dump {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            while self.pending.is_empty() && self.left > 0 {
+                self.line += 1;
+                let hash = self.line.wrapping_mul(0x9e37_79b9_7f4a_7c15);
+                self.pending = format!(
+                    "INSERT INTO orders VALUES ({}, {:016x}, 'customer {}');\n",
+                    self.line,
+                    hash,
+                    hash % 9973
+                )
+                .into_bytes();
+                self.pending.truncate(self.left as usize);
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            self.left -= n as u64;
+            Ok(n)
+        }
+    }
+
+    /// Whether `a` and `b` give the same bytes.
+    fn same_bytes(mut a: impl Read, mut b: impl Read) -> bool {
+        let (mut x, mut y) = (Vec::new(), Vec::new());
+        loop {
+            x.clear();
+            y.clear();
+            (&mut a).take(1 << 20).read_to_end(&mut x).expect("read a");
+            (&mut b).take(1 << 20).read_to_end(&mut y).expect("read b");
+            if x != y {
+                return false;
+            }
+            if x.is_empty() {
+                return true;
+            }
+        }
+    }
+
+    #[test]
+    fn members_of_unknown_size_stream_into_the_archive() {
+        const SIZE: u64 = 100 << 20;
+        let dir = TempDir::new("zpaq-rs-member-writer");
+        let archive = dir.join("db.zpaq").to_string_lossy().into_owned();
+
+        let mut writer = ArchiveWriter::create(&archive, "1").expect("create");
+        let mut member = writer
+            .open_member("dump.sql", &EntryMeta::default())
+            .expect("open member");
+        assert_eq!(
+            std::io::copy(&mut Dump::new(SIZE), &mut member).expect("copy"),
+            SIZE
+        );
+        member.finish().expect("finish member");
+        writer
+            .add_entry("after.txt", &b"added after"[..], &EntryMeta::default())
+            .expect("add");
+        writer.finish().expect("finish");
+        let first = std::fs::metadata(&archive).expect("stat").len();
+        assert!(first < SIZE / 2, "{first} bytes");
+
+        // The same dump again is stored as the fragments already there, and
+        // an abandoned member leaves nothing in the version.
+        let mut writer = ArchiveWriter::append(&archive, "1").expect("append");
+        let mut partial = writer
+            .open_member("partial", &EntryMeta::default())
+            .expect("open partial");
+        partial
+            .write_all(&random(1, 300_000))
+            .expect("write partial");
+        drop(partial);
+        let mut member = writer
+            .open_member("again.sql", &EntryMeta::default())
+            .expect("open again");
+        std::io::copy(&mut Dump::new(SIZE), &mut member).expect("copy again");
+        member.finish().expect("finish again");
+        let empty = writer
+            .open_member("empty", &EntryMeta::default())
+            .expect("open empty");
+        empty.finish().expect("finish empty");
+        writer.finish().expect("finish");
+        let grown = std::fs::metadata(&archive).expect("stat").len() - first;
+        assert!(grown < 400_000 + (1 << 16), "grew {grown} bytes");
+
+        let out = dir.join("out").to_string_lossy().into_owned();
+        zpaq_command(&["extract", &archive, "-to", &out]).expect("extract");
+        let extracted = |name: &str| File::open(format!("{out}/{name}")).expect("open extracted");
+        assert!(same_bytes(extracted("dump.sql"), Dump::new(SIZE)));
+        assert!(same_bytes(extracted("again.sql"), Dump::new(SIZE)));
+        assert_eq!(
+            std::fs::read(format!("{out}/after.txt")).expect("read"),
+            b"added after"
+        );
+        assert_eq!(std::fs::read(format!("{out}/empty")).expect("read"), b"");
+        assert!(!Path::new(&format!("{out}/partial")).exists());
+
+        let mut reader = ArchiveReader::open_path(&archive).expect("open");
+        let paths: Vec<_> = reader.entries().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, ["after.txt", "again.sql", "dump.sql", "empty"]);
+        let member = reader.open_member("dump.sql").expect("open member");
+        assert!(same_bytes(member, Dump::new(SIZE)));
+    }
+
+    #[test]
+    fn deleted_entries_leave_the_latest_version() {
+        let entries = vec![
+            ArchiveEntryOwned::from_bytes("docs/a.txt", b"alpha".to_vec()),
+            ArchiveEntryOwned::from_bytes("docs/b.txt", b"bravo".to_vec()),
+            ArchiveEntryOwned::from_bytes("keep.txt", b"keep".to_vec()),
+        ];
+        let mut archive = archive_from_owned_entries(entries, "1").expect("write");
+        let original = archive.clone();
+        assert_eq!(
+            archive_delete_entries(&mut archive, &["*.md"]).expect("delete"),
+            0
+        );
+        assert_eq!(archive, original);
+
+        assert_eq!(
+            archive_delete_entries(&mut archive, &["docs/a.txt"]).expect("delete"),
+            1
+        );
+        let paths = |archive: &[u8]| {
+            let reader = ArchiveReader::open(Cursor::new(archive)).expect("open");
+            let paths: Vec<_> = reader.entries().map(|e| e.path.clone()).collect();
+            (reader.versions(), paths)
+        };
+        assert_eq!(
+            paths(&archive),
+            (2, vec!["docs/b.txt".into(), "keep.txt".into()])
+        );
+        assert_eq!(
+            archive_delete_entries(&mut archive, &["docs"]).expect("delete"),
+            1
+        );
+        assert_eq!(paths(&archive), (3, vec!["keep.txt".into()]));
+
+        let mut reader = ArchiveReader::open(Cursor::new(&archive)).expect("open");
+        assert!(matches!(
+            reader.read("docs/a.txt"),
+            Err(ZpaqError::NotFound { .. })
+        ));
+        assert_eq!(
+            reader.read_at_version("docs/a.txt", 1).expect("v1"),
+            b"alpha"
+        );
+        assert_eq!(
+            reader.read_at_version("docs/b.txt", 2).expect("v2"),
+            b"bravo"
+        );
+
+        let entry = crate::ArchiveEntry {
+            path: "s",
+            data: b"s",
+            comment: None,
+        };
+        let mut streaming = crate::archive_from_entries(&[entry], "1").expect("streaming");
+        let refused = archive_delete_entries(&mut streaming, &["s"]);
+        assert!(matches!(refused, Err(ZpaqError::StreamingArchive)));
+    }
+
+    #[test]
+    fn renamed_entries_move_without_recompressing() {
+        let dir = TempDir::new("zpaq-rs-rename");
+        let big = random(9, 400_000);
+        let mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+        let entries = vec![
+            ArchiveEntryOwned::from_bytes("src/big.bin", big.clone()).with_mtime(mtime),
+            ArchiveEntryOwned::from_bytes("src/a.txt", b"alpha".to_vec()),
+            ArchiveEntryOwned::from_bytes("src/b.txt", b"bravo".to_vec()),
+        ];
+        let archive = dir.join("moved.zpaq").to_string_lossy().into_owned();
+        std::fs::write(
+            &archive,
+            archive_from_owned_entries(entries, "1").expect("write"),
+        )
+        .expect("write archive");
+        let before = std::fs::read(&archive).expect("read");
+
+        // Refused mappings leave the archive as it was.
+        let missing = zpaq_rename(&archive, &[("src/nope", "dst/nope")], false);
+        assert!(matches!(missing, Err(ZpaqError::NotFound { .. })));
+        let taken = zpaq_rename(&archive, &[("src/a.txt", "src/b.txt")], false);
+        assert!(matches!(taken, Err(ZpaqError::PathExists(p)) if p == "src/b.txt"));
+        let twice = zpaq_rename(&archive, &[("src/a.txt", "x"), ("src/a.txt", "y")], false);
+        assert!(matches!(twice, Err(ZpaqError::NotFound { .. })));
+        assert_eq!(
+            zpaq_rename(&archive, &[("src/a.txt", "src/a.txt")], false),
+            Ok(0)
+        );
+        assert_eq!(std::fs::read(&archive).expect("read"), before);
+
+        let renamed = zpaq_rename(
+            &archive,
+            &[("src/big.bin", "dst/big.bin"), ("src/a.txt", "dst/a.txt")],
+            false,
+        )
+        .expect("rename");
+        assert_eq!(renamed, 2);
+        let after = std::fs::read(&archive).expect("read");
+        assert!(after.starts_with(&before));
+        assert!(
+            after.len() - before.len() < 1000,
+            "{}",
+            after.len() - before.len()
+        );
+
+        let listed = archive_list_entries(&after).expect("list");
+        let paths: Vec<_> = listed.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, ["dst/a.txt", "dst/big.bin", "src/b.txt"]);
+        assert_eq!(listed[1].meta.mtime, Some(mtime));
+
+        for (until, expected) in [
+            ("1", ["src/a.txt", "src/b.txt", "src/big.bin"]),
+            ("2", ["dst/a.txt", "dst/big.bin", "src/b.txt"]),
+        ] {
+            let out = dir.join(format!("v{until}")).to_string_lossy().into_owned();
+            zpaq_command(&["extract", &archive, "-to", &out, "-until", until]).expect("extract");
+            let mut found = Vec::new();
+            for top in ["src", "dst"] {
+                if let Ok(files) = std::fs::read_dir(format!("{out}/{top}")) {
+                    for file in files {
+                        let name = file.expect("entry").file_name();
+                        found.push(format!("{top}/{}", name.to_string_lossy()));
+                    }
+                }
+            }
+            found.sort();
+            assert_eq!(found, expected, "-until {until}");
+        }
+        assert_eq!(
+            std::fs::read(dir.join("v2/dst/big.bin")).expect("read"),
+            big
+        );
+        assert_eq!(
+            std::fs::read(dir.join("v2/dst/a.txt")).expect("read"),
+            b"alpha"
+        );
+
+        // Swapping goes through a third path; overwriting replaces.
+        let mut bytes = after;
+        let swap = [
+            ("dst/a.txt", "tmp"),
+            ("src/b.txt", "dst/a.txt"),
+            ("tmp", "src/b.txt"),
+        ];
+        assert_eq!(archive_rename_entries(&mut bytes, &swap, false), Ok(2));
+        assert_eq!(
+            archive_rename_entries(&mut bytes, &[("src/b.txt", "dst/big.bin")], true),
+            Ok(1)
+        );
+        let mut reader = ArchiveReader::open(Cursor::new(&bytes)).expect("open");
+        assert_eq!(reader.versions(), 4);
+        let paths: Vec<_> = reader.entries().map(|e| e.path.clone()).collect();
+        assert_eq!(paths, ["dst/a.txt", "dst/big.bin"]);
+        assert_eq!(reader.read("dst/a.txt").expect("read"), b"bravo");
+        assert_eq!(reader.read("dst/big.bin").expect("read"), b"alpha");
+
+        let entry = crate::ArchiveEntry {
+            path: "s",
+            data: b"s",
+            comment: None,
+        };
+        let mut streaming = crate::archive_from_entries(&[entry], "1").expect("streaming");
+        let refused = archive_rename_entries(&mut streaming, &[("s", "t")], false);
+        assert!(matches!(refused, Err(ZpaqError::StreamingArchive)));
+    }
+}
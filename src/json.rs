@@ -0,0 +1,572 @@
+//! JSON output for the typed listings (the `serde` feature).
+//!
+//! The listing types implement [`serde::Serialize`] with a fixed schema, so
+//! their JSON can be handed to other programs:
+//!
+//! | Type | JSON object |
+//! |------|-------------|
+//! | [`ArchiveEntryInfo`] | `path`, `size`, `date`, `comment`, `meta` |
+//! | [`EntryMeta`] | `mtime`, `unix_mode`, `windows_attrs`, `extra` |
+//! | [`ArchiveEntryVersion`] | `path`, `size`, `mtime`, `version`, `deleted`, `extra` |
+//! | [`ArchiveListing`] | `versions`, `entries` (an array of [`ArchiveEntryInfo`]) |
+//! | [`VersionInfo`] | `number`, `date`, `added`, `deleted` |
+//! | [`ArchiveSummary`] | `versions`, `files`, `fragments`, `uncompressed_bytes`, `compressed_bytes`, `dedup_ratio` |
+//! | [`FragmentRef`] | `file`, `ordinal`, `id`, `sha1`, `size`, `block` |
+//! | [`DuplicateEntry`] | `path`, `duplicate_bytes`, `duplicate_of` |
+//! | [`ResumeToken`] | `compressed_offset`, `segment_index`, `bytes_emitted` |
+//! | [`CompressCheckpoint`] | `block_index`, `input_bytes_consumed`, `output_bytes_emitted` |
+//! | [`ArchiveSyncState`] | `len`, `tail_sha1`, `version` |
+//! | [`StreamIndex`] | `blocks` (an array of [`StreamBlock`]) |
+//! | [`StreamBlock`] | `compressed_offset`, `uncompressed_offset`, `size` |
+//!
+//! Fields appear in that order and are always present, `null` where the
+//! Rust field is `None`.  Dates and times are RFC 3339 strings in UTC to the
+//! second (`"2024-01-31T12:00:00Z"`), or `null` for times before 1970.
+//! Digests are lowercase hex strings.  `extra` is an object of strings.
+//! Fields may be added in later versions; existing ones keep their names
+//! and types.
+//!
+//! [`ResumeToken`], [`CompressCheckpoint`], [`ArchiveSyncState`] and
+//! [`StreamIndex`] also implement [`serde::Deserialize`], reading back the
+//! object they serialize to.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+use crate::diff::decimal_time;
+use crate::{
+    ArchiveEntryInfo, ArchiveEntryVersion, ArchiveListing, ArchiveReader, ArchiveSummary,
+    ArchiveSyncState, CompressCheckpoint, DuplicateEntry, EntryMeta, FragmentRef, Result,
+    ResumeToken, StreamBlock, StreamIndex, VersionInfo, ZpaqCommandOutput, ZpaqError, zpaq_command,
+    zpaq_summary,
+};
+
+/// Options for [`zpaq_command_with_options`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandOptions {
+    /// Also return the typed result of the command as JSON, for the
+    /// commands that have one.
+    pub json: bool,
+}
+
+/// What [`zpaq_command_with_options`] returns.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CommandResult {
+    /// Captured output, as [`zpaq_command`] returns it.
+    pub output: ZpaqCommandOutput,
+    /// The typed result as JSON (see the schema in the crate's `json`
+    /// module docs), if asked for and the command has one.
+    pub json: Option<String>,
+}
+
+/// Runs an embedded `zpaq` command like [`zpaq_command`] and, with
+/// `options.json`, also returns its result as JSON read from the archive
+/// itself rather than parsed from the text.
+///
+/// Commands with a typed result, where `archive` is the only other
+/// argument:
+///
+/// | Command | JSON |
+/// |---------|------|
+/// | `list <archive>` | an [`ArchiveListing`] |
+/// | `list <archive> -all` | an array of [`ArchiveEntryVersion`], as by [`archive_entries`](crate::archive_entries) with history |
+/// | `list <archive> -summary` | an [`ArchiveSummary`], as by [`zpaq_summary`] |
+///
+/// `l` may stand for `list`.  Other commands leave `json` as `None`.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::{CommandOptions, zpaq_command_with_options};
+///
+/// let options = CommandOptions { json: true };
+/// let result = zpaq_command_with_options(&["list", "backup.zpaq"], &options)?;
+/// print!("{}", result.output.stdout);
+/// println!("{}", result.json.unwrap_or_default());
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// As [`zpaq_command`], or if the archive cannot be read for the typed
+/// result.
+pub fn zpaq_command_with_options(args: &[&str], options: &CommandOptions) -> Result<CommandResult> {
+    let output = zpaq_command(args)?;
+    let json = match args {
+        _ if !options.json => None,
+        ["list" | "l", archive] => {
+            let reader = ArchiveReader::open_path(archive)?;
+            let listing = ArchiveListing {
+                versions: reader.versions(),
+                entries: reader.entries().cloned().collect(),
+            };
+            Some(to_json(&listing)?)
+        }
+        ["list" | "l", archive, "-all"] => {
+            let reader = ArchiveReader::open_path(archive)?;
+            Some(to_json(&reader.entry_versions(None, true)?)?)
+        }
+        ["list" | "l", archive, "-summary"] => Some(to_json(&zpaq_summary(archive)?)?),
+        _ => None,
+    };
+    Ok(CommandResult { output, json })
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String> {
+    serde_json::to_string(value).map_err(|e| ZpaqError::Ffi(format!("JSON output failed: {e}")))
+}
+
+/// A `YYYYMMDDHHMMSS` date as RFC 3339.
+fn rfc3339(date: u64) -> String {
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        date / 10_000_000_000,
+        date / 100_000_000 % 100,
+        date / 1_000_000 % 100,
+        date / 10_000 % 100,
+        date / 100 % 100,
+        date % 100
+    )
+}
+
+/// A time as RFC 3339, or `None` before 1970.
+fn time(t: SystemTime) -> Option<String> {
+    let secs = t.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    Some(rfc3339(decimal_time(secs)))
+}
+
+impl Serialize for EntryMeta {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("EntryMeta", 4)?;
+        s.serialize_field("mtime", &self.mtime.and_then(time))?;
+        s.serialize_field("unix_mode", &self.unix_mode)?;
+        s.serialize_field("windows_attrs", &self.windows_attrs)?;
+        s.serialize_field("extra", &self.extra)?;
+        s.end()
+    }
+}
+
+impl Serialize for ArchiveEntryInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ArchiveEntryInfo", 5)?;
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("size", &self.size)?;
+        s.serialize_field("date", &self.date.map(rfc3339))?;
+        s.serialize_field("comment", &self.comment)?;
+        s.serialize_field("meta", &self.meta)?;
+        s.end()
+    }
+}
+
+impl Serialize for ArchiveEntryVersion {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ArchiveEntryVersion", 6)?;
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("size", &self.size)?;
+        s.serialize_field("mtime", &self.mtime.and_then(time))?;
+        s.serialize_field("version", &self.version)?;
+        s.serialize_field("deleted", &self.deleted)?;
+        s.serialize_field("extra", &self.extra)?;
+        s.end()
+    }
+}
+
+impl Serialize for ArchiveListing {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ArchiveListing", 2)?;
+        s.serialize_field("versions", &self.versions)?;
+        s.serialize_field("entries", &self.entries)?;
+        s.end()
+    }
+}
+
+impl Serialize for VersionInfo {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("VersionInfo", 4)?;
+        s.serialize_field("number", &self.number)?;
+        s.serialize_field("date", &rfc3339(self.date))?;
+        s.serialize_field("added", &self.added)?;
+        s.serialize_field("deleted", &self.deleted)?;
+        s.end()
+    }
+}
+
+impl Serialize for ArchiveSummary {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ArchiveSummary", 6)?;
+        s.serialize_field("versions", &self.versions)?;
+        s.serialize_field("files", &self.files)?;
+        s.serialize_field("fragments", &self.fragments)?;
+        s.serialize_field("uncompressed_bytes", &self.uncompressed_bytes)?;
+        s.serialize_field("compressed_bytes", &self.compressed_bytes)?;
+        s.serialize_field("dedup_ratio", &self.dedup_ratio)?;
+        s.end()
+    }
+}
+
+impl Serialize for FragmentRef {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("FragmentRef", 6)?;
+        s.serialize_field("file", &self.file)?;
+        s.serialize_field("ordinal", &self.ordinal)?;
+        s.serialize_field("id", &self.id)?;
+        s.serialize_field("sha1", &crate::display::hex(&self.sha1))?;
+        s.serialize_field("size", &self.usize)?;
+        s.serialize_field("block", &self.block)?;
+        s.end()
+    }
+}
+
+impl Serialize for DuplicateEntry {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("DuplicateEntry", 3)?;
+        s.serialize_field("path", &self.path)?;
+        s.serialize_field("duplicate_bytes", &self.duplicate_bytes)?;
+        s.serialize_field("duplicate_of", &self.duplicate_of)?;
+        s.end()
+    }
+}
+
+impl Serialize for ResumeToken {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ResumeToken", 3)?;
+        s.serialize_field("compressed_offset", &self.compressed_offset)?;
+        s.serialize_field("segment_index", &self.segment_index)?;
+        s.serialize_field("bytes_emitted", &self.bytes_emitted)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ResumeToken {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut fields = BTreeMap::<String, u64>::deserialize(deserializer)?;
+        let mut field =
+            |name: &'static str| fields.remove(name).ok_or(de::Error::missing_field(name));
+        Ok(ResumeToken {
+            compressed_offset: field("compressed_offset")?,
+            segment_index: field("segment_index")?,
+            bytes_emitted: field("bytes_emitted")?,
+        })
+    }
+}
+
+impl Serialize for CompressCheckpoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("CompressCheckpoint", 3)?;
+        s.serialize_field("block_index", &self.block_index)?;
+        s.serialize_field("input_bytes_consumed", &self.input_bytes_consumed)?;
+        s.serialize_field("output_bytes_emitted", &self.output_bytes_emitted)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for CompressCheckpoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut fields = BTreeMap::<String, u64>::deserialize(deserializer)?;
+        let mut field =
+            |name: &'static str| fields.remove(name).ok_or(de::Error::missing_field(name));
+        Ok(CompressCheckpoint {
+            block_index: field("block_index")?,
+            input_bytes_consumed: field("input_bytes_consumed")?,
+            output_bytes_emitted: field("output_bytes_emitted")?,
+        })
+    }
+}
+
+impl Serialize for ArchiveSyncState {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("ArchiveSyncState", 3)?;
+        s.serialize_field("len", &self.len)?;
+        s.serialize_field("tail_sha1", &crate::display::hex(&self.tail_sha1))?;
+        s.serialize_field("version", &self.version)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ArchiveSyncState {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let fields = BTreeMap::<String, serde_json::Value>::deserialize(deserializer)?;
+        let field = |name: &'static str| fields.get(name).ok_or(de::Error::missing_field(name));
+        let invalid = |name: &str| de::Error::custom(format!("invalid {name}"));
+        let len = field("len")?.as_u64().ok_or_else(|| invalid("len"))?;
+        let version = field("version")?
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or_else(|| invalid("version"))?;
+        let hex = field("tail_sha1")?
+            .as_str()
+            .filter(|hex| hex.len() == 40)
+            .ok_or_else(|| invalid("tail_sha1"))?;
+        let mut tail_sha1 = [0u8; 20];
+        for (byte, pair) in tail_sha1.iter_mut().zip(hex.as_bytes().chunks(2)) {
+            let pair = std::str::from_utf8(pair).map_err(|_| invalid("tail_sha1"))?;
+            *byte = u8::from_str_radix(pair, 16).map_err(|_| invalid("tail_sha1"))?;
+        }
+        Ok(ArchiveSyncState {
+            len,
+            tail_sha1,
+            version,
+        })
+    }
+}
+
+impl Serialize for StreamIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("StreamIndex", 1)?;
+        s.serialize_field("blocks", &self.blocks)?;
+        s.end()
+    }
+}
+
+impl Serialize for StreamBlock {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("StreamBlock", 3)?;
+        s.serialize_field("compressed_offset", &self.compressed_offset)?;
+        s.serialize_field("uncompressed_offset", &self.uncompressed_offset)?;
+        s.serialize_field("size", &self.size)?;
+        s.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for StreamIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let mut fields = BTreeMap::<String, Vec<BTreeMap<String, u64>>>::deserialize(deserializer)?;
+        let blocks = fields
+            .remove("blocks")
+            .ok_or(de::Error::missing_field("blocks"))?;
+        let blocks = blocks
+            .into_iter()
+            .map(|mut fields| {
+                let mut field =
+                    |name: &'static str| fields.remove(name).ok_or(de::Error::missing_field(name));
+                Ok(StreamBlock {
+                    compressed_offset: field("compressed_offset")?,
+                    uncompressed_offset: field("uncompressed_offset")?,
+                    size: field("size")?,
+                })
+            })
+            .collect::<std::result::Result<_, D::Error>>()?;
+        Ok(StreamIndex { blocks })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::zpaq_add;
+
+    use std::time::Duration;
+
+    #[test]
+    fn json_schema_is_stable() {
+        // 2024-02-29T23:59:58Z
+        let leap = UNIX_EPOCH + Duration::from_secs(1_709_251_198);
+        let listing = ArchiveListing {
+            versions: 2,
+            entries: vec![
+                ArchiveEntryInfo {
+                    path: "dir/a.txt".into(),
+                    size: 5,
+                    date: Some(20240229235958),
+                    comment: None,
+                    meta: EntryMeta {
+                        mtime: Some(leap),
+                        unix_mode: Some(0o100644),
+                        ..Default::default()
+                    },
+                },
+                ArchiveEntryInfo {
+                    path: "b \"quoted\".bin".into(),
+                    size: 0,
+                    date: None,
+                    comment: Some("zpaq-rs-extra 1\nk=v".into()),
+                    meta: EntryMeta {
+                        mtime: Some(UNIX_EPOCH - Duration::from_secs(1)),
+                        extra: BTreeMap::from([("k".into(), "v".into())]),
+                        ..Default::default()
+                    },
+                },
+            ],
+        };
+        let version = ArchiveEntryVersion {
+            path: "a".into(),
+            size: 0,
+            mtime: None,
+            version: 3,
+            deleted: true,
+            extra: BTreeMap::new(),
+        };
+        let info = VersionInfo {
+            number: 1,
+            date: 19700101000000,
+            time: UNIX_EPOCH,
+            added: 4,
+            deleted: 1,
+        };
+        let summary = ArchiveSummary {
+            versions: 1,
+            files: 4,
+            fragments: 6,
+            uncompressed_bytes: 3000,
+            compressed_bytes: 1200,
+            dedup_ratio: 1.5,
+        };
+        let fragment = FragmentRef {
+            file: "a".into(),
+            ordinal: 0,
+            id: 7,
+            sha1: std::array::from_fn(|i| i as u8 * 13),
+            usize: 65536,
+            block: 5,
+        };
+
+        assert_eq!(
+            to_json(&listing).unwrap(),
+            concat!(
+                r#"{"versions":2,"entries":["#,
+                r#"{"path":"dir/a.txt","size":5,"date":"2024-02-29T23:59:58Z","comment":null,"#,
+                r#""meta":{"mtime":"2024-02-29T23:59:58Z","unix_mode":33188,"windows_attrs":null,"extra":{}}},"#,
+                r#"{"path":"b \"quoted\".bin","size":0,"date":null,"comment":"zpaq-rs-extra 1\nk=v","#,
+                r#""meta":{"mtime":null,"unix_mode":null,"windows_attrs":null,"extra":{"k":"v"}}}]}"#,
+            )
+        );
+        assert_eq!(
+            to_json(&version).unwrap(),
+            r#"{"path":"a","size":0,"mtime":null,"version":3,"deleted":true,"extra":{}}"#
+        );
+        assert_eq!(
+            to_json(&info).unwrap(),
+            r#"{"number":1,"date":"1970-01-01T00:00:00Z","added":4,"deleted":1}"#
+        );
+        assert_eq!(
+            to_json(&summary).unwrap(),
+            concat!(
+                r#"{"versions":1,"files":4,"fragments":6,"uncompressed_bytes":3000,"#,
+                r#""compressed_bytes":1200,"dedup_ratio":1.5}"#,
+            )
+        );
+        assert_eq!(
+            to_json(&fragment).unwrap(),
+            concat!(
+                r#"{"file":"a","ordinal":0,"id":7,"#,
+                r#""sha1":"000d1a2734414e5b6875828f9ca9b6c3d0ddeaf7","size":65536,"block":5}"#,
+            )
+        );
+        let duplicate = DuplicateEntry {
+            path: "b".into(),
+            duplicate_bytes: 4096,
+            duplicate_of: Some("a".into()),
+        };
+        assert_eq!(
+            to_json(&duplicate).unwrap(),
+            r#"{"path":"b","duplicate_bytes":4096,"duplicate_of":"a"}"#
+        );
+    }
+
+    #[test]
+    fn list_commands_return_text_and_json() {
+        let dir = TempDir::new("zpaq-rs-json");
+        let archive = dir.join("a.zpaq").to_string_lossy().into_owned();
+        let input = dir.join("in");
+        std::fs::create_dir_all(&input).expect("create input");
+        std::fs::write(input.join("one.txt"), "one").expect("write input");
+        zpaq_add(&archive, &[&input.to_string_lossy()], "1", 1).expect("add");
+
+        let options = CommandOptions { json: true };
+        let listed = zpaq_command_with_options(&["list", &archive], &options).expect("list");
+        assert!(listed.output.stdout.contains("one.txt"));
+        let json: serde_json::Value = serde_json::from_str(&listed.json.expect("json")).unwrap();
+        assert_eq!(json["versions"], 1);
+        let entry = &json["entries"][0];
+        assert!(entry["path"].as_str().unwrap().ends_with("in/one.txt"));
+        assert_eq!(entry["size"], 3);
+        assert!(entry["date"].as_str().unwrap().ends_with('Z'));
+
+        let all = zpaq_command_with_options(&["l", &archive, "-all"], &options).expect("list -all");
+        let json: serde_json::Value = serde_json::from_str(&all.json.expect("json")).unwrap();
+        assert_eq!(json[0]["version"], 1);
+        let summary =
+            zpaq_command_with_options(&["list", &archive, "-summary"], &options).expect("summary");
+        let json: serde_json::Value = serde_json::from_str(&summary.json.expect("json")).unwrap();
+        assert_eq!(json["files"], 1);
+
+        let text = zpaq_command_with_options(&["list", &archive], &CommandOptions::default())
+            .expect("list");
+        assert_eq!(text.output.stdout, listed.output.stdout);
+        assert_eq!(text.json, None);
+        let other = zpaq_command_with_options(&["list", &archive, "one.txt"], &options)
+            .expect("list files");
+        assert_eq!(other.json, None);
+    }
+
+    #[test]
+    fn progress_records_roundtrip() {
+        let token = ResumeToken {
+            compressed_offset: 1 << 40,
+            segment_index: 3,
+            bytes_emitted: 12_345,
+        };
+        let json = to_json(&token).expect("json");
+        assert_eq!(
+            json,
+            r#"{"compressed_offset":1099511627776,"segment_index":3,"bytes_emitted":12345}"#
+        );
+        let back: ResumeToken = serde_json::from_str(&json).expect("parse");
+        assert_eq!(back, token);
+        assert!(serde_json::from_str::<ResumeToken>(r#"{"segment_index":3}"#).is_err());
+
+        let checkpoint = CompressCheckpoint {
+            block_index: 2,
+            input_bytes_consumed: 50,
+            output_bytes_emitted: 20,
+        };
+        let json = to_json(&checkpoint).expect("json");
+        let back: CompressCheckpoint = serde_json::from_str(&json).expect("parse");
+        assert_eq!(back, checkpoint);
+
+        let state = ArchiveSyncState {
+            len: 4096,
+            tail_sha1: [0xab; 20],
+            version: 7,
+        };
+        let json = to_json(&state).expect("json");
+        assert_eq!(
+            json,
+            format!(
+                r#"{{"len":4096,"tail_sha1":"{}","version":7}}"#,
+                "ab".repeat(20)
+            )
+        );
+        let back: ArchiveSyncState = serde_json::from_str(&json).expect("parse");
+        assert_eq!(back, state);
+        let short = r#"{"len":1,"tail_sha1":"abcd","version":1}"#;
+        assert!(serde_json::from_str::<ArchiveSyncState>(short).is_err());
+
+        let index = StreamIndex {
+            blocks: vec![
+                StreamBlock {
+                    compressed_offset: 0,
+                    uncompressed_offset: 0,
+                    size: 1 << 20,
+                },
+                StreamBlock {
+                    compressed_offset: 4321,
+                    uncompressed_offset: 1 << 20,
+                    size: 17,
+                },
+            ],
+        };
+        let json = to_json(&index).expect("json");
+        assert!(
+            json.starts_with(r#"{"blocks":[{"compressed_offset":0,"#),
+            "{json}"
+        );
+        let back: StreamIndex = serde_json::from_str(&json).expect("parse");
+        assert_eq!(back, index);
+        assert!(serde_json::from_str::<StreamIndex>(r#"{"blocks":[{"size":1}]}"#).is_err());
+    }
+}
@@ -12,6 +12,7 @@
 //!
 //! | Value | Meaning |
 //! |-------|---------|
+//! | `"0"` | Store (no modeling; framing and SHA-1 only) |
 //! | `"1"` | Fast (level 1) |
 //! | `"2"` | Balanced (level 2) |
 //! | `"3"` | Better (level 3) |
@@ -23,12 +24,29 @@
 //! Explicit method strings (starting with `x`, `s`, `i`, `0`–`9`) allow
 //! fine-grained control; see the [zpaq specification](http://mattmahoney.net/dc/zpaq206.pdf)
 //! for details.
+//! To specify the model itself instead — typed components and their
+//! contexts — build a [`ComponentStack`] and compress with
+//! [`compress_with_stack`].
 //!
 //! # Feature flags
 //!
 //! * **`nojit`** — Compiles `libzpaq` with `NOJIT` defined, disabling the JIT
 //!   x86 back-end.  Required on platforms without a functional x86 JIT (NetBSD,
 //!   OpenBSD).  Enabled automatically by the CI for those targets.
+//! * **`serde`** — Implements `serde::Serialize` for the typed listings
+//!   ([`ArchiveListing`], [`VersionInfo`], [`ArchiveSummary`] and others),
+//!   with dates as RFC 3339 and digests as hex, and adds
+//!   `zpaq_command_with_options` to get `zpaq list` results as JSON.
+//! * **`cli`** — Builds the `zpaqrs` binary, a command-line front end to
+//!   this crate (`zpaqrs compress`, `list`, `extract`, `verify` and others).
+//! * **`tracing`** — Emits [`tracing`](https://docs.rs/tracing) spans and
+//!   events under the `zpaq_rs` target: a span per compression,
+//!   decompression or `zpaq add` recording the bytes in and out, and events
+//!   per block compressed and per file an [`ArchiveWriter`] adds.
+//! * **`stats`** — Adds [`StatsCollector`], counting the FFI callbacks a
+//!   call makes, the bytes they move, the blocks compressed and the time
+//!   spent in the callbacks, and `*_with_stats` variants of the streaming
+//!   functions returning the counts.
 //!
 //! # Quick start
 //!
@@ -41,8 +59,13 @@
 //! assert_eq!(restored, original);
 //! ```
 //!
+//! To keep files in an archive held in memory — a versioned store of blobs —
+//! use [`ZpaqArchive`], which batches changes into one new version per
+//! [`flush`](ZpaqArchive::flush).
+//!
 //! For large data or streaming use cases prefer [`compress_stream`] /
-//! [`decompress_stream`], which accept any [`std::io::Read`] / [`std::io::Write`].
+//! [`decompress_stream`], which accept any [`std::io::Read`] / [`std::io::Write`],
+//! or [`compress_stream_dyn`] / [`decompress_stream_dyn`] for trait objects.
 //!
 //! Use [`compress_size`] / [`compress_size_stream`] when you only need the
 //! compressed byte count and not the compressed data itself (avoids allocation).
@@ -57,23 +80,190 @@
 //! * [`compress_size_parallel`] / [`compress_size_stream_parallel`] split the
 //!   input into ZPAQ blocks and compress them in parallel, which can be faster
 //!   on multi-core machines for large inputs.
-
+//!
+//! # Thread safety
+//!
+//! The free functions can be called from any number of threads at once;
+//! the shim's error state is per thread.  Of the types:
+//!
+//! * Options, reports, listings and tokens are plain data: `Send`, `Sync`
+//!   and `Clone`.
+//! * [`Throttle`] and [`ProgressCallback`] are shared handles whose clones
+//!   share one state, from any thread.
+//! * [`StreamingCompressor`], [`PushDecompressor`], [`ArchiveReader`],
+//!   [`ResumableDecompressor`] and [`ZpaqArchive`] own `libzpaq` objects
+//!   (coders, the AES key schedule of an encrypted archive) and the
+//!   callback state they point to.  They are `Send`: a handle can move to
+//!   another thread and carry on there.  They are not `Sync` or `Clone`,
+//!   as `libzpaq`'s objects are not made for concurrent use or copying;
+//!   give each thread its own handle instead.
+//! * The rest ([`Chunker`], [`SlidingNcd`], [`ArchiveWriter`] and the
+//!   like) are plain Rust state, `Send` and `Sync` when their type
+//!   parameters are.
+//!
+//! `tests/thread_safety.rs` checks these at compile time.
+
+mod append;
+mod archive;
+mod batch;
+mod blocks;
+mod breakdown;
+mod cancel;
+mod checkpoint;
+mod chunker;
+mod comment;
+mod convert;
+mod copy;
+mod cpu;
+mod dictionary;
+mod diff;
+mod display;
+mod extract;
+mod filter;
+mod format;
+mod fragments;
+mod handle;
+mod io_tuning;
+mod journal;
+#[cfg(feature = "serde")]
+mod json;
+mod limits;
+mod listing;
+mod method;
+mod model;
+mod options;
+mod parallel;
+mod parts;
+mod progress;
+mod push;
+mod read_policy;
+mod reader;
+mod repack;
+mod resume;
+mod salvage;
+mod secret;
+mod seekable;
+mod self_check;
+mod similarity;
+#[cfg(feature = "stats")]
+mod stats;
+mod sweep;
+mod sync;
 mod sys;
-
-use std::collections::VecDeque;
-use std::ffi::CString;
-use std::fs::OpenOptions;
+mod temp;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+mod throttle;
+mod trace;
+mod verify;
+mod verify_state;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{CString, OsStr};
+use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::os::raw::{c_char, c_int};
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::slice;
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::SystemTime;
+
+use method::{StreamingMethod, method_cstring, normalize_method};
+use progress::Meter;
+
+pub use append::{AppendOptions, archive_append_entries_with};
+pub use archive::ZpaqArchive;
+pub use batch::decompress_many;
+pub use blocks::{
+    BlockInfo, SegmentInfo, list_blocks, list_blocks_stream, original_size_hint, peek_block,
+};
+pub use breakdown::{SizeBreakdown, explain_size, explain_size_file};
+pub use cancel::CancellationToken;
+pub use checkpoint::{CheckpointingCompressor, CompressCheckpoint};
+pub use chunker::{ChunkBoundary, Chunker};
+pub use comment::{decode_extra_comment, encode_extra_comment};
+pub use convert::{convert_to_journal, export_member_as_stream};
+pub use copy::{CopyMode, CopyReport, zpaq_copy_members};
+pub use cpu::{check_cpu, required_cpu_features};
+pub use dictionary::{compress_with_dictionary, decompress_with_dictionary};
+pub use diff::{
+    CompareEntry, CompareOptions, CompareState, DiffChange, DiffEntry, DiffOptions, DiffTarget,
+    archive_diff, zpaq_compare,
+};
+pub use extract::{ExtractReport, archive_extract_reader_to_dir, archive_extract_to_dir};
+pub use filter::MemberFilter;
+pub use format::{ArchiveFormat, archive_format};
+pub use fragments::{
+    ArchiveSummary, DedupEstimate, DuplicateEntry, FragmentRef, FragmentSet, VersionInfo,
+    VersionLayout, archive_fragments, archive_layout, estimate_dedup, export_fragment_hashes,
+    find_fragment, zpaq_duplicates, zpaq_summary, zpaq_versions,
+};
+pub use io_tuning::{IoTuning, set_default_io_tuning};
+pub use journal::{
+    ArchiveEntryOwned, ArchiveWriter, EntryMeta, MemberWriter, archive_append_owned_entries_file,
+    archive_delete_entries, archive_from_owned_entries, archive_from_owned_entries_with,
+    archive_rename_entries, zpaq_delete, zpaq_rename,
+};
+#[cfg(feature = "serde")]
+pub use json::{CommandOptions, CommandResult, zpaq_command_with_options};
+pub use limits::{
+    DecompressLimits, LimitKind, decompress_size_limited, decompress_stream_limited,
+    decompress_to_vec_limited,
+};
+pub use listing::{ArchiveListing, list_stream, list_stream_each};
+pub use method::{BlockSizeInfo, UnsupportedReason, method_block_size};
+pub use model::{
+    BlockModel, Component, ComponentStack, Context, compress_with_stack, describe_block,
+};
+pub use options::{
+    AlreadyCompressedPolicy, CompressOptions, CompressStats, DecompressOptions, DecompressStats,
+    Digest, HashKind, compress_stream_with, compress_to_vec_with, decompress_stream_with,
+    decompress_to_vec_with,
+};
+pub use parallel::{ParallelOptions, ThreadPriority};
+pub use parts::{ArchiveSpec, MultiPartReader, index_versions, verify_index_matches_parts};
+pub use progress::{Progress, ProgressCallback, ProgressInterval, ProgressReader, ProgressWriter};
+pub use push::{FeedResult, PushDecompressor};
+pub use read_policy::{PolicyReader, ReadPolicy};
+pub use reader::{ArchiveReader, MemberReader};
+pub use repack::{
+    RepackOptions, RepackReport, RepackSelectionOptions, RepackSelectionReport, zpaq_repack,
+    zpaq_repack_selection,
+};
+pub use resume::{ResumableDecompressor, ResumeToken, resume_decompress};
+pub use salvage::{
+    PartialFile, SalvageOptions, SalvageReport, salvage_extract, salvage_extract_reader,
+};
+pub use secret::SecretString;
+pub use seekable::{
+    STREAM_INDEX_FILENAME, StreamBlock, StreamIndex, build_stream_index, random_read,
+};
+pub use self_check::self_check;
+pub use similarity::{
+    BoundedSize, ReferenceSet, SlidingMetric, SlidingNcd, bits_per_byte, classify,
+    compress_size_bounded, cross_bits, entropy_profile, ncd,
+};
+#[cfg(feature = "stats")]
+pub use stats::{
+    OperationStats, StatsCollector, compress_stream_parallel_with_stats,
+    compress_stream_with_stats, decompress_stream_with_stats,
+};
+pub use sweep::{KeyProvider, SweepCallback, SweepOptions, SweepProgress, sweep_archives};
+pub use sync::{ArchiveSyncState, archive_delta_ranges};
+pub use temp::{MIN_TEMP_SPACE, set_temp_dir};
+pub use throttle::{RateLimit, Throttle, ThrottledReader};
+pub use verify::{
+    VerifyError, VerifyErrorKind, VerifyLocation, VerifyOptions, VerifyReport, verify_archive,
+    verify_archive_file, verify_archive_reader,
+};
 
 /// Convenience alias for `std::result::Result<T, ZpaqError>`.
 pub type Result<T> = std::result::Result<T, ZpaqError>;
 
 /// Errors returned by this crate.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ZpaqError {
     /// An error originating inside the C++ `libzpaq` / FFI shim.
     ///
@@ -86,6 +276,184 @@ pub enum ZpaqError {
     /// strings, so any input containing `\0` is rejected before crossing the FFI
     /// boundary.
     NulInString,
+    /// The input already looks like ZPAQ-compressed data and
+    /// [`CompressOptions::on_already_compressed`] is
+    /// [`AlreadyCompressedPolicy::Reject`].
+    AlreadyCompressed,
+    /// A segment's stored SHA-1 does not match the decoded data.
+    ChecksumMismatch,
+    /// The archive has no file stored under this path.
+    NotFound {
+        /// The path asked for.
+        path: String,
+        /// Whether the file is missing because a version of a journaling
+        /// archive (up to the one read) deleted it.
+        was_deleted: bool,
+    },
+    /// The archive already has a file stored under this path, and the
+    /// operation was not asked to replace it.
+    PathExists(String),
+    /// The operation needs a journaling archive (as written by `zpaq add`)
+    /// but was given a streaming one.
+    StreamingArchive,
+    /// A stored path would be written outside the destination directory:
+    /// it is absolute, or has a `..` component or one containing `:` (such
+    /// as a drive letter).
+    PathTraversal(String),
+    /// A part of a multi-part archive does not match the local index that
+    /// describes it: it is missing (`actual` is `None`), has the wrong size,
+    /// or is not in the index at all (`expected` is `None`).
+    IndexMismatch {
+        /// File name of the part.
+        part: String,
+        /// Size the index implies.
+        expected: Option<u64>,
+        /// Size of the part on disk.
+        actual: Option<u64>,
+    },
+    /// The bytes of a journaling archive that an [`ArchiveSyncState`]
+    /// records as synced have changed, for example because the archive was
+    /// repacked or replaced, so a copy of it cannot be brought up to date
+    /// by appending.  The string says what differs.
+    FullResyncRequired(String),
+    /// The archive is being written by someone else: another writer holds
+    /// its lock (see [`ArchiveLock`]), or on Windows another process has it
+    /// open without sharing.
+    ArchiveBusy {
+        /// The archive file found in use.
+        path: String,
+    },
+    /// The archive is not of a format the operation works on, for example
+    /// an encrypted archive opened without a key.  See [`archive_format`].
+    WrongFormat {
+        /// The format found.
+        found: ArchiveFormat,
+    },
+    /// The data decompressed to a different size than the one
+    /// [`DecompressOptions::exact`] trusted.
+    SizeMismatch {
+        /// The size trusted.
+        expected: u64,
+        /// The size decoded.
+        actual: u64,
+    },
+    /// Decompression stopped because the data would exceed one of the
+    /// [`DecompressLimits`].
+    LimitExceeded {
+        /// The limit that tripped.
+        limit: LimitKind,
+        /// Its configured value.
+        max: u64,
+    },
+    /// A block or segment header failed the range checks of hardened
+    /// parsing (see [`set_hardened_parsing`]).  The string says which.
+    ///
+    /// Damage that libzpaq itself detects is still reported as
+    /// [`ZpaqError::Ffi`].
+    Corrupt(String),
+    /// A file the operation was given could not be used: it does not
+    /// exist, cannot be read, or is a directory where a file is expected.
+    Io {
+        /// The file.
+        path: PathBuf,
+        /// What went wrong, as [`std::io::Error::kind`] reports it.
+        kind: std::io::ErrorKind,
+        /// The operating system's message.
+        message: String,
+    },
+    /// [`self_check`] found that this build compresses differently from
+    /// the reference build.
+    SelfCheckFailed {
+        /// The method whose output differs.
+        method: String,
+        /// SHA-256 of the reference output, in hex.
+        expected: String,
+        /// SHA-256 of this build's output, in hex.
+        actual: String,
+    },
+    /// The C++ part of this build was compiled for CPU features this CPU
+    /// lacks (see [`required_cpu_features`]), so calling into it would
+    /// crash with an illegal instruction.
+    UnsupportedCpu {
+        /// The missing features, by their `is_x86_feature_detected!` names.
+        missing: Vec<String>,
+    },
+    /// [`StreamingCompressor`] cannot run the method given.
+    UnsupportedInStreaming {
+        /// The method, normalized (see [`StreamingCompressor`]).
+        method: String,
+        /// Why not.
+        reason: UnsupportedReason,
+        /// The nearest method it can run, if there is one.
+        suggested: Option<String>,
+    },
+    /// The directory chosen for a `zpaq` command's temporary files (see
+    /// [`set_temp_dir`]) has too little free space for it to run.
+    InsufficientTempSpace {
+        /// The directory.
+        dir: PathBuf,
+        /// Bytes the command needs free.
+        required: u64,
+        /// Bytes free.
+        available: u64,
+    },
+    /// The operation stopped because its [`CancellationToken`] was
+    /// cancelled.
+    Cancelled,
+    /// Reading, writing or seeking failed on a stream or file the operation
+    /// works through, where there is no one path to report as
+    /// [`ZpaqError::Io`] does.
+    IoError(IoError),
+}
+
+/// A [`std::io::Error`] that [`ZpaqError`] can carry while staying `Clone`
+/// and `Eq`: clones share the error, and two compare equal when their
+/// kinds and messages do.
+#[derive(Debug, Clone)]
+pub struct IoError(Arc<std::io::Error>);
+
+impl IoError {
+    /// What went wrong, as [`std::io::Error::kind`] reports it.
+    pub fn kind(&self) -> std::io::ErrorKind {
+        self.0.kind()
+    }
+
+    /// The wrapped error.
+    pub fn get_ref(&self) -> &std::io::Error {
+        &self.0
+    }
+}
+
+impl From<std::io::Error> for IoError {
+    fn from(err: std::io::Error) -> Self {
+        IoError(Arc::new(err))
+    }
+}
+
+impl PartialEq for IoError {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+            || (self.kind() == other.kind() && self.0.to_string() == other.0.to_string())
+    }
+}
+
+impl Eq for IoError {}
+
+impl std::fmt::Display for IoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for IoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// Wraps an I/O failure for `map_err`.
+pub(crate) fn io_err(err: std::io::Error) -> ZpaqError {
+    ZpaqError::IoError(err.into())
 }
 
 impl std::fmt::Display for ZpaqError {
@@ -93,11 +461,98 @@ impl std::fmt::Display for ZpaqError {
         match self {
             ZpaqError::Ffi(s) => write!(f, "libzpaq: {s}"),
             ZpaqError::NulInString => write!(f, "string contained NUL byte"),
+            ZpaqError::AlreadyCompressed => write!(f, "input is already ZPAQ-compressed"),
+            ZpaqError::ChecksumMismatch => write!(f, "segment checksum mismatch"),
+            ZpaqError::NotFound {
+                path,
+                was_deleted: false,
+            } => write!(f, "file path not found in archive: {path}"),
+            ZpaqError::NotFound {
+                path,
+                was_deleted: true,
+            } => write!(f, "file deleted from archive: {path}"),
+            ZpaqError::PathExists(path) => write!(f, "file path already in archive: {path}"),
+            ZpaqError::StreamingArchive => write!(f, "not a journaling archive"),
+            ZpaqError::PathTraversal(path) => write!(f, "unsafe path in archive: {path}"),
+            ZpaqError::IndexMismatch {
+                part,
+                expected,
+                actual,
+            } => match (expected, actual) {
+                (Some(_), None) => write!(f, "archive part missing: {part}"),
+                (None, _) => write!(f, "archive part not in index: {part}"),
+                (Some(e), Some(a)) => {
+                    write!(f, "archive part {part} is {a} bytes, index expects {e}")
+                }
+            },
+            ZpaqError::FullResyncRequired(reason) => {
+                write!(f, "synced archive changed, full resync required: {reason}")
+            }
+            ZpaqError::ArchiveBusy { path } => write!(f, "archive is in use: {path}"),
+            ZpaqError::WrongFormat { found } => write!(f, "wrong archive format: {found}"),
+            ZpaqError::SizeMismatch { expected, actual } => {
+                write!(f, "decompressed {actual} bytes, expected {expected}")
+            }
+            ZpaqError::LimitExceeded { limit, max } => match limit {
+                LimitKind::ModelBytes => write!(f, "block model needs more than {max} bytes"),
+                LimitKind::OutputBytes => write!(f, "output exceeds {max} bytes"),
+                LimitKind::Blocks => write!(f, "stream has more than {max} blocks"),
+            },
+            ZpaqError::Corrupt(s) => write!(f, "{s}"),
+            ZpaqError::Io { path, message, .. } => write!(f, "{}: {message}", path.display()),
+            ZpaqError::SelfCheckFailed {
+                method,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "method {method} output differs from the reference build: sha256 {actual}, expected {expected}"
+            ),
+            ZpaqError::UnsupportedCpu { missing } => write!(
+                f,
+                "built for CPU features this CPU lacks: {}",
+                missing.join(", ")
+            ),
+            ZpaqError::UnsupportedInStreaming {
+                method,
+                reason,
+                suggested,
+            } => {
+                match reason {
+                    UnsupportedReason::NeedsPreprocessing => write!(
+                        f,
+                        "method {method} preprocesses whole blocks and cannot stream"
+                    )?,
+                    UnsupportedReason::UnknownMethod => write!(f, "unknown method {method}")?,
+                }
+                match suggested {
+                    Some(suggested) => write!(f, "; try {suggested}"),
+                    None => Ok(()),
+                }
+            }
+            ZpaqError::InsufficientTempSpace {
+                dir,
+                required,
+                available,
+            } => write!(
+                f,
+                "{}: {available} bytes free for temporary files, {required} needed",
+                dir.display()
+            ),
+            ZpaqError::Cancelled => write!(f, "operation cancelled"),
+            ZpaqError::IoError(err) => write!(f, "I/O error: {err}"),
         }
     }
 }
 
-impl std::error::Error for ZpaqError {}
+impl std::error::Error for ZpaqError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ZpaqError::IoError(err) => Some(err.get_ref()),
+            _ => None,
+        }
+    }
+}
 
 fn last_error_string() -> Option<String> {
     unsafe {
@@ -121,7 +576,8 @@ fn last_stdout_string() -> Option<String> {
         let mut buf = vec![0u8; len];
         let copied = sys::zpaq_last_stdout_copy(buf.as_mut_ptr() as *mut c_char, len);
         buf.truncate(copied);
-        String::from_utf8(buf).ok()
+        // Names that are not UTF-8 must not cost the whole output.
+        Some(String::from_utf8_lossy(&buf).into_owned())
     }
 }
 
@@ -134,24 +590,39 @@ fn last_stderr_string() -> Option<String> {
         let mut buf = vec![0u8; len];
         let copied = sys::zpaq_last_stderr_copy(buf.as_mut_ptr() as *mut c_char, len);
         buf.truncate(copied);
-        String::from_utf8(buf).ok()
+        Some(String::from_utf8_lossy(&buf).into_owned())
     }
 }
 
 fn err_from_last() -> ZpaqError {
-    last_error_string()
-        .map(ZpaqError::Ffi)
-        .unwrap_or_else(|| ZpaqError::Ffi("unknown error".to_string()))
+    let message = last_error_string().unwrap_or_else(|| "unknown error".to_string());
+    if unsafe { sys::zpaq_last_error_is_corrupt() } != 0 {
+        ZpaqError::Corrupt(message)
+    } else {
+        ZpaqError::Ffi(message)
+    }
 }
 
-fn clear_last_error() {
+/// Clears the shim's error channel before a call into it, after checking
+/// (once) that this CPU can run the C++ code at all.
+fn clear_last_error() -> Result<()> {
+    check_cpu()?;
     unsafe { sys::zpaq_clear_last_error() };
+    Ok(())
 }
 
 fn clear_last_output() {
     unsafe { sys::zpaq_clear_last_output() };
 }
 
+/// A segment filename or comment as the NUL-terminated string the segment
+/// header stores; any bytes but NUL are allowed.
+fn segment_field(field: Option<&[u8]>) -> Result<Option<CString>> {
+    field
+        .map(|bytes| CString::new(bytes).map_err(|_| ZpaqError::NulInString))
+        .transpose()
+}
+
 /// Captured output of an embedded `zpaq` command.
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ZpaqCommandOutput {
@@ -161,9 +632,22 @@ pub struct ZpaqCommandOutput {
     pub stderr: String,
 }
 
+/// Standard output followed by standard error, as a terminal would show
+/// them.
+impl std::fmt::Display for ZpaqCommandOutput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.stdout)?;
+        if !self.stdout.is_empty() && !self.stdout.ends_with('\n') && !self.stderr.is_empty() {
+            f.write_str("\n")?;
+        }
+        f.write_str(&self.stderr)
+    }
+}
+
 fn zpaq_command_inner(args: &[String]) -> Result<ZpaqCommandOutput> {
-    clear_last_error();
+    clear_last_error()?;
     clear_last_output();
+    let _temp = temp::apply()?;
 
     let mut cargs = Vec::with_capacity(args.len() + 1);
     cargs.push(CString::new("zpaq").map_err(|_| ZpaqError::NulInString)?);
@@ -173,6 +657,16 @@ fn zpaq_command_inner(args: &[String]) -> Result<ZpaqCommandOutput> {
     let ptrs: Vec<*const c_char> = cargs.iter().map(|s| s.as_ptr()).collect();
 
     let rc = unsafe { sys::zpaq_jidac_run(ptrs.len() as c_int, ptrs.as_ptr()) };
+    command_output(rc)
+}
+
+/// The output of the `zpaq` command that returned `rc`, or its error.
+fn command_output(rc: c_int) -> Result<ZpaqCommandOutput> {
+    if rc == sys::ARCHIVE_BUSY {
+        return Err(ZpaqError::ArchiveBusy {
+            path: last_error_string().unwrap_or_default(),
+        });
+    }
     if rc != 0 {
         return Err(err_from_last());
     }
@@ -210,6 +704,12 @@ impl CountingWriter {
     pub fn bytes_written(&self) -> u64 {
         self.bytes
     }
+
+    /// A writer that forwards to `inner` and counts, instead of
+    /// discarding; see [`CountedWriter`].
+    pub fn wrap<W: Write>(inner: W) -> CountedWriter<W> {
+        CountedWriter::new(inner)
+    }
 }
 
 impl Write for CountingWriter {
@@ -226,25 +726,151 @@ impl Write for CountingWriter {
     }
 }
 
+/// A [`Write`] adapter that counts the bytes `inner` accepts.
+///
+/// Writes, errors and flushes go to `inner` unchanged; only bytes it
+/// reports written are counted.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{CountingWriter, compress_stream};
+///
+/// let mut out = CountingWriter::wrap(Vec::new());
+/// compress_stream(&b"hello hello hello"[..], &mut out, "1", None, None)?;
+/// assert_eq!(out.bytes_written(), out.get_ref().len() as u64);
+/// let compressed = out.into_inner();
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct CountedWriter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: Write> CountedWriter<W> {
+    /// Wraps `inner`, counting from 0.
+    pub fn new(inner: W) -> Self {
+        CountedWriter { inner, bytes: 0 }
+    }
+
+    /// Returns the total number of bytes written so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns a reference to the inner writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner writer.  Bytes written
+    /// through it are not counted.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the inner writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A [`Read`] adapter that counts the bytes read from `inner`, such as how
+/// much of a stream a decompression consumed.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{CountingReader, compress_to_vec, decompress_stream};
+///
+/// let compressed = compress_to_vec(b"hello", "1")?;
+/// let mut input = CountingReader::new(&compressed[..]);
+/// let mut out = Vec::new();
+/// decompress_stream(&mut input, &mut out)?;
+/// assert_eq!(input.bytes_read(), compressed.len() as u64);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct CountingReader<R> {
+    inner: R,
+    bytes: u64,
+}
+
+impl<R: Read> CountingReader<R> {
+    /// Wraps `inner`, counting from 0.
+    pub fn new(inner: R) -> Self {
+        CountingReader { inner, bytes: 0 }
+    }
+
+    /// Returns the total number of bytes read so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns a reference to the inner reader.
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner reader.  Bytes read
+    /// through it are not counted.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
 // ---------------- Callback plumbing ----------------
 
-struct ReadCtx<R: Read + Send> {
-    reader: R,
+// The contexts hold their reader or writer as a trait object, so that one
+// copy of each callback serves every type, however many a program uses.
+
+struct ReadCtx<'a> {
+    reader: Box<dyn Read + Send + 'a>,
+    policy: ReadPolicy,
+    /// Bytes `reader` has returned, for the policy.
+    bytes: u64,
 }
 
-struct WriteCtx<W: Write + Send> {
-    writer: W,
+struct WriteCtx<'a> {
+    writer: Box<dyn Write + Send + 'a>,
 }
 
-unsafe extern "C" fn read_cb<R: Read + Send>(
-    ctx: *mut std::os::raw::c_void,
-    buf: *mut c_char,
-    n: c_int,
-) -> c_int {
+unsafe extern "C" fn read_cb(ctx: *mut std::os::raw::c_void, buf: *mut c_char, n: c_int) -> c_int {
+    #[cfg(feature = "stats")]
+    let call = stats::Callback::start();
     unsafe {
-        let ctx = &mut *(ctx as *mut ReadCtx<R>);
+        let ctx = &mut *(ctx as *mut ReadCtx<'_>);
         let slice = slice::from_raw_parts_mut(buf as *mut u8, n as usize);
-        match ctx.reader.read(slice) {
+        let result = ctx.policy.read(&mut ctx.reader, slice, &mut ctx.bytes);
+        #[cfg(feature = "stats")]
+        call.read(*result.as_ref().unwrap_or(&0));
+        match result {
             Ok(bytes) => bytes as c_int,
             Err(e) => {
                 set_callback_error(&e.to_string());
@@ -254,15 +880,20 @@ unsafe extern "C" fn read_cb<R: Read + Send>(
     }
 }
 
-unsafe extern "C" fn write_cb<W: Write + Send>(
+unsafe extern "C" fn write_cb(
     ctx: *mut std::os::raw::c_void,
     buf: *const c_char,
     n: c_int,
 ) -> c_int {
+    #[cfg(feature = "stats")]
+    let call = stats::Callback::start();
     unsafe {
-        let ctx = &mut *(ctx as *mut WriteCtx<W>);
+        let ctx = &mut *(ctx as *mut WriteCtx<'_>);
         let slice = slice::from_raw_parts(buf as *const u8, n as usize);
-        match ctx.writer.write_all(slice) {
+        let result = ctx.writer.write_all(slice);
+        #[cfg(feature = "stats")]
+        call.wrote(slice.len());
+        match result {
             Ok(()) => 0,
             Err(e) => {
                 set_callback_error(&e.to_string());
@@ -274,28 +905,46 @@ unsafe extern "C" fn write_cb<W: Write + Send>(
 
 // ---------------- Streaming compressor ----------------
 
-#[derive(Default)]
-struct StreamReader {
-    buf: VecDeque<u8>,
-}
+/// Bytes pushed and not yet compressed.  The compressor owns the queue and
+/// its reader holds a pointer to it, so neither side takes a lock on the
+/// per-byte path.
+struct StreamQueue(ptr::NonNull<VecDeque<u8>>);
+
+impl StreamQueue {
+    fn new() -> Self {
+        Self(ptr::NonNull::from(Box::leak(Box::default())))
+    }
+
+    /// A reader of the queue, valid while the queue lives.
+    fn reader(&self) -> StreamReader {
+        StreamReader { buf: self.0 }
+    }
 
-impl StreamReader {
     fn push(&mut self, b: u8) {
-        self.buf.push_back(b);
+        unsafe { self.0.as_mut() }.push_back(b);
+    }
+}
+
+impl Drop for StreamQueue {
+    fn drop(&mut self) {
+        drop(unsafe { Box::from_raw(self.0.as_ptr()) });
     }
 }
 
+struct StreamReader {
+    buf: ptr::NonNull<VecDeque<u8>>,
+}
+
+// SAFETY: the reader only runs inside the calls of the compressor that owns
+// its queue, never at the same time as `push`, and moves with it.
+unsafe impl Send for StreamReader {}
+
 impl Read for StreamReader {
     fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
-        let mut n = 0usize;
-        while n < out.len() {
-            match self.buf.pop_front() {
-                Some(b) => {
-                    out[n] = b;
-                    n += 1;
-                }
-                None => break,
-            }
+        let buf = unsafe { self.buf.as_mut() };
+        let n = out.len().min(buf.len());
+        for (slot, b) in out.iter_mut().zip(buf.drain(..n)) {
+            *slot = b;
         }
         Ok(n)
     }
@@ -312,13 +961,29 @@ impl Read for StreamReader {
 /// # Method string restrictions
 ///
 /// Streaming mode only supports:
-/// * Numeric levels **1, 2, or 3** — levels 4 and 5 use block pre-processing
-///   that is incompatible with per-byte feeding.
-/// * Explicit method strings that start with `x`, `s`, `i`, or a digit and do
+/// * Numeric levels **1, 2, or 3**, using `libzpaq`'s built-in models (any
+///   block size digit after the level is ignored) — levels 4 and 5 use block
+///   pre-processing that is incompatible with per-byte feeding.
+/// * Explicit method strings that start with `x`, `s`, `i`, or `0` and do
 ///   **not** enable block pre-processing.
 ///
-/// Attempting to create a compressor with level 4, 5, or a method that requires
-/// block preprocessing will return [`ZpaqError::Ffi`].
+/// As everywhere, surrounding whitespace and the case of letters do not
+/// matter.  Other methods fail with [`ZpaqError::UnsupportedInStreaming`],
+/// which says why and, where there is one, suggests the nearest method that
+/// streams:
+///
+/// ```rust
+/// use zpaq_rs::{StreamingCompressor, UnsupportedReason, ZpaqError};
+///
+/// let Err(ZpaqError::UnsupportedInStreaming { reason, suggested, .. }) =
+///     StreamingCompressor::new("4")
+/// else {
+///     panic!("level 4 cannot stream");
+/// };
+/// assert_eq!(reason, UnsupportedReason::NeedsPreprocessing);
+/// let sc = StreamingCompressor::new(&suggested.unwrap())?;
+/// # Ok::<(), ZpaqError>(())
+/// ```
 ///
 /// # Example
 ///
@@ -332,13 +997,20 @@ impl Read for StreamReader {
 /// println!("bits so far: {:.2}", sc.bits());
 /// ```
 pub struct StreamingCompressor {
-    compressor: *mut sys::Compressor,
-    reader: *mut sys::RustReader,
-    writer: *mut sys::RustWriter,
-    reader_ctx: *mut ReadCtx<StreamReader>,
-    writer_ctx: *mut WriteCtx<CountingWriter>,
+    compressor: handle::Compressor,
+    header_bits: f64,
+    pushed: bool,
+    // Dropped after the compressor that refers to them, and the queue after
+    // the reader that refers to it.
+    _reader: FfiReader<'static>,
+    _writer: FfiWriter<'static>,
+    input: StreamQueue,
 }
 
+// SAFETY: the compressor, its reader and writer and their contexts are
+// owned by the value and freed together when it drops, and `libzpaq`
+// objects have no thread affinity, so the whole can move between threads.
+// Not `Sync`: `bits` reads the coder that `push` advances.
 unsafe impl Send for StreamingCompressor {}
 
 impl StreamingCompressor {
@@ -348,134 +1020,64 @@ impl StreamingCompressor {
     /// internal reader/writer callbacks, writes the ZPAQ block tag, and opens
     /// the first segment ready to receive bytes via [`push`](Self::push).
     ///
-    /// Returns [`ZpaqError::Ffi`] if the method is unsupported in streaming mode
-    /// (e.g. numeric levels 4–5) or if any C++ initialisation step fails.
+    /// Returns [`ZpaqError::UnsupportedInStreaming`] if the method is
+    /// unsupported in streaming mode (e.g. numeric levels 4–5), and
+    /// [`ZpaqError::Ffi`] if any C++ initialisation step fails.
     pub fn new(method: &str) -> Result<Self> {
-        let method_trim = method.trim();
-        if method_trim.is_empty() {
-            return Err(ZpaqError::Ffi("method string is empty".into()));
-        }
-        let numeric = method_trim.parse::<i32>().ok();
-        let level = numeric.filter(|v| (1..=3).contains(v));
-        if numeric.is_some() && level.is_none() {
-            return Err(ZpaqError::Ffi(
-                "streaming numeric levels support 1..3 only; use x/s/i/0 with no preprocessing"
-                    .into(),
-            ));
-        }
-
-        let compressor = unsafe { sys::zpaq_compressor_new() };
-        if compressor.is_null() {
-            return Err(ZpaqError::Ffi("zpaq_compressor_new failed".into()));
-        }
+        let method = method::streaming_method(method)?;
 
-        let reader_ctx = Box::into_raw(Box::new(ReadCtx {
-            reader: StreamReader::default(),
-        }));
-        let writer_ctx = Box::into_raw(Box::new(WriteCtx {
-            writer: CountingWriter::default(),
-        }));
-
-        let reader =
-            unsafe { sys::zpaq_reader_new(reader_ctx.cast(), None, Some(read_cb::<StreamReader>)) };
-        if reader.is_null() {
-            unsafe {
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
-            return Err(ZpaqError::Ffi("zpaq_reader_new failed".into()));
-        }
-
-        let writer = unsafe {
-            sys::zpaq_writer_new(
-                writer_ctx.cast(),
-                Some(put_cb::<CountingWriter>),
-                Some(write_cb::<CountingWriter>),
-            )
-        };
-        if writer.is_null() {
-            unsafe {
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
-            return Err(ZpaqError::Ffi("zpaq_writer_new failed".into()));
-        }
+        // Running dry between pushes is how the compressor stops, whatever
+        // the policy in force.
+        let input = StreamQueue::new();
+        let reader = FfiReader::with_policy(Box::new(input.reader()), ReadPolicy::TreatZeroAsEof)?;
+        let written = SharedCounter::default();
+        let writer = FfiWriter::new(written.clone())?;
+        let compressor = handle::Compressor::new()?;
 
-        let rc_out = unsafe { sys::zpaq_compressor_set_output(compressor, writer) };
-        if rc_out != 0 {
-            unsafe {
-                sys::zpaq_writer_free(writer);
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
+        if unsafe { sys::zpaq_compressor_set_output(compressor.raw, writer.raw) } != 0 {
             return Err(err_from_last());
         }
-
-        let rc_in = unsafe { sys::zpaq_compressor_set_input(compressor, reader) };
-        if rc_in != 0 {
-            unsafe {
-                sys::zpaq_writer_free(writer);
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
+        if unsafe { sys::zpaq_compressor_set_input(compressor.raw, reader.raw) } != 0 {
             return Err(err_from_last());
         }
-
-        let rc_tag = unsafe { sys::zpaq_compressor_write_tag(compressor) };
-        if rc_tag != 0 {
-            unsafe {
-                sys::zpaq_writer_free(writer);
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
+        if unsafe { sys::zpaq_compressor_write_tag(compressor.raw) } != 0 {
             return Err(err_from_last());
         }
 
-        let rc_block = if let Some(level) = level {
-            unsafe { sys::zpaq_compressor_start_block_level(compressor, level) }
-        } else {
-            let method_c = CString::new(method_trim).map_err(|_| ZpaqError::NulInString)?;
-            unsafe { sys::zpaq_compressor_start_block_method(compressor, method_c.as_ptr()) }
+        let rc_block = match method {
+            StreamingMethod::Level(level) => unsafe {
+                sys::zpaq_compressor_start_block_level(compressor.raw, level)
+            },
+            StreamingMethod::Explicit(method) => {
+                let method_c = method_cstring(&method)?;
+                unsafe {
+                    sys::zpaq_compressor_start_block_method(compressor.raw, method_c.as_ptr())
+                }
+            }
         };
         if rc_block != 0 {
-            unsafe {
-                sys::zpaq_writer_free(writer);
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
             return Err(err_from_last());
         }
 
         let rc_seg =
-            unsafe { sys::zpaq_compressor_start_segment(compressor, ptr::null(), ptr::null()) };
+            unsafe { sys::zpaq_compressor_start_segment(compressor.raw, ptr::null(), ptr::null()) };
         if rc_seg != 0 {
-            unsafe {
-                sys::zpaq_writer_free(writer);
-                sys::zpaq_reader_free(reader);
-                sys::zpaq_compressor_free(compressor);
-                drop(Box::from_raw(reader_ctx));
-                drop(Box::from_raw(writer_ctx));
-            }
             return Err(err_from_last());
         }
 
+        // Flush the buffered header so the count includes it.
+        let rc_flush = unsafe { sys::zpaq_writer_flush(writer.raw) };
+        if rc_flush != 0 {
+            return Err(err_from_last());
+        }
+        let header_bits = written.get() as f64 * 8.0;
         Ok(Self {
             compressor,
-            reader,
-            writer,
-            reader_ctx,
-            writer_ctx,
+            header_bits,
+            pushed: false,
+            _reader: reader,
+            _writer: writer,
+            input,
         })
     }
 
@@ -484,45 +1086,45 @@ impl StreamingCompressor {
     /// Returns [`ZpaqError::Ffi`] if the underlying `libzpaq::Compressor::compress`
     /// call fails (e.g. due to an I/O error in the underlying writer callback).
     pub fn push(&mut self, b: u8) -> Result<()> {
-        unsafe {
-            let ctx = &mut *self.reader_ctx;
-            ctx.reader.push(b);
-        }
-        let rc = unsafe { sys::zpaq_compressor_compress(self.compressor, 1) };
+        self.input.push(b);
+        let rc = unsafe { sys::zpaq_compressor_compress(self.compressor.raw, 1) };
         if rc < 0 {
             return Err(err_from_last());
         }
+        self.pushed = true;
         Ok(())
     }
 
     /// Returns the number of bits written to the compressed output so far.
     ///
-    /// This reflects the running total emitted by `libzpaq`'s internal bit
-    /// counter and accounts for both the block header and all bytes fed via
-    /// [`push`](Self::push).  The value is a `f64` because `libzpaq` tracks
-    /// fractional bits internally.
+    /// This is the header (the locator tag, block header and segment
+    /// header, 8 bits a byte) plus the running total of `libzpaq`'s
+    /// internal bit counter for the bytes fed via [`push`](Self::push), so
+    /// before the first push it is exactly the header cost.  The value is a
+    /// `f64` because `libzpaq` tracks fractional bits internally.
+    ///
+    /// Releases up to 1.0.5 did not count the header, so the value started
+    /// at 0.  For the cost of the pushed bytes alone, subtract the value read
+    /// before the first push, as [`cross_bits`] and [`entropy_profile`] do.
     pub fn bits(&self) -> f64 {
-        unsafe { sys::zpaq_compressor_get_bits(self.compressor) }
-    }
-}
-
-impl Drop for StreamingCompressor {
-    fn drop(&mut self) {
-        unsafe {
-            sys::zpaq_writer_free(self.writer);
-            sys::zpaq_reader_free(self.reader);
-            sys::zpaq_compressor_free(self.compressor);
-            drop(Box::from_raw(self.reader_ctx));
-            drop(Box::from_raw(self.writer_ctx));
+        if !self.pushed {
+            // The encoder's counter starts with the first byte.
+            return self.header_bits;
         }
+        self.header_bits + unsafe { sys::zpaq_compressor_get_bits(self.compressor.raw) }
     }
 }
 
-unsafe extern "C" fn put_cb<W: Write + Send>(ctx: *mut std::os::raw::c_void, c: c_int) -> c_int {
+unsafe extern "C" fn put_cb(ctx: *mut std::os::raw::c_void, c: c_int) -> c_int {
+    #[cfg(feature = "stats")]
+    let call = stats::Callback::start();
     unsafe {
-        let ctx = &mut *(ctx as *mut WriteCtx<W>);
+        let ctx = &mut *(ctx as *mut WriteCtx<'_>);
         let byte = [c as u8];
-        match ctx.writer.write_all(&byte) {
+        let result = ctx.writer.write_all(&byte);
+        #[cfg(feature = "stats")]
+        call.wrote(1);
+        match result {
             Ok(()) => 0,
             Err(e) => {
                 set_callback_error(&e.to_string());
@@ -532,21 +1134,81 @@ unsafe extern "C" fn put_cb<W: Write + Send>(ctx: *mut std::os::raw::c_void, c:
     }
 }
 
+/// Has the shim report each block it compresses to [`on_block`], and
+/// worker threads it could not set up to [`on_worker_failures`].
+#[cfg(any(feature = "tracing", feature = "stats"))]
+fn install_hooks() {
+    static HOOK: std::sync::Once = std::sync::Once::new();
+    HOOK.call_once(|| unsafe {
+        sys::zpaq_set_block_hook(Some(on_block));
+        sys::zpaq_set_worker_failure_hook(Some(on_worker_failures));
+    });
+}
+
+#[cfg(any(feature = "tracing", feature = "stats"))]
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+unsafe extern "C" fn on_block(uncompressed: u64, compressed: u64) {
+    #[cfg(feature = "tracing")]
+    trace::block(uncompressed, compressed);
+    #[cfg(feature = "stats")]
+    stats::block();
+}
+
+#[cfg(any(feature = "tracing", feature = "stats"))]
+unsafe extern "C" fn on_worker_failures(failures: u64) {
+    worker_setup_failed(failures);
+}
+
+/// `failures` worker threads started for a call on this thread could not
+/// take their [`ParallelOptions`].
+#[cfg_attr(
+    not(any(feature = "tracing", feature = "stats")),
+    allow(unused_variables)
+)]
+fn worker_setup_failed(failures: u64) {
+    #[cfg(feature = "tracing")]
+    trace::worker_setup_failed(failures);
+    #[cfg(feature = "stats")]
+    stats::worker_setup_failed(failures);
+}
+
 fn set_callback_error(msg: &str) {
     if let Ok(cstr) = CString::new(msg) {
         unsafe { sys::zpaq_set_last_error(cstr.as_ptr()) };
     }
 }
 
-struct FfiReader<R: Read + Send> {
+struct FfiReader<'a> {
     raw: *mut sys::RustReader,
-    ctx: *mut ReadCtx<R>,
+    ctx: *mut ReadCtx<'a>,
 }
 
-impl<R: Read + Send> FfiReader<R> {
-    fn new(reader: R) -> Result<Self> {
-        let ctx = Box::into_raw(Box::new(ReadCtx { reader }));
-        let raw = unsafe { sys::zpaq_reader_new(ctx as *mut _, None, Some(read_cb::<R>)) };
+impl<'a> FfiReader<'a> {
+    fn new(reader: impl Read + Send + 'a) -> Result<Self> {
+        Self::with_policy(Box::new(reader), ReadPolicy::current())
+    }
+
+    /// Reads [`IoTuning::read_chunk`] bytes at a time, for the stream
+    /// functions.  Not for readers whose position is worked out from the
+    /// bytes the decompresser has buffered, which then counts too few.
+    fn tuned(reader: impl Read + Send + 'a) -> Result<Self> {
+        let this = Self::new(reader)?;
+        let chunk = IoTuning::current().read_chunk;
+        if unsafe { sys::zpaq_reader_set_chunk(this.raw, chunk as c_int) } != 0 {
+            return Err(err_from_last());
+        }
+        Ok(this)
+    }
+
+    /// Reads under `policy` rather than the one in force.
+    fn with_policy(reader: Box<dyn Read + Send + 'a>, policy: ReadPolicy) -> Result<Self> {
+        check_cpu()?;
+        let ctx = Box::into_raw(Box::new(ReadCtx {
+            reader,
+            policy,
+            bytes: 0,
+        }));
+        let raw = unsafe { sys::zpaq_reader_new(ctx as *mut _, None, Some(read_cb)) };
         if raw.is_null() {
             unsafe {
                 drop(Box::from_raw(ctx));
@@ -557,7 +1219,7 @@ impl<R: Read + Send> FfiReader<R> {
     }
 }
 
-impl<R: Read + Send> Drop for FfiReader<R> {
+impl Drop for FfiReader<'_> {
     fn drop(&mut self) {
         unsafe {
             sys::zpaq_reader_free(self.raw);
@@ -566,27 +1228,36 @@ impl<R: Read + Send> Drop for FfiReader<R> {
     }
 }
 
-struct FfiWriter<W: Write + Send> {
+struct FfiWriter<'a> {
     raw: *mut sys::RustWriter,
-    ctx: *mut WriteCtx<W>,
+    ctx: *mut WriteCtx<'a>,
 }
 
-impl<W: Write + Send> FfiWriter<W> {
-    fn new(writer: W) -> Result<Self> {
-        let ctx = Box::into_raw(Box::new(WriteCtx { writer }));
-        let raw =
-            unsafe { sys::zpaq_writer_new(ctx as *mut _, Some(put_cb::<W>), Some(write_cb::<W>)) };
+impl<'a> FfiWriter<'a> {
+    fn new(writer: impl Write + Send + 'a) -> Result<Self> {
+        check_cpu()?;
+        let ctx = Box::into_raw(Box::new(WriteCtx {
+            writer: Box::new(writer),
+        }));
+        let raw = unsafe { sys::zpaq_writer_new(ctx as *mut _, Some(put_cb), Some(write_cb)) };
         if raw.is_null() {
             unsafe {
                 drop(Box::from_raw(ctx));
             }
             return Err(err_from_last());
         }
-        Ok(Self { raw, ctx })
+        let this = Self { raw, ctx };
+        let chunk = IoTuning::current().write_chunk;
+        if unsafe { sys::zpaq_writer_set_chunk(raw, chunk as c_int) } != 0 {
+            return Err(err_from_last());
+        }
+        #[cfg(feature = "stats")]
+        stats::configure(raw);
+        Ok(this)
     }
 }
 
-impl<W: Write + Send> Drop for FfiWriter<W> {
+impl Drop for FfiWriter<'_> {
     fn drop(&mut self) {
         unsafe {
             sys::zpaq_writer_free(self.raw);
@@ -610,6 +1281,11 @@ impl SharedVecWriter {
     fn bytes(&self) -> Vec<u8> {
         self.inner.lock().expect("poisoned writer buffer").clone()
     }
+
+    /// The bytes written so far, leaving the buffer empty.
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.inner.lock().expect("poisoned writer buffer"))
+    }
 }
 
 impl Write for SharedVecWriter {
@@ -626,11 +1302,21 @@ impl Write for SharedVecWriter {
     }
 }
 
-#[derive(Default)]
-struct SinkWriter;
+/// Byte counter that stays readable after the writer handed to FFI is gone.
+#[derive(Clone, Default)]
+struct SharedCounter {
+    bytes: Arc<AtomicU64>,
+}
+
+impl SharedCounter {
+    fn get(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
 
-impl Write for SinkWriter {
+impl Write for SharedCounter {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.bytes.fetch_add(buf.len() as u64, Ordering::Relaxed);
         Ok(buf.len())
     }
 
@@ -640,7 +1326,7 @@ impl Write for SinkWriter {
 }
 
 fn start_block_for_method(compressor: *mut sys::Compressor, method: &str) -> Result<()> {
-    let method_trim = method.trim();
+    let method_trim = normalize_method(method);
     if method_trim.is_empty() {
         return Err(ZpaqError::Ffi("method string is empty".into()));
     }
@@ -652,7 +1338,7 @@ fn start_block_for_method(compressor: *mut sys::Compressor, method: &str) -> Res
         }
         unsafe { sys::zpaq_compressor_start_block_level(compressor, level) }
     } else {
-        let method_c = CString::new(method_trim).map_err(|_| ZpaqError::NulInString)?;
+        let method_c = method_cstring(&method_trim)?;
         unsafe { sys::zpaq_compressor_start_block_method(compressor, method_c.as_ptr()) }
     };
 
@@ -679,32 +1365,26 @@ pub struct ArchiveEntry<'a> {
 /// This performs no scratch-file I/O and writes each entry with its `path`
 /// stored as the segment filename.
 pub fn archive_from_entries(entries: &[ArchiveEntry<'_>], method: &str) -> Result<Vec<u8>> {
-    clear_last_error();
+    clear_last_error()?;
     if entries.is_empty() {
         return Ok(Vec::new());
     }
 
-    let compressor = unsafe { sys::zpaq_compressor_new() };
-    if compressor.is_null() {
-        return Err(err_from_last());
-    }
-
     let out_shared = SharedVecWriter::new();
     let out_writer = FfiWriter::new(out_shared.clone())?;
+    let compressor = handle::Compressor::new()?;
 
-    let set_out = unsafe { sys::zpaq_compressor_set_output(compressor, out_writer.raw) };
+    let set_out = unsafe { sys::zpaq_compressor_set_output(compressor.raw, out_writer.raw) };
     if set_out != 0 {
-        unsafe { sys::zpaq_compressor_free(compressor) };
         return Err(err_from_last());
     }
 
-    let rc_tag = unsafe { sys::zpaq_compressor_write_tag(compressor) };
+    let rc_tag = unsafe { sys::zpaq_compressor_write_tag(compressor.raw) };
     if rc_tag != 0 {
-        unsafe { sys::zpaq_compressor_free(compressor) };
         return Err(err_from_last());
     }
 
-    start_block_for_method(compressor, method)?;
+    start_block_for_method(compressor.raw, method)?;
 
     for entry in entries {
         let filename_c = CString::new(entry.path).map_err(|_| ZpaqError::NulInString)?;
@@ -715,7 +1395,7 @@ pub fn archive_from_entries(entries: &[ArchiveEntry<'_>], method: &str) -> Resul
 
         let rc_seg = unsafe {
             sys::zpaq_compressor_start_segment(
-                compressor,
+                compressor.raw,
                 filename_c.as_ptr(),
                 comment_c
                     .as_ref()
@@ -724,21 +1404,18 @@ pub fn archive_from_entries(entries: &[ArchiveEntry<'_>], method: &str) -> Resul
             )
         };
         if rc_seg != 0 {
-            unsafe { sys::zpaq_compressor_free(compressor) };
             return Err(err_from_last());
         }
 
         let input = FfiReader::new(std::io::Cursor::new(entry.data))?;
-        let rc_in = unsafe { sys::zpaq_compressor_set_input(compressor, input.raw) };
+        let rc_in = unsafe { sys::zpaq_compressor_set_input(compressor.raw, input.raw) };
         if rc_in != 0 {
-            unsafe { sys::zpaq_compressor_free(compressor) };
             return Err(err_from_last());
         }
 
         loop {
-            let rc = unsafe { sys::zpaq_compressor_compress(compressor, 1 << 20) };
+            let rc = unsafe { sys::zpaq_compressor_compress(compressor.raw, 1 << 20) };
             if rc < 0 {
-                unsafe { sys::zpaq_compressor_free(compressor) };
                 return Err(err_from_last());
             }
             if rc == 0 {
@@ -746,15 +1423,14 @@ pub fn archive_from_entries(entries: &[ArchiveEntry<'_>], method: &str) -> Resul
             }
         }
 
-        let rc_end_seg = unsafe { sys::zpaq_compressor_end_segment(compressor, ptr::null()) };
+        let rc_end_seg = unsafe { sys::zpaq_compressor_end_segment(compressor.raw, ptr::null()) };
         if rc_end_seg != 0 {
-            unsafe { sys::zpaq_compressor_free(compressor) };
             return Err(err_from_last());
         }
     }
 
-    let rc_end_block = unsafe { sys::zpaq_compressor_end_block(compressor) };
-    unsafe { sys::zpaq_compressor_free(compressor) };
+    let rc_end_block = unsafe { sys::zpaq_compressor_end_block(compressor.raw) };
+    drop(compressor);
     if rc_end_block != 0 {
         return Err(err_from_last());
     }
@@ -763,6 +1439,28 @@ pub fn archive_from_entries(entries: &[ArchiveEntry<'_>], method: &str) -> Resul
     Ok(out_shared.bytes())
 }
 
+/// Appends raw byte entries to an in-memory archive.
+///
+/// Appends exactly the bytes [`archive_append_entries_file`] writes to a file
+/// holding the same archive: one new streaming block with a segment per
+/// entry.  Journaling archives accept the block too; `zpaq` lists its
+/// segments alongside the journaled files.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::WrongFormat`] if `archive` is encrypted or not an
+/// archive at all.
+pub fn archive_append_entries(
+    archive: &mut Vec<u8>,
+    entries: &[ArchiveEntry<'_>],
+    method: &str,
+) -> Result<()> {
+    format::require_plain(&mut std::io::Cursor::new(&archive[..]))?;
+    let payload = archive_from_entries(entries, method)?;
+    archive.extend_from_slice(&payload);
+    Ok(())
+}
+
 /// Appends raw byte entries to an archive file path without creating scratch files.
 pub fn archive_append_entries_file(
     archive_path: &str,
@@ -775,28 +1473,53 @@ pub fn archive_append_entries_file(
     }
 
     let mut file = OpenOptions::new()
+        .read(true)
         .create(true)
         .append(true)
         .open(archive_path)
         .map_err(|e| ZpaqError::Ffi(format!("open archive for append failed: {e}")))?;
+    file.lock()
+        .map_err(|e| ZpaqError::Ffi(format!("lock archive for append failed: {e}")))?;
+    format::require_plain(&mut file)?;
     file.write_all(&payload)
         .map_err(|e| ZpaqError::Ffi(format!("append archive write failed: {e}")))
 }
 
+/// 13-byte locator tag that `libzpaq` writes before a block (`"7kSt"` + 9).
+pub(crate) const ZPAQ_TAG: [u8; 13] = [
+    0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3,
+];
+
+/// Returns `true` if `bytes` starts like a ZPAQ stream or archive.
+///
+/// Matches the locator tag written by this crate and the `zpaq` CLI, or a
+/// bare block header (`"zPQ"`, level 1 or 2, ZPAQL type 1).  This is a cheap
+/// header sniff; it does not validate the rest of the data.
+///
+/// # Example
+///
+/// ```rust
+/// let c = zpaq_rs::compress_to_vec(b"hello", "1").unwrap();
+/// assert!(zpaq_rs::is_zpaq(&c));
+/// assert!(!zpaq_rs::is_zpaq(b"hello"));
+/// ```
+pub fn is_zpaq(bytes: &[u8]) -> bool {
+    if bytes.starts_with(&ZPAQ_TAG) {
+        return true;
+    }
+    matches!(bytes, [b'z', b'P', b'Q', 1 | 2, 1, ..])
+}
+
 /// Reads the newest segment whose stored filename matches `path` from an
 /// in-memory archive stream.
 fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<Option<Vec<u8>>> {
-    clear_last_error();
+    clear_last_error()?;
 
     let reader = FfiReader::new(std::io::Cursor::new(archive))?;
-    let decompresser = unsafe { sys::zpaq_decompresser_new() };
-    if decompresser.is_null() {
-        return Err(err_from_last());
-    }
+    let decompresser = handle::Decompresser::new()?;
 
-    let set_in = unsafe { sys::zpaq_decompresser_set_input(decompresser, reader.raw) };
+    let set_in = unsafe { sys::zpaq_decompresser_set_input(decompresser.raw, reader.raw) };
     if set_in != 0 {
-        unsafe { sys::zpaq_decompresser_free(decompresser) };
         return Err(err_from_last());
     }
 
@@ -804,9 +1527,8 @@ fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<O
     let mut mem_out = 0.0f64;
 
     loop {
-        let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, &mut mem_out) };
+        let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser.raw, &mut mem_out) };
         if rc_block < 0 {
-            unsafe { sys::zpaq_decompresser_free(decompresser) };
             return Err(err_from_last());
         }
         if rc_block == 0 {
@@ -816,10 +1538,10 @@ fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<O
         loop {
             let filename_shared = SharedVecWriter::new();
             let filename_writer = FfiWriter::new(filename_shared.clone())?;
-            let rc_filename =
-                unsafe { sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw) };
+            let rc_filename = unsafe {
+                sys::zpaq_decompresser_find_filename(decompresser.raw, filename_writer.raw)
+            };
             if rc_filename < 0 {
-                unsafe { sys::zpaq_decompresser_free(decompresser) };
                 return Err(err_from_last());
             }
             if rc_filename == 0 {
@@ -827,14 +1549,11 @@ fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<O
             }
             drop(filename_writer);
 
-            let comment_writer = FfiWriter::new(SinkWriter)?;
             let rc_comment =
-                unsafe { sys::zpaq_decompresser_read_comment(decompresser, comment_writer.raw) };
+                unsafe { sys::zpaq_decompresser_read_comment(decompresser.raw, ptr::null_mut()) };
             if rc_comment != 0 {
-                unsafe { sys::zpaq_decompresser_free(decompresser) };
                 return Err(err_from_last());
             }
-            drop(comment_writer);
 
             let mut filename_bytes = filename_shared.bytes();
             while filename_bytes.last().copied() == Some(0) {
@@ -844,38 +1563,21 @@ fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<O
             let is_target = filename == path;
 
             let output_shared = SharedVecWriter::new();
-            let output_sink: Box<dyn Write + Send> = if is_target {
-                Box::new(output_shared.clone())
+            let output_writer = if is_target {
+                Some(FfiWriter::new(output_shared.clone())?)
             } else {
-                Box::new(SinkWriter)
+                None
             };
-            let output_writer = FfiWriter::new(output_sink)?;
-
-            let rc_set_out =
-                unsafe { sys::zpaq_decompresser_set_output(decompresser, output_writer.raw) };
-            if rc_set_out != 0 {
-                unsafe { sys::zpaq_decompresser_free(decompresser) };
-                return Err(err_from_last());
-            }
-
-            loop {
-                let rc_decompress =
-                    unsafe { sys::zpaq_decompresser_decompress(decompresser, 1 << 20) };
-                if rc_decompress < 0 {
-                    unsafe { sys::zpaq_decompresser_free(decompresser) };
-                    return Err(err_from_last());
-                }
-                if rc_decompress == 0 {
-                    break;
-                }
+            match &output_writer {
+                Some(output_writer) => verify::decompress_to(decompresser.raw, output_writer.raw)?,
+                None => blocks::skip_segment(decompresser.raw).map(|_| ())?,
             }
 
             let mut segment_end = [0u8; 21];
             let rc_end = unsafe {
-                sys::zpaq_decompresser_read_segment_end(decompresser, segment_end.as_mut_ptr())
+                sys::zpaq_decompresser_read_segment_end(decompresser.raw, segment_end.as_mut_ptr())
             };
             if rc_end != 0 {
-                unsafe { sys::zpaq_decompresser_free(decompresser) };
                 return Err(err_from_last());
             }
 
@@ -887,22 +1589,58 @@ fn archive_read_file_bytes_single_stream(archive: &[u8], path: &str) -> Result<O
         }
     }
 
-    unsafe { sys::zpaq_decompresser_free(decompresser) };
     Ok(found)
 }
 
-/// Reads the newest segment whose stored filename matches `path` from an
-/// archive byte slice.
+/// Reads the newest copy of the file stored as `path` from an archive byte
+/// slice: the last segment with that name in a streaming archive
+/// (concatenated streams, e.g. repeated appends, included), or the file in
+/// the latest version of a journaling archive written by `zpaq add`.
+///
+/// # Errors
 ///
-/// Supports concatenated ZPAQ streams (e.g. repeated append operations).
+/// Returns [`ZpaqError::NotFound`] if there is no such file, with
+/// `was_deleted` set if a version of a journaling archive deleted it.
 pub fn archive_read_file_bytes(archive: &[u8], path: &str) -> Result<Vec<u8>> {
-    const ZPAQ_TAG: [u8; 13] = [
-        0x37, 0x6b, 0x53, 0x74, 0xa0, 0x31, 0x83, 0xd3, 0x8c, 0xb2, 0x28, 0xb0, 0xd3,
-    ];
+    archive_read_file_bytes_at(archive, path, None)
+}
 
-    if archive.is_empty() {
+/// Like [`archive_read_file_bytes`], reading the file as it was after
+/// version `at_version` if given.  Versions are numbered from 1 as by
+/// [`archive_entries`]: transactions of a journaling archive, blocks (one
+/// per [`archive_append_entries`] call) of a streaming one.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{ArchiveEntry, archive_append_entries, archive_from_entries, archive_read_file_bytes_at};
+///
+/// let entry = |data| ArchiveEntry { path: "notes.txt", data, comment: None };
+/// let mut archive = archive_from_entries(&[entry(b"draft")], "1")?;
+/// archive_append_entries(&mut archive, &[entry(b"final")], "1")?;
+/// assert_eq!(archive_read_file_bytes_at(&archive, "notes.txt", Some(1))?, b"draft");
+/// assert_eq!(archive_read_file_bytes_at(&archive, "notes.txt", None)?, b"final");
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn archive_read_file_bytes_at(
+    archive: &[u8],
+    path: &str,
+    at_version: Option<usize>,
+) -> Result<Vec<u8>> {
+    if archive.is_empty() {
         return Err(ZpaqError::Ffi("archive is empty".into()));
     }
+    match format::sniff(&mut std::io::Cursor::new(archive))? {
+        Some(ArchiveFormat::Streaming) | None => {}
+        Some(ArchiveFormat::Journaling { .. }) => {
+            let mut reader = ArchiveReader::open(std::io::Cursor::new(archive))?;
+            return match at_version {
+                Some(version) => reader.read_at_version(path, version),
+                None => reader.read(path),
+            };
+        }
+        Some(found) => return Err(ZpaqError::WrongFormat { found }),
+    }
 
     let mut starts = Vec::new();
     for index in 0..=archive.len().saturating_sub(ZPAQ_TAG.len()) {
@@ -913,9 +1651,19 @@ pub fn archive_read_file_bytes(archive: &[u8], path: &str) -> Result<Vec<u8>> {
     if starts.is_empty() {
         return Err(ZpaqError::Ffi("no ZPAQ stream header found".into()));
     }
+    let versions = starts.len();
+    let blocks = match at_version {
+        Some(version) if version == 0 || version > versions => {
+            return Err(ZpaqError::Ffi(format!(
+                "version {version} not in archive (1..={versions})"
+            )));
+        }
+        Some(version) => version,
+        None => versions,
+    };
 
     let mut latest = None;
-    for (position, start) in starts.iter().copied().enumerate() {
+    for (position, start) in starts.iter().copied().enumerate().take(blocks) {
         let end = starts.get(position + 1).copied().unwrap_or(archive.len());
         if end <= start {
             continue;
@@ -925,7 +1673,10 @@ pub fn archive_read_file_bytes(archive: &[u8], path: &str) -> Result<Vec<u8>> {
         }
     }
 
-    latest.ok_or_else(|| ZpaqError::Ffi(format!("file path not found in archive: {path}")))
+    latest.ok_or_else(|| ZpaqError::NotFound {
+        path: path.to_string(),
+        was_deleted: false,
+    })
 }
 
 /// Reads bytes for `path` from an archive file.
@@ -935,6 +1686,160 @@ pub fn archive_read_file_bytes_from_file(archive_path: &str, path: &str) -> Resu
     archive_read_file_bytes(&archive, path)
 }
 
+/// Writes the file stored as `path` in an in-memory archive to `out` and
+/// returns its size, without holding the whole file in memory.
+///
+/// As with [`archive_read_file_bytes`], the newest copy wins: the latest
+/// version of a journaling archive, or the last segment named `path` of a
+/// streaming one.  Journaling archives are decoded one block at a time and
+/// only the blocks holding the file are decoded; see [`ArchiveReader`] to
+/// read several files.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::NotFound`] if no file is stored as `path`.
+pub fn archive_read_file_to(archive: &[u8], path: &str, out: impl Write + Send) -> Result<u64> {
+    ArchiveReader::open(std::io::Cursor::new(archive))?.read_to(path, out)
+}
+
+/// Like [`archive_read_file_to`], for the archive file at `archive_path`,
+/// which is read as needed rather than loaded.
+pub fn archive_read_file_to_from_file(
+    archive_path: &str,
+    path: &str,
+    out: impl Write + Send,
+) -> Result<u64> {
+    ArchiveReader::open_path(archive_path)?.read_to(path, out)
+}
+
+/// Like [`archive_read_file_to`], writing the file from byte `offset` on;
+/// see [`ArchiveReader::read_to_at_offset`], which also describes
+/// `progress`.
+pub fn archive_read_file_to_at_offset(
+    archive: &[u8],
+    path: &str,
+    offset: u64,
+    out: impl Write + Send,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<u64> {
+    ArchiveReader::open(std::io::Cursor::new(archive))?
+        .read_to_at_offset(path, offset, out, progress)
+}
+
+/// Like [`archive_read_file_to_at_offset`], for the archive file at
+/// `archive_path`, which is read as needed rather than loaded.
+pub fn archive_read_file_to_at_offset_from_file(
+    archive_path: &str,
+    path: &str,
+    offset: u64,
+    out: impl Write + Send,
+    progress: Option<&mut dyn FnMut(u64)>,
+) -> Result<u64> {
+    ArchiveReader::open_path(archive_path)?.read_to_at_offset(path, offset, out, progress)
+}
+
+/// One file listed by [`archive_list_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntryInfo {
+    /// Stored path.
+    pub path: String,
+    /// Uncompressed size in bytes.
+    pub size: u64,
+    /// Modification date as `YYYYMMDDHHMMSS` (UTC), for journaling archives.
+    pub date: Option<u64>,
+    /// Segment comment, for streaming archives (`None` if empty), as
+    /// stored.
+    pub comment: Option<String>,
+    /// Modification time and attributes, for journaling archives, and
+    /// extra metadata decoded from the comment, for streaming archives.
+    pub meta: EntryMeta,
+}
+
+/// Lists the files in an in-memory archive, sorted by path.
+///
+/// For journaling archives (written by `zpaq add`) these are the files of
+/// the latest version, read from the index without decompressing any data;
+/// directories are omitted.  For streaming archives (written by
+/// [`archive_from_entries`]) these are the named segments, each with the
+/// size of its newest copy, as [`archive_read_file_bytes`] would return it;
+/// listing them decompresses every segment.  See [`ArchiveReader`] to also
+/// read the files.
+pub fn archive_list_entries(archive: &[u8]) -> Result<Vec<ArchiveEntryInfo>> {
+    let reader = ArchiveReader::open(std::io::Cursor::new(archive))?;
+    Ok(reader.entries().cloned().collect())
+}
+
+/// One version of a file, as listed by [`archive_entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntryVersion {
+    /// Stored path, exactly as in the archive.
+    pub path: String,
+    /// Uncompressed size in bytes (0 for a deletion).
+    pub size: u64,
+    /// Modification time, for journaling archives.
+    pub mtime: Option<SystemTime>,
+    /// Version that stored this entry, numbered from 1.
+    pub version: u32,
+    /// Whether this entry records the file's deletion.
+    pub deleted: bool,
+    /// Extra metadata from the segment comment, for streaming archives
+    /// (see [`encode_extra_comment`]).
+    pub extra: BTreeMap<String, String>,
+}
+
+/// Lists the files of an in-memory archive, sorted by path and then
+/// version.
+///
+/// Without `include_history` these are the files of the latest version,
+/// each with the version that stored its current contents.  With it, every
+/// version of every file is listed, including ones superseded or deleted
+/// later.  For journaling archives the listing is read from the index, and
+/// versions are transactions as numbered by `zpaq list -all`.  Streaming
+/// archives have no versions as such; each block (one per
+/// [`archive_append_entries`] call) counts as one, and nothing is deleted.
+/// Directories are omitted, as are paths not selected by `filter` if given.
+pub fn archive_entries(
+    archive: &[u8],
+    filter: Option<&MemberFilter<'_>>,
+    include_history: bool,
+) -> Result<Vec<ArchiveEntryVersion>> {
+    ArchiveReader::open(std::io::Cursor::new(archive))?.entry_versions(filter, include_history)
+}
+
+/// Passes each file of an in-memory archive selected by `filter` to `sink`,
+/// with its contents, and returns how many there were.
+///
+/// Files are read as by [`archive_read_file_bytes`] (the newest copy wins)
+/// and passed in path order.  Each block is decoded at most once, however
+/// many of the selected files it holds.  See
+/// [`ArchiveReader::read_matching`].
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{ArchiveEntry, MemberFilter, archive_from_entries, archive_read_matching};
+///
+/// let entry = |path, data| ArchiveEntry { path, data, comment: None };
+/// let archive = archive_from_entries(
+///     &[entry("assets/a.png", b"a"), entry("src/main.rs", b"fn main() {}")],
+///     "1",
+/// )?;
+/// let mut names = Vec::new();
+/// archive_read_matching(&archive, &MemberFilter::Prefix("assets/"), |path, _| {
+///     names.push(path.to_string());
+///     Ok(())
+/// })?;
+/// assert_eq!(names, ["assets/a.png"]);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn archive_read_matching(
+    archive: &[u8],
+    filter: &MemberFilter<'_>,
+    sink: impl FnMut(&str, &[u8]) -> Result<()>,
+) -> Result<usize> {
+    ArchiveReader::open(std::io::Cursor::new(archive))?.read_matching(filter, sink)
+}
+
 // ---------------- Public API ----------------
 
 /// Compresses `input` into a `Vec<u8>` using the given ZPAQ method string.
@@ -973,6 +1878,14 @@ pub fn compress_size(input: &[u8], method: &str) -> Result<u64> {
     compress_size_stream(std::io::Cursor::new(input), method, None, None)
 }
 
+/// `threads` for the shim, which takes an `int`: counts past what the
+/// system can run at once become that many, so a large count neither wraps
+/// to one the shim reads as one thread nor starts threads that only contend.
+fn thread_count(threads: usize) -> c_int {
+    let cores = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+    c_int::try_from(threads.min(cores)).unwrap_or(c_int::MAX)
+}
+
 /// Returns the compressed size of `input` in bytes using multiple threads.
 ///
 /// Splits the input into ZPAQ blocks (based on the method's block size) and
@@ -997,17 +1910,23 @@ pub fn compress_size_stream<R: Read + Send>(
     filename: Option<&str>,
     comment: Option<&str>,
 ) -> Result<u64> {
-    clear_last_error();
-    let method_c = CString::new(method).map_err(|_| ZpaqError::NulInString)?;
-    let filename_c = match filename {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
-    };
-    let comment_c = match comment {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
-    };
-    let reader = FfiReader::new(reader)?;
+    compress_size_stream_as(reader, method, filename, comment, true)
+}
+
+/// [`compress_size_stream`], with or without SHA-1 segment trailers.
+pub(crate) fn compress_size_stream_as<R: Read + Send>(
+    reader: R,
+    method: &str,
+    filename: Option<&str>,
+    comment: Option<&str>,
+    dosha1: bool,
+) -> Result<u64> {
+    clear_last_error()?;
+    let _op = trace::Operation::compress("compress_size_stream", method, 1);
+    let method_c = method_cstring(method)?;
+    let filename_c = segment_field(filename.map(str::as_bytes))?;
+    let comment_c = segment_field(comment.map(str::as_bytes))?;
+    let reader = FfiReader::tuned(reader)?;
     let mut out_size: u64 = 0;
     let rc = unsafe {
         sys::zpaq_compress_size(
@@ -1021,7 +1940,7 @@ pub fn compress_size_stream<R: Read + Send>(
                 .as_ref()
                 .map(|c| c.as_ptr())
                 .unwrap_or(ptr::null()),
-            1,
+            c_int::from(dosha1),
             &mut out_size as *mut u64,
         )
     };
@@ -1036,7 +1955,9 @@ pub fn compress_size_stream<R: Read + Send>(
 /// threads.
 ///
 /// Splits the input into ZPAQ blocks and compresses them in parallel.  Falls
-/// back to the single-threaded path when `threads <= 1`.
+/// back to the single-threaded path when `threads <= 1`.  The block split is
+/// the same as the sequential path's, so the result does not depend on
+/// `threads` and equals the length of [`compress_stream_parallel`]'s output.
 pub fn compress_size_stream_parallel<R: Read + Send>(
     reader: R,
     method: &str,
@@ -1044,17 +1965,13 @@ pub fn compress_size_stream_parallel<R: Read + Send>(
     comment: Option<&str>,
     threads: usize,
 ) -> Result<u64> {
-    clear_last_error();
-    let method_c = CString::new(method).map_err(|_| ZpaqError::NulInString)?;
-    let filename_c = match filename {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
-    };
-    let comment_c = match comment {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
-    };
-    let reader = FfiReader::new(reader)?;
+    clear_last_error()?;
+    let _op = trace::Operation::compress("compress_size_stream_parallel", method, threads);
+    method_block_size(method)?;
+    let method_c = method_cstring(method)?;
+    let filename_c = segment_field(filename.map(str::as_bytes))?;
+    let comment_c = segment_field(comment.map(str::as_bytes))?;
+    let reader = FfiReader::tuned(reader)?;
     let mut out_size: u64 = 0;
     let rc = unsafe {
         sys::zpaq_compress_size_parallel(
@@ -1069,7 +1986,7 @@ pub fn compress_size_stream_parallel<R: Read + Send>(
                 .map(|c| c.as_ptr())
                 .unwrap_or(ptr::null()),
             1,
-            threads as i32,
+            thread_count(threads),
             &mut out_size as *mut u64,
         )
     };
@@ -1080,6 +1997,92 @@ pub fn compress_size_stream_parallel<R: Read + Send>(
     }
 }
 
+/// Uncompressed and compressed size of one block, as reported by
+/// [`compress_block_sizes`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockSizeReport {
+    /// Input bytes in the block.
+    pub uncompressed: u64,
+    /// Bytes the block occupies in the compressed stream, including its tag,
+    /// headers and SHA-1 trailer.
+    pub compressed: u64,
+}
+
+/// Compresses data from `reader` block by block and reports each block's
+/// size, in input order.
+///
+/// The input is cut into blocks of `block_size` bytes (the last may be
+/// shorter), at most the method's [`BlockSizeInfo::max_bytes`]; `None` uses
+/// the method's own block size, in which case the compressed sizes sum to
+/// what [`compress_size_stream_parallel`] returns for the same input and
+/// method.  Blocks are compressed on `threads` workers and at most
+/// `2 * threads` blocks are held in memory at once, so the input can be
+/// arbitrarily large.
+///
+/// Small block sizes turn this into a coarse profile of where the input is
+/// compressible.
+///
+/// # Example
+///
+/// ```rust
+/// let data = vec![0u8; 100_000];
+/// let blocks = zpaq_rs::compress_block_sizes(&data[..], "1", Some(40_000), 2).unwrap();
+/// let lens: Vec<u64> = blocks.iter().map(|b| b.uncompressed).collect();
+/// assert_eq!(lens, [40_000, 40_000, 20_000]);
+/// ```
+pub fn compress_block_sizes<R: Read + Send>(
+    reader: R,
+    method: &str,
+    block_size: Option<usize>,
+    threads: usize,
+) -> Result<Vec<BlockSizeReport>> {
+    clear_last_error()?;
+    let _op = trace::Operation::compress("compress_block_sizes", method, threads);
+    let method_c = method_cstring(method)?;
+    let info = method_block_size(method)?;
+    let block_size = match block_size {
+        None => 0,
+        Some(0) => return Err(ZpaqError::Ffi("block size must be at least 1".into())),
+        Some(n) if n as u64 <= info.max_bytes => n as i64,
+        Some(n) => {
+            return Err(ZpaqError::Ffi(format!(
+                "requested {} blocks but method '{}' caps at {}",
+                display::bytes(n as u64),
+                normalize_method(method),
+                display::bytes(info.max_bytes)
+            )));
+        }
+    };
+    let reader = FfiReader::tuned(reader)?;
+    let records = SharedVecWriter::new();
+    let writer = FfiWriter::new(records.clone())?;
+    let rc = unsafe {
+        sys::zpaq_compress_block_sizes(
+            reader.raw,
+            method_c.as_ptr(),
+            block_size,
+            thread_count(threads),
+            writer.raw,
+        )
+    };
+    drop(writer);
+    if rc != 0 {
+        return Err(err_from_last());
+    }
+    Ok(block_size_records(&records.bytes()))
+}
+
+/// Reads the 16-byte records the shim writes for each block's sizes.
+fn block_size_records(records: &[u8]) -> Vec<BlockSizeReport> {
+    records
+        .chunks_exact(16)
+        .map(|rec| BlockSizeReport {
+            uncompressed: u64::from_le_bytes(rec[..8].try_into().expect("8 bytes")),
+            compressed: u64::from_le_bytes(rec[8..].try_into().expect("8 bytes")),
+        })
+        .collect()
+}
+
 /// Returns the archive size (in bytes) that `zpaq add` would produce for a
 /// single file on disk.
 ///
@@ -1090,32 +2093,214 @@ pub fn compress_size_stream_parallel<R: Read + Send>(
 /// This is the correct metric to compare against
 /// `zpaq add my.arc <file>; du -b my.arc`.
 ///
-/// `path` must be a valid filesystem path to an existing file.  `threads`
-/// controls the number of parallel compression threads; `0` lets libzpaq
-/// choose.
+/// `path` is any path to an existing file, passed to `zpaq` as the
+/// platform's native string (see [`zpaq_command_os`]), so paths that are
+/// not valid Unicode work too.  `threads` controls the number of parallel
+/// compression threads; `0` lets libzpaq choose.
 ///
 /// # Errors
 ///
-/// Returns [`ZpaqError::Ffi`] if the file cannot be opened or if the JIDAC
-/// pipeline encounters an error.
-pub fn zpaq_add_archive_size_file(path: &str, method: &str, threads: usize) -> Result<u64> {
-    clear_last_error();
-    let path_c = CString::new(path).map_err(|_| ZpaqError::NulInString)?;
-    let method_c = CString::new(method).map_err(|_| ZpaqError::NulInString)?;
-    let mut out_size: u64 = 0;
-    let rc = unsafe {
-        sys::zpaq_jidac_add_archive_size_file(
-            path_c.as_ptr(),
-            method_c.as_ptr(),
-            threads as c_int,
-            &mut out_size as *mut u64,
-        )
+/// Returns [`ZpaqError::Io`] with the path, before running `zpaq`, if the
+/// file does not exist (`NotFound`), cannot be opened for reading
+/// (`PermissionDenied`) or is a directory (`IsADirectory`), and
+/// [`ZpaqError::Ffi`] if the JIDAC pipeline fails.
+pub fn zpaq_add_archive_size_file(
+    path: impl AsRef<Path>,
+    method: &str,
+    threads: usize,
+) -> Result<u64> {
+    let path = path.as_ref();
+    readable_file(path)?;
+    let method = normalize_method(method);
+    if method.is_empty() {
+        return Err(ZpaqError::Ffi("method string is empty".into()));
+    }
+    let threads = threads.to_string();
+    let output = zpaq_command_os(&[
+        OsStr::new("add"),
+        OsStr::new(""),
+        path.as_os_str(),
+        OsStr::new("-method"),
+        OsStr::new(&method),
+        OsStr::new("-threads"),
+        OsStr::new(&threads),
+    ])?;
+    added_bytes(&output)
+}
+
+/// Fails with [`ZpaqError::Io`] unless `path` is a file this process can
+/// open for reading.
+fn readable_file(path: &Path) -> Result<()> {
+    let io_error = |kind: std::io::ErrorKind, message: String| ZpaqError::Io {
+        path: path.to_path_buf(),
+        kind,
+        message,
     };
-    if rc == 0 {
-        Ok(out_size)
-    } else {
-        Err(err_from_last())
+    let metadata = std::fs::metadata(path).map_err(|e| io_error(e.kind(), e.to_string()))?;
+    if metadata.is_dir() {
+        return Err(io_error(
+            std::io::ErrorKind::IsADirectory,
+            "is a directory".into(),
+        ));
+    }
+    File::open(path).map_err(|e| io_error(e.kind(), e.to_string()))?;
+    Ok(())
+}
+
+/// Returns the archive size (in bytes) that `zpaq add` would produce for a
+/// single file stored as `name` holding `data`.
+///
+/// This is [`zpaq_add_archive_size_file`] for data already in memory: the
+/// same JIDAC pipeline, deduplication and index overhead, with the archive
+/// output discarded.  `zpaq.cpp` reads its inputs from disk, so `data` is
+/// written to a private scratch file (removed afterwards) that is added
+/// under `name` as by `zpaq add -to`.  The file is dated at the Unix epoch
+/// and, on Unix, has mode `0644`, so the result depends only on `data`,
+/// `name`, `method` and `threads` — and equals
+/// [`zpaq_add_archive_size_file`] for a file at `name` with that date and
+/// mode.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let x = zpaq_rs::zpaq_add_archive_size_bytes(b"some text", "x.txt", "1", 1)?;
+/// let xx = zpaq_rs::zpaq_add_archive_size_bytes(b"some textsome text", "x.txt", "1", 1)?;
+/// println!("{x} -> {xx} bytes");
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails if `name` is empty, if the scratch file cannot be written, or if
+/// the JIDAC pipeline fails.
+pub fn zpaq_add_archive_size_bytes(
+    data: &[u8],
+    name: &str,
+    method: &str,
+    threads: usize,
+) -> Result<u64> {
+    if name.is_empty() {
+        return Err(ZpaqError::Ffi("file name must not be empty".to_string()));
+    }
+    static SCRATCH: AtomicU64 = AtomicU64::new(0);
+    let scratch = std::env::temp_dir().join(format!(
+        "zpaq-rs-bytes-{}-{}",
+        std::process::id(),
+        SCRATCH.fetch_add(1, Ordering::Relaxed)
+    ));
+    let write = || -> std::io::Result<()> {
+        let file = std::fs::File::create(&scratch)?;
+        (&file).write_all(data)?;
+        file.set_modified(SystemTime::UNIX_EPOCH)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(std::fs::Permissions::from_mode(0o644))?;
+        }
+        Ok(())
+    };
+    let result = write()
+        .map_err(|e| ZpaqError::Ffi(format!("{}: {e}", scratch.display())))
+        .and_then(|()| {
+            let args = [
+                "add".to_string(),
+                String::new(),
+                scratch.to_string_lossy().into_owned(),
+                "-to".to_string(),
+                name.to_string(),
+                "-method".to_string(),
+                normalize_method(method),
+                "-threads".to_string(),
+                threads.to_string(),
+            ];
+            zpaq_command_inner(&args)
+        })
+        .and_then(|output| added_bytes(&output));
+    let _ = std::fs::remove_file(&scratch);
+    result
+}
+
+/// Returns how many bytes `zpaq add <existing_archive> <inputs...>` would
+/// append to the journaling archive `existing_archive`, without changing it.
+///
+/// The add pipeline runs with its output discarded against an index of the
+/// archive (made as by `zpaq extract -index`), so fragments already stored
+/// are deduplicated and files that have not changed are skipped exactly as
+/// a real add would.  The archive itself is only read.  An add that would
+/// change nothing predicts 0, since `zpaq` then leaves the archive as it is.
+/// If `existing_archive` does not exist, this is the size of the new
+/// archive.  For a multi-part `existing_archive` (see [`ArchiveSpec`]) it is
+/// the size of the part the add would create.
+///
+/// # Errors
+///
+/// Fails for encrypted or streaming archives and if the pipeline fails.
+pub fn zpaq_add_archive_delta_size(
+    existing_archive: &str,
+    inputs: &[&str],
+    method: &str,
+    threads: usize,
+) -> Result<u64> {
+    if inputs.is_empty() {
+        return Err(ZpaqError::Ffi(
+            "zpaq add requires at least one input path".to_string(),
+        ));
+    }
+    let mut args = vec!["add".to_string(), String::new()];
+    args.extend(inputs.iter().map(|input| input.to_string()));
+    args.extend([
+        "-method".to_string(),
+        normalize_method(method),
+        "-threads".to_string(),
+        threads.to_string(),
+    ]);
+    if ArchiveSpec::parse(existing_archive)?.parts().is_empty() {
+        return added_bytes(&zpaq_command_inner(&args)?);
+    }
+
+    static INDEXES: AtomicU64 = AtomicU64::new(0);
+    let index = std::env::temp_dir().join(format!(
+        "zpaq-rs-index-{}-{}.zpaq",
+        std::process::id(),
+        INDEXES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let index = index.to_string_lossy().into_owned();
+    args.extend(["-index".to_string(), index.clone()]);
+    let extract = [
+        "extract".to_string(),
+        existing_archive.to_string(),
+        "-index".to_string(),
+        index.clone(),
+    ];
+    let result = zpaq_command_inner(&extract)
+        .and_then(|_| zpaq_command_inner(&args))
+        .and_then(|output| added_bytes(&output));
+    let _ = std::fs::remove_file(&index);
+    result
+}
+
+/// Size of the transaction an add wrote, from its summary.
+fn added_bytes(output: &ZpaqCommandOutput) -> Result<u64> {
+    // An add that changes nothing is dropped from the archive (but not from
+    // an indexed one).
+    if output
+        .stdout
+        .lines()
+        .any(|line| line == "0 +added, 0 -removed.")
+    {
+        return Ok(0);
     }
+    // "<start> + (<input> -> <deduped> -> <added>) = <end> MB"
+    let added = output
+        .stderr
+        .lines()
+        .rev()
+        .find(|line| line.ends_with(" MB"))
+        .and_then(|line| line.rsplit_once(" -> "))
+        .and_then(|(_, rest)| rest.split_once(')'))
+        .and_then(|(mb, _)| mb.trim().parse::<f64>().ok())
+        .ok_or_else(|| ZpaqError::Ffi("failed to parse zpaq summary output".into()))?;
+    Ok((added * 1_000_000.0).round() as u64)
 }
 
 /// Runs an embedded `zpaq` command in-process and captures its output.
@@ -1135,140 +2320,1307 @@ pub fn zpaq_command(args: &[&str]) -> Result<ZpaqCommandOutput> {
     zpaq_command_inner(&owned)
 }
 
+/// Like [`zpaq_command`], with arguments as OS strings, so paths that are
+/// not valid Unicode reach `zpaq` unchanged: any bytes on Unix, and any
+/// UTF-16, unpaired surrogates included, on Windows.
+///
+/// On Windows, files whose full path exceeds the `MAX_PATH` limit are
+/// reached with the `\\?\` prefix, so long paths can be added, listed and
+/// extracted; the stored names do not include the prefix.  Names in
+/// [`ZpaqCommandOutput`] are decoded lossily.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use std::ffi::OsStr;
+/// use std::path::Path;
+///
+/// let data = Path::new("data");
+/// zpaq_rs::zpaq_command_os(&[OsStr::new("add"), OsStr::new("backup.zpaq"), data.as_os_str()])?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn zpaq_command_os(args: &[&OsStr]) -> Result<ZpaqCommandOutput> {
+    clear_last_error()?;
+    clear_last_output();
+    let _temp = temp::apply()?;
+
+    #[cfg(unix)]
+    let rc = {
+        use std::os::unix::ffi::OsStrExt;
+        let mut cargs = Vec::with_capacity(args.len() + 1);
+        cargs.push(CString::new("zpaq").map_err(|_| ZpaqError::NulInString)?);
+        for arg in args {
+            cargs.push(CString::new(arg.as_bytes()).map_err(|_| ZpaqError::NulInString)?);
+        }
+        let ptrs: Vec<*const c_char> = cargs.iter().map(|s| s.as_ptr()).collect();
+        unsafe { sys::zpaq_jidac_run(ptrs.len() as c_int, ptrs.as_ptr()) }
+    };
+    #[cfg(windows)]
+    let rc = {
+        use std::os::windows::ffi::OsStrExt;
+        let mut wargs = Vec::with_capacity(args.len() + 1);
+        for arg in std::iter::once(OsStr::new("zpaq")).chain(args.iter().copied()) {
+            let mut wide: Vec<u16> = arg.encode_wide().collect();
+            if wide.contains(&0) {
+                return Err(ZpaqError::NulInString);
+            }
+            wide.push(0);
+            wargs.push(wide);
+        }
+        let ptrs: Vec<*const u16> = wargs.iter().map(|s| s.as_ptr()).collect();
+        unsafe { sys::zpaq_jidac_run_wide(ptrs.len() as c_int, ptrs.as_ptr()) }
+    };
+    #[cfg(not(any(unix, windows)))]
+    let rc: c_int = {
+        let args = args
+            .iter()
+            .map(|arg| {
+                arg.to_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| ZpaqError::Ffi(format!("{arg:?} is not valid Unicode")))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        return zpaq_command_inner(&args);
+    };
+    command_output(rc)
+}
+
 /// Equivalent of `zpaq add <archive> <inputs...> -method <method> -threads <threads>`.
 ///
 /// This uses the real JIDAC engine from `zpaq.cpp`, so append semantics,
 /// deduplication, and archive metadata are fully interoperable with the `zpaq`
 /// binary.
+///
+/// `archive` may be a multi-part pattern such as `"backup???.zpaq"`, in which
+/// case the transaction is written to the next part (see [`ArchiveSpec`]).
+/// It fails without running `zpaq` if the pattern has no room for another
+/// part.
 pub fn zpaq_add(
     archive: &str,
     inputs: &[&str],
     method: &str,
     threads: usize,
 ) -> Result<ZpaqCommandOutput> {
-    if inputs.is_empty() {
-        return Err(ZpaqError::Ffi(
-            "zpaq add requires at least one input path".to_string(),
-        ));
-    }
-    let mut args = Vec::with_capacity(inputs.len() + 7);
-    args.push("add".to_string());
-    args.push(archive.to_string());
-    for input in inputs {
-        args.push((*input).to_string());
-    }
-    args.push("-method".to_string());
-    args.push(method.to_string());
-    args.push("-threads".to_string());
-    args.push(threads.to_string());
-    zpaq_command_inner(&args)
+    let options = ZpaqAddOptions {
+        method: method.to_string(),
+        threads,
+        ..Default::default()
+    };
+    zpaq_add_opts(archive, inputs, &options)
 }
 
-/// Equivalent of `zpaq extract <archive> [files...]`.
-pub fn zpaq_extract(archive: &str, files: &[&str]) -> Result<ZpaqCommandOutput> {
-    let mut args = Vec::with_capacity(files.len() + 2);
-    args.push("extract".to_string());
-    args.push(archive.to_string());
-    for file in files {
-        args.push((*file).to_string());
-    }
-    zpaq_command_inner(&args)
+/// Options for [`zpaq_add_opts`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ZpaqAddOptions {
+    /// Compression method (the `-method` argument); empty for `zpaq`'s
+    /// default, `1`.
+    pub method: String,
+    /// Compression threads (`-threads`); 0 for one per processor.
+    pub threads: usize,
+    /// Local index of a multi-part archive (`-index`).  The add reads this
+    /// instead of the archive, writes only the new part, and appends the new
+    /// version to the index, so earlier parts may live on remote storage.
+    /// See [`verify_index_matches_parts`].
+    pub index: Option<PathBuf>,
+    /// Settings for reproducible archives.  Its
+    /// [`version_date`](DeterministicOptions::version_date) also dates the
+    /// new version on its own, for example to keep the dates of backups
+    /// migrated from elsewhere.
+    pub deterministic: DeterministicOptions,
+    /// Modification times to store instead of the files' own, by path as
+    /// `zpaq` reaches it from `inputs` (`data/a.txt` for a file found under
+    /// the input `data`).  Directories may be listed too.  Extraction
+    /// restores these times.  Unix only.
+    pub mtime_overrides: BTreeMap<PathBuf, SystemTime>,
+    /// What to do with symbolic links found among or below `inputs`.
+    pub symlinks: SymlinkPolicy,
+    /// Locking against concurrent writers.
+    pub lock: ArchiveLock,
+    /// Flushing and atomic replacement of what the add writes.
+    pub durability: DurabilityOptions,
+    /// After the add, decode the new version and check its fragments and
+    /// tables as [`verify_archive_file`] does in deep mode, without
+    /// decoding the earlier versions, and check that each file it stores
+    /// has the size of the file on disk (which catches data altered as it
+    /// was read, such as line endings translated by a text-mode read).
+    /// Cannot be combined with an index, since the earlier parts may not be
+    /// at hand.
+    pub verify_after_add: bool,
+    /// Limit on how fast `zpaq` reads the inputs (and the archive's index),
+    /// for backups that must not starve the host.  Compression threads keep
+    /// working while reading waits.  Unix only.
+    pub rate_limit: Option<RateLimit>,
+    /// Called as `zpaq` reads the inputs (and the archive's index), with
+    /// the inputs' total size unless the callback sets a total.  Unchanged
+    /// files are not read again, so the count can end short of it.  Unix
+    /// only.
+    pub progress: Option<ProgressCallback>,
+    /// Directory for the command's temporary files, instead of the one
+    /// [`set_temp_dir`] chose.
+    pub temp_dir: Option<PathBuf>,
 }
 
-/// Equivalent of `zpaq list <archive> [files...]`.
-pub fn zpaq_list(archive: &str, files: &[&str]) -> Result<ZpaqCommandOutput> {
-    let mut args = Vec::with_capacity(files.len() + 2);
-    args.push("list".to_string());
-    args.push(archive.to_string());
-    for file in files {
-        args.push((*file).to_string());
+/// Result of [`zpaq_add_report`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AddReport {
+    /// Captured output of `zpaq add`.
+    pub output: ZpaqCommandOutput,
+    /// Whether the new version was verified and found intact (always
+    /// `false` without [`ZpaqAddOptions::verify_after_add`]).
+    pub verified: bool,
+    /// Problems verification found in the new version.
+    pub mismatches: Vec<VerifyError>,
+}
+
+/// The output of `zpaq add`, then what verification found, if it ran.
+impl std::fmt::Display for AddReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.output)?;
+        if self.verified {
+            writeln!(f, "verified")?;
+        } else if !self.mismatches.is_empty() {
+            writeln!(f, "verification failed:")?;
+            for mismatch in &self.mismatches {
+                display::indented(f, "  ", &mismatch.to_string())?;
+            }
+        }
+        Ok(())
     }
-    zpaq_command_inner(&args)
 }
 
-/// Decompresses a complete ZPAQ stream held in `input` and returns the
-/// original data as a `Vec<u8>`.
+/// How [`zpaq_add_opts`] guards the archive against a crash or power cut
+/// part-way through an add.
 ///
-/// This is a convenience wrapper around [`decompress_stream`] that owns both
-/// the input and output.  For large data, prefer [`decompress_stream`] to
-/// avoid double-buffering.
+/// `zpaq` itself only appends: a crash can leave a torn transaction at the
+/// end of the archive, which the next add cuts off but which other readers
+/// may trip over.  With `atomic`, readers see either the old archive or the
+/// new one:
 ///
-/// # Example
+/// * A single-file archive is copied to `<archive>.zpaq-rs-tmp` beside it,
+///   the add runs on the copy, and the copy is flushed and renamed over the
+///   archive.  This costs a copy of the whole archive.
+/// * With [`ZpaqAddOptions::index`], the new part is written in place and
+///   flushed before the updated index, prepared the same way as
+///   `<index>.zpaq-rs-tmp`, is renamed over the old one.  Until then the
+///   index does not mention the part, so a torn part is simply rewritten by
+///   the next add.
+/// * A multi-part archive without an index is refused, since its new part
+///   cannot be written under another name.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DurabilityOptions {
+    /// Flush the files written (the archive or new part, and the index) and,
+    /// on Unix, their directories to disk before returning.
+    pub fsync: bool,
+    /// Replace files atomically as described above; implies `fsync`.
+    pub atomic: bool,
+}
+
+/// Whether [`zpaq_add_opts`] locks the archive while it appends to it.
 ///
-/// ```rust
-/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "1").unwrap();
-/// let d = zpaq_rs::decompress_to_vec(&c).unwrap();
-/// assert_eq!(d, b"hello zpaq");
-/// ```
-pub fn decompress_to_vec(input: &[u8]) -> Result<Vec<u8>> {
-    let cursor = std::io::Cursor::new(input);
-    let mut out = Vec::new();
-    decompress_stream(cursor, &mut out)?;
-    Ok(out)
+/// The lock is advisory (`flock` on Unix) and taken on the archive, which
+/// is created empty if it does not exist yet, so adds and
+/// [`ArchiveWriter::append`] in this and other processes take turns instead
+/// of interleaving their transactions, first adds included.  The first part
+/// of a multi-part archive, or the index with [`ZpaqAddOptions::index`], is
+/// locked through a `<path>.lock` file next to it instead, which is removed
+/// when the add finishes.  On Windows `zpaq` opens the archive without
+/// sharing, so a concurrent writer fails with [`ZpaqError::ArchiveBusy`] and
+/// no lock is taken.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArchiveLock {
+    /// Wait for other writers to finish.
+    #[default]
+    Wait,
+    /// Fail with [`ZpaqError::ArchiveBusy`] if another writer holds the
+    /// lock.
+    FailIfBusy,
+    /// Take no lock.
+    Off,
 }
 
-/// Returns the decompressed size of the ZPAQ stream in `input` without
-/// materialising the output.
+/// How [`zpaq_add_opts`] treats symbolic links.
 ///
-/// Wrapper around [`decompress_size_stream`] with a [`std::io::Cursor`].
-pub fn decompress_size(input: &[u8]) -> Result<u64> {
-    decompress_size_stream(std::io::Cursor::new(input))
+/// Only [`Skip`](Self::Skip) is available on Windows, where `zpaq` reads
+/// no links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Leave links out, as `zpaq` does.
+    #[default]
+    Skip,
+    /// Store each link as a file holding its target, exactly as
+    /// `readlink` returns it (relative, absolute or dangling), with
+    /// `S_IFLNK` in its stored mode.  [`zpaq_extract_opts`] turns these back
+    /// into links; `zpaq extract` writes them as files holding the target.
+    Store,
+    /// Store what each link points to under the link's path.  Dangling
+    /// links, and links to a directory already being scanned, are skipped.
+    Follow,
 }
 
-/// Returns the decompressed size of the ZPAQ stream from `reader` without
-/// materialising output.
+/// Settings for [`zpaq_extract_opts`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractOptions {
+    /// What to do with links stored by [`SymlinkPolicy::Store`].
+    pub symlinks: ExtractSymlinkPolicy,
+    /// Called as `zpaq` writes the extracted files, from its worker
+    /// threads.  Set a total on the callback for an estimate of the time
+    /// remaining.  Unix only.
+    pub progress: Option<ProgressCallback>,
+    /// Directory for the command's temporary files, instead of the one
+    /// [`set_temp_dir`] chose.
+    pub temp_dir: Option<PathBuf>,
+}
+
+/// How [`zpaq_extract_opts`] treats stored symbolic links.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExtractSymlinkPolicy {
+    /// Recreate the link with its stored target and date.  Unix only; on
+    /// Windows no links are stored to restore.
+    #[default]
+    Restore,
+    /// Extract nothing for the link, and note it in the command's stderr as
+    /// `Skipping symbolic link <path> -> <target>`.
+    SkipWithWarning,
+}
+
+/// Settings that make a new version reproducible, so that adding the same
+/// content twice gives bit-identical archives.  Set all three for that;
+/// each is off by default.  Used by [`zpaq_add_opts`] and
+/// [`archive_from_owned_entries_with`].
 ///
-/// Uses a C++-side counting writer so no allocation is needed for the
-/// decompressed bytes.
-pub fn decompress_size_stream<R: Read + Send>(reader: R) -> Result<u64> {
-    clear_last_error();
-    let reader = FfiReader::new(reader)?;
-    let mut out_size: u64 = 0;
-    let rc = unsafe { sys::zpaq_decompress_size(reader.raw, &mut out_size as *mut u64) };
-    if rc == 0 {
-        Ok(out_size)
-    } else {
-        Err(err_from_last())
+/// Extraction restores what was stored: with [`zero_mtimes`], files come
+/// back dated at the Unix epoch with default permissions.
+///
+/// [`zero_mtimes`]: Self::zero_mtimes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeterministicOptions {
+    /// Date of the new version instead of the current time, as `zpaq list`
+    /// shows it.  It must be later than the archive's last version
+    /// (appending an earlier one would make `zpaq` roll the archive back)
+    /// and cannot be combined with an index.
+    pub version_date: Option<SystemTime>,
+    /// Store every file as last modified at the Unix epoch, and without
+    /// attributes (`-noattributes`).  Dates of journaling entries are only
+    /// second-accurate, so this is what makes checkouts of the same tree
+    /// match.  Unix only for [`zpaq_add_opts`].
+    pub zero_mtimes: bool,
+    /// Take inputs in path order, whatever order they were given in.
+    pub sort_inputs: bool,
+}
+
+impl DeterministicOptions {
+    /// All normalization on, with the version dated `version_date`.
+    pub fn pinned(version_date: SystemTime) -> Self {
+        DeterministicOptions {
+            version_date: Some(version_date),
+            zero_mtimes: true,
+            sort_inputs: true,
+        }
     }
 }
 
-/// Compresses data from `reader` and writes the ZPAQ archive to `writer`.
+/// Like [`zpaq_add`], with [`ZpaqAddOptions`].
 ///
-/// `method` is the ZPAQ method string (e.g. `"1"`, `"x4.3ci1"`).
-/// `filename` and `comment` are optional segment metadata; pass `None` for
-/// both in typical usage.
+/// With [`ZpaqAddOptions::index`], `archive` must be a multi-part pattern
+/// (see [`ArchiveSpec`]) and the new part is numbered after the versions in
+/// the index, whether or not earlier parts are present.
 ///
 /// # Example
 ///
-/// ```rust
-/// use std::io::Cursor;
-/// let mut out = Vec::new();
-/// zpaq_rs::compress_stream(Cursor::new(b"hello"), &mut out, "1", None, None).unwrap();
-/// assert!(!out.is_empty());
+/// ```rust,no_run
+/// use std::time::{Duration, UNIX_EPOCH};
+/// use zpaq_rs::{DeterministicOptions, ZpaqAddOptions};
+///
+/// let release = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+/// let options = ZpaqAddOptions {
+///     method: "3".into(),
+///     deterministic: DeterministicOptions::pinned(release),
+///     ..Default::default()
+/// };
+/// zpaq_rs::zpaq_add_opts("dist.zpaq", &["build/out"], &options)?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails without running `zpaq` if `inputs` is empty, the pattern has no
+/// room for another part, or the [`DeterministicOptions`] or
+/// [`DurabilityOptions`] cannot be honoured, and if `zpaq` fails (for example because the part it would
+/// write already exists).  With [`ZpaqAddOptions::verify_after_add`], fails
+/// with [`ZpaqError::ChecksumMismatch`] if the new version is damaged; see
+/// [`zpaq_add_report`] for what was found.
+pub fn zpaq_add_opts(
+    archive: &str,
+    inputs: &[&str],
+    options: &ZpaqAddOptions,
+) -> Result<ZpaqCommandOutput> {
+    let report = zpaq_add_report(archive, inputs, options)?;
+    if !report.mismatches.is_empty() {
+        return Err(ZpaqError::ChecksumMismatch);
+    }
+    Ok(report.output)
+}
+
+/// Like [`zpaq_add_opts`], returning an [`AddReport`].  Problems
+/// [`ZpaqAddOptions::verify_after_add`] finds are listed in the report
+/// rather than failing the call; the new version stays in the archive.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::{ZpaqAddOptions, zpaq_add_report};
+///
+/// let options = ZpaqAddOptions { verify_after_add: true, ..Default::default() };
+/// let report = zpaq_add_report("backup.zpaq", &["data"], &options)?;
+/// for mismatch in &report.mismatches {
+///     eprintln!("{mismatch}");
+/// }
+/// assert!(report.verified);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn zpaq_add_report(
+    archive: &str,
+    inputs: &[&str],
+    options: &ZpaqAddOptions,
+) -> Result<AddReport> {
+    if inputs.is_empty() {
+        return Err(ZpaqError::Ffi(
+            "zpaq add requires at least one input path".to_string(),
+        ));
+    }
+    if options.verify_after_add && options.index.is_some() {
+        return Err(ZpaqError::Ffi(
+            "verify_after_add cannot be combined with an index".into(),
+        ));
+    }
+    let lock = lock_archive(archive, options)?;
+    let locked = lock.as_ref().map(|lock| &lock.file);
+    let _op = trace::Operation::add(archive, inputs, &options.method, options.threads);
+    let before = if options.verify_after_add {
+        Some(archive_end(archive, locked)?)
+    } else {
+        None
+    };
+    let output = temp::with_temp_dir(options.temp_dir.as_deref(), || {
+        add_locked(archive, inputs, options)
+    })
+    .inspect_err(|_| {
+        if let Some(lock) = &lock {
+            lock.discard_empty();
+        }
+    })?;
+    let Some((versions, end)) = before else {
+        return Ok(AddReport {
+            output,
+            ..Default::default()
+        });
+    };
+    #[cfg(test)]
+    if let Some(hook) = BEFORE_VERIFY.get() {
+        hook(archive);
+    }
+    let reader = reopen_locked(archive, locked)?;
+    let mismatches = verify::verify_versions_after(std::io::BufReader::new(reader), versions, end)?;
+    Ok(AddReport {
+        output,
+        verified: mismatches.is_empty(),
+        mismatches,
+    })
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Called with the archive between an add and its verification.
+    static BEFORE_VERIFY: std::cell::Cell<Option<fn(&str)>> = const { std::cell::Cell::new(None) };
+}
+
+/// Number of versions of `archive` and the offset where the next begins
+/// (`(0, 0)` if it does not exist yet).
+fn archive_end(archive: &str, lock: Option<&File>) -> Result<(usize, u64)> {
+    if archive.is_empty() || ArchiveSpec::parse(archive)?.parts().is_empty() {
+        return Ok((0, 0));
+    }
+    let reader = reopen_locked(archive, lock)?;
+    let len = reader.len();
+    let mut source = verify::ArchiveSource::new(std::io::BufReader::new(reader), None)?;
+    let mut scan = verify::Scan::default();
+    scan.scan(&mut source)?;
+    Ok((scan.versions(), scan.incomplete.unwrap_or(len)))
+}
+
+/// Opens `archive` for reading, through a clone of the locked handle on
+/// its first part while that still names it.
+fn reopen_locked(archive: &str, lock: Option<&File>) -> Result<MultiPartReader> {
+    let spec = ArchiveSpec::parse(archive)?;
+    let first = match lock {
+        Some(file) if still_at(file, &spec.part(1)?) => Some(
+            file.try_clone()
+                .map_err(|e| ZpaqError::Ffi(format!("{archive}: {e}")))?,
+        ),
+        _ => None,
+    };
+    spec.open_with_first(first)
+}
+
+/// Runs the add of [`zpaq_add_opts`], with the archive locked.
+fn add_locked(
+    archive: &str,
+    inputs: &[&str],
+    options: &ZpaqAddOptions,
+) -> Result<ZpaqCommandOutput> {
+    let deterministic = &options.deterministic;
+    let mut inputs = inputs.to_vec();
+    if deterministic.sort_inputs {
+        inputs.sort_unstable();
+        inputs.dedup();
+    }
+    let mut args = vec!["add".to_string(), archive.to_string()];
+    args.extend(inputs.iter().map(|input| input.to_string()));
+    let method = normalize_method(&options.method);
+    if !method.is_empty() {
+        args.extend(["-method".to_string(), method]);
+    }
+    args.extend(["-threads".to_string(), options.threads.to_string()]);
+    if let Some(version_date) = deterministic.version_date {
+        if options.index.is_some() {
+            return Err(ZpaqError::Ffi(
+                "a version date cannot be set when adding with an index".into(),
+            ));
+        }
+        let date = decimal_time_of(version_date)?;
+        if last_version_date(archive)?.is_some_and(|last| last >= date) {
+            return Err(ZpaqError::Ffi(format!(
+                "{archive}: version date {date} is not after the last version"
+            )));
+        }
+        args.extend(["-until".to_string(), date.to_string()]);
+    }
+    if deterministic.zero_mtimes {
+        if !cfg!(unix) {
+            return Err(ZpaqError::Ffi(
+                "zero_mtimes is only supported on Unix".into(),
+            ));
+        }
+        args.push("-noattributes".to_string());
+    }
+    // The file `zpaq` appends to or creates, and the index it updates.
+    let (written, index) = match &options.index {
+        Some(index) => {
+            let spec = ArchiveSpec::parse(archive)?;
+            if !spec.is_multi_part() {
+                return Err(ZpaqError::Ffi(format!(
+                    "{archive}: -index needs a multi-part archive pattern"
+                )));
+            }
+            let index = index.to_string_lossy().into_owned();
+            let versions = if std::path::Path::new(&index).exists() {
+                index_versions(&index)?
+            } else {
+                0
+            };
+            (Some(spec.part(versions as u64 + 1)?), Some(index))
+        }
+        None if !archive.is_empty() => (Some(ArchiveSpec::parse(archive)?.next_part()?), None),
+        None => (None, None),
+    };
+    if !options.mtime_overrides.is_empty() && !cfg!(unix) {
+        return Err(ZpaqError::Ffi(
+            "mtime_overrides is only supported on Unix".into(),
+        ));
+    }
+    let mut overrides = Vec::with_capacity(options.mtime_overrides.len());
+    for (path, mtime) in &options.mtime_overrides {
+        let name = path.to_string_lossy();
+        let secs = mtime
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_err(|_| ZpaqError::Ffi(format!("{name}: mtime before 1970")))?
+            .as_secs();
+        let name = CString::new(name.trim_end_matches('/')).map_err(|_| ZpaqError::NulInString)?;
+        overrides.push((name, secs as i64));
+    }
+    let links = match options.symlinks {
+        SymlinkPolicy::Skip => LINKS_SKIP,
+        _ if !cfg!(unix) => {
+            return Err(ZpaqError::Ffi(
+                "symbolic links can only be stored or followed on Unix".into(),
+            ));
+        }
+        SymlinkPolicy::Store => LINKS_STORE,
+        SymlinkPolicy::Follow => LINKS_FOLLOW,
+    };
+    let throttle = match options.rate_limit {
+        Some(_) if !cfg!(unix) => {
+            return Err(ZpaqError::Ffi(
+                "rate_limit is only supported on Unix".into(),
+            ));
+        }
+        Some(limit) => Some(Throttle::new(limit)?),
+        None => None,
+    };
+    let progress = match &options.progress {
+        Some(_) if !cfg!(unix) => {
+            return Err(ZpaqError::Ffi("progress is only supported on Unix".into()));
+        }
+        Some(callback) => {
+            let total = inputs
+                .iter()
+                .map(|input| input_size(std::path::Path::new(input)))
+                .sum();
+            Some(Mutex::new(Meter::new(callback.total_or(total))))
+        }
+        None => None,
+    };
+    let reads =
+        (throttle.is_some() || progress.is_some()).then_some(AddReads { throttle, progress });
+    let run = |args: &[String]| {
+        if !deterministic.zero_mtimes
+            && overrides.is_empty()
+            && links == LINKS_SKIP
+            && reads.is_none()
+        {
+            return zpaq_command_inner(args);
+        }
+        unsafe {
+            if deterministic.zero_mtimes {
+                sys::zpaq_jidac_set_fixed_mtime(0);
+            }
+            for (name, secs) in &overrides {
+                sys::zpaq_jidac_override_mtime(name.as_ptr(), *secs);
+            }
+            sys::zpaq_jidac_set_symlink_policy(links, LINKS_AS_FILES);
+            if let Some(reads) = &reads {
+                let ctx = reads as *const AddReads as *mut std::os::raw::c_void;
+                sys::zpaq_jidac_set_read_throttle(ctx, Some(add_read));
+            }
+        }
+        let result = zpaq_command_inner(args);
+        if let Some(progress) = reads.as_ref().and_then(|reads| reads.progress.as_ref()) {
+            progress
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .finish();
+        }
+        unsafe {
+            sys::zpaq_jidac_set_fixed_mtime(-1);
+            sys::zpaq_jidac_clear_mtime_overrides();
+            sys::zpaq_jidac_set_symlink_policy(LINKS_SKIP, LINKS_AS_FILES);
+            sys::zpaq_jidac_set_read_throttle(ptr::null_mut(), None);
+        }
+        result
+    };
+
+    let durability = options.durability;
+    let Some(written) = written.filter(|_| durability.fsync || durability.atomic) else {
+        if let Some(index) = index {
+            args.extend(["-index".to_string(), index]);
+        }
+        return run(&args);
+    };
+    if !durability.atomic {
+        if let Some(index) = &index {
+            args.extend(["-index".to_string(), index.clone()]);
+        }
+        let output = run(&args)?;
+        sync_file(&written)?;
+        if let Some(index) = &index {
+            sync_file(index)?;
+        }
+        return Ok(output);
+    }
+    // The file replaced by renaming: the index, or the archive itself.
+    let target = match index {
+        Some(index) => index,
+        None if ArchiveSpec::parse(archive)?.is_multi_part() => {
+            return Err(ZpaqError::Ffi(format!(
+                "{archive}: atomic updates of a multi-part archive need an index"
+            )));
+        }
+        None => written.clone(),
+    };
+    let temp = format!("{target}.zpaq-rs-tmp");
+    let prepared = match std::fs::copy(&target, &temp) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            std::fs::remove_file(&temp).or_else(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(e),
+            })
+        }
+        Err(e) => Err(e),
+    };
+    prepared.map_err(|e| ZpaqError::Ffi(format!("{temp}: {e}")))?;
+    if options.index.is_some() {
+        args.extend(["-index".to_string(), temp.clone()]);
+    } else {
+        args[1] = temp.clone();
+    }
+    let result = run(&args).and_then(|output| {
+        if options.index.is_some() {
+            sync_file(&written)?;
+        }
+        sync_file(&temp)?;
+        std::fs::rename(&temp, &target).map_err(|e| ZpaqError::Ffi(format!("{target}: {e}")))?;
+        sync_parent(&target)?;
+        Ok(output)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    result
+}
+
+/// What an add does as `zpaq` reads: [`ZpaqAddOptions::rate_limit`] and
+/// [`ZpaqAddOptions::progress`].
+struct AddReads {
+    throttle: Option<Throttle>,
+    progress: Option<Mutex<Meter>>,
+}
+
+/// Read hook of an add: `ctx` is its [`AddReads`].
+unsafe extern "C" fn add_read(ctx: *mut std::os::raw::c_void, bytes: u64) {
+    let reads = unsafe { &*(ctx as *const AddReads) };
+    if let Some(throttle) = &reads.throttle {
+        throttle.consume(bytes);
+    }
+    if let Some(progress) = &reads.progress {
+        progress
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .advance(bytes);
+    }
+}
+
+/// Write hook of [`ExtractOptions::progress`]: `ctx` is the extraction's
+/// meter.
+unsafe extern "C" fn extract_write(ctx: *mut std::os::raw::c_void, bytes: u64) {
+    let progress = unsafe { &*(ctx as *const Mutex<Meter>) };
+    progress
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .advance(bytes);
+}
+
+/// Size of the regular files at or below `path`, not following links.
+fn input_size(path: &std::path::Path) -> u64 {
+    let Ok(meta) = std::fs::symlink_metadata(path) else {
+        return 0;
+    };
+    if !meta.is_dir() {
+        return if meta.is_file() { meta.len() } else { 0 };
+    }
+    std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .map(|entry| input_size(&entry.path()))
+                .sum()
+        })
+        .unwrap_or(0)
+}
+
+/// Flushes `path` and, on Unix, the directory entry naming it.
+fn sync_file(path: &str) -> Result<()> {
+    File::open(path)
+        .and_then(|file| file.sync_all())
+        .map_err(|e| ZpaqError::Ffi(format!("{path}: sync failed: {e}")))?;
+    sync_parent(path)
+}
+
+/// Flushes the directory holding `path`, on Unix only: Windows cannot open
+/// a directory as a file.
+fn sync_parent(path: &str) -> Result<()> {
+    if !cfg!(unix) {
+        return Ok(());
+    }
+    let parent = match std::path::Path::new(path).parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => std::path::Path::new("."),
+    };
+    File::open(parent)
+        .and_then(|dir| dir.sync_all())
+        .map_err(|e| ZpaqError::Ffi(format!("{}: sync failed: {e}", parent.display())))
+}
+
+/// The lock [`lock_archive`] holds for an add.
+struct AddLock {
+    file: File,
+    /// The archive, if it was created empty to be locked.
+    created: Option<String>,
+    /// The `<path>.lock` file locked instead of the archive, if any.
+    lock_path: Option<String>,
+}
+
+impl AddLock {
+    /// Removes the archive created to be locked if the add left it empty,
+    /// as a failed first add leaves no archive behind.
+    fn discard_empty(&self) {
+        if let Some(path) = &self.created
+            && self.file.metadata().is_ok_and(|meta| meta.len() == 0)
+            && still_at(&self.file, path)
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Removes the `<path>.lock` file while still holding its lock.  A writer
+/// that opened it meanwhile finds it gone once it gets the lock and opens
+/// the path again (see [`lock_archive`]).
+impl Drop for AddLock {
+    fn drop(&mut self) {
+        if let Some(path) = &self.lock_path
+            && still_at(&self.file, path)
+        {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Takes the [`ArchiveLock`] of `options` for an add to `archive`,
+/// returning the lock.
+///
+/// A single-file archive is locked itself, and created empty first if it
+/// does not exist yet (`zpaq` adds to an empty file as to a new archive), so
+/// first adds take turns too.  The first part of a multi-part archive or the
+/// index cannot be created ahead of `zpaq`, which would take it for a part
+/// or an index already written, so those are locked through a
+/// `<path>.lock` file next to them, removed again when the lock is dropped.
+fn lock_archive(archive: &str, options: &ZpaqAddOptions) -> Result<Option<AddLock>> {
+    if !cfg!(unix) || options.lock == ArchiveLock::Off || archive.is_empty() {
+        return Ok(None);
+    }
+    let (path, lock_path) = match &options.index {
+        Some(index) => {
+            let index = index.to_string_lossy().into_owned();
+            let lock_path = format!("{index}.lock");
+            (index, Some(lock_path))
+        }
+        None => {
+            let spec = ArchiveSpec::parse(archive)?;
+            if spec.is_multi_part() {
+                let first = spec.part(1)?;
+                let lock_path = format!("{first}.lock");
+                (first, Some(lock_path))
+            } else {
+                (archive.to_string(), None)
+            }
+        }
+    };
+    let target = lock_path.as_deref().unwrap_or(&path);
+    loop {
+        let existed = std::path::Path::new(target).exists();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(target)
+            .map_err(|e| ZpaqError::Ffi(format!("{target}: {e}")))?;
+        match options.lock {
+            ArchiveLock::FailIfBusy => match file.try_lock() {
+                Ok(()) => {}
+                Err(std::fs::TryLockError::WouldBlock) => {
+                    return Err(ZpaqError::ArchiveBusy { path });
+                }
+                Err(std::fs::TryLockError::Error(e)) => {
+                    return Err(ZpaqError::Ffi(format!("{target}: lock failed: {e}")));
+                }
+            },
+            _ => file
+                .lock()
+                .map_err(|e| ZpaqError::Ffi(format!("{target}: lock failed: {e}")))?,
+        }
+        // A writer with DurabilityOptions::atomic may have renamed a new
+        // file over the one locked while waiting, or a failed first add
+        // removed it.
+        if still_at(&file, target) {
+            let created = (!existed && lock_path.is_none()).then(|| path.clone());
+            return Ok(Some(AddLock {
+                file,
+                created,
+                lock_path,
+            }));
+        }
+    }
+}
+
+/// `true` if `path` still names the open `file`.
+#[cfg(unix)]
+fn still_at(file: &File, path: &str) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(named)) => (open.dev(), open.ino()) == (named.dev(), named.ino()),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn still_at(_file: &File, _path: &str) -> bool {
+    true
+}
+
+// Link policies for `zpaq_jidac_set_symlink_policy`; `zpaq` alone skips
+// links when adding and extracts stored ones as files.
+const LINKS_SKIP: c_int = 0;
+const LINKS_STORE: c_int = 1;
+const LINKS_FOLLOW: c_int = 2;
+const LINKS_AS_FILES: c_int = -1;
+const LINKS_RESTORE: c_int = 0;
+const LINKS_SKIP_WITH_WARNING: c_int = 1;
+
+/// `t` as a `YYYYMMDDHHMMSS` date.
+pub(crate) fn decimal_time_of(t: SystemTime) -> Result<u64> {
+    let secs = t
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|_| ZpaqError::Ffi("version date before 1970".into()))?
+        .as_secs();
+    Ok(diff::decimal_time(secs))
+}
+
+/// Date of the last version of `archive`, or `None` if it does not exist.
+fn last_version_date(archive: &str) -> Result<Option<u64>> {
+    if archive.is_empty() {
+        return Ok(None);
+    }
+    let spec = ArchiveSpec::parse(archive)?;
+    if spec.parts().is_empty() {
+        return Ok(None);
+    }
+    let reader = std::io::BufReader::new(spec.open()?);
+    let mut source = verify::ArchiveSource::new(reader, None)?;
+    let mut scan = verify::Scan::default();
+    scan.scan(&mut source)?;
+    Ok(scan.last_date())
+}
+
+/// Equivalent of `zpaq extract <archive> [files...]`.  Like `zpaq`, this
+/// reads all parts of a multi-part `archive`.
+pub fn zpaq_extract(archive: &str, files: &[&str]) -> Result<ZpaqCommandOutput> {
+    let mut args = Vec::with_capacity(files.len() + 2);
+    args.push("extract".to_string());
+    args.push(archive.to_string());
+    for file in files {
+        args.push((*file).to_string());
+    }
+    zpaq_command_inner(&args)
+}
+
+/// Like [`zpaq_extract`], treating links stored by [`SymlinkPolicy::Store`]
+/// as `options` says rather than writing them as files, and reporting
+/// progress to [`ExtractOptions::progress`].
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::{ExtractOptions, ExtractSymlinkPolicy, zpaq_extract_opts};
+///
+/// let options = ExtractOptions {
+///     symlinks: ExtractSymlinkPolicy::SkipWithWarning,
+///     ..Default::default()
+/// };
+/// let output = zpaq_extract_opts("home.zpaq", &["-to", "restore"], &options)?;
+/// eprint!("{}", output.stderr);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn zpaq_extract_opts(
+    archive: &str,
+    files: &[&str],
+    options: &ExtractOptions,
+) -> Result<ZpaqCommandOutput> {
+    let links = match options.symlinks {
+        ExtractSymlinkPolicy::Restore => LINKS_RESTORE,
+        ExtractSymlinkPolicy::SkipWithWarning => LINKS_SKIP_WITH_WARNING,
+    };
+    let progress = match &options.progress {
+        Some(_) if !cfg!(unix) => {
+            return Err(ZpaqError::Ffi("progress is only supported on Unix".into()));
+        }
+        Some(callback) => Some(Mutex::new(Meter::new(callback.clone()))),
+        None => None,
+    };
+    unsafe {
+        sys::zpaq_jidac_set_symlink_policy(LINKS_SKIP, links);
+        if let Some(progress) = &progress {
+            let ctx = progress as *const Mutex<Meter> as *mut std::os::raw::c_void;
+            sys::zpaq_jidac_set_write_hook(ctx, Some(extract_write));
+        }
+    }
+    let result = temp::with_temp_dir(options.temp_dir.as_deref(), || zpaq_extract(archive, files));
+    unsafe {
+        sys::zpaq_jidac_set_symlink_policy(LINKS_SKIP, LINKS_AS_FILES);
+        sys::zpaq_jidac_set_write_hook(ptr::null_mut(), None);
+    }
+    if let Some(progress) = progress {
+        progress
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+            .finish();
+    }
+    result
+}
+
+/// Equivalent of `zpaq list <archive> [files...]`.
+pub fn zpaq_list(archive: &str, files: &[&str]) -> Result<ZpaqCommandOutput> {
+    let mut args = Vec::with_capacity(files.len() + 2);
+    args.push("list".to_string());
+    args.push(archive.to_string());
+    for file in files {
+        args.push((*file).to_string());
+    }
+    zpaq_command_inner(&args)
+}
+
+/// Counterpart of [`zpaq_list`] for an archive held in memory, such as one
+/// read from standard input.  The archive is never written to a file: it is
+/// listed in-process as by [`list_stream`], giving the files of the latest
+/// version.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use std::io::Read;
+///
+/// let mut archive = Vec::new();
+/// std::io::stdin().read_to_end(&mut archive)?;
+/// for entry in zpaq_rs::zpaq_list_bytes(&archive)?.entries {
+///     println!("{:>12} {}", entry.size, entry.path);
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub fn zpaq_list_bytes(archive: &[u8]) -> Result<ArchiveListing> {
+    list_stream(archive)
+}
+
+/// Counterpart of [`zpaq_extract`] for an archive held in memory: writes
+/// the files of its latest version below `dest` without a temporary copy of
+/// the archive.
+///
+/// This is [`archive_extract_to_dir`] without a filter, so stored paths
+/// that would leave `dest` are reported in [`ExtractReport::rejected`]
+/// rather than written where `zpaq extract` would put them.
+pub fn zpaq_extract_bytes(archive: &[u8], dest: &str) -> Result<ExtractReport> {
+    archive_extract_to_dir(archive, dest, None)
+}
+
+/// Decompresses a complete ZPAQ stream held in `input` and returns the
+/// original data as a `Vec<u8>`.
+///
+/// This is a convenience wrapper around [`decompress_stream`] that owns both
+/// the input and output.  For large data, prefer [`decompress_stream`] to
+/// avoid double-buffering.  The output is reserved up front from the sizes
+/// the segment comments declare (see [`original_size_hint`] and
+/// [`decompress_to_vec_with`]).
+///
+/// # Example
+///
+/// ```rust
+/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "1").unwrap();
+/// let d = zpaq_rs::decompress_to_vec(&c).unwrap();
+/// assert_eq!(d, b"hello zpaq");
+/// ```
+pub fn decompress_to_vec(input: &[u8]) -> Result<Vec<u8>> {
+    decompress_to_vec_with(input, &DecompressOptions::default())
+}
+
+/// Returns the decompressed size of the ZPAQ stream in `input` without
+/// materialising the output.
+///
+/// Wrapper around [`decompress_size_stream`] with a [`std::io::Cursor`].
+pub fn decompress_size(input: &[u8]) -> Result<u64> {
+    decompress_size_stream(std::io::Cursor::new(input))
+}
+
+/// Returns the decompressed size of the ZPAQ stream from `reader` without
+/// materialising output.
+///
+/// Uses a C++-side counting writer so no allocation is needed for the
+/// decompressed bytes.
+pub fn decompress_size_stream<R: Read + Send>(reader: R) -> Result<u64> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress("decompress_size_stream");
+    let reader = FfiReader::tuned(reader)?;
+    let mut out_size: u64 = 0;
+    let rc = unsafe { sys::zpaq_decompress_size(reader.raw, &mut out_size as *mut u64) };
+    if rc == 0 {
+        Ok(out_size)
+    } else {
+        Err(err_from_last())
+    }
+}
+
+/// Turns hardened parsing of untrusted archives on or off, for all threads.
+///
+/// Hardened parsing is on by default.  Each block header is checked against
+/// the ranges libzpaq accepts (array and component sizes, component inputs,
+/// the HCOMP length against the declared header size) before any of its
+/// model is allocated, segment filenames and comments longer than 64 KiB are
+/// refused, and [`decompress_to_vec`] reserves no more than 4096 times the
+/// input length from the sizes segment comments declare.  Header violations
+/// fail with [`ZpaqError::Corrupt`].
+///
+/// Turning it off restores plain libzpaq parsing, for archives with longer
+/// filenames or comments.
+pub fn set_hardened_parsing(on: bool) {
+    unsafe { sys::zpaq_set_hardened_parsing(c_int::from(on)) };
+}
+
+/// Whether hardened parsing is on; see [`set_hardened_parsing`].
+pub fn hardened_parsing() -> bool {
+    unsafe { sys::zpaq_hardened_parsing() != 0 }
+}
+
+/// Compresses data from `reader` and writes the ZPAQ archive to `writer`.
+///
+/// `method` is the ZPAQ method string (e.g. `"1"`, `"x4.3ci1"`).
+/// `filename` and `comment` are optional segment metadata; pass `None` for
+/// both in typical usage.  Empty input still writes one block holding an
+/// empty segment, so the output is always a valid archive.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Cursor;
+/// let mut out = Vec::new();
+/// zpaq_rs::compress_stream(Cursor::new(b"hello"), &mut out, "1", None, None).unwrap();
+/// assert!(!out.is_empty());
 /// ```
 pub fn compress_stream<R: Read + Send, W: Write + Send>(
-    reader: R,
-    writer: W,
+    mut reader: R,
+    mut writer: W,
     method: &str,
     filename: Option<&str>,
     comment: Option<&str>,
 ) -> Result<()> {
-    clear_last_error();
-    let method_c = CString::new(method).map_err(|_| ZpaqError::NulInString)?;
-    let filename_c = match filename {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
+    compress_stream_dyn(&mut reader, &mut writer, method, filename, comment)
+}
+
+/// [`compress_stream`] for a reader and writer given as trait objects.
+///
+/// The generic functions hand their reader and writer to the shim as trait
+/// objects anyway, and [`compress_stream`] calls this, so nothing is lost
+/// by it; it suits readers and writers picked at run time, and callers that
+/// would rather not instantiate the generic function per type.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::{Read, Write};
+///
+/// let mut input: Box<dyn Read + Send> = Box::new(&b"hello"[..]);
+/// let mut out: Box<dyn Write + Send> = Box::new(Vec::new());
+/// zpaq_rs::compress_stream_dyn(&mut input, &mut out, "1", None, None)?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn compress_stream_dyn(
+    reader: &mut (dyn Read + Send),
+    writer: &mut (dyn Write + Send),
+    method: &str,
+    filename: Option<&str>,
+    comment: Option<&str>,
+) -> Result<()> {
+    compress_stream_as(
+        "compress_stream",
+        reader,
+        writer,
+        method,
+        filename.map(str::as_bytes),
+        comment.map(str::as_bytes),
+        None,
+    )
+}
+
+/// [`compress_stream`] with the segment filename and comment given as raw
+/// bytes, so names that are not valid UTF-8 are stored unchanged.
+///
+/// The ZPAQ segment header ends each field with a NUL byte, so a field
+/// containing NUL cannot be represented and fails with
+/// [`ZpaqError::NulInString`].  [`SegmentInfo::filename_bytes`] and
+/// [`SegmentInfo::comment_bytes`] read the fields back.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Cursor;
+/// let mut out = Vec::new();
+/// let name = b"caf\xe9.txt";
+/// zpaq_rs::compress_stream_bytes(Cursor::new(b"hello"), &mut out, "1", Some(name), None)
+///     .unwrap();
+/// let blocks = zpaq_rs::list_blocks(&out).unwrap();
+/// assert_eq!(blocks[0].segments[0].filename_bytes, name);
+/// ```
+pub fn compress_stream_bytes<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&[u8]>,
+    comment: Option<&[u8]>,
+) -> Result<()> {
+    compress_stream_as(
+        "compress_stream_bytes",
+        reader,
+        writer,
+        method,
+        filename,
+        comment,
+        None,
+    )
+}
+
+/// What [`compress_stream_as`] is asked to do with, and record of, the
+/// blocks it writes.
+#[derive(Debug, Default)]
+pub(crate) struct WrittenBlocks {
+    /// Store blocks that would come out larger than their input instead.
+    pub(crate) store_if_expands: bool,
+    /// Blocks stored under `store_if_expands`.
+    pub(crate) stored: u64,
+    /// End each segment comment with a checksum of this kind.
+    pub(crate) checksum: Option<HashKind>,
+    /// Each block's sizes, in order.
+    pub(crate) sizes: Vec<BlockSizeReport>,
+}
+
+/// [`compress_stream_bytes`], traced as `operation`.  With `blocks`, the
+/// blocks written are recorded there.
+pub(crate) fn compress_stream_as<R: Read + Send, W: Write + Send>(
+    operation: &'static str,
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&[u8]>,
+    comment: Option<&[u8]>,
+    blocks: Option<&mut WrittenBlocks>,
+) -> Result<()> {
+    clear_last_error()?;
+    let _op = trace::Operation::compress(operation, method, 1);
+    let method_c = method_cstring(method)?;
+    let filename_c = segment_field(filename)?;
+    let comment_c = segment_field(comment)?;
+
+    let reader = FfiReader::tuned(reader)?;
+    let writer = FfiWriter::new(writer)?;
+
+    let filename_p = filename_c
+        .as_ref()
+        .map(|c| c.as_ptr())
+        .unwrap_or(ptr::null());
+    let comment_p = comment_c
+        .as_ref()
+        .map(|c| c.as_ptr())
+        .unwrap_or(ptr::null());
+    let method_p = method_c.as_ptr();
+    let Some(blocks) = blocks else {
+        let rc = unsafe {
+            sys::zpaq_compress(reader.raw, writer.raw, method_p, filename_p, comment_p, 1)
+        };
+        return if rc == 0 {
+            Ok(())
+        } else {
+            Err(err_from_last())
+        };
     };
-    let comment_c = match comment {
-        Some(s) => Some(CString::new(s).map_err(|_| ZpaqError::NulInString)?),
-        None => None,
+    let records = SharedVecWriter::new();
+    let sizes = FfiWriter::new(records.clone())?;
+    let rc = unsafe {
+        sys::zpaq_compress_recorded(
+            reader.raw,
+            writer.raw,
+            method_p,
+            filename_p,
+            comment_p,
+            1,
+            c_int::from(blocks.store_if_expands),
+            &mut blocks.stored,
+            match blocks.checksum {
+                None => 0,
+                Some(HashKind::Sha1) => 1,
+                Some(HashKind::Sha256) => 2,
+            },
+            sizes.raw,
+        )
     };
+    drop(sizes);
+    if rc != 0 {
+        return Err(err_from_last());
+    }
+    blocks.sizes = block_size_records(&records.bytes());
+    Ok(())
+}
+
+/// Compresses data from `reader` to `writer` using multiple threads.
+///
+/// The input is split into blocks of the method's block size, exactly as
+/// [`compress_stream`] splits it, and blocks are compressed independently on
+/// `threads` workers.  Compressed blocks are written strictly in input order,
+/// so the output is byte-for-byte identical to [`compress_stream`] with the
+/// same arguments for every thread count, and its length equals
+/// [`compress_size_stream_parallel`].  At most `2 * threads` blocks are held
+/// in memory at once.  `threads <= 1` compresses on the calling thread.
+///
+/// Parallelism only helps when the input spans several blocks.  The second
+/// digit of a numeric method sets the block size, so `"12"` uses 4 MiB blocks
+/// where `"1"` uses the default 16 MiB (see [`method_block_size`]).
+pub fn compress_stream_parallel<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&str>,
+    comment: Option<&str>,
+    threads: usize,
+) -> Result<()> {
+    compress_stream_parallel_as(
+        "compress_stream_parallel",
+        reader,
+        writer,
+        method,
+        filename.map(str::as_bytes),
+        comment.map(str::as_bytes),
+        threads,
+    )
+}
+
+/// [`compress_stream_parallel`] with byte-string segment metadata, as in
+/// [`compress_stream_bytes`].
+pub fn compress_stream_parallel_bytes<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&[u8]>,
+    comment: Option<&[u8]>,
+    threads: usize,
+) -> Result<()> {
+    compress_stream_parallel_as(
+        "compress_stream_parallel_bytes",
+        reader,
+        writer,
+        method,
+        filename,
+        comment,
+        threads,
+    )
+}
 
-    let reader = FfiReader::new(reader)?;
+fn compress_stream_parallel_as<R: Read + Send, W: Write + Send>(
+    operation: &'static str,
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&[u8]>,
+    comment: Option<&[u8]>,
+    threads: usize,
+) -> Result<()> {
+    clear_last_error()?;
+    let _op = trace::Operation::compress(operation, method, threads);
+    method_block_size(method)?;
+    let method_c = method_cstring(method)?;
+    let filename_c = segment_field(filename)?;
+    let comment_c = segment_field(comment)?;
+
+    let reader = FfiReader::tuned(reader)?;
     let writer = FfiWriter::new(writer)?;
 
     let rc = unsafe {
-        sys::zpaq_compress(
+        sys::zpaq_compress_parallel(
             reader.raw,
             writer.raw,
             method_c.as_ptr(),
@@ -1281,6 +3633,7 @@ pub fn compress_stream<R: Read + Send, W: Write + Send>(
                 .map(|c| c.as_ptr())
                 .unwrap_or(ptr::null()),
             1,
+            thread_count(threads),
         )
     };
     if rc == 0 {
@@ -1290,6 +3643,24 @@ pub fn compress_stream<R: Read + Send, W: Write + Send>(
     }
 }
 
+/// Compresses `input` into a `Vec<u8>` using multiple threads.
+///
+/// The result is identical to [`compress_to_vec`] for any `threads`; see
+/// [`compress_stream_parallel`].
+///
+/// # Example
+///
+/// ```rust
+/// let data = b"parallel but deterministic ".repeat(1000);
+/// let par = zpaq_rs::compress_to_vec_parallel(&data, "1", 4).unwrap();
+/// assert_eq!(par, zpaq_rs::compress_to_vec(&data, "1").unwrap());
+/// ```
+pub fn compress_to_vec_parallel(input: &[u8], method: &str, threads: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    compress_stream_parallel(input, &mut out, method, None, None, threads)?;
+    Ok(out)
+}
+
 /// Decompresses a ZPAQ archive from `reader` and writes raw data to `writer`.
 ///
 /// # Example
@@ -1301,9 +3672,35 @@ pub fn compress_stream<R: Read + Send, W: Write + Send>(
 /// zpaq_rs::decompress_stream(Cursor::new(&compressed), &mut out).unwrap();
 /// assert_eq!(out, b"hello");
 /// ```
-pub fn decompress_stream<R: Read + Send, W: Write + Send>(reader: R, writer: W) -> Result<()> {
-    clear_last_error();
-    let reader = FfiReader::new(reader)?;
+pub fn decompress_stream<R: Read + Send, W: Write + Send>(
+    mut reader: R,
+    mut writer: W,
+) -> Result<()> {
+    decompress_stream_dyn(&mut reader, &mut writer)
+}
+
+/// [`decompress_stream`] for a reader and writer given as trait objects;
+/// see [`compress_stream_dyn`].
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::{Read, Write};
+///
+/// let compressed = zpaq_rs::compress_to_vec(b"hello", "1")?;
+/// let mut input: Box<dyn Read + Send> = Box::new(&compressed[..]);
+/// let mut out = Vec::new();
+/// zpaq_rs::decompress_stream_dyn(&mut input, &mut out)?;
+/// assert_eq!(out, b"hello");
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn decompress_stream_dyn(
+    reader: &mut (dyn Read + Send),
+    writer: &mut (dyn Write + Send),
+) -> Result<()> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress("decompress_stream");
+    let reader = FfiReader::tuned(reader)?;
     let writer = FfiWriter::new(writer)?;
     let rc = unsafe { sys::zpaq_decompress(reader.raw, writer.raw) };
     if rc == 0 {
@@ -1313,6 +3710,39 @@ pub fn decompress_stream<R: Read + Send, W: Write + Send>(reader: R, writer: W)
     }
 }
 
+/// Decompresses like [`decompress_stream`] and returns the SHA-1 digest of
+/// everything written to `writer`.
+///
+/// The data is hashed in the shim on its way out, in large chunks, so the
+/// digest costs little more than the decompression itself.
+///
+/// # Example
+///
+/// ```rust
+/// let compressed = zpaq_rs::compress_to_vec(b"hello", "1").unwrap();
+/// let mut out = Vec::new();
+/// let digest = zpaq_rs::decompress_stream_sha1(&compressed[..], &mut out).unwrap();
+/// assert_eq!(digest, zpaq_rs::sha1(&out).unwrap());
+/// ```
+pub fn decompress_stream_sha1<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+) -> Result<[u8; 20]> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress("decompress_stream_sha1");
+    let reader = FfiReader::tuned(reader)?;
+    let writer = FfiWriter::new(writer)?;
+    let mut digest = [0u8; 20];
+    let rc = unsafe {
+        sys::zpaq_decompress_sha1(reader.raw, writer.raw, digest.as_mut_ptr(), ptr::null_mut())
+    };
+    if rc == 0 {
+        Ok(digest)
+    } else {
+        Err(err_from_last())
+    }
+}
+
 /// Derives a 32-byte key from `key32` and `salt32` using scrypt.
 ///
 /// Uses libzpaq's fixed scrypt parameters: N = 16 384, r = 8, p = 1.
@@ -1320,7 +3750,7 @@ pub fn decompress_stream<R: Read + Send, W: Write + Send>(reader: R, writer: W)
 ///
 /// This is the same key-stretching used by `zpaq` encrypted archives.
 pub fn stretch_key(key32: [u8; 32], salt32: [u8; 32]) -> Result<[u8; 32]> {
-    clear_last_error();
+    clear_last_error()?;
     let mut out = [0u8; 32];
     let rc = unsafe { sys::zpaq_stretch_key(out.as_mut_ptr(), key32.as_ptr(), salt32.as_ptr()) };
     if rc == 0 {
@@ -1335,7 +3765,7 @@ pub fn stretch_key(key32: [u8; 32], salt32: [u8; 32]) -> Result<[u8; 32]> {
 /// On Unix delegates to `/dev/urandom`; on Windows uses `CryptGenRandom`.
 /// Returns [`ZpaqError::Ffi`] if the platform RNG is unavailable.
 pub fn random_bytes(len: usize) -> Result<Vec<u8>> {
-    clear_last_error();
+    clear_last_error()?;
     let mut buf = vec![0u8; len];
     let rc = unsafe { sys::zpaq_random(buf.as_mut_ptr(), len as c_int) };
     if rc == 0 {
@@ -1345,26 +3775,57 @@ pub fn random_bytes(len: usize) -> Result<Vec<u8>> {
     }
 }
 
-/// Computes the SHA-1 digest of `bytes` using the libzpaq implementation.
-///
-/// Returns the 20-byte raw digest.  For new designs prefer [`sha256`];
-/// SHA-1 is exposed because it is used internally by ZPAQ segment checksums.
-pub fn sha1(bytes: &[u8]) -> Result<[u8; 20]> {
-    clear_last_error();
-    let s = unsafe { sys::zpaq_sha1_new() };
-    if s.is_null() {
-        return Err(err_from_last());
+/// Computes the SHA-1 digest of `bytes` using the libzpaq implementation.
+///
+/// Returns the 20-byte raw digest.  For new designs prefer [`sha256`];
+/// SHA-1 is exposed because it is used internally by ZPAQ segment checksums.
+pub fn sha1(bytes: &[u8]) -> Result<[u8; 20]> {
+    let mut hasher = Sha1Hasher::new()?;
+    hasher.update(bytes);
+    hasher.finish()
+}
+
+/// An incremental libzpaq SHA-1, fed in bulk through `zpaq_sha1_write`.
+pub(crate) struct Sha1Hasher {
+    sha: handle::Sha1,
+}
+
+impl Sha1Hasher {
+    pub(crate) fn new() -> Result<Self> {
+        clear_last_error()?;
+        Ok(Sha1Hasher {
+            sha: handle::Sha1::new()?,
+        })
+    }
+
+    /// The underlying object, for the shim to hash into directly.
+    pub(crate) fn raw(&self) -> *mut sys::SHA1 {
+        self.sha.raw
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        unsafe {
+            sys::zpaq_sha1_write(
+                self.sha.raw,
+                bytes.as_ptr() as *const c_char,
+                bytes.len() as i64,
+            )
+        };
     }
-    unsafe {
-        sys::zpaq_sha1_write(s, bytes.as_ptr() as *const c_char, bytes.len() as i64);
+
+    /// Bytes hashed since the last [`finish`](Self::finish).
+    pub(crate) fn len(&self) -> u64 {
+        unsafe { sys::zpaq_sha1_usize(self.sha.raw) }
     }
-    let mut out = [0u8; 20];
-    let rc = unsafe { sys::zpaq_sha1_result(s, out.as_mut_ptr()) };
-    unsafe { sys::zpaq_sha1_free(s) };
-    if rc == 0 {
-        Ok(out)
-    } else {
-        Err(err_from_last())
+
+    /// The digest of the bytes hashed so far; the hasher starts over.
+    pub(crate) fn finish(&mut self) -> Result<[u8; 20]> {
+        let mut out = [0u8; 20];
+        if unsafe { sys::zpaq_sha1_result(self.sha.raw, out.as_mut_ptr()) } == 0 {
+            Ok(out)
+        } else {
+            Err(err_from_last())
+        }
     }
 }
 
@@ -1372,20 +3833,11 @@ pub fn sha1(bytes: &[u8]) -> Result<[u8; 20]> {
 ///
 /// Returns the 32-byte raw digest.
 pub fn sha256(bytes: &[u8]) -> Result<[u8; 32]> {
-    clear_last_error();
-    let s = unsafe { sys::zpaq_sha256_new() };
-    if s.is_null() {
-        return Err(err_from_last());
-    }
-    unsafe {
-        for &b in bytes {
-            sys::zpaq_sha256_put(s, b as c_int);
-        }
-    }
+    clear_last_error()?;
+    let s = handle::Sha256::new()?;
+    unsafe { sys::zpaq_sha256_write(s.raw, bytes.as_ptr() as *const c_char, bytes.len() as i64) };
     let mut out = [0u8; 32];
-    let rc = unsafe { sys::zpaq_sha256_result(s, out.as_mut_ptr()) };
-    unsafe { sys::zpaq_sha256_free(s) };
-    if rc == 0 {
+    if unsafe { sys::zpaq_sha256_result(s.raw, out.as_mut_ptr()) } == 0 {
         Ok(out)
     } else {
         Err(err_from_last())
@@ -1396,54 +3848,291 @@ pub fn sha256(bytes: &[u8]) -> Result<[u8; 32]> {
 mod tests {
     use super::*;
 
-    fn test_payloads() -> Vec<Vec<u8>> {
-        vec![
-            b"".to_vec(),
-            b"hello zpaq".to_vec(),
-            (0..1024).map(|i| (i * 31 % 251) as u8).collect(),
-            (0..20_000)
-                .map(|i| (i * 1315423911u64 as usize % 256) as u8)
-                .collect(),
-        ]
+    #[test]
+    fn every_level_round_trips_with_matching_sizes() {
+        let payloads: Vec<_> = test_support::payloads()
+            .into_iter()
+            .filter(|payload| payload.data.len() <= 1 << 18)
+            .collect();
+        test_support::round_trip_with(
+            &[test_support::ApiUnderTest::TO_VEC],
+            &payloads,
+            &["1", "2", "3", "4", "5", "x4.3ci1"],
+        );
+    }
+
+    #[test]
+    fn store_copies_data_into_stored_blocks() {
+        use std::time::{Duration, Instant};
+
+        let data: Vec<u8> = (0..4_000_000u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+
+        // 1 MiB blocks: four of them, each holding the data as it is plus
+        // a little framing.
+        let compressed = compress_to_vec(&data, "00").expect("compress");
+        let blocks = list_blocks(&compressed).expect("list_blocks");
+        assert_eq!(blocks.len(), 4);
+        assert!(blocks.iter().all(|block| block.stored));
+        assert!(compressed.len() < data.len() + 4096, "{}", compressed.len());
+        assert!(decompress_to_vec(&compressed).expect("decompress") == data);
+
+        // A loose bound, to catch store going back through the encoder.
+        let best = |method: &str| {
+            (0..3)
+                .map(|_| {
+                    let start = Instant::now();
+                    compress_size(&data, method).expect("compress_size");
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO)
+        };
+        let store = best("0");
+        let level1 = best("1");
+        assert!(store < level1 * 2, "store={store:?} level1={level1:?}");
     }
 
     #[test]
-    fn roundtrip_small() {
-        let data = b"hello zpaq";
-        let c = compress_to_vec(data, "1").expect("compress");
-        let d = decompress_to_vec(&c).expect("decompress");
-        assert_eq!(d, data);
+    fn hashing_does_not_slow_decompression_much() {
+        use std::time::{Duration, Instant};
+
+        // A loose bound, to catch hashing going back to a byte at a time.
+        let data = test_support::letters(1, 64 << 20);
+        let compressed = compress_to_vec_parallel(&data, "1", 4).expect("compress");
+        let digest = sha1(&data).expect("sha1");
+        let best = |hash: bool| {
+            (0..2)
+                .map(|_| {
+                    let start = Instant::now();
+                    if hash {
+                        let got = decompress_stream_sha1(&compressed[..], std::io::sink());
+                        assert_eq!(got.expect("decompress"), digest);
+                    } else {
+                        decompress_stream(&compressed[..], std::io::sink()).expect("decompress");
+                    }
+                    start.elapsed()
+                })
+                .min()
+                .unwrap_or(Duration::ZERO)
+        };
+        let plain = best(false);
+        let hashed = best(true);
+        assert!(hashed < plain * 2, "plain={plain:?} hashed={hashed:?}");
     }
 
     #[test]
-    fn roundtrip_random() {
+    fn short_reads_do_not_split_blocks() {
+        struct Trickle<'a>(&'a [u8]);
+
+        impl Read for Trickle<'_> {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.0.len()).min(7);
+                buf[..n].copy_from_slice(&self.0[..n]);
+                self.0 = &self.0[n..];
+                Ok(n)
+            }
+        }
+
         let data: Vec<u8> = (0..10_000).map(|i| (i * 31 % 251) as u8).collect();
-        let c = compress_to_vec(&data, "2").expect("compress");
-        let d = decompress_to_vec(&c).expect("decompress");
-        assert_eq!(d, data);
+        for method in ["0", "2"] {
+            let mut trickled = Vec::new();
+            compress_stream(Trickle(&data), &mut trickled, method, None, None).expect("compress");
+            assert_eq!(trickled, compress_to_vec(&data, method).expect("compress"));
+        }
+    }
+
+    #[test]
+    fn block_sizes_sum_to_parallel_size() {
+        // Method "10": level 1 with 1 MiB blocks, so this input spans four.
+        let data: Vec<u8> = b"block size accounting for the parallel compressor. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(3_500_000)
+            .collect();
+        for threads in [1, 4] {
+            let blocks = compress_block_sizes(&data[..], "10", None, threads).expect("block sizes");
+            assert_eq!(blocks.len(), 4, "threads={threads}");
+            let uncompressed: u64 = blocks.iter().map(|b| b.uncompressed).sum();
+            let compressed: u64 = blocks.iter().map(|b| b.compressed).sum();
+            assert_eq!(uncompressed, data.len() as u64);
+            assert_eq!(
+                compressed,
+                compress_size_parallel(&data, "10", threads).expect("parallel size"),
+                "threads={threads}"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_tiny_and_block_sized_inputs_agree_across_apis() {
+        // Methods with 1 MiB blocks (less libzpaq's 4096-byte margin).
+        let block = (1 << 20) - 4096;
+        let text = test_support::text(block + 1);
+        for len in [0, 1, block - 1, block, block + 1] {
+            let data = &text[..len];
+            for method in ["00", "10", "20", "x0.3ci1"] {
+                let case = format!("len={len} method={method}");
+                let compressed = compress_to_vec(data, method).expect("compress");
+                assert!(is_zpaq(&compressed), "{case}");
+                assert_eq!(
+                    decompress_to_vec(&compressed).expect("decompress"),
+                    data,
+                    "{case}"
+                );
+                assert_eq!(
+                    decompress_size(&compressed).expect("size"),
+                    len as u64,
+                    "{case}"
+                );
+                let blocks = list_blocks(&compressed).expect("blocks");
+                assert_eq!(blocks.len(), len.div_ceil(block).max(1), "{case}");
+                let size = compressed.len() as u64;
+                assert_eq!(compress_size(data, method).expect("size"), size, "{case}");
+                for threads in [0, 1, 2, 8] {
+                    let case = format!("{case} threads={threads}");
+                    let parallel =
+                        compress_to_vec_parallel(data, method, threads).expect("compress");
+                    assert!(parallel == compressed, "{case}");
+                    let parallel_size =
+                        compress_size_parallel(data, method, threads).expect("size");
+                    assert_eq!(parallel_size, size, "{case}");
+                    let sizes = compress_block_sizes(data, method, None, threads).expect("sizes");
+                    assert_eq!(sizes.len(), blocks.len(), "{case}");
+                    assert_eq!(
+                        sizes.iter().map(|b| b.compressed).sum::<u64>(),
+                        size,
+                        "{case}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_bits_start_at_the_header_cost() {
+        for method in ["1", "x4.0ci1", "x4.0ci1c0"] {
+            let mut sc = StreamingCompressor::new(method).expect("streaming");
+            let header = sc.bits();
+            assert!(
+                header > 0.0 && header % 8.0 == 0.0,
+                "method={method}: {header}"
+            );
+            assert_eq!(sc.bits(), header);
+            sc.push(b'a').expect("push");
+            assert!(sc.bits() >= header, "method={method}");
+        }
+        // An empty archive is the header plus the end of the segment and block.
+        let sc = StreamingCompressor::new("x4.0ci1").expect("streaming");
+        let empty = compress_to_vec(b"", "x4.0ci1").expect("compress");
+        assert!(sc.bits() < empty.len() as f64 * 8.0);
+        assert!(empty.starts_with(&ZPAQ_TAG));
+    }
+
+    #[test]
+    fn trait_objects_stream_like_concrete_types() {
+        let data = test_support::text(200_000);
+        let expected = compress_to_vec(&data, "2").expect("compress");
+
+        // Boxed trait objects, borrowing or owning, go straight to the
+        // generic functions.
+        let input: Box<dyn Read + Send + '_> = Box::new(&data[..]);
+        let mut out: Box<dyn Write + Send> = Box::new(Vec::new());
+        compress_stream(input, &mut out, "2", None, None).expect("compress boxed");
+        let mut compressed = Vec::new();
+        compress_stream_dyn(&mut &data[..], &mut compressed, "2", None, None).expect("dyn");
+        assert!(compressed == expected);
+
+        let mut input: Box<dyn Read + Send> = Box::new(std::io::Cursor::new(compressed));
+        let mut restored = Vec::new();
+        decompress_stream_dyn(&mut input, &mut restored).expect("decompress dyn");
+        assert!(restored == data);
+        let input: Box<dyn Read + Send> = Box::new(&expected[..]);
+        let mut restored = Vec::new();
+        decompress_stream(input, Box::new(&mut restored) as Box<dyn Write + Send + '_>)
+            .expect("decompress boxed");
+        assert!(restored == data);
+
+        // Errors still come back from behind the trait objects, without the
+        // writer's buffered bytes being retried as it is freed.
+        let mut full = std::io::Cursor::new([0u8; 16]);
+        let error = compress_stream_dyn(&mut &data[..], &mut full, "2", None, None).unwrap_err();
+        assert!(error.to_string().contains("callback failed"), "{error}");
     }
 
     #[test]
-    fn compress_size_matches_vec_len() {
-        for method in ["1", "2", "3", "4", "5", "x4.3ci1"] {
-            for data in test_payloads() {
-                let c = compress_to_vec(&data, method).expect("compress");
-                let sz = compress_size(&data, method).expect("compress_size");
-                assert_eq!(sz as usize, c.len(), "method={method}");
+    fn thread_counts_saturate_instead_of_wrapping() {
+        let cores = std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get);
+        let cores = c_int::try_from(cores).unwrap();
+        assert_eq!(thread_count(1), 1);
+        assert_eq!(thread_count(c_int::MAX as usize), cores);
+        assert_eq!(thread_count(usize::MAX), cores);
+        #[cfg(target_pointer_width = "64")]
+        assert_eq!(thread_count((1 << 32) + 1), cores);
+
+        let data = b"more threads than blocks. ".repeat(64);
+        let sizes = compress_block_sizes(&data[..], "1", Some(1 << 20), usize::MAX).unwrap();
+        assert_eq!(sizes.len(), 1);
+    }
+
+    #[test]
+    fn parallel_output_matches_sequential() {
+        let mut data: Vec<u8> = b"deterministic parallel block output. "
+            .iter()
+            .copied()
+            .cycle()
+            .take(2_500_000)
+            .collect();
+        data.extend(random_bytes(700_000).expect("random_bytes"));
+        for method in ["10", "20", "00"] {
+            let mut sequential = Vec::new();
+            compress_stream(&data[..], &mut sequential, method, Some("f"), Some("c"))
+                .expect("compress");
+            let blocks = compress_block_sizes(&data[..], method, None, 2).expect("block sizes");
+            assert_eq!(blocks.len(), 4, "method={method}");
+            for threads in [1, 2, 8] {
+                let mut parallel = Vec::new();
+                compress_stream_parallel(
+                    &data[..],
+                    &mut parallel,
+                    method,
+                    Some("f"),
+                    Some("c"),
+                    threads,
+                )
+                .expect("compress parallel");
+                assert!(parallel == sequential, "method={method} threads={threads}");
+                let size = compress_size_parallel(&data, method, threads).expect("size");
+                assert_eq!(
+                    size,
+                    blocks.iter().map(|b| b.compressed).sum::<u64>(),
+                    "method={method} threads={threads}"
+                );
+                assert_eq!(
+                    compress_to_vec_parallel(&data, method, threads).expect("to_vec"),
+                    compress_to_vec(&data, method).expect("to_vec")
+                );
             }
         }
     }
 
     #[test]
-    fn decompress_size_matches_output_len() {
-        for method in ["1", "2", "x4.3ci1"] {
-            for data in test_payloads() {
-                let c = compress_to_vec(&data, method).expect("compress");
-                let out = decompress_to_vec(&c).expect("decompress");
-                let sz = decompress_size(&c).expect("decompress_size");
-                assert_eq!(sz as usize, out.len(), "method={method}");
+    fn block_sizes_locate_incompressible_region() {
+        let mut data = random_bytes(256 * 1024).expect("random_bytes");
+        data.resize(512 * 1024, 0);
+        let blocks = compress_block_sizes(&data[..], "1", Some(64 * 1024), 3).expect("block sizes");
+        assert_eq!(blocks.len(), 8);
+        for (i, b) in blocks.iter().enumerate() {
+            assert_eq!(b.uncompressed, 64 * 1024);
+            if i < 4 {
+                assert!(b.compressed > b.uncompressed * 9 / 10, "block {i}: {b:?}");
+            } else {
+                assert!(b.compressed < b.uncompressed / 20, "block {i}: {b:?}");
             }
         }
+        assert!(compress_block_sizes(&data[..], "1", Some(0), 1).is_err());
     }
 
     #[test]
@@ -1490,5 +4179,1068 @@ mod tests {
         let err = compress_size_stream(FailingReader, "1", None, None).unwrap_err();
         let msg = err.to_string();
         assert!(msg.contains("callback failed"));
+
+        let err =
+            compress_stream_parallel(FailingReader, Vec::new(), "1", None, None, 4).unwrap_err();
+        assert!(err.to_string().contains("callback failed"));
+    }
+
+    #[test]
+    fn in_memory_append_and_list() {
+        let entries = |data: &'static [u8]| {
+            [
+                ArchiveEntry {
+                    path: "a.txt",
+                    data,
+                    comment: Some("first"),
+                },
+                ArchiveEntry {
+                    path: "b.txt",
+                    data: b"bravo",
+                    comment: None,
+                },
+            ]
+        };
+        let mut archive = archive_from_entries(&entries(b"alpha"), "1").expect("create");
+        let dir = test_support::TempDir::new("zpaq-rs-append");
+        let path = dir.join("append.zpaq");
+        let path_s = path.to_string_lossy().into_owned();
+        std::fs::write(&path, &archive).expect("write archive");
+
+        archive_append_entries(&mut archive, &entries(b"alpha, longer"), "1").expect("append");
+        archive_append_entries_file(&path_s, &entries(b"alpha, longer"), "1").expect("append");
+        assert_eq!(std::fs::read(&path).expect("read archive"), archive);
+
+        let listed = archive_list_entries(&archive).expect("list");
+        assert_eq!(
+            listed,
+            vec![
+                ArchiveEntryInfo {
+                    path: "a.txt".into(),
+                    size: 13,
+                    date: None,
+                    comment: Some("first".into()),
+                    meta: EntryMeta::default(),
+                },
+                ArchiveEntryInfo {
+                    path: "b.txt".into(),
+                    size: 5,
+                    date: None,
+                    comment: None,
+                    meta: EntryMeta::default(),
+                },
+            ]
+        );
+        assert_eq!(
+            archive_read_file_bytes(&archive, "a.txt").expect("read"),
+            b"alpha, longer"
+        );
+
+        // Journaling archives list the latest version from the index.
+        let file = dir.join("c.txt").to_string_lossy().into_owned();
+        let journal = dir.join("j.zpaq").to_string_lossy().into_owned();
+        std::fs::write(&file, b"charlie").expect("write file");
+        zpaq_add(&journal, &[&file], "1", 1).expect("add v1");
+        std::fs::write(&file, b"charlie, again").expect("rewrite file");
+        zpaq_add(&journal, &[&file], "1", 1).expect("add v2");
+        let listed = archive_list_entries(&std::fs::read(&journal).expect("read")).expect("list");
+        assert_eq!(listed.len(), 1);
+        assert_eq!(
+            (listed[0].path.as_str(), listed[0].size),
+            (file.as_str(), 14)
+        );
+        assert!(listed[0].date.is_some_and(|d| d > 19700101000000));
+    }
+
+    /// Generates numbered text lines and hashes what it produced.
+    struct GeneratedText {
+        remaining: u64,
+        line: u64,
+        pending: Vec<u8>,
+        hash: HashingSink,
+    }
+
+    impl Read for GeneratedText {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.pending.is_empty() && self.remaining > 0 {
+                while self.pending.len() < 1 << 16 {
+                    let value = self.line * 2654435761 % 1_000_003;
+                    self.pending.extend_from_slice(
+                        format!("line {} value {value}\n", self.line).as_bytes(),
+                    );
+                    self.line += 1;
+                }
+                self.pending
+                    .truncate(self.pending.len().min(self.remaining as usize));
+                self.remaining -= self.pending.len() as u64;
+            }
+            let n = buf.len().min(self.pending.len());
+            buf[..n].copy_from_slice(&self.pending[..n]);
+            self.pending.drain(..n);
+            self.hash.write_all(&buf[..n])?;
+            Ok(n)
+        }
+    }
+
+    #[derive(Default)]
+    struct HashingSink {
+        hasher: std::hash::DefaultHasher,
+        len: u64,
+    }
+
+    impl Write for HashingSink {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::hash::Hasher::write(&mut self.hasher, buf);
+            self.len += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_file_to_streams_large_member() {
+        use std::hash::Hasher;
+
+        let dir = test_support::TempDir::new("zpaq-rs-read-to");
+        let archive = dir.join("large.zpaq").to_string_lossy().into_owned();
+        let mut generated = GeneratedText {
+            remaining: 256 << 20,
+            line: 0,
+            pending: Vec::new(),
+            hash: HashingSink::default(),
+        };
+        let mut writer = ArchiveWriter::create(&archive, "1").expect("create");
+        let meta = EntryMeta::default();
+        writer
+            .add_entry("small.txt", &b"small"[..], &meta)
+            .expect("add");
+        writer
+            .add_entry("large.txt", &mut generated, &meta)
+            .expect("add");
+        writer.finish().expect("finish");
+
+        let mut sink = HashingSink::default();
+        let size = archive_read_file_to_from_file(&archive, "large.txt", &mut sink).expect("read");
+        assert_eq!(size, 256 << 20);
+        assert_eq!(sink.len, generated.hash.len);
+        assert_eq!(sink.hasher.finish(), generated.hash.hasher.finish());
+
+        let entries = [ArchiveEntry {
+            path: "a.txt",
+            data: b"alpha",
+            comment: None,
+        }];
+        let small = archive_from_entries(&entries, "1").expect("create");
+        let mut out = Vec::new();
+        assert_eq!(
+            archive_read_file_to(&small, "a.txt", &mut out).expect("read"),
+            5
+        );
+        assert_eq!(out, b"alpha");
+        let missing = archive_read_file_to(&small, "b.txt", std::io::sink());
+        assert!(matches!(missing, Err(ZpaqError::NotFound { path, .. }) if path == "b.txt"));
+    }
+
+    #[test]
+    fn entries_list_current_files_and_history() {
+        let entries = |a: &'static [u8]| {
+            [
+                ArchiveEntry {
+                    path: "docs/a.txt",
+                    data: a,
+                    comment: None,
+                },
+                ArchiveEntry {
+                    path: "docs/sub/b.txt",
+                    data: b"bravo",
+                    comment: None,
+                },
+            ]
+        };
+        let mut archive = archive_from_entries(&entries(b"alpha"), "1").expect("create");
+        archive_append_entries(&mut archive, &entries(b"alpha, longer")[..1], "1").expect("append");
+
+        let summary = |listed: Vec<ArchiveEntryVersion>| {
+            listed
+                .into_iter()
+                .map(|e| (e.path, e.size, e.version, e.deleted))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            summary(archive_entries(&archive, None, false).expect("list")),
+            vec![
+                ("docs/a.txt".to_string(), 13, 2, false),
+                ("docs/sub/b.txt".to_string(), 5, 1, false),
+            ]
+        );
+        assert_eq!(
+            summary(archive_entries(&archive, None, true).expect("history")),
+            vec![
+                ("docs/a.txt".to_string(), 5, 1, false),
+                ("docs/a.txt".to_string(), 13, 2, false),
+                ("docs/sub/b.txt".to_string(), 5, 1, false),
+            ]
+        );
+
+        // Journaling archives record updates and deletions per version.
+        let dir = test_support::TempDir::new("zpaq-rs-entries");
+        let data = dir.join("data");
+        std::fs::create_dir_all(&data).expect("create temp dir");
+        let data_s = data.to_string_lossy().into_owned();
+        let journal = dir.join("j.zpaq").to_string_lossy().into_owned();
+        std::fs::write(data.join("a.txt"), b"alpha").expect("write a");
+        std::fs::write(data.join("b.txt"), b"bravo").expect("write b");
+        zpaq_add(&journal, &[&data_s], "1", 1).expect("add v1");
+        std::fs::write(data.join("a.txt"), b"alpha, longer").expect("rewrite a");
+        std::fs::remove_file(data.join("b.txt")).expect("delete b");
+        zpaq_add(&journal, &[&data_s], "1", 1).expect("add v2");
+        let journal = std::fs::read(&journal).expect("read archive");
+        let a = format!("{data_s}/a.txt");
+        let b = format!("{data_s}/b.txt");
+
+        let current = archive_entries(&journal, None, false).expect("list");
+        assert!(current[0].mtime.is_some());
+        assert_eq!(summary(current), vec![(a.clone(), 13, 2, false)]);
+        assert_eq!(
+            summary(archive_entries(&journal, None, true).expect("history")),
+            vec![
+                (a.clone(), 5, 1, false),
+                (a, 13, 2, false),
+                (b.clone(), 5, 1, false),
+                (b, 0, 2, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn delta_size_predicts_append_growth() {
+        let dir = test_support::TempDir::new("zpaq-rs-delta");
+        let data = dir.join("data");
+        std::fs::create_dir_all(&data).expect("create temp dir");
+        let data_s = data.to_string_lossy().into_owned();
+        let archive = dir.join("backup.zpaq").to_string_lossy().into_owned();
+        let text =
+            |tag: &str| -> String { (0..20_000).map(|i| format!("{tag} line {i}\n")).collect() };
+        std::fs::write(data.join("a.txt"), text("alpha")).expect("write a");
+        std::fs::write(data.join("b.txt"), text("bravo")).expect("write b");
+
+        let predicted = zpaq_add_archive_delta_size(&archive, &[&data_s], "1", 1).expect("predict");
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v1");
+        let size = || std::fs::metadata(&archive).expect("stat").len();
+        assert_eq!(predicted, size());
+
+        // Change one file, add one with partly known contents.
+        std::fs::write(data.join("a.txt"), text("ALPHA!")).expect("rewrite a");
+        let c = [text("bravo"), text("charlie")].concat();
+        std::fs::write(data.join("c.txt"), c).expect("write c");
+        let before = std::fs::read(&archive).expect("read archive");
+        let mtime = std::fs::metadata(&archive)
+            .and_then(|m| m.modified())
+            .expect("mtime");
+        let predicted = zpaq_add_archive_delta_size(&archive, &[&data_s], "1", 2).expect("predict");
+        assert_eq!(std::fs::read(&archive).expect("read archive"), before);
+        let unchanged = std::fs::metadata(&archive).and_then(|m| m.modified());
+        assert_eq!(unchanged.expect("mtime"), mtime);
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v2");
+        assert!(predicted > 0);
+        assert_eq!(size() - before.len() as u64, predicted);
+
+        let predicted = zpaq_add_archive_delta_size(&archive, &[&data_s], "1", 1).expect("predict");
+        let before = size();
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add nothing");
+        assert_eq!((predicted, size()), (0, before));
+    }
+
+    #[test]
+    fn size_of_bytes_matches_size_of_file() {
+        let dir = test_support::TempDir::new("zpaq-rs-size-bytes");
+        let path = dir.join("sample.txt");
+        let path_s = path.to_string_lossy().into_owned();
+        let data: Vec<u8> = (0..30_000)
+            .flat_map(|i| format!("row {} of {}\n", i % 613, i % 37).into_bytes())
+            .collect();
+        let file = std::fs::File::create(&path).expect("create sample");
+        (&file).write_all(&data).expect("write sample");
+        file.set_modified(SystemTime::UNIX_EPOCH)
+            .expect("set mtime");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::Permissions::from_mode(0o644);
+            file.set_permissions(mode).expect("set mode");
+        }
+        drop(file);
+
+        for (method, threads) in [("1", 1), ("3", 2)] {
+            let from_file = zpaq_add_archive_size_file(&path_s, method, threads).expect("file");
+            let from_bytes =
+                zpaq_add_archive_size_bytes(&data, &path_s, method, threads).expect("bytes");
+            assert_eq!(from_bytes, from_file, "method {method}");
+        }
+        assert!(zpaq_add_archive_size_bytes(&data, "", "1", 1).is_err());
+    }
+
+    #[test]
+    fn size_of_file_reports_unusable_paths() {
+        let dir = test_support::TempDir::new("zpaq-rs-size-paths");
+        let kind = |path: &Path| match zpaq_add_archive_size_file(path, "1", 1) {
+            Err(ZpaqError::Io {
+                path: reported,
+                kind,
+                ..
+            }) => {
+                assert_eq!(reported, path);
+                kind
+            }
+            other => panic!("{}: {other:?}", path.display()),
+        };
+
+        let missing = dir.join("missing.txt");
+        assert_eq!(kind(&missing), std::io::ErrorKind::NotFound);
+        assert_eq!(kind(dir.path()), std::io::ErrorKind::IsADirectory);
+        let err = zpaq_add_archive_size_file(&missing, "1", 1).unwrap_err();
+        assert!(err.to_string().starts_with(&missing.display().to_string()));
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let locked = dir.join("locked.txt");
+            std::fs::write(&locked, b"secret").expect("write");
+            std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o000))
+                .expect("chmod");
+            // Root reads it anyway.
+            if File::open(&locked).is_err() {
+                assert_eq!(kind(&locked), std::io::ErrorKind::PermissionDenied);
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn size_of_file_takes_non_unicode_paths() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let dir = test_support::TempDir::new("zpaq-rs-size-bytes-os");
+        let path = dir.join(OsString::from_vec(b"caf\xe9.txt".to_vec()));
+        std::fs::write(&path, test_support::text(20_000)).expect("write");
+        let size = zpaq_add_archive_size_file(&path, "1", 1).expect("size");
+        assert!(size > 0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn deterministic_adds_are_bit_identical() {
+        let dir = test_support::TempDir::new("zpaq-rs-deterministic");
+        let tree = dir.join("tree");
+        let version = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let options = ZpaqAddOptions {
+            method: "2".into(),
+            threads: 1,
+            deterministic: DeterministicOptions::pinned(version),
+            ..Default::default()
+        };
+
+        let mut digests = Vec::new();
+        for (round, mtime) in [1_600_000_000u64, 1_650_000_000].into_iter().enumerate() {
+            let _ = std::fs::remove_dir_all(&tree);
+            let _ = std::fs::remove_file(dir.join("out.zpaq"));
+            std::fs::create_dir_all(tree.join("sub")).expect("create tree");
+            let mut files = [tree.join("a.txt"), tree.join("sub/b.txt")];
+            for (i, path) in files.iter().enumerate() {
+                let file = std::fs::File::create(path).expect("create file");
+                (&file)
+                    .write_all(format!("file {i} of the tree\n").repeat(200).as_bytes())
+                    .expect("write file");
+                file.set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+                    .expect("set mtime");
+            }
+            if round == 1 {
+                files.reverse();
+            }
+            let archive = dir.join("out.zpaq").to_string_lossy().into_owned();
+            let inputs: Vec<String> = files
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect();
+            let inputs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+            zpaq_add_opts(&archive, &inputs, &options).expect("add");
+            // A second version may not be dated at or before the first.
+            assert!(zpaq_add_opts(&archive, &inputs, &options).is_err());
+            digests.push(sha256(&std::fs::read(&archive).expect("read archive")).expect("sha256"));
+        }
+        assert_eq!(digests[0], digests[1]);
+
+        let in_memory = |mtime: u64, reverse: bool| {
+            let mut entries = vec![
+                ArchiveEntryOwned::from_bytes("a.txt", "alpha"),
+                ArchiveEntryOwned::from_bytes("b.txt", "bravo").with_unix_mode(0o100600),
+            ];
+            if reverse {
+                entries.reverse();
+            }
+            let entries = entries.into_iter().map(|e| {
+                e.with_mtime(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+            });
+            archive_from_owned_entries_with(entries, "1", &DeterministicOptions::pinned(version))
+                .expect("archive")
+        };
+        assert_eq!(
+            in_memory(1_600_000_000, false),
+            in_memory(1_650_000_000, true)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rate_limited_adds_read_no_faster_than_the_limit() {
+        let dir = test_support::TempDir::new("zpaq-rs-rate-limit");
+        let input = dir.join("input.txt");
+        let data: Vec<u8> = (0..)
+            .flat_map(|i: u32| format!("{} {}\n", i, i.wrapping_mul(2_654_435_761)).into_bytes())
+            .take(2 << 20)
+            .collect();
+        std::fs::write(&input, &data).expect("write input");
+        let input = input.to_string_lossy().into_owned();
+
+        let add = |name: &str, rate_limit: Option<RateLimit>| {
+            let archive = dir.join(name).to_string_lossy().into_owned();
+            let options = ZpaqAddOptions {
+                threads: 2,
+                rate_limit,
+                ..Default::default()
+            };
+            let started = std::time::Instant::now();
+            zpaq_add_opts(&archive, &[&input], &options).expect("add");
+            let elapsed = started.elapsed().as_secs_f64();
+            let archive = std::fs::read(&archive).expect("read archive");
+            let entries = archive_list_entries(&archive).expect("list");
+            assert_eq!(entries[0].size, data.len() as u64);
+            elapsed
+        };
+        let limited = add("limited.zpaq", Some(RateLimit::new(1 << 20)));
+        let unlimited = add("unlimited.zpaq", None);
+        assert!(limited > 1.8, "{limited}s limited");
+        assert!(unlimited < limited / 2.0, "{unlimited}s unlimited");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn adds_and_extractions_report_progress() {
+        let dir = test_support::TempDir::new("zpaq-rs-progress");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("sub")).expect("create tree");
+        let data: Vec<u8> = (0..)
+            .flat_map(|i: u32| format!("{} {}\n", i, i.wrapping_mul(2_654_435_761)).into_bytes())
+            .take(3 << 20)
+            .collect();
+        std::fs::write(tree.join("a.txt"), &data).expect("write");
+        std::fs::write(tree.join("sub/b.txt"), &data[..1 << 20]).expect("write");
+        let total = data.len() as u64 + (1 << 20);
+        let archive = dir.join("out.zpaq").to_string_lossy().into_owned();
+
+        let recorder = || {
+            let seen = Arc::new(Mutex::new(Vec::<Progress>::new()));
+            let log = seen.clone();
+            let callback = ProgressCallback::new(move |p| log.lock().unwrap().push(*p))
+                .interval(ProgressInterval::Bytes(256 << 10));
+            (callback, seen)
+        };
+        let (callback, seen) = recorder();
+        let options = ZpaqAddOptions {
+            progress: Some(callback),
+            ..Default::default()
+        };
+        zpaq_add_opts(&archive, &[&tree.to_string_lossy()], &options).expect("add");
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() >= 10, "{} reports", seen.len());
+        assert!(seen.windows(2).all(|w| w[0].bytes < w[1].bytes));
+        let last = seen.last().unwrap();
+        assert_eq!(last.total, Some(total));
+        assert!(last.bytes >= total, "{} read", last.bytes);
+
+        let (callback, seen) = recorder();
+        let restore = dir.join("restore").to_string_lossy().into_owned();
+        let options = ExtractOptions {
+            progress: Some(callback.total(total)),
+            ..Default::default()
+        };
+        zpaq_extract_opts(&archive, &["-to", &restore], &options).expect("extract");
+        let seen = seen.lock().unwrap();
+        assert!(seen.windows(2).all(|w| w[0].bytes < w[1].bytes));
+        assert_eq!(seen.last().unwrap().bytes, total);
+        assert_eq!(
+            seen.last().unwrap().remaining,
+            Some(std::time::Duration::ZERO)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn overridden_dates_are_listed_and_restored() {
+        let at = |secs: u64| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+        let dir = test_support::TempDir::new("zpaq-rs-dates");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(&tree).expect("create tree");
+        for name in ["a.txt", "b.txt"] {
+            let file = std::fs::File::create(tree.join(name)).expect("create file");
+            (&file).write_all(name.as_bytes()).expect("write file");
+            file.set_modified(at(1_100_000_000)).expect("set mtime");
+        }
+        let archive = dir.join("migrated.zpaq").to_string_lossy().into_owned();
+        let tree_s = tree.to_string_lossy().into_owned();
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            deterministic: DeterministicOptions {
+                // 2001-09-09 01:46:40 UTC
+                version_date: Some(at(1_000_000_000)),
+                ..Default::default()
+            },
+            // 2001-01-01 00:00:00 UTC
+            mtime_overrides: BTreeMap::from([(tree.join("a.txt"), at(978_307_200))]),
+            ..Default::default()
+        };
+        zpaq_add_opts(&archive, &[&tree_s], &options).expect("add");
+
+        let listing = zpaq_list(&archive, &["-all"]).expect("list");
+        assert!(
+            listing.stdout.contains("2001-09-09 01:46:40"),
+            "{}",
+            listing.stdout
+        );
+        assert!(
+            listing.stdout.contains("2001-01-01 00:00:00"),
+            "{}",
+            listing.stdout
+        );
+        let versions = zpaq_versions(&archive).expect("versions");
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].number, 1);
+        assert_eq!(versions[0].date, 20010909014640);
+        assert_eq!(versions[0].time, at(1_000_000_000));
+        assert_eq!((versions[0].added, versions[0].deleted), (3, 0));
+
+        let out = dir.join("out");
+        let out_s = out.to_string_lossy().into_owned();
+        zpaq_command(&["extract", &archive, &tree_s, "-to", &out_s]).expect("extract");
+        let mtime = |name: &str| {
+            std::fs::metadata(out.join(name))
+                .and_then(|m| m.modified())
+                .expect("mtime")
+        };
+        assert_eq!(mtime("a.txt"), at(978_307_200));
+        assert_eq!(mtime("b.txt"), at(1_100_000_000));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn os_string_arguments_reach_zpaq_unchanged() {
+        use std::ffi::OsString;
+        use std::os::unix::ffi::OsStringExt;
+
+        let dir = test_support::TempDir::new("zpaq-rs-os-args");
+        let data = dir.join("data");
+        std::fs::create_dir_all(&data).expect("create data dir");
+        // Latin-1, not valid UTF-8.
+        let name = OsString::from_vec(b"caf\xe9.txt".to_vec());
+        std::fs::write(data.join(&name), b"latin-1 name").expect("write file");
+        let archive = dir.join("os.zpaq");
+        let out = dir.join("out");
+
+        let arg = |s: &'static str| OsStr::new(s);
+        zpaq_command_os(&[arg("add"), archive.as_os_str(), data.as_os_str()]).expect("add");
+        let listing = zpaq_command_os(&[arg("list"), archive.as_os_str()]).expect("list");
+        assert!(
+            listing.stdout.contains("caf\u{fffd}.txt"),
+            "{}",
+            listing.stdout
+        );
+        zpaq_command_os(&[
+            arg("extract"),
+            archive.as_os_str(),
+            data.as_os_str(),
+            arg("-to"),
+            out.as_os_str(),
+        ])
+        .expect("extract");
+        assert_eq!(
+            std::fs::read(out.join(&name)).expect("read"),
+            b"latin-1 name"
+        );
+        assert!(matches!(
+            zpaq_command_os(&[arg("list"), OsStr::new("a\0b")]),
+            Err(ZpaqError::NulInString)
+        ));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_adds_wait_for_the_archive_lock() {
+        let dir = test_support::TempDir::new("zpaq-rs-busy");
+        let input = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, format!("{name}\n").repeat(100)).expect("write input");
+            path.to_string_lossy().into_owned()
+        };
+        let archive = dir.join("shared.zpaq").to_string_lossy().into_owned();
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            ..Default::default()
+        };
+        zpaq_add_opts(&archive, &[&input("a.txt")], &options).expect("first add");
+
+        let held = File::open(&archive).expect("open archive");
+        held.lock().expect("lock archive");
+        let fail_fast = ZpaqAddOptions {
+            lock: ArchiveLock::FailIfBusy,
+            ..options.clone()
+        };
+        match zpaq_add_opts(&archive, &[&input("b.txt")], &fail_fast) {
+            Err(ZpaqError::ArchiveBusy { path }) => assert_eq!(path, archive),
+            other => panic!("expected ArchiveBusy, got {other:?}"),
+        }
+
+        let adds: Vec<_> = ["b.txt", "c.txt"]
+            .map(|name| {
+                let (archive, input, options) = (archive.clone(), input(name), options.clone());
+                std::thread::spawn(move || zpaq_add_opts(&archive, &[&input], &options))
+            })
+            .into_iter()
+            .collect();
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(adds.iter().all(|add| !add.is_finished()));
+        drop(held);
+        for add in adds {
+            add.join().expect("join").expect("add");
+        }
+
+        let report = verify_archive_file(&archive, &VerifyOptions::default()).expect("verify");
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(zpaq_versions(&archive).expect("versions").len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn concurrent_first_adds_take_turns() {
+        let dir = test_support::TempDir::new("zpaq-rs-first-adds");
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            ..Default::default()
+        };
+        for name in ["new.zpaq", "parts???.zpaq"] {
+            let archive = dir.join(name).to_string_lossy().into_owned();
+            let adds: Vec<_> = (0..4)
+                .map(|i| {
+                    let input = dir.join(format!("{i}-{}.txt", &name[..3]));
+                    std::fs::write(&input, format!("input {i}\n").repeat(20_000))
+                        .expect("write input");
+                    let (archive, options) = (archive.clone(), options.clone());
+                    std::thread::spawn(move || {
+                        zpaq_add_opts(&archive, &[&input.to_string_lossy()], &options)
+                    })
+                })
+                .collect();
+            for add in adds {
+                add.join().expect("join").expect("add");
+            }
+            let report = verify_archive_file(&archive, &VerifyOptions::default()).expect("verify");
+            assert!(report.is_ok(), "{name}: {:?}", report.errors);
+            assert_eq!(
+                zpaq_versions(&archive).expect("versions").len(),
+                4,
+                "{name}"
+            );
+        }
+        assert!(!dir.join("parts001.zpaq.lock").exists());
+
+        // A first add that fails leaves no archive behind.
+        let archive = dir.join("failed.zpaq").to_string_lossy().into_owned();
+        let missing = dir.join("missing").to_string_lossy().into_owned();
+        let gone = ZpaqAddOptions {
+            temp_dir: Some(dir.join("gone")),
+            ..options.clone()
+        };
+        assert!(zpaq_add_opts(&archive, &[&missing], &gone).is_err());
+        assert!(!std::path::Path::new(&archive).exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn verified_adds_report_damage_in_the_new_version() {
+        let dir = test_support::TempDir::new("zpaq-rs-verified");
+        let input = dir.join("data.bin");
+        let input = input.to_str().expect("utf-8 path");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let write = |round: u64| {
+            // Sizes differ, as zpaq skips files of unchanged size and date.
+            let data: Vec<u8> = (0..200_000 + round * 1000)
+                .map(|i| (i.wrapping_mul(2654435761 + round) >> 13) as u8)
+                .collect();
+            std::fs::write(input, data).expect("write input");
+        };
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            verify_after_add: true,
+            ..Default::default()
+        };
+
+        let archive = path("checked.zpaq");
+        for round in 0..2 {
+            write(round);
+            let report = zpaq_add_report(&archive, &[input], &options).expect("add");
+            assert!(report.verified, "{:?}", report.mismatches);
+            assert!(report.mismatches.is_empty());
+        }
+        let unchecked = ZpaqAddOptions {
+            verify_after_add: false,
+            ..options.clone()
+        };
+        write(2);
+        assert!(
+            !zpaq_add_report(&archive, &[input], &unchecked)
+                .unwrap()
+                .verified
+        );
+
+        // Damage between the add and the check is reported for the new
+        // version only.
+        fn truncate(archive: &str) {
+            let file = OpenOptions::new().write(true).open(archive).unwrap();
+            let len = file.metadata().unwrap().len();
+            file.set_len(len - 200).unwrap();
+        }
+        let before = std::fs::metadata(&archive).unwrap().len();
+        BEFORE_VERIFY.set(Some(truncate));
+        write(3);
+        let report = zpaq_add_report(&archive, &[input], &options).expect("add");
+        assert!(!report.verified);
+        assert!(!report.mismatches.is_empty());
+        assert!(
+            report
+                .mismatches
+                .iter()
+                .all(|m| m.location.offset >= before)
+        );
+        let damaged = path("damaged.zpaq");
+        assert!(matches!(
+            zpaq_add_opts(&damaged, &[input], &options),
+            Err(ZpaqError::ChecksumMismatch)
+        ));
+        BEFORE_VERIFY.set(None);
+
+        let indexed = ZpaqAddOptions {
+            index: Some(dir.join("index.zpaq")),
+            ..options
+        };
+        assert!(zpaq_add_opts(&path("part??.zpaq"), &[input], &indexed).is_err());
+    }
+
+    /// Every byte value, line ending and Ctrl-Z across 1 MiB, which a file
+    /// read or written in text mode on Windows would not survive.
+    fn every_byte() -> Vec<u8> {
+        let mut data: Vec<u8> = (0..=255u8).cycle().take(1 << 20).collect();
+        for (i, pair) in data.chunks_exact_mut(2).enumerate().step_by(97) {
+            pair.copy_from_slice([b"\r\n", b"\n\r", b"\x1a\n"][i % 3]);
+        }
+        data
+    }
+
+    #[test]
+    fn added_files_round_trip_every_byte_value() {
+        let dir = test_support::TempDir::new("zpaq-rs-binary");
+        let input = dir.join("bytes.bin");
+        let input = input.to_str().expect("utf-8 path");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let data = every_byte();
+        std::fs::write(input, &data).expect("write input");
+
+        let archive = path("binary.zpaq");
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            verify_after_add: true,
+            ..Default::default()
+        };
+        let report = zpaq_add_report(&archive, &[input], &options).expect("add");
+        assert!(report.verified, "{:?}", report.mismatches);
+
+        let out = path("extracted.bin");
+        zpaq_command(&["extract", &archive, input, "-to", &out]).expect("extract");
+        assert!(std::fs::read(&out).expect("read extracted") == data);
+
+        let mut reader = ArchiveReader::open(File::open(&archive).expect("open")).expect("reader");
+        let stored = reader.read(&input.replace('\\', "/")).expect("read member");
+        assert!(stored == data);
+    }
+
+    #[test]
+    fn verified_adds_report_files_stored_with_another_size() {
+        let dir = test_support::TempDir::new("zpaq-rs-altered");
+        let input = dir.join("bytes.bin");
+        let input = input.to_str().expect("utf-8 path");
+        std::fs::write(input, every_byte()).expect("write input");
+
+        // As if the add had read the file with its line endings translated.
+        fn alter(archive: &str) {
+            let input = std::path::Path::new(archive).with_file_name("bytes.bin");
+            let mut data = std::fs::read(&input).unwrap();
+            data.retain(|&b| b != b'\r');
+            std::fs::write(&input, data).unwrap();
+        }
+        BEFORE_VERIFY.set(Some(alter));
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            verify_after_add: true,
+            ..Default::default()
+        };
+        let archive = dir.join("altered.zpaq").to_string_lossy().into_owned();
+        let report = zpaq_add_report(&archive, &[input], &options).expect("add");
+        BEFORE_VERIFY.set(None);
+        assert!(!report.verified);
+        let [mismatch] = &report.mismatches[..] else {
+            panic!("{:?}", report.mismatches);
+        };
+        let altered = std::fs::metadata(input).unwrap().len();
+        assert_eq!(
+            mismatch.location.file.as_deref(),
+            Some(&*input.replace('\\', "/"))
+        );
+        assert_eq!(
+            mismatch.kind,
+            VerifyErrorKind::SizeMismatch {
+                expected: 1 << 20,
+                actual: altered,
+            }
+        );
+    }
+
+    #[test]
+    fn durable_adds_match_plain_adds() {
+        let dir = test_support::TempDir::new("zpaq-rs-durable");
+        let input = dir.join("data.txt");
+        let input = input.to_str().expect("utf-8 path");
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let date = |secs: u64| SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(secs);
+
+        let durabilities = [
+            DurabilityOptions::default(),
+            DurabilityOptions {
+                fsync: true,
+                atomic: false,
+            },
+            DurabilityOptions {
+                fsync: false,
+                atomic: true,
+            },
+        ];
+        let mut archives = Vec::new();
+        for (i, durability) in durabilities.into_iter().enumerate() {
+            let archive = path(&format!("out{i}.zpaq"));
+            for (round, secs) in [1_700_000_000, 1_700_086_400].into_iter().enumerate() {
+                std::fs::write(input, format!("round {round}\n").repeat(100)).expect("write");
+                let options = ZpaqAddOptions {
+                    method: "1".into(),
+                    threads: 1,
+                    deterministic: DeterministicOptions::pinned(date(secs)),
+                    durability,
+                    ..Default::default()
+                };
+                zpaq_add_opts(&archive, &[input], &options).expect("add");
+            }
+            assert!(!std::path::Path::new(&format!("{archive}.zpaq-rs-tmp")).exists());
+            archives.push(std::fs::read(&archive).expect("read archive"));
+        }
+        assert_eq!(archives[0], archives[1]);
+        assert_eq!(archives[0], archives[2]);
+
+        // A failed atomic add leaves the archive as it was.
+        let archive = path("out2.zpaq");
+        let stale = ZpaqAddOptions {
+            deterministic: DeterministicOptions::pinned(date(1_600_000_000)),
+            durability: DurabilityOptions {
+                fsync: false,
+                atomic: true,
+            },
+            ..Default::default()
+        };
+        assert!(zpaq_add_opts(&archive, &[input], &stale).is_err());
+        assert_eq!(std::fs::read(&archive).expect("read archive"), archives[2]);
+        assert!(!std::path::Path::new(&format!("{archive}.zpaq-rs-tmp")).exists());
+
+        // With an index, the part is written in place and the index renamed.
+        let parts = path("part??.zpaq");
+        let index = path("local.zpaq");
+        let indexed = ZpaqAddOptions {
+            method: "1".into(),
+            index: Some(index.clone().into()),
+            durability: DurabilityOptions {
+                fsync: true,
+                atomic: true,
+            },
+            ..Default::default()
+        };
+        for round in 0..2 {
+            std::fs::write(input, format!("indexed {round}\n").repeat(100)).expect("write");
+            zpaq_add_opts(&parts, &[input], &indexed).expect("indexed add");
+        }
+        assert!(!std::path::Path::new(&format!("{index}.zpaq-rs-tmp")).exists());
+        assert_eq!(index_versions(&index).expect("index versions"), 2);
+        verify_index_matches_parts(&index, &parts).expect("index matches parts");
+
+        let unindexed = ZpaqAddOptions {
+            index: None,
+            ..indexed
+        };
+        assert!(zpaq_add_opts(&parts, &[input], &unindexed).is_err());
+        assert!(!std::path::Path::new(&path("part03.zpaq")).exists());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_and_unpaired_surrogate_paths_round_trip() {
+        use std::ffi::OsString;
+        use std::os::windows::ffi::OsStringExt;
+
+        let dir = test_support::TempDir::new("zpaq-rs-long");
+        let mut deep = dir.join("data");
+        while deep.as_os_str().len() < 320 {
+            deep.push("a-rather-long-directory-name");
+        }
+        std::fs::create_dir_all(&deep).expect("create deep dir");
+        std::fs::write(deep.join("leaf.txt"), b"deep").expect("write leaf");
+        // "x" followed by a lone high surrogate.
+        let odd = OsString::from_wide(&[u16::from(b'x'), 0xd800]);
+        std::fs::write(dir.join("data").join(&odd), b"odd").expect("write odd");
+        let archive = dir.join("long.zpaq");
+        let out = dir.join("out");
+
+        let arg = |s: &'static str| OsStr::new(s);
+        let data = dir.join("data");
+        zpaq_command_os(&[arg("add"), archive.as_os_str(), data.as_os_str()]).expect("add");
+        let listing = zpaq_command_os(&[arg("list"), archive.as_os_str()]).expect("list");
+        assert!(listing.stdout.contains("leaf.txt"), "{}", listing.stdout);
+        zpaq_command_os(&[
+            arg("extract"),
+            archive.as_os_str(),
+            data.as_os_str(),
+            arg("-to"),
+            out.as_os_str(),
+        ])
+        .expect("extract");
+        let relative = deep.strip_prefix(&data).expect("below data");
+        assert_eq!(
+            std::fs::read(out.join(relative).join("leaf.txt")).expect("read leaf"),
+            b"deep"
+        );
+        assert_eq!(std::fs::read(out.join(&odd)).expect("read odd"), b"odd");
+    }
+
+    #[test]
+    fn counted_writer_and_reader_pass_bytes_and_errors_through() {
+        struct Failing {
+            accepted: usize,
+            flushed: bool,
+        }
+        impl Write for Failing {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.accepted >= 10 {
+                    return Err(std::io::Error::other("disk full"));
+                }
+                // Short writes of at most 4 bytes.
+                let n = buf.len().min(4).min(10 - self.accepted);
+                self.accepted += n;
+                Ok(n)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                self.flushed = true;
+                Ok(())
+            }
+        }
+
+        let mut out = CountingWriter::wrap(Failing {
+            accepted: 0,
+            flushed: false,
+        });
+        let err = out.write_all(&[0u8; 32]).expect_err("inner error");
+        assert_eq!(err.to_string(), "disk full");
+        assert_eq!(out.bytes_written(), 10);
+        out.flush().expect("flush");
+        assert!(out.into_inner().flushed);
+
+        let data: Vec<u8> = (0..50_000).map(|i| (i * 31 % 251) as u8).collect();
+        let mut compressed = CountedWriter::new(Vec::new());
+        compress_stream(&data[..], &mut compressed, "1", None, None).expect("compress");
+        assert_eq!(
+            compressed.bytes_written(),
+            compressed.get_ref().len() as u64
+        );
+        let compressed = compressed.into_inner();
+
+        // Trailing bytes after the archive are not part of it, but are read.
+        let mut input = compressed.clone();
+        input.extend_from_slice(b"trailer");
+        let mut reader = CountingReader::new(&input[..]);
+        let mut out = CountedWriter::new(Vec::new());
+        decompress_stream(&mut reader, &mut out).expect("decompress");
+        assert_eq!(out.bytes_written(), data.len() as u64);
+        assert!(reader.bytes_read() >= compressed.len() as u64);
+        assert!(reader.bytes_read() <= input.len() as u64);
+
+        struct FailingReader;
+        impl Read for FailingReader {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("unreadable"))
+            }
+        }
+        let mut failing = CountingReader::new(FailingReader);
+        assert!(failing.read(&mut [0u8; 8]).is_err());
+        assert_eq!(failing.bytes_read(), 0);
+    }
+
+    #[test]
+    fn io_failures_keep_the_underlying_error() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::StorageFull,
+                    "disk full",
+                ))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let entries = [ArchiveEntry {
+            path: "a.txt",
+            data: b"alpha",
+            comment: None,
+        }];
+        let archive = archive_from_entries(&entries, "1").expect("create");
+        let err = archive_read_file_to(&archive, "a.txt", Full).unwrap_err();
+        let ZpaqError::IoError(io) = &err else {
+            panic!("{err:?}");
+        };
+        assert_eq!(io.kind(), std::io::ErrorKind::StorageFull);
+        assert_eq!(err.to_string(), "I/O error: disk full");
+        let source = std::error::Error::source(&err).expect("source");
+        assert_eq!(source.to_string(), "disk full");
+        assert_eq!(err.clone(), err);
+        assert_ne!(
+            err,
+            io_err(std::io::Error::new(
+                std::io::ErrorKind::StorageFull,
+                "quota"
+            ))
+        );
     }
 }
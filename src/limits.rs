@@ -0,0 +1,340 @@
+//! Decompression with bounds on model memory, output size and block count.
+//!
+//! A few hundred bytes of ZPAQ can declare a model needing gigabytes of
+//! memory or decode to terabytes of output, and both are valid archives.
+//! The `_limited` functions check each block's memory figure from its header
+//! before the model is allocated, and count output as it is produced, so
+//! untrusted input fails early with [`ZpaqError::LimitExceeded`].
+
+use std::io::{Read, Write};
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{
+    FfiReader, FfiWriter, Result, ZpaqError, clear_last_error, err_from_last, handle, sys, trace,
+};
+
+/// Bytes decoded per call between output checks.
+const CHUNK: i32 = 1 << 16;
+
+/// Bounds for decompressing untrusted data with
+/// [`decompress_stream_limited`] and its variants.
+///
+/// Each limit is unlimited when `None`, and the default is unlimited
+/// throughout, so the functions then behave like their unlimited
+/// counterparts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecompressLimits {
+    /// Most memory one block's model may need, as libzpaq computes it from
+    /// the block header (see [`BlockInfo::memory_bytes`]).  A block over the
+    /// limit fails before its model is allocated.
+    ///
+    /// [`BlockInfo::memory_bytes`]: crate::BlockInfo::memory_bytes
+    pub max_model_bytes: Option<u64>,
+    /// Most bytes the whole stream may decompress to.  Decoding stops
+    /// shortly after the limit is passed; bytes past it are not written.
+    pub max_output_bytes: Option<u64>,
+    /// Most blocks the stream may contain.
+    pub max_blocks: Option<u32>,
+}
+
+/// Which of the [`DecompressLimits`] a [`ZpaqError::LimitExceeded`] reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LimitKind {
+    /// [`DecompressLimits::max_model_bytes`].
+    ModelBytes,
+    /// [`DecompressLimits::max_output_bytes`].
+    OutputBytes,
+    /// [`DecompressLimits::max_blocks`].
+    Blocks,
+}
+
+/// Decompresses like [`decompress_stream`](crate::decompress_stream) within
+/// `limits`.
+///
+/// When a limit trips, `writer` holds the output decoded so far, never more
+/// than [`DecompressLimits::max_output_bytes`].
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{DecompressLimits, LimitKind, ZpaqError};
+///
+/// let c = zpaq_rs::compress_to_vec(&[0u8; 100_000], "1").unwrap();
+/// let limits = DecompressLimits {
+///     max_output_bytes: Some(1000),
+///     ..Default::default()
+/// };
+/// let mut out = Vec::new();
+/// let err = zpaq_rs::decompress_stream_limited(&c[..], &mut out, &limits).unwrap_err();
+/// assert!(matches!(
+///     err,
+///     ZpaqError::LimitExceeded { limit: LimitKind::OutputBytes, max: 1000 }
+/// ));
+/// assert_eq!(out.len(), 1000);
+/// ```
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::LimitExceeded`] naming the limit that tripped.
+pub fn decompress_stream_limited<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    limits: &DecompressLimits,
+) -> Result<()> {
+    decompress_limited("decompress_stream_limited", reader, writer, limits).map(|_| ())
+}
+
+/// Decompresses `input` into a `Vec<u8>` within `limits`.
+///
+/// See [`decompress_stream_limited`].
+pub fn decompress_to_vec_limited(input: &[u8], limits: &DecompressLimits) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    decompress_limited("decompress_to_vec_limited", input, &mut out, limits)?;
+    Ok(out)
+}
+
+/// Returns the decompressed size of `input`, like
+/// [`decompress_size`](crate::decompress_size), within `limits`.
+///
+/// See [`decompress_stream_limited`].
+pub fn decompress_size_limited(input: &[u8], limits: &DecompressLimits) -> Result<u64> {
+    decompress_limited("decompress_size_limited", input, std::io::sink(), limits)
+}
+
+/// Decodes `reader` to `writer` block by block, checking `limits`, and
+/// returns the number of bytes decoded.
+fn decompress_limited<R: Read + Send, W: Write + Send>(
+    operation: &'static str,
+    reader: R,
+    writer: W,
+    limits: &DecompressLimits,
+) -> Result<u64> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress(operation);
+    let max_output = limits.max_output_bytes.unwrap_or(u64::MAX);
+    let output_exceeded = || ZpaqError::LimitExceeded {
+        limit: LimitKind::OutputBytes,
+        max: max_output,
+    };
+    let offered = Arc::new(AtomicU64::new(0));
+    let reader = FfiReader::new(reader)?;
+    let writer = FfiWriter::new(LimitedWriter {
+        inner: writer,
+        max: max_output,
+        offered: Arc::clone(&offered),
+    })?;
+    let decompresser = handle::Decompresser::new()?;
+    let over_output = || offered.load(Ordering::Relaxed) > max_output;
+
+    let result = (|| {
+        if unsafe { sys::zpaq_decompresser_set_input(decompresser.raw, reader.raw) } != 0
+            || unsafe { sys::zpaq_decompresser_set_output(decompresser.raw, writer.raw) } != 0
+        {
+            return Err(err_from_last());
+        }
+        let mut blocks = 0u64;
+        loop {
+            let mut memory = 0.0;
+            let rc_block =
+                unsafe { sys::zpaq_decompresser_find_block(decompresser.raw, &mut memory) };
+            if rc_block < 0 {
+                return Err(err_from_last());
+            }
+            if rc_block == 0 {
+                return Ok(());
+            }
+            blocks += 1;
+            if let Some(max) = limits.max_blocks
+                && blocks > u64::from(max)
+            {
+                return Err(ZpaqError::LimitExceeded {
+                    limit: LimitKind::Blocks,
+                    max: max.into(),
+                });
+            }
+            if let Some(max) = limits.max_model_bytes
+                && memory > max as f64
+            {
+                return Err(ZpaqError::LimitExceeded {
+                    limit: LimitKind::ModelBytes,
+                    max,
+                });
+            }
+            loop {
+                let rc_filename = unsafe {
+                    sys::zpaq_decompresser_find_filename(decompresser.raw, ptr::null_mut())
+                };
+                if rc_filename < 0 {
+                    return Err(err_from_last());
+                }
+                if rc_filename == 0 {
+                    break;
+                }
+                if unsafe { sys::zpaq_decompresser_read_comment(decompresser.raw, ptr::null_mut()) }
+                    != 0
+                {
+                    return Err(err_from_last());
+                }
+                loop {
+                    let rc = unsafe { sys::zpaq_decompresser_decompress(decompresser.raw, CHUNK) };
+                    if rc < 0 {
+                        return Err(err_from_last());
+                    }
+                    if over_output() {
+                        return Err(output_exceeded());
+                    }
+                    if rc == 0 {
+                        break;
+                    }
+                }
+                if unsafe {
+                    sys::zpaq_decompresser_read_segment_end(decompresser.raw, ptr::null_mut())
+                } != 0
+                {
+                    return Err(err_from_last());
+                }
+            }
+        }
+    })();
+    drop(decompresser);
+    // Dropping the writer flushes the last buffered output.
+    drop(writer);
+    drop(reader);
+    result?;
+    if over_output() {
+        return Err(output_exceeded());
+    }
+    Ok(offered.load(Ordering::Relaxed))
+}
+
+/// Forwards at most `max` bytes to `inner`, counting every byte offered.
+struct LimitedWriter<W> {
+    inner: W,
+    max: u64,
+    offered: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let before = self.offered.fetch_add(buf.len() as u64, Ordering::Relaxed);
+        let keep = (buf.len() as u64).min(self.max.saturating_sub(before)) as usize;
+        self.inner.write_all(&buf[..keep])?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, decompress_to_vec, peek_block};
+
+    fn limits() -> DecompressLimits {
+        DecompressLimits::default()
+    }
+
+    #[test]
+    fn defaults_decompress_everything() {
+        let data = b"nothing to limit here ".repeat(5000);
+        let c = compress_to_vec(&data, "2").expect("compress");
+        assert_eq!(
+            decompress_to_vec_limited(&c, &limits()).expect("decompress"),
+            data
+        );
+        assert_eq!(
+            decompress_size_limited(&c, &limits()).expect("size"),
+            data.len() as u64
+        );
+        let exact = DecompressLimits {
+            max_output_bytes: Some(data.len() as u64),
+            max_blocks: Some(1),
+            ..limits()
+        };
+        assert_eq!(
+            decompress_to_vec_limited(&c, &exact).expect("decompress"),
+            data
+        );
+    }
+
+    #[test]
+    fn high_memory_model_is_rejected_before_decoding() {
+        // Method 5 declares large context models even for a few bytes.
+        let c = compress_to_vec(b"tiny input, huge model", "5").expect("compress");
+        let memory = peek_block(&c).expect("peek").expect("block").memory_bytes;
+        assert!(memory > (64 << 20) as f64, "model needs {memory} bytes");
+
+        let strict = DecompressLimits {
+            max_model_bytes: Some(64 << 20),
+            ..limits()
+        };
+        let mut out = Vec::new();
+        match decompress_stream_limited(&c[..], &mut out, &strict) {
+            Err(ZpaqError::LimitExceeded {
+                limit: LimitKind::ModelBytes,
+                max,
+            }) => assert_eq!(max, 64 << 20),
+            other => panic!("expected model limit, got {other:?}"),
+        }
+        assert!(out.is_empty());
+
+        let roomy = DecompressLimits {
+            max_model_bytes: Some(memory as u64 + 1),
+            ..limits()
+        };
+        assert_eq!(
+            decompress_to_vec_limited(&c, &roomy).expect("decompress"),
+            decompress_to_vec(&c).expect("decompress")
+        );
+    }
+
+    #[test]
+    fn output_cap_stops_a_bomb_early() {
+        // One 8 MiB block of zeros, repeated into a 2 GiB stream.
+        let block = compress_to_vec(&vec![0u8; 8 << 20], "1").expect("compress");
+        let bomb = block.repeat(256);
+        assert!(bomb.len() < 1 << 20);
+
+        let capped = DecompressLimits {
+            max_output_bytes: Some(1 << 20),
+            ..limits()
+        };
+        let mut out = Vec::new();
+        let err = decompress_stream_limited(&bomb[..], &mut out, &capped).unwrap_err();
+        assert!(matches!(
+            err,
+            ZpaqError::LimitExceeded {
+                limit: LimitKind::OutputBytes,
+                max: 0x10_0000
+            }
+        ));
+        assert_eq!(out.len(), 1 << 20);
+        assert!(out.iter().all(|&b| b == 0));
+        assert!(matches!(
+            decompress_size_limited(&bomb, &capped),
+            Err(ZpaqError::LimitExceeded {
+                limit: LimitKind::OutputBytes,
+                ..
+            })
+        ));
+
+        let few_blocks = DecompressLimits {
+            max_blocks: Some(2),
+            ..limits()
+        };
+        out.clear();
+        let err = decompress_stream_limited(&bomb[..], &mut out, &few_blocks).unwrap_err();
+        assert!(matches!(
+            err,
+            ZpaqError::LimitExceeded {
+                limit: LimitKind::Blocks,
+                max: 2
+            }
+        ));
+        assert_eq!(out.len(), 2 * (8 << 20));
+    }
+}
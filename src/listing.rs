@@ -0,0 +1,381 @@
+//! Listing archives that can only be read once, front to back.
+
+use std::collections::HashMap;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use crate::display;
+use crate::journal::EntryMeta;
+use crate::reader::{SegmentIndex, check_scan};
+use crate::verify::{ArchiveSource, HashTable, IndexEntry, Scan, SegmentSink};
+use crate::{ArchiveEntryInfo, ArchiveEntryVersion, Result, VerifyLocation, ZpaqError};
+
+/// The files of an archive, as listed by [`list_stream`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchiveListing {
+    /// Number of versions of a journaling archive (0 for streaming
+    /// archives).
+    pub versions: usize,
+    /// The files of the latest version, sorted by path, as
+    /// [`archive_list_entries`](crate::archive_list_entries) lists them.
+    pub entries: Vec<ArchiveEntryInfo>,
+}
+
+/// A table of size, date and path, sizes aligned, then the totals.  A
+/// precision (`{:.40}`) shortens long paths.
+impl std::fmt::Display for ArchiveListing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let width = display::size_width(self.entries.iter().map(|entry| entry.size));
+        for entry in &self.entries {
+            let date = entry.date.map_or_else(|| "-".to_string(), display::date);
+            writeln!(
+                f,
+                "{:>width$}  {date:<19}  {}",
+                entry.size,
+                display::shorten(&entry.path, f.precision())
+            )?;
+        }
+        let total: u64 = self.entries.iter().map(|entry| entry.size).sum();
+        writeln!(
+            f,
+            "{}, {}, {}",
+            display::count(self.entries.len() as u64, "file"),
+            display::count(total, "byte"),
+            display::count(self.versions as u64, "version")
+        )
+    }
+}
+
+/// Lists the unencrypted archive read from `reader` in a single forward
+/// pass, so it may come from a pipe or socket.
+///
+/// Journaling archives keep their index in `c`, `h` and `i` blocks between
+/// the data, so only those are decoded and the data is skipped as it goes
+/// by.  Streaming archives are decoded segment by segment, as by
+/// [`archive_list_entries`](crate::archive_list_entries).  No file data is
+/// kept, only the table of files.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// // For example `ssh host cat backup.zpaq | my-tool`.
+/// for entry in zpaq_rs::list_stream(std::io::stdin())?.entries {
+///     println!("{:>12} {}", entry.size, entry.path);
+/// }
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails on any damage `zpaq` would not silently skip: unlike
+/// [`ArchiveReader::open`](crate::ArchiveReader::open), a pass that cannot
+/// seek cannot resume after a damaged block.
+pub fn list_stream<R: Read + Send>(reader: R) -> Result<ArchiveListing> {
+    list_stream_each(reader, |_| {})
+}
+
+/// Like [`list_stream`], also passing each file version to `on_entry` as
+/// the pass reaches it: for journaling archives every entry of each `i`
+/// block, deletions included, and for streaming archives each file once
+/// its last segment is decoded.  Versions are numbered as by
+/// [`archive_entries`](crate::archive_entries); directories are omitted.
+pub fn list_stream_each<R: Read + Send>(
+    reader: R,
+    mut on_entry: impl FnMut(ArchiveEntryVersion),
+) -> Result<ArchiveListing> {
+    let mut source = ArchiveSource::new(Forward::new(BufReader::new(reader)), None)?;
+    let mut sink = ListSink {
+        segments: SegmentIndex::default(),
+        reported: 0,
+        sizes: HashMap::new(),
+        tables: 0,
+        error: None,
+        on_entry: &mut on_entry,
+    };
+    let mut scan = Scan::with_sink(&mut sink);
+    let scanned = scan.scan(&mut source);
+    check_scan(&scan)?;
+    scanned?;
+    let versions = scan.versions();
+    let live: Vec<(String, u64, Vec<u8>, Vec<u32>)> = scan
+        .files_at(versions)
+        .into_iter()
+        .filter(|(path, _)| !path.ends_with('/'))
+        .map(|(path, entry)| {
+            let (attr, fragments) = (entry.attr.clone(), entry.fragments.clone());
+            (path.to_string(), entry.date, attr, fragments)
+        })
+        .collect();
+    drop(scan);
+    if let Some(error) = sink.error.take() {
+        return Err(error);
+    }
+    sink.report(true);
+
+    let entries = if versions == 0 {
+        sink.segments
+            .files
+            .into_values()
+            .map(|(info, _)| info)
+            .collect()
+    } else {
+        live.into_iter()
+            .map(|(path, date, attr, fragments)| {
+                Ok(ArchiveEntryInfo {
+                    size: sink.file_size(&path, &fragments)?,
+                    path,
+                    date: Some(date),
+                    comment: None,
+                    meta: EntryMeta::from_index(date, &attr),
+                })
+            })
+            .collect::<Result<_>>()?
+    };
+    Ok(ArchiveListing { versions, entries })
+}
+
+/// Collects what [`list_stream_each`] needs from a scan and reports file
+/// versions as they are found.
+struct ListSink<'f> {
+    segments: SegmentIndex,
+    /// Streaming file versions passed to `on_entry` so far.
+    reported: usize,
+    /// Fragment sizes from the `h` blocks seen so far, by ID.
+    sizes: HashMap<u32, u32>,
+    /// `h` blocks whose sizes are in `sizes`.
+    tables: usize,
+    error: Option<ZpaqError>,
+    on_entry: &'f mut dyn FnMut(ArchiveEntryVersion),
+}
+
+impl ListSink<'_> {
+    /// Reports the streaming file versions found so far, except the last
+    /// unless `all`, since more segments of it may follow.
+    fn report(&mut self, all: bool) {
+        let history = &self.segments.history;
+        let done = if all {
+            history.len()
+        } else {
+            history.len().saturating_sub(1)
+        };
+        for entry in &history[self.reported.min(done)..done] {
+            (self.on_entry)(entry.clone());
+        }
+        self.reported = self.reported.max(done);
+    }
+
+    fn file_size(&self, path: &str, fragments: &[u32]) -> Result<u64> {
+        fragments.iter().try_fold(0u64, |total, id| {
+            let size = self.sizes.get(id).ok_or_else(|| {
+                ZpaqError::Ffi(format!(
+                    "{path}: fragment {id} is not described by any h block"
+                ))
+            })?;
+            Ok(total + *size as u64)
+        })
+    }
+}
+
+impl SegmentSink for ListSink<'_> {
+    fn segment(&mut self, location: &VerifyLocation, comment: &[u8], data: Vec<u8>, intact: bool) {
+        self.segments.segment(location, comment, data, intact);
+        self.report(false);
+    }
+
+    fn damaged(&mut self, location: &VerifyLocation) {
+        self.segments.damaged(location);
+    }
+
+    fn index_entries(&mut self, entries: &[IndexEntry], tables: &[HashTable]) {
+        for table in &tables[self.tables..] {
+            for (i, &(size, _)) in table.fragments.iter().enumerate() {
+                self.sizes.insert(table.first + i as u32, size);
+            }
+        }
+        self.tables = tables.len();
+        for entry in entries {
+            if self.error.is_some() || entry.file.ends_with('/') {
+                continue;
+            }
+            let size = match self.file_size(&entry.file, &entry.fragments) {
+                Ok(size) => size,
+                Err(error) => {
+                    self.error = Some(error);
+                    continue;
+                }
+            };
+            (self.on_entry)(ArchiveEntryVersion {
+                path: entry.file.clone(),
+                size,
+                mtime: EntryMeta::from_index(entry.date, &entry.attr).mtime,
+                version: entry.version as u32,
+                deleted: entry.date == 0,
+                extra: Default::default(),
+            });
+        }
+    }
+}
+
+/// Gives a reader the [`Seek`] that [`ArchiveSource`] asks for, though only
+/// to where it already is.
+struct Forward<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R> Forward<R> {
+    fn new(inner: R) -> Self {
+        Forward { inner, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for Forward<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R> Seek for Forward<R> {
+    fn seek(&mut self, to: SeekFrom) -> std::io::Result<u64> {
+        match to {
+            SeekFrom::Start(pos) if pos == self.pos => Ok(pos),
+            SeekFrom::Current(0) => Ok(self.pos),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "cannot seek in a stream read front to back",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{
+        ArchiveEntry, archive_append_entries, archive_entries, archive_from_entries,
+        archive_list_entries, zpaq_add, zpaq_list,
+    };
+
+    /// A pipe: hands out at most `chunk` bytes per read and cannot seek.
+    struct ChunkedReader {
+        data: Vec<u8>,
+        pos: usize,
+        chunk: usize,
+    }
+
+    impl Read for ChunkedReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(self.chunk).min(self.data.len() - self.pos);
+            buf[..n].copy_from_slice(&self.data[self.pos..self.pos + n]);
+            self.pos += n;
+            Ok(n)
+        }
+    }
+
+    fn piped(data: Vec<u8>) -> ChunkedReader {
+        ChunkedReader {
+            data,
+            pos: 0,
+            chunk: 7,
+        }
+    }
+
+    /// Lists `archive` through a pipe, returning the listing and the
+    /// versions reported, sorted as `archive_entries` sorts them.
+    fn list_piped(archive: &[u8]) -> (ArchiveListing, Vec<ArchiveEntryVersion>) {
+        let mut reported = Vec::new();
+        let listing =
+            list_stream_each(piped(archive.to_vec()), |entry| reported.push(entry)).expect("list");
+        reported.sort_by(|a, b| a.path.cmp(&b.path).then(a.version.cmp(&b.version)));
+        (listing, reported)
+    }
+
+    #[test]
+    fn piped_journaling_archive_lists_like_zpaq() {
+        let dir = TempDir::new("zpaq-rs-list-stream");
+        let tree = dir.join("tree");
+        std::fs::create_dir_all(tree.join("sub")).expect("create tree");
+        std::fs::write(tree.join("a.txt"), "alpha\n".repeat(50)).expect("write a");
+        std::fs::write(tree.join("sub/b.txt"), "bravo\n".repeat(70)).expect("write b");
+        let archive = dir.join("backup.zpaq").to_string_lossy().into_owned();
+        let tree_s = tree.to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&tree_s], "1", 1).expect("add 1");
+        std::fs::remove_file(tree.join("a.txt")).expect("remove a");
+        std::fs::write(tree.join("c.txt"), "charlie\n".repeat(30)).expect("write c");
+        zpaq_add(&archive, &[&tree_s], "1", 1).expect("add 2");
+
+        let bytes = std::fs::read(&archive).expect("read archive");
+        let (listing, reported) = list_piped(&bytes);
+        assert_eq!(listing.versions, 2);
+        assert_eq!(
+            listing.entries,
+            archive_list_entries(&bytes).expect("entries")
+        );
+        assert_eq!(
+            reported,
+            archive_entries(&bytes, None, true).expect("history")
+        );
+        assert!(
+            reported
+                .iter()
+                .any(|e| e.deleted && e.path.ends_with("/a.txt"))
+        );
+
+        let cli = zpaq_list(&archive, &[]).expect("zpaq list").stdout;
+        let cli_files: Vec<(u64, &str)> = cli
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split_whitespace().collect();
+                let path = line.split_once(fields.get(4)?)?.1.trim_start();
+                (fields[0] == "-" && !path.ends_with('/'))
+                    .then(|| Some((fields[3].parse().ok()?, path)))
+                    .flatten()
+            })
+            .collect();
+        let listed: Vec<(u64, &str)> = listing
+            .entries
+            .iter()
+            .map(|e| (e.size, e.path.as_str()))
+            .collect();
+        assert_eq!(listed, cli_files, "{cli}");
+        assert_eq!(listed.len(), 2);
+    }
+
+    #[test]
+    fn piped_streaming_archive_lists_like_a_seekable_one() {
+        let entry = |path, data, comment| ArchiveEntry {
+            path,
+            data,
+            comment,
+        };
+        let mut archive = archive_from_entries(
+            &[
+                entry("a.txt", b"alpha", None),
+                entry("b.txt", &[7; 5000], Some("20240101120000 u33188")),
+            ],
+            "1",
+        )
+        .expect("archive");
+        archive_append_entries(&mut archive, &[entry("a.txt", b"alpha two", None)], "1")
+            .expect("append");
+
+        let (listing, reported) = list_piped(&archive);
+        assert_eq!(listing.versions, 0);
+        assert_eq!(
+            listing.entries,
+            archive_list_entries(&archive).expect("entries")
+        );
+        assert_eq!(
+            reported,
+            archive_entries(&archive, None, true).expect("history")
+        );
+        assert_eq!(reported.len(), 3);
+
+        let mut damaged = archive.clone();
+        damaged.truncate(archive.len() - 30);
+        assert!(archive_list_entries(&damaged).is_err());
+        assert!(list_stream(piped(damaged)).is_err());
+    }
+}
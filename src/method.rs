@@ -0,0 +1,332 @@
+//! Method strings: the spelling every function accepts, and which of them
+//! [`StreamingCompressor`](crate::StreamingCompressor) can run.
+//!
+//! A method is a level (`"1"` to `"5"`, optionally followed by the block
+//! size digit) or an explicit string starting with `x`, `s`, `i` or `0`.
+//! Surrounding whitespace is ignored and letters may be in either case, so
+//! `" X4.0CI1 "` is `"x4.0ci1"` everywhere.
+//!
+//! The digits after the first character set the block size: `2^(20+n)`
+//! bytes, with `n` at most 11, or 16 MiB without digits.
+
+use std::ffi::CString;
+
+use crate::{Result, ZpaqError};
+
+/// Why [`StreamingCompressor`](crate::StreamingCompressor) rejected a
+/// method, in [`ZpaqError::UnsupportedInStreaming`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnsupportedReason {
+    /// The method transforms each block as a whole before modelling it
+    /// (LZ77, BWT or E8E9, as levels 4 and 5 may), which cannot be done a
+    /// byte at a time.
+    NeedsPreprocessing,
+    /// The method is not a level or explicit method string.
+    UnknownMethod,
+}
+
+/// The block sizes of a method, from [`method_block_size`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockSizeInfo {
+    /// Bytes per block when the method cuts the input itself, as
+    /// [`compress_stream`](crate::compress_stream) and the parallel
+    /// compressors do: 4096 less than [`max_bytes`](Self::max_bytes).
+    pub default_bytes: u64,
+    /// The largest block the method's buffers are sized for, and the
+    /// largest block size the functions taking one accept with it.
+    pub max_bytes: u64,
+    /// `max_bytes` as a power of two, 20 to 31.
+    pub log2: u8,
+}
+
+/// Returns the block sizes `method` implies, for choosing the block size
+/// of [`compress_block_sizes`](crate::compress_block_sizes).
+///
+/// A level's block size digit follows the level, so `"1"` and `"14"` use
+/// 16 MiB blocks and `"12"` 4 MiB; an explicit string's is its first
+/// argument, so `"x6.0ci1"` uses 64 MiB.
+///
+/// # Errors
+///
+/// Fails if `method` is empty, or is neither a level (a digit, then only
+/// digits and separators, as in `"14,128,1"`) nor an explicit string
+/// starting with `x`, `s`, `i` or `0`.
+///
+/// # Example
+///
+/// ```rust
+/// let info = zpaq_rs::method_block_size("12")?;
+/// assert_eq!(info.max_bytes, 4 << 20);
+/// assert_eq!(info.default_bytes, (4 << 20) - 4096);
+/// assert_eq!(info.log2, 22);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn method_block_size(method: &str) -> Result<BlockSizeInfo> {
+    let normalized = normalize_method(method);
+    let mut chars = normalized.chars();
+    let valid = match chars.next() {
+        None => return Err(ZpaqError::Ffi("method string is empty".into())),
+        Some('x' | 's' | 'i' | '0') => true,
+        Some(c) if c.is_ascii_digit() => chars.all(|c| c.is_ascii_digit() || c == ',' || c == '.'),
+        Some(_) => false,
+    };
+    if !valid {
+        return Err(ZpaqError::Ffi(format!(
+            "method '{normalized}' is neither a level nor a string starting with x, s, i or 0"
+        )));
+    }
+    let log2 = log_block_size(&normalized);
+    Ok(BlockSizeInfo {
+        default_bytes: (1 << log2) - 4096,
+        max_bytes: 1 << log2,
+        log2: log2 as u8,
+    })
+}
+
+/// `2^n` byte blocks for `method`, as the digits after its first character
+/// set them for `libzpaq::compress` and `zpaq add` (16 MiB by default).
+pub(crate) fn log_block_size(method: &str) -> u32 {
+    let digits: String = method
+        .trim()
+        .chars()
+        .skip(1)
+        .take_while(char::is_ascii_digit)
+        .take(2)
+        .collect();
+    20 + digits.parse::<u32>().map_or(4, |n| n.min(11))
+}
+
+/// What a method runs as in streaming mode.
+pub(crate) enum StreamingMethod {
+    /// One of `libzpaq`'s built-in models 1 to 3.
+    Level(i32),
+    /// An explicit method string without preprocessing.
+    Explicit(String),
+}
+
+/// `method` without surrounding whitespace and in lowercase, as `libzpaq`
+/// and `zpaq` spell methods.
+pub(crate) fn normalize_method(method: &str) -> String {
+    method.trim().to_ascii_lowercase()
+}
+
+/// `method`, normalized, to pass to the shim.
+pub(crate) fn method_cstring(method: &str) -> Result<CString> {
+    CString::new(normalize_method(method)).map_err(|_| ZpaqError::NulInString)
+}
+
+/// Works out how [`StreamingCompressor`](crate::StreamingCompressor) runs
+/// `method`, or why it cannot, with the nearest method it can run.
+///
+/// Levels 1 to 3 use `libzpaq`'s built-in models, whatever block size digit
+/// follows them.  Levels 4 to 9 suggest the context-model-only
+/// configuration `libzpaq` itself picks for such levels on data it does not
+/// preprocess, and explicit strings with a preprocessing step suggest the
+/// same string without it.
+pub(crate) fn streaming_method(method: &str) -> Result<StreamingMethod> {
+    let method = normalize_method(method);
+    let Some(first) = method.chars().next() else {
+        return Err(ZpaqError::Ffi("method string is empty".into()));
+    };
+    let unsupported = |reason, suggested: Option<String>| ZpaqError::UnsupportedInStreaming {
+        method: method.clone(),
+        reason,
+        suggested,
+    };
+    if first.is_ascii_digit() && method.bytes().all(|b| b.is_ascii_digit()) && first != '0' {
+        let level = i32::from(first as u8 - b'0');
+        if level <= 3 {
+            return Ok(StreamingMethod::Level(level));
+        }
+        // The block size digits, as `libzpaq` reads them after the level.
+        let block = method[1..].parse::<u32>().map_or(4, |n| n.min(11));
+        let models = if level == 4 {
+            "ci1,1,1,1,2awm"
+        } else {
+            "w2c0,1010,255i1c256ci1,1,1,1,1,1,2ac0,2,0,255i1c0,3,0,0,255i1c0,4,0,0,0,255i1mm16ts19t0"
+        };
+        return Err(unsupported(
+            UnsupportedReason::NeedsPreprocessing,
+            Some(format!("x{block},0{models}")),
+        ));
+    }
+    if !matches!(first, 'x' | 's' | 'i' | '0') {
+        return Err(unsupported(UnsupportedReason::UnknownMethod, None));
+    }
+    // The preprocessing step is the second number: `x4,3ci1` is BWT.
+    let block_end = 1 + method[1..]
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(method.len() - 1);
+    let rest = &method[block_end..];
+    let step = rest
+        .strip_prefix(|c: char| c == ',' || c == '.')
+        .map(|rest| {
+            let end = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            (&rest[..end], &rest[end..])
+        });
+    match step {
+        Some((digits, models)) if digits.parse::<u32>().is_ok_and(|n| n != 0) => {
+            let separator = &rest[..1];
+            let suggested = format!("{}{separator}0{models}", &method[..block_end]);
+            Err(unsupported(
+                UnsupportedReason::NeedsPreprocessing,
+                Some(suggested),
+            ))
+        }
+        _ => Ok(StreamingMethod::Explicit(method)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        StreamingCompressor, compress_block_sizes, compress_stream_parallel, compress_to_vec,
+        decompress_to_vec,
+    };
+
+    #[test]
+    fn suggestions_run_in_streaming_mode() {
+        let data = crate::test_support::text(50_000);
+        for (method, reason, suggested) in [
+            (
+                "4",
+                UnsupportedReason::NeedsPreprocessing,
+                Some("x4,0ci1,1,1,1,2awm"),
+            ),
+            (
+                "46",
+                UnsupportedReason::NeedsPreprocessing,
+                Some("x6,0ci1,1,1,1,2awm"),
+            ),
+            ("5", UnsupportedReason::NeedsPreprocessing, None),
+            ("9", UnsupportedReason::NeedsPreprocessing, None),
+            (
+                "x4.3ci1",
+                UnsupportedReason::NeedsPreprocessing,
+                Some("x4.0ci1"),
+            ),
+            (
+                " X6,4CI1 ",
+                UnsupportedReason::NeedsPreprocessing,
+                Some("x6,0ci1"),
+            ),
+            ("s4.1", UnsupportedReason::NeedsPreprocessing, Some("s4.0")),
+            ("fast", UnsupportedReason::UnknownMethod, None),
+            ("z3", UnsupportedReason::UnknownMethod, None),
+        ] {
+            let error = StreamingCompressor::new(method).err();
+            let Some(ZpaqError::UnsupportedInStreaming {
+                reason: got,
+                suggested: got_suggested,
+                ..
+            }) = error
+            else {
+                panic!("{method}: {error:?}");
+            };
+            assert_eq!(got, reason, "{method}");
+            if let Some(suggested) = suggested {
+                assert_eq!(got_suggested.as_deref(), Some(suggested), "{method}");
+            }
+            if reason == UnsupportedReason::UnknownMethod {
+                assert_eq!(got_suggested, None, "{method}");
+                continue;
+            }
+            let suggested = got_suggested.expect("suggestion");
+            let mut streaming = StreamingCompressor::new(&suggested)
+                .unwrap_or_else(|e| panic!("{method} -> {suggested}: {e}"));
+            for &b in &data {
+                streaming.push(b).expect("push");
+            }
+            // Levels come out close to what they achieve as a block.
+            if method.bytes().all(|b| b.is_ascii_digit()) {
+                let block = compress_to_vec(&data, method).expect("compress");
+                let ratio = streaming.bits() / 8.0 / block.len() as f64;
+                assert!(ratio < 1.25, "{method} -> {suggested}: {ratio:.2}");
+            }
+        }
+    }
+
+    #[test]
+    fn methods_accept_any_case_and_surrounding_whitespace() {
+        let data = crate::test_support::text(20_000);
+        for (plain, spelled) in [("2", " 2\n"), ("x4.0ci1", "X4.0CI1"), ("0", "\t0 ")] {
+            let expected = compress_to_vec(&data, plain).expect("compress");
+            let compressed = compress_to_vec(&data, spelled).expect("compress spelled");
+            assert!(compressed == expected, "{spelled:?}");
+            assert_eq!(decompress_to_vec(&compressed).expect("decompress"), data);
+        }
+        let mut streaming = StreamingCompressor::new(" X4.0CI1 ").expect("streaming");
+        streaming.push(b'a').expect("push");
+        for method in ["13", "0", "04,0"] {
+            StreamingCompressor::new(method).unwrap_or_else(|e| panic!("{method}: {e}"));
+        }
+        assert!(matches!(
+            StreamingCompressor::new("  "),
+            Err(ZpaqError::Ffi(_))
+        ));
+    }
+
+    #[test]
+    fn block_sizes_follow_the_method() {
+        const MIB: u64 = 1 << 20;
+        for level in ["1", "2", "3", "4", "5"] {
+            let info = method_block_size(level).expect(level);
+            assert_eq!(
+                info,
+                BlockSizeInfo {
+                    default_bytes: 16 * MIB - 4096,
+                    max_bytes: 16 * MIB,
+                    log2: 24,
+                },
+                "{level}"
+            );
+        }
+        for (method, log2) in [
+            ("10", 20),
+            ("12", 22),
+            ("59", 29),
+            ("111", 31),
+            ("199", 31),
+            ("14,128,1", 24),
+            ("x6.0ci1", 26),
+            (" X4,3CI1 ", 24),
+            ("s8.0", 28),
+            ("0", 24),
+            ("04,0", 24),
+            ("i", 24),
+        ] {
+            let info = method_block_size(method).unwrap_or_else(|e| panic!("{method}: {e}"));
+            assert_eq!(info.log2, log2, "{method}");
+            assert_eq!(info.max_bytes, 1 << log2, "{method}");
+            assert_eq!(info.default_bytes, info.max_bytes - 4096, "{method}");
+        }
+        for method in ["", "  ", "fast", "z3", "1x"] {
+            assert!(method_block_size(method).is_err(), "{method:?}");
+        }
+
+        // The method's own blocks are `default_bytes` long.
+        let data = crate::test_support::text(3 << 20);
+        let info = method_block_size("10").expect("10");
+        let blocks = compress_block_sizes(&data[..], "10", None, 2).expect("block sizes");
+        assert_eq!(blocks[0].uncompressed, info.default_bytes);
+
+        // Up to `max_bytes` may be asked for, and no more.
+        let blocks = compress_block_sizes(&data[..], "10", Some(MIB as usize), 2).expect("max");
+        assert_eq!(blocks[0].uncompressed, MIB);
+        let error = compress_block_sizes(&data[..], "1", Some(256 << 20), 2).unwrap_err();
+        let ZpaqError::Ffi(message) = error else {
+            panic!("{error:?}");
+        };
+        assert_eq!(
+            message,
+            "requested 256 MiB blocks but method '1' caps at 16 MiB"
+        );
+        assert!(compress_block_sizes(&data[..], " 10 ", Some(MIB as usize + 1), 2).is_err());
+        assert!(compress_block_sizes(&data[..], "fast", None, 2).is_err());
+        let mut out = Vec::new();
+        assert!(compress_stream_parallel(&data[..], &mut out, "fast", None, None, 2).is_err());
+    }
+}
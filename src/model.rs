@@ -0,0 +1,744 @@
+//! Explicit context models: ZPAQ component stacks without the method grammar.
+//!
+//! A ZPAQ block header declares a list of components, each predicting the
+//! next bit from a context hash or from earlier components' predictions, and
+//! an HCOMP program that computes those context hashes after every byte.
+//! [`ComponentStack`] builds both from typed values, checking ZPAQ's ordering
+//! and range rules, and compiles them to the raw header that
+//! `Compressor::startBlock` takes.  [`describe_block`] reads the components
+//! back from a compressed stream.
+
+use std::os::raw::c_char;
+use std::ptr;
+
+use crate::{
+    FfiReader, FfiWriter, Result, SharedVecWriter, ZpaqError, clear_last_error, err_from_last,
+    handle, sha1, sys,
+};
+
+/// One ZPAQ model component, with the arguments its header entry carries.
+///
+/// Inputs (`input`, `j`, `k`, `first`) are indices of earlier components in
+/// the stack.  Table sizes are base-2 logarithms of the number of entries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    /// A fixed prediction of `(value - 128) * 4` in the stretched domain.
+    Const {
+        /// 128 predicts 0 and 1 equally.
+        value: u8,
+    },
+    /// Context model: maps the context to a probability, adapting at a
+    /// rate that slows down to `1 / (limit * 4)`.
+    Cm {
+        /// Entries in the table, at most 2^32.
+        size_log2: u8,
+        /// Adaptation limit, in units of 4.
+        limit: u8,
+    },
+    /// Indirect context model: maps the context to a bit history and the
+    /// history to a probability.
+    Icm {
+        /// Bit history slots, in 64-byte buckets; at most 26.
+        size_log2: u8,
+    },
+    /// Predicts the bit that followed the last occurrence of the context.
+    Match {
+        /// Entries in the index of context hashes, at most 2^32.
+        index_log2: u8,
+        /// Bytes of history searched, at most 2^32.
+        buffer_log2: u8,
+    },
+    /// Fixed weighted average of components `j` and `k`.
+    Avg {
+        /// First input.
+        j: u8,
+        /// Second input.
+        k: u8,
+        /// Weight of `j` out of 256.
+        weight: u8,
+    },
+    /// Adaptive mix of components `j` and `k`.
+    Mix2 {
+        /// Weight sets, selected by the context.
+        bits: u8,
+        /// First input.
+        j: u8,
+        /// Second input.
+        k: u8,
+        /// Learning rate.
+        rate: u8,
+        /// Mask on the bits of the current byte added to the context.
+        mask: u8,
+    },
+    /// Adaptive mix of `count` consecutive components from `first`.
+    Mix {
+        /// Weight sets, selected by the context.
+        bits: u8,
+        /// First input.
+        first: u8,
+        /// Number of inputs.
+        count: u8,
+        /// Learning rate.
+        rate: u8,
+        /// Mask on the bits of the current byte added to the context.
+        mask: u8,
+    },
+    /// Indirect secondary symbol estimator: adjusts the prediction of
+    /// `input` by the context's bit history.
+    Isse {
+        /// Bit history slots, in 64-byte buckets; at most 2^32.
+        size_log2: u8,
+        /// The component adjusted.
+        input: u8,
+    },
+    /// Secondary symbol estimator: refines the prediction of `input` in
+    /// the context.
+    Sse {
+        /// Contexts, at most 2^32.
+        size_log2: u8,
+        /// The component refined.
+        input: u8,
+        /// Initial confidence; at most `limit * 4`.
+        start: u8,
+        /// Adaptation limit, in units of 4.
+        limit: u8,
+    },
+}
+
+impl Component {
+    fn name(&self) -> &'static str {
+        match self {
+            Component::Const { .. } => "CONST",
+            Component::Cm { .. } => "CM",
+            Component::Icm { .. } => "ICM",
+            Component::Match { .. } => "MATCH",
+            Component::Avg { .. } => "AVG",
+            Component::Mix2 { .. } => "MIX2",
+            Component::Mix { .. } => "MIX",
+            Component::Isse { .. } => "ISSE",
+            Component::Sse { .. } => "SSE",
+        }
+    }
+
+    /// Whether the component reads the context hash the HCOMP computes.
+    fn takes_context(&self) -> bool {
+        !matches!(self, Component::Const { .. } | Component::Avg { .. })
+    }
+
+    /// The header entry: type byte and arguments.
+    fn encode(&self) -> Vec<u8> {
+        match *self {
+            Component::Const { value } => vec![1, value],
+            Component::Cm { size_log2, limit } => vec![2, size_log2, limit],
+            Component::Icm { size_log2 } => vec![3, size_log2],
+            Component::Match {
+                index_log2,
+                buffer_log2,
+            } => vec![4, index_log2, buffer_log2],
+            Component::Avg { j, k, weight } => vec![5, j, k, weight],
+            Component::Mix2 {
+                bits,
+                j,
+                k,
+                rate,
+                mask,
+            } => vec![6, bits, j, k, rate, mask],
+            Component::Mix {
+                bits,
+                first,
+                count,
+                rate,
+                mask,
+            } => vec![7, bits, first, count, rate, mask],
+            Component::Isse { size_log2, input } => vec![8, size_log2, input],
+            Component::Sse {
+                size_log2,
+                input,
+                start,
+                limit,
+            } => vec![9, size_log2, input, start, limit],
+        }
+    }
+
+    /// Parses the header entry at the start of `b`, returning it and its
+    /// length.
+    fn decode(b: &[u8]) -> Option<(Component, usize)> {
+        let arg = |i: usize| b.get(i).copied();
+        let component = match *b.first()? {
+            1 => Component::Const { value: arg(1)? },
+            2 => Component::Cm {
+                size_log2: arg(1)?,
+                limit: arg(2)?,
+            },
+            3 => Component::Icm { size_log2: arg(1)? },
+            4 => Component::Match {
+                index_log2: arg(1)?,
+                buffer_log2: arg(2)?,
+            },
+            5 => Component::Avg {
+                j: arg(1)?,
+                k: arg(2)?,
+                weight: arg(3)?,
+            },
+            6 => Component::Mix2 {
+                bits: arg(1)?,
+                j: arg(2)?,
+                k: arg(3)?,
+                rate: arg(4)?,
+                mask: arg(5)?,
+            },
+            7 => Component::Mix {
+                bits: arg(1)?,
+                first: arg(2)?,
+                count: arg(3)?,
+                rate: arg(4)?,
+                mask: arg(5)?,
+            },
+            8 => Component::Isse {
+                size_log2: arg(1)?,
+                input: arg(2)?,
+            },
+            9 => Component::Sse {
+                size_log2: arg(1)?,
+                input: arg(2)?,
+                start: arg(3)?,
+                limit: arg(4)?,
+            },
+            _ => return None,
+        };
+        let len = component.encode().len();
+        Some((component, len))
+    }
+
+    /// Checks the arguments of the component at `index`.
+    fn check(&self, index: usize) -> std::result::Result<(), String> {
+        let earlier = |what: &str, input: u8| {
+            if (input as usize) < index {
+                Ok(())
+            } else {
+                Err(format!(
+                    "{what} {input} must be an earlier component (below {index})"
+                ))
+            }
+        };
+        let at_most = |what: &str, value: u8, max: u8| {
+            if value <= max {
+                Ok(())
+            } else {
+                Err(format!("{what} {value} exceeds the maximum of {max}"))
+            }
+        };
+        match *self {
+            Component::Const { .. } => Ok(()),
+            Component::Cm { size_log2, .. } => at_most("size_log2", size_log2, 32),
+            Component::Icm { size_log2 } => at_most("size_log2", size_log2, 26),
+            Component::Match {
+                index_log2,
+                buffer_log2,
+            } => {
+                at_most("index_log2", index_log2, 32)?;
+                at_most("buffer_log2", buffer_log2, 32)
+            }
+            Component::Avg { j, k, .. } => {
+                earlier("input j", j)?;
+                earlier("input k", k)
+            }
+            Component::Mix2 { bits, j, k, .. } => {
+                at_most("bits", bits, 32)?;
+                earlier("input j", j)?;
+                earlier("input k", k)
+            }
+            Component::Mix {
+                bits, first, count, ..
+            } => {
+                at_most("bits", bits, 32)?;
+                earlier("first input", first)?;
+                if count == 0 || first as usize + count as usize > index {
+                    return Err(format!(
+                        "count {count} must be 1 to {} (the components from {first} \
+                         up to this one)",
+                        index - first as usize
+                    ));
+                }
+                Ok(())
+            }
+            Component::Isse { size_log2, input } => {
+                at_most("size_log2", size_log2, 32)?;
+                earlier("input", input)
+            }
+            Component::Sse {
+                size_log2,
+                input,
+                start,
+                limit,
+            } => {
+                at_most("size_log2", size_log2, 32)?;
+                earlier("input", input)?;
+                if start as u32 > limit as u32 * 4 {
+                    return Err(format!(
+                        "start {start} exceeds limit * 4 ({})",
+                        limit as u32 * 4
+                    ));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The context a component sees, computed by the HCOMP program from the
+/// bytes coded so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Context {
+    /// A constant context: the component sees only the bits of the current
+    /// byte.
+    #[default]
+    None,
+    /// A hash of the last `n` bytes.
+    Order(u8),
+    /// A hash of `len` bytes, skipping the `skip` most recent ones.
+    Sparse {
+        /// Recent bytes left out.
+        skip: u8,
+        /// Bytes hashed.
+        len: u8,
+    },
+}
+
+impl Context {
+    /// The recent bytes hashed: `(skip, len)`.
+    fn window(&self) -> (u8, u8) {
+        match *self {
+            Context::None => (0, 0),
+            Context::Order(n) => (0, n),
+            Context::Sparse { skip, len } => (skip, len),
+        }
+    }
+}
+
+// ZPAQL opcodes used by the generated HCOMP.
+const B_INC: u8 = 9;
+const C_DEC: u8 = 18;
+const STORE_D_ZERO: u8 = 52;
+const HALT: u8 = 56;
+const HASHD: u8 = 60;
+const A_FROM_C: u8 = 66;
+const A_FROM_B_MEM: u8 = 68;
+const B_FROM_A: u8 = 72;
+const B_FROM_C: u8 = 74;
+const D_SET: u8 = 95;
+const C_MEM_FROM_A: u8 = 104;
+const A_ADD: u8 = 135;
+
+/// A ZPAQ model built from typed components, each with its context.
+///
+/// The last component's prediction codes the data.  [`hcomp`](Self::hcomp)
+/// compiles the stack to a raw block header; [`compress_with_stack`] codes
+/// data with it, and the result decompresses with any ZPAQ decoder.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{Component, ComponentStack, Context};
+///
+/// // An order-1 ICM refined by order-2 and order-3 ISSEs.
+/// let stack = ComponentStack::new()
+///     .add(Component::Icm { size_log2: 16 }, Context::Order(1))
+///     .add(Component::Isse { size_log2: 18, input: 0 }, Context::Order(2))
+///     .add(Component::Isse { size_log2: 20, input: 1 }, Context::Order(3));
+/// let data = b"abracadabra, abracadabra, abracadabra".repeat(20);
+/// let c = zpaq_rs::compress_with_stack(&data, &stack).unwrap();
+/// assert_eq!(zpaq_rs::decompress_to_vec(&c).unwrap(), data);
+/// assert_eq!(zpaq_rs::describe_block(&c).unwrap().components, stack.components());
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ComponentStack {
+    layers: Vec<(Component, Context)>,
+}
+
+impl ComponentStack {
+    /// An empty stack.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `component`, seeing `context`, as the next component.
+    pub fn add(mut self, component: Component, context: Context) -> Self {
+        self.layers.push((component, context));
+        self
+    }
+
+    /// The components, in order.
+    pub fn components(&self) -> Vec<Component> {
+        self.layers
+            .iter()
+            .map(|&(component, _)| component)
+            .collect()
+    }
+
+    /// Checks the stack against ZPAQ's rules.
+    ///
+    /// Returns [`ZpaqError::Ffi`] naming the first offending component and
+    /// what to change.
+    pub fn validate(&self) -> Result<()> {
+        if self.layers.is_empty() {
+            return Err(ZpaqError::Ffi("component stack is empty".into()));
+        }
+        if self.layers.len() > 255 {
+            return Err(ZpaqError::Ffi(format!(
+                "component stack has {} components; ZPAQ allows 255",
+                self.layers.len()
+            )));
+        }
+        for (index, (component, context)) in self.layers.iter().enumerate() {
+            let fail = |why: String| {
+                ZpaqError::Ffi(format!("component {index} ({}): {why}", component.name()))
+            };
+            component.check(index).map_err(fail)?;
+            if !component.takes_context() && *context != Context::None {
+                return Err(fail("takes no context; use Context::None".into()));
+            }
+            let (skip, len) = context.window();
+            if skip as u32 + len as u32 > 255 {
+                return Err(fail(format!(
+                    "context reaches {} bytes back; at most 255",
+                    skip as u32 + len as u32
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Compiles the stack to a raw ZPAQL block header: size, `hh hm ph pm
+    /// n`, the components, and the HCOMP program computing the contexts.
+    ///
+    /// This is what `libzpaq::Compressor::startBlock(const char*)` takes.
+    pub fn hcomp(&self) -> Result<Vec<u8>> {
+        self.validate()?;
+        let n = self.layers.len();
+        let reach = self
+            .layers
+            .iter()
+            .map(|(_, context)| {
+                let (skip, len) = context.window();
+                skip as u32 + len as u32
+            })
+            .max()
+            .unwrap_or(0);
+
+        let mut comp = vec![bits_for(n as u32), bits_for(reach), 0, 0, n as u8];
+        for (component, _) in &self.layers {
+            comp.extend(component.encode());
+        }
+        comp.push(0);
+
+        // Keep the history in M, most recent byte at C, then hash each
+        // component's window of it into H[i].
+        let mut code = vec![C_DEC, C_MEM_FROM_A];
+        for (i, (_, context)) in self.layers.iter().enumerate() {
+            let (skip, len) = context.window();
+            if len == 0 {
+                continue;
+            }
+            code.extend([D_SET, i as u8, STORE_D_ZERO]);
+            if skip == 0 {
+                code.push(B_FROM_C);
+            } else {
+                code.extend([A_FROM_C, A_ADD, skip, B_FROM_A]);
+            }
+            for k in 0..len {
+                if k > 0 {
+                    code.push(B_INC);
+                }
+                code.extend([A_FROM_B_MEM, HASHD]);
+            }
+        }
+        code.push(HALT);
+        code.push(0);
+
+        let size = comp.len() + code.len();
+        if size > 0xffff {
+            return Err(ZpaqError::Ffi(format!(
+                "context program is {size} bytes; ZPAQ headers hold 65535"
+            )));
+        }
+        let mut header = vec![(size & 255) as u8, (size >> 8) as u8];
+        header.extend(comp);
+        header.extend(code);
+        Ok(header)
+    }
+}
+
+/// Bits to index `n` entries.
+fn bits_for(n: u32) -> u8 {
+    (32 - n.saturating_sub(1).leading_zeros()) as u8
+}
+
+/// The model of a compressed block, as [`describe_block`] reads it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockModel {
+    /// The HCOMP's H array holds 2^`h_log2` context hashes.
+    pub h_log2: u8,
+    /// The HCOMP's M array holds 2^`m_log2` bytes.
+    pub m_log2: u8,
+    /// The components, in order.
+    pub components: Vec<Component>,
+    /// Whether the block carries a PCOMP postprocessor.
+    pub has_pcomp: bool,
+}
+
+/// Compresses `input` with the model `stack` describes.
+///
+/// Produces one block with one unnamed segment, whose comment is the input
+/// size and trailer its SHA-1, as [`compress_stream`](crate::compress_stream)
+/// writes.  Any ZPAQ decoder decompresses it.
+///
+/// Returns [`ZpaqError::Ffi`] if the stack breaks ZPAQ's rules (see
+/// [`ComponentStack::validate`]).
+pub fn compress_with_stack(input: &[u8], stack: &ComponentStack) -> Result<Vec<u8>> {
+    let header = stack.hcomp()?;
+    let digest = sha1(input)?;
+    let comment = std::ffi::CString::new(input.len().to_string()).expect("digits");
+    clear_last_error()?;
+    let compressor = handle::Compressor::new()?;
+    let out = SharedVecWriter::new();
+    let result = (|| {
+        let output = FfiWriter::new(out.clone())?;
+        let reader = FfiReader::new(input)?;
+        unsafe {
+            if sys::zpaq_compressor_set_output(compressor.raw, output.raw) != 0
+                || sys::zpaq_compressor_set_input(compressor.raw, reader.raw) != 0
+                || sys::zpaq_compressor_write_tag(compressor.raw) != 0
+                || sys::zpaq_compressor_start_block_hcomp(
+                    compressor.raw,
+                    header.as_ptr() as *const c_char,
+                ) != 0
+                || sys::zpaq_compressor_start_segment(compressor.raw, ptr::null(), comment.as_ptr())
+                    != 0
+            {
+                return Err(err_from_last());
+            }
+            loop {
+                let rc = sys::zpaq_compressor_compress(compressor.raw, 1 << 20);
+                if rc < 0 {
+                    return Err(err_from_last());
+                }
+                if rc == 0 {
+                    break;
+                }
+            }
+            if sys::zpaq_compressor_end_segment(compressor.raw, digest.as_ptr()) != 0
+                || sys::zpaq_compressor_end_block(compressor.raw) != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        Ok(())
+    })();
+    drop(compressor);
+    result.map(|()| out.bytes())
+}
+
+/// Reads the model of the first block of the ZPAQ stream in `archive`.
+///
+/// Only the block header is decoded.
+pub fn describe_block(archive: &[u8]) -> Result<BlockModel> {
+    clear_last_error()?;
+    let reader = FfiReader::new(archive)?;
+    let decompresser = handle::Decompresser::new()?;
+    read_model(decompresser.raw, reader.raw)
+}
+
+fn read_model(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+) -> Result<BlockModel> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+    if rc_block < 0 {
+        return Err(err_from_last());
+    }
+    if rc_block == 0 {
+        return Err(ZpaqError::Ffi("no ZPAQ block found".into()));
+    }
+    let header = SharedVecWriter::new();
+    let header_writer = FfiWriter::new(header.clone())?;
+    if unsafe { sys::zpaq_decompresser_header(decompresser, header_writer.raw) } != 0 {
+        return Err(err_from_last());
+    }
+    drop(header_writer);
+    let header = header.bytes();
+    let malformed = || ZpaqError::Ffi("malformed block header".into());
+    let fixed = header.get(..7).ok_or_else(malformed)?;
+    let mut components = Vec::with_capacity(fixed[6] as usize);
+    let mut rest = &header[7..];
+    for _ in 0..fixed[6] {
+        let (component, len) = Component::decode(rest).ok_or_else(malformed)?;
+        components.push(component);
+        rest = &rest[len..];
+    }
+    // The postprocessor is only known once the segment data starts.
+    let has_pcomp = unsafe {
+        let rc_filename = sys::zpaq_decompresser_find_filename(decompresser, ptr::null_mut());
+        if rc_filename < 0
+            || rc_filename > 0
+                && (sys::zpaq_decompresser_read_comment(decompresser, ptr::null_mut()) != 0
+                    || sys::zpaq_decompresser_set_output(decompresser, ptr::null_mut()) != 0
+                    || sys::zpaq_decompresser_decompress(decompresser, 0) < 0)
+        {
+            return Err(err_from_last());
+        }
+        rc_filename > 0 && sys::zpaq_decompresser_has_pcomp(decompresser) == 1
+    };
+    Ok(BlockModel {
+        h_log2: fixed[2],
+        m_log2: fixed[3],
+        components,
+        has_pcomp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, decompress_to_vec, test_support};
+
+    const DICKENS: &[u8] =
+        b"It was the best of times, it was the worst of times, it was the age of wisdom. ";
+
+    #[test]
+    fn icm_isse_chain_round_trips() {
+        let stack = ComponentStack::new()
+            .add(Component::Icm { size_log2: 16 }, Context::Order(1))
+            .add(
+                Component::Isse {
+                    size_log2: 18,
+                    input: 0,
+                },
+                Context::Order(2),
+            )
+            .add(
+                Component::Isse {
+                    size_log2: 19,
+                    input: 1,
+                },
+                Context::Sparse { skip: 1, len: 3 },
+            )
+            .add(
+                Component::Match {
+                    index_log2: 18,
+                    buffer_log2: 20,
+                },
+                Context::Order(6),
+            )
+            .add(
+                Component::Mix {
+                    bits: 8,
+                    first: 0,
+                    count: 4,
+                    rate: 24,
+                    mask: 255,
+                },
+                Context::Order(1),
+            )
+            .add(
+                Component::Sse {
+                    size_log2: 16,
+                    input: 4,
+                    start: 32,
+                    limit: 255,
+                },
+                Context::Order(1),
+            );
+        let data = test_support::periodic(DICKENS, 40_000);
+        let c = compress_with_stack(&data, &stack).expect("compress");
+        assert!(c.len() < data.len() / 20, "{} bytes", c.len());
+        assert_eq!(decompress_to_vec(&c).expect("decompress"), data);
+        assert_eq!(crate::original_size_hint(&c).expect("hint"), Some(40_000));
+
+        let model = describe_block(&c).expect("describe");
+        assert_eq!(model.components, stack.components());
+        assert_eq!((model.h_log2, model.m_log2), (3, 3));
+        assert!(!model.has_pcomp);
+
+        let empty = compress_with_stack(b"", &stack).expect("compress empty");
+        assert_eq!(decompress_to_vec(&empty).expect("decompress"), b"");
+    }
+
+    #[test]
+    fn describes_method_blocks() {
+        let c = compress_to_vec(&test_support::periodic(DICKENS, 40_000), "x0.0c0.0.255i2")
+            .expect("compress");
+        let model = describe_block(&c).expect("describe");
+        assert!(matches!(model.components[0], Component::Icm { .. }));
+        assert!(matches!(
+            model.components[1],
+            Component::Isse { input: 0, .. }
+        ));
+        assert_eq!(model.components.len(), 2);
+
+        let lz = compress_to_vec(&test_support::periodic(DICKENS, 40_000), "2").expect("compress");
+        assert!(describe_block(&lz).expect("describe").has_pcomp);
+        assert!(describe_block(b"not zpaq").is_err());
+    }
+
+    #[test]
+    fn validation_names_the_component_and_fix() {
+        let message = |stack: ComponentStack| match stack.validate() {
+            Err(ZpaqError::Ffi(message)) => message,
+            other => panic!("expected a validation error, got {other:?}"),
+        };
+        assert_eq!(message(ComponentStack::new()), "component stack is empty");
+        let m = message(ComponentStack::new().add(
+            Component::Isse {
+                size_log2: 18,
+                input: 0,
+            },
+            Context::Order(1),
+        ));
+        assert!(
+            m.starts_with("component 0 (ISSE): input 0 must be an earlier"),
+            "{m}"
+        );
+        let m = message(ComponentStack::new().add(Component::Icm { size_log2: 27 }, Context::None));
+        assert!(m.contains("size_log2 27 exceeds the maximum of 26"), "{m}");
+        let m =
+            message(ComponentStack::new().add(Component::Const { value: 128 }, Context::Order(1)));
+        assert!(m.contains("use Context::None"), "{m}");
+        let m = message(
+            ComponentStack::new()
+                .add(Component::Icm { size_log2: 10 }, Context::Order(1))
+                .add(
+                    Component::Mix {
+                        bits: 0,
+                        first: 0,
+                        count: 2,
+                        rate: 24,
+                        mask: 255,
+                    },
+                    Context::None,
+                ),
+        );
+        assert!(m.contains("count 2 must be 1 to 1"), "{m}");
+        let m = message(
+            ComponentStack::new()
+                .add(Component::Icm { size_log2: 10 }, Context::Order(200))
+                .add(
+                    Component::Isse {
+                        size_log2: 10,
+                        input: 0,
+                    },
+                    Context::Sparse {
+                        skip: 100,
+                        len: 200,
+                    },
+                ),
+        );
+        assert!(
+            m.contains("component 1 (ISSE): context reaches 300 bytes"),
+            "{m}"
+        );
+    }
+}
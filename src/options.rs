@@ -0,0 +1,728 @@
+//! Option-driven compression and decompression entry points.
+
+use std::io::{Cursor, Read, Write};
+
+use crate::seekable::write_stream_index;
+use crate::{
+    CountedWriter, CountingReader, FfiReader, FfiWriter, IoTuning, RateLimit, ReadPolicy, Result,
+    Throttle, WrittenBlocks, ZpaqError, clear_last_error, compress_stream_as, decompress_stream,
+    err_from_last, hardened_parsing, io_err, is_zpaq, original_size_hint, sys, trace,
+};
+
+/// How many leading bytes are examined when sniffing for existing ZPAQ data.
+const SNIFF_LEN: u64 = 1 << 16;
+
+/// The most [`decompress_to_vec_with`] reserves up front; larger outputs
+/// grow from there.
+const MAX_PREALLOC: u64 = 1 << 30;
+
+/// Under hardened parsing, the most [`decompress_to_vec_with`] reserves
+/// from the sizes segment comments declare, as a multiple of the input
+/// length.  The comments are not checked against the data.
+const MAX_HINTED_RATIO: u64 = 1 << 12;
+
+/// Below this many sniffed bytes the header match alone decides.
+const MIN_ENTROPY_SAMPLE: usize = 1024;
+
+/// Order-0 entropy (bits per byte) above which sniffed data counts as
+/// already compressed.
+const COMPRESSED_ENTROPY: f64 = 7.0;
+
+/// What to do when the input already looks like ZPAQ-compressed data.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AlreadyCompressedPolicy {
+    /// Compress it anyway (the behaviour of [`compress_stream`](crate::compress_stream)).
+    #[default]
+    Compress,
+    /// Fail with [`ZpaqError::AlreadyCompressed`].
+    Reject,
+    /// Copy the input to the output unchanged and set
+    /// [`CompressStats::passthrough`].
+    Passthrough,
+}
+
+/// Options for [`compress_stream_with`] / [`compress_to_vec_with`].
+///
+/// The default matches [`compress_stream`](crate::compress_stream) with no filename or comment.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CompressOptions {
+    /// Optional segment filename.
+    pub filename: Option<String>,
+    /// Optional segment comment.
+    pub comment: Option<String>,
+    /// Policy for inputs that are already ZPAQ.
+    ///
+    /// Detection needs both a ZPAQ header at the start of the input (see
+    /// [`is_zpaq`]) and high byte entropy over the first 64 KiB, so a store
+    /// (`"0"`) archive of compressible data is still compressed.
+    pub on_already_compressed: AlreadyCompressedPolicy,
+    /// Limit on how fast the input is read, for compressing in the
+    /// background without starving other work.
+    pub rate_limit: Option<RateLimit>,
+    /// Write any block that would come out larger than its input as a
+    /// store (`"0"`) block instead, as `zpaq add` does with fragments that
+    /// do not compress, so incompressible input grows only by the framing.
+    /// Each block is then compressed into memory before it is written.
+    pub store_if_expands: bool,
+    /// How reads of the input that return `Ok(0)` are taken, for readers
+    /// that return it with more input to come.  Applies to the sniffing of
+    /// [`on_already_compressed`](Self::on_already_compressed) too.
+    pub read_policy: ReadPolicy,
+    /// Append a [`StreamIndex`](crate::StreamIndex) of the blocks written,
+    /// which [`build_stream_index`](crate::build_stream_index) then reads
+    /// instead of scanning the stream.  The index is a store block of its
+    /// own, in a segment named
+    /// [`STREAM_INDEX_FILENAME`](crate::STREAM_INDEX_FILENAME) that
+    /// [`decompress_stream`] and the decompressors built on it skip.
+    /// Not written when the input is passed through.
+    pub embed_stream_index: bool,
+    /// Also store a checksum of this kind for each segment, at the end of
+    /// its comment as `sha256:` (or `sha1:`) and the hex digest, for
+    /// [`SegmentInfo::extra_checksum`](crate::SegmentInfo::extra_checksum)
+    /// to read and [`verify_archive`](crate::verify_archive) to check in
+    /// deep mode.  The SHA-1 trailer is written as always, so other readers
+    /// see only a longer comment.
+    pub extra_checksum: Option<HashKind>,
+    /// Chunk sizes for the callbacks, in place of the [`IoTuning`] in
+    /// force.
+    pub io_tuning: Option<IoTuning>,
+}
+
+/// Figures reported by [`compress_stream_with`] / [`compress_to_vec_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompressStats {
+    /// Bytes read from the input.
+    pub bytes_in: u64,
+    /// Bytes written to the output.
+    pub bytes_out: u64,
+    /// `true` if the input was copied through unchanged.
+    pub passthrough: bool,
+    /// Blocks written as store blocks under
+    /// [`CompressOptions::store_if_expands`].
+    pub stored_blocks: u64,
+}
+
+/// Options for [`decompress_to_vec_with`] and [`decompress_stream_with`].
+///
+/// The default matches [`decompress_to_vec`](crate::decompress_to_vec).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecompressOptions {
+    /// The expected decompressed size, reserved before decoding (up to
+    /// 1 GiB).  Without one, the sizes the segment comments declare are
+    /// used (see [`original_size_hint`]).
+    pub size_hint: Option<u64>,
+    /// Trust the size: allocate the output once and fail with
+    /// [`ZpaqError::SizeMismatch`] if the data decodes to any other size.
+    /// Without a size from the hint or the comments, has no effect.
+    pub exact: bool,
+    /// Hash the decompressed data in the shim as it is written, and check
+    /// each segment against the SHA-1 its trailer stores, reporting both in
+    /// [`DecompressStats`].  Only [`decompress_stream_with`] reports them.
+    pub hash: Option<HashKind>,
+    /// Chunk sizes for the callbacks, in place of the [`IoTuning`] in
+    /// force.
+    pub io_tuning: Option<IoTuning>,
+}
+
+/// A digest [`DecompressOptions::hash`] can ask for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HashKind {
+    /// SHA-1, as [`sha1`](crate::sha1) computes it.
+    Sha1,
+    /// SHA-256, as [`sha256`](crate::sha256) computes it.
+    Sha256,
+}
+
+/// The digest of decompressed data, of the kind asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Digest {
+    /// A SHA-1 digest.
+    Sha1([u8; 20]),
+    /// A SHA-256 digest.
+    Sha256([u8; 32]),
+}
+
+impl Digest {
+    /// The digest's bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Digest::Sha1(digest) => digest,
+            Digest::Sha256(digest) => digest,
+        }
+    }
+}
+
+/// Figures reported by [`decompress_stream_with`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecompressStats {
+    /// Bytes written to the output.
+    pub bytes_written: u64,
+    /// Digest of everything written, with [`DecompressOptions::hash`].
+    pub digest: Option<Digest>,
+    /// With [`DecompressOptions::hash`], whether every segment that stores
+    /// a SHA-1 decoded to data matching it; `None` without a hash or when
+    /// no segment stores one.
+    pub checksums_matched: Option<bool>,
+}
+
+/// Order-0 Shannon entropy of `bytes` in bits per byte.
+pub(crate) fn byte_entropy(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+    let mut counts = [0u64; 256];
+    for &b in bytes {
+        counts[b as usize] += 1;
+    }
+    let n = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / n;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn looks_already_compressed(prefix: &[u8]) -> bool {
+    is_zpaq(prefix)
+        && (prefix.len() < MIN_ENTROPY_SAMPLE || byte_entropy(prefix) >= COMPRESSED_ENTROPY)
+}
+
+/// Compresses data from `reader` into `writer` according to `options`.
+///
+/// Behaves like [`compress_stream`](crate::compress_stream) unless the input is already ZPAQ and
+/// [`CompressOptions::on_already_compressed`] says otherwise, reading no
+/// faster than [`CompressOptions::rate_limit`] allows, under
+/// [`CompressOptions::read_policy`], storing blocks
+/// that do not compress under [`CompressOptions::store_if_expands`], and
+/// ending with an index of the blocks under
+/// [`CompressOptions::embed_stream_index`].
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::AlreadyCompressed`] under
+/// [`AlreadyCompressedPolicy::Reject`], before anything is written.
+pub fn compress_stream_with<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    options: &CompressOptions,
+) -> Result<CompressStats> {
+    let reader = options.read_policy.reader(reader);
+    tuned(options.io_tuning, || match options.rate_limit {
+        Some(limit) => compress_sniffed(
+            Throttle::new(limit)?.reader(reader),
+            writer,
+            method,
+            options,
+        ),
+        None => compress_sniffed(reader, writer, method, options),
+    })
+}
+
+/// Runs `f` under `tuning`, if any.
+fn tuned<T>(tuning: Option<IoTuning>, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    match tuning {
+        Some(tuning) => tuning.run(f)?,
+        None => f(),
+    }
+}
+
+/// [`compress_stream_with`] once the input is throttled.
+fn compress_sniffed<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    options: &CompressOptions,
+) -> Result<CompressStats> {
+    let mut reader = reader;
+    let mut prefix = Vec::new();
+    if options.on_already_compressed != AlreadyCompressedPolicy::Compress {
+        (&mut reader)
+            .take(SNIFF_LEN)
+            .read_to_end(&mut prefix)
+            .map_err(io_err)?;
+    }
+    let already = !prefix.is_empty() && looks_already_compressed(&prefix);
+
+    let mut input = CountingReader::new(Cursor::new(prefix).chain(reader));
+    let mut output = CountedWriter::new(writer);
+
+    let mut blocks = WrittenBlocks {
+        store_if_expands: options.store_if_expands,
+        checksum: options.extra_checksum,
+        ..Default::default()
+    };
+    let passthrough = match options.on_already_compressed {
+        AlreadyCompressedPolicy::Reject if already => return Err(ZpaqError::AlreadyCompressed),
+        AlreadyCompressedPolicy::Passthrough if already => {
+            std::io::copy(&mut input, &mut output).map_err(io_err)?;
+            true
+        }
+        _ => {
+            let record = options.store_if_expands
+                || options.embed_stream_index
+                || options.extra_checksum.is_some();
+            compress_stream_as(
+                "compress_stream",
+                &mut input,
+                &mut output,
+                method,
+                options.filename.as_deref().map(str::as_bytes),
+                options.comment.as_deref().map(str::as_bytes),
+                record.then_some(&mut blocks),
+            )?;
+            if options.embed_stream_index {
+                write_stream_index(&mut output, &blocks.sizes)?;
+            }
+            false
+        }
+    };
+
+    Ok(CompressStats {
+        bytes_in: input.bytes_read(),
+        bytes_out: output.bytes_written(),
+        passthrough,
+        stored_blocks: blocks.stored,
+    })
+}
+
+/// Compresses `input` into a `Vec<u8>` according to `options`.
+///
+/// See [`compress_stream_with`].
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{AlreadyCompressedPolicy, CompressOptions, ZpaqError};
+///
+/// let opts = CompressOptions {
+///     on_already_compressed: AlreadyCompressedPolicy::Reject,
+///     ..Default::default()
+/// };
+/// let (once, stats) = zpaq_rs::compress_to_vec_with(b"hello zpaq", "1", &opts).unwrap();
+/// assert!(!stats.passthrough);
+/// let err = zpaq_rs::compress_to_vec_with(&once, "1", &opts).unwrap_err();
+/// assert!(matches!(err, ZpaqError::AlreadyCompressed));
+/// ```
+pub fn compress_to_vec_with(
+    input: &[u8],
+    method: &str,
+    options: &CompressOptions,
+) -> Result<(Vec<u8>, CompressStats)> {
+    let mut out = Vec::new();
+    let stats = compress_stream_with(input, &mut out, method, options)?;
+    Ok((out, stats))
+}
+
+/// Decompresses `input` into a `Vec<u8>` according to `options`.
+///
+/// Reserving the output up front saves the reallocations of growing it,
+/// which for large outputs costs noticeably.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{DecompressOptions, ZpaqError};
+///
+/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "1").unwrap();
+/// let opts = DecompressOptions { size_hint: Some(10), exact: true, ..Default::default() };
+/// let d = zpaq_rs::decompress_to_vec_with(&c, &opts).unwrap();
+/// assert_eq!(d.capacity(), d.len());
+///
+/// let wrong = DecompressOptions { size_hint: Some(9), exact: true, ..Default::default() };
+/// let err = zpaq_rs::decompress_to_vec_with(&c, &wrong).unwrap_err();
+/// assert!(matches!(err, ZpaqError::SizeMismatch { expected: 9, actual: 10 }));
+/// ```
+pub fn decompress_to_vec_with(input: &[u8], options: &DecompressOptions) -> Result<Vec<u8>> {
+    tuned(options.io_tuning, || {
+        decompress_to_vec_tuned(input, options)
+    })
+}
+
+fn decompress_to_vec_tuned(input: &[u8], options: &DecompressOptions) -> Result<Vec<u8>> {
+    // A damaged stream fails in the decompression proper.
+    let mut reserve = MAX_PREALLOC;
+    let size = options.size_hint.or_else(|| {
+        if hardened_parsing() {
+            let ratio_cap = (input.len() as u64).saturating_mul(MAX_HINTED_RATIO);
+            reserve = reserve.min(ratio_cap);
+        }
+        original_size_hint(input).ok().flatten()
+    });
+    let Some(size) = size else {
+        let mut out = Vec::new();
+        decompress_stream(input, &mut out)?;
+        return Ok(out);
+    };
+    let mut out = Vec::with_capacity(size.min(reserve) as usize);
+    if !options.exact {
+        decompress_stream(input, &mut out)?;
+        return Ok(out);
+    }
+    let mut exact = ExactWriter {
+        out,
+        expected: size,
+        actual: 0,
+    };
+    decompress_stream(input, &mut exact)?;
+    if exact.actual != size {
+        return Err(ZpaqError::SizeMismatch {
+            expected: size,
+            actual: exact.actual,
+        });
+    }
+    Ok(exact.out)
+}
+
+/// Decompresses data from `reader` into `writer` according to `options`.
+///
+/// Behaves like [`decompress_stream`], also hashing the output with
+/// [`DecompressOptions::hash`] (in the shim, as it is written, so it takes
+/// no second pass) and checking it against the segments' stored SHA-1s.
+/// A mismatch is reported in [`DecompressStats::checksums_matched`], not
+/// as an error.  With [`DecompressOptions::exact`], a
+/// [`size_hint`](DecompressOptions::size_hint) is checked once the data is
+/// written.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{DecompressOptions, Digest, HashKind};
+///
+/// let c = zpaq_rs::compress_to_vec(b"hello zpaq", "2")?;
+/// let opts = DecompressOptions { hash: Some(HashKind::Sha256), ..Default::default() };
+/// let mut out = Vec::new();
+/// let stats = zpaq_rs::decompress_stream_with(&c[..], &mut out, &opts)?;
+/// assert_eq!(stats.bytes_written, 10);
+/// assert_eq!(stats.digest, Some(Digest::Sha256(zpaq_rs::sha256(b"hello zpaq")?)));
+/// assert_eq!(stats.checksums_matched, Some(true));
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails as [`decompress_stream`] does, and with
+/// [`ZpaqError::SizeMismatch`] if the size is exact and the data decodes to
+/// another.
+pub fn decompress_stream_with<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    options: &DecompressOptions,
+) -> Result<DecompressStats> {
+    let stats = tuned(options.io_tuning, || match options.hash {
+        None => {
+            let mut output = CountedWriter::new(writer);
+            decompress_stream(reader, &mut output)?;
+            Ok(DecompressStats {
+                bytes_written: output.bytes_written(),
+                ..Default::default()
+            })
+        }
+        Some(kind) => decompress_digest(reader, writer, kind),
+    })?;
+    if let (Some(expected), true) = (options.size_hint, options.exact)
+        && stats.bytes_written != expected
+    {
+        return Err(ZpaqError::SizeMismatch {
+            expected,
+            actual: stats.bytes_written,
+        });
+    }
+    Ok(stats)
+}
+
+fn decompress_digest<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    kind: HashKind,
+) -> Result<DecompressStats> {
+    clear_last_error()?;
+    let _op = trace::Operation::decompress("decompress_stream_with");
+    let reader = FfiReader::tuned(reader)?;
+    let writer = FfiWriter::new(writer)?;
+    let mut digest = [0u8; 32];
+    let (mut size, mut checked, mut bad) = (0u64, 0u64, 0u64);
+    let rc = unsafe {
+        sys::zpaq_decompress_digest(
+            reader.raw,
+            writer.raw,
+            match kind {
+                HashKind::Sha1 => 1,
+                HashKind::Sha256 => 2,
+            },
+            digest.as_mut_ptr(),
+            &mut size,
+            &mut checked,
+            &mut bad,
+        )
+    };
+    if rc != 0 {
+        return Err(err_from_last());
+    }
+    let digest = match kind {
+        HashKind::Sha1 => Digest::Sha1(digest[..20].try_into().expect("20 bytes")),
+        HashKind::Sha256 => Digest::Sha256(digest),
+    };
+    Ok(DecompressStats {
+        bytes_written: size,
+        digest: Some(digest),
+        checksums_matched: (checked > 0).then_some(bad == 0),
+    })
+}
+
+/// Keeps at most `expected` bytes, counting all of them.
+struct ExactWriter {
+    out: Vec<u8>,
+    expected: u64,
+    actual: u64,
+}
+
+impl Write for ExactWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let room = self.expected.saturating_sub(self.actual);
+        let keep = (buf.len() as u64).min(room) as usize;
+        self.out.extend_from_slice(&buf[..keep]);
+        self.actual += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_stream, compress_to_vec, decompress_to_vec, random_bytes, sha1, sha256};
+
+    fn opts(policy: AlreadyCompressedPolicy) -> CompressOptions {
+        CompressOptions {
+            on_already_compressed: policy,
+            ..Default::default()
+        }
+    }
+
+    fn genuine_archive() -> Vec<u8> {
+        let data = random_bytes(200_000).expect("random_bytes");
+        compress_to_vec(&data, "1").expect("compress")
+    }
+
+    #[test]
+    fn rate_limit_slows_reading_but_not_the_output() {
+        let data = b"throttled in the background ".repeat(20_000);
+        let options = CompressOptions {
+            rate_limit: Some(RateLimit::new(1 << 20)),
+            ..Default::default()
+        };
+        let started = std::time::Instant::now();
+        let (out, stats) = compress_to_vec_with(&data, "1", &options).expect("compress");
+        let elapsed = started.elapsed().as_secs_f64();
+        assert_eq!(out, compress_to_vec(&data, "1").expect("compress"));
+        assert_eq!(stats.bytes_in, data.len() as u64);
+        let least = (data.len() - (64 << 10)) as f64 / (1 << 20) as f64;
+        assert!(elapsed >= least * 0.95, "{elapsed}s");
+    }
+
+    #[test]
+    fn blocks_that_expand_are_stored() {
+        // Method 30 uses 1 MiB blocks: two, the last one partial.
+        let noise = random_bytes(1_500_000).expect("random_bytes");
+        let plain = compress_to_vec(&noise, "30").expect("compress");
+        assert!(plain.len() > noise.len());
+
+        let options = CompressOptions {
+            store_if_expands: true,
+            ..Default::default()
+        };
+        let (out, stats) = compress_to_vec_with(&noise, "30", &options).expect("compress");
+        assert_eq!(stats.stored_blocks, 2);
+        assert_eq!(stats.bytes_out, out.len() as u64);
+        // Per block a header, and 4 bytes for each 64 KiB chunk.
+        assert!(out.len() <= noise.len() + 2 * 128 + 4 * (noise.len() >> 16) + 8);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), noise);
+
+        // Blocks that compress are left as they are.
+        let mut mixed = b"compressible ".repeat(100_000);
+        mixed.extend_from_slice(&noise);
+        let (out, stats) = compress_to_vec_with(&mixed, "30", &options).expect("compress");
+        assert_eq!(stats.stored_blocks, 1);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), mixed);
+        let text = b"compressible ".repeat(100_000);
+        let (out, stats) = compress_to_vec_with(&text, "30", &options).expect("compress");
+        assert_eq!(stats.stored_blocks, 0);
+        assert_eq!(out, compress_to_vec(&text, "30").expect("compress"));
+    }
+
+    #[test]
+    fn compress_policy_recompresses_archives() {
+        let archive = genuine_archive();
+        let (out, stats) =
+            compress_to_vec_with(&archive, "1", &opts(AlreadyCompressedPolicy::Compress))
+                .expect("compress");
+        assert!(!stats.passthrough);
+        assert_eq!(stats.bytes_in, archive.len() as u64);
+        assert_eq!(stats.bytes_out, out.len() as u64);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), archive);
+    }
+
+    #[test]
+    fn reject_policy_refuses_archives() {
+        let archive = genuine_archive();
+        let mut out = Vec::new();
+        let err = compress_stream_with(
+            archive.as_slice(),
+            &mut out,
+            "1",
+            &opts(AlreadyCompressedPolicy::Reject),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ZpaqError::AlreadyCompressed));
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn passthrough_policy_copies_archives() {
+        let archive = genuine_archive();
+        let (out, stats) =
+            compress_to_vec_with(&archive, "1", &opts(AlreadyCompressedPolicy::Passthrough))
+                .expect("passthrough");
+        assert!(stats.passthrough);
+        assert_eq!(out, archive);
+        assert_eq!(stats.bytes_in, archive.len() as u64);
+        assert_eq!(stats.bytes_out, archive.len() as u64);
+    }
+
+    #[test]
+    fn false_positives_are_compressed() {
+        // High entropy but no ZPAQ header.
+        let random = random_bytes(100_000).expect("random_bytes");
+        // ZPAQ header but low entropy (looks like a store archive of text).
+        let mut lookalike = b"zPQ\x02\x01".to_vec();
+        lookalike.extend(b"plain text, not compressed. ".iter().cycle().take(100_000));
+
+        for input in [random, lookalike] {
+            for policy in [
+                AlreadyCompressedPolicy::Reject,
+                AlreadyCompressedPolicy::Passthrough,
+            ] {
+                let (out, stats) =
+                    compress_to_vec_with(&input, "1", &opts(policy)).expect("compress");
+                assert!(!stats.passthrough);
+                assert_eq!(decompress_to_vec(&out).expect("decompress"), input);
+            }
+        }
+    }
+
+    #[test]
+    fn hinted_decompression_allocates_once() {
+        let data: Vec<u8> = (0..100_000_000u32).map(|i| (i % 251) as u8).collect();
+        let c = compress_to_vec(&data, "0").expect("compress");
+        let size = Some(data.len() as u64);
+
+        let auto = decompress_to_vec(&c).expect("decompress");
+        assert_eq!(auto.capacity(), auto.len());
+        assert!(auto == data);
+        for exact in [false, true] {
+            let opts = DecompressOptions {
+                size_hint: size,
+                exact,
+                ..Default::default()
+            };
+            let out = decompress_to_vec_with(&c, &opts).expect("decompress");
+            assert_eq!(out.capacity(), out.len());
+            assert!(out == data);
+        }
+
+        let short = DecompressOptions {
+            size_hint: Some(1000),
+            exact: true,
+            ..Default::default()
+        };
+        match decompress_to_vec_with(&c, &short) {
+            Err(ZpaqError::SizeMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), (1000, data.len() as u64));
+            }
+            other => panic!("expected SizeMismatch, got {other:?}"),
+        }
+        // A loose hint is only a starting capacity.
+        let loose = DecompressOptions {
+            size_hint: Some(1000),
+            exact: false,
+            ..Default::default()
+        };
+        assert!(decompress_to_vec_with(&c, &loose).expect("decompress") == data);
+    }
+
+    #[test]
+    fn declared_sizes_reserve_in_proportion_to_the_input() {
+        // A comment can declare any size; the data holds three bytes.
+        let mut c = Vec::new();
+        compress_stream(&b"abc"[..], &mut c, "1", None, Some("900000000000")).expect("compress");
+        let out = decompress_to_vec(&c).expect("decompress");
+        assert_eq!(out, b"abc");
+        assert!(out.capacity() as u64 <= c.len() as u64 * MAX_HINTED_RATIO);
+    }
+
+    #[test]
+    fn digests_are_computed_while_decompressing() {
+        let data = crate::test_support::text(300_000);
+        for method in ["0", "1", "3", "x4.3ci1"] {
+            let c = compress_to_vec(&data, method).expect("compress");
+            let plain = decompress_to_vec(&c).expect("decompress");
+            for (kind, expected) in [
+                (HashKind::Sha1, Digest::Sha1(sha1(&plain).expect("sha1"))),
+                (
+                    HashKind::Sha256,
+                    Digest::Sha256(sha256(&plain).expect("sha256")),
+                ),
+            ] {
+                let opts = DecompressOptions {
+                    hash: Some(kind),
+                    ..Default::default()
+                };
+                let mut out = Vec::new();
+                let stats = decompress_stream_with(&c[..], &mut out, &opts).expect("decompress");
+                assert!(out == plain, "method={method}");
+                assert_eq!(
+                    stats,
+                    DecompressStats {
+                        bytes_written: data.len() as u64,
+                        digest: Some(expected),
+                        checksums_matched: Some(true),
+                    },
+                    "method={method}"
+                );
+            }
+        }
+
+        let mut c = compress_to_vec(&data, "0").expect("compress");
+        let mid = c.len() / 2;
+        c[mid] ^= 1;
+        let opts = DecompressOptions {
+            hash: Some(HashKind::Sha1),
+            size_hint: Some(data.len() as u64),
+            exact: true,
+            ..Default::default()
+        };
+        let stats = decompress_stream_with(&c[..], std::io::sink(), &opts).expect("decompress");
+        assert_eq!(stats.checksums_matched, Some(false));
+        assert_ne!(stats.digest, Some(Digest::Sha1(sha1(&data).expect("sha1"))));
+
+        let plain = decompress_stream_with(&c[..], std::io::sink(), &DecompressOptions::default())
+            .expect("decompress");
+        assert_eq!((plain.digest, plain.checksums_matched), (None, None));
+        let short = DecompressOptions {
+            size_hint: Some(10),
+            exact: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            decompress_stream_with(&c[..], std::io::sink(), &short),
+            Err(ZpaqError::SizeMismatch { expected: 10, .. })
+        ));
+    }
+}
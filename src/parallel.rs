@@ -0,0 +1,271 @@
+//! Scheduling priority and names of the worker threads of parallel calls.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use std::thread::{Scope, ScopedJoinHandle};
+
+use crate::{Result, ZpaqError, sys};
+
+/// How much CPU time worker threads ask for, below the default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ThreadPriority {
+    /// Nice value raised by 10 on Linux, `QOS_CLASS_UTILITY` on macOS,
+    /// `THREAD_PRIORITY_BELOW_NORMAL` on Windows.
+    BelowNormal,
+    /// `SCHED_IDLE` on Linux, so workers only run when nothing else would;
+    /// `QOS_CLASS_BACKGROUND` on macOS, `THREAD_PRIORITY_IDLE` on Windows.
+    Idle,
+}
+
+/// Settings for the worker threads that parallel calls start: the pool
+/// of [`compress_stream_parallel`](crate::compress_stream_parallel) and
+/// its variants, the threads of `zpaq add` and `zpaq extract` run by
+/// [`zpaq_add_opts`](crate::zpaq_add_opts) and the other commands, and the
+/// helper threads of [`convert_to_journal`](crate::convert_to_journal) and
+/// [`export_member_as_stream`](crate::export_member_as_stream).
+///
+/// The default leaves workers as the operating system starts them.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{ParallelOptions, ThreadPriority};
+///
+/// let options = ParallelOptions {
+///     thread_priority: Some(ThreadPriority::BelowNormal),
+///     thread_name_prefix: Some("backup-".into()),
+/// };
+/// let data = b"compressed in the background ".repeat(10_000);
+/// let compressed = options.run(|| zpaq_rs::compress_to_vec_parallel(&data, "1", 4))??;
+/// assert_eq!(zpaq_rs::decompress_to_vec(&compressed)?, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ParallelOptions {
+    /// Priority of each worker, or `None` for the default.
+    pub thread_priority: Option<ThreadPriority>,
+    /// Workers are named this followed by their number, as shown by `top`
+    /// and debuggers.  Linux keeps 15 bytes of a name, so a longer prefix
+    /// is cut to fit the number.
+    pub thread_name_prefix: Option<String>,
+}
+
+thread_local! {
+    /// The options of the innermost [`ParallelOptions::run`] on this thread.
+    static CURRENT: RefCell<Option<ParallelOptions>> = const { RefCell::new(None) };
+}
+
+impl ParallelOptions {
+    /// Runs `f` with these options applied to the worker threads of the
+    /// calls it makes on this thread.  Runs nest, the innermost applying.
+    ///
+    /// Workers whose priority or name cannot be set, for example where the
+    /// platform does not allow it, run as they are; they are counted in
+    /// `OperationStats::thread_setup_failures` under the `stats` feature,
+    /// and logged as a warning under `tracing`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ZpaqError::NulInString`] if the name prefix contains a
+    /// NUL byte, without running `f`.
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> Result<T> {
+        let prefix = self.prefix()?;
+        #[cfg(any(feature = "tracing", feature = "stats"))]
+        crate::install_hooks();
+        let scope = Applied {
+            outer: CURRENT.replace(Some(self.clone())),
+        };
+        unsafe { sys::zpaq_set_worker_options(self.priority(), prefix.as_ptr()) };
+        let value = f();
+        drop(scope);
+        Ok(value)
+    }
+
+    fn priority(&self) -> c_int {
+        match self.thread_priority {
+            None => 0,
+            Some(ThreadPriority::BelowNormal) => 1,
+            Some(ThreadPriority::Idle) => 2,
+        }
+    }
+
+    fn prefix(&self) -> Result<CString> {
+        CString::new(self.thread_name_prefix.as_deref().unwrap_or_default())
+            .map_err(|_| ZpaqError::NulInString)
+    }
+}
+
+/// A [`ParallelOptions::run`] in progress; restores the enclosing options
+/// when dropped, so a panic in the closure leaves the thread as it was.
+struct Applied {
+    outer: Option<ParallelOptions>,
+}
+
+impl Drop for Applied {
+    fn drop(&mut self) {
+        let outer = self.outer.take();
+        let (priority, prefix) = match &outer {
+            Some(options) => (options.priority(), options.prefix().unwrap_or_default()),
+            None => (0, CString::default()),
+        };
+        unsafe { sys::zpaq_set_worker_options(priority, prefix.as_ptr()) };
+        CURRENT.set(outer);
+    }
+}
+
+/// A thread started by [`spawn_scoped`].
+pub(crate) struct Worker<'scope, T> {
+    handle: ScopedJoinHandle<'scope, (T, bool)>,
+}
+
+impl<T> Worker<'_, T> {
+    /// Waits for the thread, reporting on this thread if its options could
+    /// not be applied.
+    pub(crate) fn join(self) -> std::thread::Result<T> {
+        let (value, applied) = self.handle.join()?;
+        if !applied {
+            crate::worker_setup_failed(1);
+        }
+        Ok(value)
+    }
+}
+
+/// Spawns a helper thread in `scope` with the current [`ParallelOptions`],
+/// which also apply to the workers of the calls it makes.
+pub(crate) fn spawn_scoped<'scope, T: Send + 'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    f: impl FnOnce() -> T + Send + 'scope,
+) -> Worker<'scope, T> {
+    let options = CURRENT.with_borrow(Clone::clone);
+    let handle = scope.spawn(move || {
+        let Some(options) = options else {
+            return (f(), true);
+        };
+        let applied = options.prefix().is_ok_and(|prefix| unsafe {
+            sys::zpaq_setup_worker_thread(options.priority(), prefix.as_ptr(), 0) == 0
+        });
+        let value = options.run(f).expect("name prefix checked by run");
+        (value, applied)
+    });
+    Worker { handle }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::{ZpaqAddOptions, compress_to_vec, compress_to_vec_parallel, zpaq_add_opts};
+    use std::collections::BTreeMap;
+    use std::num::NonZeroUsize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Name, nice value and scheduling policy of this process's threads
+    /// named with `prefix`, polled until `done`.
+    fn watch(
+        prefix: &'static str,
+        done: Arc<AtomicBool>,
+    ) -> std::thread::JoinHandle<BTreeMap<String, (i64, u32)>> {
+        std::thread::spawn(move || {
+            let seen = Mutex::new(BTreeMap::new());
+            while !done.load(Ordering::Relaxed) {
+                for task in std::fs::read_dir("/proc/self/task")
+                    .expect("tasks")
+                    .flatten()
+                {
+                    let comm =
+                        std::fs::read_to_string(task.path().join("comm")).unwrap_or_default();
+                    let name = comm.trim_end();
+                    if !name.starts_with(prefix) {
+                        continue;
+                    }
+                    let stat =
+                        std::fs::read_to_string(task.path().join("stat")).unwrap_or_default();
+                    let Some((_, fields)) = stat.rsplit_once(") ") else {
+                        continue;
+                    };
+                    let fields: Vec<&str> = fields.split_whitespace().collect();
+                    // Fields 19 (nice) and 41 (policy), counted from 1.
+                    let nice = fields[16].parse().expect("nice");
+                    let policy = fields[38].parse().expect("policy");
+                    seen.lock()
+                        .unwrap()
+                        .insert(name.to_string(), (nice, policy));
+                }
+                std::thread::sleep(std::time::Duration::from_millis(2));
+            }
+            seen.into_inner().unwrap()
+        })
+    }
+
+    fn sample() -> Vec<u8> {
+        crate::test_support::text(12 << 20)
+    }
+
+    #[test]
+    fn shim_workers_are_named_and_niced() {
+        let data = sample();
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = watch("zpq-pool", done.clone());
+        let options = ParallelOptions {
+            thread_priority: Some(ThreadPriority::BelowNormal),
+            thread_name_prefix: Some("zpq-pool".into()),
+        };
+        let compressed = options
+            .run(|| compress_to_vec_parallel(&data, "10", 4))
+            .expect("options")
+            .expect("compress");
+        done.store(true, Ordering::Relaxed);
+        let seen = watcher.join().expect("watcher");
+
+        assert_eq!(compressed, compress_to_vec(&data, "10").expect("compress"));
+        // With one core the shim compresses on the calling thread.
+        let pooled = std::thread::available_parallelism().map_or(1, NonZeroUsize::get) > 1;
+        assert_eq!(seen.is_empty(), !pooled);
+        for (name, (nice, _)) in &seen {
+            assert!(["zpq-pool0", "zpq-pool1", "zpq-pool2", "zpq-pool3"].contains(&name.as_str()));
+            assert!(*nice >= 10, "{name} has nice {nice}");
+        }
+        // Outside the run, workers are left alone.
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = watch("zpq-pool", done.clone());
+        compress_to_vec_parallel(&data, "10", 4).expect("compress");
+        done.store(true, Ordering::Relaxed);
+        assert!(watcher.join().expect("watcher").is_empty());
+    }
+
+    #[test]
+    fn zpaq_workers_run_idle() {
+        let dir = crate::test_support::TempDir::new("zpaq-rs-workers");
+        let input = dir.join("input.txt");
+        std::fs::write(&input, sample()).expect("write");
+        let archive = dir.join("out.zpaq").to_string_lossy().into_owned();
+        let input = input.to_string_lossy().into_owned();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = watch("zpq-add", done.clone());
+        let options = ParallelOptions {
+            thread_priority: Some(ThreadPriority::Idle),
+            thread_name_prefix: Some("zpq-add".into()),
+        };
+        let add = ZpaqAddOptions {
+            method: "2".into(),
+            threads: 2,
+            ..Default::default()
+        };
+        options
+            .run(|| zpaq_add_opts(&archive, &[&input], &add))
+            .expect("options")
+            .expect("add");
+        done.store(true, Ordering::Relaxed);
+        let seen = watcher.join().expect("watcher");
+        assert!(!seen.is_empty());
+        for (name, (_, policy)) in &seen {
+            // SCHED_IDLE
+            assert_eq!(*policy, 5, "{name}");
+        }
+        let listed =
+            crate::archive_list_entries(&std::fs::read(&archive).expect("read")).expect("list");
+        assert_eq!(listed[0].size, 12 << 20);
+    }
+}
@@ -0,0 +1,383 @@
+//! Multi-part archives, split across numbered files.
+//!
+//! Like `zpaq`, an archive name containing `?` or `*` names a multi-part
+//! archive: part `n` is the name with the `?`s replaced by the digits of `n`
+//! (zero padded) or the `*` by `n`.  Parts 1, 2, ... concatenated form the
+//! archive, each `zpaq add` writes its transaction to a new part, and part 0
+//! is the optional index (`zpaq add -index`).
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+use crate::verify::{ArchiveSource, Scan};
+use crate::{Result, ZpaqError};
+
+/// An archive name, which may be a multi-part pattern such as
+/// `"backup???.zpaq"`.
+///
+/// # Example
+///
+/// ```rust
+/// let spec = zpaq_rs::ArchiveSpec::parse("backup??.zpaq")?;
+/// assert!(spec.is_multi_part());
+/// assert_eq!(spec.part(7)?, "backup07.zpaq");
+/// assert_eq!(spec.max_parts(), Some(99));
+/// assert!(spec.part(100).is_err());
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveSpec {
+    pattern: String,
+}
+
+impl ArchiveSpec {
+    /// Parses an archive name as `zpaq` does.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `archive` is empty.
+    pub fn parse(archive: &str) -> Result<Self> {
+        if archive.is_empty() {
+            return Err(ZpaqError::Ffi("archive name must not be empty".into()));
+        }
+        Ok(ArchiveSpec {
+            pattern: archive.to_string(),
+        })
+    }
+
+    /// The name as given.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the name has a `?` or `*`, making it a multi-part archive.
+    pub fn is_multi_part(&self) -> bool {
+        self.pattern.contains(['?', '*'])
+    }
+
+    /// The highest part number the pattern can name: `10^k - 1` for `k`
+    /// `?`s, or `None` if it has a `*`.  A single-file archive has one part.
+    pub fn max_parts(&self) -> Option<u64> {
+        if self.pattern.contains('*') {
+            return None;
+        }
+        let digits = self.pattern.matches('?').count() as u32;
+        if digits == 0 {
+            return Some(1);
+        }
+        Some(10u64.checked_pow(digits).map_or(u64::MAX, |n| n - 1))
+    }
+
+    /// The file name of part `n` (0 for the index).  For a single-file
+    /// archive every part is the archive itself.
+    ///
+    /// # Errors
+    ///
+    /// Fails if `n` has more digits than the pattern has `?`s.
+    pub fn part(&self, n: u64) -> Result<String> {
+        if !self.is_multi_part() {
+            return Ok(self.pattern.clone());
+        }
+        if self.max_parts().is_some_and(|max| n > max) {
+            return Err(ZpaqError::Ffi(format!(
+                "{}: too many archive parts for part {n}",
+                self.pattern
+            )));
+        }
+        // As subpart() in zpaq.cpp: fill from the right.
+        let mut rest = n;
+        let mut out = Vec::new();
+        for c in self.pattern.chars().rev() {
+            match c {
+                '?' => {
+                    out.push(char::from(b'0' + (rest % 10) as u8));
+                    rest /= 10;
+                }
+                '*' => {
+                    out.extend(rest.to_string().chars().rev());
+                    rest = 0;
+                }
+                c => out.push(c),
+            }
+        }
+        Ok(out.into_iter().rev().collect())
+    }
+
+    /// The file names of the existing parts, in order: parts 1, 2, ... up
+    /// to the first that does not exist.  Empty if the archive does not
+    /// exist.
+    pub fn parts(&self) -> Vec<String> {
+        if !self.is_multi_part() {
+            let exists = std::path::Path::new(&self.pattern).is_file();
+            return if exists {
+                vec![self.pattern.clone()]
+            } else {
+                Vec::new()
+            };
+        }
+        (1..)
+            .map_while(|n| self.part(n).ok())
+            .take_while(|name| std::path::Path::new(name).is_file())
+            .collect()
+    }
+
+    /// The file the next `zpaq add` would write: the part after the last
+    /// existing one, or the archive itself if it is not multi-part.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the pattern has too few `?`s for another part.
+    pub fn next_part(&self) -> Result<String> {
+        if !self.is_multi_part() {
+            return Ok(self.pattern.clone());
+        }
+        self.part(self.parts().len() as u64 + 1)
+    }
+
+    /// Opens the concatenation of the existing parts for reading.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the archive does not exist or a part cannot be opened.
+    pub fn open(&self) -> Result<MultiPartReader> {
+        self.open_with_first(None)
+    }
+
+    /// Like [`open`](Self::open), reading the first part through `first`,
+    /// a handle already open on it, instead of opening it again.
+    pub(crate) fn open_with_first(&self, mut first: Option<File>) -> Result<MultiPartReader> {
+        let names = self.parts();
+        if names.is_empty() {
+            let first = self.part(1)?;
+            return Err(ZpaqError::Ffi(format!("{first}: archive not found")));
+        }
+        let mut parts = Vec::with_capacity(names.len());
+        for name in names {
+            let file = match first.take() {
+                Some(mut file) => {
+                    file.seek(SeekFrom::Start(0))
+                        .map_err(|e| ZpaqError::Ffi(format!("{name}: {e}")))?;
+                    file
+                }
+                None => File::open(&name).map_err(|e| ZpaqError::Ffi(format!("{name}: {e}")))?,
+            };
+            let len = file
+                .metadata()
+                .map_err(|e| ZpaqError::Ffi(format!("{name}: {e}")))?
+                .len();
+            parts.push((file, len));
+        }
+        Ok(MultiPartReader {
+            parts,
+            current: 0,
+            pos: 0,
+        })
+    }
+}
+
+/// The parts of a multi-part archive read as one stream, from
+/// [`ArchiveSpec::open`].  Part sizes are taken when it is opened.
+#[derive(Debug)]
+pub struct MultiPartReader {
+    /// Each part and its size.
+    parts: Vec<(File, u64)>,
+    /// Part holding `pos`, whose file is positioned there.
+    current: usize,
+    pos: u64,
+}
+
+impl MultiPartReader {
+    /// Total size of the parts.
+    pub fn len(&self) -> u64 {
+        self.parts.iter().map(|&(_, len)| len).sum()
+    }
+
+    /// Whether all parts are empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Read for MultiPartReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some((file, _)) = self.parts.get_mut(self.current) {
+            let n = file.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            self.current += 1;
+            if let Some((next, _)) = self.parts.get_mut(self.current) {
+                next.seek(SeekFrom::Start(0))?;
+            }
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for MultiPartReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(p) => Some(p),
+            SeekFrom::End(d) => self.len().checked_add_signed(d),
+            SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        let mut start = 0;
+        let mut index = self.parts.len().saturating_sub(1);
+        for (i, &(_, len)) in self.parts.iter().enumerate() {
+            if target < start + len || i + 1 == self.parts.len() {
+                index = i;
+                break;
+            }
+            start += len;
+        }
+        if let Some((file, _)) = self.parts.get_mut(index) {
+            file.seek(SeekFrom::Start(target - start))?;
+        }
+        self.current = index;
+        self.pos = target;
+        Ok(target)
+    }
+}
+
+/// Returns the number of versions in the index at `index`, made by `zpaq
+/// add <parts> -index` (or `zpaq extract -index`): the number of parts of
+/// the archive it describes, and one less than the part the next add writes.
+///
+/// # Errors
+///
+/// Fails if `index` cannot be read or is not a journaling archive.
+pub fn index_versions(index: &str) -> Result<u32> {
+    Ok(scan_index(index)?.versions() as u32)
+}
+
+/// Checks that the parts named by the pattern `parts` are those the local
+/// index at `index` describes, before `zpaq add <parts> -index <index>`
+/// appends a new part.
+///
+/// Part `n` must exist for each version `n` of the index, with the size of
+/// that version's blocks in the index plus the compressed data its `h`
+/// tables record, and the part after the last must not exist yet.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// zpaq_rs::verify_index_matches_parts("local.idx", "/mnt/remote/parts???.zpaq")?;
+/// let options = zpaq_rs::ZpaqAddOptions {
+///     index: Some("local.idx".into()),
+///     ..Default::default()
+/// };
+/// zpaq_rs::zpaq_add_opts("/mnt/remote/parts???.zpaq", &["docs"], &options)?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::IndexMismatch`] for the first part that is missing,
+/// has the wrong size, or should not exist yet.
+pub fn verify_index_matches_parts(index: &str, parts: &str) -> Result<()> {
+    let spec = ArchiveSpec::parse(parts)?;
+    if !spec.is_multi_part() {
+        return Err(ZpaqError::Ffi(format!(
+            "{parts}: not a multi-part archive pattern"
+        )));
+    }
+    let scan = scan_index(index)?;
+    let extents = scan.transaction_extents();
+    let end = match scan.incomplete {
+        Some(end) => end,
+        None => std::fs::metadata(index)
+            .map_err(|e| ZpaqError::Ffi(format!("{index}: {e}")))?
+            .len(),
+    };
+    let size = |name: &str| std::fs::metadata(name).ok().map(|m| m.len());
+    for (i, &(offset, data)) in extents.iter().enumerate() {
+        // The first part also holds what precedes the first version.
+        let start = if i == 0 { 0 } else { offset };
+        let next = extents.get(i + 1).map_or(end, |&(next, _)| next);
+        let expected = next - start + data;
+        let part = spec.part(i as u64 + 1)?;
+        let actual = size(&part);
+        if actual != Some(expected) {
+            return Err(ZpaqError::IndexMismatch {
+                part,
+                expected: Some(expected),
+                actual,
+            });
+        }
+    }
+    let next = spec.part(extents.len() as u64 + 1)?;
+    if let Some(actual) = size(&next) {
+        return Err(ZpaqError::IndexMismatch {
+            part: next,
+            expected: None,
+            actual: Some(actual),
+        });
+    }
+    Ok(())
+}
+
+fn scan_index(index: &str) -> Result<Scan<'static>> {
+    let file = File::open(index).map_err(|e| ZpaqError::Ffi(format!("{index}: {e}")))?;
+    let mut source = ArchiveSource::new(BufReader::new(file), None)?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    if scan.versions() == 0 && scan.incomplete.is_none() {
+        return Err(ZpaqError::StreamingArchive);
+    }
+    Ok(scan)
+}
+
+/// Opens the archive at `archive`, joining its parts if it is multi-part.
+pub(crate) fn open_archive(archive: &str) -> Result<MultiPartReader> {
+    ArchiveSpec::parse(archive)?.open()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    #[test]
+    fn parts_are_named_and_joined_like_zpaq() {
+        let star = ArchiveSpec::parse("a*-v?.zpaq").expect("parse");
+        assert_eq!(star.part(123).expect("part"), "a12-v3.zpaq");
+        assert_eq!(star.max_parts(), None);
+        let single = ArchiveSpec::parse("plain.zpaq").expect("parse");
+        assert!(!single.is_multi_part());
+        assert_eq!(single.part(5).expect("part"), "plain.zpaq");
+        assert!(ArchiveSpec::parse("").is_err());
+
+        let dir = TempDir::new("zpaq-rs-parts");
+        let pattern = dir.join("arc?.zpaq").to_string_lossy().into_owned();
+        let spec = ArchiveSpec::parse(&pattern).expect("parse");
+        assert!(spec.parts().is_empty());
+        assert!(spec.open().is_err());
+        let contents: [&[u8]; 3] = [b"first ", b"", b"second part"];
+        for (i, data) in contents.iter().enumerate() {
+            std::fs::write(spec.part(i as u64 + 1).expect("part"), data).expect("write part");
+        }
+        // Part 0 is the index, not part of the archive.
+        std::fs::write(spec.part(0).expect("index"), b"index").expect("write index");
+        assert_eq!(spec.parts().len(), 3);
+        assert_eq!(spec.next_part().expect("next"), spec.part(4).expect("part"));
+
+        let mut reader = spec.open().expect("open");
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).expect("read");
+        assert_eq!(all, b"first second part");
+        reader.seek(SeekFrom::Start(4)).expect("seek");
+        let mut buf = [0u8; 6];
+        reader.read_exact(&mut buf).expect("read across parts");
+        assert_eq!(&buf, b"t seco");
+        assert_eq!(reader.seek(SeekFrom::End(-4)).expect("seek"), 13);
+        reader.read_exact(&mut buf[..4]).expect("read tail");
+        assert_eq!(&buf[..4], b"part");
+
+        for n in 4..=9 {
+            std::fs::write(spec.part(n).expect("part"), b"x").expect("write part");
+        }
+        assert!(spec.next_part().is_err());
+    }
+}
@@ -0,0 +1,383 @@
+//! Progress reports with an estimated time remaining, for progress bars.
+//!
+//! A [`ProgressCallback`] is called as bytes go by: through a
+//! [`ProgressReader`] around the input of a compression, a
+//! [`ProgressWriter`] around the output of a decompression, or as `zpaq`
+//! reads and writes files under [`ZpaqAddOptions::progress`] and
+//! [`ExtractOptions::progress`].  The rate, and the time remaining worked
+//! out from it, are smoothed over the reports so they do not jump about
+//! with each block.
+//!
+//! [`ZpaqAddOptions::progress`]: crate::ZpaqAddOptions::progress
+//! [`ExtractOptions::progress`]: crate::ExtractOptions::progress
+
+use std::io::{Read, Write};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Weight of the newest rate in the smoothed one.
+const SMOOTHING: f64 = 0.3;
+
+/// One report to a [`ProgressCallback`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Bytes so far.
+    pub bytes: u64,
+    /// The expected number of bytes, if known.
+    pub total: Option<u64>,
+    /// Smoothed throughput in bytes per second; 0 before the first
+    /// interval has passed.
+    pub bytes_per_sec: f64,
+    /// Estimated time to reach the total, if one is known and the rate has
+    /// been measured.  Zero once the total is reached.
+    pub remaining: Option<Duration>,
+}
+
+/// How often a [`ProgressCallback`] is called.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ProgressInterval {
+    /// Each time this many more bytes have gone by.
+    Bytes(u64),
+    /// At most this often, when bytes go by.
+    Time(Duration),
+}
+
+impl Default for ProgressInterval {
+    /// Ten times a second.
+    fn default() -> Self {
+        ProgressInterval::Time(Duration::from_millis(100))
+    }
+}
+
+/// A function called with [`Progress`] reports, with the expected total
+/// and how often to call it.
+///
+/// Cloning gives another handle on the same function.  A report is made
+/// every [`interval`](Self::interval) and once more at the end, with the
+/// final count.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+/// use zpaq_rs::{ProgressCallback, ProgressInterval};
+///
+/// let data = b"progress ".repeat(100_000);
+/// let seen = Arc::new(Mutex::new(Vec::new()));
+/// let log = seen.clone();
+/// let progress = ProgressCallback::new(move |p| log.lock().unwrap().push(p.bytes))
+///     .total(data.len() as u64)
+///     .interval(ProgressInterval::Bytes(100_000));
+/// let mut out = Vec::new();
+/// zpaq_rs::compress_stream(progress.reader(&data[..]), &mut out, "1", None, None)?;
+/// assert_eq!(seen.lock().unwrap().last(), Some(&900_000));
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Clone)]
+pub struct ProgressCallback {
+    f: Arc<dyn Fn(&Progress) + Send + Sync>,
+    total: Option<u64>,
+    interval: ProgressInterval,
+}
+
+impl ProgressCallback {
+    /// Calls `f` at the default interval, with no total.
+    pub fn new(f: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        ProgressCallback {
+            f: Arc::new(f),
+            total: None,
+            interval: ProgressInterval::default(),
+        }
+    }
+
+    /// Sets the expected number of bytes, needed for an estimate of the
+    /// time remaining.
+    pub fn total(mut self, total: u64) -> Self {
+        self.total = Some(total);
+        self
+    }
+
+    /// Sets how often `f` is called.
+    pub fn interval(mut self, interval: ProgressInterval) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Wraps `reader` so that reading from it reports progress.
+    pub fn reader<R: Read>(&self, reader: R) -> ProgressReader<R> {
+        ProgressReader {
+            inner: reader,
+            meter: Meter::new(self.clone()),
+        }
+    }
+
+    /// Wraps `writer` so that writing to it reports progress.
+    pub fn writer<W: Write>(&self, writer: W) -> ProgressWriter<W> {
+        ProgressWriter {
+            inner: writer,
+            meter: Meter::new(self.clone()),
+        }
+    }
+
+    pub(crate) fn total_or(&self, total: u64) -> Self {
+        let mut callback = self.clone();
+        callback.total.get_or_insert(total);
+        callback
+    }
+}
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProgressCallback")
+            .field("total", &self.total)
+            .field("interval", &self.interval)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Handles are equal if they call the same function the same way.
+impl PartialEq for ProgressCallback {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.f, &other.f)
+            && self.total == other.total
+            && self.interval == other.interval
+    }
+}
+
+impl Eq for ProgressCallback {}
+
+/// The count behind a [`ProgressCallback`] and when it last reported.
+#[derive(Debug)]
+pub(crate) struct Meter {
+    callback: ProgressCallback,
+    bytes: u64,
+    last_bytes: u64,
+    last_time: Instant,
+    rate: Option<f64>,
+    reported: bool,
+}
+
+impl Meter {
+    pub(crate) fn new(callback: ProgressCallback) -> Self {
+        Meter {
+            callback,
+            bytes: 0,
+            last_bytes: 0,
+            last_time: Instant::now(),
+            rate: None,
+            reported: false,
+        }
+    }
+
+    /// Counts `n` more bytes, reporting if an interval has passed.
+    pub(crate) fn advance(&mut self, n: u64) {
+        if n == 0 {
+            return;
+        }
+        self.bytes += n;
+        let due = match self.callback.interval {
+            ProgressInterval::Bytes(every) => self.bytes - self.last_bytes >= every,
+            ProgressInterval::Time(every) => self.last_time.elapsed() >= every,
+        };
+        if due {
+            self.report();
+        }
+    }
+
+    /// Reports the final count, unless it was just reported.
+    pub(crate) fn finish(&mut self) {
+        if self.bytes != self.last_bytes || !self.reported {
+            self.report();
+        }
+    }
+
+    fn report(&mut self) {
+        let now = Instant::now();
+        let seconds = now.duration_since(self.last_time).as_secs_f64();
+        if seconds > 0.0 {
+            let latest = (self.bytes - self.last_bytes) as f64 / seconds;
+            let rate = self
+                .rate
+                .map_or(latest, |rate| rate + SMOOTHING * (latest - rate));
+            self.rate = Some(rate);
+        }
+        self.last_bytes = self.bytes;
+        self.last_time = now;
+        self.reported = true;
+        let bytes_per_sec = self.rate.unwrap_or(0.0);
+        let remaining = self.callback.total.and_then(|total| {
+            let left = total.saturating_sub(self.bytes);
+            match left {
+                0 => Some(Duration::ZERO),
+                _ if bytes_per_sec > 0.0 => {
+                    Duration::try_from_secs_f64(left as f64 / bytes_per_sec).ok()
+                }
+                _ => None,
+            }
+        });
+        (self.callback.f)(&Progress {
+            bytes: self.bytes,
+            total: self.callback.total,
+            bytes_per_sec,
+            remaining,
+        });
+    }
+}
+
+/// A reader reporting to a [`ProgressCallback`]; see
+/// [`ProgressCallback::reader`].  The final report is made at the end of
+/// the input.
+#[derive(Debug)]
+pub struct ProgressReader<R> {
+    inner: R,
+    meter: Meter,
+}
+
+impl<R> ProgressReader<R> {
+    /// Returns the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ProgressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        match n {
+            0 if !buf.is_empty() => self.meter.finish(),
+            _ => self.meter.advance(n as u64),
+        }
+        Ok(n)
+    }
+}
+
+/// A writer reporting to a [`ProgressCallback`]; see
+/// [`ProgressCallback::writer`].  The final report is made when it is
+/// flushed.
+#[derive(Debug)]
+pub struct ProgressWriter<W> {
+    inner: W,
+    meter: Meter,
+}
+
+impl<W> ProgressWriter<W> {
+    /// Returns the wrapped writer.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.meter.advance(n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()?;
+        self.meter.finish();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, decompress_stream};
+    use std::sync::Mutex;
+
+    type Reports = Arc<Mutex<Vec<(Instant, Progress)>>>;
+
+    /// A callback recording its reports and when they came.
+    fn recorder(interval: ProgressInterval) -> (ProgressCallback, Reports) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let callback =
+            ProgressCallback::new(move |p| log.lock().unwrap().push((Instant::now(), *p)))
+                .interval(interval);
+        (callback, seen)
+    }
+
+    /// Gives `chunk` bytes per read, sleeping `pause` before each.
+    struct Slow {
+        left: usize,
+        chunk: usize,
+        pause: Duration,
+    }
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            std::thread::sleep(self.pause);
+            let n = self.left.min(self.chunk).min(buf.len());
+            buf[..n].fill(b'x');
+            self.left -= n;
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn byte_intervals_report_each_step_and_the_end() {
+        let (callback, seen) = recorder(ProgressInterval::Bytes(1000));
+        let mut reader = callback.total(10_500).reader(&[7u8; 10_500][..]);
+        let mut buf = [0u8; 100];
+        while reader.read(&mut buf).expect("read") > 0 {}
+        let seen = seen.lock().unwrap();
+        let bytes: Vec<u64> = seen.iter().map(|(_, p)| p.bytes).collect();
+        let mut expected: Vec<u64> = (1..=10).map(|i| i * 1000).collect();
+        expected.push(10_500);
+        assert_eq!(bytes, expected);
+        let last = seen.last().unwrap().1;
+        assert_eq!(last.total, Some(10_500));
+        assert_eq!(last.remaining, Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn time_intervals_are_kept_and_estimates_track_the_rate() {
+        // About 1000 bytes every 10 ms: 100 KB/s for 0.4 s.
+        let (callback, seen) = recorder(ProgressInterval::Time(Duration::from_millis(50)));
+        let slow = Slow {
+            left: 40_000,
+            chunk: 1000,
+            pause: Duration::from_millis(10),
+        };
+        let started = Instant::now();
+        std::io::copy(
+            &mut callback.total(40_000).reader(slow),
+            &mut std::io::sink(),
+        )
+        .expect("copy");
+        let seen = seen.lock().unwrap();
+        assert!(seen.len() >= 3, "{} reports", seen.len());
+        let mut previous = (started, 0);
+        for (i, (at, p)) in seen.iter().enumerate() {
+            assert!(p.bytes > previous.1);
+            if i + 1 < seen.len() {
+                assert!(at.duration_since(previous.0) >= Duration::from_millis(50));
+            }
+            previous = (*at, p.bytes);
+        }
+        // Halfway through, the rest takes about as long again.
+        let (_, middle) = seen[seen.len() / 2];
+        let expected = (40_000 - middle.bytes) as f64 / 100_000.0;
+        let remaining = middle.remaining.expect("estimate").as_secs_f64();
+        assert!(
+            remaining > expected / 3.0 && remaining < expected * 3.0,
+            "{remaining}s"
+        );
+    }
+
+    #[test]
+    fn adapters_compose_with_the_streaming_functions() {
+        let data = b"progress through a round trip ".repeat(50_000);
+        let compressed = compress_to_vec(&data, "1").expect("compress");
+        let (callback, seen) = recorder(ProgressInterval::Bytes(1 << 16));
+        let mut out = callback.total(data.len() as u64).writer(Vec::new());
+        decompress_stream(&compressed[..], &mut out).expect("decompress");
+        out.flush().expect("flush");
+        assert_eq!(out.into_inner(), data);
+        let seen = seen.lock().unwrap();
+        assert_eq!(seen.last().unwrap().1.bytes, data.len() as u64);
+        assert!(seen.windows(2).all(|w| w[0].1.bytes < w[1].1.bytes));
+    }
+}
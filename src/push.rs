@@ -0,0 +1,617 @@
+//! Push-style decompression: the caller hands over compressed bytes as they
+//! arrive instead of libzpaq pulling them from a reader.
+//!
+//! libzpaq cannot stop part way through a header or a decoding step and
+//! resume later: running out of input there is an error.  So
+//! [`PushDecompressor`] keeps the input libzpaq has not consumed yet (its
+//! read-ahead counted through `zpaq_decompresser_buffered`), parses block
+//! and segment headers itself to wait until they are complete, and decodes
+//! segment data only as far as the input at hand is sure to reach.
+
+use std::io::Read;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use crate::{
+    FfiReader, FfiWriter, Result, SharedVecWriter, ZpaqError, clear_last_error, err_from_last,
+    handle, sys,
+};
+
+/// The 13-byte block tag followed by `zPQ`, which `findBlock` looks for.
+const BLOCK_MARKER: &[u8; 16] = b"7kSt\xa0\x31\x83\xd3\x8c\xb2\x28\xb0\xd3zPQ";
+
+/// Input bytes one decoded byte can take at most: nine arithmetic-coded
+/// bits (end flag and eight data bits), each shifting in up to four bytes.
+const MODELED_BYTES_PER_BYTE: u64 = 36;
+
+/// The same for store blocks: the byte itself and a chunk length.
+const STORED_BYTES_PER_BYTE: u64 = 5;
+
+/// Bytes read to start decoding a segment.
+const SEGMENT_START: u64 = 4;
+
+/// Decoded bytes the first call of a block may spend loading a
+/// postprocessor: its type, length and up to 65535 bytes of code.
+const MAX_PCOMP_BYTES: u64 = 3 + 65535;
+
+/// Most bytes decoded per call.
+const CHUNK: u64 = 1 << 20;
+
+/// What one [`PushDecompressor::feed`] produced.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FeedResult {
+    /// Decompressed bytes released by this feed.
+    pub output: Vec<u8>,
+    /// `true` if the stream is inside a block (or holds part of the next
+    /// block's header) and cannot be decoded further without more input;
+    /// `false` at a block boundary, where the stream may end.
+    pub needs_more: bool,
+    /// Blocks completed during this feed.
+    pub finished_blocks: u32,
+}
+
+/// Decompresses a ZPAQ stream pushed in pieces of any size, such as
+/// network frames.
+///
+/// Unlike [`decompress_stream`](crate::decompress_stream), which pulls from
+/// a reader until it is done, each [`feed`](Self::feed) returns as soon as
+/// the input so far is used up.  A piece may end anywhere, including inside
+/// a block header or a segment's filename.
+///
+/// Output follows input closely but not exactly: segment data is decoded
+/// only as far as the bytes at hand are certain to cover, libzpaq releases
+/// decoded bytes in runs of 16 KiB, and a block with a postprocessor starts
+/// producing output after about 2.4 MB of its input or once its first
+/// segment is complete.  Everything is out by the end of each segment.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::PushDecompressor;
+///
+/// let data = b"pushed one frame at a time ".repeat(100);
+/// let compressed = zpaq_rs::compress_to_vec(&data, "1")?;
+/// let mut push = PushDecompressor::new()?;
+/// let mut out = Vec::new();
+/// for frame in compressed.chunks(10) {
+///     out.extend(push.feed(frame)?.output);
+/// }
+/// push.finish()?;
+/// assert_eq!(out, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub struct PushDecompressor {
+    decompresser: handle::Decompresser,
+    input: Arc<Mutex<Window>>,
+    output: SharedVecWriter,
+    state: State,
+    /// Whether the current block's components decode with a model (store
+    /// blocks have none).
+    modeled: bool,
+    /// Whether the current block may still have a postprocessor to load.
+    pcomp_pending: bool,
+    failed: bool,
+    // Dropped after the decompresser that refers to them.
+    _reader: FfiReader<'static>,
+    _writer: FfiWriter<'static>,
+}
+
+// SAFETY: the decompresser and the callbacks' state are owned by the value
+// and freed with it, and the window and output it shares with the
+// callbacks are behind `Arc<Mutex<_>>`, so the whole can move between
+// threads.  Not `Sync`; feeding needs `&mut self`.
+unsafe impl Send for PushDecompressor {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Before a block header.
+    Block,
+    /// Before a segment header or the end of the block.
+    Segment,
+    /// In segment data; `ended` once the decompresser has decoded all of it.
+    Data { scan: SegmentScan, ended: bool },
+}
+
+/// The input libzpaq has not consumed yet.
+#[derive(Default)]
+struct Window {
+    bytes: Vec<u8>,
+    /// Stream offset of `bytes[0]`.
+    start: u64,
+    /// Stream offset up to which bytes were handed to libzpaq.
+    read: u64,
+    /// Stream offset up to which libzpaq has used them.
+    consumed: u64,
+}
+
+impl Window {
+    fn end(&self) -> u64 {
+        self.start + self.bytes.len() as u64
+    }
+
+    /// The bytes libzpaq has not used.
+    fn pending(&self) -> &[u8] {
+        &self.bytes[(self.consumed - self.start) as usize..]
+    }
+}
+
+struct WindowReader(Arc<Mutex<Window>>);
+
+impl Read for WindowReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut window = self
+            .0
+            .lock()
+            .map_err(|_| std::io::Error::other("poisoned input window"))?;
+        let from = (window.read - window.start) as usize;
+        let n = buf.len().min(window.bytes.len() - from);
+        buf[..n].copy_from_slice(&window.bytes[from..from + n]);
+        window.read += n as u64;
+        Ok(n)
+    }
+}
+
+impl PushDecompressor {
+    /// Creates a decompressor expecting the start of a stream.
+    pub fn new() -> Result<Self> {
+        clear_last_error()?;
+        let input = Arc::new(Mutex::new(Window::default()));
+        let output = SharedVecWriter::new();
+        let reader = FfiReader::new(WindowReader(Arc::clone(&input)))?;
+        let writer = FfiWriter::new(output.clone())?;
+        let decompresser = handle::Decompresser::new()?;
+        let push = PushDecompressor {
+            decompresser,
+            input,
+            output,
+            state: State::Block,
+            modeled: false,
+            pcomp_pending: false,
+            failed: false,
+            _reader: reader,
+            _writer: writer,
+        };
+        let d = push.decompresser.raw;
+        if unsafe { sys::zpaq_decompresser_set_input(d, push._reader.raw) } != 0
+            || unsafe { sys::zpaq_decompresser_set_output(d, push._writer.raw) } != 0
+        {
+            return Err(err_from_last());
+        }
+        Ok(push)
+    }
+
+    /// Adds `data` to the stream and decodes as much as it allows.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the stream is damaged.  The decompressor cannot continue
+    /// after an error, and later calls fail too.
+    pub fn feed(&mut self, data: &[u8]) -> Result<FeedResult> {
+        if self.failed {
+            return Err(ZpaqError::Ffi(
+                "push decompressor failed on earlier input".into(),
+            ));
+        }
+        self.window().bytes.extend_from_slice(data);
+        let mut finished_blocks = 0;
+        if let Err(err) = self.advance(&mut finished_blocks) {
+            self.failed = true;
+            return Err(err);
+        }
+        Ok(FeedResult {
+            output: self.output.take(),
+            needs_more: !self.at_boundary(),
+            finished_blocks,
+        })
+    }
+
+    /// Checks that the stream ended at a block boundary.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the input stopped inside a block, or part way into the
+    /// header of another.
+    pub fn finish(self) -> Result<()> {
+        if self.failed || !self.at_boundary() {
+            return Err(ZpaqError::Ffi(
+                "compressed stream ends inside a block".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    fn window(&self) -> std::sync::MutexGuard<'_, Window> {
+        self.input.lock().expect("poisoned input window")
+    }
+
+    /// Whether all input so far is decoded up to the end of a block.
+    fn at_boundary(&self) -> bool {
+        self.state == State::Block && self.window().pending().is_empty()
+    }
+
+    /// Updates how much input libzpaq has consumed and returns the window.
+    /// Consumed bytes are dropped once they are a good part of it.
+    fn consumed_window(&self) -> std::sync::MutexGuard<'_, Window> {
+        let buffered = unsafe { sys::zpaq_decompresser_buffered(self.decompresser.raw) }.max(0);
+        let mut window = self.window();
+        window.consumed = window.read - buffered as u64;
+        let used = (window.consumed - window.start) as usize;
+        if used >= window.bytes.len() / 2 {
+            window.bytes.drain(..used);
+            window.start = window.consumed;
+        }
+        window
+    }
+
+    fn advance(&mut self, finished_blocks: &mut u32) -> Result<()> {
+        let d = self.decompresser.raw;
+        loop {
+            match self.state {
+                State::Block => {
+                    {
+                        let window = self.consumed_window();
+                        let pending = window.pending();
+                        match block_header_len(pending) {
+                            Some(len) if len <= pending.len() => {}
+                            _ => return Ok(()),
+                        }
+                    }
+                    let rc = unsafe { sys::zpaq_decompresser_find_block(d, ptr::null_mut()) };
+                    if rc <= 0 {
+                        return Err(err_from_last());
+                    }
+                    let components = unsafe { sys::zpaq_decompresser_components(d) };
+                    if components < 0 {
+                        return Err(err_from_last());
+                    }
+                    self.modeled = components > 0;
+                    self.pcomp_pending = true;
+                    self.state = State::Segment;
+                }
+                State::Segment => {
+                    {
+                        let window = self.consumed_window();
+                        let pending = window.pending();
+                        match pending.first() {
+                            None => return Ok(()),
+                            Some(1) if !segment_header_complete(pending) => return Ok(()),
+                            _ => {}
+                        }
+                    }
+                    let rc = unsafe { sys::zpaq_decompresser_find_filename(d, ptr::null_mut()) };
+                    if rc < 0 {
+                        return Err(err_from_last());
+                    }
+                    if rc == 0 {
+                        *finished_blocks += 1;
+                        self.state = State::Block;
+                        continue;
+                    }
+                    if unsafe { sys::zpaq_decompresser_read_comment(d, ptr::null_mut()) } != 0 {
+                        return Err(err_from_last());
+                    }
+                    let scan = SegmentScan::new(self.modeled, self.consumed_window().consumed);
+                    self.state = State::Data { scan, ended: false };
+                }
+                State::Data { mut scan, ended } => {
+                    let (available, complete) = {
+                        let window = self.consumed_window();
+                        let from = (scan.pos - window.start) as usize;
+                        scan.scan(&window.bytes[from..]);
+                        (window.end() - window.consumed, scan.complete(window.end()))
+                    };
+                    if complete {
+                        if !ended {
+                            self.decode(u64::MAX)?;
+                        }
+                        if unsafe { sys::zpaq_decompresser_read_segment_end(d, ptr::null_mut()) }
+                            != 0
+                        {
+                            return Err(err_from_last());
+                        }
+                        self.pcomp_pending = false;
+                        self.state = State::Segment;
+                        continue;
+                    }
+                    self.state = State::Data { scan, ended };
+                    if ended {
+                        return Ok(());
+                    }
+                    let per_byte = if self.modeled {
+                        MODELED_BYTES_PER_BYTE
+                    } else {
+                        STORED_BYTES_PER_BYTE
+                    };
+                    let reserved = if self.pcomp_pending {
+                        MAX_PCOMP_BYTES
+                    } else {
+                        0
+                    };
+                    let safe = (available.saturating_sub(SEGMENT_START) / per_byte)
+                        .saturating_sub(reserved);
+                    if safe == 0 {
+                        return Ok(());
+                    }
+                    let ended = !self.decode(safe)?;
+                    self.pcomp_pending = false;
+                    self.state = State::Data { scan, ended };
+                }
+            }
+        }
+    }
+
+    /// Decodes up to `n` bytes of the current segment; `false` once the
+    /// segment's data is all decoded.
+    fn decode(&mut self, mut n: u64) -> Result<bool> {
+        while n > 0 {
+            let step = n.min(CHUNK);
+            let rc =
+                unsafe { sys::zpaq_decompresser_decompress(self.decompresser.raw, step as i32) };
+            if rc < 0 {
+                return Err(err_from_last());
+            }
+            if rc == 0 {
+                return Ok(false);
+            }
+            n -= step;
+        }
+        Ok(true)
+    }
+}
+
+/// Where a block header starts in `bytes`, if anywhere.
+fn marker_start(bytes: &[u8]) -> Option<usize> {
+    bytes
+        .windows(BLOCK_MARKER.len())
+        .position(|w| w == BLOCK_MARKER)
+}
+
+/// Bytes up to the end of the first block header in `bytes`: the marker,
+/// level and type, the two-byte header size and the header itself.  `None`
+/// until the size is known.
+fn block_header_len(bytes: &[u8]) -> Option<usize> {
+    let start = marker_start(bytes)?;
+    let size_at = start + BLOCK_MARKER.len() + 2;
+    let size = bytes.get(size_at..size_at + 2)?;
+    Some(size_at + 2 + usize::from(u16::from_le_bytes([size[0], size[1]])))
+}
+
+/// Whether `bytes`, starting at a segment's `1` byte, hold its whole
+/// header: filename, comment and the reserved byte.
+fn segment_header_complete(bytes: &[u8]) -> bool {
+    let mut nuls = bytes
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|&(_, &b)| b == 0)
+        .map(|(i, _)| i);
+    let (Some(_), Some(comment_end)) = (nuls.next(), nuls.next()) else {
+        return false;
+    };
+    bytes.len() > comment_end + 1
+}
+
+/// Finds the end of a segment's data the way libzpaq's `Decoder::skip`
+/// does, from bytes seen once each as they arrive.
+///
+/// Arithmetic-coded data ends with four zero bytes, which do not otherwise
+/// occur in it after the first nonzero byte; stored data is a sequence of
+/// chunks with big-endian lengths, ending with a zero length.  Either is
+/// followed by the end marker: 254, or 253 and a SHA-1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SegmentScan {
+    modeled: bool,
+    /// Stream offset of the next byte to scan.
+    pos: u64,
+    /// The last four bytes (modeled) or the chunk length being read.
+    curr: u32,
+    /// Bytes of the current chunk length read, or left in the chunk.
+    count: u32,
+    phase: ScanPhase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ScanPhase {
+    /// Modeled: zero bytes before the data; stored: reading a length.
+    Start,
+    /// Modeled: in the data; stored: in a chunk.
+    Data,
+    /// Past the final zeros, before the end marker.
+    Trailer,
+    /// At the end marker, found at this offset.
+    Marker(u64, u8),
+}
+
+impl SegmentScan {
+    fn new(modeled: bool, pos: u64) -> Self {
+        SegmentScan {
+            modeled,
+            pos,
+            curr: 0,
+            count: 0,
+            phase: ScanPhase::Start,
+        }
+    }
+
+    fn scan(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            if let ScanPhase::Marker(..) = self.phase {
+                return;
+            }
+            let here = self.pos;
+            self.pos += 1;
+            self.phase = match (self.modeled, self.phase) {
+                (true, ScanPhase::Start) if b == 0 => ScanPhase::Start,
+                (true, ScanPhase::Start) => {
+                    self.curr = u32::from(b);
+                    ScanPhase::Data
+                }
+                (true, ScanPhase::Data) => {
+                    self.curr = self.curr << 8 | u32::from(b);
+                    if self.curr == 0 {
+                        ScanPhase::Trailer
+                    } else {
+                        ScanPhase::Data
+                    }
+                }
+                (true, ScanPhase::Trailer) if b == 0 => ScanPhase::Trailer,
+                (false, ScanPhase::Start) => {
+                    self.curr = self.curr << 8 | u32::from(b);
+                    self.count += 1;
+                    match (self.count, self.curr) {
+                        (4, 0) => ScanPhase::Trailer,
+                        (4, len) => {
+                            self.count = len;
+                            ScanPhase::Data
+                        }
+                        _ => ScanPhase::Start,
+                    }
+                }
+                (false, ScanPhase::Data) => {
+                    self.count -= 1;
+                    if self.count == 0 {
+                        self.curr = 0;
+                        ScanPhase::Start
+                    } else {
+                        ScanPhase::Data
+                    }
+                }
+                (_, ScanPhase::Trailer) => ScanPhase::Marker(here, b),
+                (_, phase) => phase,
+            };
+        }
+    }
+
+    /// Whether the data and its end marker lie before stream offset `end`.
+    fn complete(&self, end: u64) -> bool {
+        match self.phase {
+            ScanPhase::Marker(at, 253) => end >= at + 21,
+            ScanPhase::Marker(..) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        ArchiveEntry, archive_from_entries, compress_to_vec, list_blocks, peek_block, test_support,
+    };
+
+    /// Blocks of every kind: with and without a postprocessor, stored, and
+    /// with several segments.
+    fn stream() -> (Vec<u8>, Vec<u8>) {
+        let text = test_support::text(120_000);
+        let noise = test_support::random(1, 50_000);
+        let entries = [
+            ArchiveEntry {
+                path: "a",
+                data: &text[..1000],
+                comment: Some("first"),
+            },
+            ArchiveEntry {
+                path: "b",
+                data: &text[1000..],
+                comment: None,
+            },
+        ];
+        let mut stream = compress_to_vec(&text[..50_000], "1").expect("compress");
+        stream.extend(compress_to_vec(&noise, "0").expect("store"));
+        stream.extend(archive_from_entries(&entries, "1").expect("entries"));
+        stream.extend(compress_to_vec(b"", "1").expect("empty"));
+        stream.extend(compress_to_vec(&text[..5000], "3").expect("compress"));
+
+        let mut expected = text[..50_000].to_vec();
+        expected.extend(&noise);
+        expected.extend(&text);
+        expected.extend(&text[..5000]);
+        (stream, expected)
+    }
+
+    fn push_all<'a>(pieces: impl IntoIterator<Item = &'a [u8]>) -> (Vec<u8>, u32, bool) {
+        let mut push = PushDecompressor::new().expect("new");
+        let mut out = Vec::new();
+        let mut blocks = 0;
+        let mut needs_more = true;
+        for piece in pieces {
+            let fed = push.feed(piece).expect("feed");
+            out.extend(fed.output);
+            blocks += fed.finished_blocks;
+            needs_more = fed.needs_more;
+        }
+        push.finish().expect("finish");
+        (out, blocks, needs_more)
+    }
+
+    #[test]
+    fn any_split_reassembles() {
+        let (stream, expected) = stream();
+        let blocks = list_blocks(&stream).expect("list").len() as u32;
+        let cuts = (0..=stream.len())
+            .step_by(stream.len() / 150)
+            .chain(0..64)
+            .chain(stream.len() - 64..=stream.len());
+        for cut in cuts {
+            let (out, finished, needs_more) = push_all([&stream[..cut], &stream[cut..]]);
+            assert!(out == expected, "split at {cut}");
+            assert_eq!(finished, blocks, "split at {cut}");
+            assert!(!needs_more);
+        }
+    }
+
+    #[test]
+    fn small_frames_reassemble() {
+        let (stream, expected) = stream();
+        for size in [1, 7, 1500] {
+            let (out, _, needs_more) = push_all(stream.chunks(size));
+            assert!(out == expected, "frames of {size}");
+            assert!(!needs_more);
+        }
+    }
+
+    #[test]
+    fn large_segments_decode_as_input_arrives() {
+        // Stored data is released almost as it arrives; modeled data only
+        // as far as the worst case of 36 input bytes per byte is covered.
+        let nibbles: Vec<u8> = test_support::random(2, 8 << 20)
+            .iter()
+            .map(|b| b & 15)
+            .collect();
+        let data = [test_support::random(3, 1 << 20), nibbles];
+        for (data, method) in data.iter().zip(["0", "1"]) {
+            let stream = compress_to_vec(data, method).expect("compress");
+            let block = peek_block(&stream).expect("peek").expect("block");
+            assert_eq!(block.stored, method == "0");
+            let mut push = PushDecompressor::new().expect("new");
+            let mut out = Vec::new();
+            let frames = stream.chunks(stream.len() / 40 + 1).collect::<Vec<_>>();
+            for (i, frame) in frames.iter().enumerate() {
+                let fed = push.feed(frame).expect("feed");
+                assert_eq!(fed.needs_more, i + 1 < frames.len(), "method {method}");
+                out.extend(fed.output);
+                if i + 2 == frames.len() {
+                    let least = if method == "0" { data.len() / 2 } else { 1 };
+                    assert!(out.len() >= least, "method {method}: {}", out.len());
+                }
+            }
+            push.finish().expect("finish");
+            assert!(out == *data, "method {method}");
+        }
+    }
+
+    #[test]
+    fn truncation_and_damage_are_reported() {
+        let (stream, _) = stream();
+        let mut push = PushDecompressor::new().expect("new");
+        let fed = push.feed(&stream[..stream.len() - 3]).expect("feed");
+        assert!(fed.needs_more);
+        assert!(push.finish().is_err());
+
+        let mut damaged = stream.clone();
+        damaged[20] ^= 0xff;
+        let mut push = PushDecompressor::new().expect("new");
+        assert!(push.feed(&damaged).is_err());
+        assert!(push.feed(b"").is_err());
+    }
+}
@@ -0,0 +1,285 @@
+//! What a read of zero bytes means.
+//!
+//! `Read` says `Ok(0)` is the end of the input, and the read callbacks
+//! take it so.  Some readers break that rule, such as a non-blocking socket
+//! used as a blocking one, and return `Ok(0)` while no data happens to be
+//! there; their input then ends early without an error.  A [`ReadPolicy`]
+//! retries such reads, or fails them when the caller knows the length.
+
+use std::cell::Cell;
+use std::io::{self, Read};
+use std::time::Duration;
+
+/// How readers treat a read that returns `Ok(0)`.
+///
+/// The policy in force for a call is the one of the innermost
+/// [`run`](Self::run) on the calling thread, or
+/// [`CompressOptions::read_policy`](crate::CompressOptions::read_policy).
+/// It applies to every reader the library hands to the shim during the
+/// call, including those of parallel compression (which reads on the
+/// calling thread) and the cursors over in-memory archives, which never
+/// return a spurious `Ok(0)`.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+/// use zpaq_rs::ReadPolicy;
+///
+/// let data = b"patience pays off. ".repeat(1000);
+/// let policy = ReadPolicy::RetryZeroReads { max_retries: 3, backoff: Duration::ZERO };
+/// let compressed = policy.run(|| zpaq_rs::compress_to_vec_parallel(&data, "1", 2))?;
+/// assert_eq!(zpaq_rs::decompress_to_vec(&compressed)?, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum ReadPolicy {
+    /// `Ok(0)` ends the input, as `Read` specifies.  A reader returning it
+    /// spuriously has its input cut short there, and the output is a
+    /// valid archive of the part read.
+    #[default]
+    TreatZeroAsEof,
+    /// Retry a read that returns `Ok(0)`, sleeping `backoff` before each
+    /// retry, and take `max_retries` zero reads in a row after the first
+    /// as the end.  The true end of the input costs those retries too.
+    RetryZeroReads {
+        /// Retries after a zero read before it counts as the end.
+        max_retries: u32,
+        /// Pause before each retry.
+        backoff: Duration,
+    },
+    /// Fail a read that returns `Ok(0)` before `expected_len` bytes have
+    /// come from the reader, with an [`io::ErrorKind::UnexpectedEof`]
+    /// error.  Zero reads after that end the input.
+    ErrorOnZeroBeforeExpected {
+        /// Length of the input.
+        expected_len: u64,
+    },
+}
+
+thread_local! {
+    /// The policy of the innermost [`ReadPolicy::run`] on this thread.
+    static CURRENT: Cell<ReadPolicy> = const { Cell::new(ReadPolicy::TreatZeroAsEof) };
+}
+
+impl ReadPolicy {
+    /// Runs `f` with this policy applied to the readers of the calls it
+    /// makes on this thread.  Runs nest, the innermost applying.
+    pub fn run<T>(&self, f: impl FnOnce() -> T) -> T {
+        let _scope = Scope {
+            outer: CURRENT.replace(*self),
+        };
+        f()
+    }
+
+    /// Wraps `inner` so its reads follow this policy.
+    pub fn reader<R: Read>(self, inner: R) -> PolicyReader<R> {
+        PolicyReader {
+            inner,
+            policy: self,
+            bytes: 0,
+        }
+    }
+
+    /// The policy in force on this thread.
+    pub(crate) fn current() -> ReadPolicy {
+        CURRENT.get()
+    }
+
+    /// Reads from `reader` into `buf` under this policy; `bytes` counts
+    /// what the reader has returned so far.
+    pub(crate) fn read(
+        &self,
+        reader: &mut impl Read,
+        buf: &mut [u8],
+        bytes: &mut u64,
+    ) -> io::Result<usize> {
+        let mut n = reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            match *self {
+                ReadPolicy::TreatZeroAsEof => {}
+                ReadPolicy::RetryZeroReads {
+                    max_retries,
+                    backoff,
+                } => {
+                    for _ in 0..max_retries {
+                        std::thread::sleep(backoff);
+                        n = reader.read(buf)?;
+                        if n > 0 {
+                            break;
+                        }
+                    }
+                }
+                ReadPolicy::ErrorOnZeroBeforeExpected { expected_len } => {
+                    if *bytes < expected_len {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("reader returned 0 after {bytes} of {expected_len} bytes"),
+                        ));
+                    }
+                }
+            }
+        }
+        *bytes += n as u64;
+        Ok(n)
+    }
+}
+
+/// A [`ReadPolicy::run`] in progress; restores the enclosing policy when
+/// dropped, so a panic in the closure leaves the thread as it was.
+struct Scope {
+    outer: ReadPolicy,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.set(self.outer);
+    }
+}
+
+/// A [`Read`] adapter applying a [`ReadPolicy`] to `inner`, made by
+/// [`ReadPolicy::reader`].
+#[derive(Debug)]
+pub struct PolicyReader<R> {
+    inner: R,
+    policy: ReadPolicy,
+    bytes: u64,
+}
+
+impl<R> PolicyReader<R> {
+    /// Returns the inner reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for PolicyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.policy.read(&mut self.inner, buf, &mut self.bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CompressOptions, compress_stream, compress_stream_parallel, compress_stream_with,
+        decompress_to_vec, test_support,
+    };
+
+    /// Returns `Ok(0)` before every other read, and at most `chunk` bytes
+    /// a read, like a non-blocking socket read in a loop.
+    struct Flaky<'a> {
+        data: &'a [u8],
+        chunk: usize,
+        idle: bool,
+    }
+
+    fn flaky(data: &[u8]) -> Flaky<'_> {
+        Flaky {
+            data,
+            chunk: 1000,
+            idle: false,
+        }
+    }
+
+    impl Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.idle = !self.idle;
+            if self.idle {
+                return Ok(0);
+            }
+            let n = buf.len().min(self.chunk).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Ok(n)
+        }
+    }
+
+    const RETRY: ReadPolicy = ReadPolicy::RetryZeroReads {
+        max_retries: 2,
+        backoff: Duration::ZERO,
+    };
+
+    #[test]
+    fn zero_reads_end_the_input_by_default() {
+        let data = test_support::text(50_000);
+        let mut out = Vec::new();
+        compress_stream(flaky(&data), &mut out, "1", None, None).expect("compress");
+        // The first read is a zero, so nothing was read.
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), b"");
+
+        let mut out = Vec::new();
+        let mut reader = flaky(&data);
+        reader.idle = true;
+        compress_stream_parallel(reader, &mut out, "1", None, None, 4).expect("compress");
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), &data[..1000]);
+    }
+
+    #[test]
+    fn retries_read_the_whole_input() {
+        let data = test_support::text(300_000);
+        for threads in [0, 1, 4] {
+            let mut out = Vec::new();
+            RETRY
+                .run(|| compress_stream_parallel(flaky(&data), &mut out, "1", None, None, threads))
+                .expect("compress");
+            assert_eq!(
+                decompress_to_vec(&out).expect("decompress"),
+                data,
+                "threads={threads}"
+            );
+        }
+        // The policy ends with the run.
+        assert_eq!(ReadPolicy::current(), ReadPolicy::TreatZeroAsEof);
+
+        let options = CompressOptions {
+            read_policy: RETRY,
+            on_already_compressed: crate::AlreadyCompressedPolicy::Reject,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        let stats = compress_stream_with(flaky(&data), &mut out, "1", &options).expect("compress");
+        assert_eq!(stats.bytes_in, data.len() as u64);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), data);
+    }
+
+    #[test]
+    fn expected_lengths_turn_early_zeros_into_errors() {
+        let data = test_support::text(10_000);
+        let policy = ReadPolicy::ErrorOnZeroBeforeExpected {
+            expected_len: data.len() as u64,
+        };
+        let mut out = Vec::new();
+        let err = policy
+            .run(|| compress_stream(flaky(&data), &mut out, "1", None, None))
+            .expect_err("early zero");
+        assert!(err.to_string().contains("callback failed"), "{err}");
+
+        let mut reader = policy.reader(flaky(&data));
+        let mut chunk = [0; 1000];
+        let err = reader.read(&mut chunk).expect_err("early zero");
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(err.to_string(), "reader returned 0 after 0 of 10000 bytes");
+
+        let mut out = Vec::new();
+        policy
+            .run(|| compress_stream(&data[..], &mut out, "1", None, None))
+            .expect("compress");
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), data);
+    }
+
+    #[test]
+    fn policy_readers_count_and_retry() {
+        let data = test_support::text(5000);
+        let mut read = Vec::new();
+        RETRY
+            .reader(flaky(&data))
+            .read_to_end(&mut read)
+            .expect("read");
+        assert_eq!(read, data);
+
+        let mut never = RETRY.reader(io::empty());
+        assert_eq!(never.read(&mut [0; 8]).expect("read"), 0);
+    }
+}
@@ -0,0 +1,1215 @@
+//! Random access to the files of an archive.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+
+use crate::comment::decode_extra_comment;
+use crate::format::require_plain;
+use crate::journal::EntryMeta;
+use crate::parts::open_archive;
+use crate::verify::{
+    ArchiveSource, Scan, SegmentSink, fragment_sizes, read_segment_at, read_segments_at,
+};
+use crate::{
+    ArchiveEntryInfo, ArchiveEntryVersion, MemberFilter, MultiPartReader, Result, VerifyErrorKind,
+    VerifyLocation, ZpaqError, io_err, sha1,
+};
+
+/// Reads files from an archive without decoding the rest of it.
+///
+/// [`open`](Self::open) reads the archive's structure once: for journaling
+/// archives only the `c`, `h` and `i` blocks are decoded; streaming archives
+/// are decoded once to find where each file's segments are.  Each read then
+/// decodes only the blocks holding the file's data, so reading many files is
+/// linear in their size rather than in the archive's.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// let mut reader = zpaq_rs::ArchiveReader::open_path("backup.zpaq")?;
+/// let paths: Vec<String> = reader.entries().map(|e| e.path.clone()).collect();
+/// for path in paths {
+///     let data = reader.read(&path)?;
+///     println!("{path}: {} bytes", data.len());
+/// }
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub struct ArchiveReader<R: Read + Seek + Send> {
+    source: ArchiveSource<R>,
+    layout: Layout,
+    /// Files of the latest version, by path.
+    entries: Vec<ArchiveEntryInfo>,
+    /// The last `d` block decoded, by offset.
+    cache: Option<(u64, DecodedBlock)>,
+}
+
+/// The data of a `d` block and the offset of each fragment in it.
+type DecodedBlock = (Vec<u8>, Vec<usize>);
+
+enum Layout {
+    Journal {
+        scan: Box<Scan<'static, dyn SegmentSink + Send>>,
+        fragments: HashMap<u32, Fragment>,
+        blocks: Vec<VerifyLocation>,
+    },
+    Streaming {
+        files: HashMap<String, Vec<SegmentRef>>,
+        /// Every named file, in archive order, with its block number as the
+        /// version.
+        history: Vec<ArchiveEntryVersion>,
+    },
+}
+
+/// Where a fragment is stored and what it must hash to.
+struct Fragment {
+    /// Index into the `d` block locations.
+    block: usize,
+    /// Position in the block.
+    ordinal: usize,
+    size: u32,
+    sha1: [u8; 20],
+}
+
+impl ArchiveReader<BufReader<MultiPartReader>> {
+    /// Opens the unencrypted archive at `path`, which may be a multi-part
+    /// pattern (see [`ArchiveSpec`](crate::ArchiveSpec)).
+    pub fn open_path(path: &str) -> Result<Self> {
+        Self::open(BufReader::new(open_archive(path)?))
+    }
+}
+
+impl<R: Read + Seek + Send> ArchiveReader<R> {
+    /// Opens an unencrypted archive.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::WrongFormat`] if the archive is encrypted or not
+    /// an archive at all.  Fails if any block cannot be decoded or fails its
+    /// checksum; see
+    /// [`verify_archive_reader`](crate::verify_archive_reader) and
+    /// [`salvage_extract_reader`](crate::salvage_extract_reader) for damaged
+    /// archives.  An incomplete last transaction is ignored, as by `zpaq`.
+    pub fn open(mut reader: R) -> Result<Self> {
+        require_plain(&mut reader)?;
+        Self::from_source(ArchiveSource::new(reader, None)?)
+    }
+
+    /// Opens an archive encrypted with `key` (the `-key` argument of
+    /// `zpaq`).
+    pub fn with_key(reader: R, key: &str) -> Result<Self> {
+        Self::from_source(ArchiveSource::new(reader, Some(key))?)
+    }
+
+    fn from_source(mut source: ArchiveSource<R>) -> Result<Self> {
+        let mut scan = Scan::default();
+        scan.scan(&mut source)?;
+        check_scan(&scan)?;
+        let versions = scan.versions();
+        if versions == 0 {
+            // Streaming segments were skipped; decode them once to index them.
+            let mut segments = SegmentIndex::default();
+            let mut scan = Scan::with_sink(&mut segments);
+            source.seek_to(source.data_start)?;
+            scan.scan(&mut source)?;
+            check_scan(&scan)?;
+            let (entries, files) = segments
+                .files
+                .into_iter()
+                .map(|(path, (info, segments))| (info, (path, segments)))
+                .unzip();
+            let history = segments.history;
+            return Ok(ArchiveReader {
+                source,
+                layout: Layout::Streaming { files, history },
+                entries,
+                cache: None,
+            });
+        }
+
+        let mut blocks = Vec::new();
+        let mut fragments = HashMap::new();
+        for table in &scan.tables {
+            let Some(block) = scan
+                .data_blocks
+                .iter()
+                .find(|d| d.location.offset == table.data_offset && d.first == table.first)
+            else {
+                continue;
+            };
+            for (ordinal, &(size, sha1)) in table.fragments.iter().enumerate() {
+                let fragment = Fragment {
+                    block: blocks.len(),
+                    ordinal,
+                    size,
+                    sha1,
+                };
+                fragments.insert(table.first + ordinal as u32, fragment);
+            }
+            blocks.push(block.location.clone());
+        }
+
+        let mut reader = ArchiveReader {
+            source,
+            layout: Layout::Journal {
+                scan: Box::new(scan.detach()),
+                fragments,
+                blocks,
+            },
+            entries: Vec::new(),
+            cache: None,
+        };
+        reader.entries = reader.journal_entries(versions)?;
+        Ok(reader)
+    }
+
+    /// The files of the latest version, sorted by path.  Directories are
+    /// omitted.
+    pub fn entries(&self) -> impl Iterator<Item = &ArchiveEntryInfo> {
+        self.entries.iter()
+    }
+
+    /// Whether the latest version has a file stored as `path`.
+    pub fn contains(&self, path: &str) -> bool {
+        self.entries
+            .binary_search_by(|e| e.path.as_str().cmp(path))
+            .is_ok()
+    }
+
+    /// Number of versions of a journaling archive (0 for streaming archives).
+    pub fn versions(&self) -> usize {
+        match &self.layout {
+            Layout::Journal { scan, .. } => scan.versions(),
+            Layout::Streaming { .. } => 0,
+        }
+    }
+
+    /// The structure of a journaling archive, as read on opening.
+    pub(crate) fn journal_scan(&self) -> Option<&Scan<'static, dyn SegmentSink + Send>> {
+        match &self.layout {
+            Layout::Journal { scan, .. } => Some(scan),
+            Layout::Streaming { .. } => None,
+        }
+    }
+
+    /// Reads the file stored as `path` in the latest version.
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        self.read_to(path, &mut out)?;
+        Ok(out)
+    }
+
+    /// Writes the file stored as `path` in the latest version to `out` and
+    /// returns its size.  Only one block of it is held in memory at a time.
+    pub fn read_to(&mut self, path: &str, out: impl Write) -> Result<u64> {
+        self.read_to_at_offset(path, 0, out, None)
+    }
+
+    /// Writes the file stored as `path` in the latest version to `out` from
+    /// byte `offset` on, and returns the number of bytes written.
+    ///
+    /// Fragments of a journaling archive that end before `offset` are
+    /// skipped without decoding their blocks, so an interrupted read
+    /// resumes at the cost of the block holding `offset`.  `progress` is
+    /// called after each fragment (each segment of a streaming archive,
+    /// which are decoded whole) with the offset in the file written up to,
+    /// which is where to resume from if the read stops there.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::NotFound`] if no file is stored as `path`, and
+    /// fails if `offset` is past its end.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::fs::OpenOptions;
+    ///
+    /// let mut reader = zpaq_rs::ArchiveReader::open_path("media.zpaq")?;
+    /// let mut out = OpenOptions::new().append(true).create(true).open("talk.mkv").unwrap();
+    /// let offset = out.metadata().unwrap().len();
+    /// let mut saved = |done: u64| println!("written up to byte {done}");
+    /// reader.read_to_at_offset("video/talk.mkv", offset, &mut out, Some(&mut saved))?;
+    /// # Ok::<(), zpaq_rs::ZpaqError>(())
+    /// ```
+    pub fn read_to_at_offset(
+        &mut self,
+        path: &str,
+        offset: u64,
+        out: impl Write,
+        progress: Option<&mut dyn FnMut(u64)>,
+    ) -> Result<u64> {
+        let mut part = Part {
+            offset,
+            progress,
+            pos: 0,
+        };
+        let written = match &self.layout {
+            Layout::Journal { scan, .. } => {
+                let version = scan.versions();
+                self.read_version_to(path, version, out, &mut part)?
+            }
+            Layout::Streaming { files, .. } => {
+                let segments = files.get(path).ok_or_else(|| not_found(path))?.to_vec();
+                self.read_segments_to(path, &segments, out, &mut part)?
+            }
+        };
+        if part.pos < offset {
+            return Err(ZpaqError::Ffi(format!(
+                "offset {offset} is past the end of {path} ({} bytes)",
+                part.pos
+            )));
+        }
+        Ok(written)
+    }
+
+    /// Opens the file stored as `path` in the latest version for reading.
+    ///
+    /// Nothing is decoded until the file is read, and then only a piece of
+    /// it at a time: the fragment being read for journaling archives (with
+    /// the block holding it, which is kept for the fragments after it), or
+    /// the segment being read for streaming ones.  The reader can seek
+    /// anywhere in the file; the next read decodes the piece holding the
+    /// new position and skips to it within that piece.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ZpaqError::NotFound`] if no file is stored as `path`.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use std::io::{BufRead, BufReader};
+    ///
+    /// let mut reader = zpaq_rs::ArchiveReader::open_path("logs.zpaq")?;
+    /// let member = reader.open_member("server.log")?;
+    /// for line in BufReader::new(member).lines() {
+    ///     let line = line.unwrap();
+    ///     if line.contains("ERROR") {
+    ///         println!("{line}");
+    ///     }
+    /// }
+    /// # Ok::<(), zpaq_rs::ZpaqError>(())
+    /// ```
+    pub fn open_member(&mut self, path: &str) -> Result<MemberReader<'_, R>> {
+        let (pieces, sizes): (Vec<Piece>, Vec<u64>) = match &self.layout {
+            Layout::Journal { scan, .. } => self
+                .fragments_at(path, scan.versions())?
+                .into_iter()
+                .map(|(id, size)| (Piece::Fragment(id), size))
+                .unzip(),
+            Layout::Streaming { files, .. } => files
+                .get(path)
+                .ok_or_else(|| not_found(path))?
+                .iter()
+                .map(|(location, ordinal, size)| {
+                    (Piece::Segment(location.clone(), *ordinal), *size)
+                })
+                .unzip(),
+        };
+        let mut starts = Vec::with_capacity(pieces.len());
+        let mut size = 0;
+        for piece_size in sizes {
+            starts.push(size);
+            size += piece_size;
+        }
+        Ok(MemberReader {
+            archive: self,
+            path: path.to_string(),
+            pieces,
+            starts,
+            size,
+            pos: 0,
+            current: None,
+        })
+    }
+
+    /// Reads the file stored as `path` as it was after version `version` of
+    /// a journaling archive (numbered from 1, as by `zpaq list -all`).
+    pub fn read_at_version(&mut self, path: &str, version: usize) -> Result<Vec<u8>> {
+        let versions = self.versions();
+        if version == 0 || version > versions {
+            return Err(ZpaqError::Ffi(format!(
+                "version {version} not in archive (1..={versions})"
+            )));
+        }
+        let mut out = Vec::new();
+        self.read_version_to(path, version, &mut out, &mut Part::default())?;
+        Ok(out)
+    }
+
+    fn journal_entries(&self, version: usize) -> Result<Vec<ArchiveEntryInfo>> {
+        let Layout::Journal {
+            scan, fragments, ..
+        } = &self.layout
+        else {
+            return Ok(Vec::new());
+        };
+        scan.files_at(version)
+            .into_iter()
+            .filter(|(path, _)| !path.ends_with('/'))
+            .map(|(path, entry)| {
+                Ok(ArchiveEntryInfo {
+                    path: path.to_string(),
+                    size: file_size(fragments, path, &entry.fragments)?,
+                    date: Some(entry.date),
+                    comment: None,
+                    meta: EntryMeta::from_index(entry.date, &entry.attr),
+                })
+            })
+            .collect()
+    }
+
+    /// The files of the latest version, or with `include_history` every
+    /// version of every file ever stored, including deletions.  Sorted by
+    /// path, then version; directories are omitted.
+    pub(crate) fn entry_versions(
+        &self,
+        filter: Option<&MemberFilter<'_>>,
+        include_history: bool,
+    ) -> Result<Vec<ArchiveEntryVersion>> {
+        let mut out = match &self.layout {
+            Layout::Journal {
+                scan, fragments, ..
+            } => {
+                let end = scan.incomplete.unwrap_or(u64::MAX);
+                let live = scan.files_at(scan.versions());
+                let mut out = Vec::new();
+                for entry in &scan.index {
+                    if entry.location.offset >= end || entry.file.ends_with('/') {
+                        continue;
+                    }
+                    let is_live = live
+                        .get(entry.file.as_str())
+                        .is_some_and(|&live| std::ptr::eq(live, entry));
+                    if !include_history && !is_live {
+                        continue;
+                    }
+                    out.push(ArchiveEntryVersion {
+                        path: entry.file.clone(),
+                        size: file_size(fragments, &entry.file, &entry.fragments)?,
+                        mtime: EntryMeta::from_index(entry.date, &entry.attr).mtime,
+                        version: entry.version as u32,
+                        deleted: entry.date == 0,
+                        extra: BTreeMap::new(),
+                    });
+                }
+                out
+            }
+            Layout::Streaming { history, .. } if include_history => history.clone(),
+            Layout::Streaming { history, .. } => {
+                let mut latest = BTreeMap::new();
+                for entry in history {
+                    latest.insert(entry.path.as_str(), entry);
+                }
+                latest.into_values().cloned().collect()
+            }
+        };
+        out.retain(|entry| filter.is_none_or(|filter| filter.matches(&entry.path)));
+        out.sort_by(|a, b| a.path.cmp(&b.path).then(a.version.cmp(&b.version)));
+        Ok(out)
+    }
+
+    fn read_version_to(
+        &mut self,
+        path: &str,
+        version: usize,
+        mut out: impl Write,
+        part: &mut Part<'_>,
+    ) -> Result<u64> {
+        let mut total = 0;
+        for (id, size) in self.fragments_at(path, version)? {
+            if part.pos + size <= part.offset {
+                part.pos += size;
+                continue;
+            }
+            let data = self.fragment(path, id)?;
+            total += part.write(data, &mut out)?;
+        }
+        Ok(total)
+    }
+
+    /// The fragments of the file `path` as it was after `version`, in
+    /// order, with their sizes.
+    fn fragments_at(&self, path: &str, version: usize) -> Result<Vec<(u32, u64)>> {
+        let Layout::Journal {
+            scan, fragments, ..
+        } = &self.layout
+        else {
+            return Err(ZpaqError::Ffi("streaming archives have no versions".into()));
+        };
+        let ids = match scan.files_at(version).get(path) {
+            Some(entry) if !path.ends_with('/') => entry.fragments.clone(),
+            _ => {
+                let end = scan.incomplete.unwrap_or(u64::MAX);
+                let last =
+                    scan.index.iter().rev().find(|e| {
+                        e.file == path && e.version <= version && e.location.offset < end
+                    });
+                return Err(ZpaqError::NotFound {
+                    path: path.to_string(),
+                    was_deleted: last.is_some_and(|e| e.date == 0),
+                });
+            }
+        };
+        ids.into_iter()
+            .map(|id| {
+                let fragment = fragments.get(&id).ok_or_else(|| missing(path, id))?;
+                Ok((id, fragment.size as u64))
+            })
+            .collect()
+    }
+
+    /// Returns fragment `id`, decoding its block unless it is cached.
+    fn fragment(&mut self, path: &str, id: u32) -> Result<&[u8]> {
+        let Layout::Journal {
+            fragments, blocks, ..
+        } = &self.layout
+        else {
+            unreachable!("fragments are only read from journaling archives");
+        };
+        let fragment = fragments.get(&id).ok_or_else(|| missing(path, id))?;
+        let location = &blocks[fragment.block];
+        if self.cache.as_ref().is_none_or(|c| c.0 != location.offset) {
+            let block = decode_block(&mut self.source, location)?;
+            self.cache = Some((location.offset, block));
+        }
+        let (_, block) = self.cache.as_ref().expect("cached block");
+        fragment_bytes(block, fragment, path, id)
+    }
+
+    /// Passes each file of the latest version selected by `filter` to
+    /// `sink`, with its contents, in path order, and returns how many there
+    /// were.  Directories are skipped.
+    ///
+    /// Each block is decoded at most once, however many of the selected
+    /// files it holds and in whatever order, and is kept in memory only
+    /// until the last of them has been passed.  An error from `sink` stops
+    /// the read and is returned.
+    pub fn read_matching(
+        &mut self,
+        filter: &MemberFilter<'_>,
+        mut sink: impl FnMut(&str, &[u8]) -> Result<()>,
+    ) -> Result<usize> {
+        let paths: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|entry| filter.matches(&entry.path))
+            .map(|entry| entry.path.clone())
+            .collect();
+        match &self.layout {
+            Layout::Journal {
+                scan,
+                fragments,
+                blocks,
+            } => {
+                let files = scan.files_at(scan.versions());
+                // Fragments each block still has to supply.
+                let mut uses = HashMap::new();
+                for path in &paths {
+                    for &id in &files[path.as_str()].fragments {
+                        let fragment = fragments.get(&id).ok_or_else(|| missing(path, id))?;
+                        *uses.entry(fragment.block).or_insert(0usize) += 1;
+                    }
+                }
+                let mut decoded = HashMap::new();
+                for path in &paths {
+                    let mut data = Vec::new();
+                    for &id in &files[path.as_str()].fragments {
+                        let fragment = &fragments[&id];
+                        let block = match decoded.entry(fragment.block) {
+                            Entry::Occupied(block) => block.into_mut(),
+                            Entry::Vacant(slot) => slot
+                                .insert(decode_block(&mut self.source, &blocks[fragment.block])?),
+                        };
+                        data.extend_from_slice(fragment_bytes(block, fragment, path, id)?);
+                        let left = uses.get_mut(&fragment.block).expect("counted block");
+                        *left -= 1;
+                        if *left == 0 {
+                            decoded.remove(&fragment.block);
+                        }
+                    }
+                    sink(path, &data)?;
+                }
+            }
+            Layout::Streaming { files, .. } => {
+                // Segments to decode from each block, and how many of them
+                // are still to be used.
+                let mut needed: HashMap<u64, (usize, usize)> = HashMap::new();
+                for (location, ordinal, _) in paths.iter().flat_map(|path| &files[path]) {
+                    let (count, uses) = needed.entry(location.offset).or_default();
+                    *count = (*count).max(ordinal + 1);
+                    *uses += 1;
+                }
+                let mut decoded = HashMap::new();
+                for path in &paths {
+                    let mut data = Vec::new();
+                    for (location, ordinal, _) in &files[path] {
+                        let (count, uses) = needed.get_mut(&location.offset).expect("counted");
+                        let segments = match decoded.entry(location.offset) {
+                            Entry::Occupied(segments) => segments.into_mut(),
+                            Entry::Vacant(slot) => slot.insert(
+                                read_segments_at(&mut self.source, location.offset, *count)?
+                                    .map_err(|message| {
+                                        ZpaqError::Ffi(format!("{path}: {message}"))
+                                    })?,
+                            ),
+                        };
+                        let (name, segment, intact) = &segments[*ordinal];
+                        if name != location.segment.as_bytes() {
+                            return Err(ZpaqError::Ffi(format!("{path}: block header damaged")));
+                        }
+                        if !intact {
+                            return Err(ZpaqError::ChecksumMismatch);
+                        }
+                        data.extend_from_slice(segment);
+                        *uses -= 1;
+                        if *uses == 0 {
+                            decoded.remove(&location.offset);
+                        }
+                    }
+                    sink(path, &data)?;
+                }
+            }
+        }
+        Ok(paths.len())
+    }
+
+    fn read_segments_to(
+        &mut self,
+        path: &str,
+        segments: &[SegmentRef],
+        mut out: impl Write,
+        part: &mut Part<'_>,
+    ) -> Result<u64> {
+        let mut total = 0;
+        for (location, ordinal, _) in segments {
+            let (data, intact) = read_segment_at(&mut self.source, location, *ordinal)?
+                .map_err(|message| ZpaqError::Ffi(format!("{path}: {message}")))?;
+            if !intact {
+                return Err(ZpaqError::ChecksumMismatch);
+            }
+            total += part.write(&data, &mut out)?;
+        }
+        Ok(total)
+    }
+}
+
+/// A file of an archive, read a piece at a time; see
+/// [`ArchiveReader::open_member`].
+///
+/// Errors from the archive (a damaged block, a checksum that does not
+/// match) come out of [`Read::read`] as [`std::io::Error`]s wrapping the
+/// [`ZpaqError`], which `into_inner` and `downcast` give back.
+pub struct MemberReader<'a, R: Read + Seek + Send> {
+    archive: &'a mut ArchiveReader<R>,
+    path: String,
+    pieces: Vec<Piece>,
+    /// Offset in the file of each piece.
+    starts: Vec<u64>,
+    size: u64,
+    pos: u64,
+    /// The piece last decoded, by index.
+    current: Option<(usize, Vec<u8>)>,
+}
+
+/// A piece of a file, decoded whole.
+enum Piece {
+    Fragment(u32),
+    Segment(VerifyLocation, usize),
+}
+
+impl<R: Read + Seek + Send> MemberReader<'_, R> {
+    /// Size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Decodes piece `index` unless it is the current one, and returns it.
+    fn piece(&mut self, index: usize) -> Result<&[u8]> {
+        if self.current.as_ref().is_none_or(|c| c.0 != index) {
+            let path = &self.path;
+            let data = match &self.pieces[index] {
+                Piece::Fragment(id) => self.archive.fragment(path, *id)?.to_vec(),
+                Piece::Segment(location, ordinal) => {
+                    let (data, intact) =
+                        read_segment_at(&mut self.archive.source, location, *ordinal)?
+                            .map_err(|message| ZpaqError::Ffi(format!("{path}: {message}")))?;
+                    if !intact {
+                        return Err(ZpaqError::ChecksumMismatch);
+                    }
+                    data
+                }
+            };
+            let end = self.starts.get(index + 1).copied().unwrap_or(self.size);
+            if data.len() as u64 != end - self.starts[index] {
+                return Err(ZpaqError::Ffi(format!(
+                    "{path}: piece {index} changed size since the archive was opened"
+                )));
+            }
+            self.current = Some((index, data));
+        }
+        Ok(&self.current.as_ref().expect("decoded piece").1)
+    }
+}
+
+impl<R: Read + Seek + Send> Read for MemberReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.size {
+            return Ok(0);
+        }
+        // The last piece starting at or before the position; empty pieces
+        // share their start with the next one.
+        let index = self.starts.partition_point(|&start| start <= self.pos) - 1;
+        let skip = (self.pos - self.starts[index]) as usize;
+        let data = &self.piece(index).map_err(std::io::Error::other)?[skip..];
+        let n = data.len().min(buf.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek + Send> Seek for MemberReader<'_, R> {
+    /// Moves to `pos` without decoding anything.  Positions past the end
+    /// are allowed, and read as the end.
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// The part of a file a read writes: what comes after `offset`.
+#[derive(Default)]
+struct Part<'a> {
+    offset: u64,
+    progress: Option<&'a mut dyn FnMut(u64)>,
+    /// Offset in the file of the next piece.
+    pos: u64,
+}
+
+impl Part<'_> {
+    /// Writes what of the next piece of the file, `data`, is past the
+    /// offset, reports progress and returns the number of bytes written.
+    fn write(&mut self, data: &[u8], out: &mut impl Write) -> Result<u64> {
+        let skip = self.offset.saturating_sub(self.pos).min(data.len() as u64);
+        let data = &data[skip as usize..];
+        out.write_all(data).map_err(io_err)?;
+        self.pos += skip + data.len() as u64;
+        if let Some(progress) = self.progress.as_mut() {
+            progress(self.pos);
+        }
+        Ok(data.len() as u64)
+    }
+}
+
+/// Fails on any damage `zpaq` would not silently skip.
+pub(crate) fn check_scan<S: ?Sized>(scan: &Scan<'_, S>) -> Result<()> {
+    match scan
+        .report
+        .errors
+        .iter()
+        .find(|e| e.kind != VerifyErrorKind::IncompleteTransaction)
+    {
+        Some(error) => Err(ZpaqError::Ffi(format!("archive is damaged: {error}"))),
+        None => Ok(()),
+    }
+}
+
+fn not_found(path: &str) -> ZpaqError {
+    ZpaqError::NotFound {
+        path: path.to_string(),
+        was_deleted: false,
+    }
+}
+
+/// Decodes the `d` block at `location`.
+fn decode_block<R: Read + Seek + Send>(
+    source: &mut ArchiveSource<R>,
+    location: &VerifyLocation,
+) -> Result<DecodedBlock> {
+    let (data, _) = read_segment_at(source, location, 0)?
+        .map_err(|message| ZpaqError::Ffi(format!("{}: {message}", location.segment)))?;
+    let sizes = fragment_sizes(&data)
+        .map_err(|message| ZpaqError::Ffi(format!("{}: {message}", location.segment)))?;
+    let starts = sizes
+        .iter()
+        .scan(0, |start, &size| {
+            let this = *start;
+            *start += size as usize;
+            Some(this)
+        })
+        .collect();
+    Ok((data, starts))
+}
+
+/// Fragment `id` of the file `path`, from its decoded block, checked
+/// against its SHA-1.
+fn fragment_bytes<'a>(
+    block: &'a DecodedBlock,
+    fragment: &Fragment,
+    path: &str,
+    id: u32,
+) -> Result<&'a [u8]> {
+    let (data, starts) = block;
+    let start = *starts
+        .get(fragment.ordinal)
+        .ok_or_else(|| missing(path, id))?;
+    let bytes = data
+        .get(start..start + fragment.size as usize)
+        .ok_or_else(|| missing(path, id))?;
+    if sha1(bytes)? != fragment.sha1 {
+        return Err(ZpaqError::ChecksumMismatch);
+    }
+    Ok(bytes)
+}
+
+/// Total size of the fragments `ids` of the file `path`.
+fn file_size(fragments: &HashMap<u32, Fragment>, path: &str, ids: &[u32]) -> Result<u64> {
+    ids.iter().try_fold(0u64, |total, id| {
+        let fragment = fragments.get(id).ok_or_else(|| missing(path, *id))?;
+        Ok(total + fragment.size as u64)
+    })
+}
+
+fn missing(path: &str, id: u32) -> ZpaqError {
+    ZpaqError::Ffi(format!(
+        "{path}: fragment {id} is not described by any h block"
+    ))
+}
+
+/// A segment of a streaming file: its block, its position in the block and
+/// its size.
+pub(crate) type SegmentRef = (VerifyLocation, usize, u64);
+
+/// Collects the streaming files of an archive: a named segment starts (or
+/// replaces) a file, and unnamed ones continue it.
+#[derive(Default)]
+pub(crate) struct SegmentIndex {
+    pub(crate) files: BTreeMap<String, (ArchiveEntryInfo, Vec<SegmentRef>)>,
+    pub(crate) history: Vec<ArchiveEntryVersion>,
+    current: Option<String>,
+    /// Number of blocks seen.
+    blocks: u32,
+    /// Block offset and position of the last segment.
+    last: Option<(u64, usize)>,
+}
+
+impl SegmentSink for SegmentIndex {
+    fn segment(&mut self, location: &VerifyLocation, comment: &[u8], data: Vec<u8>, _: bool) {
+        let ordinal = match self.last {
+            Some((offset, ordinal)) if offset == location.offset => ordinal + 1,
+            _ => {
+                self.blocks += 1;
+                0
+            }
+        };
+        self.last = Some((location.offset, ordinal));
+        let segment = (location.clone(), ordinal, data.len() as u64);
+        if !location.segment.is_empty() {
+            let comment = String::from_utf8_lossy(comment).into_owned();
+            let extra = decode_extra_comment(&comment).unwrap_or_default();
+            let info = ArchiveEntryInfo {
+                path: location.segment.clone(),
+                size: data.len() as u64,
+                date: None,
+                comment: (!comment.is_empty()).then_some(comment),
+                meta: EntryMeta {
+                    extra: extra.clone(),
+                    ..EntryMeta::default()
+                },
+            };
+            self.history.push(ArchiveEntryVersion {
+                path: location.segment.clone(),
+                size: info.size,
+                mtime: None,
+                version: self.blocks,
+                deleted: false,
+                extra,
+            });
+            self.files
+                .insert(location.segment.clone(), (info, vec![segment]));
+            self.current = Some(location.segment.clone());
+        } else if let Some((info, segments)) =
+            self.current.as_ref().and_then(|p| self.files.get_mut(p))
+        {
+            info.size += data.len() as u64;
+            segments.push(segment);
+            if let Some(last) = self.history.last_mut() {
+                last.size += data.len() as u64;
+            }
+        }
+    }
+
+    fn damaged(&mut self, _: &VerifyLocation) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::random;
+    use crate::{
+        ArchiveEntry, ArchiveWriter, archive_from_entries, archive_read_file_bytes, zpaq_command,
+    };
+    use std::io::Cursor;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Counts the bytes read through it.
+    struct CountingReader<R> {
+        inner: R,
+        count: Arc<AtomicU64>,
+    }
+
+    impl<R: Read> Read for CountingReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.count.fetch_add(n as u64, Ordering::Relaxed);
+            Ok(n)
+        }
+    }
+
+    impl<R: Seek> Seek for CountingReader<R> {
+        fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    fn reads_match_extraction() {
+        let dir = TempDir::new("zpaq-rs-reader");
+        let data = dir.join("data");
+        std::fs::create_dir_all(data.join("sub")).expect("data dir");
+        let data_s = data.to_string_lossy().into_owned();
+        let files = [
+            ("a.txt", b"alpha ".repeat(5000)),
+            ("sub/b.bin", random(1, 300_000)),
+            ("sub/empty", Vec::new()),
+        ];
+        for (name, contents) in &files {
+            std::fs::write(data.join(name), contents).expect("write file");
+        }
+        let archive = dir.join("test.zpaq").to_string_lossy().into_owned();
+        let keyed = dir.join("keyed.zpaq").to_string_lossy().into_owned();
+        zpaq_command(&["add", &archive, &data_s, "-method", "1"]).expect("add v1");
+        zpaq_command(&["add", &keyed, &data_s, "-method", "1", "-key", "pw"]).expect("add keyed");
+        std::fs::write(data.join("a.txt"), b"changed").expect("rewrite a");
+        zpaq_command(&["add", &archive, &data_s, "-method", "1"]).expect("add v2");
+        let out = dir.join("out").to_string_lossy().into_owned();
+        zpaq_command(&["extract", &archive, "-to", &out]).expect("extract");
+
+        let mut reader = ArchiveReader::open_path(&archive).expect("open");
+        assert_eq!(reader.versions(), 2);
+        let entries: Vec<_> = reader.entries().cloned().collect();
+        assert_eq!(entries.len(), files.len());
+        for entry in &entries {
+            let stored = entry.path.strip_prefix('/').unwrap_or(&entry.path);
+            let extracted = std::fs::read(format!("{out}/{stored}")).expect("read extracted");
+            assert!(reader.contains(&entry.path));
+            assert_eq!(reader.read(&entry.path).expect("read"), extracted);
+            assert_eq!(entry.size, extracted.len() as u64);
+            assert!(entry.meta.mtime.is_some());
+        }
+        let a = format!("{data_s}/a.txt");
+        assert_eq!(reader.read_at_version(&a, 1).expect("v1"), files[0].1);
+        assert!(reader.read_at_version(&a, 3).is_err());
+        assert!(reader.read("missing").is_err());
+
+        assert!(ArchiveReader::open_path(&keyed).is_err());
+        let file = std::fs::File::open(&keyed).expect("open keyed");
+        let mut reader = ArchiveReader::with_key(BufReader::new(file), "pw").expect("with key");
+        assert_eq!(reader.read(&a).expect("read keyed"), files[0].1);
+
+        // Streaming archives: the newest copy of each file.
+        let entry = |path, data| ArchiveEntry {
+            path,
+            data,
+            comment: None,
+        };
+        let mut streaming =
+            archive_from_entries(&[entry("x", b"first x"), entry("y", &files[1].1)], "1")
+                .expect("streaming");
+        crate::archive_append_entries(&mut streaming, &[entry("x", b"second x")], "1")
+            .expect("append");
+        let mut reader = ArchiveReader::open(Cursor::new(&streaming)).expect("open streaming");
+        for path in ["x", "y"] {
+            let expected = archive_read_file_bytes(&streaming, path).expect("read bytes");
+            assert_eq!(reader.read(path).expect("read streaming"), expected);
+        }
+        assert_eq!(reader.versions(), 0);
+    }
+
+    #[test]
+    fn reading_one_file_decodes_only_its_block() {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), "11").expect("writer");
+        let big = random(2, 6 << 20);
+        let meta = EntryMeta::default();
+        writer
+            .add_entry("big.bin", &big[..], &meta)
+            .expect("add big");
+        writer
+            .add_entry("small.txt", &b"small"[..], &meta)
+            .expect("add small");
+        let archive = writer.finish().expect("finish").into_inner();
+
+        let count = Arc::new(AtomicU64::new(0));
+        let counting = CountingReader {
+            inner: Cursor::new(&archive),
+            count: count.clone(),
+        };
+        let mut reader = ArchiveReader::open(counting).expect("open");
+        count.store(0, Ordering::Relaxed);
+        assert_eq!(reader.read("small.txt").expect("read small"), b"small");
+        // At most the 2 MiB block holding it, of a 6 MiB archive.
+        let read = count.load(Ordering::Relaxed);
+        assert!(
+            read < (2 << 20) + (1 << 17) && archive.len() > 6 << 20,
+            "read {read} of {} bytes",
+            archive.len()
+        );
+        assert_eq!(reader.read("big.bin").expect("read big"), big);
+    }
+
+    #[test]
+    fn reads_resume_at_an_offset() {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), "11").expect("writer");
+        let big = random(3, 6 << 20);
+        writer
+            .add_entry("big.bin", &big[..], &EntryMeta::default())
+            .expect("add big");
+        let archive = writer.finish().expect("finish").into_inner();
+        let count = Arc::new(AtomicU64::new(0));
+        let counting = CountingReader {
+            inner: Cursor::new(&archive),
+            count: count.clone(),
+        };
+        let mut reader = ArchiveReader::open(counting).expect("open");
+        let whole = reader.read("big.bin").expect("read");
+
+        let half = big.len() as u64 / 2 + 12_345;
+        let mut first = Vec::new();
+        let mut reached = Vec::new();
+        let mut progress = |pos: u64| reached.push(pos);
+        let written = reader
+            .read_to_at_offset("big.bin", 0, &mut first, Some(&mut progress))
+            .expect("read");
+        assert_eq!(written, big.len() as u64);
+        assert!(reached.is_sorted() && reached.len() > 10, "{reached:?}");
+        assert_eq!(reached.last(), Some(&(big.len() as u64)));
+        first.truncate(half as usize);
+
+        count.store(0, Ordering::Relaxed);
+        let mut second = Vec::new();
+        let written = reader
+            .read_to_at_offset("big.bin", half, &mut second, None)
+            .expect("read rest");
+        assert_eq!(written, big.len() as u64 - half);
+        // The blocks before the one holding the offset are not read.
+        let read = count.load(Ordering::Relaxed);
+        assert!(read < archive.len() as u64 * 3 / 4, "read {read} bytes");
+        first.extend(second);
+        assert_eq!(first, whole);
+
+        // Resuming from a reported point gives the rest.
+        let point = reached[reached.len() / 3];
+        let mut rest = Vec::new();
+        reader
+            .read_to_at_offset("big.bin", point, &mut rest, None)
+            .expect("resume");
+        assert_eq!(rest, big[point as usize..]);
+
+        let end = big.len() as u64;
+        let mut nothing = Vec::new();
+        assert_eq!(
+            reader.read_to_at_offset("big.bin", end, &mut nothing, None),
+            Ok(0)
+        );
+        let past = reader.read_to_at_offset("big.bin", end + 1, std::io::sink(), None);
+        assert!(matches!(past, Err(ZpaqError::Ffi(_))), "{past:?}");
+
+        let entries = [ArchiveEntry {
+            path: "s.txt",
+            data: b"streaming member",
+            comment: None,
+        }];
+        let streaming = archive_from_entries(&entries, "1").expect("streaming");
+        let mut out = Vec::new();
+        crate::archive_read_file_to_at_offset(&streaming, "s.txt", 10, &mut out, None)
+            .expect("read streaming");
+        assert_eq!(out, b"member");
+    }
+
+    #[test]
+    fn matching_reads_decode_each_block_once() {
+        let prefixes = ["assets/textures/", "assets/sounds/", "src/"];
+        let members: Vec<(String, Vec<u8>)> = (0..50)
+            .map(|i| {
+                let path = format!("{}{i:02}.bin", prefixes[i % 3]);
+                (path, random(i as u64, 20_000))
+            })
+            .collect();
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), "1").expect("writer");
+        for (path, data) in &members {
+            writer
+                .add_entry(path, &data[..], &EntryMeta::default())
+                .expect("add");
+        }
+        let journal = writer.finish().expect("finish").into_inner();
+        let entries: Vec<_> = members
+            .iter()
+            .map(|(path, data)| ArchiveEntry {
+                path,
+                data,
+                comment: None,
+            })
+            .collect();
+        let streaming = archive_from_entries(&entries, "1").expect("streaming");
+
+        for archive in [&journal, &streaming] {
+            let count = Arc::new(AtomicU64::new(0));
+            let counting = CountingReader {
+                inner: Cursor::new(archive),
+                count: count.clone(),
+            };
+            let mut reader = ArchiveReader::open(counting).expect("open");
+            let select = |filter: MemberFilter<'_>| {
+                members
+                    .iter()
+                    .filter(|(path, _)| filter.matches(path))
+                    .count()
+            };
+            for (filter, expected) in [
+                (MemberFilter::Prefix("assets/"), 34),
+                (MemberFilter::Glob("*/*.bin"), 16),
+                (MemberFilter::Glob("assets/**/0?.bin"), 7),
+                (MemberFilter::Exact("src/47.bin"), 1),
+            ] {
+                assert_eq!(select(filter), expected);
+                let listed = reader.entry_versions(Some(&filter), false).expect("list");
+                assert_eq!(listed.len(), expected);
+            }
+
+            // The textures are spread over the one block, between the
+            // other files.
+            count.store(0, Ordering::Relaxed);
+            let mut read = Vec::new();
+            let filter = MemberFilter::Prefix("assets/textures/");
+            let n = reader
+                .read_matching(&filter, |path, data| {
+                    read.push((path.to_string(), data.to_vec()));
+                    Ok(())
+                })
+                .expect("read matching");
+            assert_eq!(n, 17);
+            let expected: Vec<_> = members
+                .iter()
+                .filter(|(path, _)| filter.matches(path))
+                .cloned()
+                .collect();
+            assert_eq!(read, expected);
+            let bytes = count.load(Ordering::Relaxed);
+            assert!(
+                bytes < archive.len() as u64 + (1 << 16),
+                "read {bytes} of {} bytes",
+                archive.len()
+            );
+
+            let stop = reader.read_matching(&filter, |_, _| Err(ZpaqError::Ffi("stop".into())));
+            assert!(stop.is_err());
+        }
+    }
+
+    #[test]
+    fn members_read_like_their_extracted_bytes() {
+        use std::io::BufRead;
+
+        let mut log = crate::test_support::text(3 << 20);
+        log.extend(random(5, 1 << 20));
+        log.extend(b"last line without a newline");
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()), "10").expect("writer");
+        writer
+            .add_entry("log.txt", &log[..], &EntryMeta::default())
+            .expect("add log");
+        writer
+            .add_entry("empty", &b""[..], &EntryMeta::default())
+            .expect("add empty");
+        let journal = writer.finish().expect("finish").into_inner();
+        // A 1 MiB block size splits the streaming file into segments.
+        let mut streaming = Vec::new();
+        crate::compress_stream(&log[..], &mut streaming, "10", Some("log.txt"), None)
+            .expect("streaming");
+
+        for archive in [&journal, &streaming] {
+            let mut reader = ArchiveReader::open(Cursor::new(archive)).expect("open");
+            let whole = reader.read("log.txt").expect("read");
+            assert_eq!(whole, log);
+            let mut member = reader.open_member("log.txt").expect("open member");
+            assert_eq!(member.size(), log.len() as u64);
+            assert!(member.pieces.len() > 3, "{} pieces", member.pieces.len());
+
+            let mut lines = Vec::new();
+            for line in BufReader::new(&mut member).split(b'\n') {
+                lines.push(line.expect("line"));
+            }
+            let expected: Vec<&[u8]> = whole.split(|&b| b == b'\n').collect();
+            assert_eq!(lines.len(), expected.len());
+            assert!(lines.iter().zip(&expected).all(|(a, b)| a == b));
+
+            let mut state = 7u64;
+            for _ in 0..40 {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let pos = (state >> 16) % (whole.len() as u64 + 10);
+                let want = 1 + (state % 200_000) as usize;
+                let from = if state.is_multiple_of(3) {
+                    member.seek(SeekFrom::End(pos as i64 - whole.len() as i64))
+                } else {
+                    member.seek(SeekFrom::Start(pos))
+                };
+                assert_eq!(from.expect("seek"), pos);
+                let mut buf = Vec::new();
+                (&mut member)
+                    .take(want as u64)
+                    .read_to_end(&mut buf)
+                    .expect("read after seek");
+                let start = (pos as usize).min(whole.len());
+                let end = (start + want).min(whole.len());
+                assert!(buf == whole[start..end], "{want} bytes at {pos}");
+            }
+            member.seek(SeekFrom::Start(10)).expect("seek");
+            assert_eq!(member.seek(SeekFrom::Current(-4)).expect("seek back"), 6);
+            assert!(
+                member
+                    .seek(SeekFrom::End(-(whole.len() as i64) - 1))
+                    .is_err()
+            );
+            drop(member);
+
+            assert!(matches!(
+                reader.open_member("missing"),
+                Err(ZpaqError::NotFound { .. })
+            ));
+        }
+
+        let mut reader = ArchiveReader::open(Cursor::new(&journal)).expect("open");
+        let mut empty = Vec::new();
+        let mut member = reader.open_member("empty").expect("open empty");
+        member.read_to_end(&mut empty).expect("read empty");
+        assert!(empty.is_empty() && member.size() == 0);
+    }
+}
@@ -0,0 +1,437 @@
+//! Rewriting a journaling archive to hold only one version, or only some
+//! of its files.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+
+use crate::method::normalize_method;
+use crate::parts::open_archive;
+use crate::verify::{ArchiveSource, Scan};
+use crate::{Result, SecretString, ZpaqError, io_err, zpaq_command_inner};
+
+/// Options for [`zpaq_repack`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepackOptions {
+    /// Recompress with this method (as for [`zpaq_add`](crate::zpaq_add)).
+    ///
+    /// Without a method, `d` blocks are copied as they are, as
+    /// `zpaq extract -repack` does.  That is fast, but a block still
+    /// referenced by a current file is kept whole, including any fragments
+    /// of superseded versions it holds.  With a method the current files
+    /// are extracted to a scratch directory next to `dst_archive` and added
+    /// afresh, so the result matches a new `zpaq add` of them.
+    pub method: Option<String>,
+    /// Password of the source archive, if encrypted.
+    pub key_in: Option<SecretString>,
+    /// Password to encrypt the new archive with.
+    pub key_out: Option<SecretString>,
+    /// Keep the state after this version instead of the latest (the `-until`
+    /// argument of `zpaq`).
+    pub until: Option<u32>,
+}
+
+/// Result of [`zpaq_repack`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepackReport {
+    /// Size of the source archive in bytes.
+    pub original_size: u64,
+    /// Size of the new archive in bytes.
+    pub repacked_size: u64,
+}
+
+impl RepackReport {
+    /// Bytes saved (0 if the archive grew).
+    pub fn reduction(&self) -> u64 {
+        self.original_size.saturating_sub(self.repacked_size)
+    }
+}
+
+/// Writes a new archive at `dst_archive` holding a single version: the files
+/// current in `src_archive` (or current as of [`RepackOptions::until`]).
+/// Deleted files and superseded versions are dropped; dates and attributes
+/// are kept.
+///
+/// # Errors
+///
+/// Fails if `dst_archive` already exists, if `src_archive` is a streaming
+/// archive, or if the embedded `zpaq` fails.
+pub fn zpaq_repack(
+    src_archive: &str,
+    dst_archive: &str,
+    options: &RepackOptions,
+) -> Result<RepackReport> {
+    if Path::new(dst_archive).exists() {
+        return Err(ZpaqError::Ffi(format!("{dst_archive}: output exists")));
+    }
+    let original_size = fs::metadata(src_archive)
+        .map_err(|e| ZpaqError::Ffi(format!("{src_archive}: {e}")))?
+        .len();
+
+    let mut args = vec!["extract".to_string(), src_archive.to_string()];
+    if let Some(key) = &options.key_in {
+        args.extend(["-key".to_string(), key.expose().to_string()]);
+    }
+    if let Some(until) = options.until {
+        args.extend(["-until".to_string(), until.to_string()]);
+    }
+
+    match &options.method {
+        None => {
+            args.extend(["-repack".to_string(), dst_archive.to_string()]);
+            args.extend(options.key_out.as_ref().map(|key| key.expose().to_string()));
+            zpaq_command_inner(&args)?;
+        }
+        Some(method) => {
+            let roots = current_roots(src_archive, options)?;
+            if roots.is_empty() {
+                return Err(ZpaqError::Ffi(format!("{src_archive}: no files to repack")));
+            }
+            let scratch = format!("{dst_archive}.repack-{}", std::process::id());
+            fs::create_dir_all(&scratch).map_err(io_err)?;
+            let result = readd(&args, &scratch, &roots, dst_archive, method, options);
+            fs::remove_dir_all(&scratch).map_err(io_err)?;
+            result?;
+        }
+    }
+
+    let repacked_size = fs::metadata(dst_archive)
+        .map_err(|e| ZpaqError::Ffi(format!("{dst_archive}: {e}")))?
+        .len();
+    Ok(RepackReport {
+        original_size,
+        repacked_size,
+    })
+}
+
+/// Options for [`zpaq_repack_selection`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepackSelectionOptions {
+    /// Password of the source archive, if encrypted.
+    pub key_in: Option<SecretString>,
+    /// Password to encrypt the new archive with; with `key_in` set to the
+    /// old password this rotates an archive's key.
+    pub key_out: Option<SecretString>,
+    /// Select from the state after this version instead of the latest (the
+    /// `-until` argument of `zpaq`).
+    pub until: Option<u32>,
+}
+
+/// Result of [`zpaq_repack_selection`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RepackSelectionReport {
+    /// Number of files in the new archive, directories excluded.
+    pub files: u64,
+    /// Size of the new archive in bytes.
+    pub bytes_written: u64,
+}
+
+/// Writes the files of `src_archive` selected by `files` into a new
+/// single-version archive at `dest_archive`, as
+/// `zpaq extract <src> <files...> -repack <dest>` does, without writing
+/// anything else to disk.
+///
+/// `files` are stored paths or prefixes of them, as for
+/// [`zpaq_extract`](crate::zpaq_extract); an empty list selects every file.
+/// The `d` blocks holding the selected files are copied as they are, so
+/// the new archive may also hold fragments of files left out; use
+/// [`zpaq_repack`] with a method to drop them.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::{RepackSelectionOptions, zpaq_repack_selection};
+///
+/// // Re-encrypt the reports under a new password.
+/// let options = RepackSelectionOptions {
+///     key_in: Some("old secret".into()),
+///     key_out: Some("new secret".into()),
+///     ..Default::default()
+/// };
+/// let report = zpaq_repack_selection("all.zpaq", "reports.zpaq", &["/srv/reports"], &options)?;
+/// println!("{} files, {} bytes", report.files, report.bytes_written);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+///
+/// # Errors
+///
+/// Fails if `dest_archive` already exists, if a selection starts with `-`
+/// (which `zpaq` would take for an option), if `src_archive` is a
+/// streaming archive, or if the embedded `zpaq` fails.
+pub fn zpaq_repack_selection(
+    src_archive: &str,
+    dest_archive: &str,
+    files: &[&str],
+    options: &RepackSelectionOptions,
+) -> Result<RepackSelectionReport> {
+    if Path::new(dest_archive).exists() {
+        return Err(ZpaqError::Ffi(format!("{dest_archive}: output exists")));
+    }
+    if let Some(file) = files.iter().find(|f| f.starts_with('-')) {
+        return Err(ZpaqError::Ffi(format!(
+            "{file}: selection looks like an option"
+        )));
+    }
+
+    let mut args = vec!["extract".to_string(), src_archive.to_string()];
+    args.extend(files.iter().map(|f| f.to_string()));
+    if let Some(key) = &options.key_in {
+        args.extend(["-key".to_string(), key.expose().to_string()]);
+    }
+    if let Some(until) = options.until {
+        args.extend(["-until".to_string(), until.to_string()]);
+    }
+    args.extend(["-repack".to_string(), dest_archive.to_string()]);
+    args.extend(options.key_out.as_ref().map(|key| key.expose().to_string()));
+    zpaq_command_inner(&args)?;
+
+    let file = open_archive(dest_archive)?;
+    let mut source = ArchiveSource::new(
+        BufReader::new(file),
+        options.key_out.as_ref().map(SecretString::expose),
+    )?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    let files = scan
+        .files_at(scan.versions())
+        .keys()
+        .filter(|path| !path.ends_with('/'))
+        .count() as u64;
+    let bytes_written = fs::metadata(dest_archive)
+        .map_err(|e| ZpaqError::Ffi(format!("{dest_archive}: {e}")))?
+        .len();
+    Ok(RepackSelectionReport {
+        files,
+        bytes_written,
+    })
+}
+
+/// Extracts into `scratch`, then adds the extracted trees back under their
+/// stored names.
+fn readd(
+    extract_args: &[String],
+    scratch: &str,
+    roots: &[String],
+    dst_archive: &str,
+    method: &str,
+    options: &RepackOptions,
+) -> Result<()> {
+    let mut args = extract_args.to_vec();
+    args.extend(["-to".to_string(), scratch.to_string()]);
+    zpaq_command_inner(&args)?;
+
+    let mut args = vec!["add".to_string(), dst_archive.to_string()];
+    args.extend(roots.iter().map(|root| append_path(scratch, root)));
+    args.push("-to".to_string());
+    args.extend(roots.iter().cloned());
+    args.extend(["-method".to_string(), normalize_method(method)]);
+    if let Some(key) = &options.key_out {
+        args.extend(["-key".to_string(), key.expose().to_string()]);
+    }
+    zpaq_command_inner(&args)?;
+    Ok(())
+}
+
+/// Stored paths (without a trailing `/`) of the current entries that are not
+/// inside another current directory entry.
+fn current_roots(src_archive: &str, options: &RepackOptions) -> Result<Vec<String>> {
+    let file = open_archive(src_archive)?;
+    let mut source = ArchiveSource::new(
+        BufReader::new(file),
+        options.key_in.as_ref().map(SecretString::expose),
+    )?;
+    let mut scan = Scan::default();
+    scan.scan(&mut source)?;
+    let versions = scan.versions();
+    if versions == 0 {
+        return Err(ZpaqError::Ffi(format!(
+            "{src_archive}: cannot repack streaming archive"
+        )));
+    }
+    let version = options.until.map_or(versions, |v| versions.min(v as usize));
+    let files = scan.files_at(version);
+    let dirs: BTreeSet<&str> = files.keys().copied().filter(|p| p.ends_with('/')).collect();
+    let roots = files
+        .keys()
+        .filter(|path| {
+            let trimmed = path.strip_suffix('/').unwrap_or(path);
+            !trimmed
+                .match_indices('/')
+                .any(|(i, _)| dirs.contains(&trimmed[..=i]))
+        })
+        .map(|path| path.strip_suffix('/').unwrap_or(path).to_string())
+        .collect();
+    Ok(roots)
+}
+
+/// Where `zpaq extract -to dir` puts the stored path `name` (`append_path()`
+/// in `zpaq.cpp`).
+fn append_path(dir: &str, name: &str) -> String {
+    let mut name = name.to_string();
+    if cfg!(not(unix)) && name.as_bytes().get(1) == Some(&b':') {
+        // Drive letter: "c:/x" -> "c/x", "c:x" -> "c/x".
+        if name.as_bytes().get(2).is_some_and(|&b| b != b'/') {
+            name.replace_range(1..2, "/");
+        } else {
+            name.remove(1);
+        }
+    }
+    let name = name.strip_prefix('/').unwrap_or(&name);
+    let dir = dir.strip_suffix('/').unwrap_or(dir);
+    format!("{dir}/{name}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{
+        DiffChange, DiffOptions, DiffTarget, archive_diff, zpaq_add, zpaq_command, zpaq_extract,
+        zpaq_list,
+    };
+
+    fn text(tag: &str, step: u64) -> String {
+        (0..6000u64)
+            .map(|i| format!("{tag} {}\n", i * step % 100_003))
+            .collect()
+    }
+
+    #[test]
+    fn repack_drops_superseded_versions() {
+        let dir = TempDir::new("zpaq-rs-repack");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).expect("data dir");
+        let data_s = data.to_string_lossy().into_owned();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let archive = path("history.zpaq");
+        let write = |name: &str, contents: String| fs::write(data.join(name), contents).unwrap();
+
+        write("a.txt", text("alpha", 7));
+        write("b.txt", text("bravo", 11));
+        write("c.txt", text("charlie", 13));
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v1");
+        write("b.txt", text("BRAVO", 17));
+        fs::remove_file(data.join("c.txt")).expect("delete c");
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v2");
+        write("a.txt", text("ALPHA", 19));
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add v3");
+
+        let fresh = path("fresh.zpaq");
+        zpaq_add(&fresh, &[&data_s], "1", 1).expect("fresh add");
+        let fresh_size = fs::metadata(&fresh).expect("fresh").len();
+
+        let recompressed = path("recompressed.zpaq");
+        let options = RepackOptions {
+            method: Some("1".into()),
+            ..Default::default()
+        };
+        let report = zpaq_repack(&archive, &recompressed, &options).expect("repack");
+        assert_eq!(
+            report.original_size,
+            fs::metadata(&archive).expect("src").len()
+        );
+        assert!(report.reduction() > 0);
+        let diff = report.repacked_size.abs_diff(fresh_size);
+        assert!(
+            diff * 20 <= fresh_size,
+            "repacked {} vs fresh {fresh_size}",
+            report.repacked_size
+        );
+
+        let copied = path("copied.zpaq");
+        let report = zpaq_repack(&archive, &copied, &RepackOptions::default()).expect("repack");
+        assert!(report.repacked_size <= report.original_size);
+        assert!(zpaq_repack(&archive, &copied, &RepackOptions::default()).is_err());
+
+        let hashing = DiffOptions {
+            hash: true,
+            ..Default::default()
+        };
+        for repacked in [&recompressed, &copied] {
+            let v = archive_diff(repacked, &DiffTarget::Version(0, 2), &hashing);
+            assert!(v.is_err(), "repacked archive has a single version");
+            let diff =
+                archive_diff(repacked, &DiffTarget::Directory(&data_s), &hashing).expect("diff");
+            let names: Vec<_> = diff.iter().map(|e| (e.path.as_str(), e.change)).collect();
+            assert_eq!(diff.len(), 2, "{names:?}");
+            assert!(
+                diff.iter().all(|e| e.change == DiffChange::Unchanged),
+                "{names:?}"
+            );
+        }
+
+        // Rolling back keeps the first version, deleted file included.
+        let rolled = path("rolled.zpaq");
+        let options = RepackOptions {
+            until: Some(1),
+            ..Default::default()
+        };
+        zpaq_repack(&archive, &rolled, &options).expect("repack -until");
+        let diff = archive_diff(&rolled, &DiffTarget::Version(0, 1), &hashing).expect("diff");
+        assert_eq!(diff.len(), 3);
+    }
+
+    #[test]
+    fn repack_selection_keeps_only_the_selected_files() {
+        let dir = TempDir::new("zpaq-rs-repack-selection");
+        let data = dir.join("data");
+        fs::create_dir_all(&data).expect("data dir");
+        let data_s = data.to_string_lossy().into_owned();
+        let path = |name: &str| dir.join(name).to_string_lossy().into_owned();
+        let names = ["a.txt", "b.txt", "c.txt", "d.txt"];
+        for (i, name) in names.iter().enumerate() {
+            fs::write(data.join(name), text(name, i as u64 + 3)).expect("write");
+        }
+        let archive = path("all.zpaq");
+        zpaq_add(&archive, &[&data_s], "1", 1).expect("add");
+
+        let selected = [format!("{data_s}/a.txt"), format!("{data_s}/c.txt")];
+        let selected: Vec<&str> = selected.iter().map(String::as_str).collect();
+        let subset = path("subset.zpaq");
+        let options = RepackSelectionOptions::default();
+        let report = zpaq_repack_selection(&archive, &subset, &selected, &options).expect("repack");
+        assert_eq!(report.files, 2);
+        assert_eq!(report.bytes_written, fs::metadata(&subset).unwrap().len());
+        assert!(zpaq_repack_selection(&archive, &subset, &selected, &options).is_err());
+
+        let listing = zpaq_list(&subset, &[]).expect("list").stdout;
+        assert!(
+            listing.contains("a.txt") && listing.contains("c.txt"),
+            "{listing}"
+        );
+        assert!(
+            !listing.contains("b.txt") && !listing.contains("d.txt"),
+            "{listing}"
+        );
+        let out = dir.join("out");
+        let out_s = out.to_string_lossy().into_owned();
+        zpaq_extract(&subset, &["-to", &out_s]).expect("extract");
+        let restored = out.join(append_path("", &data_s).trim_start_matches('/'));
+        for (i, name) in names.iter().enumerate() {
+            let file = restored.join(name);
+            if selected.iter().any(|s| s.ends_with(name)) {
+                assert_eq!(fs::read_to_string(file).unwrap(), text(name, i as u64 + 3));
+            } else {
+                assert!(!file.exists(), "{name} was not selected");
+            }
+        }
+
+        // Rotating the key of an encrypted archive.
+        let locked = path("locked.zpaq");
+        zpaq_command(&["add", &locked, &data_s, "-method", "1", "-key", "old"]).expect("add -key");
+        let rotated = path("rotated.zpaq");
+        let options = RepackSelectionOptions {
+            key_in: Some("old".into()),
+            key_out: Some("new".into()),
+            ..Default::default()
+        };
+        let report = zpaq_repack_selection(&locked, &rotated, &[], &options).expect("rotate");
+        assert_eq!(report.files, 4);
+        assert!(zpaq_command(&["list", &rotated, "-key", "old"]).is_err());
+        let listing = zpaq_command(&["list", &rotated, "-key", "new"])
+            .expect("list -key")
+            .stdout;
+        assert!(names.iter().all(|name| listing.contains(name)), "{listing}");
+    }
+}
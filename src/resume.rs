@@ -0,0 +1,377 @@
+//! Decompression that can be interrupted and continued in another process.
+//!
+//! libzpaq resets every model at the start of a block, so a block can be
+//! decoded knowing only where it starts.  [`ResumableDecompressor`] decodes
+//! whole blocks and describes its progress with a [`ResumeToken`], which is
+//! always taken between two blocks; a later run passes it to
+//! [`ResumableDecompressor::resume`] or [`resume_decompress`] to continue
+//! from there instead of from the start of the stream.
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ptr;
+
+use crate::verify::{ArchiveSource, SourceState};
+use crate::{
+    FfiReader, FfiWriter, Result, ZpaqError, clear_last_error, err_from_last, handle, io_err, sys,
+};
+
+/// Progress of a [`ResumableDecompressor`], recorded at a block boundary.
+///
+/// With the `serde` feature the token serializes as an object with the
+/// three fields below, so it can be saved between runs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ResumeToken {
+    /// Offset in the compressed stream of the next block to decode.
+    pub compressed_offset: u64,
+    /// Segments decoded before that block.
+    pub segment_index: u64,
+    /// Decompressed bytes written before that block.
+    pub bytes_emitted: u64,
+}
+
+/// Decompresses a ZPAQ stream one block at a time, so that the work done
+/// survives the process.
+///
+/// After each block, [`token`](Self::token) tells where decoding stopped.
+/// Tokens exist only between blocks: there is no way to stop inside one,
+/// because its model state could not be restored.  An interrupted block is
+/// decoded again in full when resuming.  Segment SHA-1 trailers are not
+/// checked, as in [`decompress_stream`](crate::decompress_stream).
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Cursor;
+/// use zpaq_rs::ResumableDecompressor;
+///
+/// let data = vec![7u8; 3 << 20];
+/// // Method "00" stores 1 MiB blocks.
+/// let c = zpaq_rs::compress_to_vec(&data, "00")?;
+///
+/// let mut out = Vec::new();
+/// let mut first = ResumableDecompressor::new(Cursor::new(&c))?;
+/// first.next_block(&mut out)?;
+/// let token = first.token();
+///
+/// // Later, possibly in another process:
+/// let mut rest = ResumableDecompressor::resume(Cursor::new(&c), token)?;
+/// rest.finish(&mut out)?;
+/// assert_eq!(out, data);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub struct ResumableDecompressor<R> {
+    source: ArchiveSource<R>,
+    token: ResumeToken,
+    skip: u64,
+    done: bool,
+}
+
+impl<R: Read + Seek + Send> ResumableDecompressor<R> {
+    /// Decompresses the stream starting at offset 0 of `reader`.
+    pub fn new(reader: R) -> Result<Self> {
+        Self::resume_unchecked(reader, ResumeToken::default())
+    }
+
+    /// Continues decompressing `reader` where `token` was taken.
+    ///
+    /// Fails if `token.compressed_offset` is not the start of a block.  The
+    /// check reads the block tag, so it cannot tell the token was taken from
+    /// another stream that has a block at the same offset.
+    pub fn resume(reader: R, token: ResumeToken) -> Result<Self> {
+        let mut decompressor = Self::resume_unchecked(reader, token)?;
+        let mut tag = [0u8; 4];
+        let n = read_full(&mut decompressor.source, &mut tag)?;
+        let at_block = n == tag.len() && (&tag == b"7kSt" || (&tag[..3] == b"zPQ" && tag[3] >= 1));
+        if n > 0 && !at_block {
+            return Err(ZpaqError::Ffi(format!(
+                "resume token offset {} is not at a block boundary",
+                token.compressed_offset
+            )));
+        }
+        decompressor.done = n == 0;
+        Ok(decompressor)
+    }
+
+    fn resume_unchecked(reader: R, token: ResumeToken) -> Result<Self> {
+        let mut source = ArchiveSource::new(reader, None)?;
+        source.seek_to(token.compressed_offset)?;
+        Ok(ResumableDecompressor {
+            source,
+            token,
+            skip: 0,
+            done: false,
+        })
+    }
+
+    /// Discards the next `bytes` of decompressed output instead of writing
+    /// them.
+    ///
+    /// For an output that already holds data past
+    /// [`ResumeToken::bytes_emitted`], written before the interruption: the
+    /// resumed run regenerates that data and must not write it twice.
+    /// Skipped bytes still count as emitted.
+    pub fn skip_output(&mut self, bytes: u64) {
+        self.skip = self.skip.saturating_add(bytes);
+    }
+
+    /// Progress so far; pass it to [`resume`](Self::resume) to continue
+    /// from here.
+    pub fn token(&self) -> ResumeToken {
+        self.token
+    }
+
+    /// Decodes the next block to `writer` and flushes it.  Returns `false`
+    /// (writing nothing) once the stream is exhausted.
+    ///
+    /// On error the token still describes the last complete block.
+    pub fn next_block<W: Write + Send>(&mut self, mut writer: W) -> Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+        self.source.seek_to(self.token.compressed_offset)?;
+        let state = self.source.shared_state();
+        let mut emitted = 0u64;
+        let mut skip = self.skip;
+        clear_last_error()?;
+        let decompresser = handle::Decompresser::new()?;
+        let result = FfiReader::new(&mut self.source).and_then(|reader| {
+            let output = FfiWriter::new(BlockOutput {
+                inner: &mut writer,
+                skip: &mut skip,
+                emitted: &mut emitted,
+            })?;
+            decode_block(decompresser.raw, reader.raw, output.raw, &state)
+        });
+        drop(decompresser);
+        if let Some(err) = self.source.take_io_error() {
+            return Err(err);
+        }
+        let Some((segments, next_offset)) = result? else {
+            self.done = true;
+            return Ok(false);
+        };
+        writer.flush().map_err(io_err)?;
+        self.skip = skip;
+        self.token = ResumeToken {
+            compressed_offset: next_offset,
+            segment_index: self.token.segment_index + segments,
+            bytes_emitted: self.token.bytes_emitted + emitted,
+        };
+        Ok(true)
+    }
+
+    /// Decodes every remaining block to `writer` and returns the final
+    /// token.
+    pub fn finish<W: Write + Send>(&mut self, mut writer: W) -> Result<ResumeToken> {
+        while self.next_block(&mut writer)? {}
+        Ok(self.token)
+    }
+}
+
+/// Continues decompressing `reader` where `token` was taken, appending the
+/// rest of the output to `writer`.
+///
+/// `writer` is the output of the interrupted run, such as the partly
+/// extracted file.  Its length must be at least
+/// [`ResumeToken::bytes_emitted`]; output it already holds beyond that
+/// (from a block that was cut short) is skipped rather than written again.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Cursor;
+/// use zpaq_rs::{ResumableDecompressor, resume_decompress};
+///
+/// let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+/// let c = zpaq_rs::compress_to_vec(&data, "00")?;
+///
+/// let mut out = Cursor::new(Vec::new());
+/// let mut first = ResumableDecompressor::new(Cursor::new(&c))?;
+/// first.next_block(&mut out)?;
+/// let token = first.token();
+///
+/// let token = resume_decompress(Cursor::new(&c), token, &mut out)?;
+/// assert_eq!(out.into_inner(), data);
+/// assert_eq!(token.bytes_emitted, data.len() as u64);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn resume_decompress<R, W>(reader: R, token: ResumeToken, mut writer: W) -> Result<ResumeToken>
+where
+    R: Read + Seek + Send,
+    W: Write + Seek + Send,
+{
+    let written = writer.seek(SeekFrom::End(0)).map_err(io_err)?;
+    if written < token.bytes_emitted {
+        return Err(ZpaqError::Ffi(format!(
+            "output holds {written} bytes, resume token expects at least {}",
+            token.bytes_emitted
+        )));
+    }
+    let mut decompressor = ResumableDecompressor::resume(reader, token)?;
+    decompressor.skip_output(written - token.bytes_emitted);
+    decompressor.finish(writer)
+}
+
+/// Decodes the block at the input.  Returns the number of segments and the
+/// offset just past the block, or `None` at the end of the stream.
+fn decode_block(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+    output: *mut sys::RustWriter,
+    state: &SourceState,
+) -> Result<Option<(u64, u64)>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+    if rc_block < 0 {
+        return Err(err_from_last());
+    }
+    if rc_block == 0 {
+        return Ok(None);
+    }
+    let mut segments = 0u64;
+    loop {
+        let rc_filename =
+            unsafe { sys::zpaq_decompresser_find_filename(decompresser, ptr::null_mut()) };
+        if rc_filename < 0 {
+            return Err(err_from_last());
+        }
+        if rc_filename == 0 {
+            break;
+        }
+        if unsafe { sys::zpaq_decompresser_read_comment(decompresser, ptr::null_mut()) } != 0 {
+            return Err(err_from_last());
+        }
+        if unsafe { sys::zpaq_decompresser_set_output(decompresser, output) } != 0 {
+            return Err(err_from_last());
+        }
+        loop {
+            let rc = unsafe { sys::zpaq_decompresser_decompress(decompresser, 1 << 20) };
+            if rc < 0 {
+                return Err(err_from_last());
+            }
+            if rc == 0 {
+                break;
+            }
+        }
+        if unsafe { sys::zpaq_decompresser_read_segment_end(decompresser, ptr::null_mut()) } != 0 {
+            return Err(err_from_last());
+        }
+        segments += 1;
+    }
+    let buffered = unsafe { sys::zpaq_decompresser_buffered(decompresser) }.max(0);
+    Ok(Some((
+        segments,
+        state.pos().saturating_sub(buffered as u64),
+    )))
+}
+
+/// Reads until `buf` is full or the input ends.
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]).map_err(io_err)? {
+            0 => break,
+            k => n += k,
+        }
+    }
+    Ok(n)
+}
+
+/// The caller's writer, minus the output still to be skipped.
+struct BlockOutput<'a, W> {
+    inner: &'a mut W,
+    skip: &'a mut u64,
+    emitted: &'a mut u64,
+}
+
+impl<W: Write> Write for BlockOutput<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let skipped = (*self.skip).min(buf.len() as u64) as usize;
+        *self.skip -= skipped as u64;
+        self.inner.write_all(&buf[skipped..])?;
+        *self.emitted += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_to_vec, decompress_to_vec};
+    use std::io::Cursor;
+
+    /// Five 1 MiB blocks and a short sixth, of hard-to-model bytes.
+    fn multi_block() -> (Vec<u8>, Vec<u8>) {
+        let mut x = 12345u32;
+        let data: Vec<u8> = (0..5_500_000)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                b'a' + (x >> 16) as u8 % 16
+            })
+            .collect();
+        let c = compress_to_vec(&data, "10").expect("compress");
+        (data, c)
+    }
+
+    #[test]
+    fn interrupted_runs_concatenate_to_full_output() {
+        let (data, c) = multi_block();
+        assert_eq!(decompress_to_vec(&c).expect("decompress"), data);
+        for stop_after in [0, 1, 3, 6] {
+            let mut out = Vec::new();
+            let mut first = ResumableDecompressor::new(Cursor::new(&c)).expect("new");
+            for _ in 0..stop_after {
+                assert!(first.next_block(&mut out).expect("block"));
+            }
+            let token = first.token();
+            assert_eq!(token.segment_index, stop_after);
+            assert_eq!(token.bytes_emitted, out.len() as u64);
+            drop(first);
+
+            let mut rest = ResumableDecompressor::resume(Cursor::new(&c), token).expect("resume");
+            let end = rest.finish(&mut out).expect("finish");
+            assert_eq!(out, data, "stop_after={stop_after}");
+            assert_eq!(end.compressed_offset, c.len() as u64);
+            assert_eq!(end.segment_index, 6);
+            assert!(!rest.next_block(&mut out).expect("after end"));
+        }
+    }
+
+    #[test]
+    fn partial_block_output_is_not_written_twice() {
+        let (data, c) = multi_block();
+        let mut first = ResumableDecompressor::new(Cursor::new(&c)).expect("new");
+        let mut out = Vec::new();
+        first.next_block(&mut out).expect("block");
+        let token = first.token();
+        // The interrupted run got partway through the second block.
+        out.extend_from_slice(&data[out.len()..out.len() + 300_000]);
+
+        let mut out = Cursor::new(out);
+        let end = resume_decompress(Cursor::new(&c), token, &mut out).expect("resume");
+        assert_eq!(out.into_inner(), data);
+        assert_eq!(end.bytes_emitted, data.len() as u64);
+
+        let short = Cursor::new(vec![0u8; 10]);
+        assert!(resume_decompress(Cursor::new(&c), token, short).is_err());
+    }
+
+    #[test]
+    fn tokens_inside_a_block_are_rejected() {
+        let (_, c) = multi_block();
+        let token = ResumeToken {
+            compressed_offset: 100,
+            ..Default::default()
+        };
+        let err = ResumableDecompressor::resume(Cursor::new(&c), token)
+            .err()
+            .expect("mid-block offset");
+        assert!(err.to_string().contains("block boundary"), "{err}");
+    }
+}
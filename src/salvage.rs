@@ -0,0 +1,561 @@
+//! Salvage extraction from damaged archives.
+//!
+//! [`salvage_extract`] first scans the archive the way
+//! [`verify_archive`](crate::verify_archive) does, skipping to the next block
+//! after a decode error, to collect the fragment tables and the file index.
+//! It then decodes only the `d` blocks holding fragments of current files and
+//! writes each fragment whose size and SHA-1 match its `h` table entry.
+//! Streaming archives have no index; their segments are written as they are
+//! decoded.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use crate::parts::open_archive;
+use crate::verify::{
+    ArchiveSource, Scan, SegmentSink, fragment_sizes, parse_journal_name, read_segment_at,
+};
+use crate::{Result, SecretString, VerifyError, VerifyErrorKind, VerifyLocation, io_err, sha1};
+
+/// Options for [`salvage_extract`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvageOptions {
+    /// Password of an encrypted archive (the `-key` argument of `zpaq`).
+    pub key: Option<SecretString>,
+    /// Keep partially recovered files, with lost ranges filled with zeros.
+    /// By default only fully recovered files are left in the destination.
+    pub keep_partial: bool,
+}
+
+/// Result of [`salvage_extract`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SalvageReport {
+    /// Files recovered bit-exactly.
+    pub recovered: Vec<String>,
+    /// Files of which only some ranges could be recovered.
+    pub partial: Vec<PartialFile>,
+    /// Files of which nothing could be recovered.
+    pub lost: Vec<String>,
+    /// Damage found in the archive, as reported by
+    /// [`verify_archive`](crate::verify_archive).
+    pub errors: Vec<VerifyError>,
+}
+
+/// A partially recovered file in a [`SalvageReport`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialFile {
+    /// Path as stored in the archive.
+    pub path: String,
+    /// Original size, if the archive still records it.
+    pub size: Option<u64>,
+    /// Byte ranges recovered, sorted and non-overlapping.
+    pub recovered: Vec<Range<u64>>,
+}
+
+/// Extracts everything that can be recovered from the archive at `archive`
+/// into the directory `dest`.
+///
+/// Wrapper around [`salvage_extract_reader`].
+pub fn salvage_extract(
+    archive: &str,
+    dest: &str,
+    options: &SalvageOptions,
+) -> Result<SalvageReport> {
+    salvage_extract_reader(BufReader::new(open_archive(archive)?), dest, options)
+}
+
+/// Extracts everything that can be recovered from the archive read from
+/// `reader` into the directory `dest`.
+///
+/// For journaling archives the latest version of each file in the index is
+/// extracted; a file is recovered if every one of its fragments is.  Stored
+/// paths are recreated below `dest` with any leading `/` or drive letter
+/// removed; paths containing `..` are reported as lost.  Existing files are
+/// overwritten.  File dates and attributes are not restored.
+///
+/// `Err` is returned only for I/O failures and a wrong
+/// [`SalvageOptions::key`]; damage is described in the report.
+pub fn salvage_extract_reader<R: Read + Seek + Send>(
+    reader: R,
+    dest: &str,
+    options: &SalvageOptions,
+) -> Result<SalvageReport> {
+    let dest = Path::new(dest);
+    fs::create_dir_all(dest).map_err(io_err)?;
+    let mut source = ArchiveSource::new(reader, options.key.as_ref().map(SecretString::expose))?;
+
+    let mut streaming = StreamingFiles::new(dest);
+    let mut scan = Scan::with_sink(&mut streaming);
+    scan.scan(&mut source)?;
+    let fragments = scan.fragments();
+    let Scan {
+        report,
+        data_blocks,
+        tables,
+        index,
+        ..
+    } = scan;
+
+    let mut salvage = SalvageReport {
+        errors: report.errors,
+        ..Default::default()
+    };
+    if let Some(err) = streaming.io_error.take() {
+        return Err(io_err(err));
+    }
+    streaming.finish(options, &mut salvage)?;
+
+    // Latest version of each file; a date of 0 marks a deletion.
+    let mut current = BTreeMap::new();
+    for entry in index {
+        if entry.date == 0 {
+            current.remove(&entry.file);
+        } else {
+            current.insert(entry.file, entry.fragments);
+        }
+    }
+
+    let mut files = Vec::new();
+    let mut placements: HashMap<u32, Vec<(usize, u64)>> = HashMap::new();
+    for (path, ptrs) in current {
+        let Some(target) = dest_path(dest, &path) else {
+            salvage.lost.push(path);
+            continue;
+        };
+        if path.ends_with('/') {
+            fs::create_dir_all(&target).map_err(io_err)?;
+            salvage.recovered.push(path);
+            continue;
+        }
+        // Fragments after one of unknown size cannot be placed.
+        let mut offset = Some(0u64);
+        for &fragment in &ptrs {
+            let (Some(start), Some(&(size, _))) = (offset, fragments.get(&fragment)) else {
+                offset = None;
+                break;
+            };
+            placements
+                .entry(fragment)
+                .or_default()
+                .push((files.len(), start));
+            offset = Some(start + size as u64);
+        }
+        files.push(OutputFile {
+            path,
+            target,
+            size: offset,
+            fragments: ptrs.len(),
+            written: 0,
+            recovered: Vec::new(),
+            created: false,
+        });
+    }
+
+    for table in &tables {
+        let ids = table.first..table.first + table.fragments.len() as u32;
+        if !ids.clone().any(|id| placements.contains_key(&id)) {
+            continue;
+        }
+        let Some(block) = data_blocks
+            .iter()
+            .find(|d| d.location.offset == table.data_offset && d.first == table.first)
+        else {
+            continue;
+        };
+        // Fragments are checked individually, so a block failing its
+        // segment SHA-1 may still yield intact fragments.
+        let data = match read_segment_at(&mut source, &block.location, 0)? {
+            Ok((data, _)) => data,
+            Err(message) => {
+                salvage.errors.push(VerifyError {
+                    location: block.location.clone(),
+                    kind: VerifyErrorKind::Undecodable(message),
+                });
+                continue;
+            }
+        };
+        let sizes = match fragment_sizes(&data) {
+            Ok(sizes) => sizes,
+            Err(message) => {
+                salvage.errors.push(VerifyError {
+                    location: block.location.clone(),
+                    kind: VerifyErrorKind::Malformed(message.into()),
+                });
+                continue;
+            }
+        };
+
+        let mut rest = &data[..];
+        for (id, size) in ids.zip(sizes) {
+            let (fragment, tail) = rest.split_at(size as usize);
+            rest = tail;
+            let Some(targets) = placements.get(&id) else {
+                continue;
+            };
+            if fragments.get(&id) != Some(&(size, sha1(fragment)?)) {
+                salvage.errors.push(VerifyError {
+                    location: VerifyLocation {
+                        fragment: Some(id),
+                        ..block.location.clone()
+                    },
+                    kind: VerifyErrorKind::FragmentMismatch,
+                });
+                continue;
+            }
+            for &(file, offset) in targets {
+                files[file].write_at(offset, fragment)?;
+            }
+        }
+    }
+
+    for file in files {
+        file.finish(options, &mut salvage)?;
+    }
+    Ok(salvage)
+}
+
+/// Maps a stored path to a path below `dest`, or `None` if it would escape.
+fn dest_path(dest: &Path, stored: &str) -> Option<PathBuf> {
+    let mut out = dest.to_path_buf();
+    for (i, part) in stored.split(['/', '\\']).enumerate() {
+        match part {
+            "" | "." => {}
+            ".." => return None,
+            _ if i == 0 && part.len() == 2 && part.ends_with(':') => {}
+            _ => out.push(part),
+        }
+    }
+    (out != dest).then_some(out)
+}
+
+/// Adds `range` to sorted, non-overlapping `ranges`, merging neighbours.
+fn add_range(ranges: &mut Vec<Range<u64>>, range: Range<u64>) {
+    if range.is_empty() {
+        return;
+    }
+    let at = ranges.partition_point(|r| r.start < range.start);
+    ranges.insert(at, range);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for r in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+            _ => merged.push(r),
+        }
+    }
+    *ranges = merged;
+}
+
+// ---------------- Output files ----------------
+
+/// A file from the journaling index being reassembled from fragments.
+struct OutputFile {
+    path: String,
+    target: PathBuf,
+    /// `None` if a fragment's size is unknown.
+    size: Option<u64>,
+    fragments: usize,
+    written: usize,
+    recovered: Vec<Range<u64>>,
+    created: bool,
+}
+
+impl OutputFile {
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        if !self.created {
+            if let Some(parent) = self.target.parent() {
+                fs::create_dir_all(parent).map_err(io_err)?;
+            }
+            File::create(&self.target).map_err(io_err)?;
+            self.created = true;
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(&self.target)
+            .map_err(io_err)?;
+        file.seek(SeekFrom::Start(offset)).map_err(io_err)?;
+        file.write_all(data).map_err(io_err)?;
+        self.written += 1;
+        add_range(&mut self.recovered, offset..offset + data.len() as u64);
+        Ok(())
+    }
+
+    fn finish(mut self, options: &SalvageOptions, report: &mut SalvageReport) -> Result<()> {
+        let complete = self.size.is_some() && self.written == self.fragments;
+        if complete && !self.created {
+            // Empty file.
+            self.write_at(0, &[])?;
+        }
+        if complete || (options.keep_partial && self.created) {
+            if let Some(size) = self.size {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(&self.target)
+                    .map_err(io_err)?;
+                file.set_len(size).map_err(io_err)?;
+            }
+        } else if self.created {
+            fs::remove_file(&self.target).map_err(io_err)?;
+        }
+
+        if complete {
+            report.recovered.push(self.path);
+        } else if self.recovered.is_empty() {
+            report.lost.push(self.path);
+        } else {
+            report.partial.push(PartialFile {
+                path: self.path,
+                size: self.size,
+                recovered: self.recovered,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Writes the segments of a streaming archive: a named segment starts a
+/// file, unnamed ones continue it.  After a damaged segment the position of
+/// the rest of that file is unknown, so its later segments are dropped.
+struct StreamingFiles<'a> {
+    dest: &'a Path,
+    files: Vec<StreamingFile>,
+    /// Block offset and name of the last segment decoded.
+    last: Option<(u64, String)>,
+    io_error: Option<std::io::Error>,
+}
+
+struct StreamingFile {
+    path: String,
+    target: Option<PathBuf>,
+    file: Option<File>,
+    written: u64,
+    broken: bool,
+}
+
+impl<'a> StreamingFiles<'a> {
+    fn new(dest: &'a Path) -> Self {
+        StreamingFiles {
+            dest,
+            files: Vec::new(),
+            last: None,
+            io_error: None,
+        }
+    }
+
+    fn start(&mut self, path: &str) -> &mut StreamingFile {
+        let target = dest_path(self.dest, path);
+        self.files.push(StreamingFile {
+            path: path.to_string(),
+            broken: target.is_none(),
+            target,
+            file: None,
+            written: 0,
+        });
+        self.files.last_mut().expect("just pushed")
+    }
+
+    fn write(file: &mut StreamingFile, data: &[u8]) -> std::io::Result<()> {
+        if file.file.is_none() {
+            let target = file.target.as_ref().expect("unbroken file has a target");
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            file.file = Some(File::create(target)?);
+        }
+        file.file.as_mut().expect("opened").write_all(data)?;
+        file.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn finish(self, options: &SalvageOptions, report: &mut SalvageReport) -> Result<()> {
+        for mut file in self.files {
+            if !file.broken {
+                if file.file.is_none() {
+                    Self::write(&mut file, &[]).map_err(io_err)?;
+                }
+                report.recovered.push(file.path);
+            } else if file.written == 0 {
+                report.lost.push(file.path);
+            } else {
+                drop(file.file.take());
+                if !options.keep_partial {
+                    let target = file.target.as_ref().expect("written file has a target");
+                    fs::remove_file(target).map_err(io_err)?;
+                }
+                report.partial.push(PartialFile {
+                    path: file.path,
+                    size: None,
+                    recovered: std::iter::once(0..file.written).collect(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SegmentSink for StreamingFiles<'_> {
+    fn segment(&mut self, location: &VerifyLocation, _: &[u8], data: Vec<u8>, intact: bool) {
+        if self.io_error.is_some() {
+            return;
+        }
+        self.last = Some((location.offset, location.segment.clone()));
+        let file = if location.segment.is_empty() {
+            match self.files.last_mut() {
+                Some(file) => file,
+                // Continuation of a file whose start was lost.
+                None => return,
+            }
+        } else {
+            self.start(&location.segment)
+        };
+        if file.broken {
+            return;
+        }
+        if !intact {
+            file.broken = true;
+            return;
+        }
+        if let Err(err) = Self::write(file, &data) {
+            self.io_error = Some(err);
+        }
+    }
+
+    fn damaged(&mut self, location: &VerifyLocation) {
+        if parse_journal_name(&location.segment).is_some() {
+            return;
+        }
+        // A failure reading the next segment header still carries the name of
+        // the previous segment.
+        let done = self.last.as_ref() == Some(&(location.offset, location.segment.clone()));
+        let file = if location.segment.is_empty() || done {
+            match self.files.last_mut() {
+                Some(file) => file,
+                None => return,
+            }
+        } else {
+            self.start(&location.segment)
+        };
+        file.broken = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{ArchiveEntry, ZPAQ_TAG, archive_from_entries, zpaq_add};
+
+    fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+        haystack[from..]
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .map(|i| i + from)
+    }
+
+    /// Start and end of the compressed data of every `d` block.
+    fn data_segments(archive: &[u8]) -> Vec<(usize, usize)> {
+        let mut out = Vec::new();
+        let mut from = 0;
+        while let Some(name) = find(archive, b"jDC", from) {
+            from = name + 1;
+            if archive.get(name + 17) != Some(&b'd') {
+                continue;
+            }
+            let comment_end = find(archive, b"jDC\x01", name + 28).expect("comment");
+            let start = comment_end + 4 + 2;
+            let end = find(archive, &ZPAQ_TAG, start).unwrap_or(archive.len());
+            out.push((start, end));
+        }
+        out
+    }
+
+    fn salvaged(dest: &Path, stored: &str) -> Option<Vec<u8>> {
+        fs::read(dest_path(dest, stored).expect("path")).ok()
+    }
+
+    #[test]
+    fn files_outside_a_damaged_block_are_recovered() {
+        let dir = TempDir::new("zpaq-rs-salvage");
+        let archive = dir.join("test.zpaq");
+        let archive_s = archive.to_string_lossy().into_owned();
+        // One transaction, and so one d block, per file.
+        let mut files = Vec::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            let path = dir.join(name);
+            let text: String = (0..3000).map(|i| format!("{name} line {i}\n")).collect();
+            fs::write(&path, &text).expect("write input");
+            let path = path.to_string_lossy().into_owned();
+            zpaq_add(&archive_s, &[&path], "1", 1).expect("zpaq add");
+            files.push((path, text.into_bytes()));
+        }
+
+        let mut bytes = fs::read(&archive).expect("read archive");
+        let blocks = data_segments(&bytes);
+        assert_eq!(blocks.len(), 3);
+        let (start, end) = blocks[1];
+        bytes[(start + end) / 2] ^= 0x10;
+        fs::write(&archive, &bytes).expect("write damaged archive");
+
+        let dest = dir.join("out");
+        let dest_s = dest.to_string_lossy().into_owned();
+        let report =
+            salvage_extract(&archive_s, &dest_s, &SalvageOptions::default()).expect("salvage");
+        assert!(!report.errors.is_empty());
+        let stored: Vec<String> = files.iter().map(|(p, _)| p.replace('\\', "/")).collect();
+        assert!(report.recovered.contains(&stored[0]), "{report:?}");
+        assert!(report.recovered.contains(&stored[2]), "{report:?}");
+        assert!(!report.recovered.contains(&stored[1]), "{report:?}");
+        assert_eq!(salvaged(&dest, &stored[0]).as_ref(), Some(&files[0].1));
+        assert_eq!(salvaged(&dest, &stored[2]).as_ref(), Some(&files[2].1));
+        assert_eq!(salvaged(&dest, &stored[1]), None);
+    }
+
+    #[test]
+    fn streaming_segments_are_salvaged() {
+        let texts: Vec<Vec<u8>> = (0..3)
+            .map(|n| {
+                (0..2000)
+                    .flat_map(|i| format!("{n}:{i} ").into_bytes())
+                    .collect()
+            })
+            .collect();
+        let mut archive = Vec::new();
+        let mut second = 0;
+        for (n, text) in texts.iter().enumerate() {
+            let path = format!("dir/file{n}.txt");
+            let entry = ArchiveEntry {
+                path: &path,
+                data: text,
+                comment: None,
+            };
+            if n == 1 {
+                second = archive.len();
+            }
+            archive.extend(archive_from_entries(&[entry], "2").expect("archive"));
+        }
+        let third = find(&archive, &ZPAQ_TAG, second + 1).expect("third block");
+        archive[(second + third) / 2] ^= 0x10;
+
+        let dir = TempDir::new("zpaq-rs-salvage-stream");
+        let dest_s = dir.to_string_lossy().into_owned();
+        let report = salvage_extract_reader(
+            std::io::Cursor::new(&archive),
+            &dest_s,
+            &SalvageOptions::default(),
+        )
+        .expect("salvage");
+        assert_eq!(report.recovered, ["dir/file0.txt", "dir/file2.txt"]);
+        assert!(report.partial.is_empty() || report.partial[0].path == "dir/file1.txt");
+        assert_eq!(
+            fs::read(dir.join("dir/file0.txt")).expect("file0"),
+            texts[0]
+        );
+        assert_eq!(
+            fs::read(dir.join("dir/file2.txt")).expect("file2"),
+            texts[2]
+        );
+        assert!(!dir.join("dir/file1.txt").exists());
+    }
+}
@@ -0,0 +1,57 @@
+//! Passwords that stay out of logs.
+
+/// A password, such as the `-key` of an encrypted archive.
+///
+/// Its [`Debug`] output is `[redacted]`, so options holding one can be
+/// logged whole, and it has no [`Display`](std::fmt::Display).  The text is
+/// overwritten when the value is dropped.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{SecretString, VerifyOptions};
+///
+/// let options = VerifyOptions { key: Some("hunter2".into()), ..Default::default() };
+/// assert_eq!(format!("{options:?}"), r#"VerifyOptions { deep: false, key: Some([redacted]), persist_state: None, cancel: None }"#);
+/// assert_eq!(options.key.as_ref().map(SecretString::expose), Some("hunter2"));
+/// ```
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    /// Wraps `secret`.
+    pub fn new(secret: String) -> Self {
+        SecretString(secret)
+    }
+
+    /// The password itself.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        SecretString(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        SecretString(secret.to_string())
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        let mut bytes = std::mem::take(&mut self.0).into_bytes();
+        bytes.fill(0);
+        std::hint::black_box(&bytes);
+    }
+}
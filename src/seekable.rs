@@ -0,0 +1,618 @@
+//! Random access to the data of a stream of many blocks.
+//!
+//! libzpaq resets every model at the start of a block, so the blocks of a
+//! stream written by [`compress_stream`](crate::compress_stream) can be
+//! decoded on their own.  A [`StreamIndex`] says which part of the
+//! decompressed data each block holds, and [`random_read`] decodes only the
+//! blocks holding the bytes asked for.
+//!
+//! [`CompressOptions::embed_stream_index`](crate::CompressOptions::embed_stream_index)
+//! ends a stream with its index, in a store block of its own.  The index
+//! holds a magic number, the block count and three little-endian `u64`s
+//! per block, then zeros and a footer: the offset of the index block, the
+//! length of the index and the magic number again.  The zeros keep the
+//! footer in the last chunk of the store block, so it lies a fixed distance
+//! from the end of the stream, where [`build_stream_index`] looks for it.
+
+use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::ptr;
+
+use crate::blocks::{comment_size, skip_segment};
+use crate::verify::{ArchiveSource, SourceState};
+use crate::{
+    BlockSizeReport, FfiReader, FfiWriter, Result, ResumableDecompressor, ResumeToken,
+    SharedVecWriter, ZPAQ_TAG, ZpaqError, clear_last_error, compress_stream_as, err_from_last,
+    handle, io_err, list_blocks, sha1, sys,
+};
+
+/// Segment filename of the index
+/// [`CompressOptions::embed_stream_index`](crate::CompressOptions::embed_stream_index)
+/// appends to a stream.
+///
+/// [`decompress_stream`](crate::decompress_stream) and the decompressors
+/// built on it skip segments of this name, so the index adds nothing to
+/// the output.  Other readers see it as a segment: `zpaq x` extracts it as
+/// a small file of this name next to the stream's own files.
+pub const STREAM_INDEX_FILENAME: &str = ".zpaq-rs-stream-index";
+
+/// Starts and ends an embedded index.
+const INDEX_MAGIC: [u8; 8] = *b"zpqsidx1";
+
+/// Bytes of an embedded index's footer.
+const FOOTER_LEN: usize = 24;
+
+/// Bytes a store block with a SHA-1 ends with after its data: the zero
+/// chunk length, the SHA-1 marker and digest, and the end of block.
+const STORE_TRAILER_LEN: usize = 26;
+
+/// Data bytes in the first chunk of a store block, after the PASS byte.
+const FIRST_CHUNK: usize = (1 << 16) - 1;
+
+/// Data bytes in each later chunk of a store block.
+const CHUNK: usize = 1 << 16;
+
+/// Most bytes of tag and headers the index block has besides its data.
+const INDEX_BLOCK_HEADERS: u64 = 1 << 10;
+
+/// Where each block of a stream lies, in the stream and in its
+/// decompressed data, as built by [`build_stream_index`].
+///
+/// With the `serde` feature the index serializes as an object with a
+/// `blocks` array of [`StreamBlock`] objects, so it can be built once and
+/// saved next to the stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct StreamIndex {
+    /// The blocks, in stream order.
+    pub blocks: Vec<StreamBlock>,
+}
+
+/// One block of a [`StreamIndex`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct StreamBlock {
+    /// Offset of the block in the compressed stream.
+    pub compressed_offset: u64,
+    /// Offset of its data in the decompressed stream.
+    pub uncompressed_offset: u64,
+    /// Decompressed size of its segments together.
+    pub size: u64,
+}
+
+impl StreamIndex {
+    /// Decompressed size of the whole stream.
+    pub fn len(&self) -> u64 {
+        self.blocks
+            .last()
+            .map_or(0, |b| b.uncompressed_offset + b.size)
+    }
+
+    /// Whether the stream decompresses to nothing.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Indexes the blocks of the ZPAQ stream in `reader`, for [`random_read`].
+///
+/// The data are what [`decompress_stream`](crate::decompress_stream)
+/// writes: the segments of every block, one after another.  The size of
+/// each segment is taken from its comment, which libzpaq starts with the
+/// size (as in streams from [`compress_stream`](crate::compress_stream)),
+/// and nothing is decoded.  If a comment does not give it, the whole
+/// stream is decoded once to measure the segments.
+///
+/// A stream written with
+/// [`CompressOptions::embed_stream_index`](crate::CompressOptions::embed_stream_index)
+/// carries its index at the end, and only that is read.  An index that
+/// does not check out is ignored and the stream scanned instead.
+///
+/// # Example
+///
+/// ```rust
+/// use std::io::Cursor;
+///
+/// let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+/// // Blocks of 1 MiB.
+/// let c = zpaq_rs::compress_to_vec(&data, "10")?;
+/// let index = zpaq_rs::build_stream_index(Cursor::new(&c))?;
+/// assert_eq!(index.len(), data.len() as u64);
+/// let middle = zpaq_rs::random_read(Cursor::new(&c), &index, 1_500_000..1_500_100)?;
+/// assert_eq!(middle, data[1_500_000..1_500_100]);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn build_stream_index<R: Read + Seek + Send>(mut reader: R) -> Result<StreamIndex> {
+    let position = reader.stream_position().map_err(io_err)?;
+    if let Some(index) = embedded_index(&mut reader)? {
+        return Ok(index);
+    }
+    reader.seek(SeekFrom::Start(position)).map_err(io_err)?;
+    let mut source = ArchiveSource::new(reader, None)?;
+    let start = source.position();
+    if let Some(index) = index_blocks(&mut source, false)? {
+        return Ok(index);
+    }
+    source.seek_to(start)?;
+    let index = index_blocks(&mut source, true)?;
+    Ok(index.expect("decoded segments have sizes"))
+}
+
+/// Reads `range` of the decompressed data of the stream in `reader`,
+/// which `index` describes, decoding only the blocks holding it.
+///
+/// # Errors
+///
+/// Fails if `range` reaches past the end of the data, and if a block
+/// decodes to a size other than the index gives, as when the index is of
+/// another stream.
+pub fn random_read<R: Read + Seek + Send>(
+    reader: R,
+    index: &StreamIndex,
+    range: Range<u64>,
+) -> Result<Vec<u8>> {
+    if range.start > range.end || range.end > index.len() {
+        return Err(ZpaqError::Ffi(format!(
+            "range {range:?} is not within the {} bytes of the stream",
+            index.len()
+        )));
+    }
+    let mut out = Vec::with_capacity((range.end - range.start) as usize);
+    if range.is_empty() {
+        return Ok(out);
+    }
+    let first = index
+        .blocks
+        .partition_point(|b| b.uncompressed_offset + b.size <= range.start);
+    let token = ResumeToken {
+        compressed_offset: index.blocks[first].compressed_offset,
+        segment_index: 0,
+        bytes_emitted: index.blocks[first].uncompressed_offset,
+    };
+    // The blocks are consecutive, so one decompressor reads them in turn.
+    let mut decompressor = ResumableDecompressor::resume(reader, token)?;
+    for block in &index.blocks[first..] {
+        if block.uncompressed_offset >= range.end {
+            break;
+        }
+        let mut data = Vec::with_capacity(block.size as usize);
+        decompressor.next_block(&mut data)?;
+        if data.len() as u64 != block.size {
+            return Err(ZpaqError::Ffi(format!(
+                "block at offset {} decoded to {} bytes, the index gives {}",
+                block.compressed_offset,
+                data.len(),
+                block.size
+            )));
+        }
+        let start = range.start.saturating_sub(block.uncompressed_offset) as usize;
+        let end = (range.end - block.uncompressed_offset).min(block.size) as usize;
+        out.extend_from_slice(&data[start..end]);
+    }
+    Ok(out)
+}
+
+/// Appends to `writer`, after the blocks `sizes` describes, an index of
+/// them in a store block named [`STREAM_INDEX_FILENAME`].
+pub(crate) fn write_stream_index<W: Write + Send>(
+    writer: W,
+    sizes: &[BlockSizeReport],
+) -> Result<()> {
+    let mut index = INDEX_MAGIC.to_vec();
+    index.extend_from_slice(&(sizes.len() as u64).to_le_bytes());
+    let (mut compressed, mut uncompressed) = (0u64, 0u64);
+    for size in sizes {
+        for field in [compressed, uncompressed, size.uncompressed] {
+            index.extend_from_slice(&field.to_le_bytes());
+        }
+        compressed += size.compressed;
+        uncompressed += size.uncompressed;
+    }
+    let last = last_chunk(index.len() + FOOTER_LEN);
+    if last < FOOTER_LEN {
+        index.resize(index.len() + FOOTER_LEN - last, 0);
+    }
+    let len = (index.len() + FOOTER_LEN) as u64;
+    for field in [compressed, len] {
+        index.extend_from_slice(&field.to_le_bytes());
+    }
+    index.extend_from_slice(&INDEX_MAGIC);
+    compress_stream_as(
+        "compress_stream",
+        &index[..],
+        writer,
+        "0",
+        Some(STREAM_INDEX_FILENAME.as_bytes()),
+        None,
+        None,
+    )
+}
+
+/// Data bytes in the last chunk of a store block of `len` bytes.
+fn last_chunk(len: usize) -> usize {
+    if len <= FIRST_CHUNK {
+        len
+    } else {
+        (len - FIRST_CHUNK - 1) % CHUNK + 1
+    }
+}
+
+/// The index [`write_stream_index`] ended the stream in `reader` with, or
+/// `None` if there is none or it does not check out.
+fn embedded_index<R: Read + Seek + Send>(reader: &mut R) -> Result<Option<StreamIndex>> {
+    let len = reader.seek(SeekFrom::End(0)).map_err(io_err)?;
+    let mut tail = [0u8; FOOTER_LEN + STORE_TRAILER_LEN];
+    if len < tail.len() as u64 {
+        return Ok(None);
+    }
+    reader
+        .seek(SeekFrom::Start(len - tail.len() as u64))
+        .map_err(io_err)?;
+    reader.read_exact(&mut tail).map_err(io_err)?;
+    let (footer, trailer) = tail.split_at(FOOTER_LEN);
+    if footer[16..] != INDEX_MAGIC || trailer[..5] != [0, 0, 0, 0, 253] || trailer[25] != 255 {
+        return Ok(None);
+    }
+    let (start, index_len) = (le64(&footer[..8]), le64(&footer[8..16]));
+    // The data, a length before each chunk, and the headers.
+    let most = index_len + 4 * (index_len / CHUNK as u64 + 2) + INDEX_BLOCK_HEADERS;
+    if start >= len || len - start > most {
+        return Ok(None);
+    }
+    reader.seek(SeekFrom::Start(start)).map_err(io_err)?;
+    let mut block = Vec::new();
+    reader
+        .take(len - start)
+        .read_to_end(&mut block)
+        .map_err(io_err)?;
+    if !block.starts_with(&ZPAQ_TAG) {
+        return Ok(None);
+    }
+    let Ok(blocks) = list_blocks(&block) else {
+        return Ok(None);
+    };
+    let [info] = &blocks[..] else {
+        return Ok(None);
+    };
+    let [segment] = &info.segments[..] else {
+        return Ok(None);
+    };
+    if segment.filename_bytes != STREAM_INDEX_FILENAME.as_bytes() {
+        return Ok(None);
+    }
+    let mut index = Vec::new();
+    if ResumableDecompressor::new(Cursor::new(&block))?
+        .next_block(&mut index)
+        .is_err()
+    {
+        return Ok(None);
+    }
+    if segment.sha1 != Some(sha1(&index)?) {
+        return Ok(None);
+    }
+    Ok(parse_index(&index, start))
+}
+
+/// Reads an index [`write_stream_index`] wrote in a block at `start`,
+/// checking that its blocks follow one another and end there.
+fn parse_index(index: &[u8], start: u64) -> Option<StreamIndex> {
+    let body = index.strip_prefix(&INDEX_MAGIC[..])?;
+    let (body, footer) = body.split_at(body.len().checked_sub(FOOTER_LEN)?);
+    if footer[16..] != INDEX_MAGIC
+        || le64(&footer[..8]) != start
+        || le64(&footer[8..16]) != index.len() as u64
+    {
+        return None;
+    }
+    let count = le64(body.get(..8)?);
+    let records = &body[8..];
+    if count > (records.len() / 24) as u64 {
+        return None;
+    }
+    let (records, padding) = records.split_at(count as usize * 24);
+    if padding.iter().any(|&b| b != 0) {
+        return None;
+    }
+    let mut parsed = StreamIndex::default();
+    for record in records.chunks_exact(24) {
+        let block = StreamBlock {
+            compressed_offset: le64(&record[..8]),
+            uncompressed_offset: le64(&record[8..16]),
+            size: le64(&record[16..]),
+        };
+        let follows = match parsed.blocks.last() {
+            Some(prev) => {
+                block.compressed_offset > prev.compressed_offset
+                    && Some(block.uncompressed_offset)
+                        == prev.uncompressed_offset.checked_add(prev.size)
+            }
+            None => block.uncompressed_offset == 0,
+        };
+        if !follows || block.compressed_offset >= start {
+            return None;
+        }
+        block.uncompressed_offset.checked_add(block.size)?;
+        parsed.blocks.push(block);
+    }
+    Some(parsed)
+}
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes.try_into().expect("8 bytes"))
+}
+
+/// Indexes the blocks from the source's position, decoding the segments
+/// with `decode` and otherwise reading their sizes from their comments.
+/// Returns `None` without `decode` when a comment has no size.
+fn index_blocks<R: Read + Seek + Send>(
+    source: &mut ArchiveSource<R>,
+    decode: bool,
+) -> Result<Option<StreamIndex>> {
+    let state = source.shared_state();
+    clear_last_error()?;
+    let reader = FfiReader::new(&mut *source)?;
+    let decompresser = handle::Decompresser::new()?;
+    let result = read_index(decompresser.raw, reader.raw, &state, decode);
+    drop(decompresser);
+    drop(reader);
+    if let Some(err) = source.take_io_error() {
+        return Err(err);
+    }
+    result
+}
+
+fn read_index(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+    state: &SourceState,
+    decode: bool,
+) -> Result<Option<StreamIndex>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let offset = || {
+        let buffered = unsafe { sys::zpaq_decompresser_buffered(decompresser) }.max(0);
+        state.pos().saturating_sub(buffered as u64)
+    };
+    let mut index = StreamIndex::default();
+    let mut uncompressed = 0u64;
+    loop {
+        let compressed_offset = offset();
+        let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+        if rc_block < 0 {
+            return Err(err_from_last());
+        }
+        if rc_block == 0 {
+            return Ok(Some(index));
+        }
+        let mut size = 0u64;
+        let mut data = false;
+        loop {
+            let filename = SharedVecWriter::new();
+            let filename_writer = FfiWriter::new(filename.clone())?;
+            let rc_filename =
+                unsafe { sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw) };
+            drop(filename_writer);
+            if rc_filename < 0 {
+                return Err(err_from_last());
+            }
+            if rc_filename == 0 {
+                break;
+            }
+            let comment = SharedVecWriter::new();
+            let comment_writer = FfiWriter::new(comment.clone())?;
+            if unsafe { sys::zpaq_decompresser_read_comment(decompresser, comment_writer.raw) } != 0
+            {
+                return Err(err_from_last());
+            }
+            drop(comment_writer);
+            // An index embedded in the stream is passed over undecoded.
+            if filename.bytes() != STREAM_INDEX_FILENAME.as_bytes() {
+                data = true;
+                size += if decode {
+                    skip_segment(decompresser)?
+                } else {
+                    match comment_size(&comment.bytes()) {
+                        Some(size) => size,
+                        None => return Ok(None),
+                    }
+                };
+            }
+            let rc_end =
+                unsafe { sys::zpaq_decompresser_read_segment_end(decompresser, ptr::null_mut()) };
+            if rc_end != 0 {
+                return Err(err_from_last());
+            }
+        }
+        if !data {
+            continue;
+        }
+        index.blocks.push(StreamBlock {
+            compressed_offset,
+            uncompressed_offset: uncompressed,
+            size,
+        });
+        uncompressed += size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::{random, text};
+    use crate::{
+        ArchiveEntry, CompressOptions, archive_append_entries, archive_from_entries,
+        compress_stream, compress_to_vec_parallel, compress_to_vec_with, decompress_size,
+        decompress_to_vec, original_size_hint, zpaq_command,
+    };
+    use std::io::Cursor;
+
+    /// Deterministic ranges within `len`, from empty to a few blocks long.
+    fn ranges(len: u64, count: u64) -> Vec<Range<u64>> {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        (0..count)
+            .map(|i| {
+                let start = next() % len;
+                let max = [0, 1, 100, 1 << 16, 3 << 20][i as usize % 5];
+                start..(start + next() % (max + 1)).min(len)
+            })
+            .chain([0..0, 0..len, len - 1..len, len..len])
+            .collect()
+    }
+
+    #[test]
+    fn random_ranges_match_the_decompressed_stream() {
+        let data = [text(32 << 20), random(4, 32 << 20)].concat();
+        // 1 MiB blocks.
+        let compressed = compress_to_vec_parallel(&data, "10", 4).expect("compress");
+        let index = build_stream_index(Cursor::new(&compressed)).expect("index");
+        assert!(index.blocks.len() >= 64, "{} blocks", index.blocks.len());
+        assert_eq!(index.len(), data.len() as u64);
+        for range in ranges(index.len(), 200) {
+            let read = random_read(Cursor::new(&compressed), &index, range.clone())
+                .unwrap_or_else(|e| panic!("{range:?}: {e}"));
+            assert!(
+                read == data[range.start as usize..range.end as usize],
+                "{range:?}"
+            );
+        }
+        let past = random_read(Cursor::new(&compressed), &index, 0..index.len() + 1);
+        assert!(matches!(past, Err(ZpaqError::Ffi(_))), "{past:?}");
+    }
+
+    #[test]
+    fn segments_without_sizes_are_measured() {
+        // Streaming archives store the segments without a size comment.
+        let entry = |path, data| ArchiveEntry {
+            path,
+            data,
+            comment: None,
+        };
+        let mut archive =
+            archive_from_entries(&[entry("a", b"first "), entry("b", b"block")], "1").expect("a");
+        archive_append_entries(&mut archive, &[entry("c", b" and the second")], "1")
+            .expect("append");
+        let data = decompress_to_vec(&archive).expect("decompress");
+        let index = build_stream_index(Cursor::new(&archive)).expect("index");
+        let sizes: Vec<u64> = index.blocks.iter().map(|b| b.size).collect();
+        assert_eq!(sizes, [11, 15]);
+        let read = random_read(Cursor::new(&archive), &index, 8..20).expect("read");
+        assert_eq!(read, data[8..20]);
+
+        // An index of another stream is caught when a block disagrees.
+        let mut other = Vec::new();
+        compress_stream(&data[..], &mut other, "1", None, None).expect("compress");
+        let wrong = random_read(Cursor::new(&other), &index, 0..5);
+        assert!(wrong.is_err(), "{wrong:?}");
+    }
+
+    #[test]
+    fn embedded_indexes_are_read_and_left_out_of_the_data() {
+        let data = [text(6 << 20), random(5, 2 << 20)].concat();
+        let options = CompressOptions {
+            filename: Some("data.bin".into()),
+            ..Default::default()
+        };
+        // 1 MiB blocks.
+        let (plain, _) = compress_to_vec_with(&data, "10", &options).expect("compress");
+        let embedding = CompressOptions {
+            embed_stream_index: true,
+            ..options
+        };
+        let (indexed, stats) = compress_to_vec_with(&data, "10", &embedding).expect("compress");
+        assert!(indexed.len() > plain.len() && indexed.starts_with(&plain));
+        assert_eq!(stats.bytes_out, indexed.len() as u64);
+
+        assert!(decompress_to_vec(&indexed).expect("decompress") == data);
+        assert_eq!(decompress_size(&indexed).expect("size"), data.len() as u64);
+        assert_eq!(
+            original_size_hint(&indexed).expect("hint"),
+            Some(data.len() as u64)
+        );
+
+        let scanned = build_stream_index(Cursor::new(&plain)).expect("index");
+        let index = build_stream_index(Cursor::new(&indexed)).expect("index");
+        assert_eq!(index, scanned);
+        assert_eq!(index.len(), data.len() as u64);
+        let read = random_read(Cursor::new(&indexed), &index, 3_000_000..5_000_000).expect("read");
+        assert!(read == data[3_000_000..5_000_000]);
+
+        // zpaq extracts the index as a file of its own, beside the data.
+        let dir = TempDir::new("zpaq-rs-stream-index");
+        let archive = dir.join("indexed.zpaq").to_string_lossy().into_owned();
+        let out = dir.join("out").to_string_lossy().into_owned();
+        std::fs::write(&archive, &indexed).expect("write");
+        zpaq_command(&["extract", &archive, "-to", &out]).expect("extract");
+        let extracted = std::fs::read(dir.join("out").join("data.bin")).expect("read");
+        assert!(extracted == data);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn embedded_indexes_save_the_scan() {
+        use crate::StatsCollector;
+
+        let data = text(16 << 20);
+        let options = CompressOptions {
+            embed_stream_index: true,
+            ..Default::default()
+        };
+        let (indexed, _) = compress_to_vec_with(&data, "10", &options).expect("compress");
+        let plain = compress_to_vec_parallel(&data, "10", 4).expect("compress");
+        let collector = StatsCollector::new();
+        let (scanned, scan) = collector.collect(|| build_stream_index(Cursor::new(&plain)));
+        let (index, read) = collector.collect(|| build_stream_index(Cursor::new(&indexed)));
+        assert_eq!(index.expect("index"), scanned.expect("index"));
+        assert!(scan.bytes_read >= plain.len() as u64 / 2, "{scan:?}");
+        assert!(read.bytes_read < 4 << 10, "{read:?}");
+    }
+
+    #[test]
+    fn damaged_embedded_indexes_fall_back_to_a_scan() {
+        let data = text(3 << 20);
+        let options = CompressOptions {
+            embed_stream_index: true,
+            ..Default::default()
+        };
+        let (mut indexed, _) = compress_to_vec_with(&data, "10", &options).expect("compress");
+        let expected = build_stream_index(Cursor::new(&indexed)).expect("index");
+        // The footer's offset of the index block.
+        let at = indexed.len() - FOOTER_LEN - STORE_TRAILER_LEN;
+        indexed[at] ^= 1;
+        assert_eq!(
+            embedded_index(&mut Cursor::new(&indexed)).expect("read"),
+            None
+        );
+        let index = build_stream_index(Cursor::new(&indexed)).expect("index");
+        assert_eq!(index, expected);
+        assert_eq!(index.len(), data.len() as u64);
+    }
+
+    #[test]
+    fn footers_stay_at_the_end_of_large_indexes() {
+        // Around the count whose index first needs a second chunk, and the
+        // one whose footer would start in the first.
+        for count in [2728, 2729, 2730] {
+            let sizes = vec![
+                BlockSizeReport {
+                    uncompressed: 3,
+                    compressed: 1,
+                };
+                count
+            ];
+            // The index follows one byte of each block it describes.
+            let mut stream = vec![0u8; count];
+            write_stream_index(&mut stream, &sizes).expect("write");
+            let index = embedded_index(&mut Cursor::new(&stream))
+                .expect("read")
+                .unwrap_or_else(|| panic!("no index of {count} blocks"));
+            assert_eq!(index.blocks.len(), count);
+            assert_eq!(index.len(), 3 * count as u64);
+        }
+    }
+}
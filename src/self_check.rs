@@ -0,0 +1,81 @@
+//! A runtime check that this build compresses exactly like the reference
+//! build, for applications that address content by its compressed bytes.
+
+use crate::display::hex;
+use crate::{Result, ZpaqError, compress_to_vec, sha256};
+
+/// SHA-256 of [`compress_to_vec`] of [`corpus`] for each method, as
+/// produced by the reference build.  `tests/determinism.rs` checks a wider
+/// corpus against hashes committed the same way.
+const GOLDEN: &[(&str, &str)] = &[
+    (
+        "0",
+        "3ede1705796b470cfd3f7aaf4c735329e203b78ccc9cdd679a4c527761b121bd",
+    ),
+    (
+        "1",
+        "7d9c0971a46fe8255cc14fbdca1a9f6e8144dcf66bf53b67ae3a5acefffb40ce",
+    ),
+    (
+        "2",
+        "55727026d08356e7af1bfe3fc0df74718b5a39caa9d536960d98add77d2d20d6",
+    ),
+    (
+        "3",
+        "5d000db165caf48bf37c795d9fd752c7d4f8cf52c0091b764238bd9d5b23e906",
+    ),
+    (
+        "4",
+        "ae724d01038ccfb242961f2ac962c35d6ed49a61c7ffe83ef126f5515814815a",
+    ),
+    (
+        "5",
+        "5fd3ef433c7acfdc946dd87b2d54549deee306262c3c35f7a5896dbb2cd03226",
+    ),
+];
+
+/// The built-in input: text, a run of zeros and pseudo-random bytes, so
+/// that every model component sees some work.
+fn corpus() -> Vec<u8> {
+    let mut data: Vec<u8> = (0..400u32)
+        .flat_map(|i| format!("record {i}: value {} flag {}\n", i * i % 1009, i % 3).into_bytes())
+        .collect();
+    data.resize(data.len() + 4096, 0);
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    data.extend((0..8192).map(|_| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 56) as u8
+    }));
+    data
+}
+
+/// Compresses a built-in input with methods 0 to 5 and compares each result
+/// with the output of the reference build.
+///
+/// Compressed output is meant to be identical on every platform and with
+/// or without the JIT (the `nojit` feature), so a mismatch means this build
+/// is broken: fails with [`ZpaqError::SelfCheckFailed`] for the first
+/// method that differs.
+///
+/// # Example
+///
+/// ```rust
+/// zpaq_rs::self_check()?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn self_check() -> Result<()> {
+    let data = corpus();
+    for &(method, expected) in GOLDEN {
+        let actual = hex(&sha256(&compress_to_vec(&data, method)?)?);
+        if actual != expected {
+            return Err(ZpaqError::SelfCheckFailed {
+                method: method.to_string(),
+                expected: expected.to_string(),
+                actual,
+            });
+        }
+    }
+    Ok(())
+}
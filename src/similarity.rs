@@ -0,0 +1,736 @@
+//! Compression-based information measures built on [`StreamingCompressor`]
+//! and [`compress_size`].
+
+use std::collections::VecDeque;
+use std::io::Read;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use std::ptr;
+
+use crate::{
+    FfiReader, FfiWriter, Result, SharedCounter, StreamingCompressor, ZpaqError, clear_last_error,
+    compress_size, compress_size_stream, err_from_last, handle, start_block_for_method, sys,
+};
+
+fn trained(train: &[u8], method: &str) -> Result<StreamingCompressor> {
+    let mut sc = StreamingCompressor::new(method)?;
+    for &b in train {
+        sc.push(b)?;
+    }
+    Ok(sc)
+}
+
+/// Returns the number of bits needed to encode `test` with a model first
+/// trained on `train`.
+///
+/// The model keeps learning from `test` as it is scored, so this is the
+/// conditional code length of `test` given `train`, not the cross-entropy
+/// of `test` under a model frozen after `train`: libzpaq models learn in the
+/// same step that moves them on to the next context, so one cannot follow
+/// the test data without also learning from it.
+///
+/// `method` follows the [`StreamingCompressor`] restrictions (levels 1–3 or
+/// a streamable explicit method).
+///
+/// # Example
+///
+/// ```rust
+/// let bits = zpaq_rs::cross_bits(b"abcabcabcabc", b"abcabc", "2").unwrap();
+/// assert!(bits > 0.0);
+/// ```
+pub fn cross_bits(train: &[u8], test: &[u8], method: &str) -> Result<f64> {
+    let mut sc = trained(train, method)?;
+    let base = sc.bits();
+    for &b in test {
+        sc.push(b)?;
+    }
+    Ok(sc.bits() - base)
+}
+
+/// Returns the label whose training data gives the smallest [`cross_bits`]
+/// for `test`.
+///
+/// Ties keep the earliest class.  Returns [`ZpaqError::Ffi`] if `classes` is
+/// empty.
+///
+/// # Example
+///
+/// ```rust
+/// let classes = [("a", &b"aaaaaaaaaaaaaaaa"[..]), ("b", &b"bbbbbbbbbbbbbbbb"[..])];
+/// let label = zpaq_rs::classify(b"bbbb", &classes, "2").unwrap();
+/// assert_eq!(label, "b");
+/// ```
+pub fn classify<L: Clone>(test: &[u8], classes: &[(L, &[u8])], method: &str) -> Result<L> {
+    let mut best: Option<(f64, &L)> = None;
+    for (label, train) in classes {
+        let bits = cross_bits(train, test, method)?;
+        if best.is_none_or(|(b, _)| bits < b) {
+            best = Some((bits, label));
+        }
+    }
+    best.map(|(_, label)| label.clone())
+        .ok_or_else(|| ZpaqError::Ffi("classify requires at least one class".into()))
+}
+
+/// Size in bytes of `data` compressed as one block and one segment with the
+/// same model [`StreamingCompressor`] would use for `method`.
+fn modeled_size(data: &[u8], method: &str) -> Result<u64> {
+    clear_last_error()?;
+    let compressor = handle::Compressor::new()?;
+    let counter = SharedCounter::default();
+    let result = (|| {
+        let output = FfiWriter::new(counter.clone())?;
+        let input = FfiReader::new(data)?;
+        unsafe {
+            if sys::zpaq_compressor_set_output(compressor.raw, output.raw) != 0
+                || sys::zpaq_compressor_set_input(compressor.raw, input.raw) != 0
+                || sys::zpaq_compressor_write_tag(compressor.raw) != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        start_block_for_method(compressor.raw, method)?;
+        unsafe {
+            if sys::zpaq_compressor_start_segment(compressor.raw, ptr::null(), ptr::null()) != 0 {
+                return Err(err_from_last());
+            }
+            loop {
+                let rc = sys::zpaq_compressor_compress(compressor.raw, 1 << 20);
+                if rc < 0 {
+                    return Err(err_from_last());
+                }
+                if rc == 0 {
+                    break;
+                }
+            }
+            if sys::zpaq_compressor_end_segment(compressor.raw, ptr::null()) != 0
+                || sys::zpaq_compressor_end_block(compressor.raw) != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        Ok(())
+    })();
+    drop(compressor);
+    result.map(|()| counter.get())
+}
+
+/// Returns the compressed cost of `data` in bits per input byte.
+///
+/// Computed as `8 * (C(data) − C(∅)) / data.len()`, where `C` is the size of a
+/// single-block, single-segment stream and `C(∅)` is the same stream with no
+/// data, so block and segment framing is not counted.  This differs from
+/// [`StreamingCompressor::bits`], which includes the headers; its starting
+/// value is subtracted the same way by [`entropy_profile`].  The model is the
+/// one [`StreamingCompressor`] uses for `method` (same restrictions), which
+/// keeps the result comparable with [`entropy_profile`].  Empty input returns
+/// `0.0`.
+///
+/// # Example
+///
+/// ```rust
+/// let text = b"to be or not to be, that is the question. ".repeat(50);
+/// let bpb = zpaq_rs::bits_per_byte(&text, "2").unwrap();
+/// assert!(bpb < 4.0);
+/// ```
+pub fn bits_per_byte(data: &[u8], method: &str) -> Result<f64> {
+    if data.is_empty() {
+        return Ok(0.0);
+    }
+    let full = modeled_size(data, method)?;
+    let empty = modeled_size(&[], method)?;
+    Ok(8.0 * full.saturating_sub(empty) as f64 / data.len() as f64)
+}
+
+/// Evaluates the cumulative cost of `data` at `points` evenly spaced prefix
+/// lengths, returning `(prefix_len, bits_per_byte)` pairs.
+///
+/// All points come from a single [`StreamingCompressor`] pass.  Prefix
+/// lengths are strictly increasing and the last one is `data.len()`; fewer
+/// than `points` pairs are returned when `data` is shorter than `points`.
+/// The final value agrees with [`bits_per_byte`] up to arithmetic-coder
+/// flushing (a few bytes over the whole input).
+pub fn entropy_profile(data: &[u8], method: &str, points: usize) -> Result<Vec<(usize, f64)>> {
+    let mut profile = Vec::with_capacity(points.min(data.len()));
+    if data.is_empty() || points == 0 {
+        return Ok(profile);
+    }
+    let mut sc = StreamingCompressor::new(method)?;
+    let base = sc.bits();
+    let mut fed = 0usize;
+    for k in 1..=points {
+        let target = data.len() * k / points;
+        if target <= fed {
+            continue;
+        }
+        for &b in &data[fed..target] {
+            sc.push(b)?;
+        }
+        fed = target;
+        profile.push((fed, (sc.bits() - base) / fed as f64));
+    }
+    Ok(profile)
+}
+
+/// Normalized compression distance between `x` and `y`:
+/// `(C(x ‖ y) − min(C(x), C(y))) / max(C(x), C(y))`, where `C` is
+/// [`compress_size`] under `method`.
+///
+/// Near 0 for identical content, near 1 (and a little above, from the
+/// framing) for unrelated content.
+///
+/// # Example
+///
+/// ```rust
+/// let text = b"the cat sat on the mat. ".repeat(40);
+/// let same = zpaq_rs::ncd(&text, &text, "2")?;
+/// let noise = zpaq_rs::random_bytes(text.len())?;
+/// assert!(same < zpaq_rs::ncd(&text, &noise, "2")?);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn ncd(x: &[u8], y: &[u8], method: &str) -> Result<f64> {
+    let x_cost = compress_size(x, method)?;
+    let y_cost = compress_size(y, method)?;
+    let joint_cost = compress_size_stream(x.chain(y), method, None, None)?;
+    Ok(normalized(joint_cost, x_cost, y_cost))
+}
+
+/// NCD from the joint and separate compressed sizes.
+fn normalized(joint_cost: u64, x_cost: u64, y_cost: u64) -> f64 {
+    let lo = x_cost.min(y_cost) as f64;
+    let hi = x_cost.max(y_cost) as f64;
+    if hi == 0.0 {
+        1.0
+    } else {
+        (joint_cost as f64 - lo) / hi
+    }
+}
+
+/// A fixed set of reference inputs to measure queries against with
+/// [`ncd`].
+///
+/// Each reference is compressed once, when the set is built, so a query
+/// costs one compression of itself and one of itself followed by each
+/// reference, spread over [`threads`](Self::threads) threads.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::ReferenceSet;
+///
+/// let refs = [
+///     b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n".repeat(20),
+///     b"SELECT id, name FROM users WHERE id = 42;\n".repeat(20),
+/// ];
+/// let set = ReferenceSet::new(&refs, "2")?;
+/// let query = b"GET /about.html HTTP/1.1\r\nHost: example.org\r\n".repeat(5);
+/// let nearest = set.nearest(&query, 1)?;
+/// assert_eq!(nearest[0].0, 0);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReferenceSet {
+    method: String,
+    threads: usize,
+    /// Each reference with its compressed size.
+    references: Vec<(Vec<u8>, u64)>,
+}
+
+impl ReferenceSet {
+    /// Compresses each of `references` under `method` and keeps it with its
+    /// size, on every core.
+    pub fn new<T: AsRef<[u8]>>(
+        references: impl IntoIterator<Item = T>,
+        method: &str,
+    ) -> Result<Self> {
+        let references: Vec<Vec<u8>> = references
+            .into_iter()
+            .map(|r| r.as_ref().to_vec())
+            .collect();
+        let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let sizes = each(references.len(), threads, |i| {
+            compress_size(&references[i], method)
+        })?;
+        Ok(ReferenceSet {
+            method: method.to_string(),
+            threads,
+            references: references.into_iter().zip(sizes).collect(),
+        })
+    }
+
+    /// Spreads queries over `threads` threads instead of one per core.
+    /// `threads <= 1` runs them on the calling thread.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Number of references.
+    pub fn len(&self) -> usize {
+        self.references.len()
+    }
+
+    /// Whether the set has no references.
+    pub fn is_empty(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// The [`ncd`] of `x` and each reference, in the order the references
+    /// were given.
+    pub fn query(&self, x: &[u8]) -> Result<Vec<f64>> {
+        let x_cost = compress_size(x, &self.method)?;
+        each(self.references.len(), self.threads, |i| {
+            let (reference, cost) = &self.references[i];
+            let joint_cost =
+                compress_size_stream(x.chain(&reference[..]), &self.method, None, None)?;
+            Ok(normalized(joint_cost, x_cost, *cost))
+        })
+    }
+
+    /// The `k` references nearest `x` by [`query`](Self::query), as
+    /// `(index, distance)` pairs, nearest first.  Ties keep the earlier
+    /// reference first.  Fewer than `k` if the set is smaller.
+    pub fn nearest(&self, x: &[u8], k: usize) -> Result<Vec<(usize, f64)>> {
+        let mut distances: Vec<(usize, f64)> = self.query(x)?.into_iter().enumerate().collect();
+        distances.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        distances.truncate(k);
+        Ok(distances)
+    }
+}
+
+/// `f(0..n)` on up to `threads` scoped threads, taking indices in turn, or
+/// on the calling thread for `threads <= 1`.  Fails with the error of the
+/// lowest failing index.
+pub(crate) fn each<T: Send>(
+    n: usize,
+    threads: usize,
+    f: impl Fn(usize) -> Result<T> + Sync,
+) -> Result<Vec<T>> {
+    if threads <= 1 || n <= 1 {
+        return (0..n).map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new((0..n).map(|_| None).collect::<Vec<Option<Result<T>>>>());
+    std::thread::scope(|scope| {
+        for _ in 0..threads.min(n) {
+            scope.spawn(|| {
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= n {
+                        break;
+                    }
+                    let result = f(i);
+                    results.lock().expect("poisoned")[i] = Some(result);
+                }
+            });
+        }
+    });
+    results
+        .into_inner()
+        .expect("poisoned")
+        .into_iter()
+        .map(|result| result.expect("every index taken"))
+        .collect()
+}
+
+/// Score reported by [`SlidingNcd::push`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlidingMetric {
+    /// Conditional cost of the chunk given the window, in bits per input
+    /// byte: `8 * (C(window ‖ chunk) − C(window)) / chunk.len()`.  Near 0 for
+    /// fully predictable data, around 8 for incompressible data.
+    #[default]
+    BitsPerByte,
+    /// Normalized compression distance: `(C(window ‖ chunk) −
+    /// min(C(window), C(chunk))) / max(C(window), C(chunk))`.  Near 0 for
+    /// identical content, near 1 for unrelated content.  Note that it drifts
+    /// towards 1 when the window is much larger than the chunk.
+    Ncd,
+}
+
+/// Scores how surprising each new chunk is relative to a trailing window of
+/// previously pushed data.
+///
+/// `C` is [`compress_size`].  `C(window)` is cached between pushes and only
+/// recomputed after an eviction, so a push normally costs one compression of
+/// `window ‖ chunk` (plus one of `chunk` for [`SlidingMetric::Ncd`]).
+///
+/// Eviction works on whole chunks: after a push, the oldest chunks are dropped
+/// until the window holds at most `window_size` bytes (the newest chunk is
+/// always kept).
+///
+/// # Example
+///
+/// ```rust
+/// let mut monitor = zpaq_rs::SlidingNcd::new(64 * 1024, "2");
+/// monitor.push(b"GET /index.html 200\n").unwrap();
+/// let score = monitor.push(b"GET /index.html 200\n").unwrap();
+/// assert!(score < 8.0);
+/// ```
+#[derive(Debug, Clone)]
+pub struct SlidingNcd {
+    method: String,
+    metric: SlidingMetric,
+    window_size: usize,
+    window: VecDeque<u8>,
+    chunk_lens: VecDeque<usize>,
+    window_cost: Option<u64>,
+}
+
+impl SlidingNcd {
+    /// Creates an empty window holding at most `window_size` bytes, scored
+    /// with [`SlidingMetric::BitsPerByte`] under the given ZPAQ `method`.
+    pub fn new(window_size: usize, method: &str) -> Self {
+        Self::with_metric(window_size, method, SlidingMetric::default())
+    }
+
+    /// Like [`new`](Self::new) with an explicit [`SlidingMetric`].
+    pub fn with_metric(window_size: usize, method: &str, metric: SlidingMetric) -> Self {
+        Self {
+            method: method.to_string(),
+            metric,
+            window_size,
+            window: VecDeque::new(),
+            chunk_lens: VecDeque::new(),
+            window_cost: Some(0),
+        }
+    }
+
+    /// Scores `chunk` against the current window, then appends it and evicts
+    /// old chunks.
+    ///
+    /// An empty `chunk` scores `0.0` and leaves the window unchanged.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<f64> {
+        if chunk.is_empty() {
+            return Ok(0.0);
+        }
+        self.window.make_contiguous();
+        let (window, _) = self.window.as_slices();
+        let window_cost = match self.window_cost {
+            Some(cost) => cost,
+            None => compress_size(window, &self.method)?,
+        };
+        let joint_cost = compress_size_stream(window.chain(chunk), &self.method, None, None)?;
+
+        let score = match self.metric {
+            SlidingMetric::BitsPerByte => {
+                8.0 * joint_cost.saturating_sub(window_cost) as f64 / chunk.len() as f64
+            }
+            SlidingMetric::Ncd => {
+                let chunk_cost = compress_size(chunk, &self.method)?;
+                if self.window.is_empty() {
+                    1.0
+                } else {
+                    normalized(joint_cost, window_cost, chunk_cost)
+                }
+            }
+        };
+
+        self.window.extend(chunk);
+        self.chunk_lens.push_back(chunk.len());
+        self.window_cost = Some(joint_cost);
+        while self.window.len() > self.window_size && self.chunk_lens.len() > 1 {
+            let evicted = self.chunk_lens.pop_front().unwrap_or(0);
+            self.window.drain(..evicted);
+            self.window_cost = None;
+        }
+        Ok(score)
+    }
+
+    /// Current number of bytes held in the window.
+    pub fn window_len(&self) -> usize {
+        self.window.len()
+    }
+}
+
+/// Bytes [`compress_size_bounded`] hands over per read, and so how often
+/// it checks the clock.
+const BOUNDED_CHUNK: usize = 64 << 10;
+
+/// Result of [`compress_size_bounded`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoundedSize {
+    /// The compressed size if `exact`, or else the size of what was
+    /// compressed scaled up to the whole input.
+    pub bytes: u64,
+    /// Whether all of the input was compressed in time, so that `bytes` is
+    /// what [`compress_size`] returns.
+    pub exact: bool,
+    /// Bytes of input compressed.
+    pub input_consumed: u64,
+}
+
+/// [`compress_size`] within a time budget: compresses `input` until
+/// `budget` has passed, and if it has not finished by then, extrapolates
+/// the size linearly from the ratio achieved so far.
+///
+/// The deadline is checked as input is read, between blocks and within
+/// them every 64 KiB, so the call can overrun it by the time it takes to
+/// compress one block.  Methods with smaller blocks (the second digit of
+/// a numeric method) stop sooner.  Some input is always compressed, so
+/// the estimate has a ratio to go on.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Duration;
+///
+/// let text = b"approximately the same ".repeat(1000);
+/// let size = zpaq_rs::compress_size_bounded(&text, "1", Duration::from_secs(60))?;
+/// assert!(size.exact);
+/// assert_eq!(size.bytes, zpaq_rs::compress_size(&text, "1")?);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn compress_size_bounded(input: &[u8], method: &str, budget: Duration) -> Result<BoundedSize> {
+    let mut reader = DeadlineReader {
+        rest: input,
+        deadline: Instant::now().checked_add(budget),
+        started: false,
+        expired: false,
+    };
+    let compressed = compress_size_stream(&mut reader, method, None, None)?;
+    let consumed = (input.len() - reader.rest.len()) as u64;
+    let bytes = match reader.expired {
+        false => compressed,
+        true => (compressed as f64 * input.len() as f64 / consumed as f64).round() as u64,
+    };
+    Ok(BoundedSize {
+        bytes,
+        exact: !reader.expired,
+        input_consumed: consumed,
+    })
+}
+
+/// Reads a slice until a deadline, then reports the end of the input.
+struct DeadlineReader<'a> {
+    rest: &'a [u8],
+    /// `None` if the budget reaches beyond what an [`Instant`] can hold.
+    deadline: Option<Instant>,
+    started: bool,
+    expired: bool,
+}
+
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.rest.is_empty() || self.expired {
+            return Ok(0);
+        }
+        if self.started
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            self.expired = true;
+            return Ok(0);
+        }
+        self.started = true;
+        let n = buf.len().min(self.rest.len()).min(BOUNDED_CHUNK);
+        buf[..n].copy_from_slice(&self.rest[..n]);
+        self.rest = &self.rest[n..];
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_bytes;
+
+    const ENGLISH: &[u8] = b"It was the best of times, it was the worst of times, it was the age \
+        of wisdom, it was the age of foolishness, it was the epoch of belief, it was the epoch \
+        of incredulity, it was the season of Light, it was the season of Darkness, it was the \
+        spring of hope, it was the winter of despair, we had everything before us, we had \
+        nothing before us, we were all going direct to Heaven, we were all going direct the \
+        other way.";
+
+    const SAMPLE: &[u8] = b"it was the season of hope and the age of belief";
+
+    #[test]
+    fn bounded_sizes_are_exact_in_time_and_estimated_after() {
+        let text: Vec<u8> = ENGLISH.iter().copied().cycle().take(200_000).collect();
+        let size = compress_size_bounded(&text, "2", Duration::from_secs(600)).expect("size");
+        assert!(size.exact);
+        assert_eq!(size.input_consumed, text.len() as u64);
+        assert_eq!(size.bytes, compress_size(&text, "2").expect("size"));
+
+        // Method 30 uses 1 MiB blocks: the first takes far longer than 1 ms.
+        let noise = random_bytes(8 << 20).expect("random_bytes");
+        let size = compress_size_bounded(&noise, "30", Duration::from_millis(1)).expect("size");
+        assert!(!size.exact);
+        assert!(size.input_consumed > 0 && size.input_consumed < noise.len() as u64);
+        // Noise does not compress, so the estimate is about the input size.
+        let ratio = size.bytes as f64 / noise.len() as f64;
+        assert!((0.99..1.05).contains(&ratio), "{ratio}");
+    }
+
+    #[test]
+    fn same_language_training_scores_lower() {
+        let random = random_bytes(ENGLISH.len()).expect("random_bytes");
+        let text = cross_bits(ENGLISH, SAMPLE, "2").expect("text");
+        let noise = cross_bits(&random, SAMPLE, "2").expect("noise");
+        assert!(text < noise, "text={text} noise={noise}");
+    }
+
+    #[test]
+    fn cross_bits_is_the_compressed_growth() {
+        assert_eq!(cross_bits(ENGLISH, b"", "2").expect("empty"), 0.0);
+
+        // Adding `test` after `train` grows the compressed stream by the
+        // bits scored, give or take the coder's final flush.
+        let joined = [ENGLISH, SAMPLE].concat();
+        let growth = modeled_size(&joined, "2").expect("joined") as f64
+            - modeled_size(ENGLISH, "2").expect("train") as f64;
+        let bits = cross_bits(ENGLISH, SAMPLE, "2").expect("bits");
+        assert!(
+            (bits - 8.0 * growth).abs() <= 32.0,
+            "bits={bits} growth={growth}"
+        );
+    }
+
+    #[test]
+    fn classify_picks_matching_language() {
+        let random = random_bytes(ENGLISH.len()).expect("random_bytes");
+        let classes = [("random", random.as_slice()), ("english", ENGLISH)];
+        assert_eq!(
+            classify(SAMPLE, &classes, "2").expect("classify"),
+            "english"
+        );
+    }
+
+    fn log_chunk(i: usize) -> Vec<u8> {
+        (0..40)
+            .flat_map(|j| {
+                format!(
+                    "INFO request id={} path=/api/items status=200\n",
+                    i * 40 + j
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sliding_scores_trend_down_then_spike() {
+        let mut monitor = SlidingNcd::new(16 * 1024, "2");
+        let scores: Vec<f64> = (0..8)
+            .map(|i| monitor.push(&log_chunk(i)).expect("push"))
+            .collect();
+        assert!(
+            scores[1..].iter().all(|&s| s < scores[0] / 2.0),
+            "scores={scores:?}"
+        );
+
+        let noise = random_bytes(2000).expect("random_bytes");
+        let spike = monitor.push(&noise).expect("push");
+        assert!(
+            spike > 7.0 && spike > scores[7] + 4.0,
+            "spike={spike} scores={scores:?}"
+        );
+        assert!(monitor.window_len() <= 16 * 1024);
+    }
+
+    #[test]
+    fn sliding_ncd_spikes_on_new_distribution() {
+        let mut monitor = SlidingNcd::with_metric(16 * 1024, "2", SlidingMetric::Ncd);
+        let mut last = 0.0;
+        for i in 0..4 {
+            last = monitor.push(&log_chunk(i)).expect("push");
+        }
+        let noise = random_bytes(2000).expect("random_bytes");
+        let spike = monitor.push(&noise).expect("push");
+        assert!(spike > last, "spike={spike} last={last}");
+    }
+
+    #[test]
+    fn entropy_profile_ends_at_bits_per_byte() {
+        let data = ENGLISH.repeat(20);
+        let bpb = bits_per_byte(&data, "2").expect("bits_per_byte");
+        let profile = entropy_profile(&data, "2", 10).expect("entropy_profile");
+        assert_eq!(profile.len(), 10);
+        assert!(profile.windows(2).all(|w| w[0].0 < w[1].0));
+        let (last_len, last_bpb) = profile[profile.len() - 1];
+        assert_eq!(last_len, data.len());
+        assert!(
+            (last_bpb - bpb).abs() < 0.05,
+            "profile={last_bpb} bpb={bpb}"
+        );
+        // The model adapts: later prefixes are cheaper per byte.
+        assert!(last_bpb < profile[0].1);
+    }
+
+    #[test]
+    fn entropy_profile_handles_short_input() {
+        let profile = entropy_profile(b"abc", "1", 10).expect("entropy_profile");
+        let lens: Vec<usize> = profile.iter().map(|&(len, _)| len).collect();
+        assert_eq!(lens, vec![1, 2, 3]);
+        assert!(entropy_profile(b"", "1", 10).expect("empty").is_empty());
+        assert_eq!(bits_per_byte(b"", "1").expect("empty"), 0.0);
+    }
+
+    fn corpus() -> Vec<Vec<u8>> {
+        vec![
+            ENGLISH.repeat(3),
+            random_bytes(600).expect("random"),
+            random_bytes(400)
+                .expect("random")
+                .iter()
+                .flat_map(|b| format!("{b:02x}").into_bytes())
+                .collect(),
+        ]
+    }
+
+    #[test]
+    fn reference_queries_match_pairwise_ncd() {
+        let corpus = corpus();
+        let set = ReferenceSet::new(&corpus, "2").expect("set");
+        assert_eq!(set.len(), corpus.len());
+        let distances = set.query(SAMPLE).expect("query");
+        let serial = set.clone().threads(1).query(SAMPLE).expect("query");
+        assert_eq!(distances, serial);
+        for (reference, distance) in corpus.iter().zip(&distances) {
+            assert_eq!(*distance, ncd(SAMPLE, reference, "2").expect("ncd"));
+        }
+
+        let nearest = set.nearest(SAMPLE, 2).expect("nearest");
+        assert_eq!(nearest.len(), 2);
+        assert_eq!(nearest[0].0, 0);
+        assert!(nearest[0].1 <= nearest[1].1);
+        assert_eq!(
+            set.nearest(SAMPLE, 10).expect("nearest").len(),
+            corpus.len()
+        );
+        assert!(ncd(ENGLISH, ENGLISH, "2").expect("ncd") < distances[1]);
+    }
+
+    #[test]
+    fn empty_reference_sets_return_no_distances() {
+        let set = ReferenceSet::new(Vec::<Vec<u8>>::new(), "2").expect("set");
+        assert!(set.is_empty());
+        assert!(set.query(SAMPLE).expect("query").is_empty());
+        assert!(set.nearest(SAMPLE, 3).expect("nearest").is_empty());
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn reference_sizes_are_not_recomputed_per_query() {
+        use crate::StatsCollector;
+
+        let corpus = corpus();
+        let set = ReferenceSet::new(&corpus, "2").expect("set").threads(1);
+        let joint: usize = corpus.iter().map(|r| SAMPLE.len() + r.len()).sum();
+        for _ in 0..2 {
+            let (distances, stats) = StatsCollector::new().collect(|| set.query(SAMPLE));
+            assert_eq!(distances.expect("query").len(), corpus.len());
+            assert_eq!(stats.bytes_read, (SAMPLE.len() + joint) as u64, "{stats:?}");
+        }
+    }
+
+    #[test]
+    fn classify_rejects_empty_classes() {
+        let classes: [(&str, &[u8]); 0] = [];
+        assert!(classify(SAMPLE, &classes, "2").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+//! Counters of the traffic through the FFI callbacks (the `stats` feature).
+
+use std::cell::Cell;
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::Result;
+
+/// What crossed the FFI boundary during [`StatsCollector::collect`].
+///
+/// Only calls made on the collecting thread are counted, which covers every
+/// callback of the streaming functions: parallel compression reads its
+/// input and writes its output from the calling thread too.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OperationStats {
+    /// Calls from the shim to a Rust reader.
+    pub read_calls: u64,
+    /// Calls from the shim to a Rust writer.
+    pub write_calls: u64,
+    /// Bytes the shim read from Rust readers.
+    pub bytes_read: u64,
+    /// Bytes the shim wrote to Rust writers.
+    pub bytes_written: u64,
+    /// Blocks the shim compressed, on any thread.
+    pub blocks: u64,
+    /// Segments of an archive decoded to verify or index it, as
+    /// [`verify_archive`](crate::verify_archive) and
+    /// [`ArchiveReader::open`](crate::ArchiveReader::open) do.
+    pub segments_decoded: u64,
+    /// Wall time of the collection.
+    pub elapsed: Duration,
+    /// The part of [`elapsed`](Self::elapsed) spent in Rust callbacks.
+    pub callback_time: Duration,
+    /// Worker threads whose priority or name could not be set as the
+    /// [`ParallelOptions`](crate::ParallelOptions) in force asked.
+    pub thread_setup_failures: u64,
+}
+
+impl OperationStats {
+    /// The part of [`elapsed`](Self::elapsed) spent outside Rust callbacks,
+    /// mostly in the shim and `libzpaq`.
+    pub fn native_time(&self) -> Duration {
+        self.elapsed.saturating_sub(self.callback_time)
+    }
+
+    fn add(&mut self, other: &OperationStats) {
+        self.read_calls += other.read_calls;
+        self.write_calls += other.write_calls;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.blocks += other.blocks;
+        self.segments_decoded += other.segments_decoded;
+        self.callback_time += other.callback_time;
+        self.thread_setup_failures += other.thread_setup_failures;
+    }
+}
+
+thread_local! {
+    /// The counters of the innermost collection on this thread, if any.
+    static ACTIVE: Cell<Option<OperationStats>> = const { Cell::new(None) };
+    /// Whether writers made during the collection call back for each byte.
+    static UNBUFFERED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Collects [`OperationStats`] for the library calls made in a closure.
+///
+/// During a collection each callback costs two clock reads and a
+/// thread-local update more; outside one, a thread-local read.
+///
+/// # Example
+///
+/// ```rust
+/// use zpaq_rs::{StatsCollector, compress_to_vec};
+///
+/// let (compressed, stats) = StatsCollector::new().collect(|| compress_to_vec(b"hello", "1"));
+/// assert_eq!(stats.bytes_written, compressed?.len() as u64);
+/// assert_eq!(stats.blocks, 1);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct StatsCollector {
+    buffered: bool,
+}
+
+impl Default for StatsCollector {
+    fn default() -> Self {
+        Self { buffered: true }
+    }
+}
+
+impl StatsCollector {
+    /// A collector leaving write buffering on.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether the shim batches the bytes `libzpaq` writes one at a time
+    /// into one callback (the default).  Turning it off, for writers made
+    /// during the collection, shows what the batching saves.
+    pub fn buffered(mut self, buffered: bool) -> Self {
+        self.buffered = buffered;
+        self
+    }
+
+    /// Runs `f`, counting the callbacks it causes on this thread.
+    /// Collections nest: an enclosing one counts the inner one's calls too.
+    pub fn collect<T>(&self, f: impl FnOnce() -> T) -> (T, OperationStats) {
+        crate::install_hooks();
+        let scope = Scope {
+            outer: ACTIVE.replace(Some(OperationStats::default())),
+            outer_unbuffered: UNBUFFERED.replace(!self.buffered),
+            start: Instant::now(),
+        };
+        let value = f();
+        (value, scope.finish())
+    }
+}
+
+/// A collection in progress; restores the enclosing one when dropped, so a
+/// panic in the closure leaves the thread as it was.
+struct Scope {
+    outer: Option<OperationStats>,
+    outer_unbuffered: bool,
+    start: Instant,
+}
+
+impl Scope {
+    fn finish(self) -> OperationStats {
+        let mut stats = ACTIVE.get().unwrap_or_default();
+        stats.elapsed = self.start.elapsed();
+        stats
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        let inner = ACTIVE.get().unwrap_or_default();
+        let outer = self.outer.map(|mut outer| {
+            outer.add(&inner);
+            outer
+        });
+        ACTIVE.set(outer);
+        UNBUFFERED.set(self.outer_unbuffered);
+    }
+}
+
+fn update(f: impl FnOnce(&mut OperationStats)) {
+    ACTIVE.with(|cell| {
+        if let Some(mut stats) = cell.get() {
+            f(&mut stats);
+            cell.set(Some(stats));
+        }
+    });
+}
+
+/// A callback in progress, timed if a collection is.
+pub(crate) struct Callback(Option<Instant>);
+
+impl Callback {
+    pub(crate) fn start() -> Self {
+        Callback(ACTIVE.get().map(|_| Instant::now()))
+    }
+
+    /// A read callback that returned `bytes`.
+    pub(crate) fn read(self, bytes: usize) {
+        self.finish(|stats| {
+            stats.read_calls += 1;
+            stats.bytes_read += bytes as u64;
+        });
+    }
+
+    /// A write callback given `bytes`.
+    pub(crate) fn wrote(self, bytes: usize) {
+        self.finish(|stats| {
+            stats.write_calls += 1;
+            stats.bytes_written += bytes as u64;
+        });
+    }
+
+    fn finish(self, f: impl FnOnce(&mut OperationStats)) {
+        if let Some(start) = self.0 {
+            update(|stats| {
+                f(stats);
+                stats.callback_time += start.elapsed();
+            });
+        }
+    }
+}
+
+/// A block compressed.
+pub(crate) fn block() {
+    update(|stats| stats.blocks += 1);
+}
+
+/// A segment decoded by an archive scan.
+pub(crate) fn segment_decoded() {
+    update(|stats| stats.segments_decoded += 1);
+}
+
+/// Worker threads that could not be set up.
+pub(crate) fn worker_setup_failed(failures: u64) {
+    update(|stats| stats.thread_setup_failures += failures);
+}
+
+/// Sets up a writer made during a collection.
+pub(crate) fn configure(writer: *mut crate::sys::RustWriter) {
+    if UNBUFFERED.get() {
+        unsafe { crate::sys::zpaq_writer_set_buffered(writer, 0) };
+    }
+}
+
+/// [`compress_stream`](crate::compress_stream), with the [`OperationStats`]
+/// of the call.
+pub fn compress_stream_with_stats<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&str>,
+    comment: Option<&str>,
+) -> Result<OperationStats> {
+    let (result, stats) = StatsCollector::new()
+        .collect(|| crate::compress_stream(reader, writer, method, filename, comment));
+    result.map(|()| stats)
+}
+
+/// [`compress_stream_parallel`](crate::compress_stream_parallel), with the
+/// [`OperationStats`] of the call.
+pub fn compress_stream_parallel_with_stats<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+    method: &str,
+    filename: Option<&str>,
+    comment: Option<&str>,
+    threads: usize,
+) -> Result<OperationStats> {
+    let (result, stats) = StatsCollector::new().collect(|| {
+        crate::compress_stream_parallel(reader, writer, method, filename, comment, threads)
+    });
+    result.map(|()| stats)
+}
+
+/// [`decompress_stream`](crate::decompress_stream), with the
+/// [`OperationStats`] of the call.
+pub fn decompress_stream_with_stats<R: Read + Send, W: Write + Send>(
+    reader: R,
+    writer: W,
+) -> Result<OperationStats> {
+    let (result, stats) =
+        StatsCollector::new().collect(|| crate::decompress_stream(reader, writer));
+    result.map(|()| stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::StreamingCompressor;
+
+    /// Random letters, which compress to about half.
+    fn sample() -> Vec<u8> {
+        crate::test_support::letters(1, 100_000)
+    }
+
+    #[test]
+    fn buffering_cuts_the_callback_count() {
+        // The streaming compressor's encoder writes a byte at a time.
+        let data = sample();
+        let compress = |buffered: bool| {
+            let (result, stats) = StatsCollector::new().buffered(buffered).collect(|| {
+                let mut compressor = StreamingCompressor::new("2")?;
+                for &b in &data {
+                    compressor.push(b)?;
+                }
+                Ok::<_, crate::ZpaqError>(compressor.bits())
+            });
+            (result.expect("compress"), stats)
+        };
+        let (bits, buffered) = compress(true);
+        let (unbuffered_bits, unbuffered) = compress(false);
+        assert_eq!(bits, unbuffered_bits);
+
+        for stats in [&buffered, &unbuffered] {
+            assert_eq!(stats.read_calls, data.len() as u64);
+            assert_eq!(stats.bytes_read, data.len() as u64);
+            assert!(stats.callback_time <= stats.elapsed);
+        }
+        assert_eq!(buffered.bytes_written, unbuffered.bytes_written);
+        assert!(
+            buffered.write_calls * 1000 < unbuffered.write_calls,
+            "{buffered:?} vs {unbuffered:?}"
+        );
+    }
+
+    #[test]
+    fn stats_variants_count_their_call_only() {
+        let data = sample();
+        let mut compressed = Vec::new();
+        let (result, outer) = StatsCollector::new().collect(|| {
+            compress_stream_parallel_with_stats(&data[..], &mut compressed, "10", None, None, 2)
+        });
+        let inner = result.expect("compress");
+        assert_eq!(inner.blocks, 1);
+        assert_eq!(inner.bytes_written, compressed.len() as u64);
+        assert_eq!(outer.write_calls, inner.write_calls);
+
+        let mut restored = Vec::new();
+        let stats =
+            decompress_stream_with_stats(&compressed[..], &mut restored).expect("decompress");
+        assert_eq!(restored, data);
+        assert_eq!(stats.bytes_written, data.len() as u64);
+        assert_eq!(stats.blocks, 0);
+
+        let (_, idle) = StatsCollector::new().collect(|| ());
+        assert_eq!(idle.read_calls + idle.write_calls + idle.blocks, 0);
+    }
+
+    #[test]
+    fn listing_skips_segment_data_inside_the_shim() {
+        // 100 MB in seven store blocks.
+        let data = vec![b'z'; 100_000_000];
+        let compressed = crate::compress_to_vec(&data, "0").expect("compress");
+
+        let (blocks, listing) = StatsCollector::new().collect(|| crate::list_blocks(&compressed));
+        let total: u64 = blocks
+            .expect("list_blocks")
+            .iter()
+            .flat_map(|b| &b.segments)
+            .map(|s| s.size)
+            .sum();
+        assert_eq!(total, data.len() as u64);
+
+        let (_, decompression) = StatsCollector::new().collect(|| {
+            crate::decompress_stream(&compressed[..], std::io::sink()).expect("decompress")
+        });
+        assert_eq!(decompression.bytes_written, data.len() as u64);
+        // Only filenames and comments reach Rust writers.
+        assert!(listing.bytes_written < 1000, "{listing:?}");
+        assert!(
+            listing.write_calls * 10 < decompression.write_calls,
+            "{listing:?} vs {decompression:?}"
+        );
+    }
+}
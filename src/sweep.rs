@@ -0,0 +1,284 @@
+//! Verifying every archive in a directory at once.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::similarity::each;
+use crate::{
+    ArchiveSpec, Result, SecretString, VerifyError, VerifyErrorKind, VerifyLocation, VerifyOptions,
+    VerifyReport, ZpaqError, verify_archive_file,
+};
+
+/// Gives the password of an archive, given its path; see
+/// [`SweepOptions::key_provider`].
+pub type KeyProvider = Arc<dyn Fn(&Path) -> Option<SecretString> + Send + Sync>;
+
+/// Receives a [`SweepProgress`] per archive; see [`SweepOptions::progress`].
+pub type SweepCallback = Arc<dyn Fn(&SweepProgress<'_>) + Send + Sync>;
+
+/// Options for [`sweep_archives`].
+#[derive(Clone, Default)]
+pub struct SweepOptions {
+    /// Verify in deep mode (see [`VerifyOptions::deep`]).
+    pub deep: bool,
+    /// Archives verified at once; 0 for one per processor.
+    pub threads: usize,
+    /// The password of each archive, given its path (the pattern, for a
+    /// multi-part set).  Archives it returns `None` for, and all of them
+    /// without a provider, are verified without a key.
+    pub key_provider: Option<KeyProvider>,
+    /// Called as the verification of each archive finishes, on the thread
+    /// that verified it.
+    pub progress: Option<SweepCallback>,
+}
+
+impl std::fmt::Debug for SweepOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SweepOptions")
+            .field("deep", &self.deep)
+            .field("threads", &self.threads)
+            .field("key_provider", &self.key_provider.is_some())
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+/// One report to [`SweepOptions::progress`].
+#[derive(Debug, Clone, Copy)]
+pub struct SweepProgress<'a> {
+    /// The archive just verified.
+    pub archive: &'a Path,
+    /// What its verification found.
+    pub report: &'a VerifyReport,
+    /// Archives verified so far, this one included.
+    pub done: usize,
+    /// Archives the sweep found.
+    pub total: usize,
+}
+
+/// Verifies every archive in `dir` and returns the report of each, by
+/// path.
+///
+/// Files named `*.zpaq` directly in `dir` are archives, except that those
+/// ending in a number which, with the same name and number width, include
+/// part 1 form a multi-part set: `backup01.zpaq` and `backup02.zpaq` are
+/// parts of `backup??.zpaq`, reported under that pattern (see
+/// [`ArchiveSpec`]), and a `backup00.zpaq` with them is its index, not
+/// verified on its own.
+///
+/// Archives are verified [`SweepOptions::threads`] at a time.  One that
+/// cannot be verified, because it cannot be read or its key is wrong,
+/// does not stop the others: its report holds a single
+/// [`VerifyErrorKind::Unreadable`] error.
+///
+/// # Errors
+///
+/// Fails only if `dir` cannot be listed.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::SweepOptions;
+///
+/// let options = SweepOptions { deep: true, threads: 4, ..Default::default() };
+/// for (archive, report) in zpaq_rs::sweep_archives("backups".as_ref(), &options)? {
+///     if !report.is_ok() {
+///         println!("{}:\n{report}", archive.display());
+///     }
+/// }
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn sweep_archives(dir: &Path, options: &SweepOptions) -> Result<Vec<(PathBuf, VerifyReport)>> {
+    let archives = discover(dir)?;
+    let threads = match options.threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        threads => threads,
+    };
+    let done = AtomicUsize::new(0);
+    let reports = each(archives.len(), threads, |i| {
+        let archive = &archives[i];
+        let report = verify(archive, options).unwrap_or_else(|e| VerifyReport {
+            errors: vec![VerifyError {
+                location: VerifyLocation::default(),
+                kind: VerifyErrorKind::Unreadable(e.to_string()),
+            }],
+            ..VerifyReport::default()
+        });
+        if let Some(progress) = &options.progress {
+            progress(&SweepProgress {
+                archive,
+                report: &report,
+                done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                total: archives.len(),
+            });
+        }
+        Ok(report)
+    })?;
+    Ok(archives.into_iter().zip(reports).collect())
+}
+
+fn verify(archive: &Path, options: &SweepOptions) -> Result<VerifyReport> {
+    let name = archive
+        .to_str()
+        .ok_or_else(|| ZpaqError::Ffi(format!("{}: path is not UTF-8", archive.display())))?;
+    let options = VerifyOptions {
+        deep: options.deep,
+        key: options.key_provider.as_ref().and_then(|key| key(archive)),
+        ..VerifyOptions::default()
+    };
+    verify_archive_file(name, &options)
+}
+
+/// The archives in `dir`, multi-part sets as their patterns, sorted.
+fn discover(dir: &Path) -> Result<Vec<PathBuf>> {
+    let io = |e: std::io::Error| ZpaqError::Io {
+        path: dir.to_path_buf(),
+        kind: e.kind(),
+        message: e.to_string(),
+    };
+    let mut archives = Vec::new();
+    // Files ending in a number, by the name around it and its width.
+    let mut numbered: BTreeMap<(String, usize, String), BTreeMap<u64, PathBuf>> = BTreeMap::new();
+    for entry in std::fs::read_dir(dir).map_err(io)? {
+        let entry = entry.map_err(io)?;
+        let path = entry.path();
+        if !path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("zpaq"))
+            || !entry.file_type().map_err(io)?.is_file()
+        {
+            continue;
+        }
+        let (Some(stem), Some(ext)) = (
+            path.file_stem().and_then(|s| s.to_str()),
+            path.extension().and_then(|s| s.to_str()),
+        ) else {
+            archives.push(path);
+            continue;
+        };
+        let prefix = stem.trim_end_matches(|c: char| c.is_ascii_digit());
+        let digits = &stem[prefix.len()..];
+        match digits.parse::<u64>() {
+            Ok(n) => {
+                let key = (prefix.to_string(), digits.len(), ext.to_string());
+                numbered.entry(key).or_default().insert(n, path);
+            }
+            Err(_) => archives.push(path),
+        }
+    }
+
+    for ((prefix, width, ext), mut files) in numbered {
+        if files.contains_key(&1) {
+            let pattern = dir.join(format!("{prefix}{}.{ext}", "?".repeat(width)));
+            let spec = ArchiveSpec::parse(&pattern.to_string_lossy())?;
+            for n in 0..=spec.parts().len() as u64 {
+                files.remove(&n);
+            }
+            archives.push(pattern);
+        }
+        archives.extend(files.into_values());
+    }
+    archives.sort();
+    Ok(archives)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{zpaq_add, zpaq_command};
+    use std::sync::Mutex;
+
+    #[test]
+    fn sweeps_report_the_one_damaged_archive() {
+        let dir = TempDir::new("zpaq-rs-sweep");
+        let inputs = TempDir::new("zpaq-rs-sweep-inputs");
+        let input = |name: &str, seed: u64| {
+            let path = inputs.join(name);
+            std::fs::write(&path, crate::test_support::letters(seed, 300_000)).expect("write");
+            path.to_string_lossy().into_owned()
+        };
+        let archive = |name: &str| dir.join(name).to_string_lossy().into_owned();
+
+        // An encrypted archive, a multi-part set of two versions with a
+        // local index, and an archive with a flipped bit in its data.
+        zpaq_command(&[
+            "add",
+            &archive("locked.zpaq"),
+            &input("a.txt", 1),
+            "-key",
+            "hunter2",
+        ])
+        .expect("add -key");
+        for seed in [2, 3] {
+            zpaq_command(&[
+                "add",
+                &archive("set??.zpaq"),
+                &input(&format!("{seed}.txt"), seed),
+                "-index",
+                &archive("set00.zpaq"),
+            ])
+            .expect("add part");
+        }
+        zpaq_add(&archive("broken.zpaq"), &[&input("b.txt", 4)], "1", 1).expect("add");
+        // Most of the archive is its one `d` block.
+        let mut broken = std::fs::read(archive("broken.zpaq")).expect("read");
+        let middle = broken.len() / 2;
+        broken[middle] ^= 0x40;
+        std::fs::write(archive("broken.zpaq"), &broken).expect("write");
+        std::fs::write(dir.join("notes.txt"), "not an archive").expect("write");
+
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        let options = SweepOptions {
+            deep: true,
+            threads: 2,
+            key_provider: Some(Arc::new(|path: &Path| {
+                path.ends_with("locked.zpaq").then(|| "hunter2".into())
+            })),
+            progress: Some(Arc::new(move |p: &SweepProgress<'_>| {
+                log.lock()
+                    .unwrap()
+                    .push((p.archive.to_path_buf(), p.done, p.total));
+            })),
+        };
+        let reports = sweep_archives(&dir, &options).expect("sweep");
+        let names: Vec<_> = reports
+            .iter()
+            .map(|(path, _)| path.file_name().expect("name").to_string_lossy())
+            .collect();
+        assert_eq!(names, ["broken.zpaq", "locked.zpaq", "set??.zpaq"]);
+        let unhealthy: Vec<_> = reports.iter().filter(|(_, r)| !r.is_ok()).collect();
+        assert_eq!(unhealthy.len(), 1, "{reports:?}");
+        assert_eq!(unhealthy[0].0, dir.join("broken.zpaq"));
+        // Both versions of the set were verified.
+        assert_eq!(reports[2].1.blocks_checked, 8);
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort_by_key(|&(_, done, _)| done);
+        let dones: Vec<_> = seen.iter().map(|&(_, done, total)| (done, total)).collect();
+        assert_eq!(dones, [(1, 3), (2, 3), (3, 3)]);
+
+        // With the wrong key, the encrypted archive cannot be verified,
+        // and the sweep goes on.
+        let options = SweepOptions {
+            deep: true,
+            key_provider: Some(Arc::new(|path: &Path| {
+                path.ends_with("locked.zpaq").then(|| "guess".into())
+            })),
+            ..Default::default()
+        };
+        let reports = sweep_archives(&dir, &options).expect("sweep");
+        assert!(matches!(
+            &reports[1].1.errors[..],
+            [VerifyError {
+                kind: VerifyErrorKind::Unreadable(_),
+                ..
+            }]
+        ));
+        assert!(reports[2].1.is_ok());
+        assert!(sweep_archives(&dir.join("missing"), &options).is_err());
+    }
+}
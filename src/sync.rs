@@ -0,0 +1,169 @@
+//! Keeping a copy of a journaling archive up to date by appending.
+//!
+//! `zpaq add` only appends to an archive, so a copy made earlier is a
+//! prefix of it, and the versions added since are byte ranges past the end
+//! of the copy.  An [`ArchiveSyncState`] records what a copy holds, so that
+//! [`archive_delta_ranges`] can check the prefix is still there and list
+//! what to append.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+
+use crate::parts::open_archive;
+use crate::{Result, ZpaqError, archive_layout, io_err, sha1};
+
+/// Bytes at the end of the synced prefix that [`ArchiveSyncState`] hashes.
+const TAIL: u64 = 64 << 10;
+
+/// What a copy of a journaling archive holds, as returned by
+/// [`archive_delta_ranges`] once the ranges it listed were copied.
+///
+/// The default state is that of an empty copy.  With the `serde` feature
+/// the state serializes as an object with the three fields below, the
+/// digest as a hex string, so it can be saved between syncs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct ArchiveSyncState {
+    /// Bytes of the archive the copy holds.
+    pub len: u64,
+    /// SHA-1 of the last 64 KiB of those bytes, or of all of them if fewer.
+    pub tail_sha1: [u8; 20],
+    /// Versions in those bytes.
+    pub version: u32,
+}
+
+/// Lists the byte ranges the journaling archive at `archive` gained since
+/// a copy of it was in state `previous`, one per version added, with the
+/// state of the copy once they are appended to it.
+///
+/// The ranges come from [`archive_layout`] and follow one another from
+/// `previous.len`.  They end with the last complete version, so a
+/// transaction still being written is left for the next sync.
+///
+/// # Errors
+///
+/// Returns [`ZpaqError::FullResyncRequired`] if the archive no longer
+/// starts with the bytes the copy holds: it is shorter, has fewer versions
+/// there, or the end of those bytes has a different SHA-1.  Only the last
+/// 64 KiB are hashed, which catches an archive repacked or replaced, since
+/// that rewrites the version ending there, but not every change further
+/// back.  Returns [`ZpaqError::StreamingArchive`] for a streaming archive.
+///
+/// # Example
+///
+/// ```rust,no_run
+/// use zpaq_rs::ArchiveSyncState;
+///
+/// let state = ArchiveSyncState::default();
+/// let (ranges, state) = zpaq_rs::archive_delta_ranges("backup.zpaq", &state)?;
+/// // Upload `ranges`, keep `state`, and after the next add:
+/// let (ranges, state) = zpaq_rs::archive_delta_ranges("backup.zpaq", &state)?;
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+pub fn archive_delta_ranges(
+    archive: &str,
+    previous: &ArchiveSyncState,
+) -> Result<(Vec<Range<u64>>, ArchiveSyncState)> {
+    let layout = archive_layout(archive)?;
+    let len = layout.last().map_or(0, |v| v.byte_range.end);
+    if previous.len > len {
+        return Err(ZpaqError::FullResyncRequired(format!(
+            "{archive} holds {len} bytes of complete versions, the copy {}",
+            previous.len
+        )));
+    }
+    let versions = layout
+        .iter()
+        .filter(|v| v.byte_range.end <= previous.len)
+        .count() as u32;
+    if versions != previous.version {
+        return Err(ZpaqError::FullResyncRequired(format!(
+            "{archive} has {versions} versions in the first {} bytes, the copy {}",
+            previous.len, previous.version
+        )));
+    }
+    if previous.len > 0 && tail_sha1(archive, previous.len)? != previous.tail_sha1 {
+        return Err(ZpaqError::FullResyncRequired(format!(
+            "{archive} differs from the copy before byte {}",
+            previous.len
+        )));
+    }
+    let ranges = layout
+        .iter()
+        .filter(|v| v.byte_range.end > previous.len)
+        .map(|v| v.byte_range.start.max(previous.len)..v.byte_range.end)
+        .collect();
+    let state = ArchiveSyncState {
+        len,
+        tail_sha1: tail_sha1(archive, len)?,
+        version: layout.len() as u32,
+    };
+    Ok((ranges, state))
+}
+
+/// SHA-1 of the [`TAIL`] bytes of `archive` before `end`.
+fn tail_sha1(archive: &str, end: u64) -> Result<[u8; 20]> {
+    let start = end.saturating_sub(TAIL);
+    let mut reader = open_archive(archive)?;
+    reader.seek(SeekFrom::Start(start)).map_err(io_err)?;
+    let mut tail = vec![0u8; (end - start) as usize];
+    reader.read_exact(&mut tail).map_err(io_err)?;
+    sha1(&tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::test_support::random;
+    use crate::zpaq_add;
+
+    #[test]
+    fn syncs_report_only_what_was_added() {
+        let dir = TempDir::new("zpaq-rs-sync");
+        let archive = dir.join("synced.zpaq").to_string_lossy().into_owned();
+        let add = |name: &str, seed: u64| {
+            let file = dir.join(name);
+            std::fs::write(&file, random(seed, 200_000)).expect("write");
+            zpaq_add(&archive, &[&file.to_string_lossy()], "1", 1).expect("add");
+            std::fs::metadata(&archive).expect("stat").len()
+        };
+
+        let first = add("a.bin", 1);
+        let (ranges, state) =
+            archive_delta_ranges(&archive, &ArchiveSyncState::default()).expect("sync");
+        assert_eq!(ranges, vec![0..first]);
+        assert_eq!((state.len, state.version), (first, 1));
+
+        // The copy, built from the ranges listed.
+        let mut copy = std::fs::read(&archive).expect("read")[..first as usize].to_vec();
+        let second = add("b.bin", 2);
+        let (ranges, next) = archive_delta_ranges(&archive, &state).expect("sync");
+        assert_eq!(ranges, vec![first..second]);
+        assert_eq!((next.len, next.version), (second, 2));
+        let bytes = std::fs::read(&archive).expect("read");
+        for range in &ranges {
+            copy.extend_from_slice(&bytes[range.start as usize..range.end as usize]);
+        }
+        assert_eq!(copy, bytes);
+
+        let (ranges, again) = archive_delta_ranges(&archive, &next).expect("sync");
+        assert!(ranges.is_empty());
+        assert_eq!(again, next);
+
+        // Replacing the archive with one of the same versions is caught.
+        std::fs::remove_file(&archive).expect("remove");
+        add("c.bin", 3);
+        add("d.bin", 4);
+        let refused = archive_delta_ranges(&archive, &next);
+        assert!(
+            matches!(refused, Err(ZpaqError::FullResyncRequired(_))),
+            "{refused:?}"
+        );
+        std::fs::write(&archive, &bytes[..first as usize]).expect("truncate");
+        let refused = archive_delta_ranges(&archive, &next);
+        assert!(
+            matches!(refused, Err(ZpaqError::FullResyncRequired(_))),
+            "{refused:?}"
+        );
+    }
+}
@@ -47,6 +47,8 @@ pub struct AES_CTR {
 }
 
 pub const RUST_CALLBACK_ERROR: c_int = -2;
+/// `zpaq_jidac_run` result when a file was in use; the last error is its path.
+pub const ARCHIVE_BUSY: c_int = -3;
 
 pub type GetFn = Option<unsafe extern "C" fn(ctx: *mut c_void) -> c_int>;
 pub type ReadFn =
@@ -54,6 +56,11 @@ pub type ReadFn =
 pub type PutFn = Option<unsafe extern "C" fn(ctx: *mut c_void, c: c_int) -> c_int>;
 pub type WriteFn =
     Option<unsafe extern "C" fn(ctx: *mut c_void, buf: *const c_char, n: c_int) -> c_int>;
+/// Called with the uncompressed and compressed size of each block compressed.
+pub type BlockFn = Option<unsafe extern "C" fn(uncompressed: u64, compressed: u64)>;
+pub type WorkerFailureFn = Option<unsafe extern "C" fn(failures: u64)>;
+pub type ReadThrottleFn = Option<unsafe extern "C" fn(ctx: *mut c_void, bytes: u64)>;
+pub type WriteHookFn = Option<unsafe extern "C" fn(ctx: *mut c_void, bytes: u64)>;
 
 #[link(name = "zpaq_rs_ffi", kind = "static")]
 unsafe extern "C" {
@@ -63,6 +70,9 @@ unsafe extern "C" {
     pub fn zpaq_last_error_len() -> usize;
     pub fn zpaq_last_error_copy(buf: *mut c_char, buf_len: usize) -> usize;
     pub fn zpaq_set_last_error(msg: *const c_char);
+    pub fn zpaq_last_error_is_corrupt() -> c_int;
+    pub fn zpaq_set_hardened_parsing(on: c_int);
+    pub fn zpaq_hardened_parsing() -> c_int;
     pub fn zpaq_last_stdout_ptr() -> *const c_char;
     pub fn zpaq_last_stdout_len() -> usize;
     pub fn zpaq_last_stdout_copy(buf: *mut c_char, buf_len: usize) -> usize;
@@ -76,8 +86,24 @@ unsafe extern "C" {
     pub fn zpaq_reader_free(r: *mut RustReader);
     pub fn zpaq_writer_new(ctx: *mut c_void, put_cb: PutFn, write_cb: WriteFn) -> *mut RustWriter;
     pub fn zpaq_writer_free(w: *mut RustWriter);
+    pub fn zpaq_writer_set_buffered(w: *mut RustWriter, buffered: c_int);
+    pub fn zpaq_writer_flush(w: *mut RustWriter) -> c_int;
+    pub fn zpaq_reader_set_chunk(r: *mut RustReader, n: c_int) -> c_int;
+    pub fn zpaq_writer_set_chunk(w: *mut RustWriter, n: c_int) -> c_int;
 
     // Convenience top-level
+    pub fn zpaq_set_block_hook(hook: BlockFn);
+    pub fn zpaq_set_worker_failure_hook(hook: WorkerFailureFn);
+    pub fn zpaq_set_worker_options(priority: c_int, name_prefix: *const c_char);
+    pub fn zpaq_debug_live_objects() -> i64;
+    pub fn zpaq_debug_fail_new(n: c_int);
+    pub fn zpaq_set_temp_dir(dir: *const c_char);
+    pub fn zpaq_temp_space(dir: *const c_char, available: *mut u64) -> c_int;
+    pub fn zpaq_setup_worker_thread(
+        priority: c_int,
+        name_prefix: *const c_char,
+        index: c_int,
+    ) -> c_int;
     pub fn zpaq_compress(
         input: *mut RustReader,
         output: *mut RustWriter,
@@ -86,6 +112,27 @@ unsafe extern "C" {
         comment: *const c_char,
         dosha1: c_int,
     ) -> c_int;
+    pub fn zpaq_compress_recorded(
+        input: *mut RustReader,
+        output: *mut RustWriter,
+        method: *const c_char,
+        filename: *const c_char,
+        comment: *const c_char,
+        dosha1: c_int,
+        store_if_expands: c_int,
+        stored_blocks: *mut u64,
+        extra_hash: c_int,
+        sizes: *mut RustWriter,
+    ) -> c_int;
+    pub fn zpaq_compress_parallel(
+        input: *mut RustReader,
+        output: *mut RustWriter,
+        method: *const c_char,
+        filename: *const c_char,
+        comment: *const c_char,
+        dosha1: c_int,
+        threads: c_int,
+    ) -> c_int;
     pub fn zpaq_decompress(input: *mut RustReader, output: *mut RustWriter) -> c_int;
 
     // Size-only convenience (avoids copying compressed/decompressed bytes into Rust)
@@ -106,16 +153,40 @@ unsafe extern "C" {
         threads: ::std::os::raw::c_int,
         out_size: *mut u64,
     ) -> ::std::os::raw::c_int;
-    pub fn zpaq_decompress_size(input: *mut RustReader, out_size: *mut u64) -> c_int;
-
-    // JIDAC (zpaq.cpp) convenience
-    pub fn zpaq_jidac_add_archive_size_file(
-        path: *const c_char,
+    pub fn zpaq_compress_block_sizes(
+        input: *mut RustReader,
         method: *const c_char,
+        block_size: i64,
         threads: c_int,
-        out_archive_size_bytes: *mut u64,
+        out: *mut RustWriter,
     ) -> c_int;
+    pub fn zpaq_decompress_size(input: *mut RustReader, out_size: *mut u64) -> c_int;
+    pub fn zpaq_decompress_sha1(
+        input: *mut RustReader,
+        output: *mut RustWriter,
+        out_hash20: *mut c_uchar,
+        out_size: *mut u64,
+    ) -> c_int;
+    pub fn zpaq_decompress_digest(
+        input: *mut RustReader,
+        output: *mut RustWriter,
+        hash: c_int,
+        out_hash: *mut c_uchar,
+        out_size: *mut u64,
+        out_checked: *mut u64,
+        out_bad: *mut u64,
+    ) -> c_int;
+
+    // JIDAC (zpaq.cpp)
     pub fn zpaq_jidac_run(argc: c_int, argv: *const *const c_char) -> c_int;
+    #[cfg(windows)]
+    pub fn zpaq_jidac_run_wide(argc: c_int, argv: *const *const u16) -> c_int;
+    pub fn zpaq_jidac_set_fixed_mtime(unix_seconds: i64);
+    pub fn zpaq_jidac_override_mtime(path: *const c_char, unix_seconds: i64);
+    pub fn zpaq_jidac_clear_mtime_overrides();
+    pub fn zpaq_jidac_set_symlink_policy(add: c_int, extract: c_int);
+    pub fn zpaq_jidac_set_read_throttle(ctx: *mut c_void, throttle: ReadThrottleFn);
+    pub fn zpaq_jidac_set_write_hook(ctx: *mut c_void, hook: WriteHookFn);
 
     // StringBuffer
     pub fn zpaq_string_buffer_new(initial: usize) -> *mut StringBuffer;
@@ -147,6 +218,7 @@ unsafe extern "C" {
         len: c_int,
     ) -> c_int;
     pub fn zpaq_compressor_compress(c: *mut Compressor, n: c_int) -> c_int;
+    pub fn zpaq_compressor_prime(c: *mut Compressor, buf: *const c_uchar, n: usize) -> c_int;
     pub fn zpaq_compressor_end_segment(c: *mut Compressor, sha1_or_null: *const c_uchar) -> c_int;
     pub fn zpaq_compressor_end_segment_checksum(
         c: *mut Compressor,
@@ -173,9 +245,26 @@ unsafe extern "C" {
         comment_out: *mut RustWriter,
     ) -> c_int;
     pub fn zpaq_decompresser_set_output(d: *mut Decompresser, out: *mut RustWriter) -> c_int;
+    pub fn zpaq_decompresser_header(d: *mut Decompresser, out: *mut RustWriter) -> c_int;
+    pub fn zpaq_decompresser_hcomp_fingerprint(
+        d: *mut Decompresser,
+        out_len: *mut u64,
+        out_sha256: *mut c_uchar,
+    ) -> c_int;
+    pub fn zpaq_decompresser_set_sha1(d: *mut Decompresser, sha1: *mut SHA1) -> c_int;
     pub fn zpaq_decompresser_decompress(d: *mut Decompresser, n: c_int) -> c_int;
+    pub fn zpaq_decompresser_skip_segment(d: *mut Decompresser, out_size: *mut u64) -> c_int;
+    pub fn zpaq_decompresser_prime(d: *mut Decompresser, buf: *const c_uchar, n: usize) -> c_int;
     pub fn zpaq_decompresser_read_segment_end(d: *mut Decompresser, out_21: *mut c_uchar) -> c_int;
+    pub fn zpaq_decompresser_decompress_bytes(
+        d: *mut Decompresser,
+        buf: *const c_uchar,
+        n: usize,
+        out: *mut RustWriter,
+    ) -> c_int;
     pub fn zpaq_decompresser_buffered(d: *mut Decompresser) -> c_int;
+    pub fn zpaq_decompresser_components(d: *mut Decompresser) -> c_int;
+    pub fn zpaq_decompresser_has_pcomp(d: *mut Decompresser) -> c_int;
 
     // SHA1 / SHA256
     pub fn zpaq_sha1_new() -> *mut SHA1;
@@ -189,6 +278,7 @@ unsafe extern "C" {
     pub fn zpaq_sha256_new() -> *mut SHA256;
     pub fn zpaq_sha256_free(s: *mut SHA256);
     pub fn zpaq_sha256_put(s: *mut SHA256, c: c_int);
+    pub fn zpaq_sha256_write(s: *mut SHA256, buf: *const c_char, n: i64);
     pub fn zpaq_sha256_usize(s: *const SHA256) -> c_ulonglong;
     pub fn zpaq_sha256_size(s: *const SHA256) -> c_double;
     pub fn zpaq_sha256_result(s: *mut SHA256, out_hash32: *mut c_uchar) -> c_int;
@@ -0,0 +1,223 @@
+//! Where the `zpaq` command wrappers put their temporary files: the
+//! captured output of every command, and the targets of links being
+//! stored.  By default the C library's `tmpfile()` places them (in `/tmp`
+//! on most Unix systems); [`set_temp_dir`] and the `temp_dir` of
+//! [`ZpaqAddOptions`](crate::ZpaqAddOptions) and
+//! [`ExtractOptions`](crate::ExtractOptions) choose another directory.
+
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+use crate::{Result, ZpaqError, sys};
+
+/// Free space a temporary directory must have before a command runs in it.
+pub const MIN_TEMP_SPACE: u64 = 1 << 20;
+
+/// The directory of [`set_temp_dir`].
+static DEFAULT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+thread_local! {
+    /// The directory of the innermost [`with_temp_dir`] on this thread.
+    static CURRENT: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Sets the directory for the temporary files of `zpaq` commands run from
+/// now on, in any thread, unless a call chooses its own.  `None` goes back
+/// to the C library's default.
+///
+/// The directory is not created; commands fail with [`ZpaqError::Io`] if
+/// it does not exist, and with [`ZpaqError::InsufficientTempSpace`] if it
+/// has less than [`MIN_TEMP_SPACE`] free.  Unlike setting `TMPDIR`, this
+/// does not change the environment of the process.
+pub fn set_temp_dir(dir: Option<PathBuf>) {
+    *DEFAULT.lock().unwrap_or_else(PoisonError::into_inner) = dir;
+}
+
+/// Runs `f` with the `zpaq` commands it makes on this thread putting their
+/// temporary files in `dir`, or in the [`set_temp_dir`] directory for
+/// `None`.
+pub(crate) fn with_temp_dir<T>(dir: Option<&Path>, f: impl FnOnce() -> T) -> T {
+    let Some(dir) = dir else {
+        return f();
+    };
+    let _scope = Scope {
+        outer: CURRENT.replace(Some(dir.to_path_buf())),
+    };
+    f()
+}
+
+/// A [`with_temp_dir`] in progress; restores the enclosing directory when
+/// dropped.
+struct Scope {
+    outer: Option<PathBuf>,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.set(self.outer.take());
+    }
+}
+
+/// Points the shim at the temporary directory for a command about to run
+/// on this thread, after checking it has room.  The shim goes back to the
+/// default when the guard is dropped.
+pub(crate) fn apply() -> Result<Applied> {
+    let dir = CURRENT.with_borrow(Clone::clone).or_else(|| {
+        DEFAULT
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    });
+    let Some(dir) = dir else {
+        return Ok(Applied);
+    };
+    check_space(&dir, MIN_TEMP_SPACE)?;
+    let path =
+        CString::new(dir.to_string_lossy().into_owned()).map_err(|_| ZpaqError::NulInString)?;
+    unsafe { sys::zpaq_set_temp_dir(path.as_ptr()) };
+    Ok(Applied)
+}
+
+/// Returned by [`apply`].
+pub(crate) struct Applied;
+
+impl Drop for Applied {
+    fn drop(&mut self) {
+        unsafe { sys::zpaq_set_temp_dir(std::ptr::null()) };
+    }
+}
+
+/// Fails unless `dir` is a directory with at least `required` bytes free.
+pub(crate) fn check_space(dir: &Path, required: u64) -> Result<()> {
+    let io_error = |kind: std::io::ErrorKind, message: String| ZpaqError::Io {
+        path: dir.to_path_buf(),
+        kind,
+        message,
+    };
+    match std::fs::metadata(dir) {
+        Ok(meta) if meta.is_dir() => {}
+        Ok(_) => {
+            return Err(io_error(
+                std::io::ErrorKind::NotADirectory,
+                "not a directory".into(),
+            ));
+        }
+        Err(e) => return Err(io_error(e.kind(), e.to_string())),
+    }
+    let path =
+        CString::new(dir.to_string_lossy().into_owned()).map_err(|_| ZpaqError::NulInString)?;
+    let mut available = 0u64;
+    if unsafe { sys::zpaq_temp_space(path.as_ptr(), &mut available) } != 0 {
+        // Free space is unknown here; let the command find out.
+        return Ok(());
+    }
+    if available < required {
+        return Err(ZpaqError::InsufficientTempSpace {
+            dir: dir.to_path_buf(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{ExtractOptions, ZpaqAddOptions, zpaq_add_opts, zpaq_extract_opts};
+
+    fn entries(dir: &Path) -> usize {
+        std::fs::read_dir(dir).expect("read dir").count()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn commands_stage_in_the_chosen_directory_and_clean_up() {
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        use crate::{ProgressCallback, ProgressInterval};
+
+        let dir = TempDir::new("zpaq-rs-temp");
+        let staging = dir.join("staging");
+        std::fs::create_dir(&staging).expect("create staging");
+        let input = dir.join("input.txt");
+        std::fs::write(&input, b"staged through a chosen directory\n".repeat(5000))
+            .expect("write input");
+        let input = input.to_str().expect("utf-8 path");
+        let archive = dir.join("archive.zpaq").to_string_lossy().into_owned();
+
+        let add = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            temp_dir: Some(staging.clone()),
+            ..Default::default()
+        };
+        zpaq_add_opts(&archive, &[input], &add).expect("add");
+        assert_eq!(entries(&staging), 0);
+
+        // The captured output files exist while the command runs.
+        let seen = Arc::new(AtomicUsize::new(0));
+        let progress = {
+            let (seen, staging) = (seen.clone(), staging.clone());
+            ProgressCallback::new(move |_| {
+                seen.fetch_max(entries(&staging), Ordering::Relaxed);
+            })
+            .interval(ProgressInterval::Bytes(1))
+        };
+        let extract = ExtractOptions {
+            progress: Some(progress),
+            temp_dir: Some(staging.clone()),
+            ..Default::default()
+        };
+        let out = dir.join("out.txt").to_string_lossy().into_owned();
+        zpaq_extract_opts(&archive, &[input, "-to", &out], &extract).expect("extract");
+        assert_eq!(seen.load(Ordering::Relaxed), 2);
+        assert_eq!(entries(&staging), 0);
+        assert_eq!(
+            std::fs::read(&out).expect("read out"),
+            std::fs::read(input).expect("read input")
+        );
+
+        // And are removed when it fails.
+        let missing = dir.join("missing.zpaq").to_string_lossy().into_owned();
+        assert!(zpaq_extract_opts(&missing, &[], &extract).is_err());
+        assert_eq!(entries(&staging), 0);
+    }
+
+    #[test]
+    fn unusable_directories_fail_before_the_command_runs() {
+        let dir = TempDir::new("zpaq-rs-temp-check");
+        let archive = dir.join("archive.zpaq").to_string_lossy().into_owned();
+        let input = dir.join("input.txt");
+        std::fs::write(&input, b"data").expect("write input");
+        let input = input.to_str().expect("utf-8 path");
+
+        let gone = ZpaqAddOptions {
+            temp_dir: Some(dir.join("gone")),
+            ..Default::default()
+        };
+        assert!(matches!(
+            zpaq_add_opts(&archive, &[input], &gone),
+            Err(ZpaqError::Io { path, .. }) if path == dir.join("gone")
+        ));
+        assert!(!Path::new(&archive).exists());
+
+        check_space(&dir, MIN_TEMP_SPACE).expect("room for a command");
+        match check_space(&dir, u64::MAX) {
+            Err(ZpaqError::InsufficientTempSpace {
+                dir: named,
+                required,
+                available,
+            }) => {
+                assert_eq!(named, dir.path());
+                assert_eq!(required, u64::MAX);
+                assert!(available < required);
+            }
+            other => panic!("expected InsufficientTempSpace, got {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,474 @@
+//! Round-trip test harness: payload generators, a driver that pushes every
+//! payload through each compression API and checks the sizes agree, and
+//! corrupted inputs for negative tests.
+//!
+//! Built with the `test-support` feature, for the test suites of crates
+//! that wrap this one; normal builds leave it out.  Generators are seeded,
+//! so a failure reproduces exactly.
+//!
+//! # Example
+//!
+//! ```rust
+//! use zpaq_rs::test_support::{self, ApiUnderTest, Payload};
+//!
+//! // Downstream wrappers plug in as further `ApiUnderTest`s.
+//! let payloads = [Payload::new("text", test_support::text(50_000))];
+//! test_support::round_trip_with(&ApiUnderTest::ALL, &payloads, &["1"]);
+//!
+//! let data = test_support::random(7, 10_000);
+//! let outcomes = test_support::check_corrupted(&ApiUnderTest::TO_VEC, &data, "1", 7);
+//! assert!(outcomes.rejected > 0);
+//! ```
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    Result, compress_size, compress_size_parallel, compress_stream, compress_to_vec,
+    compress_to_vec_parallel, decompress_size, decompress_stream, decompress_to_vec,
+};
+
+/// Methods [`round_trip_all`] compresses with: store, level 1 with 1 MiB
+/// blocks (so the multi-megabyte payloads span several), level 2, and an
+/// explicit context model.
+pub const METHODS: &[&str] = &["0", "10", "2", "x4.3ci1"];
+
+/// Threads [`ApiUnderTest::PARALLEL`] compresses with.
+pub const PARALLEL_THREADS: usize = 4;
+
+/// A named input for round-trip tests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Payload {
+    /// Shown when a check fails.
+    pub name: String,
+    /// The bytes to compress.
+    pub data: Vec<u8>,
+}
+
+impl Payload {
+    /// A payload called `name`.
+    pub fn new(name: impl Into<String>, data: Vec<u8>) -> Self {
+        Payload {
+            name: name.into(),
+            data,
+        }
+    }
+}
+
+/// `len` zero bytes.
+pub fn zeros(len: usize) -> Vec<u8> {
+    vec![0; len]
+}
+
+/// `pattern` repeated to `len` bytes.
+///
+/// # Panics
+///
+/// Panics if `pattern` is empty and `len` is not 0.
+pub fn periodic(pattern: &[u8], len: usize) -> Vec<u8> {
+    assert!(len == 0 || !pattern.is_empty(), "empty pattern");
+    pattern.iter().copied().cycle().take(len).collect()
+}
+
+/// Numbered lines of text, `len` bytes in all.
+pub fn text(len: usize) -> Vec<u8> {
+    (0u32..)
+        .flat_map(|i| format!("{i}: {}\n", i.wrapping_mul(2_654_435_761)).into_bytes())
+        .take(len)
+        .collect()
+}
+
+/// `len` pseudo-random bytes from `seed`, which no method can shrink.
+pub fn random(seed: u64, len: usize) -> Vec<u8> {
+    lcg(seed).map(|x| (x >> 56) as u8).take(len).collect()
+}
+
+/// `len` pseudo-random bytes from `seed` with the top bit clear, which the
+/// stronger methods shrink by about an eighth.
+pub fn near_incompressible(seed: u64, len: usize) -> Vec<u8> {
+    lcg(seed).map(|x| (x >> 57) as u8).take(len).collect()
+}
+
+/// `len` pseudo-random letters from `seed`, drawn from `a` to `p`: four bits
+/// of entropy a byte, with no repeats for a match model to find.
+pub fn letters(seed: u64, len: usize) -> Vec<u8> {
+    lcg(seed)
+        .map(|x| b"abcdefghijklmnop"[(x >> 60) as usize])
+        .take(len)
+        .collect()
+}
+
+fn lcg(seed: u64) -> impl Iterator<Item = u64> {
+    let mut x = seed;
+    std::iter::repeat_with(move || {
+        x = x
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        x
+    })
+}
+
+/// The payloads [`round_trip_all`] uses: empty and one-byte inputs, runs of
+/// zeros, periodic data, text, random and near-incompressible data, and a
+/// 4 MiB input mixing text and random data.
+pub fn payloads() -> Vec<Payload> {
+    let mut mixed = text(3 << 20);
+    mixed.extend(random(3, 1 << 20));
+    vec![
+        Payload::new("empty", Vec::new()),
+        Payload::new("one byte", vec![0x5a]),
+        Payload::new("hello", b"hello zpaq".to_vec()),
+        Payload::new("zeros", zeros(1 << 16)),
+        Payload::new("periodic", periodic(b"0123456789abcdefghij", 100_000)),
+        Payload::new("period 251", (0..20_000).map(|i| (i % 251) as u8).collect()),
+        Payload::new("text", text(200_000)),
+        Payload::new("letters", letters(1, 100_000)),
+        Payload::new("random", random(1, 1 << 16)),
+        Payload::new("near incompressible", near_incompressible(2, 1 << 18)),
+        Payload::new("multi-megabyte", mixed),
+    ]
+}
+
+/// Compresses `data` with `method`.
+pub type CompressFn = fn(data: &[u8], method: &str) -> Result<Vec<u8>>;
+/// Decompresses a whole archive.
+pub type DecompressFn = fn(compressed: &[u8]) -> Result<Vec<u8>>;
+/// Size of what a [`CompressFn`] would return.
+pub type CompressedSizeFn = fn(data: &[u8], method: &str) -> Result<u64>;
+/// Size of what a [`DecompressFn`] would return.
+pub type DecompressedSizeFn = fn(compressed: &[u8]) -> Result<u64>;
+
+/// One way of compressing and decompressing, with the size functions that
+/// must agree with it.
+#[derive(Debug, Clone, Copy)]
+pub struct ApiUnderTest {
+    /// Shown when a check fails.
+    pub name: &'static str,
+    /// Compresses `data` with `method`.
+    pub compress: CompressFn,
+    /// Undoes `compress`.
+    pub decompress: DecompressFn,
+    /// Must return the length of `compress`'s output without it.
+    pub compressed_size: Option<CompressedSizeFn>,
+    /// Must return the length of `decompress`'s output without it.
+    pub decompressed_size: Option<DecompressedSizeFn>,
+}
+
+impl ApiUnderTest {
+    /// [`compress_to_vec`] and [`decompress_to_vec`].
+    pub const TO_VEC: ApiUnderTest = ApiUnderTest {
+        name: "to_vec",
+        compress: compress_to_vec,
+        decompress: decompress_to_vec,
+        compressed_size: Some(compress_size),
+        decompressed_size: Some(decompress_size),
+    };
+
+    /// [`compress_stream`] and [`decompress_stream`] over slices and
+    /// vectors.
+    pub const STREAM: ApiUnderTest = ApiUnderTest {
+        name: "stream",
+        compress: |data, method| {
+            let mut out = Vec::new();
+            compress_stream(data, &mut out, method, None, None)?;
+            Ok(out)
+        },
+        decompress: |compressed| {
+            let mut out = Vec::new();
+            decompress_stream(compressed, &mut out)?;
+            Ok(out)
+        },
+        compressed_size: Some(compress_size),
+        decompressed_size: Some(decompress_size),
+    };
+
+    /// [`compress_to_vec_parallel`] with [`PARALLEL_THREADS`] threads.
+    pub const PARALLEL: ApiUnderTest = ApiUnderTest {
+        name: "parallel",
+        compress: |data, method| compress_to_vec_parallel(data, method, PARALLEL_THREADS),
+        decompress: decompress_to_vec,
+        compressed_size: Some(|data, method| {
+            compress_size_parallel(data, method, PARALLEL_THREADS)
+        }),
+        decompressed_size: Some(decompress_size),
+    };
+
+    /// Every API of this crate above.
+    pub const ALL: [ApiUnderTest; 3] = [Self::TO_VEC, Self::STREAM, Self::PARALLEL];
+}
+
+/// Round-trips every one of [`payloads`] through each of `apis` with each
+/// of [`METHODS`].
+///
+/// # Panics
+///
+/// Panics, naming the API, method and payload, if any call fails, the data
+/// does not come back unchanged, or a size function disagrees.
+pub fn round_trip_all(apis: &[ApiUnderTest]) {
+    round_trip_with(apis, &payloads(), METHODS);
+}
+
+/// [`round_trip_all`] with chosen payloads and methods.
+///
+/// # Panics
+///
+/// As [`round_trip_all`].
+pub fn round_trip_with(apis: &[ApiUnderTest], payloads: &[Payload], methods: &[&str]) {
+    for api in apis {
+        for &method in methods {
+            for payload in payloads {
+                let case = format!("{} method={method} payload={}", api.name, payload.name);
+                let compressed = (api.compress)(&payload.data, method)
+                    .unwrap_or_else(|e| panic!("{case}: compress failed: {e}"));
+                let decompressed = (api.decompress)(&compressed)
+                    .unwrap_or_else(|e| panic!("{case}: decompress failed: {e}"));
+                assert!(decompressed == payload.data, "{case}: data changed");
+                if let Some(size) = api.compressed_size {
+                    let size = size(&payload.data, method)
+                        .unwrap_or_else(|e| panic!("{case}: compressed size failed: {e}"));
+                    assert_eq!(size, compressed.len() as u64, "{case}: compressed size");
+                }
+                if let Some(size) = api.decompressed_size {
+                    let size = size(&compressed)
+                        .unwrap_or_else(|e| panic!("{case}: decompressed size failed: {e}"));
+                    assert_eq!(size, payload.data.len() as u64, "{case}: decompressed size");
+                }
+            }
+        }
+    }
+}
+
+/// `compressed` damaged in the ways storage and transfers damage data:
+/// truncated at several points, single bits flipped at positions picked by
+/// `seed`, a run of bytes zeroed, and garbage appended.
+pub fn corruptions(compressed: &[u8], seed: u64) -> Vec<Payload> {
+    let len = compressed.len();
+    let mut out = Vec::new();
+    for cut in [1, len / 4, len / 2, len.saturating_sub(1)] {
+        if cut < len {
+            out.push(Payload::new(
+                format!("truncated to {cut}"),
+                compressed[..cut].to_vec(),
+            ));
+        }
+    }
+    if len > 0 {
+        for x in lcg(seed).take(16) {
+            let pos = (x >> 32) as usize % len;
+            let bit = (x >> 29) as u8 & 7;
+            let mut data = compressed.to_vec();
+            data[pos] ^= 1 << bit;
+            out.push(Payload::new(
+                format!("bit {bit} of byte {pos} flipped"),
+                data,
+            ));
+        }
+        let start = len / 3;
+        let end = (start + 16).min(len);
+        let mut data = compressed.to_vec();
+        data[start..end].fill(0);
+        out.push(Payload::new(format!("bytes {start}..{end} zeroed"), data));
+    }
+    let mut data = compressed.to_vec();
+    data.extend(random(seed, 64));
+    out.push(Payload::new("garbage appended", data));
+    out
+}
+
+/// How the [`corruptions`] of an archive decompressed, as counted by
+/// [`check_corrupted`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CorruptionOutcomes {
+    /// Failed with an error.
+    pub rejected: usize,
+    /// Gave back the original data: the damage hit a segment's filename or
+    /// comment, or garbage was appended after the last block.
+    pub intact: usize,
+    /// Gave back nothing: libzpaq skips input before a block's header, so
+    /// damage that hides the only header leaves no block to decompress.
+    pub empty: usize,
+    /// Gave back other data.  The decompressors of this crate do not check
+    /// segment SHA-1s, so a flipped bit in coded data can get through;
+    /// [`verify_archive`](crate::verify_archive) catches it.  An API that
+    /// checks them should have none.
+    pub altered: usize,
+}
+
+/// Compresses `data` with `api`, decompresses each of its [`corruptions`]
+/// and counts the outcomes.  The caller decides which are acceptable; none
+/// may panic or crash.
+///
+/// # Panics
+///
+/// Panics if `data` does not compress.
+pub fn check_corrupted(
+    api: &ApiUnderTest,
+    data: &[u8],
+    method: &str,
+    seed: u64,
+) -> CorruptionOutcomes {
+    let compressed = (api.compress)(data, method)
+        .unwrap_or_else(|e| panic!("{} method={method}: compress failed: {e}", api.name));
+    let mut outcomes = CorruptionOutcomes::default();
+    for corrupted in corruptions(&compressed, seed) {
+        match (api.decompress)(&corrupted.data) {
+            Err(_) => outcomes.rejected += 1,
+            Ok(out) if out == data => outcomes.intact += 1,
+            Ok(out) if out.is_empty() => outcomes.empty += 1,
+            Ok(_) => outcomes.altered += 1,
+        }
+    }
+    outcomes
+}
+
+/// The number of `libzpaq` objects (compressors, decompressers, readers,
+/// writers, hashes and ciphers) made on this thread and not yet freed on
+/// it.  Compare it before and after an operation to check nothing leaked.
+pub fn live_objects() -> i64 {
+    crate::handle::live_objects()
+}
+
+/// Makes the `n`th allocation of a `libzpaq` object from now on this thread
+/// fail as if out of memory, with a [`ZpaqError::Ffi`](crate::ZpaqError)
+/// error; 0 turns the failure off.  Stepping `n` up from 1 drives an
+/// operation through each of its allocation failures in turn.
+pub fn fail_nth_allocation(n: u32) {
+    crate::handle::fail_nth_new(n);
+}
+
+/// A new directory below [`std::env::temp_dir`], removed with everything
+/// in it when dropped.  It derefs to its [`Path`].
+#[derive(Debug)]
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    /// Creates a directory whose name starts with `prefix` and is unique to
+    /// this process and call.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the directory cannot be created.
+    pub fn new(prefix: &str) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock")
+            .as_nanos();
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path =
+            std::env::temp_dir().join(format!("{prefix}-{}-{nanos}-{n}", std::process::id()));
+        std::fs::create_dir_all(&path).expect("create temp dir");
+        TempDir { path }
+    }
+
+    /// The directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TempDir {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generators_are_deterministic_and_sized() {
+        assert_eq!(random(5, 1000), random(5, 1000));
+        assert_ne!(random(5, 1000), random(6, 1000));
+        assert!(near_incompressible(1, 1000).iter().all(|&b| b < 128));
+        assert!(letters(1, 1000).iter().all(u8::is_ascii_lowercase));
+        assert_eq!(periodic(b"ab", 5), b"ababa");
+        for payload in payloads() {
+            assert_eq!(payload, payload.clone());
+        }
+        assert_eq!(payloads().last().expect("payloads").data.len(), 4 << 20);
+    }
+
+    #[test]
+    fn temp_dirs_are_distinct_and_removed_on_drop() {
+        let a = TempDir::new("zpaq-rs-test-support");
+        let b = TempDir::new("zpaq-rs-test-support");
+        assert_ne!(a.path(), b.path());
+        std::fs::write(a.join("file"), b"data").expect("write");
+        let path = a.to_path_buf();
+        drop(a);
+        assert!(!path.exists());
+        assert!(b.is_dir());
+    }
+
+    #[test]
+    fn every_api_round_trips_every_payload() {
+        round_trip_all(&ApiUnderTest::ALL);
+    }
+
+    #[test]
+    fn corrupted_archives_fail_or_are_caught_by_verify() {
+        for method in ["0", "2"] {
+            let data = text(30_000);
+            let compressed = compress_to_vec(&data, method).expect("compress");
+            for corrupted in corruptions(&compressed, 11) {
+                let Ok(out) = decompress_to_vec(&corrupted.data) else {
+                    continue;
+                };
+                if out != data && !out.is_empty() {
+                    let deep = crate::VerifyOptions {
+                        deep: true,
+                        ..Default::default()
+                    };
+                    let report = crate::verify_archive(&corrupted.data, &deep).expect("verify");
+                    assert!(!report.is_ok(), "method={method}: {}", corrupted.name);
+                }
+            }
+            let outcomes = check_corrupted(&ApiUnderTest::STREAM, &data, method, 12);
+            assert!(outcomes.rejected > 0, "method={method}: {outcomes:?}");
+            assert_eq!(
+                outcomes.rejected + outcomes.intact + outcomes.empty + outcomes.altered,
+                corruptions(&compressed, 12).len()
+            );
+        }
+        assert!(corruptions(&[], 1).iter().all(|c| c.data.len() == 64));
+    }
+
+    #[test]
+    #[should_panic(expected = "lossy method=0 payload=hello: data changed")]
+    fn wrong_round_trips_name_the_case() {
+        let lossy = ApiUnderTest {
+            name: "lossy",
+            decompress: |compressed| {
+                let mut out = decompress_to_vec(compressed)?;
+                out.pop();
+                Ok(out)
+            },
+            ..ApiUnderTest::TO_VEC
+        };
+        round_trip_with(
+            &[lossy],
+            &[Payload::new("hello", b"hello".to_vec())],
+            &["0"],
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Throughput limits for background compression.
+//!
+//! A [`Throttle`] is a token bucket: reading takes one token per byte, and
+//! tokens come back at the configured rate up to the burst size.  Readers
+//! made from clones of one throttle share its bucket, so several inputs,
+//! or the blocks of a parallel compression, together stay under the limit.
+//! Waiting happens in the Rust read callback, outside any lock of the C++
+//! side, so compression threads keep working on what was read already.
+
+use std::io::Read;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::{Result, ZpaqError};
+
+/// Burst size of [`RateLimit::new`].
+const DEFAULT_BURST: u64 = 64 << 10;
+
+/// A limit on how fast input is read, for [`Throttle`],
+/// [`CompressOptions::rate_limit`](crate::CompressOptions::rate_limit) and
+/// [`ZpaqAddOptions::rate_limit`](crate::ZpaqAddOptions::rate_limit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RateLimit {
+    /// Sustained rate in bytes per second.
+    pub bytes_per_sec: u64,
+    /// Bytes that may be read at once after a pause, ahead of the rate.
+    /// Single reads are also cut to this size, so smaller bursts give a
+    /// smoother rate at the cost of more calls.
+    pub burst: u64,
+}
+
+impl RateLimit {
+    /// A limit of `bytes_per_sec` with a burst of 64 KiB.
+    pub fn new(bytes_per_sec: u64) -> Self {
+        RateLimit {
+            bytes_per_sec,
+            burst: DEFAULT_BURST,
+        }
+    }
+}
+
+/// A shared token bucket enforcing a [`RateLimit`].
+///
+/// Cloning gives another handle on the same bucket.
+///
+/// # Example
+///
+/// ```rust
+/// use std::time::Instant;
+/// use zpaq_rs::{RateLimit, Throttle};
+///
+/// let data = vec![7u8; 300_000];
+/// let throttle = Throttle::new(RateLimit::new(1_000_000))?;
+/// let started = Instant::now();
+/// let mut out = Vec::new();
+/// zpaq_rs::compress_stream_parallel(throttle.reader(&data[..]), &mut out, "1", None, None, 4)?;
+/// assert!(started.elapsed().as_secs_f64() > 0.2);
+/// # Ok::<(), zpaq_rs::ZpaqError>(())
+/// ```
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    bucket: Arc<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: f64,
+    burst: u64,
+    /// Tokens available (negative while readers owe time) and when that
+    /// was last worked out.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Throttle {
+    /// Creates a throttle whose bucket starts full.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the rate or burst is zero.
+    pub fn new(limit: RateLimit) -> Result<Self> {
+        if limit.bytes_per_sec == 0 || limit.burst == 0 {
+            return Err(ZpaqError::Ffi(
+                "rate limit and burst must be positive".into(),
+            ));
+        }
+        Ok(Throttle {
+            bucket: Arc::new(Bucket {
+                rate: limit.bytes_per_sec as f64,
+                burst: limit.burst,
+                state: Mutex::new((limit.burst as f64, Instant::now())),
+            }),
+        })
+    }
+
+    /// Wraps `reader` so that reading from it draws on this throttle.
+    pub fn reader<R: Read>(&self, reader: R) -> ThrottledReader<R> {
+        ThrottledReader {
+            inner: reader,
+            throttle: self.clone(),
+        }
+    }
+
+    /// Takes `bytes` tokens, sleeping until the bucket has paid for them.
+    ///
+    /// Tokens are taken at once and may leave the bucket in debt, which
+    /// later callers wait off too, so concurrent readers share the rate
+    /// rather than race for it.
+    pub fn consume(&self, bytes: u64) {
+        let bucket = &self.bucket;
+        let wait = {
+            let mut state = bucket.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let (tokens, last) = &mut *state;
+            let now = Instant::now();
+            let refill = now.duration_since(*last).as_secs_f64() * bucket.rate;
+            *tokens = (*tokens + refill).min(bucket.burst as f64) - bytes as f64;
+            *last = now;
+            (-*tokens).max(0.0) / bucket.rate
+        };
+        if wait > 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+
+    pub(crate) fn burst(&self) -> u64 {
+        self.bucket.burst
+    }
+}
+
+/// A reader limited by a [`Throttle`]; see [`Throttle::reader`].
+#[derive(Debug)]
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Throttle,
+}
+
+impl<R> ThrottledReader<R> {
+    /// Returns the wrapped reader.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.throttle.burst() as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        self.throttle.consume(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compress_stream_parallel, compress_to_vec, decompress_to_vec, test_support};
+
+    #[test]
+    fn rejects_empty_limits() {
+        assert!(Throttle::new(RateLimit::new(0)).is_err());
+        let no_burst = RateLimit {
+            bytes_per_sec: 1000,
+            burst: 0,
+        };
+        assert!(Throttle::new(no_burst).is_err());
+    }
+
+    #[test]
+    fn parallel_blocks_share_one_bucket() {
+        // Method 1 with 1 MiB blocks, so four threads read blocks in turn.
+        let data = test_support::text(3 << 20);
+        let unlimited = Instant::now();
+        let expected = compress_to_vec(&data, "10").expect("compress");
+        let unlimited = unlimited.elapsed().as_secs_f64();
+
+        let limit = RateLimit {
+            bytes_per_sec: 2 << 20,
+            burst: 256 << 10,
+        };
+        let throttle = Throttle::new(limit).expect("throttle");
+        let started = Instant::now();
+        let mut out = Vec::new();
+        compress_stream_parallel(throttle.reader(&data[..]), &mut out, "10", None, None, 4)
+            .expect("compress");
+        let limited = started.elapsed().as_secs_f64();
+        assert_eq!(out, expected);
+        assert_eq!(decompress_to_vec(&out).expect("decompress"), data);
+
+        // 3 MiB at 2 MiB/s, less the first burst.
+        let least = (data.len() as u64 - limit.burst) as f64 / limit.bytes_per_sec as f64;
+        assert!(limited >= least * 0.95, "{limited}s");
+        assert!(limited < least + 2.0 + unlimited, "{limited}s");
+        assert!(unlimited < least / 2.0, "{unlimited}s unlimited");
+    }
+}
@@ -0,0 +1,272 @@
+//! Spans and events through the `tracing` crate (the `tracing` feature).
+//!
+//! Long-running calls open a debug span under the `zpaq_rs` target, which
+//! records the bytes read and written when the call returns; each block the
+//! shim compresses is a trace event and each file added a debug event.
+//! Without the feature everything here compiles to nothing.
+
+#[cfg(feature = "tracing")]
+use std::cell::Cell;
+
+#[cfg(feature = "tracing")]
+thread_local! {
+    /// Blocks and bytes compressed on this thread by the innermost
+    /// [`Operation`]: `(blocks, uncompressed, compressed)`.
+    static TOTALS: Cell<(u64, u64, u64)> = const { Cell::new((0, 0, 0)) };
+}
+
+/// Whether `libzpaq` was built with its x86 JIT.
+#[cfg(feature = "tracing")]
+const JIT: bool = cfg!(all(target_arch = "x86_64", not(feature = "nojit")));
+
+/// A traced call, in its span until dropped.
+pub(crate) struct Operation {
+    #[cfg(feature = "tracing")]
+    span: tracing::span::EnteredSpan,
+    /// The enclosing operation's totals, restored on drop.
+    #[cfg(feature = "tracing")]
+    outer: (u64, u64, u64),
+}
+
+impl Operation {
+    /// Compressing with `method` on `threads` workers, by the function
+    /// named `call`.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn compress(call: &'static str, method: &str, threads: usize) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            crate::install_hooks();
+            let span = tracing::debug_span!(
+                target: "zpaq_rs",
+                "compress",
+                call,
+                method,
+                threads,
+                blocks = tracing::field::Empty,
+                bytes_in = tracing::field::Empty,
+                bytes_out = tracing::field::Empty,
+            );
+            let operation = Self::enter(span);
+            if !JIT {
+                tracing::debug!(target: "zpaq_rs", "JIT disabled, interpreting ZPAQL");
+            }
+            operation
+        }
+        #[cfg(not(feature = "tracing"))]
+        Operation {}
+    }
+
+    /// Decompressing, by the function named `call`.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn decompress(call: &'static str) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            if !JIT {
+                tracing::debug!(target: "zpaq_rs", call, "JIT disabled, interpreting ZPAQL");
+            }
+            Self::enter(tracing::debug_span!(target: "zpaq_rs", "decompress", call))
+        }
+        #[cfg(not(feature = "tracing"))]
+        Operation {}
+    }
+
+    /// Adding `inputs` to `archive` with `zpaq add`.
+    #[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+    pub(crate) fn add(archive: &str, inputs: &[&str], method: &str, threads: usize) -> Self {
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::debug_span!(
+                target: "zpaq_rs",
+                "add",
+                archive,
+                inputs = inputs.len(),
+                method,
+                threads,
+            );
+            let operation = Self::enter(span);
+            for input in inputs {
+                tracing::debug!(target: "zpaq_rs", input, "adding input");
+            }
+            operation
+        }
+        #[cfg(not(feature = "tracing"))]
+        Operation {}
+    }
+
+    #[cfg(feature = "tracing")]
+    fn enter(span: tracing::Span) -> Self {
+        Operation {
+            span: span.entered(),
+            outer: TOTALS.replace((0, 0, 0)),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let (blocks, bytes_in, bytes_out) = TOTALS.get();
+        if blocks > 0 {
+            self.span.record("blocks", blocks);
+            self.span.record("bytes_in", bytes_in);
+            self.span.record("bytes_out", bytes_out);
+        }
+        let (outer_blocks, outer_in, outer_out) = self.outer;
+        TOTALS.set((
+            outer_blocks + blocks,
+            outer_in + bytes_in,
+            outer_out + bytes_out,
+        ));
+    }
+}
+
+/// A block compressed: an event, counted in [`TOTALS`].
+#[cfg(feature = "tracing")]
+pub(crate) fn block(uncompressed: u64, compressed: u64) {
+    let (blocks, bytes_in, bytes_out) = TOTALS.get();
+    TOTALS.set((blocks + 1, bytes_in + uncompressed, bytes_out + compressed));
+    tracing::trace!(
+        target: "zpaq_rs",
+        index = blocks,
+        uncompressed,
+        compressed,
+        "block compressed"
+    );
+}
+
+/// A file of `size` bytes in `fragments` fragments, `new_fragments` of
+/// them stored, added as `path` to a journaling archive.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+pub(crate) fn file_added(path: &str, size: u64, fragments: usize, new_fragments: usize) {
+    #[cfg(feature = "tracing")]
+    tracing::debug!(
+        target: "zpaq_rs",
+        path,
+        size,
+        fragments,
+        new_fragments,
+        "file added"
+    );
+}
+
+/// Worker threads that could not be given their priority or name: a
+/// warning, since they ran all the same.
+#[cfg(feature = "tracing")]
+pub(crate) fn worker_setup_failed(failures: u64) {
+    tracing::warn!(
+        target: "zpaq_rs",
+        failures,
+        "worker thread priority or name not applied"
+    );
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::{ArchiveWriter, EntryMeta, compress_size_parallel, compress_to_vec_parallel};
+    use std::sync::{Arc, Mutex};
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Metadata, Subscriber};
+
+    /// Records the messages of events and the fields recorded on spans.
+    #[derive(Clone, Default)]
+    struct Capture {
+        events: Arc<Mutex<Vec<String>>>,
+        recorded: Arc<Mutex<Vec<(String, u64)>>>,
+        next_id: Arc<Mutex<u64>>,
+    }
+
+    struct Message<'a>(&'a mut String);
+
+    impl Visit for Message<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            if field.name() == "message" {
+                *self.0 = format!("{value:?}");
+            }
+        }
+    }
+
+    struct Numbers<'a>(&'a mut Vec<(String, u64)>);
+
+    impl Visit for Numbers<'_> {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.push((field.name().to_string(), value));
+        }
+
+        fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {}
+    }
+
+    impl Subscriber for Capture {
+        fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+            metadata.target() == "zpaq_rs"
+        }
+
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            let mut next = self.next_id.lock().unwrap();
+            *next += 1;
+            Id::from_u64(*next)
+        }
+
+        fn record(&self, _: &Id, values: &Record<'_>) {
+            values.record(&mut Numbers(&mut self.recorded.lock().unwrap()));
+        }
+
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+        fn event(&self, event: &Event<'_>) {
+            let mut message = String::new();
+            event.record(&mut Message(&mut message));
+            self.events.lock().unwrap().push(message);
+        }
+
+        fn enter(&self, _: &Id) {}
+
+        fn exit(&self, _: &Id) {}
+    }
+
+    impl Capture {
+        fn count(&self, message: &str) -> usize {
+            let events = self.events.lock().unwrap();
+            events.iter().filter(|m| *m == message).count()
+        }
+    }
+
+    #[test]
+    fn parallel_compression_emits_one_event_per_block() {
+        // Method "10" uses 1 MiB blocks: four, the last one partial.
+        let data: Vec<u8> = (0..3_500_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let capture = Capture::default();
+        let (compressed, size) = tracing::subscriber::with_default(capture.clone(), || {
+            let compressed = compress_to_vec_parallel(&data, "10", 3).expect("compress");
+            let size = compress_size_parallel(&data, "10", 3).expect("size");
+            (compressed, size)
+        });
+        assert_eq!(compressed.len() as u64, size);
+        assert_eq!(capture.count("block compressed"), 8);
+
+        let recorded = capture.recorded.lock().unwrap();
+        let total = |name: &str| -> u64 {
+            let values = recorded.iter().filter(|(field, _)| field == name);
+            values.map(|(_, v)| v).sum()
+        };
+        assert_eq!(total("blocks"), 8);
+        assert_eq!(total("bytes_in"), 2 * data.len() as u64);
+        assert_eq!(total("bytes_out"), 2 * size);
+    }
+
+    #[test]
+    fn archive_writer_emits_an_event_per_file() {
+        let capture = Capture::default();
+        tracing::subscriber::with_default(capture.clone(), || {
+            let mut writer =
+                ArchiveWriter::new(std::io::Cursor::new(Vec::new()), "1").expect("writer");
+            for path in ["a.txt", "b.txt", "c.txt"] {
+                let meta = EntryMeta::default();
+                writer.add_entry(path, &b"same"[..], &meta).expect("add");
+            }
+            writer.finish().expect("finish");
+        });
+        assert_eq!(capture.count("file added"), 3);
+        assert!(capture.count("block compressed") >= 3);
+    }
+}
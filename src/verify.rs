@@ -0,0 +1,1781 @@
+//! Structural and content verification of ZPAQ archives.
+//!
+//! Journaling archives (the format `zpaq add` writes) consist of transactions,
+//! each a `c` header block followed by `d` (fragment data), `h` (fragment
+//! hashes and sizes) and `i` (file index) blocks.  [`verify_archive`] walks
+//! every block, resynchronising at the next block after a decode error as
+//! `zpaq` does, and cross-checks the tables against each other.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::os::raw::c_char;
+use std::path::PathBuf;
+use std::ptr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::blocks::{comment_checksum, skip_segment};
+use crate::display;
+use crate::parts::open_archive;
+use crate::verify_state::{SegmentOutcome, VerifyState, fragments_digest};
+use crate::{
+    CancellationToken, Digest, FfiReader, FfiWriter, Result, SecretString, Sha1Hasher,
+    SharedVecWriter, ZpaqError, clear_last_error, err_from_last, handle, io_err, sha1, sha256,
+    stretch_key, sys,
+};
+
+/// Options for [`verify_archive`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyOptions {
+    /// Decompress `d` (data) blocks and streaming segments too, checking
+    /// every fragment against its `h` table entry and every segment SHA-1.
+    ///
+    /// Shallow mode decodes only the small `c`/`h`/`i` blocks and skips over
+    /// data, so it validates headers, trailers, block sizes and index
+    /// references but cannot see damage inside compressed data.
+    pub deep: bool,
+    /// Password of an encrypted archive (the `-key` argument of `zpaq`).
+    pub key: Option<SecretString>,
+    /// File recording, with [`deep`](Self::deep), what each block
+    /// decoded to as it is checked, so that a run stopped part way (see
+    /// [`cancel`](Self::cancel)) can be continued: a later run with the
+    /// same file reads past the blocks it records instead of decoding
+    /// them, and reports what a single run would.
+    ///
+    /// The file is created if missing.  It is tied to the archive's length
+    /// and the SHA-1 of its last 64 KiB, and started afresh if either
+    /// differs, as when versions were added; a change further back goes
+    /// unseen.  Shallow verification neither reads nor writes it.
+    pub persist_state: Option<PathBuf>,
+    /// Stops verification at the next block once cancelled, with
+    /// [`ZpaqError::Cancelled`].
+    pub cancel: Option<CancellationToken>,
+}
+
+/// Result of [`verify_archive`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Blocks found in the archive.
+    pub blocks_checked: u64,
+    /// Segments found in those blocks.
+    pub segments_checked: u64,
+    /// Segments decoded and checked against the checksum their comment
+    /// stores, as [`CompressOptions::extra_checksum`](crate::CompressOptions::extra_checksum)
+    /// writes it.
+    pub extra_checksums_checked: u64,
+    /// Every problem found, in archive order followed by cross-check
+    /// failures.
+    pub errors: Vec<VerifyError>,
+}
+
+/// One line per error, then the totals.
+impl std::fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for error in &self.errors {
+            writeln!(f, "{error}")?;
+        }
+        writeln!(
+            f,
+            "{}, {}, {}",
+            display::count(self.blocks_checked, "block"),
+            display::count(self.segments_checked, "segment"),
+            display::count(self.errors.len() as u64, "error")
+        )
+    }
+}
+
+impl VerifyReport {
+    /// `true` if no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// One problem found by [`verify_archive`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyError {
+    /// Where the problem is.
+    pub location: VerifyLocation,
+    /// What the problem is.
+    pub kind: VerifyErrorKind,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let loc = &self.location;
+        write!(f, "block at offset {}", loc.offset)?;
+        if !loc.segment.is_empty() {
+            write!(f, " ({})", loc.segment)?;
+        }
+        if let Some(fragment) = loc.fragment {
+            write!(f, ", fragment {fragment}")?;
+        }
+        if let Some(file) = &loc.file {
+            write!(f, ", file {file}")?;
+        }
+        write!(f, ": {:?}", self.kind)
+    }
+}
+
+/// Location of a [`VerifyError`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyLocation {
+    /// Byte offset of the block in the archive file.  For encrypted archives
+    /// this counts the 32-byte salt, matching the offsets `zpaq` prints.
+    pub offset: u64,
+    /// Segment filename, e.g. `jDC20240101120000d0000000001` (empty if the
+    /// block header itself could not be read).
+    pub segment: String,
+    /// Fragment ID the problem concerns, if any.
+    pub fragment: Option<u32>,
+    /// File (from the index) the problem concerns, if any.
+    pub file: Option<String>,
+}
+
+/// Kind of a [`VerifyError`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErrorKind {
+    /// libzpaq failed to decode the block; the message is libzpaq's.
+    /// Verification resumed at the next block.
+    Undecodable(String),
+    /// A segment's SHA-1 trailer, or the extra checksum its comment
+    /// stores, does not match its decoded contents.
+    ChecksumMismatch,
+    /// A size recorded in the archive does not match the data, or (after
+    /// [`ZpaqAddOptions::verify_after_add`](crate::ZpaqAddOptions::verify_after_add))
+    /// a file just added is stored with a size other than its size on disk.
+    SizeMismatch {
+        /// Size recorded in the archive.
+        expected: u64,
+        /// Size found.
+        actual: u64,
+    },
+    /// A journaling block's name or contents do not follow the format.
+    Malformed(String),
+    /// A transaction header marks the transaction as never completed
+    /// (an interrupted `zpaq add`).
+    IncompleteTransaction,
+    /// An `h` table describes a `d` block that is not where it should be.
+    MissingDataBlock,
+    /// A fragment's decoded size or SHA-1 differs from its `h` table entry.
+    FragmentMismatch,
+    /// The index references a fragment no `h` table describes.
+    MissingFragment,
+    /// The archive could not be verified at all: it could not be opened or
+    /// read, or its key is missing or wrong.  The string says why.  Only
+    /// [`sweep_archives`](crate::sweep_archives) reports this; the verify
+    /// functions return the error instead.
+    Unreadable(String),
+}
+
+/// Verifies an in-memory archive.
+///
+/// Wrapper around [`verify_archive_reader`] with a [`std::io::Cursor`].
+pub fn verify_archive(archive: &[u8], options: &VerifyOptions) -> Result<VerifyReport> {
+    verify_archive_reader(std::io::Cursor::new(archive), options)
+}
+
+/// Verifies the archive at `path` without loading it into memory.  A
+/// multi-part pattern (see [`ArchiveSpec`](crate::ArchiveSpec)) verifies the
+/// concatenation of its parts.
+pub fn verify_archive_file(path: &str, options: &VerifyOptions) -> Result<VerifyReport> {
+    verify_archive_reader(BufReader::new(open_archive(path)?), options)
+}
+
+/// Verifies the archive read from `reader`.
+///
+/// Problems with the archive are collected in the report; `Err` is returned
+/// only for I/O failures (of the state file too), a wrong
+/// [`VerifyOptions::key`], and [`ZpaqError::Cancelled`].
+///
+/// Checks, in both modes: every block header and segment trailer parses;
+/// `c`, `h` and `i` blocks decode to their declared size and SHA-1; each
+/// transaction's `d` blocks have the compressed sizes its `h` tables record
+/// and add up to the size in its `c` header; every fragment the index
+/// references is described by an `h` table.  Deep mode additionally decodes
+/// every `d` block and streaming segment (see [`VerifyOptions::deep`]).
+pub fn verify_archive_reader<R: Read + Seek + Send>(
+    reader: R,
+    options: &VerifyOptions,
+) -> Result<VerifyReport> {
+    let mut reader = reader;
+    let state = match &options.persist_state {
+        Some(path) if options.deep => Some(Box::new(VerifyState::open(path, &mut reader)?)),
+        _ => None,
+    };
+    let mut source = ArchiveSource::new(reader, options.key.as_ref().map(SecretString::expose))?;
+    let mut scan = Scan {
+        deep: options.deep,
+        state,
+        cancel: options.cancel.clone(),
+        ..Default::default()
+    };
+    scan.scan(&mut source)?;
+    scan.decode_recorded(&mut source)?;
+    scan.cross_check();
+    Ok(scan.report)
+}
+
+/// Checks the versions of the journaling archive read from `reader` that
+/// follow its first `versions`, which ended at offset `end`, decoding their
+/// `d` blocks as [`VerifyOptions::deep`] does.  Returns the problems found
+/// in those versions, or past `end` if there are none.
+pub(crate) fn verify_versions_after<R: Read + Seek + Send>(
+    reader: R,
+    versions: usize,
+    end: u64,
+) -> Result<Vec<VerifyError>> {
+    let mut source = ArchiveSource::new(reader, None)?;
+    let mut scan = Scan {
+        deep: true,
+        deep_after: versions,
+        ..Default::default()
+    };
+    scan.scan(&mut source)?;
+    scan.cross_check();
+    let on_disk = scan.differing_from_disk(versions);
+    scan.report.errors.extend(on_disk);
+    let start = scan
+        .transactions
+        .get(versions)
+        .map_or(end, |t| t.location.offset.min(end));
+    let mut errors = scan.report.errors;
+    errors.retain(|e| e.location.offset >= start);
+    Ok(errors)
+}
+
+// ---------------- Archive input ----------------
+
+/// Archive reader that decrypts (if keyed) and tracks the archive offset.
+pub(crate) struct ArchiveSource<R> {
+    inner: R,
+    state: Arc<SourceState>,
+    aes: Option<handle::AesCtr>,
+    /// Offset of the first block (after the salt of an encrypted archive).
+    pub(crate) data_start: u64,
+}
+
+/// Shared with the scan while libzpaq owns the reader.
+#[derive(Default)]
+pub(crate) struct SourceState {
+    pos: AtomicU64,
+    io_error: Mutex<Option<std::io::Error>>,
+}
+
+impl SourceState {
+    pub(crate) fn pos(&self) -> u64 {
+        self.pos.load(Ordering::Relaxed)
+    }
+
+    fn take_io_error(&self) -> Option<ZpaqError> {
+        let err = self.io_error.lock().expect("poisoned").take()?;
+        Some(io_err(err))
+    }
+}
+
+impl<R: Read + Seek> ArchiveSource<R> {
+    pub(crate) fn new(inner: R, key: Option<&str>) -> Result<Self> {
+        let mut source = ArchiveSource {
+            inner,
+            state: Arc::default(),
+            aes: None,
+            data_start: 0,
+        };
+        let Some(key) = key else {
+            return Ok(source);
+        };
+
+        let mut salt = [0u8; 32];
+        source.inner.read_exact(&mut salt).map_err(io_err)?;
+        let key = stretch_key(sha256(key.as_bytes())?, salt)?;
+        clear_last_error()?;
+        source.aes = Some(handle::AesCtr::new(&key, &salt)?);
+        source.data_start = 32;
+        source.state.pos.store(32, Ordering::Relaxed);
+
+        // Same test as zpaq: decrypted data must start with a block tag.
+        let mut head = [0u8; 4];
+        let n = source.read(&mut head).map_err(io_err)?;
+        if n > 0 && &head != b"7kSt" && (&head[..3] != b"zPQ" || head[3] < 1) {
+            return Err(ZpaqError::Ffi("password incorrect".into()));
+        }
+        source.seek_to(32)?;
+        Ok(source)
+    }
+
+    pub(crate) fn position(&self) -> u64 {
+        self.state.pos()
+    }
+
+    /// The position, readable while libzpaq owns the source.
+    pub(crate) fn shared_state(&self) -> Arc<SourceState> {
+        self.state.clone()
+    }
+
+    pub(crate) fn take_io_error(&self) -> Option<ZpaqError> {
+        self.state.take_io_error()
+    }
+
+    pub(crate) fn seek_to(&mut self, pos: u64) -> Result<()> {
+        self.inner.seek(SeekFrom::Start(pos)).map_err(io_err)?;
+        self.state.pos.store(pos, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ArchiveSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(i32::MAX as usize);
+        let buf = &mut buf[..len];
+        let n = self.inner.read(buf).inspect_err(|e| {
+            let copy = std::io::Error::new(e.kind(), e.to_string());
+            *self.state.io_error.lock().expect("poisoned") = Some(copy);
+        })?;
+        let pos = self.state.pos();
+        if let Some(aes) = &self.aes {
+            unsafe {
+                sys::zpaq_aes_ctr_encrypt_slice(
+                    aes.raw,
+                    buf.as_mut_ptr() as *mut c_char,
+                    n as i32,
+                    pos,
+                )
+            };
+        }
+        self.state.pos.store(pos + n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// ---------------- Block scan ----------------
+
+/// A journaling segment name: `jDC` + 14-digit date + type + 10-digit number.
+pub(crate) struct JournalName {
+    pub(crate) kind: u8,
+    pub(crate) num: u32,
+}
+
+pub(crate) fn parse_journal_name(name: &str) -> Option<JournalName> {
+    let b = name.as_bytes();
+    if b.len() != 28 || &b[..3] != b"jDC" || !b[3..17].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    if !b[18..].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let num = name[18..].parse::<u64>().ok()?;
+    Some(JournalName {
+        kind: b[17],
+        num: u32::try_from(num).ok()?,
+    })
+}
+
+/// Uncompressed size from a journaling comment (`"<size> jDC\x01"`).
+fn journal_size(comment: &[u8]) -> Option<u64> {
+    let digits = comment.iter().take_while(|b| b.is_ascii_digit()).count();
+    std::str::from_utf8(&comment[..digits]).ok()?.parse().ok()
+}
+
+fn le32(b: &[u8]) -> u32 {
+    u32::from_le_bytes(b[..4].try_into().expect("4 bytes"))
+}
+
+struct Transaction {
+    location: VerifyLocation,
+    data_size: u64,
+    data_offset: u64,
+    table_total: u64,
+}
+
+pub(crate) struct DataBlock {
+    pub(crate) location: VerifyLocation,
+    end: u64,
+    pub(crate) first: u32,
+    /// `(size, sha1)` per fragment, decoded in deep mode.
+    fragments: Option<Vec<(u32, [u8; 20])>>,
+    /// Digest of the fragments an earlier run decoded, in place of
+    /// `fragments`.
+    recorded: Option<[u8; 20]>,
+}
+
+pub(crate) struct HashTable {
+    pub(crate) location: VerifyLocation,
+    pub(crate) first: u32,
+    pub(crate) data_offset: u64,
+    pub(crate) data_size: u64,
+    /// `(size, sha1)` per fragment.
+    pub(crate) fragments: Vec<(u32, [u8; 20])>,
+}
+
+/// One `i` block entry: a file version, or a deletion if `date` is 0.
+pub(crate) struct IndexEntry {
+    pub(crate) location: VerifyLocation,
+    /// Number of complete transactions up to and including this entry's.
+    pub(crate) version: usize,
+    pub(crate) file: String,
+    pub(crate) date: u64,
+    /// Attribute bytes: `'u'` and 2 bytes of Unix mode, or `'w'` and 4 bytes
+    /// of Windows attributes.
+    pub(crate) attr: Vec<u8>,
+    pub(crate) fragments: Vec<u32>,
+}
+
+/// Decodes segment `ordinal` (0 for the first) of the block at
+/// `location.offset`, whose name must be `location.segment`, and tells
+/// whether it matches its SHA-1 trailer (if it has one).  Earlier segments of
+/// the block share its model state, so they are decoded and discarded.
+///
+/// The outer `Err` is an I/O failure, the inner one libzpaq's message.
+pub(crate) fn read_segment_at<R: Read + Seek + Send>(
+    source: &mut ArchiveSource<R>,
+    location: &VerifyLocation,
+    ordinal: usize,
+) -> Result<std::result::Result<(Vec<u8>, bool), String>> {
+    let segments = read_segments(source, location.offset, ordinal, ordinal + 1)?;
+    Ok(segments.and_then(|mut segments| match segments.pop() {
+        Some((name, data, intact)) if name == location.segment.as_bytes() => Ok((data, intact)),
+        _ => Err("block header damaged".into()),
+    }))
+}
+
+/// Decodes the first `count` segments of the block at `offset`, returning
+/// the name, data and SHA-1 check of each.
+pub(crate) fn read_segments_at<R: Read + Seek + Send>(
+    source: &mut ArchiveSource<R>,
+    offset: u64,
+    count: usize,
+) -> Result<std::result::Result<Vec<SegmentData>, String>> {
+    read_segments(source, offset, 0, count)
+}
+
+/// Name, data and whether the data matches its SHA-1 trailer (if any).
+pub(crate) type SegmentData = (Vec<u8>, Vec<u8>, bool);
+
+fn read_segments<R: Read + Seek + Send>(
+    source: &mut ArchiveSource<R>,
+    offset: u64,
+    keep_from: usize,
+    count: usize,
+) -> Result<std::result::Result<Vec<SegmentData>, String>> {
+    source.seek_to(offset)?;
+    clear_last_error()?;
+    let decompresser = handle::Decompresser::new()?;
+    let result = FfiReader::new(&mut *source)
+        .and_then(|reader| decode_segments(decompresser.raw, reader.raw, keep_from, count));
+    drop(decompresser);
+    if let Some(err) = source.take_io_error() {
+        return Err(err);
+    }
+    Ok(result.map_err(|err| match err {
+        ZpaqError::Ffi(message) => message,
+        other => other.to_string(),
+    }))
+}
+
+/// Decodes segments `0..count` of the block at the input, keeping those from
+/// `keep_from` on.
+fn decode_segments(
+    decompresser: *mut sys::Decompresser,
+    input: *mut sys::RustReader,
+    keep_from: usize,
+    count: usize,
+) -> Result<Vec<SegmentData>> {
+    if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+        return Err(err_from_last());
+    }
+    let rc_block = unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+    if rc_block < 0 {
+        return Err(err_from_last());
+    }
+    // A damaged tag makes find_block skip ahead to some other block.
+    if rc_block == 0 {
+        return Err(ZpaqError::Ffi("block header damaged".into()));
+    }
+    let mut segments = Vec::new();
+    for i in 0..count {
+        let filename = SharedVecWriter::new();
+        let filename_writer = FfiWriter::new(filename.clone())?;
+        let rc_filename =
+            unsafe { sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw) };
+        if rc_filename < 0 {
+            return Err(err_from_last());
+        }
+        drop(filename_writer);
+        if rc_filename == 0 {
+            return Err(ZpaqError::Ffi("block header damaged".into()));
+        }
+        if unsafe { sys::zpaq_decompresser_read_comment(decompresser, ptr::null_mut()) } != 0 {
+            return Err(err_from_last());
+        }
+        if i < keep_from {
+            skip_segment(decompresser)?;
+            if unsafe { sys::zpaq_decompresser_read_segment_end(decompresser, ptr::null_mut()) }
+                != 0
+            {
+                return Err(err_from_last());
+            }
+            continue;
+        }
+
+        let out = SharedVecWriter::new();
+        let out_writer = FfiWriter::new(out.clone())?;
+        let mut hasher = Sha1Hasher::new()?;
+        if unsafe { sys::zpaq_decompresser_set_sha1(decompresser, hasher.raw()) } != 0 {
+            return Err(err_from_last());
+        }
+        decompress_to(decompresser, out_writer.raw)?;
+        if unsafe { sys::zpaq_decompresser_set_sha1(decompresser, ptr::null_mut()) } != 0 {
+            return Err(err_from_last());
+        }
+        let mut segment_end = [0u8; 21];
+        if unsafe {
+            sys::zpaq_decompresser_read_segment_end(decompresser, segment_end.as_mut_ptr())
+        } != 0
+        {
+            return Err(err_from_last());
+        }
+        drop(out_writer);
+        let data = out.bytes();
+        let intact = segment_end[0] != 1 || segment_end[1..] == hasher.finish()?;
+        segments.push((filename.bytes(), data, intact));
+    }
+    Ok(segments)
+}
+
+/// Decodes the current segment's data to `out`.
+pub(crate) fn decompress_to(
+    decompresser: *mut sys::Decompresser,
+    out: *mut sys::RustWriter,
+) -> Result<()> {
+    if unsafe { sys::zpaq_decompresser_set_output(decompresser, out) } != 0 {
+        return Err(err_from_last());
+    }
+    loop {
+        let rc = unsafe { sys::zpaq_decompresser_decompress(decompresser, 1 << 20) };
+        if rc < 0 {
+            return Err(err_from_last());
+        }
+        if rc == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// Receives streaming (non-journaling) segments as they are decoded.
+pub(crate) trait SegmentSink {
+    /// A segment was decoded.  `intact` is `false` if its SHA-1 trailer does
+    /// not match `data`.
+    fn segment(&mut self, location: &VerifyLocation, comment: &[u8], data: Vec<u8>, intact: bool);
+    /// Decoding failed at `location`; the rest of that block is lost.
+    fn damaged(&mut self, location: &VerifyLocation);
+    /// An `i` block of a complete transaction added `entries`; `tables` are
+    /// the `h` blocks read so far.
+    fn index_entries(&mut self, _entries: &[IndexEntry], _tables: &[HashTable]) {}
+}
+
+/// A pass over an archive's blocks, with what it found.
+///
+/// `S` is the type of the sink; scans kept after they run, such as an
+/// [`ArchiveReader`](crate::ArchiveReader)'s, use `dyn SegmentSink + Send`
+/// so that holding one, without a sink, keeps its owner `Send`.
+pub(crate) struct Scan<'a, S: ?Sized = dyn SegmentSink> {
+    pub(crate) deep: bool,
+    /// With `deep`, decode only the `d` blocks of versions after this many.
+    pub(crate) deep_after: usize,
+    pub(crate) report: VerifyReport,
+    transactions: Vec<Transaction>,
+    pub(crate) data_blocks: Vec<DataBlock>,
+    pub(crate) tables: Vec<HashTable>,
+    pub(crate) index: Vec<IndexEntry>,
+    /// Offset of the first incomplete transaction, where `zpaq` stops
+    /// reading.
+    pub(crate) incomplete: Option<u64>,
+    /// If set, streaming segments are decoded and passed here.
+    pub(crate) sink: Option<&'a mut S>,
+    /// Outcomes of deep decoding recorded by earlier runs, and where this
+    /// run records its own.
+    state: Option<Box<VerifyState>>,
+    /// Checked before each block.
+    cancel: Option<CancellationToken>,
+}
+
+impl Default for Scan<'_> {
+    fn default() -> Self {
+        Scan {
+            deep: false,
+            deep_after: 0,
+            report: VerifyReport::default(),
+            transactions: Vec::new(),
+            data_blocks: Vec::new(),
+            tables: Vec::new(),
+            index: Vec::new(),
+            incomplete: None,
+            sink: None,
+            state: None,
+            cancel: None,
+        }
+    }
+}
+
+/// What to do with a segment's data.
+enum Decode {
+    Skip,
+    Keep,
+    Hash,
+}
+
+/// What the scan has of a journaling block's data.
+enum Contents {
+    /// Nothing: the data was skipped.
+    Skipped,
+    /// The data of a `c`, `h` or `i` block.
+    Data(Vec<u8>),
+    /// The `(size, sha1)` fragments of a decoded `d` block, or why its data
+    /// did not split into fragments.
+    Fragments(std::result::Result<Vec<(u32, [u8; 20])>, String>),
+    /// The same for a `d` block an earlier run decoded, with the digest of
+    /// the fragments (see [`fragments_digest`]) in place of the fragments.
+    Recorded(std::result::Result<[u8; 20], String>),
+}
+
+impl<'a, S: SegmentSink + ?Sized> Scan<'a, S> {
+    pub(crate) fn with_sink(sink: &'a mut S) -> Self {
+        Scan {
+            deep: false,
+            deep_after: 0,
+            report: VerifyReport::default(),
+            transactions: Vec::new(),
+            data_blocks: Vec::new(),
+            tables: Vec::new(),
+            index: Vec::new(),
+            incomplete: None,
+            sink: Some(sink),
+            state: None,
+            cancel: None,
+        }
+    }
+
+    /// What the scan found, without its sink, to keep.
+    pub(crate) fn detach(self) -> Scan<'static, dyn SegmentSink + Send> {
+        Scan {
+            deep: self.deep,
+            deep_after: self.deep_after,
+            report: self.report,
+            transactions: self.transactions,
+            data_blocks: self.data_blocks,
+            tables: self.tables,
+            index: self.index,
+            incomplete: self.incomplete,
+            sink: None,
+            state: None,
+            cancel: None,
+        }
+    }
+
+    /// Number of versions `zpaq` would list: complete transactions before
+    /// the first incomplete one.
+    pub(crate) fn versions(&self) -> usize {
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        self.transactions
+            .iter()
+            .filter(|t| t.location.offset < end)
+            .count()
+    }
+
+    /// Date (`YYYYMMDDHHMMSS`) of the last version `zpaq` would list.
+    pub(crate) fn last_date(&self) -> Option<u64> {
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        let last = self
+            .transactions
+            .iter()
+            .rev()
+            .find(|t| t.location.offset < end)?;
+        last.location.segment.get(3..17)?.parse().ok()
+    }
+
+    /// Date (`YYYYMMDDHHMMSS`) of each version `zpaq` would list.
+    pub(crate) fn version_dates(&self) -> Vec<u64> {
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        self.transactions
+            .iter()
+            .filter(|t| t.location.offset < end)
+            .map(|t| {
+                t.location
+                    .segment
+                    .get(3..17)
+                    .and_then(|date| date.parse().ok())
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// Offset and `d` block bytes (as its `h` tables record them) of each
+    /// version `zpaq` would list.
+    pub(crate) fn transaction_extents(&self) -> Vec<(u64, u64)> {
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        self.transactions
+            .iter()
+            .filter(|t| t.location.offset < end)
+            .map(|t| (t.location.offset, t.table_total))
+            .collect()
+    }
+
+    /// Index entries live after `version` (deletions applied), by path, as
+    /// `zpaq` would read them.
+    pub(crate) fn files_at(&self, version: usize) -> BTreeMap<&str, &IndexEntry> {
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        let mut files = BTreeMap::new();
+        for entry in &self.index {
+            if entry.version > version || entry.location.offset >= end {
+                continue;
+            }
+            if entry.date == 0 {
+                files.remove(entry.file.as_str());
+            } else {
+                files.insert(entry.file.as_str(), entry);
+            }
+        }
+        files
+    }
+
+    /// `(size, sha1)` of every fragment described by an `h` table, by ID.
+    pub(crate) fn fragments(&self) -> HashMap<u32, (u32, [u8; 20])> {
+        let mut fragments = HashMap::new();
+        for table in &self.tables {
+            for (i, &entry) in table.fragments.iter().enumerate() {
+                fragments.insert(table.first + i as u32, entry);
+            }
+        }
+        fragments
+    }
+
+    /// Files stored by the versions after `versions` whose stored size is
+    /// not the size of the regular file at that path now.  Reading a file
+    /// in text mode (which translates line endings on Windows) stores
+    /// consistent but altered data, so only this comparison catches it.
+    /// Paths that are gone or no longer regular files are skipped.
+    fn differing_from_disk(&self, versions: usize) -> Vec<VerifyError> {
+        let fragments = self.fragments();
+        let end = self.incomplete.unwrap_or(u64::MAX);
+        let mut errors = Vec::new();
+        for entry in &self.index {
+            if entry.version <= versions
+                || entry.location.offset >= end
+                || entry.date == 0
+                || entry.file.ends_with('/')
+            {
+                continue;
+            }
+            let Some(stored) = entry
+                .fragments
+                .iter()
+                .map(|id| fragments.get(id).map(|&(size, _)| size as u64))
+                .sum::<Option<u64>>()
+            else {
+                continue;
+            };
+            let Ok(meta) = std::fs::symlink_metadata(&entry.file) else {
+                continue;
+            };
+            if meta.is_file() && meta.len() != stored {
+                errors.push(VerifyError {
+                    location: VerifyLocation {
+                        file: Some(entry.file.clone()),
+                        ..entry.location.clone()
+                    },
+                    kind: VerifyErrorKind::SizeMismatch {
+                        expected: stored,
+                        actual: meta.len(),
+                    },
+                });
+            }
+        }
+        errors
+    }
+
+    /// Whether `d` blocks read now are decoded.
+    fn deep_here(&self) -> bool {
+        self.deep && self.transactions.len() > self.deep_after
+    }
+
+    fn error(&mut self, location: VerifyLocation, kind: VerifyErrorKind) {
+        self.report.errors.push(VerifyError { location, kind });
+    }
+
+    /// Scans the whole archive from the source's current position, resuming
+    /// after each decode error.
+    pub(crate) fn scan<R: Read + Seek + Send>(
+        &mut self,
+        source: &mut ArchiveSource<R>,
+    ) -> Result<()> {
+        let mut resume = source.position();
+        loop {
+            source.seek_to(resume)?;
+            match self.run(source)? {
+                None => break,
+                Some(next) => resume = next.max(resume + 1),
+            }
+        }
+        if self.report.blocks_checked == 0 && source.position() > source.data_start {
+            self.error(
+                VerifyLocation::default(),
+                VerifyErrorKind::Malformed("archive contains no data".into()),
+            );
+        }
+        Ok(())
+    }
+
+    /// Scans blocks from the source's current position.  Returns `None` at
+    /// the end of the archive or the offset to resume at after a decode error.
+    fn run<R: Read + Seek + Send>(&mut self, source: &mut ArchiveSource<R>) -> Result<Option<u64>> {
+        let pos = source.state.clone();
+        clear_last_error()?;
+        let reader = FfiReader::new(&mut *source)?;
+        let decompresser = handle::Decompresser::new()?;
+        let mut location = VerifyLocation {
+            offset: pos.pos(),
+            ..Default::default()
+        };
+        let result = self.read_blocks(decompresser.raw, reader.raw, &pos, &mut location);
+        let resume = match result {
+            Ok(()) => None,
+            Err(err @ (ZpaqError::Cancelled | ZpaqError::Io { .. })) => return Err(err),
+            Err(_) if pos.io_error.lock().expect("poisoned").is_some() => {
+                return Err(pos.take_io_error().expect("I/O error"));
+            }
+            Err(err) => {
+                let buffered = unsafe { sys::zpaq_decompresser_buffered(decompresser.raw) }.max(0);
+                let message = match err {
+                    ZpaqError::Ffi(message) => message,
+                    other => other.to_string(),
+                };
+                if let Some(sink) = self.sink.as_mut() {
+                    sink.damaged(&location);
+                }
+                self.error(location, VerifyErrorKind::Undecodable(message));
+                Some(pos.pos().saturating_sub(buffered as u64))
+            }
+        };
+        Ok(resume)
+    }
+
+    fn read_blocks(
+        &mut self,
+        decompresser: *mut sys::Decompresser,
+        input: *mut sys::RustReader,
+        pos: &SourceState,
+        location: &mut VerifyLocation,
+    ) -> Result<()> {
+        if unsafe { sys::zpaq_decompresser_set_input(decompresser, input) } != 0 {
+            return Err(err_from_last());
+        }
+        let offset = || {
+            let buffered = unsafe { sys::zpaq_decompresser_buffered(decompresser) }.max(0);
+            pos.pos().saturating_sub(buffered as u64)
+        };
+
+        loop {
+            if let Some(cancel) = &self.cancel {
+                cancel.check()?;
+            }
+            *location = VerifyLocation {
+                offset: offset(),
+                ..Default::default()
+            };
+            let rc_block =
+                unsafe { sys::zpaq_decompresser_find_block(decompresser, ptr::null_mut()) };
+            if rc_block < 0 {
+                return Err(err_from_last());
+            }
+            if rc_block == 0 {
+                return Ok(());
+            }
+            self.report.blocks_checked += 1;
+
+            let block = location.offset;
+            let mut pending = Vec::new();
+            let mut outcomes = Vec::new();
+            for ordinal in 0u32.. {
+                let filename = SharedVecWriter::new();
+                let filename_writer = FfiWriter::new(filename.clone())?;
+                let rc_filename = unsafe {
+                    sys::zpaq_decompresser_find_filename(decompresser, filename_writer.raw)
+                };
+                if rc_filename < 0 {
+                    return Err(err_from_last());
+                }
+                if rc_filename == 0 {
+                    break;
+                }
+                drop(filename_writer);
+                location.segment = String::from_utf8_lossy(&filename.bytes()).into_owned();
+
+                let comment = SharedVecWriter::new();
+                let comment_writer = FfiWriter::new(comment.clone())?;
+                if unsafe { sys::zpaq_decompresser_read_comment(decompresser, comment_writer.raw) }
+                    != 0
+                {
+                    return Err(err_from_last());
+                }
+                drop(comment_writer);
+                let comment = comment.bytes();
+                self.report.segments_checked += 1;
+
+                let journal = if comment.ends_with(b"jDC\x01") {
+                    match parse_journal_name(&location.segment) {
+                        Some(name) => Some(name),
+                        None => {
+                            self.error(
+                                location.clone(),
+                                VerifyErrorKind::Malformed("bad journaling block name".into()),
+                            );
+                            None
+                        }
+                    }
+                } else {
+                    None
+                };
+                let checksum = match journal {
+                    Some(_) => None,
+                    None => comment_checksum(&comment),
+                };
+                // Only these are decoded for the sake of deep mode, and an
+                // earlier run may have recorded what they decode to.
+                let deep_only = match &journal {
+                    Some(name) => name.kind == b'd' && self.deep_here(),
+                    None => self.deep && self.sink.is_none(),
+                };
+                let recorded = match &self.state {
+                    Some(state) if deep_only => state.recorded(block, ordinal).cloned(),
+                    _ => None,
+                };
+                let decode = match &journal {
+                    _ if recorded.is_some() => Decode::Skip,
+                    Some(name) if name.kind != b'd' || self.deep_here() => Decode::Keep,
+                    None if self.sink.is_some() => Decode::Keep,
+                    // The extra checksum is computed here, from the data.
+                    None if self.deep && checksum.is_some() => Decode::Keep,
+                    None if self.deep => Decode::Hash,
+                    _ => Decode::Skip,
+                };
+
+                let (data, digest, size) = self.read_segment(decompresser, &decode)?;
+                let mut segment_end = [0u8; 21];
+                let rc_end = unsafe {
+                    sys::zpaq_decompresser_read_segment_end(decompresser, segment_end.as_mut_ptr())
+                };
+                if rc_end != 0 {
+                    return Err(err_from_last());
+                }
+                let is_data = journal.as_ref().is_some_and(|name| name.kind == b'd');
+                let (intact, extra_checked, size, contents) = match recorded {
+                    Some(outcome) => {
+                        let contents = match is_data {
+                            true => Contents::Recorded(outcome.fragments),
+                            false => Contents::Skipped,
+                        };
+                        (
+                            outcome.intact,
+                            outcome.extra_checked,
+                            outcome.size,
+                            contents,
+                        )
+                    }
+                    None if matches!(decode, Decode::Skip) => {
+                        if let Some(name) = journal {
+                            pending.push((location.clone(), name, Contents::Skipped));
+                        }
+                        continue;
+                    }
+                    None => {
+                        let mut intact = segment_end[0] != 1 || segment_end[1..] == digest;
+                        if let Some(expected) = checksum {
+                            let actual = match expected {
+                                Digest::Sha1(_) => Digest::Sha1(sha1(&data)?),
+                                Digest::Sha256(_) => Digest::Sha256(sha256(&data)?),
+                            };
+                            intact &= actual == expected;
+                        }
+                        let fragments =
+                            is_data.then(|| split_fragments(&data).map_err(String::from));
+                        if deep_only && self.state.is_some() {
+                            outcomes.push(SegmentOutcome {
+                                ordinal,
+                                intact,
+                                extra_checked: checksum.is_some(),
+                                size,
+                                fragments: match &fragments {
+                                    Some(Ok(list)) => Ok(fragments_digest(list)?),
+                                    Some(Err(msg)) => Err(msg.clone()),
+                                    None => Ok([0; 20]),
+                                },
+                            });
+                        }
+                        let contents = match fragments {
+                            Some(fragments) => Contents::Fragments(fragments),
+                            None => Contents::Data(data),
+                        };
+                        (intact, checksum.is_some(), size, contents)
+                    }
+                };
+                if extra_checked {
+                    self.report.extra_checksums_checked += 1;
+                }
+                if !intact {
+                    self.error(location.clone(), VerifyErrorKind::ChecksumMismatch);
+                }
+                let Some(name) = journal else {
+                    if let (Some(sink), Contents::Data(data)) = (self.sink.as_mut(), contents) {
+                        sink.segment(location, &comment, data, intact);
+                    }
+                    continue;
+                };
+                // Fragment checks still pinpoint the damage in a d block.
+                if !intact && name.kind != b'd' {
+                    continue;
+                }
+                let declared = journal_size(&comment).unwrap_or(u64::MAX);
+                if declared != size {
+                    self.error(
+                        location.clone(),
+                        VerifyErrorKind::SizeMismatch {
+                            expected: declared,
+                            actual: size,
+                        },
+                    );
+                    continue;
+                }
+                pending.push((location.clone(), name, contents));
+            }
+
+            // The block is complete; its end is the next block's start.
+            let end = offset();
+            for (location, name, contents) in pending {
+                self.journal_block(location, name, contents, end);
+            }
+            if let Some(state) = &mut self.state
+                && !outcomes.is_empty()
+            {
+                state.record(block, &outcomes)?;
+            }
+        }
+    }
+
+    /// Decodes (or skips) the current segment's data.
+    fn read_segment(
+        &mut self,
+        decompresser: *mut sys::Decompresser,
+        decode: &Decode,
+    ) -> Result<(Vec<u8>, [u8; 20], u64)> {
+        if let Decode::Skip = decode {
+            return Ok((Vec::new(), [0; 20], 0));
+        }
+        #[cfg(feature = "stats")]
+        crate::stats::segment_decoded();
+        // The shim hashes what it decodes; only kept data crosses into Rust.
+        let kept = SharedVecWriter::new();
+        let mut hasher = Sha1Hasher::new()?;
+        {
+            let kept_writer;
+            let raw = match decode {
+                Decode::Keep => {
+                    kept_writer = FfiWriter::new(kept.clone())?;
+                    kept_writer.raw
+                }
+                _ => ptr::null_mut(),
+            };
+            if unsafe { sys::zpaq_decompresser_set_output(decompresser, raw) } != 0
+                || unsafe { sys::zpaq_decompresser_set_sha1(decompresser, hasher.raw()) } != 0
+            {
+                return Err(err_from_last());
+            }
+            loop {
+                let rc = unsafe { sys::zpaq_decompresser_decompress(decompresser, 1 << 20) };
+                if rc < 0 {
+                    return Err(err_from_last());
+                }
+                if rc == 0 {
+                    break;
+                }
+            }
+            if unsafe { sys::zpaq_decompresser_set_output(decompresser, ptr::null_mut()) } != 0
+                || unsafe { sys::zpaq_decompresser_set_sha1(decompresser, ptr::null_mut()) } != 0
+            {
+                return Err(err_from_last());
+            }
+        }
+        let size = hasher.len();
+        let digest = hasher.finish()?;
+        Ok((kept.bytes(), digest, size))
+    }
+
+    fn journal_block(
+        &mut self,
+        location: VerifyLocation,
+        name: JournalName,
+        contents: Contents,
+        end: u64,
+    ) {
+        let data = match &contents {
+            Contents::Data(data) => data.as_slice(),
+            _ => &[],
+        };
+        match name.kind {
+            b'c' => {
+                if data.len() < 8 {
+                    self.error(
+                        location,
+                        VerifyErrorKind::Malformed("c block too small".into()),
+                    );
+                    return;
+                }
+                let size = i64::from_le_bytes(data[..8].try_into().expect("8 bytes"));
+                if size < 0 {
+                    self.incomplete.get_or_insert(location.offset);
+                    self.error(location, VerifyErrorKind::IncompleteTransaction);
+                    return;
+                }
+                self.transactions.push(Transaction {
+                    location,
+                    data_size: size as u64,
+                    data_offset: end,
+                    table_total: 0,
+                });
+            }
+            b'd' => {
+                let (fragments, recorded) = match contents {
+                    Contents::Fragments(Ok(fragments)) => (Some(fragments), None),
+                    Contents::Recorded(Ok(digest)) => (None, Some(digest)),
+                    Contents::Fragments(Err(msg)) | Contents::Recorded(Err(msg)) => {
+                        self.error(location, VerifyErrorKind::Malformed(msg));
+                        return;
+                    }
+                    Contents::Skipped | Contents::Data(_) => (None, None),
+                };
+                self.data_blocks.push(DataBlock {
+                    location,
+                    end,
+                    first: name.num,
+                    fragments,
+                    recorded,
+                });
+            }
+            b'h' => {
+                if data.len() % 24 != 4 {
+                    self.error(
+                        location,
+                        VerifyErrorKind::Malformed("bad h block size".into()),
+                    );
+                    return;
+                }
+                if name.num == 0 {
+                    self.error(
+                        location,
+                        VerifyErrorKind::Malformed("bad h fragment".into()),
+                    );
+                    return;
+                }
+                let Some(transaction) = self.transactions.last_mut() else {
+                    self.error(
+                        location,
+                        VerifyErrorKind::Malformed("h block outside a transaction".into()),
+                    );
+                    return;
+                };
+                let data_size = le32(data) as u64;
+                let data_offset = transaction.data_offset + transaction.table_total;
+                transaction.table_total += data_size;
+                let fragments = data[4..]
+                    .chunks_exact(24)
+                    .map(|entry| {
+                        let mut sha1 = [0u8; 20];
+                        sha1.copy_from_slice(&entry[..20]);
+                        (le32(&entry[20..]), sha1)
+                    })
+                    .collect();
+                self.tables.push(HashTable {
+                    location,
+                    first: name.num,
+                    data_offset,
+                    data_size,
+                    fragments,
+                });
+            }
+            b'i' => {
+                let start = self.index.len();
+                if let Err(msg) = self.parse_index(&location, data) {
+                    self.error(location.clone(), VerifyErrorKind::Malformed(msg.into()));
+                }
+                if self.incomplete.is_none_or(|end| location.offset < end)
+                    && let Some(sink) = self.sink.as_mut()
+                {
+                    sink.index_entries(&self.index[start..], &self.tables);
+                }
+            }
+            _ => self.error(
+                location,
+                VerifyErrorKind::Malformed("unexpected journaling block".into()),
+            ),
+        }
+    }
+
+    fn parse_index(
+        &mut self,
+        location: &VerifyLocation,
+        data: &[u8],
+    ) -> std::result::Result<(), &'static str> {
+        let mut s = data;
+        while s.len() >= 9 {
+            let date = u64::from_le_bytes(s[..8].try_into().expect("8 bytes"));
+            s = &s[8..];
+            let len = s.iter().position(|&b| b == 0).ok_or("filename too long")?;
+            let file = String::from_utf8_lossy(&s[..len]).into_owned();
+            s = &s[len + 1..];
+            let mut entry = IndexEntry {
+                location: location.clone(),
+                version: self.transactions.len(),
+                file,
+                date,
+                attr: Vec::new(),
+                fragments: Vec::new(),
+            };
+            if date == 0 {
+                self.index.push(entry);
+                continue;
+            }
+            if s.len() < 4 {
+                return Err("missing attr");
+            }
+            let na = le32(s) as usize;
+            s = &s[4..];
+            if na > s.len() || na > 65535 {
+                return Err("attr too long");
+            }
+            entry.attr = s[..na].to_vec();
+            s = &s[na..];
+            if s.len() < 4 {
+                return Err("missing ptr");
+            }
+            let ni = le32(s) as usize;
+            s = &s[4..];
+            if ni > s.len() / 4 {
+                return Err("ptr list too long");
+            }
+            entry.fragments = s[..ni * 4].chunks_exact(4).map(le32).collect();
+            self.index.push(entry);
+            s = &s[ni * 4..];
+        }
+        Ok(())
+    }
+
+    /// Decodes again each `d` block an earlier run recorded (see
+    /// [`VerifyOptions::persist_state`]) whose fragments differ from its `h`
+    /// table's, so that [`cross_check`](Self::cross_check) can tell which.
+    /// Blocks that match need no fragments there.
+    fn decode_recorded<R: Read + Seek + Send>(
+        &mut self,
+        source: &mut ArchiveSource<R>,
+    ) -> Result<()> {
+        let tables: HashMap<(u64, u32), &HashTable> = self
+            .tables
+            .iter()
+            .map(|t| ((t.data_offset, t.first), t))
+            .collect();
+        for data in &mut self.data_blocks {
+            let Some(recorded) = data.recorded else {
+                continue;
+            };
+            let Some(table) = tables.get(&(data.location.offset, data.first)) else {
+                continue;
+            };
+            if fragments_digest(&table.fragments)? == recorded {
+                continue;
+            }
+            #[cfg(feature = "stats")]
+            crate::stats::segment_decoded();
+            if let Ok((decoded, _)) = read_segment_at(source, &data.location, 0)? {
+                data.fragments = split_fragments(&decoded).ok();
+            }
+        }
+        Ok(())
+    }
+
+    fn cross_check(&mut self) {
+        let data_at: HashMap<u64, usize> = self
+            .data_blocks
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (d.location.offset, i))
+            .collect();
+
+        let mut errors = Vec::new();
+        for transaction in &self.transactions {
+            if transaction.table_total != transaction.data_size {
+                errors.push(VerifyError {
+                    location: transaction.location.clone(),
+                    kind: VerifyErrorKind::SizeMismatch {
+                        expected: transaction.data_size,
+                        actual: transaction.table_total,
+                    },
+                });
+            }
+        }
+
+        let mut described = HashSet::new();
+        for table in &self.tables {
+            described.extend((0..table.fragments.len() as u32).map(|i| table.first + i));
+            let Some(data) = data_at
+                .get(&table.data_offset)
+                .map(|&i| &self.data_blocks[i])
+                .filter(|d| d.first == table.first)
+            else {
+                errors.push(VerifyError {
+                    location: VerifyLocation {
+                        fragment: Some(table.first),
+                        ..table.location.clone()
+                    },
+                    kind: VerifyErrorKind::MissingDataBlock,
+                });
+                continue;
+            };
+            let actual = data.end - data.location.offset;
+            if actual != table.data_size {
+                errors.push(VerifyError {
+                    location: data.location.clone(),
+                    kind: VerifyErrorKind::SizeMismatch {
+                        expected: table.data_size,
+                        actual,
+                    },
+                });
+            }
+            let Some(fragments) = &data.fragments else {
+                continue;
+            };
+            if fragments.len() != table.fragments.len() {
+                errors.push(VerifyError {
+                    location: data.location.clone(),
+                    kind: VerifyErrorKind::SizeMismatch {
+                        expected: table.fragments.len() as u64,
+                        actual: fragments.len() as u64,
+                    },
+                });
+                continue;
+            }
+            for (i, (found, recorded)) in fragments.iter().zip(&table.fragments).enumerate() {
+                if found != recorded {
+                    errors.push(VerifyError {
+                        location: VerifyLocation {
+                            fragment: Some(data.first + i as u32),
+                            ..data.location.clone()
+                        },
+                        kind: VerifyErrorKind::FragmentMismatch,
+                    });
+                }
+            }
+        }
+
+        for entry in &self.index {
+            for &fragment in &entry.fragments {
+                if !described.contains(&fragment) {
+                    errors.push(VerifyError {
+                        location: VerifyLocation {
+                            fragment: Some(fragment),
+                            file: Some(entry.file.clone()),
+                            ..entry.location.clone()
+                        },
+                        kind: VerifyErrorKind::MissingFragment,
+                    });
+                }
+            }
+        }
+        self.report.errors.extend(errors);
+    }
+}
+
+/// Reads the fragment sizes from the table at the end of a decoded `d`
+/// block.
+///
+/// Layout: fragment data, then one 4-byte size per fragment, then a 4-byte
+/// first fragment ID (written as 0) and a 4-byte fragment count.
+pub(crate) fn fragment_sizes(data: &[u8]) -> std::result::Result<Vec<u32>, &'static str> {
+    if data.len() < 8 {
+        return Err("d block too small");
+    }
+    let count = le32(&data[data.len() - 4..]) as usize;
+    let table_len = count
+        .checked_mul(4)
+        .and_then(|n| n.checked_add(8))
+        .filter(|&n| n <= data.len())
+        .ok_or("bad fragment count")?;
+    let table = &data[data.len() - table_len..data.len() - 8];
+    let sizes: Vec<u32> = table.chunks_exact(4).map(le32).collect();
+    let total: u64 = sizes.iter().map(|&s| s as u64).sum();
+    if total != (data.len() - table_len) as u64 {
+        return Err("fragment sizes do not add up");
+    }
+    Ok(sizes)
+}
+
+/// Splits a decoded `d` block into per-fragment `(size, sha1)` pairs.
+fn split_fragments(data: &[u8]) -> std::result::Result<Vec<(u32, [u8; 20])>, &'static str> {
+    let sizes = fragment_sizes(data)?;
+    let mut fragments = Vec::with_capacity(sizes.len());
+    let mut rest = data;
+    for size in sizes {
+        let (fragment, tail) = rest.split_at(size as usize);
+        fragments.push((size, crate::sha1(fragment).map_err(|_| "sha1 failed")?));
+        rest = tail;
+    }
+    Ok(fragments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use crate::{ArchiveEntry, ZPAQ_TAG, archive_from_entries, zpaq_add, zpaq_command};
+
+    /// A two-file journaling archive, optionally encrypted.
+    fn journal_archive(key: Option<&str>) -> Vec<u8> {
+        let dir = TempDir::new("zpaq-rs-verify");
+        let mut inputs = Vec::new();
+        for (name, seed) in [("a.txt", 1u32), ("b.txt", 2)] {
+            let path = dir.join(name);
+            let text: String = (0..4000)
+                .map(|i| format!("line {} of file {name}\n", i * seed))
+                .collect();
+            std::fs::write(&path, text).expect("write input");
+            inputs.push(path.to_string_lossy().into_owned());
+        }
+        let archive = dir.join("test.zpaq");
+        let archive_s = archive.to_string_lossy().into_owned();
+        match key {
+            None => {
+                let refs: Vec<&str> = inputs.iter().map(String::as_str).collect();
+                zpaq_add(&archive_s, &refs, "1", 1).expect("zpaq add");
+            }
+            Some(key) => {
+                let mut args = vec!["add", &archive_s];
+                args.extend(inputs.iter().map(String::as_str));
+                args.extend(["-method", "1", "-threads", "1", "-key", key]);
+                zpaq_command(&args).expect("zpaq add -key");
+            }
+        }
+        std::fs::read(&archive).expect("read archive")
+    }
+
+    fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+        haystack[from..]
+            .windows(needle.len())
+            .position(|w| w == needle)
+            .map(|i| i + from)
+    }
+
+    /// Start and end of the compressed data of the first segment whose
+    /// journaling type is `kind`.
+    fn segment_data(archive: &[u8], kind: u8) -> (usize, usize) {
+        let mut from = 0;
+        loop {
+            let name = find(archive, b"jDC", from).expect("segment name");
+            from = name + 1;
+            if archive.get(name + 17) != Some(&kind) {
+                continue;
+            }
+            let comment_end = find(archive, b"jDC\x01", name + 28).expect("comment");
+            let start = comment_end + 4 + 2;
+            let end = find(archive, &ZPAQ_TAG, start).unwrap_or(archive.len());
+            return (start, end);
+        }
+    }
+
+    fn deep() -> VerifyOptions {
+        VerifyOptions {
+            deep: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn intact_archives_verify() {
+        let archive = journal_archive(None);
+        for options in [VerifyOptions::default(), deep()] {
+            let report = verify_archive(&archive, &options).expect("verify");
+            assert!(report.is_ok(), "{:?}", report.errors);
+            // One transaction: c, d, h and i blocks.
+            assert_eq!(report.blocks_checked, 4);
+            assert_eq!(report.segments_checked, 4);
+        }
+
+        let entries = [
+            ArchiveEntry {
+                path: "x.txt",
+                data: b"streaming format entry",
+                comment: None,
+            },
+            ArchiveEntry {
+                path: "y.txt",
+                data: b"another one",
+                comment: Some("note"),
+            },
+        ];
+        let streaming = archive_from_entries(&entries, "2").expect("archive");
+        let report = verify_archive(&streaming, &deep()).expect("verify");
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.segments_checked, 2);
+    }
+
+    #[test]
+    fn encrypted_archives_need_the_key() {
+        let archive = journal_archive(Some("secret"));
+        let keyed = VerifyOptions {
+            deep: true,
+            key: Some("secret".into()),
+            ..Default::default()
+        };
+        let report = verify_archive(&archive, &keyed).expect("verify");
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert_eq!(report.blocks_checked, 4);
+
+        let wrong = VerifyOptions {
+            key: Some("guess".into()),
+            ..keyed
+        };
+        assert!(verify_archive(&archive, &wrong).is_err());
+        let report = verify_archive(&archive, &deep()).expect("verify");
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn data_block_damage_is_localized() {
+        let archive = journal_archive(None);
+        let (start, end) = segment_data(&archive, b'd');
+        let mut damaged = archive.clone();
+        damaged[(start + end) / 2] ^= 0x10;
+
+        let report = verify_archive(&damaged, &deep()).expect("verify");
+        assert!(!report.is_ok());
+        for error in &report.errors {
+            assert_eq!(error.location.segment.as_bytes()[17], b'd', "{error}");
+            assert!(error.location.offset < start as u64, "{error}");
+        }
+        assert!(report.errors.iter().any(|e| matches!(
+            e.kind,
+            VerifyErrorKind::ChecksumMismatch
+                | VerifyErrorKind::FragmentMismatch
+                | VerifyErrorKind::Undecodable(_)
+        )));
+    }
+
+    #[test]
+    fn index_damage_is_localized() {
+        let archive = journal_archive(None);
+        let (start, end) = segment_data(&archive, b'i');
+        let mut damaged = archive.clone();
+        damaged[(start + end) / 2] ^= 0x10;
+
+        for options in [VerifyOptions::default(), deep()] {
+            let report = verify_archive(&damaged, &options).expect("verify");
+            assert!(!report.is_ok());
+            for error in &report.errors {
+                assert_eq!(error.location.segment.as_bytes()[17], b'i', "{error}");
+            }
+        }
+    }
+
+    #[test]
+    fn extra_checksums_are_stored_and_verified() {
+        use crate::test_support::text;
+        use crate::{
+            CompressOptions, HashKind, compress_to_vec_with, decompress_to_vec, list_blocks,
+        };
+
+        let data = text(3 << 20);
+        let options = CompressOptions {
+            filename: Some("data.txt".into()),
+            comment: Some("note".into()),
+            extra_checksum: Some(HashKind::Sha256),
+            ..Default::default()
+        };
+        // 1 MiB blocks.
+        let (mut compressed, _) = compress_to_vec_with(&data, "10", &options).expect("compress");
+        assert!(decompress_to_vec(&compressed).expect("decompress") == data);
+
+        let segments: Vec<_> = list_blocks(&compressed)
+            .expect("list")
+            .into_iter()
+            .flat_map(|block| block.segments)
+            .collect();
+        assert!(segments.len() > 1);
+        assert!(segments[0].comment.contains(" note sha256:"));
+        let mut at = 0;
+        for segment in &segments {
+            let part = &data[at..at + segment.size as usize];
+            assert_eq!(segment.sha1, Some(sha1(part).expect("sha1")));
+            assert_eq!(
+                segment.extra_checksum,
+                Some(Digest::Sha256(sha256(part).expect("sha256")))
+            );
+            at += segment.size as usize;
+        }
+
+        let report = verify_archive(&compressed, &deep()).expect("verify");
+        assert!(report.is_ok(), "{report}");
+        assert_eq!(report.extra_checksums_checked, segments.len() as u64);
+        let shallow = verify_archive(&compressed, &VerifyOptions::default()).expect("verify");
+        assert_eq!(shallow.extra_checksums_checked, 0);
+
+        // zpaq takes the checksum for part of the comment.
+        let dir = TempDir::new("zpaq-rs-verify-checksum");
+        let archive = dir.join("checked.zpaq").to_string_lossy().into_owned();
+        let out = dir.join("out").to_string_lossy().into_owned();
+        std::fs::write(&archive, &compressed).expect("write");
+        zpaq_command(&["extract", &archive, "-to", &out]).expect("extract");
+        let extracted = std::fs::read(dir.join("out").join("data.txt")).expect("read");
+        assert!(extracted == data);
+
+        // A checksum that no longer matches fails the segment, though its
+        // SHA-1 still does.
+        let word = compressed
+            .windows(7)
+            .position(|w| w == b"sha256:")
+            .expect("checksum")
+            + 7;
+        compressed[word] = if compressed[word] == b'0' { b'1' } else { b'0' };
+        let report = verify_archive(&compressed, &deep()).expect("verify");
+        let kinds: Vec<_> = report.errors.iter().map(|e| &e.kind).collect();
+        assert_eq!(kinds, [&VerifyErrorKind::ChecksumMismatch]);
+
+        let options = CompressOptions {
+            extra_checksum: Some(HashKind::Sha1),
+            ..Default::default()
+        };
+        let (compressed, _) = compress_to_vec_with(b"hello", "1", &options).expect("compress");
+        let segment = &list_blocks(&compressed).expect("list")[0].segments[0];
+        assert_eq!(
+            segment.comment,
+            format!("5 sha1:{}", display::hex(&sha1(b"hello").expect("sha1")))
+        );
+        assert_eq!(segment.extra_checksum, segment.sha1.map(Digest::Sha1));
+    }
+
+    /// A journaling archive of seven `d` blocks, the second damaged.
+    fn many_blocks_archive() -> Vec<u8> {
+        let dir = TempDir::new("zpaq-rs-verify-blocks");
+        let input = dir.join("letters.txt");
+        std::fs::write(&input, crate::test_support::letters(3, 6 << 20)).expect("write");
+        let archive = dir.join("blocks.zpaq").to_string_lossy().into_owned();
+        zpaq_add(&archive, &[&input.to_string_lossy()], "10", 1).expect("zpaq add");
+        let mut bytes = std::fs::read(&archive).expect("read archive");
+        let first = segment_data(&bytes, b'd').1;
+        let second = find(&bytes, &ZPAQ_TAG, first + 1).expect("second d block");
+        bytes[second + 100_000] ^= 0x10;
+        bytes
+    }
+
+    /// Cancels `token` once more than `at` bytes are read, wherever from.
+    struct CancelAt<'a> {
+        inner: std::io::Cursor<&'a [u8]>,
+        at: u64,
+        token: CancellationToken,
+    }
+
+    impl Read for CancelAt<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.at = self.at.saturating_sub(n as u64);
+            if self.at == 0 {
+                self.token.cancel();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Seek for CancelAt<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// `f`'s result, with the segments it decoded when the `stats` feature
+    /// counts them.
+    fn decoding<T>(f: impl FnOnce() -> T) -> (T, Option<u64>) {
+        #[cfg(feature = "stats")]
+        {
+            let (value, stats) = crate::StatsCollector::new().collect(f);
+            (value, Some(stats.segments_decoded))
+        }
+        #[cfg(not(feature = "stats"))]
+        (f(), None)
+    }
+
+    #[test]
+    fn interrupted_deep_verification_continues_where_it_stopped() {
+        let archive = many_blocks_archive();
+        let (expected, full) = decoding(|| verify_archive(&archive, &deep()).expect("verify"));
+        assert!(
+            expected
+                .errors
+                .iter()
+                .any(|e| e.kind == VerifyErrorKind::FragmentMismatch),
+            "{expected}"
+        );
+
+        let dir = TempDir::new("zpaq-rs-verify-state");
+        let state = dir.join("verify.state");
+        let token = CancellationToken::new();
+        let options = VerifyOptions {
+            deep: true,
+            persist_state: Some(state.clone()),
+            cancel: Some(token.clone()),
+            ..Default::default()
+        };
+        let reader = CancelAt {
+            inner: std::io::Cursor::new(&archive),
+            at: archive.len() as u64 / 2,
+            token,
+        };
+        let stopped = verify_archive_reader(reader, &options);
+        assert!(matches!(stopped, Err(ZpaqError::Cancelled)), "{stopped:?}");
+        let recorded = VerifyState::open(&state, &mut std::io::Cursor::new(&archive))
+            .expect("state")
+            .recorded_segments();
+        assert!((2..7).contains(&recorded), "{recorded}");
+
+        // The rest, then nothing more: the reports are a single run's.
+        let options = VerifyOptions {
+            cancel: None,
+            ..options
+        };
+        let (resumed, rest) = decoding(|| verify_archive(&archive, &options).expect("verify"));
+        assert_eq!(resumed, expected);
+        let (again, none) = decoding(|| verify_archive(&archive, &options).expect("verify"));
+        assert_eq!(again, expected);
+        // A record cut short by a crash is dropped and its block redone.
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&state)
+            .expect("open state");
+        let len = file.metadata().expect("stat").len();
+        file.set_len(len - 3).expect("truncate");
+        assert_eq!(
+            verify_archive(&archive, &options).expect("verify"),
+            expected
+        );
+        let shallow = VerifyOptions::default();
+        let (_, headers) = decoding(|| verify_archive(&archive, &shallow).expect("verify"));
+        if let (Some(full), Some(rest), Some(none), Some(headers)) = (full, rest, none, headers) {
+            // The damaged block, among the first recorded, is decoded again
+            // to pinpoint its fragments.
+            assert_eq!(rest, full - recorded as u64 + 1);
+            assert_eq!(none, headers + 1);
+        }
+
+        // An archive that changed starts over.
+        let mut grown = archive.clone();
+        grown.extend(crate::compress_to_vec(b"more", "1").expect("compress"));
+        let (fresh, all) = decoding(|| verify_archive(&grown, &deep()).expect("verify"));
+        let (restarted, redone) = decoding(|| verify_archive(&grown, &options).expect("verify"));
+        assert_eq!(restarted, fresh);
+        assert_eq!(redone, all);
+    }
+}
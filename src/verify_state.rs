@@ -0,0 +1,219 @@
+//! What a deep [`verify_archive`](crate::verify_archive) found in each
+//! block, saved as it goes so that a later run can skip those blocks.
+//!
+//! The file is a header followed by one record per block, appended as each
+//! block is checked:
+//!
+//! ```text
+//! header:  "zpaqvfy1"  archive length (u64)  SHA-1 of its last 64 KiB
+//! record:  payload length (u32)  payload  first 4 bytes of its SHA-1
+//! payload: block offset (u64), segment count (u32), per segment:
+//!          ordinal (u32)  flags (u8)  size (u64)  digest (20)
+//!          message length (u16)  message
+//! ```
+//!
+//! Integers are little-endian.  A record cut short by a crash fails its
+//! check and is dropped, with everything after it.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::{Result, ZpaqError, io_err, sha1};
+
+const MAGIC: &[u8; 8] = b"zpaqvfy1";
+const HEADER_LEN: usize = 36;
+/// Bytes at the end of the archive the header hashes.
+const TAIL: u64 = 64 << 10;
+
+const INTACT: u8 = 1;
+const EXTRA_CHECKED: u8 = 2;
+const SPLIT: u8 = 4;
+
+/// What decoding one segment found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SegmentOutcome {
+    /// Position of the segment in its block, from 0.
+    pub(crate) ordinal: u32,
+    /// Whether the data matched its SHA-1 trailer and extra checksum.
+    pub(crate) intact: bool,
+    /// Whether an extra checksum from the comment was checked.
+    pub(crate) extra_checked: bool,
+    /// Decoded size.
+    pub(crate) size: u64,
+    /// For a `d` block, the digest of its `(size, sha1)` fragment list (see
+    /// [`fragments_digest`]), or why the data did not split into fragments.
+    pub(crate) fragments: std::result::Result<[u8; 20], String>,
+}
+
+/// A state file open for appending, with the records it held.
+pub(crate) struct VerifyState {
+    path: PathBuf,
+    file: File,
+    recorded: HashMap<u64, Vec<SegmentOutcome>>,
+}
+
+impl VerifyState {
+    /// Opens the state at `path` for the archive in `archive`, starting it
+    /// afresh if it is missing, damaged, or was saved for other bytes.
+    /// Leaves `archive` where it was.
+    pub(crate) fn open<R: Read + Seek>(path: &Path, archive: &mut R) -> Result<Self> {
+        let header = fingerprint(archive)?;
+        let io = |e: std::io::Error| ZpaqError::Io {
+            path: path.to_path_buf(),
+            kind: e.kind(),
+            message: e.to_string(),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(io)?;
+        let mut saved = Vec::new();
+        file.read_to_end(&mut saved).map_err(io)?;
+
+        let mut recorded = HashMap::new();
+        let mut end = 0;
+        if saved.len() >= HEADER_LEN && saved[..HEADER_LEN] == header {
+            end = HEADER_LEN;
+            while let Some((offset, outcomes, len)) = parse_record(&saved[end..]) {
+                recorded.insert(offset, outcomes);
+                end += len;
+            }
+        }
+        // Drop what did not check out, so that appends follow good records.
+        file.set_len(end as u64).map_err(io)?;
+        file.seek(SeekFrom::Start(end as u64)).map_err(io)?;
+        if end == 0 {
+            file.write_all(&header).map_err(io)?;
+        }
+        Ok(VerifyState {
+            path: path.to_path_buf(),
+            file,
+            recorded,
+        })
+    }
+
+    /// The outcome recorded for segment `ordinal` of the block at `offset`.
+    pub(crate) fn recorded(&self, offset: u64, ordinal: u32) -> Option<&SegmentOutcome> {
+        self.recorded
+            .get(&offset)?
+            .iter()
+            .find(|outcome| outcome.ordinal == ordinal)
+    }
+
+    /// Appends the outcomes of the block at `offset`.
+    pub(crate) fn record(&mut self, offset: u64, outcomes: &[SegmentOutcome]) -> Result<()> {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&offset.to_le_bytes());
+        payload.extend_from_slice(&(outcomes.len() as u32).to_le_bytes());
+        for outcome in outcomes {
+            let (digest, message) = match &outcome.fragments {
+                Ok(digest) => (*digest, ""),
+                Err(message) => ([0; 20], message.as_str()),
+            };
+            let message = &message.as_bytes()[..message.len().min(u16::MAX as usize)];
+            let mut flags = 0;
+            if outcome.intact {
+                flags |= INTACT;
+            }
+            if outcome.extra_checked {
+                flags |= EXTRA_CHECKED;
+            }
+            if outcome.fragments.is_ok() {
+                flags |= SPLIT;
+            }
+            payload.extend_from_slice(&outcome.ordinal.to_le_bytes());
+            payload.push(flags);
+            payload.extend_from_slice(&outcome.size.to_le_bytes());
+            payload.extend_from_slice(&digest);
+            payload.extend_from_slice(&(message.len() as u16).to_le_bytes());
+            payload.extend_from_slice(message);
+        }
+        let mut record = Vec::with_capacity(payload.len() + 8);
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&payload);
+        record.extend_from_slice(&sha1(&payload)?[..4]);
+        self.file.write_all(&record).map_err(|e| ZpaqError::Io {
+            path: self.path.clone(),
+            kind: e.kind(),
+            message: e.to_string(),
+        })
+    }
+
+    /// Number of segments with a recorded outcome.
+    #[cfg(test)]
+    pub(crate) fn recorded_segments(&self) -> usize {
+        self.recorded.values().map(Vec::len).sum()
+    }
+}
+
+/// The digest [`SegmentOutcome::fragments`] records for a fragment list.
+pub(crate) fn fragments_digest(fragments: &[(u32, [u8; 20])]) -> Result<[u8; 20]> {
+    let mut bytes = Vec::with_capacity(fragments.len() * 24);
+    for (size, sha1) in fragments {
+        bytes.extend_from_slice(sha1);
+        bytes.extend_from_slice(&size.to_le_bytes());
+    }
+    sha1(&bytes)
+}
+
+/// The header of a state file for the archive in `archive`.
+fn fingerprint<R: Read + Seek>(archive: &mut R) -> Result<[u8; HEADER_LEN]> {
+    let position = archive.stream_position().map_err(io_err)?;
+    let len = archive.seek(SeekFrom::End(0)).map_err(io_err)?;
+    let start = len.saturating_sub(TAIL);
+    archive.seek(SeekFrom::Start(start)).map_err(io_err)?;
+    let mut tail = vec![0u8; (len - start) as usize];
+    archive.read_exact(&mut tail).map_err(io_err)?;
+    archive.seek(SeekFrom::Start(position)).map_err(io_err)?;
+
+    let mut header = [0u8; HEADER_LEN];
+    header[..8].copy_from_slice(MAGIC);
+    header[8..16].copy_from_slice(&len.to_le_bytes());
+    header[16..].copy_from_slice(&sha1(&tail)?);
+    Ok(header)
+}
+
+/// The record at the start of `bytes` and its length, or `None` if it is
+/// cut short or fails its check.
+fn parse_record(bytes: &[u8]) -> Option<(u64, Vec<SegmentOutcome>, usize)> {
+    let len = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?) as usize;
+    let payload = bytes.get(4..4 + len)?;
+    let check = bytes.get(4 + len..8 + len)?;
+    if sha1(payload).ok()?[..4] != *check {
+        return None;
+    }
+    let mut s = payload;
+    let mut take = |n: usize| {
+        let (head, tail) = s.split_at_checked(n)?;
+        s = tail;
+        Some(head)
+    };
+    let offset = u64::from_le_bytes(take(8)?.try_into().ok()?);
+    let count = u32::from_le_bytes(take(4)?.try_into().ok()?);
+    let mut outcomes = Vec::new();
+    for _ in 0..count {
+        let ordinal = u32::from_le_bytes(take(4)?.try_into().ok()?);
+        let flags = take(1)?[0];
+        let size = u64::from_le_bytes(take(8)?.try_into().ok()?);
+        let digest: [u8; 20] = take(20)?.try_into().ok()?;
+        let message_len = u16::from_le_bytes(take(2)?.try_into().ok()?) as usize;
+        let message = String::from_utf8_lossy(take(message_len)?).into_owned();
+        outcomes.push(SegmentOutcome {
+            ordinal,
+            intact: flags & INTACT != 0,
+            extra_checked: flags & EXTRA_CHECKED != 0,
+            size,
+            fragments: if flags & SPLIT != 0 {
+                Ok(digest)
+            } else {
+                Err(message)
+            },
+        });
+    }
+    Some((offset, outcomes, len + 8))
+}
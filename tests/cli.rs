@@ -0,0 +1,232 @@
+#![cfg(feature = "cli")]
+
+use std::fs;
+
+use assert_cmd::Command;
+use zpaq_rs::test_support::{self, TempDir};
+use zpaq_rs::{
+    ArchiveEntry, VerifyOptions, archive_from_entries, archive_list_entries, compress_size,
+    compress_size_parallel, compress_to_vec, verify_archive, zpaq_extract_bytes, zpaq_list_bytes,
+};
+
+fn zpaqrs() -> Command {
+    Command::cargo_bin("zpaqrs").expect("zpaqrs binary")
+}
+
+#[test]
+fn compress_decompress_and_size_match_the_library() {
+    let dir = TempDir::new("zpaq-rs-cli-stream");
+    let data = test_support::text(300_000);
+    let input = dir.join("input.txt");
+    fs::write(&input, &data).expect("write input");
+
+    // Files in, file out.
+    let compressed = dir.join("input.zpaq");
+    zpaqrs()
+        .args(["compress", "-m", "2"])
+        .arg(&input)
+        .arg(&compressed)
+        .assert()
+        .success();
+    let written = fs::read(&compressed).expect("read compressed");
+    assert_eq!(written, compress_to_vec(&data, "2").expect("library"));
+
+    // Pipes through `-`.
+    let out = zpaqrs()
+        .args(["compress", "-", "-"])
+        .write_stdin(data.clone())
+        .output()
+        .expect("run");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, compress_to_vec(&data, "1").expect("library"));
+    let out = zpaqrs()
+        .arg("decompress")
+        .write_stdin(written)
+        .output()
+        .expect("run");
+    assert!(out.status.success());
+    assert_eq!(out.stdout, data);
+
+    let size = |args: &[&str]| {
+        let out = zpaqrs()
+            .args(args)
+            .write_stdin(data.clone())
+            .output()
+            .expect("run");
+        assert!(out.status.success());
+        String::from_utf8(out.stdout)
+            .expect("utf-8")
+            .trim()
+            .parse::<u64>()
+    };
+    assert_eq!(
+        size(&["size", "-m", "2"]),
+        Ok(compress_size(&data, "2").expect("library"))
+    );
+    assert_eq!(
+        size(&["size", "-t", "4", "-"]),
+        Ok(compress_size_parallel(&data, "1", 4).expect("library"))
+    );
+
+    zpaqrs().args(["compress", "-x"]).assert().code(2);
+    zpaqrs().arg("frobnicate").assert().code(2);
+    zpaqrs()
+        .arg("decompress")
+        .arg(dir.join("missing.zpaq"))
+        .assert()
+        .code(1);
+}
+
+#[test]
+fn archives_are_added_listed_extracted_and_verified() {
+    let dir = TempDir::new("zpaq-rs-cli-archive");
+    let entries = [
+        ArchiveEntry {
+            path: "docs/a.txt",
+            data: b"alpha\n",
+            comment: None,
+        },
+        ArchiveEntry {
+            path: "docs/b.txt",
+            data: b"bravo bravo\n",
+            comment: None,
+        },
+        ArchiveEntry {
+            path: "c.bin",
+            data: &[7; 5000],
+            comment: None,
+        },
+    ];
+    let archive = archive_from_entries(&entries, "1").expect("archive");
+    let archive_path = dir.join("entries.zpaq");
+    fs::write(&archive_path, &archive).expect("write archive");
+
+    let expected: String = archive_list_entries(&archive)
+        .expect("library")
+        .iter()
+        .map(|e| format!("{:>12} {}\n", e.size, e.path))
+        .collect();
+    zpaqrs()
+        .arg("list")
+        .arg(&archive_path)
+        .assert()
+        .success()
+        .stdout(expected.clone());
+    zpaqrs()
+        .args(["list", "-"])
+        .write_stdin(archive.clone())
+        .assert()
+        .success()
+        .stdout(expected);
+
+    let out = dir.join("out");
+    zpaqrs()
+        .args(["extract", "-"])
+        .arg(&out)
+        .arg("docs/")
+        .write_stdin(archive.clone())
+        .assert()
+        .success();
+    assert_eq!(fs::read(out.join("docs/a.txt")).expect("a"), b"alpha\n");
+    assert_eq!(
+        fs::read(out.join("docs/b.txt")).expect("b"),
+        b"bravo bravo\n"
+    );
+    assert!(!out.join("c.bin").exists());
+
+    let report = verify_archive(
+        &archive,
+        &VerifyOptions {
+            deep: true,
+            ..Default::default()
+        },
+    )
+    .expect("library");
+    zpaqrs()
+        .args(["verify", "--deep"])
+        .arg(&archive_path)
+        .assert()
+        .success()
+        .stdout(report.to_string());
+    let mut damaged = archive.clone();
+    damaged.truncate(archive.len() - 10);
+    zpaqrs()
+        .args(["verify", "-"])
+        .write_stdin(damaged)
+        .assert()
+        .code(1);
+
+    // A journaling archive from files on disk, extracted from the file.
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).expect("tree");
+    fs::write(tree.join("d.txt"), "delta\n").expect("write d");
+    let journal = dir.join("journal.zpaq");
+    zpaqrs()
+        .args(["add", "-m", "1", "-t", "1"])
+        .arg(&journal)
+        .arg("tree")
+        .current_dir(&dir)
+        .assert()
+        .success();
+    let listed = archive_list_entries(&fs::read(&journal).expect("journal")).expect("library");
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0].path, "tree/d.txt");
+    let restored = dir.join("restored");
+    zpaqrs()
+        .arg("extract")
+        .arg(&journal)
+        .arg(&restored)
+        .assert()
+        .success();
+    assert_eq!(
+        fs::read(restored.join("tree/d.txt")).expect("d"),
+        b"delta\n"
+    );
+}
+
+#[test]
+fn piped_archives_are_listed_and_extracted_in_memory() {
+    let dir = TempDir::new("zpaq-rs-cli-pipe");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("sub")).expect("tree");
+    fs::write(tree.join("e.txt"), "echo\n").expect("write e");
+    fs::write(tree.join("sub/f.txt"), test_support::text(300_000)).expect("write f");
+    let journal = dir.join("journal.zpaq");
+    zpaqrs()
+        .args(["add", "-t", "1"])
+        .arg(&journal)
+        .arg("tree")
+        .current_dir(&dir)
+        .assert()
+        .success();
+
+    // The archive as `cat journal.zpaq |` would deliver it.
+    let archive = fs::read(&journal).expect("journal");
+    let listing = zpaq_list_bytes(&archive).expect("list");
+    assert_eq!(listing.versions, 1);
+    let listed: String = listing
+        .entries
+        .iter()
+        .map(|e| format!("{:>12} {}\n", e.size, e.path))
+        .collect();
+    assert_eq!(
+        listed,
+        format!("{:>12} tree/e.txt\n{:>12} tree/sub/f.txt\n", 5, 300_000)
+    );
+    zpaqrs()
+        .args(["list", "-"])
+        .write_stdin(archive.clone())
+        .assert()
+        .success()
+        .stdout(listed);
+
+    let out = dir.join("out");
+    let report = zpaq_extract_bytes(&archive, out.to_str().expect("utf-8")).expect("extract");
+    assert_eq!(report.written.len(), 2);
+    assert!(report.rejected.is_empty());
+    assert_eq!(fs::read(out.join("tree/e.txt")).expect("e"), b"echo\n");
+    assert_eq!(
+        fs::read(out.join("tree/sub/f.txt")).expect("f"),
+        test_support::text(300_000)
+    );
+}
@@ -0,0 +1,226 @@
+//! Golden SHA-256 hashes of `compress_to_vec` output, so that a build
+//! whose output differs (another platform, the `nojit` feature, other
+//! compiler flags) fails here instead of silently changing content
+//! addresses.  The hashes must only change together with the format.
+
+use zpaq_rs::{compress_to_vec, self_check, sha256};
+
+const METHODS: [&str; 6] = ["0", "1", "2", "3", "4", "5"];
+
+/// `(input, method, sha256 of the output)` for every input and method.
+const GOLDEN: &[(&str, &str, &str)] = &[
+    (
+        "empty",
+        "0",
+        "ae32037e68e450dffaa70b7cc17d01ed7ed7790b3ed04943df706d493ad47fba",
+    ),
+    (
+        "empty",
+        "1",
+        "52b61d2c505a03c01eb140ec6510bd1dad1699eb68e363d5f3d1892984324c16",
+    ),
+    (
+        "empty",
+        "2",
+        "52b61d2c505a03c01eb140ec6510bd1dad1699eb68e363d5f3d1892984324c16",
+    ),
+    (
+        "empty",
+        "3",
+        "bd55df1c3d0dfda2a5ab76400201d3892d655eaacebfba739538729b34a0b862",
+    ),
+    (
+        "empty",
+        "4",
+        "63746f83646d3ca2b218e271556a6cc11b30c8e93e767c86cc12bca9265293de",
+    ),
+    (
+        "empty",
+        "5",
+        "93ab446615519b343a4f8a33249ed29e36d78929b1433620971f09d41a94697d",
+    ),
+    (
+        "zeros",
+        "0",
+        "f608ad40979e54f58b9a84f5057f7325c8735d38a7fd391b041ad597efcb0cec",
+    ),
+    (
+        "zeros",
+        "1",
+        "56bb16a9a62f63d1038575f67014f47a5974897ff71e16eb43f9fc8875a5e463",
+    ),
+    (
+        "zeros",
+        "2",
+        "3688674c6b393bc297ab9d63f4e8df49b3913cad51351637433abb85caaef87c",
+    ),
+    (
+        "zeros",
+        "3",
+        "4aeb22c39dd5560b742a725feb647c5d9d704263f63ed0b553bfa52636e385e1",
+    ),
+    (
+        "zeros",
+        "4",
+        "e876b02505a4b1976c84c2655fd941129a3b58e9e65357492465a381663273b2",
+    ),
+    (
+        "zeros",
+        "5",
+        "cf3544d76e7e792b6e54ee2c81f3d40e9b1c9b6a365b8bcbf62d491071b17888",
+    ),
+    (
+        "text",
+        "0",
+        "e6a6b7098ea8221e8a16ac5b9eead0c8553e355ba653632d239c9138cbbc6a45",
+    ),
+    (
+        "text",
+        "1",
+        "ed1cff9892dfad59ff51925d373afaf501d44fea3fa6efac5463d506d6313bd5",
+    ),
+    (
+        "text",
+        "2",
+        "cb970927b7ecd0dc72653e814286d6b6519b9c396bd819306a758eedb33ac837",
+    ),
+    (
+        "text",
+        "3",
+        "4aa44142ecb3ff03f611e9b689142bf80e13a36c4f995232cc59de758f406170",
+    ),
+    (
+        "text",
+        "4",
+        "e09c054d83ea2a5916067511189de5f78246b6d3d8c38ccef489ac661c110142",
+    ),
+    (
+        "text",
+        "5",
+        "d0e19796aa47748634c1e09e687fd6929bd95d613e4c74802d2ed36107f1597f",
+    ),
+    (
+        "random",
+        "0",
+        "d96b5a419f1c0153fc3fb64a93be17cf542eef6d436bead82a1d0233824524e9",
+    ),
+    (
+        "random",
+        "1",
+        "ceebbb15ccd80e9f93d06944d027134ab4e17f2c79012ed19bf6804b6d24de9a",
+    ),
+    (
+        "random",
+        "2",
+        "ceebbb15ccd80e9f93d06944d027134ab4e17f2c79012ed19bf6804b6d24de9a",
+    ),
+    (
+        "random",
+        "3",
+        "4b8459f8348d5d39fe6a857433685cc7eb44d816e70a78d9706288d5166fc8a1",
+    ),
+    (
+        "random",
+        "4",
+        "a367848dd3322caeb2834b93c53626a3a4ed7f3b56bc99123a0d7bc2ca78b332",
+    ),
+    (
+        "random",
+        "5",
+        "af70fbd9616c00878ef5a4ae12921f31c354e292971b9c24c545a8e73e374652",
+    ),
+    (
+        "mixed",
+        "0",
+        "bb389f90ebba5cccce3840bba81c33a1ecf28344eb08d778e6eef436913bd7b0",
+    ),
+    (
+        "mixed",
+        "1",
+        "81a094cf571e0bbbf00cf1ea943fb5c6cd1dfff5f81a71adfa12ee6ef4f99f58",
+    ),
+    (
+        "mixed",
+        "2",
+        "63342df658db708abd7f2d884edb2b9485e225be729af7c09a60e60da9f7b95a",
+    ),
+    (
+        "mixed",
+        "3",
+        "8e71fc4ff474216ea7f5ca151b76429ccfeb8463d092cfd1fa943b675591c0ec",
+    ),
+    (
+        "mixed",
+        "4",
+        "ec0b1c7c50031ab45be85813395bd407e423b1140da2b6654d3f6a09e9d7e70d",
+    ),
+    (
+        "mixed",
+        "5",
+        "d6dc912df9e798410159632ca769be7cdda32fb6b5938727e57d7996b4d78302",
+    ),
+];
+
+fn xorshift(len: usize, mut state: u64) -> Vec<u8> {
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 56) as u8
+        })
+        .collect()
+}
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    let text: Vec<u8> = (0..3000u32)
+        .flat_map(|i| {
+            format!(
+                "{i:05} the quick brown fox jumps over {} lazy dogs\n",
+                i % 17
+            )
+            .into_bytes()
+        })
+        .collect();
+    let random = xorshift(32 << 10, 0x2545_f491_4f6c_dd1d);
+    let mixed = text
+        .chunks(4096)
+        .zip(random.chunks(1024))
+        .flat_map(|(t, r)| t.iter().chain(r).copied())
+        .collect();
+    vec![
+        ("empty", Vec::new()),
+        ("zeros", vec![0; 64 << 10]),
+        ("text", text),
+        ("random", random),
+        ("mixed", mixed),
+    ]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[test]
+fn compressed_output_matches_the_golden_hashes() {
+    let mut mismatches = Vec::new();
+    for (name, data) in inputs() {
+        for method in METHODS {
+            let &(_, _, expected) = GOLDEN
+                .iter()
+                .find(|(n, m, _)| *n == name && *m == method)
+                .expect("golden hash for every input and method");
+            let actual =
+                hex(&sha256(&compress_to_vec(&data, method).expect("compress")).expect("hash"));
+            if actual != expected {
+                mismatches.push(format!("{name} -{method}: {actual}, expected {expected}"));
+            }
+        }
+    }
+    assert!(mismatches.is_empty(), "{mismatches:#?}");
+}
+
+#[test]
+fn the_runtime_self_check_passes() {
+    self_check().expect("self check");
+}
@@ -0,0 +1,318 @@
+//! Runs each program in `examples/` on generated data and checks what it
+//! leaves behind independently of what it prints.
+//!
+//! The examples are built by `cargo test` (or `cargo build --examples`)
+//! next to this test's own directory.  They may only use the public API, so
+//! each must forbid `unsafe` and build as a separate crate.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::process::Command;
+
+use zpaq_rs::test_support::{self, TempDir};
+use zpaq_rs::{ArchiveReader, decompress_to_vec, sha256};
+
+const EXAMPLES: [&str; 4] = [
+    "backup_dir",
+    "ncd_cluster",
+    "pipeline_encrypt",
+    "seekable_store",
+];
+
+/// Runs example `name` with `args`, returning its stdout.
+fn run_example<I, S>(name: &str, args: I) -> String
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    // target/debug/deps/examples-<hash> -> target/debug/examples/<name>
+    let exe = std::env::current_exe().expect("test executable");
+    let program = exe
+        .parent()
+        .and_then(Path::parent)
+        .expect("target directory")
+        .join("examples")
+        .join(format!("{name}{}", std::env::consts::EXE_SUFFIX));
+    assert!(
+        program.is_file(),
+        "{} is missing; build the examples with `cargo test` or `cargo build --examples`",
+        program.display()
+    );
+    let output = Command::new(&program)
+        .args(args)
+        .output()
+        .expect("run example");
+    assert!(
+        output.status.success(),
+        "{name} failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).expect("utf-8 output")
+}
+
+/// The `key=value` lines of `stdout`.
+fn metrics(stdout: &str) -> HashMap<&str, &str> {
+    stdout
+        .lines()
+        .filter(|line| !line.contains(' '))
+        .filter_map(|line| line.split_once('='))
+        .collect()
+}
+
+fn number(metrics: &HashMap<&str, &str>, key: &str) -> u64 {
+    metrics
+        .get(key)
+        .unwrap_or_else(|| panic!("no {key} in {metrics:?}"))
+        .parse()
+        .unwrap_or_else(|e| panic!("{key}: {e}"))
+}
+
+/// Deterministic bytes with the statistics of random data.
+/// Repetitive records, which every method shrinks several times over.
+fn text(len: usize) -> Vec<u8> {
+    (0u32..)
+        .flat_map(|i| format!("record {} status {}\n", i % 1009, i % 7).into_bytes())
+        .take(len)
+        .collect()
+}
+
+fn key_reads(archive: &[u8], key: &str) -> Vec<u8> {
+    let mut reader = ArchiveReader::with_key(Cursor::new(archive), key).expect("open with key");
+    let path = reader.entries().next().expect("one entry").path.clone();
+    reader.read(&path).expect("read entry")
+}
+
+#[test]
+fn every_example_is_tested_and_forbids_unsafe() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut found: Vec<String> = fs::read_dir(&dir)
+        .expect("examples directory")
+        .map(|entry| entry.expect("entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "rs"))
+        .map(|path| {
+            let source = fs::read_to_string(&path).expect("read example");
+            assert!(
+                source.contains("#![forbid(unsafe_code)]"),
+                "{} must forbid unsafe code",
+                path.display()
+            );
+            path.file_stem()
+                .expect("stem")
+                .to_string_lossy()
+                .into_owned()
+        })
+        .collect();
+    found.sort();
+    assert_eq!(found, EXAMPLES);
+}
+
+#[test]
+fn pipeline_encrypt_compresses_before_encrypting() {
+    let dir = TempDir::new("zpaq-rs-example-pipeline");
+    let data = text(400_000);
+    let input = dir.join("input.txt");
+    fs::write(&input, &data).expect("write input");
+
+    let stdout = run_example(
+        "pipeline_encrypt",
+        [input.as_os_str(), dir.as_os_str(), "hunter2".as_ref()],
+    );
+    let m = metrics(&stdout);
+    assert_eq!(number(&m, "input_bytes"), data.len() as u64);
+
+    let compressed_first = fs::read(m["compressed_first"]).expect("read");
+    assert_eq!(
+        compressed_first.len() as u64,
+        number(&m, "compressed_first_bytes")
+    );
+    assert_eq!(key_reads(&compressed_first, "hunter2"), data);
+    assert!(ArchiveReader::open(Cursor::new(&compressed_first)).is_err());
+
+    let encrypted_first = fs::read(m["encrypted_first"]).expect("read");
+    assert_eq!(
+        encrypted_first.len() as u64,
+        number(&m, "encrypted_first_bytes")
+    );
+    let encrypted = decompress_to_vec(&encrypted_first).expect("decompress");
+    assert_eq!(encrypted.len() as u64, number(&m, "encrypted_bytes"));
+    assert_eq!(key_reads(&encrypted, "hunter2"), data);
+    // Ciphertext does not compress; the plaintext does.
+    assert!(encrypted_first.len() * 10 > data.len() * 9);
+    assert!(compressed_first.len() * 4 < data.len());
+
+    let hashed = fs::read(m["hashed"]).expect("read");
+    assert_eq!(hashed.len() as u64, number(&m, "hashed_bytes"));
+    assert_eq!(decompress_to_vec(&hashed).expect("decompress"), data);
+    assert_eq!(m["sha256"], hex::encode(sha256(&data).expect("sha256")));
+}
+
+#[test]
+fn backup_dir_adds_a_version_per_run_within_the_rate() {
+    let dir = TempDir::new("zpaq-rs-example-backup");
+    let tree = dir.join("tree");
+    fs::create_dir_all(tree.join("nested/deeper")).expect("create tree");
+    let files = [
+        ("a.txt", text(150_000)),
+        ("nested/b.bin", test_support::random(1, 50_000)),
+        ("nested/deeper/c.txt", text(100_000)),
+        ("empty", Vec::new()),
+    ];
+    for (name, data) in &files {
+        fs::write(tree.join(name), data).expect("write file");
+    }
+    let total: u64 = files.iter().map(|(_, data)| data.len() as u64).sum();
+    let archive = dir.join("backup.zpaq");
+    let rate = 1_000_000u64;
+
+    let stdout = run_example(
+        "backup_dir",
+        [
+            tree.as_os_str(),
+            archive.as_os_str(),
+            rate.to_string().as_ref(),
+        ],
+    );
+    let m = metrics(&stdout);
+    assert_eq!(number(&m, "files"), files.len() as u64);
+    assert_eq!(number(&m, "bytes"), total);
+    assert_eq!(number(&m, "versions"), 1);
+    assert_eq!(
+        number(&m, "archive_bytes"),
+        fs::metadata(&archive).expect("stat").len()
+    );
+    // The throttle may let a burst through at the start, but not the lot.
+    assert!(number(&m, "elapsed_ms") * 2 >= total * 1000 / rate);
+
+    let progress: Vec<u64> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("progress bytes="))
+        .map(|rest| {
+            rest.split(' ')
+                .next()
+                .expect("bytes")
+                .parse()
+                .expect("number")
+        })
+        .collect();
+    assert!(!progress.is_empty());
+    assert!(progress.windows(2).all(|w| w[0] <= w[1]), "{progress:?}");
+    assert!(progress.iter().all(|&bytes| bytes <= total));
+
+    let mut reader =
+        ArchiveReader::open_path(archive.to_str().expect("utf-8 path")).expect("open archive");
+    for (name, data) in &files {
+        assert_eq!(&reader.read(name).expect("read"), data, "{name}");
+    }
+
+    // A second run over unchanged files adds a version and little else.
+    let first_size = fs::metadata(&archive).expect("stat").len();
+    let stdout = run_example(
+        "backup_dir",
+        [
+            tree.as_os_str(),
+            archive.as_os_str(),
+            rate.to_string().as_ref(),
+        ],
+    );
+    let m = metrics(&stdout);
+    assert_eq!(number(&m, "versions"), 2);
+    assert!(number(&m, "archive_bytes") < first_size + 4096);
+}
+
+#[test]
+fn ncd_cluster_groups_files_with_shared_content() {
+    let dir = TempDir::new("zpaq-rs-example-ncd");
+    // Three families: two of unrelated noise, one of text.  Members of a
+    // family share most of their bytes.
+    let mut families: Vec<Vec<String>> = Vec::new();
+    for (family, base) in [
+        test_support::random(10, 40_000),
+        test_support::random(20, 40_000),
+        text(40_000),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut members = Vec::new();
+        for member in 0..3u64 {
+            let mut data = base.clone();
+            let edit = test_support::random(100 + family as u64 * 10 + member, 4_000);
+            let at = member as usize * 10_000;
+            data[at..at + edit.len()].copy_from_slice(&edit);
+            let name = format!("f{family}m{member}");
+            fs::write(dir.join(&name), data).expect("write member");
+            members.push(name);
+        }
+        families.push(members);
+    }
+
+    let stdout = run_example("ncd_cluster", [dir.as_os_str(), "0.5".as_ref()]);
+    let pairs = stdout
+        .lines()
+        .filter(|line| line.starts_with("ncd "))
+        .count();
+    assert_eq!(pairs, 9 * 8 / 2);
+    for line in stdout.lines().filter(|line| line.starts_with("ncd ")) {
+        let distance: f64 = line
+            .rsplit(' ')
+            .next()
+            .expect("value")
+            .parse()
+            .expect("number");
+        assert!((0.0..=1.2).contains(&distance), "{line}");
+    }
+    let mut clusters: Vec<Vec<String>> = stdout
+        .lines()
+        .filter_map(|line| line.strip_prefix("cluster "))
+        .map(|members| members.split(' ').map(str::to_string).collect())
+        .collect();
+    clusters.sort();
+    assert_eq!(clusters, families);
+    assert_eq!(number(&metrics(&stdout), "clusters"), 3);
+}
+
+#[test]
+fn seekable_store_objects_form_the_stream() {
+    let dir = TempDir::new("zpaq-rs-example-store");
+    let data: Vec<u8> = text(3_000_000)
+        .into_iter()
+        .zip(test_support::random(5, 3_000_000))
+        .map(|(t, n)| if n < 16 { n } else { t })
+        .collect();
+    let input = dir.join("input.bin");
+    fs::write(&input, &data).expect("write input");
+    let store = dir.join("store");
+    fs::create_dir_all(&store).expect("create store");
+    let part_bytes = 100_000u64;
+
+    let stdout = run_example(
+        "seekable_store",
+        [
+            input.as_os_str(),
+            store.as_os_str(),
+            part_bytes.to_string().as_ref(),
+            "3".as_ref(),
+        ],
+    );
+    let m = metrics(&stdout);
+    assert_eq!(number(&m, "input_bytes"), data.len() as u64);
+    assert_eq!(number(&m, "ranges_ok"), 4);
+    assert!(number(&m, "blocks") >= 3);
+
+    let parts = zpaq_rs::ArchiveSpec::parse(m["pattern"])
+        .expect("pattern")
+        .parts();
+    assert_eq!(parts.len() as u64, number(&m, "parts"));
+    assert!(parts.len() > 1);
+    let mut stream = Vec::new();
+    for (n, part) in parts.iter().enumerate() {
+        let object = fs::read(part).expect("read object");
+        if n + 1 < parts.len() {
+            assert_eq!(object.len() as u64, part_bytes, "{part}");
+        }
+        stream.extend_from_slice(&object);
+    }
+    assert_eq!(decompress_to_vec(&stream).expect("decompress"), data);
+}
@@ -0,0 +1,46 @@
+//! Inputs that once crashed or exhausted memory in the shim, from
+//! `fuzz/regressions`.  Each must now fail cleanly.
+
+use std::fs;
+use std::path::Path;
+
+use zpaq_rs::{
+    ZpaqError, decompress_size, decompress_to_vec, list_blocks, original_size_hint,
+    set_hardened_parsing,
+};
+
+fn corpus() -> Vec<(String, Vec<u8>)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/regressions");
+    let mut inputs: Vec<(String, Vec<u8>)> = fs::read_dir(dir)
+        .expect("regressions directory")
+        .map(|entry| {
+            let path = entry.expect("entry").path();
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, fs::read(&path).expect("read input"))
+        })
+        .collect();
+    inputs.sort();
+    inputs
+}
+
+#[test]
+fn regression_inputs_fail_cleanly() {
+    let inputs = corpus();
+    assert!(inputs.len() >= 8);
+    for (name, input) in &inputs {
+        let err = decompress_to_vec(input).expect_err(name);
+        if name != "truncated_header" {
+            assert!(matches!(err, ZpaqError::Corrupt(_)), "{name}: {err:?}");
+        }
+        assert!(decompress_size(input).is_err(), "{name}");
+        assert!(list_blocks(input).is_err(), "{name}");
+        let _ = original_size_hint(input);
+    }
+
+    // Without hardening, the long fields are accepted as libzpaq accepts them.
+    set_hardened_parsing(false);
+    let long_name = &inputs.iter().find(|(n, _)| n == "long_filename").unwrap().1;
+    let plain = decompress_to_vec(long_name);
+    set_hardened_parsing(true);
+    assert_eq!(plain.expect("unhardened"), b"abc");
+}
@@ -22,3 +22,26 @@ fn streaming_bits_matches_compress_size_minus_header() {
         "stream bits mismatch: bits={bits:.3} expected={expected:.3} diff={diff:.3}"
     );
 }
+
+#[test]
+fn streaming_bits_include_the_header() {
+    let data = b"abababababababababababababababababababababababababababababababab";
+    // An explicit model, as levels stream with a model of their own.
+    let method = "x4.0ci1";
+
+    let mut stream = StreamingCompressor::new(method).expect("streaming compressor");
+    let header = stream.bits();
+    assert!(header > 0.0, "header={header}");
+    for &b in data {
+        stream.push(b).expect("push byte");
+    }
+    let bits = stream.bits();
+
+    // The segment trailer is only written when the stream ends.
+    let expected = compress_size(data, method).expect("compress size") as f64 * 8.0;
+    let diff = (bits - expected).abs();
+    assert!(
+        diff < 256.0,
+        "stream bits mismatch: bits={bits:.3} expected={expected:.3} diff={diff:.3}"
+    );
+}
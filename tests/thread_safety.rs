@@ -0,0 +1,167 @@
+//! The `Send` and `Sync` contract of the public types (see "Thread safety"
+//! in the crate docs), checked at compile time so that a new field cannot
+//! change it unnoticed.
+
+use std::io::Cursor;
+use std::thread;
+
+use static_assertions::{assert_impl_all, assert_not_impl_any};
+use zpaq_rs::*;
+
+type Bytes = Cursor<Vec<u8>>;
+
+// Plain data: options, reports, listings and tokens.
+assert_impl_all!(AddReport: Send, Sync, Clone);
+assert_impl_all!(AppendOptions: Send, Sync, Clone);
+assert_impl_all!(ArchiveEntryInfo: Send, Sync, Clone);
+assert_impl_all!(ArchiveListing: Send, Sync, Clone);
+assert_impl_all!(ArchiveSpec: Send, Sync, Clone);
+assert_impl_all!(ArchiveSummary: Send, Sync, Clone);
+assert_impl_all!(ArchiveSyncState: Send, Sync, Clone);
+assert_impl_all!(BlockInfo: Send, Sync, Clone);
+assert_impl_all!(BlockModel: Send, Sync, Clone);
+assert_impl_all!(CancellationToken: Send, Sync, Clone);
+assert_impl_all!(ComponentStack: Send, Sync, Clone);
+assert_impl_all!(CompressCheckpoint: Send, Sync, Clone);
+assert_impl_all!(CompressOptions: Send, Sync, Clone);
+assert_impl_all!(CompressStats: Send, Sync, Clone);
+assert_impl_all!(CopyReport: Send, Sync, Clone);
+assert_impl_all!(DecompressLimits: Send, Sync, Clone);
+assert_impl_all!(DecompressOptions: Send, Sync, Clone);
+assert_impl_all!(DecompressStats: Send, Sync, Clone);
+assert_impl_all!(ExtractOptions: Send, Sync, Clone);
+assert_impl_all!(ExtractReport: Send, Sync, Clone);
+assert_impl_all!(MemberFilter: Send, Sync, Clone);
+assert_impl_all!(ParallelOptions: Send, Sync, Clone);
+assert_impl_all!(ReadPolicy: Send, Sync, Clone);
+assert_impl_all!(RepackOptions: Send, Sync, Clone);
+assert_impl_all!(ResumeToken: Send, Sync, Clone);
+assert_impl_all!(SalvageOptions: Send, Sync, Clone);
+assert_impl_all!(SecretString: Send, Sync, Clone);
+assert_impl_all!(SizeBreakdown: Send, Sync, Clone);
+assert_impl_all!(StreamIndex: Send, Sync, Clone);
+assert_impl_all!(SweepOptions: Send, Sync, Clone);
+assert_impl_all!(VerifyOptions: Send, Sync, Clone);
+assert_impl_all!(VerifyReport: Send, Sync, Clone);
+assert_impl_all!(VersionLayout: Send, Sync, Clone);
+assert_impl_all!(ZpaqAddOptions: Send, Sync, Clone);
+assert_impl_all!(ZpaqError: Send, Sync, Clone);
+
+// Shared handles: clones share state, from any thread.
+assert_impl_all!(ProgressCallback: Send, Sync, Clone);
+assert_impl_all!(Throttle: Send, Sync, Clone);
+
+// Handles owning libzpaq objects: movable, but not shared or copied.
+assert_impl_all!(StreamingCompressor: Send);
+assert_not_impl_any!(StreamingCompressor: Sync, Clone);
+assert_impl_all!(PushDecompressor: Send);
+assert_not_impl_any!(PushDecompressor: Sync, Clone);
+assert_impl_all!(ArchiveReader<Bytes>: Send);
+assert_not_impl_any!(ArchiveReader<Bytes>: Sync, Clone);
+assert_impl_all!(MemberReader<'static, Bytes>: Send);
+assert_not_impl_any!(MemberReader<'static, Bytes>: Sync, Clone);
+assert_impl_all!(ResumableDecompressor<Bytes>: Send);
+assert_not_impl_any!(ResumableDecompressor<Bytes>: Sync, Clone);
+assert_impl_all!(ZpaqArchive: Send);
+assert_not_impl_any!(ZpaqArchive: Sync, Clone);
+
+// Entries reading their data from a `Read + Send` source.
+assert_impl_all!(ArchiveEntryOwned: Send);
+assert_not_impl_any!(ArchiveEntryOwned: Sync, Clone);
+
+// Pure Rust state: movable and shareable.
+assert_impl_all!(CheckpointingCompressor: Send, Sync);
+assert_impl_all!(Chunker: Send, Sync, Clone);
+assert_impl_all!(ReferenceSet: Send, Sync, Clone);
+assert_impl_all!(SlidingNcd: Send, Sync, Clone);
+assert_impl_all!(ArchiveWriter<Bytes>: Send, Sync);
+assert_impl_all!(MemberWriter<'static, Bytes>: Send, Sync);
+assert_impl_all!(MultiPartReader: Send, Sync);
+
+#[test]
+fn streaming_compressors_keep_counting_on_another_thread() {
+    let data = b"moved between threads ".repeat(200);
+    let (half, rest) = data.split_at(data.len() / 2);
+    let rest = rest.to_vec();
+    let mut here = StreamingCompressor::new("1").unwrap();
+    let mut there = StreamingCompressor::new("1").unwrap();
+    for &b in half {
+        here.push(b).unwrap();
+        there.push(b).unwrap();
+    }
+    let (moved, rest) = thread::spawn(move || {
+        for &b in &rest {
+            there.push(b).unwrap();
+        }
+        (there, rest)
+    })
+    .join()
+    .unwrap();
+    for &b in &rest {
+        here.push(b).unwrap();
+    }
+    assert_eq!(moved.bits(), here.bits());
+}
+
+#[test]
+fn push_decompressors_continue_on_another_thread() {
+    let data = b"fed from two threads ".repeat(500);
+    let compressed = compress_to_vec(&data, "2").unwrap();
+    let (first, second) = compressed.split_at(compressed.len() / 2);
+    let second = second.to_vec();
+    let mut push = PushDecompressor::new().unwrap();
+    let mut out = push.feed(first).unwrap().output;
+    let (push, rest) = thread::spawn(move || {
+        let rest = push.feed(&second).unwrap().output;
+        (push, rest)
+    })
+    .join()
+    .unwrap();
+    push.finish().unwrap();
+    out.extend(rest);
+    assert_eq!(out, data);
+}
+
+#[test]
+fn archive_readers_read_on_another_thread() {
+    let mut archive = Vec::new();
+    archive_append_entries(
+        &mut archive,
+        &[
+            ArchiveEntry {
+                path: "a.txt",
+                data: b"first",
+                comment: None,
+            },
+            ArchiveEntry {
+                path: "b.txt",
+                data: b"second",
+                comment: None,
+            },
+        ],
+        "1",
+    )
+    .unwrap();
+    let mut reader = ArchiveReader::open(Cursor::new(archive)).unwrap();
+    assert_eq!(reader.read("a.txt").unwrap(), b"first");
+    let second = thread::spawn(move || reader.read("b.txt").unwrap())
+        .join()
+        .unwrap();
+    assert_eq!(second, b"second");
+}
+
+#[test]
+fn resumable_decompressors_finish_on_another_thread() {
+    let data = vec![3u8; 3 << 20];
+    let compressed = compress_to_vec(&data, "00").unwrap();
+    let mut decompressor = ResumableDecompressor::new(Cursor::new(compressed)).unwrap();
+    let mut out = Vec::new();
+    decompressor.next_block(&mut out).unwrap();
+    let out = thread::spawn(move || {
+        decompressor.finish(&mut out).unwrap();
+        out
+    })
+    .join()
+    .unwrap();
+    assert_eq!(out, data);
+}
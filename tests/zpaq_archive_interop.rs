@@ -1,26 +1,26 @@
 #![cfg(unix)]
 
+use std::collections::BTreeMap;
 use std::ffi::OsStr;
 use std::fs;
+use std::io::Read;
+use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::OnceLock;
+use std::time::{Duration, UNIX_EPOCH};
 
+use zpaq_rs::test_support::TempDir;
 use zpaq_rs::{
-    ArchiveEntry, archive_append_entries_file, archive_from_entries, archive_read_file_bytes,
-    zpaq_add, zpaq_command, zpaq_list,
+    ArchiveEntry, ArchiveEntryOwned, ArchiveReader, ArchiveSpec, ArchiveWriter, EntryMeta,
+    VerifyOptions, ZpaqAddOptions, ZpaqError, archive_append_entries_file,
+    archive_append_owned_entries_file, archive_entries, archive_fragments, archive_from_entries,
+    archive_from_owned_entries, archive_read_file_bytes, archive_read_file_bytes_at,
+    encode_extra_comment, index_versions, verify_archive_file, verify_index_matches_parts,
+    zpaq_add, zpaq_add_archive_delta_size, zpaq_add_opts, zpaq_command, zpaq_delete, zpaq_list,
+    zpaq_summary,
 };
 
-fn unique_temp_dir(prefix: &str) -> PathBuf {
-    let nanos = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("clock")
-        .as_nanos();
-    let dir = std::env::temp_dir().join(format!("{prefix}-{}-{nanos}", std::process::id()));
-    fs::create_dir_all(&dir).expect("create temp dir");
-    dir
-}
-
 fn run_ok<I, S>(program: &Path, args: I)
 where
     I: IntoIterator<Item = S>,
@@ -40,6 +40,12 @@ where
 }
 
 fn ensure_zpaq_cli(root: &Path) -> PathBuf {
+    // Tests run in parallel; build the CLI once.
+    static CLI: OnceLock<PathBuf> = OnceLock::new();
+    CLI.get_or_init(|| build_zpaq_cli(root)).clone()
+}
+
+fn build_zpaq_cli(root: &Path) -> PathBuf {
     let zpaq_dir = root.join("zpaq");
     let clean_output = Command::new("make")
         .current_dir(&zpaq_dir)
@@ -110,7 +116,7 @@ fn archive_add_append_list_extract_interop_matches_cli() {
     let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
     let zpaq_bin = ensure_zpaq_cli(&root);
 
-    let temp = unique_temp_dir("zpaq-rs-interop");
+    let temp = TempDir::new("zpaq-rs-interop");
     let src_dir = temp.join("dataset");
     fs::create_dir_all(&src_dir).expect("create dataset dir");
 
@@ -285,5 +291,692 @@ fn archive_add_append_list_extract_interop_matches_cli() {
         b"\x01\x02\x03\x04"
     );
 
-    let _ = fs::remove_dir_all(temp);
+    // A third CLI version updates alpha and deletes beta; reads honour the
+    // latest version unless asked for an earlier one.
+    fs::write(&alpha, b"alpha, rewritten for version three\n").expect("rewrite alpha");
+    fs::remove_file(&beta).expect("remove beta");
+    run_ok(
+        &zpaq_bin,
+        [
+            OsStr::new("add"),
+            OsStr::new(&archive_cli_s),
+            OsStr::new(&src_dir_s),
+            OsStr::new("-method"),
+            OsStr::new("3"),
+        ],
+    );
+    let cli_bytes = fs::read(&archive_cli).expect("read cli archive");
+    let stored = |name: &str| {
+        archive_entries(&cli_bytes, None, true)
+            .expect("cli entries")
+            .into_iter()
+            .find(|e| e.path.ends_with(&format!("/{name}")))
+            .expect("stored path")
+            .path
+    };
+    let (alpha_s, beta_s, gamma_s) = (stored("alpha.txt"), stored("beta.txt"), stored("gamma.txt"));
+    assert_eq!(
+        archive_read_file_bytes(&cli_bytes, &alpha_s).expect("read alpha"),
+        b"alpha, rewritten for version three\n"
+    );
+    assert_eq!(
+        archive_read_file_bytes_at(&cli_bytes, &alpha_s, Some(1)).expect("read alpha v1"),
+        b"alpha alpha alpha\n"
+    );
+    assert!(matches!(
+        archive_read_file_bytes(&cli_bytes, &beta_s),
+        Err(ZpaqError::NotFound {
+            was_deleted: true,
+            ..
+        })
+    ));
+    assert_eq!(
+        archive_read_file_bytes_at(&cli_bytes, &beta_s, Some(2)).expect("read beta v2"),
+        b"beta beta beta\n"
+    );
+    assert!(matches!(
+        archive_read_file_bytes_at(&cli_bytes, &gamma_s, Some(1)),
+        Err(ZpaqError::NotFound {
+            was_deleted: false,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn owned_entry_metadata_round_trips_through_cli() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-owned-interop");
+    let archive = temp.join("owned.zpaq");
+    let archive_s = archive.to_string_lossy().to_string();
+    let script_mtime = UNIX_EPOCH + Duration::from_secs(1_500_000_000);
+    let notes_mtime = UNIX_EPOCH + Duration::from_secs(1_600_000_123);
+
+    let blob = archive_from_owned_entries(
+        [
+            ArchiveEntryOwned::new("meta/run.sh", &b"#!/bin/sh\necho hi\n"[..])
+                .with_mtime(script_mtime)
+                .with_unix_mode(0o100755),
+            ArchiveEntryOwned::from_bytes("meta/notes.txt", b"private notes".to_vec())
+                .with_mtime(notes_mtime)
+                .with_unix_mode(0o100600),
+        ],
+        "2",
+    )
+    .expect("build owned archive");
+    fs::write(&archive, &blob).expect("persist owned archive");
+
+    archive_append_owned_entries_file(
+        &archive_s,
+        [
+            ArchiveEntryOwned::from_bytes("meta/later.txt", b"second version".to_vec())
+                .with_mtime(notes_mtime)
+                .with_unix_mode(0o100640),
+        ],
+        "2",
+    )
+    .expect("append owned entry");
+
+    let extract_dir = temp.join("extract");
+    run_ok(
+        &zpaq_bin,
+        [
+            OsStr::new("extract"),
+            OsStr::new(&archive_s),
+            OsStr::new("-to"),
+            extract_dir.as_os_str(),
+        ],
+    );
+
+    for (name, contents, mtime, mode) in [
+        ("run.sh", &b"#!/bin/sh\necho hi\n"[..], script_mtime, 0o755),
+        ("notes.txt", b"private notes", notes_mtime, 0o600),
+        ("later.txt", b"second version", notes_mtime, 0o640),
+    ] {
+        let path = find_file_named(&extract_dir, name).expect("find extracted file");
+        assert_eq!(fs::read(&path).expect("read extracted"), contents, "{name}");
+        let meta = fs::metadata(&path).expect("stat extracted");
+        assert_eq!(meta.modified().expect("mtime"), mtime, "{name} mtime");
+        assert_eq!(meta.permissions().mode() & 0o7777, mode, "{name} mode");
+    }
+}
+
+#[test]
+fn extra_metadata_survives_cli_extract_and_streaming_add() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-extra-interop");
+    let archive = temp.join("extra.zpaq");
+    let archive_s = archive.to_string_lossy().to_string();
+    let extra = BTreeMap::from([
+        ("sha256".to_string(), "9f86d081884c7d65".to_string()),
+        (
+            "origin".to_string(),
+            "https://example.com/get?a=1&b=2".to_string(),
+        ),
+        (
+            "tags".to_string(),
+            "x=y\nnot a newline
+real newline"
+                .to_string(),
+        ),
+    ]);
+    let comment = encode_extra_comment(&extra);
+    let entries = [
+        ArchiveEntry {
+            path: "tagged.txt",
+            data: b"tagged contents",
+            comment: Some(&comment),
+        },
+        ArchiveEntry {
+            path: "plain.txt",
+            data: b"plain contents",
+            comment: Some("a note"),
+        },
+    ];
+    fs::write(
+        &archive,
+        archive_from_entries(&entries, "1").expect("build"),
+    )
+    .expect("write");
+
+    let extract_dir = temp.join("extract");
+    let extract_s = extract_dir.to_string_lossy().to_string();
+    fs::create_dir_all(&extract_dir).expect("create extract dir");
+    run_ok(&zpaq_bin, ["extract", &archive_s, "-to", &extract_s]);
+    let tagged = find_file_named(&extract_dir, "tagged.txt").expect("find tagged");
+    assert_eq!(fs::read(&tagged).expect("read tagged"), b"tagged contents");
+
+    // Add the extracted files back in streaming mode, where `zpaq` writes
+    // its own date comments.
+    let readded = tagged.to_string_lossy().to_string();
+    run_ok(&zpaq_bin, ["add", &archive_s, &readded, "-method", "s1"]);
+
+    let reader = ArchiveReader::open_path(&archive_s).expect("open");
+    let by_path = |path: &str| {
+        reader
+            .entries()
+            .find(|e| e.path == path)
+            .unwrap_or_else(|| panic!("{path} listed"))
+    };
+    assert_eq!(by_path("tagged.txt").meta.extra, extra);
+    assert_eq!(
+        by_path("tagged.txt").comment.as_deref(),
+        Some(comment.as_str())
+    );
+    assert!(by_path("plain.txt").meta.extra.is_empty());
+    assert_eq!(by_path("plain.txt").comment.as_deref(), Some("a note"));
+    let from_cli = by_path(&readded);
+    assert!(from_cli.meta.extra.is_empty());
+    let cli_comment = from_cli.comment.as_deref().expect("cli comment");
+    assert!(
+        cli_comment.starts_with(|c: char| c.is_ascii_digit()),
+        "{cli_comment}"
+    );
+
+    let listed = archive_entries(&fs::read(&archive).expect("read"), None, false).expect("entries");
+    let tagged = listed
+        .iter()
+        .find(|e| e.path == "tagged.txt")
+        .expect("tagged");
+    assert_eq!(tagged.extra, extra);
+}
+
+#[test]
+fn symlink_policies_for_add_and_extract() {
+    use std::os::unix::fs::symlink;
+    use zpaq_rs::{ExtractOptions, ExtractSymlinkPolicy, SymlinkPolicy, zpaq_extract_opts};
+
+    let temp = TempDir::new("zpaq-rs-symlinks");
+    let tree = temp.join("tree");
+    fs::create_dir_all(tree.join("sub")).expect("create tree");
+    fs::write(tree.join("file.txt"), b"file contents").expect("write file");
+    fs::write(tree.join("sub/data.txt"), b"data contents").expect("write data");
+    let absolute = tree.join("sub/data.txt").to_string_lossy().to_string();
+    let links = [
+        ("rel", "file.txt"),
+        ("abs", absolute.as_str()),
+        ("dangling", "missing/nowhere"),
+        ("subdir", "sub"),
+        ("loop", "."),
+    ];
+    for (name, target) in links {
+        symlink(target, tree.join(name)).expect("create link");
+    }
+    let tree_s = tree.to_string_lossy().to_string();
+    let stored = |name: &str| format!("{tree_s}/{name}");
+
+    let add = |name: &str, symlinks| {
+        let archive = temp.join(name).to_string_lossy().to_string();
+        let options = ZpaqAddOptions {
+            method: "1".into(),
+            threads: 1,
+            symlinks,
+            ..Default::default()
+        };
+        zpaq_add_opts(&archive, &[&tree_s], &options).expect("add");
+        let reader = ArchiveReader::open_path(&archive).expect("open");
+        (archive, reader)
+    };
+
+    // Skip leaves links out, as zpaq does.
+    let (_, reader) = add("skip.zpaq", SymlinkPolicy::Skip);
+    let mut paths: Vec<_> = reader.entries().map(|e| e.path.clone()).collect();
+    paths.sort();
+    assert_eq!(paths, [stored("file.txt"), stored("sub/data.txt")]);
+
+    // Follow stores what the links point to, but not dangling links or
+    // links back up the tree.
+    let (_, mut reader) = add("follow.zpaq", SymlinkPolicy::Follow);
+    assert_eq!(reader.read(&stored("rel")).expect("rel"), b"file contents");
+    assert_eq!(reader.read(&stored("abs")).expect("abs"), b"data contents");
+    assert_eq!(
+        reader.read(&stored("subdir/data.txt")).expect("subdir"),
+        b"data contents"
+    );
+    assert!(!reader.contains(&stored("dangling")));
+    assert!(
+        !reader
+            .entries()
+            .any(|e| e.path.starts_with(&stored("loop")))
+    );
+
+    // Store keeps each link's target exactly.
+    let (archive, mut reader) = add("store.zpaq", SymlinkPolicy::Store);
+    for (name, target) in links {
+        let path = stored(name);
+        let info = reader
+            .entries()
+            .find(|e| e.path == path)
+            .expect("link stored");
+        assert_eq!(
+            info.meta.unix_mode.expect("mode") & 0o170000,
+            0o120000,
+            "{name}"
+        );
+        assert_eq!(reader.read(&path).expect("read link"), target.as_bytes());
+    }
+    assert!(!reader.contains(&stored("subdir/data.txt")));
+
+    let out = temp.join("restored");
+    let out_s = out.to_string_lossy().to_string();
+    let restore = ExtractOptions::default();
+    zpaq_extract_opts(&archive, &[&tree_s, "-to", &out_s], &restore).expect("extract");
+    for (name, target) in links {
+        let link = fs::read_link(out.join(name)).expect("restored link");
+        assert_eq!(link, Path::new(target), "{name}");
+    }
+    assert_eq!(
+        fs::read(out.join("rel")).expect("follow restored"),
+        b"file contents"
+    );
+
+    let out = temp.join("skipped");
+    let out_s = out.to_string_lossy().to_string();
+    let skip = ExtractOptions {
+        symlinks: ExtractSymlinkPolicy::SkipWithWarning,
+        ..Default::default()
+    };
+    let output = zpaq_extract_opts(&archive, &[&tree_s, "-to", &out_s], &skip).expect("extract");
+    for (name, _) in links {
+        assert!(fs::symlink_metadata(out.join(name)).is_err(), "{name}");
+        assert!(
+            output
+                .stderr
+                .contains(&format!("Skipping symbolic link {out_s}/{name}"))
+        );
+    }
+    assert_eq!(
+        fs::read(out.join("file.txt")).expect("file"),
+        b"file contents"
+    );
+
+    // Plain zpaq writes a stored link as a file holding its target.
+    let out = temp.join("plain");
+    let out_s = out.to_string_lossy().to_string();
+    zpaq_command(&["extract", &archive, &tree_s, "-to", &out_s]).expect("extract");
+    assert_eq!(
+        fs::read(out.join("dangling")).expect("plain"),
+        b"missing/nowhere"
+    );
+}
+
+/// `len` bytes of log-like text, generated as they are read.
+struct GeneratedLog {
+    remaining: u64,
+    line: u64,
+    pending: Vec<u8>,
+}
+
+impl GeneratedLog {
+    fn new(len: u64) -> Self {
+        GeneratedLog {
+            remaining: len,
+            line: 0,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Read for GeneratedLog {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pending.is_empty() {
+            self.line += 1;
+            let x = self.line.wrapping_mul(2654435761) % 1_000_003;
+            self.pending = format!("{} worker-{} request {x} ok\n", self.line, x % 17).into_bytes();
+        }
+        let remaining = usize::try_from(self.remaining).unwrap_or(usize::MAX);
+        let n = buf.len().min(self.pending.len()).min(remaining);
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+#[test]
+fn archive_writer_streams_many_entries_for_cli() {
+    const SMALL: usize = 1000;
+    const BIG: u64 = 50 << 20;
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-writer-interop");
+    let archive = temp.join("writer.zpaq");
+    let archive_s = archive.to_string_lossy().to_string();
+    // Entries `i` and `i + SMALL / 2` have the same contents.
+    let small = |i: usize| {
+        let i = i % (SMALL / 2);
+        format!("entry {i} of the set\n").repeat(1 + i % 7)
+    };
+
+    let mut writer = ArchiveWriter::create(&archive_s, "1").expect("create writer");
+    for i in 0..SMALL {
+        let meta = EntryMeta {
+            unix_mode: Some(0o100644),
+            ..Default::default()
+        };
+        writer
+            .add_entry(&format!("set/{i:04}.txt"), small(i).as_bytes(), &meta)
+            .expect("add small entry");
+    }
+    writer
+        .add_entry("set/big.log", GeneratedLog::new(BIG), &EntryMeta::default())
+        .expect("add big entry");
+    writer.finish().expect("finish");
+
+    let first = archive_fragments(&archive_s, Some("set/0003.txt")).expect("fragments");
+    let twin = archive_fragments(&archive_s, Some("set/0503.txt")).expect("fragments");
+    assert_eq!(
+        first.iter().map(|f| f.id).collect::<Vec<_>>(),
+        twin.iter().map(|f| f.id).collect::<Vec<_>>()
+    );
+
+    let listing = Command::new(&zpaq_bin)
+        .args(["list", &archive_s])
+        .output()
+        .expect("run zpaq list");
+    let listing = String::from_utf8_lossy(&listing.stdout);
+    assert!(
+        listing.contains(&format!("{} files", SMALL + 1)),
+        "{listing}"
+    );
+
+    let extract_dir = temp.join("extract");
+    run_ok(
+        &zpaq_bin,
+        [
+            OsStr::new("extract"),
+            OsStr::new(&archive_s),
+            OsStr::new("-to"),
+            extract_dir.as_os_str(),
+        ],
+    );
+    for i in 0..SMALL {
+        let path = extract_dir.join(format!("set/{i:04}.txt"));
+        assert_eq!(fs::read_to_string(&path).expect("read small"), small(i));
+    }
+    let mut expected = GeneratedLog::new(BIG);
+    let mut extracted = fs::File::open(extract_dir.join("set/big.log")).expect("open big");
+    let (mut a, mut b) = (vec![0u8; 1 << 16], vec![0u8; 1 << 16]);
+    let mut total = 0u64;
+    loop {
+        let n = extracted.read(&mut a).expect("read extracted");
+        if n == 0 {
+            break;
+        }
+        expected.read_exact(&mut b[..n]).expect("regenerate");
+        assert_eq!(a[..n], b[..n], "big.log differs near offset {total}");
+        total += n as u64;
+    }
+    assert_eq!(total, BIG);
+}
+
+#[test]
+fn deleted_member_is_gone_at_tip_for_cli() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-delete-interop");
+    let data = temp.join("data");
+    fs::create_dir_all(&data).expect("create data dir");
+    fs::write(data.join("keep.txt"), b"keep me\n").expect("write keep");
+    fs::write(data.join("drop.txt"), b"drop me\n").expect("write drop");
+    let archive = temp.join("delete.zpaq");
+    let archive_s = archive.to_string_lossy().to_string();
+    let data_s = data.to_string_lossy().to_string();
+    run_ok(&zpaq_bin, ["add", &archive_s, &data_s, "-method", "1"]);
+
+    let dropped = format!("{data_s}/drop.txt");
+    assert_eq!(zpaq_delete(&archive_s, &[&dropped]).expect("delete"), 1);
+    assert_eq!(
+        zpaq_delete(&archive_s, &[&dropped]).expect("delete again"),
+        0
+    );
+
+    let list = |extra: &[&str]| {
+        let output = Command::new(&zpaq_bin)
+            .args(["list", &archive_s])
+            .args(extra)
+            .output()
+            .expect("run zpaq list");
+        assert!(output.status.success());
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    let tip = list(&[]);
+    assert!(
+        tip.contains("keep.txt") && !tip.contains("drop.txt"),
+        "{tip}"
+    );
+    let earlier = list(&["-until", "1"]);
+    assert!(earlier.contains("drop.txt"), "{earlier}");
+
+    let extract_dir = temp.join("extract");
+    run_ok(
+        &zpaq_bin,
+        [
+            OsStr::new("extract"),
+            OsStr::new(&archive_s),
+            OsStr::new("-until"),
+            OsStr::new("1"),
+            OsStr::new("-to"),
+            extract_dir.as_os_str(),
+        ],
+    );
+    let restored = find_file_named(&extract_dir, "drop.txt").expect("restored file");
+    assert_eq!(fs::read(restored).expect("read restored"), b"drop me\n");
+
+    let streaming = temp.join("streaming.zpaq");
+    let entry = ArchiveEntry {
+        path: "s",
+        data: b"s",
+        comment: None,
+    };
+    fs::write(
+        &streaming,
+        archive_from_entries(&[entry], "1").expect("streaming"),
+    )
+    .expect("write streaming");
+    let refused = zpaq_delete(&streaming.to_string_lossy(), &["s"]);
+    assert!(matches!(refused, Err(zpaq_rs::ZpaqError::StreamingArchive)));
+}
+
+#[test]
+fn summary_agrees_with_listing_and_file_size() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-summary-interop");
+    let data = temp.join("data");
+    fs::create_dir_all(data.join("sub")).expect("create data dir");
+    let text: String = (0..5_000).map(|i| format!("line {i}\n")).collect();
+    fs::write(data.join("a.txt"), &text).expect("write a");
+    fs::write(data.join("copy.txt"), &text).expect("write copy");
+    fs::write(data.join("sub/b.txt"), b"bravo\n").expect("write b");
+    let archive = temp.join("summary.zpaq");
+    let archive_s = archive.to_string_lossy().to_string();
+    let data_s = data.to_string_lossy().to_string();
+    run_ok(&zpaq_bin, ["add", &archive_s, &data_s, "-method", "1"]);
+    fs::write(data.join("sub/b.txt"), b"bravo, changed\n").expect("rewrite b");
+    run_ok(&zpaq_bin, ["add", &archive_s, &data_s, "-method", "1"]);
+
+    let summary = zpaq_summary(&archive_s).expect("summary");
+    let bytes = fs::read(&archive).expect("read archive");
+    let entries = archive_entries(&bytes, None, false).expect("entries");
+    assert_eq!(summary.versions, 2);
+    assert_eq!(summary.files, entries.len() as u64);
+    assert_eq!(summary.files, 3);
+    let total: u64 = entries.iter().map(|e| e.size).sum();
+    assert_eq!(summary.uncompressed_bytes, total);
+    assert_eq!(summary.compressed_bytes, bytes.len() as u64);
+    // a.txt and copy.txt share their fragments.
+    let unique = (text.len() + 15) as f64;
+    assert!((summary.dedup_ratio - total as f64 / unique).abs() < 1e-9);
+
+    let output = Command::new(&zpaq_bin)
+        .args(["list", &archive_s, "-summary", "1"])
+        .output()
+        .expect("run zpaq list");
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let frags = format!(" of {} frags)", summary.fragments);
+    assert!(stdout.contains(&frags), "{stdout}");
+}
+
+#[test]
+fn multi_part_adds_match_cli_part_for_part() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-parts-interop");
+    let data = temp.join("data");
+    fs::create_dir_all(&data).expect("create data dir");
+    let data_s = data.to_string_lossy().to_string();
+    let text: String = (0..3_000).map(|i| format!("entry {i}\n")).collect();
+    fs::write(data.join("a.txt"), &text).expect("write a");
+    let cli = temp.join("cli").join("arc??.zpaq");
+    let lib = temp.join("lib").join("arc??.zpaq");
+    fs::create_dir_all(cli.parent().expect("dir")).expect("create cli dir");
+    fs::create_dir_all(lib.parent().expect("dir")).expect("create lib dir");
+    let cli_s = cli.to_string_lossy().to_string();
+    let lib_s = lib.to_string_lossy().to_string();
+
+    // Fixed version dates make the two archives comparable byte for byte.
+    for (i, date) in ["20200101000000", "20200102000000"].into_iter().enumerate() {
+        if i == 1 {
+            fs::write(data.join("b.txt"), text.to_uppercase()).expect("write b");
+        }
+        let add = |archive: &str| {
+            vec![
+                "add", archive, &data_s, "-method", "1", "-threads", "1", "-until", date,
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+        };
+        run_ok(&zpaq_bin, add(&cli_s));
+        let predicted = zpaq_add_archive_delta_size(&lib_s, &[&data_s], "1", 1).expect("predict");
+        let args = add(&lib_s);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        zpaq_command(&args).expect("wrapper add");
+        let spec = ArchiveSpec::parse(&lib_s).expect("spec");
+        let newest = spec.parts().pop().expect("new part");
+        assert_eq!(fs::metadata(&newest).expect("stat").len(), predicted);
+    }
+
+    let cli_parts = ArchiveSpec::parse(&cli_s).expect("spec").parts();
+    let lib_parts = ArchiveSpec::parse(&lib_s).expect("spec").parts();
+    assert_eq!(lib_parts.len(), 2);
+    assert!(lib_parts[1].ends_with("arc02.zpaq"), "{lib_parts:?}");
+    assert_eq!(cli_parts.len(), lib_parts.len());
+    for (c, l) in cli_parts.iter().zip(&lib_parts) {
+        assert_eq!(
+            fs::read(c).expect("cli part"),
+            fs::read(l).expect("lib part")
+        );
+    }
+
+    let report = verify_archive_file(&lib_s, &VerifyOptions::default()).expect("verify");
+    assert!(report.is_ok(), "{report:?}");
+    let summary = zpaq_summary(&lib_s).expect("summary");
+    assert_eq!((summary.versions, summary.files), (2, 2));
+    let total: u64 = lib_parts
+        .iter()
+        .map(|p| fs::metadata(p).expect("stat").len())
+        .sum();
+    assert_eq!(summary.compressed_bytes, total);
+    let mut reader = ArchiveReader::open_path(&lib_s).expect("open parts");
+    let b = format!("{data_s}/b.txt");
+    assert_eq!(
+        reader.read(&b).expect("read b"),
+        text.to_uppercase().as_bytes()
+    );
+}
+
+#[test]
+fn indexed_adds_write_one_remote_part_each() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let zpaq_bin = ensure_zpaq_cli(&root);
+
+    let temp = TempDir::new("zpaq-rs-index-interop");
+    let data = temp.join("data");
+    let remote = temp.join("remote");
+    fs::create_dir_all(&data).expect("create data dir");
+    fs::create_dir_all(&remote).expect("create remote dir");
+    let data_s = data.to_string_lossy().to_string();
+    let parts = remote.join("parts???.zpaq").to_string_lossy().to_string();
+    let index = temp.join("local.idx");
+    let index_s = index.to_string_lossy().to_string();
+    let options = ZpaqAddOptions {
+        method: "1".into(),
+        threads: 1,
+        index: Some(index.clone()),
+        ..Default::default()
+    };
+    let remote_files = || {
+        let mut names: Vec<String> = fs::read_dir(&remote)
+            .expect("read remote")
+            .map(|e| e.expect("entry").file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    };
+
+    fs::write(data.join("a.txt"), "alpha\n".repeat(2_000)).expect("write a");
+    zpaq_add_opts(&parts, &[&data_s], &options).expect("first add");
+    assert_eq!(remote_files(), ["parts001.zpaq"]);
+    assert_eq!(index_versions(&index_s).expect("versions"), 1);
+    verify_index_matches_parts(&index_s, &parts).expect("consistent");
+
+    fs::write(data.join("b.txt"), "bravo\n".repeat(2_000)).expect("write b");
+    zpaq_add_opts(&parts, &[&data_s], &options).expect("second add");
+    assert_eq!(remote_files(), ["parts001.zpaq", "parts002.zpaq"]);
+    assert_eq!(index_versions(&index_s).expect("versions"), 2);
+    verify_index_matches_parts(&index_s, &parts).expect("consistent");
+
+    // The parts form an ordinary archive for the CLI.
+    let output = Command::new(&zpaq_bin)
+        .args(["list", &parts, "-all"])
+        .output()
+        .expect("run zpaq list");
+    assert!(output.status.success());
+    let listing = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        listing.contains("a.txt") && listing.contains("b.txt"),
+        "{listing}"
+    );
+    assert!(listing.contains("2 versions"), "{listing}");
+
+    // An upload that never arrived is caught before the next add.
+    let second = remote.join("parts002.zpaq");
+    let uploaded = fs::read(&second).expect("read part");
+    fs::remove_file(&second).expect("lose part");
+    match verify_index_matches_parts(&index_s, &parts) {
+        Err(ZpaqError::IndexMismatch {
+            part,
+            expected: Some(size),
+            actual: None,
+        }) => {
+            assert!(part.ends_with("parts002.zpaq"));
+            assert_eq!(size, uploaded.len() as u64);
+        }
+        other => panic!("unexpected {other:?}"),
+    }
+    fs::write(&second, &uploaded[..uploaded.len() - 1]).expect("truncated upload");
+    assert!(matches!(
+        verify_index_matches_parts(&index_s, &parts),
+        Err(ZpaqError::IndexMismatch {
+            actual: Some(_),
+            ..
+        })
+    ));
+    fs::write(&second, &uploaded).expect("restore part");
+    fs::write(remote.join("parts003.zpaq"), b"stray").expect("stray part");
+    assert!(matches!(
+        verify_index_matches_parts(&index_s, &parts),
+        Err(ZpaqError::IndexMismatch { expected: None, .. })
+    ));
 }
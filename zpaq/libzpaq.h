@@ -1212,6 +1212,7 @@ public:
     return rpos<wpos ? U8(buf[rpos++]) : -1;
   }
   int buffered() {return wpos-rpos;}  // how far read ahead?
+  friend struct ModelAccess;  // zpaq-rs: primes the predictor (zpaq_rs_ffi.cpp)
 private:
   U32 low, high;     // range
   U32 curr;          // last 4 bytes of archive or remaining bytes in subblock
@@ -1256,6 +1257,7 @@ public:
   void readSegmentEnd(char* sha1string = 0);
   int stat(int x) {return dec.stat(x);}
   int buffered() {return dec.buffered();}
+  friend struct ModelAccess;  // zpaq-rs: primes the predictor (zpaq_rs_ffi.cpp)
 private:
   ZPAQL z;
   Decoder dec;
@@ -1280,6 +1282,7 @@ public:
   int stat(int x) {return pr.stat(x);}
   double bitCount() const {return bits;}
   Writer* out;  // destination
+  friend struct ModelAccess;  // zpaq-rs: primes the predictor (zpaq_rs_ffi.cpp)
 private:
   U32 low, high; // range
   Predictor pr;  // to get p
@@ -1364,6 +1367,7 @@ public:
   const char* getChecksum() {return sha1.result();}
   void endBlock();
   int stat(int x) {return enc.stat(x);}
+  friend struct ModelAccess;  // zpaq-rs: primes the predictor (zpaq_rs_ffi.cpp)
 private:
   ZPAQL z, pz;  // model and test postprocessor
   Encoder enc;  // arithmetic encoder containing predictor
@@ -0,0 +1,67 @@
+// Builds the vendored zpaq.cpp with its file scanning routed through
+// zpaq_rs_ffi.cpp, so the crate can adjust what `zpaq add` records about
+// input files (see zpaq_jidac_set_fixed_mtime, zpaq_jidac_override_mtime
+// and zpaq_jidac_set_symlink_policy), throttle its reads (see
+// zpaq_jidac_set_read_throttle), count what it extracts (see
+// zpaq_jidac_set_write_hook), start its threads with the worker options
+// (see zpaq_set_worker_options) and reach long paths on Windows, without
+// patching zpaq.cpp.
+
+#ifdef unix
+#include <cstdio>
+#include <stdio.h>
+#include <sys/types.h>
+#include <sys/stat.h>
+#include <pthread.h>
+
+// Defined in zpaq_rs_ffi.cpp.  The system headers are included first so
+// only zpaq.cpp's own calls are renamed.
+int zpaq_rs_lstat(const char* path, struct stat* sb);
+bool zpaq_rs_is_reg(mode_t mode);
+FILE* zpaq_rs_fopen(const char* path, const char* mode);
+size_t zpaq_rs_fread(void* ptr, size_t size, size_t nobj, FILE* fp);
+size_t zpaq_rs_fwrite(const void* ptr, size_t size, size_t nobj, FILE* fp);
+int zpaq_rs_pthread_create(pthread_t* tid, const pthread_attr_t* attr, void* (*f)(void*), void* arg);
+int zpaq_rs_chmod(const char* path, mode_t mode);
+#define lstat zpaq_rs_lstat
+#undef S_ISREG
+#define S_ISREG(m) zpaq_rs_is_reg(m)
+#define fopen zpaq_rs_fopen
+#define fread zpaq_rs_fread
+#define fwrite zpaq_rs_fwrite
+#define pthread_create zpaq_rs_pthread_create
+#define chmod zpaq_rs_chmod
+#endif
+
+#ifdef _WIN32
+#ifndef UNICODE
+#define UNICODE
+#endif
+#include <windows.h>
+
+// Defined in zpaq_rs_ffi.cpp; they add the \\?\ prefix to long paths.
+HANDLE zpaq_rs_CreateFileW(LPCWSTR path, DWORD access, DWORD share, LPSECURITY_ATTRIBUTES sa,
+                           DWORD disposition, DWORD flags, HANDLE template_file);
+DWORD zpaq_rs_GetFileAttributesW(LPCWSTR path);
+BOOL zpaq_rs_SetFileAttributesW(LPCWSTR path, DWORD attributes);
+BOOL zpaq_rs_DeleteFileW(LPCWSTR path);
+BOOL zpaq_rs_CreateDirectoryW(LPCWSTR path, LPSECURITY_ATTRIBUTES sa);
+HANDLE zpaq_rs_FindFirstFileW(LPCWSTR pattern, LPWIN32_FIND_DATAW data);
+HANDLE zpaq_rs_CreateThread(LPSECURITY_ATTRIBUTES sa, SIZE_T stack, LPTHREAD_START_ROUTINE f, LPVOID arg,
+                            DWORD flags, LPDWORD id);
+#undef CreateFile
+#define CreateFile zpaq_rs_CreateFileW
+#undef GetFileAttributes
+#define GetFileAttributes zpaq_rs_GetFileAttributesW
+#undef SetFileAttributes
+#define SetFileAttributes zpaq_rs_SetFileAttributesW
+#undef DeleteFile
+#define DeleteFile zpaq_rs_DeleteFileW
+#undef CreateDirectory
+#define CreateDirectory zpaq_rs_CreateDirectoryW
+#undef FindFirstFile
+#define FindFirstFile zpaq_rs_FindFirstFileW
+#define CreateThread zpaq_rs_CreateThread
+#endif
+
+#include "zpaq/zpaq.cpp"
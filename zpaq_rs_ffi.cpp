@@ -1,16 +1,22 @@
 #include "zpaq/libzpaq.h"
 
+#include <cerrno>
 #include <cstddef>
 #include <cstdint>
 #include <cstring>
 #include <exception>
 #include <new>
 
+#include <algorithm>
+#include <atomic>
 #include <condition_variable>
 #include <deque>
+#include <map>
+#include <memory>
 #include <mutex>
 #include <string>
 #include <thread>
+#include <utility>
 #include <vector>
 
 #include <cstdio>
@@ -40,6 +46,11 @@
         return result;
     }
     
+    // zpaq_cli_main itself, for wide arguments from Rust's OsStr.
+    static int zpaq_cli_main_wide(int argc, LPWSTR* argv) {
+        return zpaq_cli_main(argc, argv);
+    }
+
     // Wrapper to call zpaq_cli_main with char** arguments
     static int zpaq_cli_main_wrapper(int argc, const char** argv) {
         std::vector<std::wstring> wargs;
@@ -58,6 +69,16 @@
     #define zpaq_cli_main zpaq_cli_main_wrapper
 #else
     #include <unistd.h>  // for dup, dup2, close
+    #include <pthread.h>
+    #include <sys/resource.h>
+    #include <sys/statvfs.h>
+    #ifdef __linux__
+        #include <sched.h>
+        #include <sys/syscall.h>
+    #endif
+    #ifdef __APPLE__
+        #include <pthread/qos.h>
+    #endif
     #define DEV_NULL "/dev/null"
     
     // On UNIX, main signature uses const char**
@@ -65,26 +86,6 @@
     int zpaq_cli_main(int argc, const char** argv);
 #endif
 
-// Reverse-search helper:
-// - Use system memrchr where it is known to exist (GNU/Linux + BSDs).
-// - Use a local fallback on platforms where memrchr is typically unavailable
-//   (notably macOS and Windows). IF PORTING: Here may be a point to change!
-static inline const void* zpaq_memrchr(const void* s, int c, size_t n) {
-#if defined(__APPLE__) || defined(_WIN32)
-    if (!s || n == 0) return nullptr;
-    const unsigned char* begin = static_cast<const unsigned char*>(s);
-    const unsigned char* p = begin + n;
-    const unsigned char ch = static_cast<unsigned char>(c);
-    while (p != begin) {
-        --p;
-        if (*p == ch) return p;
-    }
-    return nullptr;
-#else
-    return ::memrchr(s, c, n);
-#endif
-}
-
 namespace {
 
 class LibZpaqError final : public std::exception {
@@ -96,10 +97,193 @@ public:
 };
 
 thread_local std::string g_last_error;
+// Whether g_last_error reports input rejected by hardened parsing.
+thread_local bool g_last_error_corrupt = false;
 thread_local std::string g_last_stdout;
 thread_local std::string g_last_stderr;
 
-inline void clear_last_error() { g_last_error.clear(); }
+// Modification time reported for every file zpaq.cpp scans on this thread,
+// or -1 for the real one.
+thread_local int64_t g_fixed_mtime = -1;
+
+// Modification times reported for particular paths (as zpaq.cpp reaches
+// them from its inputs) on this thread, taking precedence over
+// g_fixed_mtime.
+thread_local std::map<std::string, int64_t> g_mtime_overrides;
+
+// Called with the size of each read zpaq.cpp makes on this thread, which
+// may sleep to throttle an add (see zpaq_jidac_set_read_throttle).  An add
+// reads its inputs on the calling thread without holding any of its job
+// locks, so the compression workers carry on meanwhile.  Null when unset.
+typedef void (*zpaq_read_throttle_fn)(void* ctx, uint64_t bytes);
+thread_local zpaq_read_throttle_fn g_read_throttle = nullptr;
+thread_local void* g_read_throttle_ctx = nullptr;
+
+// Called with the size of each write zpaq.cpp makes during the current run,
+// from whichever thread makes it.  Extraction writes files from its worker
+// threads, so this is not per thread: run_captured() installs the hook its
+// caller asked for (see zpaq_jidac_set_write_hook) while it holds the
+// command lock, and clears it before releasing it.  Null when unset.
+typedef void (*zpaq_write_hook_fn)(void* ctx, uint64_t bytes);
+std::atomic<zpaq_write_hook_fn> g_write_hook{nullptr};
+std::atomic<void*> g_write_hook_ctx{nullptr};
+
+// The hook asked for by the next run on this thread.
+thread_local zpaq_write_hook_fn g_requested_write_hook = nullptr;
+thread_local void* g_requested_write_hook_ctx = nullptr;
+
+// Scheduling priority and name prefix for the worker threads started on
+// behalf of this thread: the pool of compress_blocks_parallel() and the
+// threads of zpaq.cpp (see zpaq_set_worker_options).
+enum : int { kPriorityNormal = 0, kPriorityBelowNormal = 1, kPriorityIdle = 2 };
+struct WorkerOptions {
+  int priority = kPriorityNormal;
+  std::string name_prefix;
+  bool active() const { return priority != kPriorityNormal || !name_prefix.empty(); }
+};
+thread_local WorkerOptions g_worker_options;
+
+// Directory for the temporary files of commands run on this thread (see
+// zpaq_set_temp_dir); empty for tmpfile()'s default.
+thread_local std::string g_temp_dir;
+
+// A temporary file opened "w+b", in g_temp_dir if set and from tmpfile()
+// otherwise.  Closed and removed when destroyed.  release() hands over the
+// FILE*, the file then being removed already (Unix) or when closed
+// (Windows).
+class TempFile {
+public:
+  TempFile() {
+    if (g_temp_dir.empty()) {
+      f_ = tmpfile();
+      return;
+    }
+#ifdef _WIN32
+    wchar_t name[MAX_PATH];
+    if (GetTempFileNameW(utf8_to_wide(g_temp_dir.c_str()).c_str(), L"zrs", 0, name) == 0) return;
+    f_ = _wfopen(name, L"w+bD");
+    if (!f_) DeleteFileW(name);
+#else
+    std::string name = g_temp_dir + "/zpaq-rs-XXXXXX";
+    const int fd = mkstemp(&name[0]);
+    if (fd < 0) return;
+    f_ = fdopen(fd, "w+b");
+    if (!f_) {
+      close(fd);
+      unlink(name.c_str());
+      return;
+    }
+    path_ = name;
+#endif
+  }
+  TempFile(const TempFile&) = delete;
+  TempFile& operator=(const TempFile&) = delete;
+  ~TempFile() {
+    if (f_) fclose(f_);
+    if (!path_.empty()) remove(path_.c_str());
+  }
+
+  FILE* get() const { return f_; }
+
+  FILE* release() {
+    if (!path_.empty()) remove(path_.c_str());
+    path_.clear();
+    FILE* f = f_;
+    f_ = nullptr;
+    return f;
+  }
+
+private:
+  FILE* f_ = nullptr;
+  std::string path_;
+};
+
+// zpaq.cpp workers started by the current run on this thread, and how many
+// of them failed to take g_worker_options.
+thread_local int g_jidac_workers = 0;
+thread_local std::atomic<uint64_t> g_jidac_worker_failures{0};
+
+// Called from the thread that started them with the number of workers
+// whose priority or name could not be set. Set by
+// zpaq_set_worker_failure_hook(); null when unset.
+typedef void (*zpaq_worker_failure_fn)(uint64_t failures);
+std::atomic<zpaq_worker_failure_fn> g_worker_failure_hook{nullptr};
+
+void report_worker_failures(uint64_t failures) {
+  zpaq_worker_failure_fn hook = g_worker_failure_hook.load(std::memory_order_relaxed);
+  if (failures > 0 && hook) hook(failures);
+}
+
+// Gives the calling thread `priority` and the name `prefix` followed by
+// `index`, cutting the prefix to fit where names are short. Returns false
+// if either could not be set, leaving the thread as it was in that respect.
+bool setup_worker(int priority, const std::string& prefix, int index) {
+  bool ok = true;
+  if (!prefix.empty()) {
+    const std::string number = std::to_string(index);
+#if defined(_WIN32)
+    typedef HRESULT(WINAPI * SetDescriptionFn)(HANDLE, PCWSTR);
+    const SetDescriptionFn set_description = reinterpret_cast<SetDescriptionFn>(
+        reinterpret_cast<void*>(GetProcAddress(GetModuleHandleW(L"kernel32.dll"), "SetThreadDescription")));
+    const std::wstring name = utf8_to_wide((prefix + number).c_str());
+    ok = set_description && SUCCEEDED(set_description(GetCurrentThread(), name.c_str()));
+#elif defined(__linux__)
+    const size_t room = 15 - number.size();  // Linux names hold 15 bytes
+    const std::string name = prefix.substr(0, room) + number;
+    ok = pthread_setname_np(pthread_self(), name.c_str()) == 0;
+#elif defined(__APPLE__)
+    ok = pthread_setname_np((prefix.substr(0, 60) + number).c_str()) == 0;
+#else
+    ok = false;
+#endif
+  }
+  if (priority != kPriorityNormal) {
+#if defined(_WIN32)
+    const int level = priority == kPriorityIdle ? THREAD_PRIORITY_IDLE : THREAD_PRIORITY_BELOW_NORMAL;
+    ok = SetThreadPriority(GetCurrentThread(), level) && ok;
+#elif defined(__linux__)
+    // Linux keeps a nice value per thread.
+    if (priority == kPriorityIdle) {
+      sched_param param{};
+      ok = pthread_setschedparam(pthread_self(), SCHED_IDLE, &param) == 0 && ok;
+    } else {
+      const id_t tid = static_cast<id_t>(syscall(SYS_gettid));
+      errno = 0;
+      const int nice = getpriority(PRIO_PROCESS, tid);
+      ok = !(nice == -1 && errno != 0) && setpriority(PRIO_PROCESS, tid, std::min(nice + 10, 19)) == 0 && ok;
+    }
+#elif defined(__APPLE__)
+    const qos_class_t qos = priority == kPriorityIdle ? QOS_CLASS_BACKGROUND : QOS_CLASS_UTILITY;
+    ok = pthread_set_qos_class_self_np(qos, 0) == 0 && ok;
+#else
+    ok = false;
+#endif
+  }
+  return ok;
+}
+
+// What zpaq.cpp does with symbolic links during the current run.
+// Extraction closes files on worker threads, so these are not per thread:
+// run_captured() copies in the policy its caller asked for (see
+// zpaq_jidac_set_symlink_policy) while it holds the command lock, and puts
+// back the defaults before releasing it.
+enum : int { kLinksSkip = 0, kLinksStore = 1, kLinksFollow = 2 };
+enum : int { kLinksAsFiles = -1, kLinksRestore = 0, kLinksSkipWithWarning = 1 };
+std::atomic<int> g_add_links{kLinksSkip};
+std::atomic<int> g_extract_links{kLinksAsFiles};
+
+// The policy asked for by the next run on this thread.
+thread_local int g_requested_add_links = kLinksSkip;
+thread_local int g_requested_extract_links = kLinksAsFiles;
+
+#ifdef unix
+void zpaq_rs_reset_links();
+#endif
+
+inline void clear_last_error() {
+  g_last_error.clear();
+  g_last_error_corrupt = false;
+}
 
 inline void clear_last_output() {
   g_last_stdout.clear();
@@ -110,9 +294,64 @@ inline void set_last_error(const char* msg) {
   g_last_error.assign(msg ? msg : "(null)");
 }
 
+// Handles made by the zpaq_*_new functions on this thread, less those freed
+// on it (see zpaq_debug_live_objects).
+thread_local int64_t g_live_objects = 0;
+
+// Countdown to the zpaq_*_new call on this thread that fails on purpose;
+// 0 for none (see zpaq_debug_fail_new).
+thread_local int g_fail_new = 0;
+
+// A new T for a zpaq_*_new function, or null with the last error set.
+template <class T, class... Args>
+T* new_handle(Args&&... args) {
+  clear_last_error();
+  try {
+    if (g_fail_new > 0 && --g_fail_new == 0) {
+      set_last_error("injected allocation failure");
+      return nullptr;
+    }
+    T* p = new T(std::forward<Args>(args)...);
+    ++g_live_objects;
+    return p;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return nullptr;
+  }
+}
+
+// Deletes a handle made by new_handle(); null is ignored.
+template <class T>
+void free_handle(T* p) {
+  if (!p) return;
+  --g_live_objects;
+  delete p;
+}
+
 // Sentinel for Rust callback failure (panic or explicit error)
 constexpr int kRustCallbackError = -2;
 
+// Returned by zpaq_jidac_run() when the command failed because a file it
+// opened was in use (locked, or open without sharing, by another process);
+// the last error is then that file's path.
+constexpr int kArchiveBusy = -3;
+
+// Path of the last file zpaq.cpp found in use during the current run.
+std::mutex g_busy_mu;
+std::string g_busy_path;
+
+void set_busy_path(const std::string& path) {
+  std::lock_guard<std::mutex> lock(g_busy_mu);
+  g_busy_path = path;
+}
+
+std::string take_busy_path() {
+  std::lock_guard<std::mutex> lock(g_busy_mu);
+  std::string path;
+  path.swap(g_busy_path);
+  return path;
+}
+
 struct CountingWriter final : public libzpaq::Writer {
   uint64_t n = 0;
   void put(int) override { ++n; }
@@ -121,6 +360,302 @@ struct CountingWriter final : public libzpaq::Writer {
   }
 };
 
+// Reads from a buffer in memory, with no Rust callback.
+struct BytesReader final : public libzpaq::Reader {
+  const unsigned char* p;
+  size_t left;
+  BytesReader(const unsigned char* buf, size_t n) : p(buf), left(n) {}
+  int get() override {
+    if (left == 0) return -1;
+    --left;
+    return *p++;
+  }
+  int read(char* buf, int n) override {
+    if (!buf || n <= 0) return 0;
+    const size_t k = std::min(left, static_cast<size_t>(n));
+    std::memcpy(buf, p, k);
+    p += k;
+    left -= k;
+    return static_cast<int>(k);
+  }
+};
+
+// SHA-1 hashes everything written to it, then passes it on to `next` (if
+// any).  Bytes put one at a time are hashed with SHA1::write in 64 KiB
+// chunks, so a hashing pass costs about what SHA1 itself does.
+class HashSink final : public libzpaq::Writer {
+  static constexpr int kChunk = 1 << 16;
+  libzpaq::SHA1* sha1_;
+  libzpaq::Writer* next_;
+  std::vector<char> buf_;
+  int used_ = 0;
+
+public:
+  HashSink(libzpaq::SHA1* sha1, libzpaq::Writer* next) : sha1_(sha1), next_(next), buf_(kChunk) {}
+
+  ~HashSink() override { flush(); }
+
+  void flush() {
+    if (used_ <= 0) return;
+    sha1_->write(buf_.data(), used_);
+    if (next_) next_->write(buf_.data(), used_);
+    used_ = 0;
+  }
+
+  void put(int c) override {
+    buf_[used_++] = static_cast<char>(c);
+    if (used_ == kChunk) flush();
+  }
+
+  void write(const char* buf, int n) override {
+    if (!buf || n <= 0) return;
+    flush();
+    sha1_->write(buf, n);
+    if (next_) next_->write(buf, n);
+  }
+};
+
+// Hashes everything written to it with SHA-1 or SHA-256 (either may be
+// null), and with a SHA-1 of the current segment, then passes it on to
+// `next` (if any).  Buffered like HashSink.
+class DigestSink final : public libzpaq::Writer {
+  static constexpr int kChunk = 1 << 16;
+  libzpaq::SHA1* sha1_;
+  libzpaq::SHA256* sha256_;
+  libzpaq::Writer* next_;
+  libzpaq::SHA1 segment_;
+  std::vector<char> buf_;
+  int used_ = 0;
+  uint64_t n_ = 0;
+
+  void consume(const char* buf, int n) {
+    segment_.write(buf, n);
+    if (sha1_) sha1_->write(buf, n);
+    if (sha256_)
+      for (int i = 0; i < n; ++i) sha256_->put(static_cast<unsigned char>(buf[i]));
+    if (next_) next_->write(buf, n);
+    n_ += static_cast<uint64_t>(n);
+  }
+
+public:
+  DigestSink(libzpaq::SHA1* sha1, libzpaq::SHA256* sha256, libzpaq::Writer* next)
+      : sha1_(sha1), sha256_(sha256), next_(next), buf_(kChunk) {}
+
+  ~DigestSink() override { flush(); }
+
+  void flush() {
+    if (used_ <= 0) return;
+    consume(buf_.data(), used_);
+    used_ = 0;
+  }
+
+  // SHA-1 of the segment so far; starts the next one.
+  const char* segment_result() {
+    flush();
+    return segment_.result();
+  }
+
+  uint64_t size() const { return n_ + static_cast<uint64_t>(used_); }
+
+  void put(int c) override {
+    buf_[used_++] = static_cast<char>(c);
+    if (used_ == kChunk) flush();
+  }
+
+  void write(const char* buf, int n) override {
+    if (!buf || n <= 0) return;
+    flush();
+    consume(buf, n);
+  }
+};
+
+// Hardened parsing (see zpaq_set_hardened_parsing): header fields are range
+// checked before libzpaq acts on them.
+std::atomic<int> g_hardened{1};
+
+// Longest segment filename or comment accepted under hardened parsing.
+constexpr uint64_t kMaxSegmentField = 1 << 16;
+
+// Rejects input that fails a hardened parsing check.  The error is reported
+// to Rust as ZpaqError::Corrupt.
+[[noreturn]] void corrupt(const char* msg) {
+  g_last_error_corrupt = true;
+  throw LibZpaqError(std::string("corrupt input: ") + msg);
+}
+
+// Checks the block header the decompresser just read against the ranges
+// libzpaq accepts, so that a damaged header fails before any of its model
+// is allocated rather than part way through.  The header is hsize[2] hh hm
+// ph pm n, n components, 0, HCOMP, 0.
+void check_block_header(libzpaq::Decompresser* d) {
+  if (!g_hardened.load(std::memory_order_relaxed)) return;
+  libzpaq::StringBuffer hdr;
+  d->hcomp(&hdr);
+  const unsigned char* h = hdr.data();
+  const size_t size = hdr.size();
+  if (size < 8) corrupt("block header too short");
+  if (size != static_cast<size_t>(h[0] + 256 * h[1]) + 2)
+    corrupt("HCOMP length does not match the declared header size");
+  for (int i = 2; i < 6; ++i)
+    if (h[i] > 32) corrupt("ZPAQL array size out of range");
+  const int n = h[6];
+  size_t cp = 7;
+  for (int i = 0; i < n; ++i) {
+    if (cp >= size) corrupt("component list overflows the header");
+    const int len = libzpaq::compsize[h[cp]];
+    if (len < 1 || cp + len >= size) corrupt("invalid component");
+    const unsigned char* c = h + cp;
+    switch (c[0]) {
+      case libzpaq::CONS:
+        break;
+      case libzpaq::CM:
+        if (c[1] > 32) corrupt("CM size out of range");
+        break;
+      case libzpaq::ICM:
+        if (c[1] > 26) corrupt("ICM size out of range");
+        break;
+      case libzpaq::MATCH:
+        if (c[1] > 32 || c[2] > 32) corrupt("MATCH size out of range");
+        break;
+      case libzpaq::AVG:
+        if (c[1] >= i || c[2] >= i) corrupt("AVG input out of range");
+        break;
+      case libzpaq::MIX2:
+        if (c[1] > 32) corrupt("MIX2 size out of range");
+        if (c[2] >= i || c[3] >= i) corrupt("MIX2 input out of range");
+        break;
+      case libzpaq::MIX:
+        if (c[1] > 32) corrupt("MIX size out of range");
+        if (c[2] >= i || c[3] < 1 || c[3] > i - c[2]) corrupt("MIX inputs out of range");
+        break;
+      case libzpaq::ISSE:
+        if (c[1] > 32) corrupt("ISSE size out of range");
+        if (c[2] >= i) corrupt("ISSE input out of range");
+        break;
+      case libzpaq::SSE:
+        if (c[1] > 32) corrupt("SSE size out of range");
+        if (c[2] >= i) corrupt("SSE input out of range");
+        if (c[3] > c[4] * 4) corrupt("SSE start above limit");
+        break;
+      default:
+        corrupt("invalid component");
+    }
+    cp += len;
+  }
+  if (h[cp] != 0) corrupt("missing COMP END");
+}
+
+// Passes a segment filename or comment on to `next` (if any), failing once
+// it runs past kMaxSegmentField bytes under hardened parsing.
+class FieldWriter final : public libzpaq::Writer {
+  libzpaq::Writer* next_;
+  const char* too_long_;
+  uint64_t n_ = 0;
+
+public:
+  FieldWriter(libzpaq::Writer* next, const char* too_long) : next_(next), too_long_(too_long) {}
+
+  void put(int c) override {
+    if (++n_ > kMaxSegmentField && g_hardened.load(std::memory_order_relaxed)) corrupt(too_long_);
+    if (next_) next_->put(c);
+  }
+};
+
+bool find_block_checked(libzpaq::Decompresser* d, double* mem_out) {
+  if (!d->findBlock(mem_out)) return false;
+  check_block_header(d);
+  return true;
+}
+
+bool find_filename_checked(libzpaq::Decompresser* d, libzpaq::Writer* filename_out) {
+  FieldWriter field(filename_out, "segment filename too long");
+  return d->findFilename(&field);
+}
+
+void read_comment_checked(libzpaq::Decompresser* d, libzpaq::Writer* comment_out) {
+  FieldWriter field(comment_out, "segment comment too long");
+  d->readComment(&field);
+}
+
+// Segment filename of the stream index compress_stream_with() can append
+// (STREAM_INDEX_FILENAME in seekable.rs). Its data describes the stream
+// rather than being part of it, so the decompress loops skip it.
+constexpr char kStreamIndexFilename[] = ".zpaq-rs-stream-index";
+
+class NameWriter final : public libzpaq::Writer {
+public:
+  std::string name;
+  void put(int c) override { name.push_back(static_cast<char>(c)); }
+};
+
+// find_filename_checked() and read_comment_checked() for the decompress
+// loops: finds the next segment whose data belongs in the output, reading
+// past stream index segments without decoding them.
+bool find_data_segment(libzpaq::Decompresser* d) {
+  for (;;) {
+    NameWriter filename;
+    if (!find_filename_checked(d, &filename)) return false;
+    read_comment_checked(d, nullptr);
+    if (filename.name != kStreamIndexFilename) return true;
+    d->readSegmentEnd();
+  }
+}
+
+// libzpaq::decompress with the hardened parsing checks.
+void decompress_checked(libzpaq::Reader* in, libzpaq::Writer* out) {
+  libzpaq::Decompresser d;
+  d.setInput(in);
+  d.setOutput(out);
+  while (find_block_checked(&d, nullptr)) {
+    while (find_data_segment(&d)) {
+      d.decompress();
+      d.readSegmentEnd();
+    }
+  }
+}
+
+} // namespace
+
+// Priming runs bytes through a block's model without coding them, so a
+// compressor and a decompresser primed alike start the segment with the
+// same statistics.  The predictors are private to libzpaq's coders, which
+// the vendored libzpaq.h patches to befriend this class (the lines marked
+// "zpaq-rs"); nothing else there changes.
+namespace libzpaq {
+struct ModelAccess {
+  static Predictor& predictor(Compressor& c) { return c.enc.pr; }
+  static Predictor& predictor(Decompresser& d) { return d.dec.pr; }
+};
+} // namespace libzpaq
+
+namespace {
+
+// Updates the model as if buf[0..n-1] had been coded, as Encoder::compress
+// and Decoder::decompress do bit by bit, MSB first.
+void prime_predictor(libzpaq::Predictor& pr, const char* buf, size_t n) {
+  if (!pr.isModeled()) return;
+  for (size_t i = 0; i < n; ++i) {
+    const int c = static_cast<unsigned char>(buf[i]);
+    for (int j = 7; j >= 0; --j) {
+      pr.predict();
+      pr.update(c >> j & 1);
+    }
+  }
+}
+
+// The segment's postprocessor header is coded first, as compress() would.
+void prime_compressor(libzpaq::Compressor* c, const char* buf, size_t n) {
+  c->postProcess();
+  prime_predictor(libzpaq::ModelAccess::predictor(*c), buf, n);
+}
+
+// decompress(0) starts the block and reads the postprocessor header, as
+// the compressor coded it before priming, and decodes no data.
+void prime_decompresser(libzpaq::Decompresser* d, const char* buf, size_t n) {
+  d->decompress(0);
+  prime_predictor(libzpaq::ModelAccess::predictor(*d), buf, n);
+}
+
 } // namespace
 
 namespace {
@@ -139,6 +674,15 @@ const char* zpaq_last_error_ptr() { return g_last_error.empty() ? nullptr : g_la
 
 size_t zpaq_last_error_len() { return g_last_error.size(); }
 
+// 1 if the last error reports input rejected by hardened parsing.
+int zpaq_last_error_is_corrupt() { return g_last_error_corrupt ? 1 : 0; }
+
+// Turns hardened parsing of block and segment headers on (the default) or
+// off, for all threads.
+void zpaq_set_hardened_parsing(int on) { g_hardened.store(on != 0, std::memory_order_relaxed); }
+
+int zpaq_hardened_parsing() { return g_hardened.load(std::memory_order_relaxed); }
+
 void zpaq_set_last_error(const char* msg) { set_last_error(msg); }
 
 // Copy error message into buf (not NUL-terminated unless space allows).
@@ -205,11 +749,32 @@ struct zpaq_writer {
 
 class RustReader final : public libzpaq::Reader {
   zpaq_reader inner_;
+  // Read-ahead for set_chunk(); empty while reads go straight through.
+  std::vector<char> buf_;
+  size_t pos_ = 0;
+  size_t end_ = 0;
+
+  // Refills the read-ahead from the callback; false at the end of input.
+  bool fill() {
+    pos_ = end_ = 0;
+    const int got = inner_.read_cb(inner_.ctx, buf_.data(), static_cast<int>(buf_.size()));
+    if (got == kRustCallbackError) libzpaq::error("Rust reader callback failed");
+    if (got <= 0) return false;
+    end_ = static_cast<size_t>(got);
+    return true;
+  }
 
 public:
   RustReader(void* ctx, zpaq_get_fn get_cb, zpaq_read_fn read_cb)
       : inner_(ctx, get_cb, read_cb) {}
 
+  // Reads n bytes from the callback at a time, whatever libzpaq asks for;
+  // 0 passes each request straight through.  Only before the first read.
+  void set_chunk(int n) {
+    buf_.assign(n > 0 ? static_cast<size_t>(n) : 0, 0);
+    pos_ = end_ = 0;
+  }
+
   int get() override {
     if (inner_.get_cb) {
       const int v = inner_.get_cb(inner_.ctx);
@@ -217,6 +782,10 @@ public:
       return v;
     }
     if (!inner_.read_cb) return -1;
+    if (!buf_.empty()) {
+      if (pos_ == end_ && !fill()) return -1;
+      return static_cast<unsigned char>(buf_[pos_++]);
+    }
     char b = 0;
     const int n = inner_.read_cb(inner_.ctx, &b, 1);
     if (n == kRustCallbackError) libzpaq::error("Rust reader callback failed");
@@ -226,6 +795,13 @@ public:
 
   int read(char* buf, int n) override {
     if (!buf || n <= 0) return 0;
+    if (inner_.read_cb && !buf_.empty()) {
+      if (pos_ == end_ && !fill()) return 0;
+      const size_t take = std::min(static_cast<size_t>(n), end_ - pos_);
+      std::memcpy(buf, buf_.data() + pos_, take);
+      pos_ += take;
+      return static_cast<int>(take);
+    }
     if (inner_.read_cb) {
       const int got = inner_.read_cb(inner_.ctx, buf, n);
       if (got == kRustCallbackError) libzpaq::error("Rust reader callback failed");
@@ -238,33 +814,63 @@ public:
 
 class RustWriter final : public libzpaq::Writer {
   zpaq_writer inner_;
-  char buf_[kPutBufferSize];
+  std::vector<char> buf_ = std::vector<char>(kPutBufferSize);
   int used_ = 0;
+  int limit_ = kPutBufferSize;
+  bool buffered_ = true;
 
+  // The bytes are dropped before the callback, so that after a failure the
+  // destructor does not hand them to the failing writer again.
   void flush_buf() {
-    if (used_ <= 0) return;
+    const int used = used_;
+    if (used <= 0) return;
+    used_ = 0;
     if (inner_.write_cb) {
-      const int rc = inner_.write_cb(inner_.ctx, buf_, used_);
+      const int rc = inner_.write_cb(inner_.ctx, buf_.data(), used);
       if (rc == kRustCallbackError) libzpaq::error("Rust writer callback failed");
     } else if (inner_.put_cb) {
-      for (int i = 0; i < used_; ++i) {
+      for (int i = 0; i < used; ++i) {
         const int rc = inner_.put_cb(inner_.ctx, static_cast<unsigned char>(buf_[i]));
         if (rc == kRustCallbackError) libzpaq::error("Rust writer callback failed");
       }
     }
-    used_ = 0;
   }
 
 public:
   RustWriter(void* ctx, zpaq_put_fn put_cb, zpaq_write_fn write_cb)
       : inner_(ctx, put_cb, write_cb) {}
 
-  ~RustWriter() override { flush_buf(); }
+  // A destructor must not throw; a failure here was already reported, or
+  // has no caller left to report it to.
+  ~RustWriter() override {
+    try {
+      flush_buf();
+    } catch (...) {
+    }
+  }
+
+  // Hands buffered bytes to the callback now.
+  void flush() { flush_buf(); }
+
+  // Whether put() batches bytes into one callback per chunk (the default)
+  // or calls back for each byte.
+  void set_buffered(bool buffered) {
+    flush_buf();
+    buffered_ = buffered;
+    limit_ = buffered ? static_cast<int>(buf_.size()) : 1;
+  }
+
+  // The bytes put() batches into one callback; kPutBufferSize by default.
+  void set_chunk(int n) {
+    flush_buf();
+    buf_.resize(n > 0 ? static_cast<size_t>(n) : 1);
+    limit_ = buffered_ ? static_cast<int>(buf_.size()) : 1;
+  }
 
   void put(int c) override {
     if (!inner_.write_cb && !inner_.put_cb) return;
     buf_[used_++] = static_cast<char>(c);
-    if (used_ == kPutBufferSize) flush_buf();
+    if (used_ == limit_) flush_buf();
   }
 
   void write(const char* buf, int n) override {
@@ -282,40 +888,42 @@ public:
 
 // Opaque handles exposed to Rust.
 RustReader* zpaq_reader_new(void* ctx, zpaq_get_fn get_cb, zpaq_read_fn read_cb) {
-  clear_last_error();
-  try {
-    return new RustReader(ctx, get_cb, read_cb);
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<RustReader>(ctx, get_cb, read_cb);
 }
 
 void zpaq_reader_free(RustReader* r) {
-  delete r;
+  free_handle(r);
 }
 
 RustWriter* zpaq_writer_new(void* ctx, zpaq_put_fn put_cb, zpaq_write_fn write_cb) {
+  return new_handle<RustWriter>(ctx, put_cb, write_cb);
+}
+
+void zpaq_writer_free(RustWriter* w) {
+  free_handle(w);
+}
+
+void zpaq_writer_set_buffered(RustWriter* w, int buffered) {
+  if (w) w->set_buffered(buffered != 0);
+}
+
+int zpaq_reader_set_chunk(RustReader* r, int n) {
   clear_last_error();
   try {
-    return new RustWriter(ctx, put_cb, write_cb);
+    if (!r) return -1;
+    r->set_chunk(n);
+    return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
-    return nullptr;
+    return -1;
   }
 }
 
-void zpaq_writer_free(RustWriter* w) {
-  delete w;
-}
-
-// ---------------- Top-level convenience API ----------------
-
-int zpaq_compress(RustReader* in, RustWriter* out, const char* method, const char* filename,
-                 const char* comment, int dosha1) {
+int zpaq_writer_set_chunk(RustWriter* w, int n) {
   clear_last_error();
   try {
-    libzpaq::compress(in, out, method, filename, comment, dosha1 != 0);
+    if (!w) return -1;
+    w->set_chunk(n);
     return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
@@ -323,10 +931,11 @@ int zpaq_compress(RustReader* in, RustWriter* out, const char* method, const cha
   }
 }
 
-int zpaq_decompress(RustReader* in, RustWriter* out) {
+int zpaq_writer_flush(RustWriter* w) {
   clear_last_error();
   try {
-    libzpaq::decompress(in, out);
+    if (!w) return -1;
+    w->flush();
     return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
@@ -346,13 +955,477 @@ static int method_block_size(const char* method) {
   return block > 0 ? block : (1 << 20);
 }
 
-int zpaq_compress_size(RustReader* in, const char* method, const char* filename, const char* comment, int dosha1,
-                      uint64_t* out_size) {
+// ---------------- Store fast path ----------------
+
+// compressBlock() expands every method beginning with '0' to a store block
+// ("0B,0": no components, no preprocessing).
+static bool is_store_method(const char* method) {
+  return method && method[0] == '0';
+}
+
+static void put_u32_be(libzpaq::Writer* out, uint32_t v) {
+  out->put((v >> 24) & 255);
+  out->put((v >> 16) & 255);
+  out->put((v >> 8) & 255);
+  out->put(v & 255);
+}
+
+// Write the same bytes compressBlock() produces for a store method, but copy
+// the payload straight to `out` instead of feeding it byte by byte through
+// the (unmodeled) encoder.
+static void store_block(const char* data, size_t n, libzpaq::Writer* out, const char* filename,
+                        const char* comment, bool dosha1) {
+  // Block size argument, as in compressBlock(): lg(n+4095)-20, at least 0.
+  int arg0 = 0;
+  for (size_t v = n + 4095; v >> (arg0 + 20); ) ++arg0;
+
+  char sha1result[20];
+  if (dosha1) {
+    libzpaq::SHA1 sha1;
+    sha1.write(data, static_cast<int64_t>(n));
+    std::memcpy(sha1result, sha1.result(), 20);
+  }
+
+  const std::string method = "0" + std::to_string(arg0) + ",0";
+  int args[9] = {0};
+  const std::string config = libzpaq::makeConfig(method.c_str(), args);
+  libzpaq::Compressor co;
+  co.setOutput(out);
+  libzpaq::StringBuffer pcomp_cmd;
+  co.writeTag();
+  co.startBlock(config.c_str(), args, &pcomp_cmd);
+  std::string cs = std::to_string(n);
+  if (comment) cs = cs + " " + comment;
+  co.startSegment(filename, cs.c_str());
+
+  // An unmodeled encoder emits (PASS byte + data) in 64 KiB chunks, each
+  // prefixed by a big-endian length, terminated by a zero length.
+  constexpr size_t kChunk = 1 << 16;
+  size_t pos = 0;
+  bool first = true;
+  while (first || pos < n) {
+    const size_t room = first ? kChunk - 1 : kChunk;
+    const size_t len = n - pos < room ? n - pos : room;
+    put_u32_be(out, static_cast<uint32_t>(len + (first ? 1 : 0)));
+    if (first) out->put(0);  // PASS: no postprocessor
+    if (len) out->write(data + pos, static_cast<int>(len));
+    pos += len;
+    first = false;
+  }
+  put_u32_be(out, 0);
+  if (dosha1) {
+    out->put(253);
+    for (int i = 0; i < 20; ++i) out->put(sha1result[i]);
+  } else {
+    out->put(254);
+  }
+  out->put(255);
+}
+
+// compressBlock() with the store fast path.
+static void compress_block(libzpaq::StringBuffer* in, libzpaq::Writer* out, const char* method,
+                           const char* filename, const char* comment, bool dosha1) {
+  if (is_store_method(method)) {
+    store_block(reinterpret_cast<const char*>(in->data()), in->size(), out, filename, comment, dosha1);
+  } else {
+    libzpaq::compressBlock(in, out, method, filename, comment, dosha1);
+  }
+}
+
+// Read until `n` bytes or EOF so block boundaries do not depend on how the
+// underlying reader splits its reads.
+static int read_full(libzpaq::Reader* in, char* buf, int n) {
+  int got = 0;
+  while (got < n) {
+    const int r = in->read(buf + got, n - got);
+    if (r <= 0) break;
+    got += r;
+  }
+  return got;
+}
+
+// Counts bytes and forwards them to `next`, if any.
+struct CountingTee final : public libzpaq::Writer {
+  libzpaq::Writer* next;
+  uint64_t n = 0;
+  explicit CountingTee(libzpaq::Writer* next_) : next(next_) {}
+  void put(int c) override {
+    ++n;
+    if (next) next->put(c);
+  }
+  void write(const char* buf, int len) override {
+    if (!buf || len <= 0) return;
+    n += static_cast<uint64_t>(len);
+    if (next) next->write(buf, len);
+  }
+};
+
+// Called with the uncompressed and compressed size of each block
+// compress_blocks() and compress_blocks_parallel() write, from the calling
+// thread, in order. Set by zpaq_set_block_hook(); null when unset.
+typedef void (*zpaq_block_fn)(uint64_t uncompressed, uint64_t compressed);
+static std::atomic<zpaq_block_fn> g_block_hook{nullptr};
+
+static void report_block(uint64_t uncompressed, uint64_t compressed) {
+  if (zpaq_block_fn hook = g_block_hook.load(std::memory_order_relaxed)) hook(uncompressed, compressed);
+}
+
+struct StringWriter final : public libzpaq::Writer {
+  std::string s;
+  void put(int c) override { s.push_back(static_cast<char>(c)); }
+  void write(const char* buf, int len) override {
+    if (buf && len > 0) s.append(buf, static_cast<size_t>(len));
+  }
+};
+
+// compress_block(), but writes a store block instead when the compressed
+// block would be larger than its input, as zpaq add stores fragments that
+// do not compress. Returns whether it stored. The block is compressed
+// into memory first, and its input copied, since preprocessing (E8E9)
+// rewrites `in` in place.
+static bool compress_block_or_store(libzpaq::StringBuffer* in, libzpaq::Writer* out, const char* method,
+                                    const char* filename, const char* comment, bool dosha1) {
+  if (is_store_method(method)) {
+    compress_block(in, out, method, filename, comment, dosha1);
+    return false;
+  }
+  const std::string original(in->c_str(), in->size());
+  StringWriter bytes;
+  libzpaq::compressBlock(in, &bytes, method, filename, comment, dosha1);
+  if (bytes.s.size() > original.size()) {
+    store_block(original.data(), original.size(), out, filename, comment, dosha1);
+    return true;
+  }
+  for (size_t pos = 0; pos < bytes.s.size();) {
+    const size_t len = std::min(bytes.s.size() - pos, size_t(1) << 30);
+    out->write(bytes.s.data() + pos, static_cast<int>(len));
+    pos += len;
+  }
+  return false;
+}
+
+struct BlockSize {
+  uint64_t uncompressed;
+  uint64_t compressed;
+};
+
+// The word compress_blocks() ends each segment comment with for an extra
+// checksum: "sha1:" (hash 1) or "sha256:" (hash 2) and the lowercase hex
+// digest of the segment's data.
+static std::string checksum_word(int hash, const unsigned char* data, size_t n) {
+  static const char kDigits[] = "0123456789abcdef";
+  libzpaq::SHA1 sha1;
+  libzpaq::SHA256 sha256;
+  const char* digest;
+  int len;
+  if (hash == 1) {
+    sha1.write(reinterpret_cast<const char*>(data), static_cast<int64_t>(n));
+    digest = sha1.result();
+    len = 20;
+  } else {
+    for (size_t i = 0; i < n; ++i) sha256.put(data[i]);
+    digest = sha256.result();
+    len = 32;
+  }
+  std::string word = hash == 1 ? "sha1:" : "sha256:";
+  for (int i = 0; i < len; ++i) {
+    word += kDigits[(digest[i] >> 4) & 15];
+    word += kDigits[digest[i] & 15];
+  }
+  return word;
+}
+
+// libzpaq::compress() with full-block reads and the store fast path. With
+// `stored` set, blocks that would expand are stored instead (see
+// compress_block_or_store) and counted there. With `sizes` set, each
+// block's sizes are appended to it. With `extra_hash` (1 or 2), each
+// segment comment ends with a checksum of the data (see checksum_word).
+static void compress_blocks(libzpaq::Reader* in, libzpaq::Writer* out, const char* method,
+                            const char* filename, const char* comment, bool dosha1,
+                            uint64_t* stored = nullptr, std::vector<BlockSize>* sizes = nullptr,
+                            int extra_hash = 0) {
+  const int bs = method_block_size(method);
+  libzpaq::StringBuffer sb(bs);
+  sb.write(nullptr, bs);
+  int n = bs;
+  const std::string given = comment ? comment : "";
+  std::string with_checksum;
+  // A short read ends the input, as a read of 0 does. Empty input still
+  // makes one (empty) block, so the output is an archive.
+  for (bool first = true; in && n == bs; first = false) {
+    n = read_full(in, reinterpret_cast<char*>(sb.data()), bs);
+    if (n == 0 && !first) break;
+    sb.resize(n);
+    if (extra_hash) {
+      // Hashed before compressing, which may preprocess the data in place.
+      with_checksum = comment ? given + " " : "";
+      with_checksum += checksum_word(extra_hash, sb.data(), static_cast<size_t>(n));
+      comment = with_checksum.c_str();
+    }
+    if (stored || sizes) {
+      CountingTee tee(out);
+      if (!stored)
+        compress_block(&sb, &tee, method, filename, comment, dosha1);
+      else if (compress_block_or_store(&sb, &tee, method, filename, comment, dosha1))
+        ++*stored;
+      if (sizes) sizes->push_back(BlockSize{static_cast<uint64_t>(n), tee.n});
+      report_block(static_cast<uint64_t>(n), tee.n);
+    } else if (g_block_hook.load(std::memory_order_relaxed)) {
+      CountingTee tee(out);
+      compress_block(&sb, &tee, method, filename, comment, dosha1);
+      report_block(static_cast<uint64_t>(n), tee.n);
+    } else {
+      compress_block(&sb, out, method, filename, comment, dosha1);
+    }
+    filename = nullptr;
+    comment = nullptr;
+    sb.resize(0);
+  }
+}
+
+// Split `in` into blocks of `bs` bytes and compress each with compress_block()
+// on `threads` workers. Blocks are written to `out` (if not null) and their
+// sizes appended to `sizes` (if not null) strictly in input order, from the
+// calling thread, so the output is byte-identical to compress_blocks() with
+// the same block size whatever the thread count. At most 2*threads blocks
+// are in flight, and no more workers start than there are blocks, so memory
+// use does not grow with the input.
+static void compress_blocks_parallel(libzpaq::Reader* in, libzpaq::Writer* out, const char* method,
+                                     const char* filename, const char* comment, bool dosha1,
+                                     int threads, int bs, std::vector<BlockSize>* sizes) {
+  if (sizes) sizes->clear();
+  if (threads <= 1) {
+    libzpaq::StringBuffer sb(bs);
+    sb.write(nullptr, bs);
+    int n = bs;
+    for (bool first = true; in && n == bs; first = false) {
+      n = read_full(in, reinterpret_cast<char*>(sb.data()), bs);
+      if (n == 0 && !first) break;
+      sb.resize(n);
+      CountingTee tee(out);
+      compress_block(&sb, &tee, method, filename, comment, dosha1);
+      if (sizes) sizes->push_back(BlockSize{static_cast<uint64_t>(n), tee.n});
+      report_block(static_cast<uint64_t>(n), tee.n);
+      filename = nullptr;
+      comment = nullptr;
+      sb.resize(0);
+    }
+    return;
+  }
+
+  struct Block {
+    size_t idx;
+    std::string data;
+  };
+  struct Compressed {
+    BlockSize size;
+    std::string bytes;
+  };
+
+  std::mutex mu;
+  std::condition_variable cv_work;      // workers: queue non-empty or finished
+  std::condition_variable cv_progress;  // caller: a block finished or failed
+  std::deque<Block> q;
+  std::map<size_t, Compressed> finished;
+  size_t next_read = 0;
+  size_t next_emit = 0;
+  bool done = false;
+  bool failed = false;
+  std::string fail_msg;
+
+  auto fail = [&](const char* msg) {
+    std::lock_guard<std::mutex> lock(mu);
+    if (!failed) {
+      failed = true;
+      fail_msg = msg;
+    }
+    cv_work.notify_all();
+    cv_progress.notify_all();
+  };
+
+  auto worker = [&]() {
+    for (;;) {
+      Block blk;
+      {
+        std::unique_lock<std::mutex> lock(mu);
+        cv_work.wait(lock, [&] { return failed || done || !q.empty(); });
+        if (failed) return;
+        if (q.empty()) {
+          if (done) return;
+          continue;
+        }
+        blk = std::move(q.front());
+        q.pop_front();
+      }
+
+      try {
+        libzpaq::StringBuffer sb(bs);
+        sb.write(nullptr, blk.data.size());
+        if (blk.data.size()) std::memcpy(sb.data(), blk.data.data(), blk.data.size());
+        sb.resize(blk.data.size());
+
+        StringWriter bytes;
+        CountingTee tee(out ? &bytes : nullptr);
+        const char* fn = (blk.idx == 0) ? filename : nullptr;
+        const char* cm = (blk.idx == 0) ? comment : nullptr;
+        compress_block(&sb, &tee, method, fn, cm, dosha1);
+
+        std::lock_guard<std::mutex> lock(mu);
+        finished[blk.idx] = Compressed{BlockSize{blk.data.size(), tee.n}, std::move(bytes.s)};
+        cv_progress.notify_all();
+      } catch (const std::exception& e) {
+        fail(e.what());
+        return;
+      }
+    }
+  };
+
+  const WorkerOptions options = g_worker_options;
+  std::atomic<uint64_t> setup_failures{0};
+  // Workers start as blocks are read, one per block up to `threads`, so a
+  // short input starts no more workers than it has blocks.  If the system
+  // will not start another, those already running share the blocks.
+  std::vector<std::thread> pool;
+  size_t max_workers = static_cast<size_t>(threads);
+  auto add_worker = [&] {
+    if (pool.size() >= max_workers) return;
+    const int i = static_cast<int>(pool.size());
+    try {
+      pool.emplace_back([&, i] {
+        if (options.active() && !setup_worker(options.priority, options.name_prefix, i)) ++setup_failures;
+        worker();
+      });
+    } catch (const std::exception&) {
+      if (pool.empty()) throw;
+      max_workers = pool.size();
+    }
+  };
+  auto max_in_flight = [&] { return std::max<size_t>(pool.size(), 1) * 2; };
+
+  // Emit finished blocks in order; read more input while there is room.
+  try {
+    bool eof = false;
+    for (;;) {
+      Compressed ready;
+      {
+        std::unique_lock<std::mutex> lock(mu);
+        cv_progress.wait(lock, [&] {
+          return failed || finished.count(next_emit) ||
+                 (!eof && next_read - next_emit < max_in_flight()) || (eof && next_emit == next_read);
+        });
+        if (failed || (eof && next_emit == next_read)) break;
+        auto it = finished.find(next_emit);
+        if (it == finished.end()) {
+          lock.unlock();
+          std::string buf(static_cast<size_t>(bs), '\0');
+          const int n = read_full(in, &buf[0], bs);
+          lock.lock();
+          if (n < bs) {
+            eof = true;
+            done = true;
+            cv_work.notify_all();
+            // Empty input still makes one (empty) block, as in compress_blocks().
+            if (n <= 0 && next_read > 0) continue;
+          }
+          buf.resize(static_cast<size_t>(n));
+          q.push_back(Block{next_read++, std::move(buf)});
+          cv_work.notify_one();
+          if (pool.size() < next_read) {
+            lock.unlock();
+            add_worker();
+          }
+          continue;
+        }
+        ready = std::move(it->second);
+        finished.erase(it);
+        ++next_emit;
+      }
+      if (out) {
+        for (size_t pos = 0; pos < ready.bytes.size();) {
+          const size_t len = std::min(ready.bytes.size() - pos, size_t(1) << 30);
+          out->write(ready.bytes.data() + pos, static_cast<int>(len));
+          pos += len;
+        }
+      }
+      if (sizes) sizes->push_back(ready.size);
+      report_block(ready.size.uncompressed, ready.size.compressed);
+    }
+  } catch (const std::exception& e) {
+    fail(e.what());
+  }
+
+  {
+    std::lock_guard<std::mutex> lock(mu);
+    done = true;
+  }
+  cv_work.notify_all();
+  for (auto& t : pool) t.join();
+  report_worker_failures(setup_failures);
+
+  if (failed) libzpaq::error(fail_msg.c_str());
+}
+
+// Writes each block's uncompressed and compressed size to `out` as two
+// little-endian u64s.
+static void write_block_sizes(const std::vector<BlockSize>& sizes, libzpaq::Writer* out) {
+  for (const BlockSize& s : sizes) {
+    char rec[16];
+    for (int i = 0; i < 8; ++i) {
+      rec[i] = static_cast<char>((s.uncompressed >> (8 * i)) & 255);
+      rec[8 + i] = static_cast<char>((s.compressed >> (8 * i)) & 255);
+    }
+    out->write(rec, 16);
+  }
+}
+
+// ---------------- Top-level convenience API ----------------
+
+void zpaq_set_block_hook(zpaq_block_fn hook) { g_block_hook.store(hook, std::memory_order_relaxed); }
+
+void zpaq_set_worker_failure_hook(zpaq_worker_failure_fn hook) {
+  g_worker_failure_hook.store(hook, std::memory_order_relaxed);
+}
+
+void zpaq_set_worker_options(int priority, const char* name_prefix) {
+  g_worker_options.priority = priority;
+  g_worker_options.name_prefix = name_prefix ? name_prefix : "";
+}
+
+int64_t zpaq_debug_live_objects() {
+  return g_live_objects;
+}
+
+void zpaq_debug_fail_new(int n) {
+  g_fail_new = n > 0 ? n : 0;
+}
+
+void zpaq_set_temp_dir(const char* dir) {
+  g_temp_dir = dir ? dir : "";
+}
+
+int zpaq_temp_space(const char* dir, uint64_t* available) {
+  if (!dir || !available) return -1;
+#ifdef _WIN32
+  ULARGE_INTEGER free_bytes;
+  if (!GetDiskFreeSpaceExW(utf8_to_wide(dir).c_str(), &free_bytes, nullptr, nullptr)) return -1;
+  *available = free_bytes.QuadPart;
+#else
+  struct statvfs st;
+  if (statvfs(dir, &st) != 0) return -1;
+  *available = static_cast<uint64_t>(st.f_bavail) * st.f_frsize;
+#endif
+  return 0;
+}
+
+int zpaq_setup_worker_thread(int priority, const char* name_prefix, int index) {
+  return setup_worker(priority, name_prefix ? name_prefix : "", index) ? 0 : -1;
+}
+
+int zpaq_compress(RustReader* in, RustWriter* out, const char* method, const char* filename,
+                 const char* comment, int dosha1) {
   clear_last_error();
   try {
-    CountingWriter out;
-    libzpaq::compress(in, &out, method, filename, comment, dosha1 != 0);
-    if (out_size) *out_size = out.n;
+    compress_blocks(in, out, method, filename, comment, dosha1 != 0);
     return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
@@ -360,12 +1433,27 @@ int zpaq_compress_size(RustReader* in, const char* method, const char* filename,
   }
 }
 
-int zpaq_decompress_size(RustReader* in, uint64_t* out_size) {
+// zpaq_compress(), storing blocks that would expand if store_if_expands
+// is set (*stored_blocks is set to how many), ending each segment comment
+// with a checksum of hash extra_hash (0 for none, else 1 for SHA-1 or 2 for
+// SHA-256), and writing each block's uncompressed and compressed size to
+// `sizes` (if not null) as two little-endian u64s, as
+// zpaq_compress_block_sizes() does.
+int zpaq_compress_recorded(RustReader* in, RustWriter* out, const char* method, const char* filename,
+                           const char* comment, int dosha1, int store_if_expands, uint64_t* stored_blocks,
+                           int extra_hash, RustWriter* sizes) {
   clear_last_error();
   try {
-    CountingWriter out;
-    libzpaq::decompress(in, &out);
-    if (out_size) *out_size = out.n;
+    if (extra_hash < 0 || extra_hash > 2) {
+      set_last_error("unknown checksum");
+      return -1;
+    }
+    uint64_t stored = 0;
+    std::vector<BlockSize> written;
+    compress_blocks(in, out, method, filename, comment, dosha1 != 0, store_if_expands ? &stored : nullptr,
+                    sizes ? &written : nullptr, extra_hash);
+    if (stored_blocks) *stored_blocks = stored;
+    if (sizes) write_block_sizes(written, sizes);
     return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
@@ -373,116 +1461,142 @@ int zpaq_decompress_size(RustReader* in, uint64_t* out_size) {
   }
 }
 
-int zpaq_compress_size_parallel(RustReader* in, const char* method, const char* filename, const char* comment, int dosha1,
-                               int threads, uint64_t* out_size) {
+int zpaq_compress_parallel(RustReader* in, RustWriter* out, const char* method, const char* filename,
+                           const char* comment, int dosha1, int threads) {
   clear_last_error();
   try {
-    if (!in) return -1;
-    if (threads <= 1) {
-      CountingWriter out;
-      libzpaq::compress(in, &out, method, filename, comment, dosha1 != 0);
-      if (out_size) *out_size = out.n;
-      return 0;
-    }
+    if (!in || !out) return -1;
+    compress_blocks_parallel(in, out, method, filename, comment, dosha1 != 0, threads,
+                             method_block_size(method), nullptr);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
 
-    const int bs = method_block_size(method);
-    struct Block {
-      size_t idx;
-      std::string data;
-    };
-
-    std::mutex mu;
-    std::condition_variable cv;
-    std::deque<Block> q;
-    bool done = false;
-    bool failed = false;
-    std::string fail_msg;
-    std::vector<uint64_t> sizes;
-
-    auto worker = [&]() {
-      for (;;) {
-        Block blk;
-        {
-          std::unique_lock<std::mutex> lock(mu);
-          cv.wait(lock, [&] { return failed || done || !q.empty(); });
-          if (failed) return;
-          if (q.empty()) {
-            if (done) return;
-            continue;
-          }
-          blk = std::move(q.front());
-          q.pop_front();
-        }
+int zpaq_decompress(RustReader* in, RustWriter* out) {
+  clear_last_error();
+  try {
+    decompress_checked(in, out);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
 
-        try {
-          libzpaq::StringBuffer sb(bs);
-          sb.write(nullptr, blk.data.size());
-          if (blk.data.size()) std::memcpy(sb.data(), blk.data.data(), blk.data.size());
-          sb.resize(blk.data.size());
-
-          CountingWriter out;
-          const char* fn = (blk.idx == 0) ? filename : nullptr;
-          const char* cm = (blk.idx == 0) ? comment : nullptr;
-          libzpaq::compressBlock(&sb, &out, method, fn, cm, dosha1 != 0);
-
-          {
-            std::lock_guard<std::mutex> lock(mu);
-            if (blk.idx >= sizes.size()) sizes.resize(blk.idx + 1, 0);
-            sizes[blk.idx] = out.n;
-          }
-        } catch (const std::exception& e) {
-          std::lock_guard<std::mutex> lock(mu);
-          if (!failed) {
-            failed = true;
-            fail_msg = e.what();
+// Decompresses like zpaq_decompress (to `out`, or nowhere if null) and
+// returns the SHA-1 and size of everything decoded.
+int zpaq_decompress_sha1(RustReader* in, RustWriter* out, unsigned char out_hash20[20],
+                         uint64_t* out_size) {
+  clear_last_error();
+  try {
+    if (!out_hash20) return -1;
+    libzpaq::SHA1 sha1;
+    {
+      HashSink sink(&sha1, out);
+      decompress_checked(in, &sink);
+    }
+    if (out_size) *out_size = sha1.usize();
+    std::memcpy(out_hash20, sha1.result(), 20);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
+// Decompresses like zpaq_decompress (to `out`, or nowhere if null),
+// hashing everything decoded with SHA-1 (hash 1, 20 bytes of out_hash) or
+// SHA-256 (hash 2, 32 bytes), and checking each segment against the SHA-1
+// its trailer stores.  Counts the segments with a stored SHA-1 in
+// *out_checked and those whose data did not match it in *out_bad.
+int zpaq_decompress_digest(RustReader* in, RustWriter* out, int hash, unsigned char* out_hash,
+                           uint64_t* out_size, uint64_t* out_checked, uint64_t* out_bad) {
+  clear_last_error();
+  try {
+    if (!out_hash || hash < 1 || hash > 2) return -1;
+    libzpaq::SHA1 sha1;
+    libzpaq::SHA256 sha256;
+    uint64_t checked = 0, bad = 0, size = 0;
+    {
+      DigestSink sink(hash == 1 ? &sha1 : nullptr, hash == 2 ? &sha256 : nullptr, out);
+      libzpaq::Decompresser d;
+      d.setInput(in);
+      d.setOutput(&sink);
+      while (find_block_checked(&d, nullptr)) {
+        while (find_data_segment(&d)) {
+          d.decompress();
+          char stored[21];
+          d.readSegmentEnd(stored);
+          const char* actual = sink.segment_result();
+          if (stored[0]) {
+            ++checked;
+            if (std::memcmp(actual, stored + 1, 20) != 0) ++bad;
           }
-          cv.notify_all();
-          return;
         }
       }
-    };
-
-    std::vector<std::thread> pool;
-    pool.reserve(static_cast<size_t>(threads));
-    for (int i = 0; i < threads; ++i) pool.emplace_back(worker);
-
-    size_t idx = 0;
-    for (;;) {
-      {
-        std::lock_guard<std::mutex> lock(mu);
-        if (failed) break;
-      }
-
-      std::string buf;
-      buf.resize(static_cast<size_t>(bs));
-      const int n = in->read(&buf[0], bs);
-      if (n <= 0) break;
-      buf.resize(static_cast<size_t>(n));
-
-      {
-        std::lock_guard<std::mutex> lock(mu);
-        q.push_back(Block{idx++, std::move(buf)});
-      }
-      cv.notify_one();
+      size = sink.size();
     }
+    if (hash == 1)
+      std::memcpy(out_hash, sha1.result(), 20);
+    else
+      std::memcpy(out_hash, sha256.result(), 32);
+    if (out_size) *out_size = size;
+    if (out_checked) *out_checked = checked;
+    if (out_bad) *out_bad = bad;
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
 
-    {
-      std::lock_guard<std::mutex> lock(mu);
-      done = true;
-    }
-    cv.notify_all();
-    for (auto& t : pool) t.join();
+int zpaq_compress_size(RustReader* in, const char* method, const char* filename, const char* comment, int dosha1,
+                      uint64_t* out_size) {
+  clear_last_error();
+  try {
+    CountingWriter out;
+    compress_blocks(in, &out, method, filename, comment, dosha1 != 0);
+    if (out_size) *out_size = out.n;
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
 
-    {
-      std::lock_guard<std::mutex> lock(mu);
-      if (failed) {
-        set_last_error(fail_msg.c_str());
-        return -1;
-      }
+int zpaq_decompress_size(RustReader* in, uint64_t* out_size) {
+  clear_last_error();
+  try {
+    CountingWriter out;
+    decompress_checked(in, &out);
+    if (out_size) *out_size = out.n;
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
+int zpaq_compress_size_parallel(RustReader* in, const char* method, const char* filename, const char* comment, int dosha1,
+                               int threads, uint64_t* out_size) {
+  clear_last_error();
+  try {
+    if (!in) return -1;
+    if (threads <= 1) {
+      CountingWriter out;
+      compress_blocks(in, &out, method, filename, comment, dosha1 != 0);
+      if (out_size) *out_size = out.n;
+      return 0;
     }
 
+    std::vector<BlockSize> sizes;
+    compress_blocks_parallel(in, nullptr, method, filename, comment, dosha1 != 0, threads,
+                             method_block_size(method), &sizes);
     uint64_t total = 0;
-    for (uint64_t s : sizes) total += s;
+    for (const BlockSize& s : sizes) total += s.compressed;
     if (out_size) *out_size = total;
     return 0;
   } catch (const std::exception& e) {
@@ -491,32 +1605,24 @@ int zpaq_compress_size_parallel(RustReader* in, const char* method, const char*
   }
 }
 
-static bool parse_last_archive_mb(const char* s, size_t n, double* out_mb) {
-  if (!s || n == 0 || !out_mb) return false;
-  // Look for the last occurrence of "= <num> MB" in the captured stderr.
-  // The summary line in zpaq.cpp is:
-  //   "... = %1.6f MB\n"
-  const char* end = s + n;
-  const char* p = end;
-  while (p > s) {
-    // Find previous '='
-    const char* eq = (const char*)zpaq_memrchr(s, '=', (size_t)(p - s));
-    if (!eq) break;
-    const char* q = eq + 1;
-    while (q < end && (*q == ' ' || *q == '\t')) ++q;
-    char* num_end = nullptr;
-    const double val = std::strtod(q, &num_end);
-    if (num_end && num_end > q) {
-      const char* r = num_end;
-      while (r < end && (*r == ' ' || *r == '\t')) ++r;
-      if (r + 2 <= end && r[0] == 'M' && r[1] == 'B') {
-        *out_mb = val;
-        return true;
-      }
+int zpaq_compress_block_sizes(RustReader* in, const char* method, int64_t block_size, int threads,
+                              RustWriter* out) {
+  clear_last_error();
+  try {
+    if (!in || !out) return -1;
+    if (block_size < 0 || block_size > INT32_MAX) {
+      set_last_error("block size must be in 1..=2147483647");
+      return -1;
     }
-    p = eq;
+    const int bs = block_size ? static_cast<int>(block_size) : method_block_size(method);
+    std::vector<BlockSize> sizes;
+    compress_blocks_parallel(in, nullptr, method, nullptr, nullptr, true, threads, bs, &sizes);
+    write_block_sizes(sizes, out);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
   }
-  return false;
 }
 
 static bool read_stream_to_string(FILE* stream, std::string* out) {
@@ -551,17 +1657,74 @@ static void set_error_from_stderr_fallback() {
   }
 }
 
-int zpaq_jidac_run(int argc, const char* const* argv) {
+void zpaq_jidac_set_fixed_mtime(int64_t unix_seconds) { g_fixed_mtime = unix_seconds; }
+
+void zpaq_jidac_override_mtime(const char* path, int64_t unix_seconds) {
+  if (path) g_mtime_overrides[path] = unix_seconds;
+}
+
+void zpaq_jidac_clear_mtime_overrides() { g_mtime_overrides.clear(); }
+
+void zpaq_jidac_set_read_throttle(void* ctx, zpaq_read_throttle_fn throttle) {
+  g_read_throttle = throttle;
+  g_read_throttle_ctx = throttle ? ctx : nullptr;
+}
+
+void zpaq_jidac_set_write_hook(void* ctx, zpaq_write_hook_fn hook) {
+  g_requested_write_hook = hook;
+  g_requested_write_hook_ctx = hook ? ctx : nullptr;
+}
+
+// Installs this thread's requested write hook for one run, clearing it when
+// the run ends so no write reaches a context its caller has since freed.
+// Only constructed with run_captured()'s lock held.
+struct RunWriteHook {
+  RunWriteHook() {
+    g_write_hook_ctx = g_requested_write_hook_ctx;
+    g_write_hook = g_requested_write_hook;
+  }
+  ~RunWriteHook() {
+    g_write_hook = nullptr;
+    g_write_hook_ctx = nullptr;
+  }
+  RunWriteHook(const RunWriteHook&) = delete;
+  RunWriteHook& operator=(const RunWriteHook&) = delete;
+};
+
+void zpaq_jidac_set_symlink_policy(int add, int extract) {
+  g_requested_add_links = add;
+  g_requested_extract_links = extract;
+}
+
+// Installs this thread's requested link policy for one run, restoring the
+// defaults when it ends.  Only constructed with run_captured()'s lock held.
+struct RunLinkPolicy {
+  RunLinkPolicy() {
+    g_add_links = g_requested_add_links;
+    g_extract_links = g_requested_extract_links;
+#ifdef unix
+    zpaq_rs_reset_links();
+#endif
+  }
+  ~RunLinkPolicy() {
+    g_add_links = kLinksSkip;
+    g_extract_links = kLinksAsFiles;
+  }
+  RunLinkPolicy(const RunLinkPolicy&) = delete;
+  RunLinkPolicy& operator=(const RunLinkPolicy&) = delete;
+};
+
+// Runs cli_main(ctx), zpaq.cpp's main on some arguments, with stdout and
+// stderr captured into g_last_stdout and g_last_stderr.
+static int run_captured(int (*cli_main)(void* ctx), void* ctx) {
   clear_last_error();
   clear_last_output();
   try {
-    if (argc <= 0 || !argv) {
-      set_last_error("invalid argv");
-      return -1;
-    }
-
     static std::mutex g_mu;
     std::lock_guard<std::mutex> lock(g_mu);
+    const RunLinkPolicy links;
+    const RunWriteHook write_hook;
+    take_busy_path();
 
     fflush(stdout);
     fflush(stderr);
@@ -582,11 +1745,12 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
       return -1;
     }
 
-    FILE* err_stream = tmpfile();
-    FILE* out_stream = tmpfile();
+    // Closed and removed on every path out of here.
+    TempFile err_file;
+    TempFile out_file;
+    FILE* err_stream = err_file.get();
+    FILE* out_stream = out_file.get();
     if (!err_stream || !out_stream) {
-      if (err_stream) fclose(err_stream);
-      if (out_stream) fclose(out_stream);
       close(old_stderr);
       close(old_stdout);
       set_last_error("failed to create capture streams");
@@ -594,8 +1758,6 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
     }
 
     if (dup2(fileno(err_stream), stderr_fd) < 0 || dup2(fileno(out_stream), stdout_fd) < 0) {
-      fclose(err_stream);
-      fclose(out_stream);
       close(old_stderr);
       close(old_stdout);
       set_last_error("dup2() failed");
@@ -603,12 +1765,15 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
     }
 
     int rc = 0;
+    g_jidac_workers = 0;
+    g_jidac_worker_failures = 0;
     try {
-      rc = zpaq_cli_main(argc, const_cast<const char**>(argv));
+      rc = cli_main(ctx);
     } catch (const std::exception& e) {
       rc = 2;
       set_last_error(e.what());
     }
+    report_worker_failures(g_jidac_worker_failures.exchange(0));
 
     fflush(stdout);
     fflush(stderr);
@@ -619,8 +1784,6 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
     const int restore_err = dup2(old_stderr, stderr_fd);
     const int restore_out = dup2(old_stdout, stdout_fd);
 
-    fclose(err_stream);
-    fclose(out_stream);
     close(old_stderr);
     close(old_stdout);
 
@@ -634,6 +1797,11 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
     }
 
     if (rc != 0) {
+      const std::string busy = take_busy_path();
+      if (!busy.empty()) {
+        set_last_error(busy.c_str());
+        return kArchiveBusy;
+      }
       set_error_from_stderr_fallback();
       return -1;
     }
@@ -644,56 +1812,61 @@ int zpaq_jidac_run(int argc, const char* const* argv) {
   }
 }
 
-int zpaq_jidac_add_archive_size_file(const char* path, const char* method, int threads, uint64_t* out_archive_size_bytes) {
-  clear_last_error();
-  try {
-    if (!path || !*path || !method || !*method || !out_archive_size_bytes) return -1;
-
-    // Build argv like: zpaq add "" <path> -method <method> -threads <N>
-    std::string threads_s = std::to_string(threads);
-    const char* argv[9];
-    int argc = 0;
-    argv[argc++] = "zpaq";
-    argv[argc++] = "add";
-    argv[argc++] = "";
-    argv[argc++] = path;
-    argv[argc++] = "-method";
-    argv[argc++] = method;
-    argv[argc++] = "-threads";
-    argv[argc++] = threads_s.c_str();
-    const int rc = zpaq_jidac_run(argc, argv);
-    if (rc != 0) return -1;
-
-    double archive_mb = 0.0;
-    const bool ok = parse_last_archive_mb(g_last_stderr.data(), g_last_stderr.size(), &archive_mb);
-    if (!ok) {
-      set_last_error("failed to parse zpaq summary output");
-      return -1;
-    }
+struct NarrowArgs {
+  int argc;
+  const char* const* argv;
+};
 
-    const double bytes_d = archive_mb * 1000000.0;
-    const uint64_t bytes = bytes_d <= 0.0 ? 0ULL : (uint64_t)(bytes_d + 0.5);
-    *out_archive_size_bytes = bytes;
-    return 0;
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
+static int run_narrow(void* ctx) {
+  const NarrowArgs* args = static_cast<const NarrowArgs*>(ctx);
+  return zpaq_cli_main(args->argc, const_cast<const char**>(args->argv));
+}
+
+int zpaq_jidac_run(int argc, const char* const* argv) {
+  if (argc <= 0 || !argv) {
+    clear_last_error();
+    set_last_error("invalid argv");
+    return -1;
+  }
+  NarrowArgs args{argc, argv};
+  return run_captured(run_narrow, &args);
+}
+
+#ifdef _WIN32
+struct WideArgs {
+  int argc;
+  const wchar_t* const* argv;
+};
+
+static int run_wide(void* ctx) {
+  const WideArgs* args = static_cast<const WideArgs*>(ctx);
+  std::vector<std::wstring> wargs(args->argv, args->argv + args->argc);
+  std::vector<LPWSTR> wargv;
+  wargv.reserve(wargs.size());
+  for (std::wstring& arg : wargs) wargv.push_back(&arg[0]);
+  return zpaq_cli_main_wide(args->argc, wargv.data());
+}
+
+// Like zpaq_jidac_run(), with UTF-16 arguments passed to zpaq.cpp as they
+// are, unpaired surrogates included.
+int zpaq_jidac_run_wide(int argc, const wchar_t* const* argv) {
+  if (argc <= 0 || !argv) {
+    clear_last_error();
+    set_last_error("invalid argv");
     return -1;
   }
+  WideArgs args{argc, argv};
+  return run_captured(run_wide, &args);
 }
+#endif
 
 // ---------------- StringBuffer ----------------
 
 libzpaq::StringBuffer* zpaq_string_buffer_new(size_t initial) {
-  clear_last_error();
-  try {
-    return new libzpaq::StringBuffer(initial);
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<libzpaq::StringBuffer>(initial);
 }
 
-void zpaq_string_buffer_free(libzpaq::StringBuffer* sb) { delete sb; }
+void zpaq_string_buffer_free(libzpaq::StringBuffer* sb) { free_handle(sb); }
 
 size_t zpaq_string_buffer_size(const libzpaq::StringBuffer* sb) {
   return sb ? sb->size() : 0;
@@ -719,16 +1892,10 @@ void zpaq_string_buffer_resize(libzpaq::StringBuffer* sb, size_t n) {
 // ---------------- Compressor ----------------
 
 libzpaq::Compressor* zpaq_compressor_new() {
-  clear_last_error();
-  try {
-    return new libzpaq::Compressor();
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<libzpaq::Compressor>();
 }
 
-void zpaq_compressor_free(libzpaq::Compressor* c) { delete c; }
+void zpaq_compressor_free(libzpaq::Compressor* c) { free_handle(c); }
 
 int zpaq_compressor_set_output(libzpaq::Compressor* c, RustWriter* out) {
   clear_last_error();
@@ -864,6 +2031,18 @@ int zpaq_compressor_compress(libzpaq::Compressor* c, int n) {
   }
 }
 
+int zpaq_compressor_prime(libzpaq::Compressor* c, const unsigned char* buf, size_t n) {
+  clear_last_error();
+  try {
+    if (!c || (!buf && n)) return -1;
+    prime_compressor(c, reinterpret_cast<const char*>(buf), n);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
 int zpaq_compressor_end_segment(libzpaq::Compressor* c, const unsigned char* sha1_20_or_null) {
   clear_last_error();
   try {
@@ -929,16 +2108,10 @@ int zpaq_compressor_end_block(libzpaq::Compressor* c) {
 // ---------------- Decompresser ----------------
 
 libzpaq::Decompresser* zpaq_decompresser_new() {
-  clear_last_error();
-  try {
-    return new libzpaq::Decompresser();
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<libzpaq::Decompresser>();
 }
 
-void zpaq_decompresser_free(libzpaq::Decompresser* d) { delete d; }
+void zpaq_decompresser_free(libzpaq::Decompresser* d) { free_handle(d); }
 
 int zpaq_decompresser_set_input(libzpaq::Decompresser* d, RustReader* in) {
   clear_last_error();
@@ -956,7 +2129,7 @@ int zpaq_decompresser_find_block(libzpaq::Decompresser* d, double* mem_out) {
   clear_last_error();
   try {
     if (!d) return -1;
-    return d->findBlock(mem_out) ? 1 : 0;
+    return find_block_checked(d, mem_out) ? 1 : 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
     return -1;
@@ -967,7 +2140,7 @@ int zpaq_decompresser_find_filename(libzpaq::Decompresser* d, RustWriter* filena
   clear_last_error();
   try {
     if (!d) return -1;
-    return d->findFilename(filename_out) ? 1 : 0;
+    return find_filename_checked(d, filename_out) ? 1 : 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
     return -1;
@@ -978,7 +2151,7 @@ int zpaq_decompresser_read_comment(libzpaq::Decompresser* d, RustWriter* comment
   clear_last_error();
   try {
     if (!d) return -1;
-    d->readComment(comment_out);
+    read_comment_checked(d, comment_out);
     return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
@@ -998,6 +2171,20 @@ int zpaq_decompresser_set_output(libzpaq::Decompresser* d, RustWriter* out) {
   }
 }
 
+// Hashes the current segment's decoded data into `sha1` (null to stop),
+// in the same bulk flushes that write it to the output.
+int zpaq_decompresser_set_sha1(libzpaq::Decompresser* d, libzpaq::SHA1* sha1) {
+  clear_last_error();
+  try {
+    if (!d) return -1;
+    d->setSHA1(sha1);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
 int zpaq_decompresser_decompress(libzpaq::Decompresser* d, int n) {
   clear_last_error();
   try {
@@ -1010,6 +2197,39 @@ int zpaq_decompresser_decompress(libzpaq::Decompresser* d, int n) {
   }
 }
 
+// Decodes the rest of the current segment with no output object, so that no
+// Rust callback runs, and stores the number of bytes decoded in *out_size
+// (if not null).  The output is null afterwards.  Earlier segments of a
+// block must be decoded, not skipped, for the model state of later ones.
+int zpaq_decompresser_skip_segment(libzpaq::Decompresser* d, uint64_t* out_size) {
+  clear_last_error();
+  if (!d) return -1;
+  try {
+    CountingWriter counter;
+    d->setOutput(&counter);
+    d->decompress(-1);
+    d->setOutput(nullptr);
+    if (out_size) *out_size = counter.n;
+    return 0;
+  } catch (const std::exception& e) {
+    d->setOutput(nullptr);
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
+int zpaq_decompresser_prime(libzpaq::Decompresser* d, const unsigned char* buf, size_t n) {
+  clear_last_error();
+  try {
+    if (!d || (!buf && n)) return -1;
+    prime_decompresser(d, reinterpret_cast<const char*>(buf), n);
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
 int zpaq_decompresser_read_segment_end(libzpaq::Decompresser* d, unsigned char out_21[21]) {
   clear_last_error();
   try {
@@ -1024,23 +2244,118 @@ int zpaq_decompresser_read_segment_end(libzpaq::Decompresser* d, unsigned char o
   }
 }
 
+// Decompresses the whole ZPAQ stream in buf[0..n] to `out` with `d`, then
+// hands what `out` buffered to its callback.  `d` reads the stream from
+// memory, so no Rust reader is called, and is ready for the next stream
+// afterwards: a Decompresser that has found no more blocks can take new
+// input.  After a failure `d` is in an unknown state and must be freed.
+int zpaq_decompresser_decompress_bytes(libzpaq::Decompresser* d, const unsigned char* buf,
+                                       size_t n, RustWriter* out) {
+  clear_last_error();
+  if (!d || (!buf && n)) return -1;
+  BytesReader in(buf, n);
+  try {
+    d->setInput(&in);
+    d->setOutput(out);
+    while (find_block_checked(d, nullptr)) {
+      while (find_data_segment(d)) {
+        d->decompress();
+        d->readSegmentEnd();
+      }
+    }
+    d->setInput(nullptr);
+    d->setOutput(nullptr);
+    if (out) out->flush();
+    return 0;
+  } catch (const std::exception& e) {
+    d->setInput(nullptr);
+    d->setOutput(nullptr);
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
 int zpaq_decompresser_buffered(libzpaq::Decompresser* d) {
   return d ? d->buffered() : 0;
 }
 
-// ---------------- SHA1 / SHA256 ----------------
+// Number of context-model components in the current block header (the `n`
+// byte of COMP). Valid after find_block().
+int zpaq_decompresser_components(libzpaq::Decompresser* d) {
+  clear_last_error();
+  try {
+    if (!d) return -1;
+    libzpaq::StringBuffer hdr;
+    d->hcomp(&hdr);
+    if (hdr.size() < 7) {
+      set_last_error("no block header has been read");
+      return -1;
+    }
+    return hdr.data()[6];
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
 
-libzpaq::SHA1* zpaq_sha1_new() {
+// 1 if the current block has a PCOMP postprocessor, 0 if it is PASS. Only
+// meaningful once decompression of the block's first segment has started
+// (decompress(0) is enough to load it).
+// Writes the raw header of the current block: size, hh hm ph pm n, COMP,
+// END, HCOMP, END (what Compressor::startBlock(const char*) takes).
+int zpaq_decompresser_header(libzpaq::Decompresser* d, RustWriter* out) {
   clear_last_error();
   try {
-    return new libzpaq::SHA1();
+    if (!d || !out) return -1;
+    d->hcomp(out);
+    return 0;
   } catch (const std::exception& e) {
     set_last_error(e.what());
-    return nullptr;
+    return -1;
+  }
+}
+
+// Stores the length of the current block's HCOMP (the header hcomp() writes)
+// and the SHA-256 of those bytes, which identifies the block's model.
+int zpaq_decompresser_hcomp_fingerprint(libzpaq::Decompresser* d, uint64_t* out_len,
+                                        unsigned char out_sha256[32]) {
+  clear_last_error();
+  try {
+    if (!d || !out_sha256) return -1;
+    libzpaq::StringBuffer hcomp;
+    d->hcomp(&hcomp);
+    libzpaq::SHA256 sha256;
+    const unsigned char* p = hcomp.data();
+    const size_t n = hcomp.size();
+    for (size_t i = 0; i < n; ++i) sha256.put(p[i]);
+    std::memcpy(out_sha256, sha256.result(), 32);
+    if (out_len) *out_len = n;
+    return 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
+  }
+}
+
+int zpaq_decompresser_has_pcomp(libzpaq::Decompresser* d) {
+  clear_last_error();
+  try {
+    if (!d) return -1;
+    CountingWriter out;
+    return d->pcomp(&out) ? 1 : 0;
+  } catch (const std::exception& e) {
+    set_last_error(e.what());
+    return -1;
   }
 }
 
-void zpaq_sha1_free(libzpaq::SHA1* s) { delete s; }
+// ---------------- SHA1 / SHA256 ----------------
+
+libzpaq::SHA1* zpaq_sha1_new() {
+  return new_handle<libzpaq::SHA1>();
+}
+
+void zpaq_sha1_free(libzpaq::SHA1* s) { free_handle(s); }
 
 void zpaq_sha1_put(libzpaq::SHA1* s, int c) {
   if (s) s->put(c);
@@ -1068,21 +2383,20 @@ int zpaq_sha1_result(libzpaq::SHA1* s, unsigned char out_hash20[20]) {
 }
 
 libzpaq::SHA256* zpaq_sha256_new() {
-  clear_last_error();
-  try {
-    return new libzpaq::SHA256();
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<libzpaq::SHA256>();
 }
 
-void zpaq_sha256_free(libzpaq::SHA256* s) { delete s; }
+void zpaq_sha256_free(libzpaq::SHA256* s) { free_handle(s); }
 
 void zpaq_sha256_put(libzpaq::SHA256* s, int c) {
   if (s) s->put(c);
 }
 
+void zpaq_sha256_write(libzpaq::SHA256* s, const char* buf, int64_t n) {
+  if (!s || !buf) return;
+  for (int64_t i = 0; i < n; ++i) s->put(static_cast<unsigned char>(buf[i]));
+}
+
 uint64_t zpaq_sha256_usize(const libzpaq::SHA256* s) { return s ? s->usize() : 0; }
 
 double zpaq_sha256_size(const libzpaq::SHA256* s) { return s ? s->size() : 0.0; }
@@ -1103,16 +2417,10 @@ int zpaq_sha256_result(libzpaq::SHA256* s, unsigned char out_hash32[32]) {
 // ---------------- AES_CTR / scrypt / random ----------------
 
 libzpaq::AES_CTR* zpaq_aes_ctr_new(const char* key, int keylen, const char* iv) {
-  clear_last_error();
-  try {
-    return new libzpaq::AES_CTR(key, keylen, iv);
-  } catch (const std::exception& e) {
-    set_last_error(e.what());
-    return nullptr;
-  }
+  return new_handle<libzpaq::AES_CTR>(key, keylen, iv);
 }
 
-void zpaq_aes_ctr_free(libzpaq::AES_CTR* a) { delete a; }
+void zpaq_aes_ctr_free(libzpaq::AES_CTR* a) { free_handle(a); }
 
 int zpaq_aes_ctr_encrypt_slice(libzpaq::AES_CTR* a, char* buf, int n, uint64_t offset) {
   clear_last_error();
@@ -1169,3 +2477,268 @@ uint16_t zpaq_to_u16(const char* p) {
 }
 
 } // extern "C"
+
+#ifdef unix
+#include <cerrno>
+#include <fcntl.h>
+#include <set>
+#include <sys/stat.h>
+#include <unistd.h>
+
+namespace {
+
+// Links zpaq.cpp has accepted as files under kLinksStore, whose contents
+// are their targets, and the last link it examined.
+thread_local std::set<std::string> g_stored_links;
+thread_local std::string g_last_link;
+
+// Directories scanned under kLinksFollow, so links back up the tree are
+// not followed forever.
+thread_local std::set<std::pair<dev_t, ino_t>> g_scanned_dirs;
+
+bool read_link(const char* path, std::string* target) {
+  std::vector<char> buf(256);
+  for (;;) {
+    const ssize_t n = readlink(path, buf.data(), buf.size());
+    if (n < 0) return false;
+    if (static_cast<size_t>(n) < buf.size()) {
+      target->assign(buf.data(), static_cast<size_t>(n));
+      return true;
+    }
+    buf.resize(buf.size() * 2);
+  }
+}
+
+// Clears the state above for a new run (declared near the top for
+// RunLinkPolicy).
+void zpaq_rs_reset_links() {
+  g_stored_links.clear();
+  g_last_link.clear();
+  g_scanned_dirs.clear();
+}
+
+} // namespace
+
+// lstat() for zpaq.cpp (see zpaq_rs_cli.cpp).
+int zpaq_rs_lstat(const char* path, struct stat* sb) {
+  const int rc = lstat(path, sb);
+  if (rc != 0) return rc;
+  if (S_ISLNK(sb->st_mode)) {
+    g_last_link = path;
+    struct stat target;
+    if (g_add_links == kLinksFollow && stat(path, &target) == 0
+        && !(S_ISDIR(target.st_mode) && g_scanned_dirs.count({target.st_dev, target.st_ino}))) {
+      *sb = target;
+    }
+  }
+  if (S_ISDIR(sb->st_mode) && g_add_links == kLinksFollow) {
+    g_scanned_dirs.insert({sb->st_dev, sb->st_ino});
+  }
+  const auto it = g_mtime_overrides.find(path);
+  if (it != g_mtime_overrides.end()) {
+    sb->st_mtime = static_cast<time_t>(it->second);
+  } else if (g_fixed_mtime >= 0) {
+    sb->st_mtime = static_cast<time_t>(g_fixed_mtime);
+  }
+  return rc;
+}
+
+// S_ISREG() for zpaq.cpp, which adds only regular files and directories.
+// Under kLinksStore a link is added as a file too, keeping S_IFLNK in its
+// stored mode.  zpaq.cpp tests the mode right after lstat(), so the link is
+// the one zpaq_rs_lstat() saw last.
+bool zpaq_rs_is_reg(mode_t mode) {
+  if (S_ISREG(mode)) return true;
+  if (!S_ISLNK(mode) || g_add_links != kLinksStore) return false;
+  g_stored_links.insert(g_last_link);
+  return true;
+}
+
+// fopen() for zpaq.cpp: reading a stored link gives its target, and files
+// found in use are noted for kArchiveBusy.  zpaq.cpp only asks for binary
+// modes ("rb", "wb", "rb+", "wb+"); on Windows it opens files with
+// CreateFile instead, which has no text mode.
+FILE* zpaq_rs_fopen(const char* path, const char* mode) {
+  if (strcmp(mode, "rb") != 0 || !g_stored_links.count(path)) {
+    FILE* f = fopen(path, mode);
+    if (!f && (errno == EAGAIN || errno == EWOULDBLOCK || errno == EBUSY || errno == ETXTBSY)) {
+      const int saved = errno;
+      set_busy_path(path);
+      errno = saved;
+    }
+    return f;
+  }
+  std::string target;
+  FILE* f = read_link(path, &target) ? TempFile().release() : nullptr;
+  if (f && (fwrite(target.data(), 1, target.size(), f) != target.size() || fseek(f, 0, SEEK_SET) != 0)) {
+    fclose(f);
+    f = nullptr;
+  }
+  return f;
+}
+
+namespace {
+
+struct PthreadStart {
+  void* (*f)(void*);
+  void* arg;
+  WorkerOptions options;
+  int index;
+  std::atomic<uint64_t>* failures;
+};
+
+void* start_pthread(void* p) {
+  std::unique_ptr<PthreadStart> start(static_cast<PthreadStart*>(p));
+  if (!setup_worker(start->options.priority, start->options.name_prefix, start->index)) ++*start->failures;
+  return start->f(start->arg);
+}
+
+} // namespace
+
+// pthread_create() for zpaq.cpp: its workers take the worker options of the
+// thread running the command.
+int zpaq_rs_pthread_create(pthread_t* tid, const pthread_attr_t* attr, void* (*f)(void*), void* arg) {
+  if (!g_worker_options.active()) return pthread_create(tid, attr, f, arg);
+  PthreadStart* start =
+      new PthreadStart{f, arg, g_worker_options, g_jidac_workers++, &g_jidac_worker_failures};
+  const int rc = pthread_create(tid, attr, start_pthread, start);
+  if (rc != 0) delete start;
+  return rc;
+}
+
+// fread() for zpaq.cpp: reports what was read to the read throttle.
+size_t zpaq_rs_fread(void* ptr, size_t size, size_t nobj, FILE* fp) {
+  const size_t n = fread(ptr, size, nobj, fp);
+  if (g_read_throttle && n > 0) g_read_throttle(g_read_throttle_ctx, static_cast<uint64_t>(n) * size);
+  return n;
+}
+
+// fwrite() for zpaq.cpp: reports what was written to the write hook.
+size_t zpaq_rs_fwrite(const void* ptr, size_t size, size_t nobj, FILE* fp) {
+  const size_t n = fwrite(ptr, size, nobj, fp);
+  if (n > 0) {
+    if (zpaq_write_hook_fn hook = g_write_hook.load()) hook(g_write_hook_ctx.load(), static_cast<uint64_t>(n) * size);
+  }
+  return n;
+}
+
+// chmod() for zpaq.cpp, which sets the stored mode of each file it
+// extracts.  Files stored from links (S_IFLNK in the mode) hold their
+// target; they are replaced by the link or removed, as the policy says.
+int zpaq_rs_chmod(const char* path, mode_t mode) {
+  const int policy = g_extract_links;
+  if ((mode & S_IFMT) != S_IFLNK || policy == kLinksAsFiles) return chmod(path, mode);
+  std::string target;
+  struct stat sb;
+  FILE* f = fopen(path, "rb");
+  if (!f || fstat(fileno(f), &sb) != 0) {
+    if (f) fclose(f);
+    return -1;
+  }
+  char buf[4096];
+  for (size_t n; (n = fread(buf, 1, sizeof buf, f)) > 0;) target.append(buf, n);
+  fclose(f);
+  if (unlink(path) != 0) return -1;
+  if (policy == kLinksSkipWithWarning) {
+    fprintf(stderr, "Skipping symbolic link %s -> %s\n", path, target.c_str());
+    return 0;
+  }
+  if (symlink(target.c_str(), path) != 0) {
+    perror(path);
+    return -1;
+  }
+  const struct timespec times[2] = {{0, UTIME_OMIT}, {sb.st_mtime, 0}};
+  utimensat(AT_FDCWD, path, times, AT_SYMLINK_NOFOLLOW);
+  return 0;
+}
+
+#endif
+
+#ifdef _WIN32
+namespace {
+
+// `path` as zpaq.cpp gave it if its full path is short, or else its full
+// path with the \\?\ prefix, which lifts the MAX_PATH limit.  The limit is
+// taken as 248, that of CreateDirectory(), so long directories can be
+// created too.
+std::wstring long_path(LPCWSTR path) {
+  std::wstring given = path ? path : L"";
+  if (given.rfind(L"\\\\?\\", 0) == 0 || given.rfind(L"\\\\.\\", 0) == 0) return given;
+  DWORD n = GetFullPathNameW(given.c_str(), 0, nullptr, nullptr);
+  if (n == 0) return given;
+  std::wstring full(n, L'\0');
+  n = GetFullPathNameW(given.c_str(), n, &full[0], nullptr);
+  if (n == 0 || n >= full.size()) return given;
+  full.resize(n);
+  if (full.size() < 248) return given;
+  if (full.rfind(L"\\\\", 0) == 0) return L"\\\\?\\UNC\\" + full.substr(2);
+  return L"\\\\?\\" + full;
+}
+
+} // namespace
+
+// File API calls of zpaq.cpp (see zpaq_rs_cli.cpp), made with long_path().
+// Files found in use are noted for kArchiveBusy.
+HANDLE zpaq_rs_CreateFileW(LPCWSTR path, DWORD access, DWORD share, LPSECURITY_ATTRIBUTES sa,
+                           DWORD disposition, DWORD flags, HANDLE template_file) {
+  HANDLE h = CreateFileW(long_path(path).c_str(), access, share, sa, disposition, flags, template_file);
+  const DWORD err = GetLastError();
+  if (h == INVALID_HANDLE_VALUE && (err == ERROR_SHARING_VIOLATION || err == ERROR_LOCK_VIOLATION)) {
+    const int n = WideCharToMultiByte(CP_UTF8, 0, path, -1, nullptr, 0, nullptr, nullptr);
+    std::string utf8(n > 0 ? n - 1 : 0, '\0');
+    if (n > 1) WideCharToMultiByte(CP_UTF8, 0, path, -1, &utf8[0], n, nullptr, nullptr);
+    set_busy_path(utf8);
+    SetLastError(err);
+  }
+  return h;
+}
+
+DWORD zpaq_rs_GetFileAttributesW(LPCWSTR path) {
+  return GetFileAttributesW(long_path(path).c_str());
+}
+
+BOOL zpaq_rs_SetFileAttributesW(LPCWSTR path, DWORD attributes) {
+  return SetFileAttributesW(long_path(path).c_str(), attributes);
+}
+
+BOOL zpaq_rs_DeleteFileW(LPCWSTR path) {
+  return DeleteFileW(long_path(path).c_str());
+}
+
+BOOL zpaq_rs_CreateDirectoryW(LPCWSTR path, LPSECURITY_ATTRIBUTES sa) {
+  return CreateDirectoryW(long_path(path).c_str(), sa);
+}
+
+HANDLE zpaq_rs_FindFirstFileW(LPCWSTR pattern, LPWIN32_FIND_DATAW data) {
+  return FindFirstFileW(long_path(pattern).c_str(), data);
+}
+
+namespace {
+
+struct ThreadStart {
+  LPTHREAD_START_ROUTINE f;
+  LPVOID arg;
+  WorkerOptions options;
+  int index;
+  std::atomic<uint64_t>* failures;
+};
+
+DWORD WINAPI start_thread(LPVOID p) {
+  std::unique_ptr<ThreadStart> start(static_cast<ThreadStart*>(p));
+  if (!setup_worker(start->options.priority, start->options.name_prefix, start->index)) ++*start->failures;
+  return start->f(start->arg);
+}
+
+} // namespace
+
+// CreateThread() for zpaq.cpp: its workers take the worker options of the
+// thread running the command.
+HANDLE zpaq_rs_CreateThread(LPSECURITY_ATTRIBUTES sa, SIZE_T stack, LPTHREAD_START_ROUTINE f, LPVOID arg,
+                            DWORD flags, LPDWORD id) {
+  if (!g_worker_options.active()) return CreateThread(sa, stack, f, arg, flags, id);
+  ThreadStart* start = new ThreadStart{f, arg, g_worker_options, g_jidac_workers++, &g_jidac_worker_failures};
+  HANDLE h = CreateThread(sa, stack, start_thread, start, flags, id);
+  if (!h) delete start;
+  return h;
+}
+#endif